@@ -0,0 +1,4 @@
+-- Record the sender of to-device messages so /sync can deliver them as events
+ALTER TABLE to_device_messages ADD COLUMN IF NOT EXISTS sender VARCHAR(255);
+
+CREATE INDEX IF NOT EXISTS idx_to_device_user_device_id ON to_device_messages(user_id, device_id, id);
@@ -0,0 +1,13 @@
+-- Per-connection state for sliding sync, so reconnects with a known pos stay incremental.
+-- A row is kept for the latest pos and the one before it, allowing a client to retry.
+CREATE TABLE IF NOT EXISTS sliding_sync_connections (
+    user_id VARCHAR(255) NOT NULL,
+    device_id VARCHAR(255) NOT NULL,
+    conn_id VARCHAR(255) NOT NULL,
+    pos BIGINT NOT NULL,
+    room_states JSONB NOT NULL DEFAULT '{}',
+    created_ts BIGINT NOT NULL,
+    PRIMARY KEY (user_id, device_id, conn_id, pos)
+);
+
+CREATE INDEX IF NOT EXISTS idx_sliding_sync_connections_created ON sliding_sync_connections(created_ts);
@@ -0,0 +1,14 @@
+-- Events received over federation are sent by users on other servers
+ALTER TABLE events DROP CONSTRAINT IF EXISTS events_sender_fkey;
+ALTER TABLE events ALTER COLUMN origin TYPE VARCHAR(255);
+
+-- DAG and signature fields of federated PDUs
+ALTER TABLE events ADD COLUMN IF NOT EXISTS auth_events JSONB;
+ALTER TABLE events ADD COLUMN IF NOT EXISTS prev_events JSONB;
+ALTER TABLE events ADD COLUMN IF NOT EXISTS hashes JSONB;
+ALTER TABLE events ADD COLUMN IF NOT EXISTS signatures JSONB;
+
+-- Soft-failed events are kept for the DAG but hidden from clients and current state
+ALTER TABLE events ADD COLUMN IF NOT EXISTS soft_failed BOOLEAN NOT NULL DEFAULT FALSE;
+
+CREATE INDEX IF NOT EXISTS idx_events_soft_failed ON events(room_id) WHERE soft_failed;
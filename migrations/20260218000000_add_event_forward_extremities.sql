@@ -0,0 +1,15 @@
+-- Events in each room that no other event references yet; new local events point at them
+CREATE TABLE IF NOT EXISTS event_forward_extremities (
+    room_id VARCHAR(255) NOT NULL,
+    event_id VARCHAR(255) NOT NULL,
+    PRIMARY KEY (room_id, event_id),
+    FOREIGN KEY (room_id) REFERENCES rooms(room_id) ON DELETE CASCADE,
+    FOREIGN KEY (event_id) REFERENCES events(event_id) ON DELETE CASCADE
+);
+
+-- Existing rooms continue from their latest event
+INSERT INTO event_forward_extremities (room_id, event_id)
+SELECT DISTINCT ON (room_id) room_id, event_id FROM events
+WHERE NOT soft_failed
+ORDER BY room_id, origin_server_ts DESC
+ON CONFLICT DO NOTHING;
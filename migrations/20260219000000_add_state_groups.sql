@@ -0,0 +1,37 @@
+-- Snapshots of room state, shared by every event that leaves the room in the same state
+CREATE TABLE IF NOT EXISTS state_groups (
+    id BIGSERIAL PRIMARY KEY,
+    room_id VARCHAR(255) NOT NULL,
+    event_id VARCHAR(255),
+    created_ts BIGINT NOT NULL,
+    FOREIGN KEY (room_id) REFERENCES rooms(room_id) ON DELETE CASCADE
+);
+
+CREATE INDEX IF NOT EXISTS idx_state_groups_room ON state_groups(room_id);
+
+CREATE TABLE IF NOT EXISTS state_groups_state (
+    state_group BIGINT NOT NULL,
+    event_type VARCHAR(255) NOT NULL,
+    state_key VARCHAR(255) NOT NULL,
+    event_id VARCHAR(255) NOT NULL,
+    PRIMARY KEY (state_group, event_type, state_key),
+    FOREIGN KEY (state_group) REFERENCES state_groups(id) ON DELETE CASCADE
+);
+
+-- The state group holding the room state after each event
+CREATE TABLE IF NOT EXISTS event_to_state_groups (
+    event_id VARCHAR(255) NOT NULL PRIMARY KEY,
+    state_group BIGINT NOT NULL,
+    FOREIGN KEY (state_group) REFERENCES state_groups(id) ON DELETE CASCADE
+);
+
+CREATE INDEX IF NOT EXISTS idx_event_to_state_groups_group ON event_to_state_groups(state_group);
+
+-- Results of resolving a set of state groups, so each fork is only resolved once
+CREATE TABLE IF NOT EXISTS state_group_resolutions (
+    room_id VARCHAR(255) NOT NULL,
+    input_groups BIGINT[] NOT NULL,
+    state_group BIGINT NOT NULL,
+    PRIMARY KEY (room_id, input_groups),
+    FOREIGN KEY (state_group) REFERENCES state_groups(id) ON DELETE CASCADE
+);
@@ -0,0 +1,24 @@
+-- Outgoing PDUs and EDUs, one row per destination. The id is the queue's stream position:
+-- rows are deleted once acknowledged by the destination, so whatever is left is what a
+-- destination still has to catch up on.
+CREATE TABLE IF NOT EXISTS federation_outbound_queue (
+    id BIGSERIAL PRIMARY KEY,
+    destination VARCHAR(255) NOT NULL,
+    pdu JSONB,
+    edu JSONB,
+    created_ts BIGINT NOT NULL,
+    CHECK ((pdu IS NULL) <> (edu IS NULL))
+);
+
+CREATE INDEX IF NOT EXISTS idx_federation_outbound_queue_destination
+    ON federation_outbound_queue(destination, id);
+
+-- Delivery state per destination, so backoff survives restarts
+CREATE TABLE IF NOT EXISTS federation_destinations (
+    destination VARCHAR(255) NOT NULL PRIMARY KEY,
+    failure_count INTEGER NOT NULL DEFAULT 0,
+    retry_last_ts BIGINT NOT NULL DEFAULT 0,
+    retry_interval_ms BIGINT NOT NULL DEFAULT 0,
+    last_successful_stream_id BIGINT NOT NULL DEFAULT 0,
+    last_success_ts BIGINT
+);
@@ -0,0 +1,13 @@
+-- Rooms joined over federation are created by, and have members on, other servers
+ALTER TABLE rooms DROP CONSTRAINT IF EXISTS rooms_creator_fkey;
+ALTER TABLE room_memberships DROP CONSTRAINT IF EXISTS room_memberships_user_id_fkey;
+
+-- Rooms joined without the full member list. The servers named by the resident server
+-- receive our events until the full state at the join has been fetched.
+CREATE TABLE IF NOT EXISTS partial_state_rooms (
+    room_id VARCHAR(255) NOT NULL PRIMARY KEY,
+    join_event_id VARCHAR(255) NOT NULL,
+    servers_in_room TEXT[] NOT NULL DEFAULT '{}',
+    created_ts BIGINT NOT NULL,
+    FOREIGN KEY (room_id) REFERENCES rooms(room_id) ON DELETE CASCADE
+);
@@ -0,0 +1,30 @@
+-- Push rules users have added, and the actions they have set on server-default rules.
+-- Within a kind, rules with a higher priority are evaluated first.
+CREATE TABLE IF NOT EXISTS push_rules (
+    user_id VARCHAR(255) NOT NULL,
+    kind VARCHAR(16) NOT NULL,
+    rule_id VARCHAR(255) NOT NULL,
+    priority BIGINT NOT NULL DEFAULT 0,
+    conditions JSONB NOT NULL DEFAULT '[]',
+    pattern TEXT,
+    actions JSONB NOT NULL DEFAULT '[]',
+    created_ts BIGINT NOT NULL,
+    PRIMARY KEY (user_id, kind, rule_id)
+);
+
+CREATE INDEX IF NOT EXISTS idx_push_rules_user ON push_rules(user_id, kind, priority DESC);
+
+-- Rules, user-defined or server-default, that a user has switched on or off
+CREATE TABLE IF NOT EXISTS push_rules_enable (
+    user_id VARCHAR(255) NOT NULL,
+    kind VARCHAR(16) NOT NULL,
+    rule_id VARCHAR(255) NOT NULL,
+    enabled BOOLEAN NOT NULL,
+    PRIMARY KEY (user_id, kind, rule_id)
+);
+
+-- When each user's rules last changed, so /sync knows to send `m.push_rules` again
+CREATE TABLE IF NOT EXISTS push_rules_stream (
+    user_id VARCHAR(255) NOT NULL PRIMARY KEY,
+    updated_ts BIGINT NOT NULL
+);
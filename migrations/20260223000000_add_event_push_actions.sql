@@ -0,0 +1,26 @@
+-- What each local user's push rules made of a new event, for the events that notify them.
+-- Events outside a thread have thread_id 'main'; stream_ordering is the event's processed_ts.
+CREATE TABLE IF NOT EXISTS event_push_actions (
+    user_id VARCHAR(255) NOT NULL,
+    event_id VARCHAR(255) NOT NULL,
+    room_id VARCHAR(255) NOT NULL,
+    thread_id VARCHAR(255) NOT NULL DEFAULT 'main',
+    stream_ordering BIGINT NOT NULL,
+    actions JSONB NOT NULL DEFAULT '[]',
+    notif BOOLEAN NOT NULL DEFAULT TRUE,
+    highlight BOOLEAN NOT NULL DEFAULT FALSE,
+    PRIMARY KEY (user_id, event_id)
+);
+
+CREATE INDEX IF NOT EXISTS idx_event_push_actions_room ON event_push_actions(user_id, room_id, stream_ordering);
+
+-- How far each user has read in each room, from their m.read and m.read.private receipts.
+-- An unthreaded receipt is stored with an empty thread_id and covers every thread.
+CREATE TABLE IF NOT EXISTS event_push_receipts (
+    user_id VARCHAR(255) NOT NULL,
+    room_id VARCHAR(255) NOT NULL,
+    thread_id VARCHAR(255) NOT NULL,
+    event_id VARCHAR(255) NOT NULL,
+    stream_ordering BIGINT NOT NULL,
+    PRIMARY KEY (user_id, room_id, thread_id)
+);
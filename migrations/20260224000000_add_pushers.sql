@@ -0,0 +1,21 @@
+-- Push gateways users have registered through /pushers/set. A pusher belongs to the device
+-- that registered it and goes away with it.
+CREATE TABLE IF NOT EXISTS pushers (
+    id BIGSERIAL PRIMARY KEY,
+    user_id VARCHAR(255) NOT NULL,
+    device_id VARCHAR(255),
+    kind VARCHAR(32) NOT NULL,
+    app_id VARCHAR(255) NOT NULL,
+    pushkey TEXT NOT NULL,
+    app_display_name VARCHAR(255) NOT NULL,
+    device_display_name VARCHAR(255) NOT NULL,
+    profile_tag VARCHAR(255),
+    lang VARCHAR(64) NOT NULL,
+    data JSONB NOT NULL DEFAULT '{}',
+    created_ts BIGINT NOT NULL,
+    last_success_ts BIGINT,
+    failing_since BIGINT,
+    CONSTRAINT uk_pushers UNIQUE (app_id, pushkey, user_id)
+);
+
+CREATE INDEX IF NOT EXISTS idx_pushers_user ON pushers(user_id);
@@ -0,0 +1,36 @@
+-- The m.relates_to of each event that has one: threads, edits, references and annotations.
+-- stream_ordering is the relating event's processed_ts.
+CREATE TABLE IF NOT EXISTS event_relations (
+    event_id VARCHAR(255) PRIMARY KEY,
+    room_id VARCHAR(255) NOT NULL,
+    relates_to_id VARCHAR(255) NOT NULL,
+    rel_type VARCHAR(255) NOT NULL,
+    aggregation_key TEXT,
+    sender VARCHAR(255) NOT NULL,
+    event_type VARCHAR(255) NOT NULL,
+    stream_ordering BIGINT NOT NULL
+);
+
+CREATE INDEX IF NOT EXISTS idx_event_relations_parent ON event_relations(relates_to_id, rel_type, stream_ordering);
+CREATE INDEX IF NOT EXISTS idx_event_relations_room ON event_relations(room_id, rel_type, stream_ordering);
+
+INSERT INTO event_relations (event_id, room_id, relates_to_id, rel_type, aggregation_key, sender, event_type, stream_ordering)
+SELECT event_id, room_id, content->'m.relates_to'->>'event_id', content->'m.relates_to'->>'rel_type',
+       content->'m.relates_to'->>'key', user_id, event_type, COALESCE(processed_ts, origin_server_ts)
+FROM events
+WHERE content->'m.relates_to'->>'rel_type' IS NOT NULL
+  AND content->'m.relates_to'->>'event_id' IS NOT NULL
+  AND NOT COALESCE(redacted, FALSE) AND NOT soft_failed
+ON CONFLICT (event_id) DO NOTHING;
+
+-- Threads users follow; replacing the in-memory subscriptions of ThreadService.
+CREATE TABLE IF NOT EXISTS thread_subscriptions (
+    user_id VARCHAR(255) NOT NULL,
+    room_id VARCHAR(255) NOT NULL,
+    root_event_id VARCHAR(255) NOT NULL,
+    notify BOOLEAN NOT NULL DEFAULT TRUE,
+    subscribed_ts BIGINT NOT NULL,
+    PRIMARY KEY (user_id, root_event_id)
+);
+
+CREATE INDEX IF NOT EXISTS idx_thread_subscriptions_root ON thread_subscriptions(root_event_id);
@@ -0,0 +1,29 @@
+-- Full-text index of message bodies and room names and topics, for /search without Elasticsearch.
+-- key is the searched field (content.body, content.name or content.topic); stream_ordering is
+-- the event's processed_ts.
+CREATE TABLE IF NOT EXISTS event_search (
+    event_id VARCHAR(255) PRIMARY KEY,
+    room_id VARCHAR(255) NOT NULL,
+    sender VARCHAR(255) NOT NULL,
+    key VARCHAR(32) NOT NULL,
+    value TEXT NOT NULL,
+    vector TSVECTOR NOT NULL,
+    origin_server_ts BIGINT NOT NULL,
+    stream_ordering BIGINT NOT NULL
+);
+
+CREATE INDEX IF NOT EXISTS idx_event_search_vector ON event_search USING GIN (vector);
+CREATE INDEX IF NOT EXISTS idx_event_search_room ON event_search(room_id, stream_ordering);
+
+INSERT INTO event_search (event_id, room_id, sender, key, value, vector, origin_server_ts, stream_ordering)
+SELECT event_id, room_id, user_id, 'content.' || field, content->>field, to_tsvector('english', content->>field),
+       origin_server_ts, COALESCE(processed_ts, origin_server_ts)
+FROM (
+    SELECT event_id, room_id, user_id, content, origin_server_ts, processed_ts,
+           CASE event_type WHEN 'm.room.message' THEN 'body' WHEN 'm.room.name' THEN 'name' ELSE 'topic' END AS field
+    FROM events
+    WHERE event_type IN ('m.room.message', 'm.room.name', 'm.room.topic')
+      AND NOT COALESCE(redacted, FALSE) AND NOT soft_failed
+) searchable
+WHERE jsonb_typeof(content->field) = 'string' AND content->>field <> ''
+ON CONFLICT (event_id) DO NOTHING;
@@ -0,0 +1,23 @@
+-- Events, ephemeral events and to-device messages waiting to be pushed to an application
+-- service. As with federation_outbound_queue, the id is the stream position and rows are
+-- deleted once the application service has acknowledged the transaction carrying them.
+CREATE TABLE IF NOT EXISTS appservice_outbound_queue (
+    id BIGSERIAL PRIMARY KEY,
+    as_id VARCHAR(255) NOT NULL,
+    kind VARCHAR(16) NOT NULL CHECK (kind IN ('event', 'ephemeral', 'to_device')),
+    payload JSONB NOT NULL,
+    created_ts BIGINT NOT NULL
+);
+
+CREATE INDEX IF NOT EXISTS idx_appservice_outbound_queue_as_id
+    ON appservice_outbound_queue(as_id, id);
+
+-- Delivery state per application service, so backoff survives restarts
+CREATE TABLE IF NOT EXISTS appservice_state (
+    as_id VARCHAR(255) NOT NULL PRIMARY KEY,
+    failure_count INTEGER NOT NULL DEFAULT 0,
+    retry_last_ts BIGINT NOT NULL DEFAULT 0,
+    retry_interval_ms BIGINT NOT NULL DEFAULT 0,
+    last_successful_stream_id BIGINT NOT NULL DEFAULT 0,
+    last_success_ts BIGINT
+);
@@ -0,0 +1,12 @@
+-- Tokens that let users register while open registration is closed (MSC3231). `pending`
+-- counts registrations that have passed the token stage but not yet finished; a token is
+-- used up once pending + completed reaches uses_allowed.
+CREATE TABLE IF NOT EXISTS registration_tokens (
+    token VARCHAR(64) NOT NULL PRIMARY KEY,
+    uses_allowed INTEGER CHECK (uses_allowed >= 0),
+    pending INTEGER NOT NULL DEFAULT 0,
+    completed INTEGER NOT NULL DEFAULT 0,
+    expiry_time BIGINT,
+    created_ts BIGINT NOT NULL,
+    created_by VARCHAR(255)
+);
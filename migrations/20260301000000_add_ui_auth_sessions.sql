@@ -0,0 +1,13 @@
+-- User-interactive authentication sessions. `action` is the operation the session authorizes,
+-- so a session completed for one request cannot be replayed against another, and `completed`
+-- maps each finished stage type to its result.
+CREATE TABLE IF NOT EXISTS ui_auth_sessions (
+    session_id VARCHAR(64) NOT NULL PRIMARY KEY,
+    user_id VARCHAR(255),
+    action VARCHAR(64) NOT NULL,
+    completed JSONB NOT NULL DEFAULT '{}',
+    created_ts BIGINT NOT NULL
+);
+
+CREATE INDEX IF NOT EXISTS idx_ui_auth_sessions_user_id ON ui_auth_sessions(user_id, created_ts);
+CREATE INDEX IF NOT EXISTS idx_ui_auth_sessions_created_ts ON ui_auth_sessions(created_ts);
@@ -0,0 +1,23 @@
+-- Position of each event in the order this server persisted it. /sync tokens carry the last
+-- position a client has seen, which unlike origin_server_ts never goes backwards or repeats.
+CREATE SEQUENCE IF NOT EXISTS events_stream_ordering_seq;
+
+ALTER TABLE events ADD COLUMN IF NOT EXISTS stream_ordering BIGINT;
+
+UPDATE events SET stream_ordering = numbered.position
+FROM (
+    SELECT event_id, nextval('events_stream_ordering_seq') AS position
+    FROM (
+        SELECT event_id FROM events
+        WHERE stream_ordering IS NULL
+        ORDER BY COALESCE(processed_ts, origin_server_ts), origin_server_ts, event_id
+    ) ordered
+) numbered
+WHERE events.event_id = numbered.event_id;
+
+ALTER TABLE events ALTER COLUMN stream_ordering SET DEFAULT nextval('events_stream_ordering_seq');
+ALTER TABLE events ALTER COLUMN stream_ordering SET NOT NULL;
+ALTER SEQUENCE events_stream_ordering_seq OWNED BY events.stream_ordering;
+
+CREATE UNIQUE INDEX IF NOT EXISTS idx_events_stream_ordering ON events(stream_ordering);
+CREATE INDEX IF NOT EXISTS idx_events_room_stream_ordering ON events(room_id, stream_ordering DESC);
@@ -0,0 +1,3 @@
+-- Each event exactly as it was built here or received over federation, so it can be served to
+-- other servers with its hashes and signatures intact. Events stored before this have none.
+ALTER TABLE events ADD COLUMN IF NOT EXISTS pdu JSONB;
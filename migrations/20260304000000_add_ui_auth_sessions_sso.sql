@@ -0,0 +1,7 @@
+-- The SSO fallback in progress for a UIA session: the identity provider comes back with
+-- `sso_state`, which finds the session on whichever worker handles the redirect.
+ALTER TABLE ui_auth_sessions ADD COLUMN IF NOT EXISTS sso_state VARCHAR(255);
+ALTER TABLE ui_auth_sessions ADD COLUMN IF NOT EXISTS sso_redirect_uri TEXT;
+ALTER TABLE ui_auth_sessions ADD COLUMN IF NOT EXISTS sso_code_verifier TEXT;
+
+CREATE UNIQUE INDEX IF NOT EXISTS idx_ui_auth_sessions_sso_state ON ui_auth_sessions(sso_state);
@@ -0,0 +1,6 @@
+-- Order the search index by each event's position in the events stream rather than its
+-- processed_ts, which two events can share. Rows indexed before this are re-keyed.
+UPDATE event_search
+SET stream_ordering = events.stream_ordering
+FROM events
+WHERE events.event_id = event_search.event_id;
@@ -1,26 +1,56 @@
+pub mod scheduler;
+pub mod thirdparty;
+
+pub use scheduler::*;
+pub use thirdparty::*;
+
 use regex::Regex;
-use serde::{Deserialize, Serialize};
+use serde::{Deserialize, Deserializer, Serialize};
 use std::collections::HashMap;
 use std::sync::Arc;
 use tokio::sync::RwLock;
 use tracing::{debug, error, info};
+use url::form_urlencoded::byte_serialize;
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct AppServiceConfig {
     pub id: String,
+    /// Where transactions and queries are sent; empty when the registration sets `url: null`,
+    /// in which case nothing is pushed to the service.
+    #[serde(default, deserialize_with = "deserialize_nullable_url")]
     pub url: String,
     pub as_token: String,
     pub hs_token: String,
     pub sender_localpart: String,
+    #[serde(default = "default_rate_limited")]
     pub rate_limited: bool,
+    #[serde(default)]
     pub protocols: Vec<String>,
+    #[serde(default)]
     pub namespaces: Namespaces,
+    /// Whether typing notifications and receipts are pushed in the `ephemeral` field.
+    #[serde(default, alias = "de.sorunome.msc2409.push_ephemeral", alias = "push_ephemeral")]
+    pub receive_ephemeral: bool,
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+fn default_rate_limited() -> bool {
+    true
+}
+
+fn deserialize_nullable_url<'de, D>(deserializer: D) -> Result<String, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    Ok(Option::<String>::deserialize(deserializer)?.unwrap_or_default())
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
 pub struct Namespaces {
+    #[serde(default)]
     pub users: Vec<Namespace>,
+    #[serde(default)]
     pub aliases: Vec<Namespace>,
+    #[serde(default)]
     pub rooms: Vec<Namespace>,
 }
 
@@ -106,6 +136,21 @@ impl AppService {
         &self.config.hs_token
     }
 
+    /// The user the service acts as when it does not masquerade as another.
+    pub fn sender_user_id(&self, server_name: &str) -> String {
+        format!("@{}:{}", self.config.sender_localpart, server_name)
+    }
+
+    /// Whether the service may act as `user_id`: its sender or a user in its namespaces.
+    pub fn is_interested_in_user(&self, user_id: &str, server_name: &str) -> bool {
+        self.is_user_in_namespace(user_id) || user_id == self.sender_user_id(server_name)
+    }
+
+    /// Whether transactions are sent to the service at all.
+    pub fn has_url(&self) -> bool {
+        !self.config.url.is_empty()
+    }
+
     pub fn is_user_in_namespace(&self, user_id: &str) -> bool {
         self.compiled_user_namespaces
             .iter()
@@ -185,7 +230,14 @@ impl AppService {
     }
 
     pub async fn query_user(&self, user_id: &str) -> Result<Option<QueryResponse>, AppServiceError> {
-        let url = format!("{}/_matrix/app/v1/users/{}", self.config.url, user_id);
+        if !self.has_url() {
+            return Ok(None);
+        }
+        let url = format!(
+            "{}/_matrix/app/v1/users/{}",
+            self.config.url,
+            byte_serialize(user_id.as_bytes()).collect::<String>()
+        );
         
         let response = self.http_client
             .get(&url)
@@ -209,7 +261,14 @@ impl AppService {
     }
 
     pub async fn query_alias(&self, alias: &str) -> Result<Option<QueryResponse>, AppServiceError> {
-        let url = format!("{}/_matrix/app/v1/rooms/{}", self.config.url, alias);
+        if !self.has_url() {
+            return Ok(None);
+        }
+        let url = format!(
+            "{}/_matrix/app/v1/rooms/{}",
+            self.config.url,
+            byte_serialize(alias.as_bytes()).collect::<String>()
+        );
         
         let response = self.http_client
             .get(&url)
@@ -235,10 +294,20 @@ impl AppService {
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct AppServiceEvent {
+    /// Sent in the request path rather than the body.
+    #[serde(skip)]
     pub txn_id: String,
     pub events: Vec<serde_json::Value>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
     pub ephemeral: Option<Vec<serde_json::Value>>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
     pub device_lists: Option<DeviceLists>,
+    #[serde(
+        default,
+        rename = "de.sorunome.msc2409.to_device",
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub to_device: Option<Vec<serde_json::Value>>,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -275,6 +344,17 @@ pub enum AppServiceError {
     ParseError(String),
     #[error("Invalid regex: {0}")]
     RegexError(#[from] regex::Error),
+    #[error("Invalid registration: {0}")]
+    ConfigError(String),
+}
+
+/// Reads an application service registration file.
+pub fn load_registration(path: &str) -> Result<AppServiceConfig, AppServiceError> {
+    config::Config::builder()
+        .add_source(config::File::new(path, config::FileFormat::Yaml))
+        .build()
+        .and_then(|c| c.try_deserialize())
+        .map_err(|e| AppServiceError::ConfigError(format!("{}: {}", path, e)))
 }
 
 pub struct AppServiceRegistry {
@@ -290,6 +370,42 @@ impl AppServiceRegistry {
         }
     }
 
+    /// Builds a registry from registrations, rejecting duplicate IDs or `as_token`s.
+    pub fn from_configs(configs: Vec<AppServiceConfig>) -> Result<Self, AppServiceError> {
+        let mut services = HashMap::new();
+        let mut token_to_service = HashMap::new();
+        for config in configs {
+            if services.contains_key(&config.id) {
+                return Err(AppServiceError::ConfigError(format!(
+                    "duplicate application service id {}",
+                    config.id
+                )));
+            }
+            if token_to_service.contains_key(&config.as_token) {
+                return Err(AppServiceError::ConfigError(format!(
+                    "application service {} reuses another service's as_token",
+                    config.id
+                )));
+            }
+            let service = AppService::new(config)?;
+            token_to_service.insert(service.as_token().to_string(), service.id().to_string());
+            services.insert(service.id().to_string(), Arc::new(service));
+        }
+        Ok(Self {
+            services: Arc::new(RwLock::new(services)),
+            token_to_service: Arc::new(RwLock::new(token_to_service)),
+        })
+    }
+
+    /// Builds a registry from the registration files listed in `app_service_config_files`.
+    pub fn load(paths: &[String]) -> Result<Self, AppServiceError> {
+        let configs = paths
+            .iter()
+            .map(|path| load_registration(path))
+            .collect::<Result<Vec<_>, _>>()?;
+        Self::from_configs(configs)
+    }
+
     pub async fn register(&self, config: AppServiceConfig) -> Result<(), AppServiceError> {
         let service = AppService::new(config.clone())?;
         let id = config.id.clone();
@@ -362,6 +478,42 @@ impl AppServiceRegistry {
         services.values().any(|s| s.has_exclusive_room_namespace(room_id))
     }
 
+    /// Asks the services whose namespaces cover `user_id` whether it exists, so that
+    /// they can create it on demand.
+    pub async fn query_user(&self, user_id: &str) -> bool {
+        for service in self.get_all().await {
+            if !service.is_user_in_namespace(user_id) {
+                continue;
+            }
+            match service.query_user(user_id).await {
+                Ok(Some(_)) => return true,
+                Ok(None) => {}
+                Err(e) => {
+                    debug!(appservice_id = %service.id(), error = %e, "User query failed");
+                }
+            }
+        }
+        false
+    }
+
+    /// Asks the services whose namespaces cover `alias` whether it exists, so that they
+    /// can create the room on demand.
+    pub async fn query_alias(&self, alias: &str) -> bool {
+        for service in self.get_all().await {
+            if !service.is_alias_in_namespace(alias) {
+                continue;
+            }
+            match service.query_alias(alias).await {
+                Ok(Some(_)) => return true,
+                Ok(None) => {}
+                Err(e) => {
+                    debug!(appservice_id = %service.id(), error = %e, "Alias query failed");
+                }
+            }
+        }
+        false
+    }
+
     pub async fn get_services_for_protocol(&self, protocol: &str) -> Vec<Arc<AppService>> {
         let services = self.services.read().await;
         services
@@ -408,6 +560,7 @@ mod tests {
                 ],
                 rooms: vec![],
             },
+            receive_ephemeral: false,
         }
     }
 
@@ -455,6 +608,54 @@ mod tests {
         assert!(!service.supports_protocol("discord"));
     }
 
+    #[test]
+    fn test_load_registration_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("irc.yaml");
+        std::fs::write(
+            &path,
+            r#"
+id: irc-bridge
+url: null
+as_token: as_secret
+hs_token: hs_secret
+sender_localpart: ircbot
+receive_ephemeral: true
+namespaces:
+  users:
+    - exclusive: true
+      regex: "@irc_.*:example\\.com"
+"#,
+        )
+        .unwrap();
+
+        let config = load_registration(path.to_str().unwrap()).unwrap();
+        assert_eq!(config.id, "irc-bridge");
+        assert!(config.url.is_empty());
+        assert!(config.rate_limited);
+        assert!(config.receive_ephemeral);
+        assert!(config.namespaces.aliases.is_empty());
+
+        let service = AppService::new(config).unwrap();
+        assert!(!service.has_url());
+        assert!(service.has_exclusive_user_namespace("@irc_alice:example.com"));
+        assert!(service.is_interested_in_user("@ircbot:example.com", "example.com"));
+    }
+
+    #[tokio::test]
+    async fn test_registry_from_configs_rejects_duplicates() {
+        let registry = AppServiceRegistry::from_configs(vec![create_test_config()]).unwrap();
+        assert!(registry.get_by_token("test_as_token").await.is_some());
+
+        let mut other = create_test_config();
+        other.as_token = "other_token".to_string();
+        assert!(AppServiceRegistry::from_configs(vec![create_test_config(), other]).is_err());
+
+        let mut other = create_test_config();
+        other.id = "other".to_string();
+        assert!(AppServiceRegistry::from_configs(vec![create_test_config(), other]).is_err());
+    }
+
     #[tokio::test]
     async fn test_registry_register() {
         let registry = AppServiceRegistry::new();
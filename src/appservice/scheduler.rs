@@ -0,0 +1,438 @@
+use super::{AppService, AppServiceEvent, AppServiceRegistry};
+use crate::storage::{
+    AppServiceQueueKind, AppServiceQueueStorage, QueuedAppServiceTransaction, RoomEvent, RoomMemberStorage,
+    RoomStorage,
+};
+use chrono::Utc;
+use futures::StreamExt;
+use serde_json::{json, Value};
+use std::collections::HashSet;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::task::JoinHandle;
+use tracing::{debug, info, warn};
+
+#[derive(Debug, Clone)]
+pub struct AppServiceSchedulerConfig {
+    pub max_rows_per_transaction: i64,
+    pub min_retry_interval_ms: i64,
+    pub max_retry_interval_ms: i64,
+    pub poll_interval_ms: u64,
+    pub max_concurrent_services: usize,
+}
+
+impl AppServiceSchedulerConfig {
+    /// Exponential backoff for an unreachable service, doubling from the minimum up to the maximum.
+    pub fn next_retry_interval(&self, previous_ms: i64) -> i64 {
+        previous_ms
+            .saturating_mul(2)
+            .clamp(self.min_retry_interval_ms, self.max_retry_interval_ms)
+    }
+}
+
+impl Default for AppServiceSchedulerConfig {
+    fn default() -> Self {
+        Self {
+            max_rows_per_transaction: 100,
+            min_retry_interval_ms: 5_000,
+            max_retry_interval_ms: 5 * 60 * 1000,
+            poll_interval_ms: 1_000,
+            max_concurrent_services: 10,
+        }
+    }
+}
+
+/// Whether `service` is interested in `event`: its sender or target is in the service's user
+/// namespace, the room is in its room namespace, or one of the room's `aliases` or joined
+/// `members` is in its namespaces.
+pub fn is_interested_in_event(
+    service: &AppService,
+    event: &RoomEvent,
+    aliases: &[String],
+    members: &[String],
+    server_name: &str,
+) -> bool {
+    is_interested_in_event_directly(service, event, server_name)
+        || is_interested_in_room(service, aliases, members, server_name)
+}
+
+fn is_interested_in_event_directly(service: &AppService, event: &RoomEvent, server_name: &str) -> bool {
+    service.is_interested_in_user(&event.user_id, server_name)
+        || service.is_room_in_namespace(&event.room_id)
+        || (event.event_type == "m.room.member"
+            && event
+                .state_key
+                .as_deref()
+                .is_some_and(|target| service.is_interested_in_user(target, server_name)))
+}
+
+fn is_interested_in_room(service: &AppService, aliases: &[String], members: &[String], server_name: &str) -> bool {
+    aliases.iter().any(|alias| service.is_alias_in_namespace(alias))
+        || members.iter().any(|member| service.is_interested_in_user(member, server_name))
+}
+
+/// Pushes the events, ephemeral EDUs and to-device messages application services are
+/// interested in, through a durable queue retried with backoff while a service is down.
+pub struct AppServiceScheduler {
+    config: AppServiceSchedulerConfig,
+    registry: Arc<AppServiceRegistry>,
+    queue: AppServiceQueueStorage,
+    member_storage: RoomMemberStorage,
+    room_storage: RoomStorage,
+    server_name: String,
+    in_flight: Arc<parking_lot::Mutex<HashSet<String>>>,
+}
+
+impl AppServiceScheduler {
+    pub fn new(
+        registry: Arc<AppServiceRegistry>,
+        queue: AppServiceQueueStorage,
+        member_storage: RoomMemberStorage,
+        room_storage: RoomStorage,
+        server_name: &str,
+    ) -> Self {
+        Self {
+            config: AppServiceSchedulerConfig::default(),
+            registry,
+            queue,
+            member_storage,
+            room_storage,
+            server_name: server_name.to_string(),
+            in_flight: Arc::new(parking_lot::Mutex::new(HashSet::new())),
+        }
+    }
+
+    pub fn with_config(mut self, config: AppServiceSchedulerConfig) -> Self {
+        self.config = config;
+        self
+    }
+
+    pub fn registry(&self) -> &Arc<AppServiceRegistry> {
+        &self.registry
+    }
+
+    /// Services with somewhere to send transactions to.
+    async fn pushing_services(&self) -> Vec<Arc<AppService>> {
+        self.registry
+            .get_all()
+            .await
+            .into_iter()
+            .filter(|service| service.has_url())
+            .collect()
+    }
+
+    /// The room's aliases and joined members, which namespaces are also matched against.
+    async fn room_interest_keys(&self, room_id: &str) -> (Vec<String>, Vec<String>) {
+        let aliases = self.room_storage.get_room_aliases(room_id).await.unwrap_or_else(|e| {
+            warn!(room_id = %room_id, error = %e, "Failed to load room aliases for application services");
+            Vec::new()
+        });
+        let members = self.member_storage.get_members(room_id).await.unwrap_or_else(|e| {
+            warn!(room_id = %room_id, error = %e, "Failed to load room members for application services");
+            Vec::new()
+        });
+        (aliases, members)
+    }
+
+    /// The services interested in a new room event.
+    pub async fn interested_services(&self, event: &RoomEvent) -> Vec<String> {
+        let services = self.pushing_services().await;
+        let (mut interested, undecided): (Vec<_>, Vec<_>) = services
+            .into_iter()
+            .partition(|service| is_interested_in_event_directly(service, event, &self.server_name));
+        if !undecided.is_empty() {
+            let (aliases, members) = self.room_interest_keys(&event.room_id).await;
+            interested.extend(
+                undecided
+                    .into_iter()
+                    .filter(|service| is_interested_in_room(service, &aliases, &members, &self.server_name)),
+            );
+        }
+        interested.iter().map(|service| service.id().to_string()).collect()
+    }
+
+    /// Queues a new room event for the services interested in it.
+    pub async fn handle_event(&self, event: &RoomEvent) {
+        let as_ids = self.interested_services(event).await;
+        if as_ids.is_empty() {
+            return;
+        }
+        if let Err(e) = self
+            .queue
+            .enqueue(&as_ids, AppServiceQueueKind::Event, &event.to_client_event())
+            .await
+        {
+            warn!(event_id = %event.event_id, error = %e, "Failed to queue event for application services");
+        }
+    }
+
+    /// Queues a typing notification or receipt for the services interested in the room that
+    /// asked for ephemeral events.
+    pub async fn handle_room_ephemeral(&self, room_id: &str, edu_type: &str, content: Value) {
+        let services: Vec<_> = self
+            .pushing_services()
+            .await
+            .into_iter()
+            .filter(|service| service.config.receive_ephemeral)
+            .collect();
+        if services.is_empty() {
+            return;
+        }
+        let (aliases, members) = self.room_interest_keys(room_id).await;
+        let as_ids: Vec<String> = services
+            .iter()
+            .filter(|service| {
+                service.is_room_in_namespace(room_id)
+                    || is_interested_in_room(service, &aliases, &members, &self.server_name)
+            })
+            .map(|service| service.id().to_string())
+            .collect();
+        let edu = json!({ "type": edu_type, "room_id": room_id, "content": content });
+        if let Err(e) = self.queue.enqueue(&as_ids, AppServiceQueueKind::Ephemeral, &edu).await {
+            warn!(room_id = %room_id, error = %e, "Failed to queue {} for application services", edu_type);
+        }
+    }
+
+    /// Queues a to-device message for the services whose namespaces cover the recipient and
+    /// that asked for ephemeral events.
+    pub async fn handle_to_device(
+        &self,
+        sender: &str,
+        user_id: &str,
+        device_id: &str,
+        event_type: &str,
+        content: &Value,
+    ) {
+        let as_ids: Vec<String> = self
+            .pushing_services()
+            .await
+            .iter()
+            .filter(|service| service.config.receive_ephemeral && service.is_user_in_namespace(user_id))
+            .map(|service| service.id().to_string())
+            .collect();
+        let message = json!({
+            "type": event_type,
+            "sender": sender,
+            "content": content,
+            "to_user_id": user_id,
+            "to_device_id": device_id,
+        });
+        if let Err(e) = self.queue.enqueue(&as_ids, AppServiceQueueKind::ToDevice, &message).await {
+            warn!(user_id = %user_id, error = %e, "Failed to queue to-device message for application services");
+        }
+    }
+
+    /// Runs the queue until the process exits, waking on new entries and polling for ones
+    /// queued by other processes or whose backoff has expired.
+    pub fn start(self: &Arc<Self>) -> JoinHandle<()> {
+        let wakeup = self.queue.wakeup();
+        let scheduler = self.clone();
+        let poll_interval = Duration::from_millis(self.config.poll_interval_ms);
+
+        tokio::spawn(async move {
+            info!("Application service scheduler started");
+            loop {
+                if let Err(e) = scheduler.process_queue().await {
+                    warn!(error = %e, "Failed to process application service queue");
+                }
+                tokio::select! {
+                    _ = wakeup.notified() => {}
+                    _ = tokio::time::sleep(poll_interval) => {}
+                }
+            }
+        })
+    }
+
+    /// Sends queued transactions to every service that is not backing off. Returns the number
+    /// of transactions delivered.
+    pub async fn process_queue(&self) -> Result<usize, sqlx::Error> {
+        let as_ids = self.queue.get_ready_services(Utc::now().timestamp_millis()).await?;
+
+        let delivered = futures::stream::iter(as_ids)
+            .map(|as_id| async move {
+                let Some(service) = self.registry.get(&as_id).await.filter(|service| service.has_url()) else {
+                    return 0;
+                };
+                if !self.in_flight.lock().insert(as_id.clone()) {
+                    return 0;
+                }
+                let delivered = self.send_queued(&service).await;
+                self.in_flight.lock().remove(&as_id);
+                delivered.unwrap_or_else(|e| {
+                    warn!(appservice_id = %as_id, error = %e, "Failed to send queued application service transactions");
+                    0
+                })
+            })
+            .buffer_unordered(self.config.max_concurrent_services.max(1))
+            .fold(0, |total, delivered| async move { total + delivered })
+            .await;
+        Ok(delivered)
+    }
+
+    /// Sends the service's queue oldest first until it is empty or a send fails.
+    async fn send_queued(&self, service: &AppService) -> Result<usize, sqlx::Error> {
+        let mut delivered = 0;
+        loop {
+            let transaction = self
+                .queue
+                .get_next_transaction(service.id(), self.config.max_rows_per_transaction)
+                .await?;
+            if transaction.is_empty() {
+                return Ok(delivered);
+            }
+
+            let body = Self::transaction_body(&transaction);
+            match service.send_event(&body).await {
+                Ok(()) => {
+                    self.queue.complete_transaction(service.id(), &transaction).await?;
+                    delivered += 1;
+                    debug!(
+                        appservice_id = %service.id(),
+                        txn_id = %body.txn_id,
+                        "Queued application service transaction delivered"
+                    );
+                }
+                Err(e) => {
+                    let previous = self
+                        .queue
+                        .get_state(service.id())
+                        .await?
+                        .map(|state| state.retry_interval_ms)
+                        .unwrap_or(0);
+                    let retry_interval_ms = self.config.next_retry_interval(previous);
+                    self.queue.record_failure(service.id(), retry_interval_ms).await?;
+                    warn!(
+                        appservice_id = %service.id(),
+                        txn_id = %body.txn_id,
+                        retry_interval_ms,
+                        error = %e,
+                        "Application service transaction delivery failed"
+                    );
+                    return Ok(delivered);
+                }
+            }
+        }
+    }
+
+    fn transaction_body(transaction: &QueuedAppServiceTransaction) -> AppServiceEvent {
+        let non_empty = |values: &Vec<Value>| (!values.is_empty()).then(|| values.clone());
+        AppServiceEvent {
+            txn_id: transaction.transaction_id(),
+            events: transaction.events.clone(),
+            ephemeral: non_empty(&transaction.ephemeral),
+            device_lists: None,
+            to_device: non_empty(&transaction.to_device),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::appservice::{AppServiceConfig, Namespace, Namespaces};
+
+    fn service() -> AppService {
+        let namespace = |regex: &str| Namespace {
+            exclusive: true,
+            regex: regex.to_string(),
+            group_id: None,
+        };
+        AppService::new(AppServiceConfig {
+            id: "bridge".to_string(),
+            url: "http://localhost:9999".to_string(),
+            as_token: "as_token".to_string(),
+            hs_token: "hs_token".to_string(),
+            sender_localpart: "bridgebot".to_string(),
+            rate_limited: false,
+            protocols: vec![],
+            namespaces: Namespaces {
+                users: vec![namespace("@irc_.*:example\\.com")],
+                aliases: vec![namespace("#irc_.*:example\\.com")],
+                rooms: vec![namespace("!bridged:example\\.com")],
+            },
+            receive_ephemeral: true,
+        })
+        .unwrap()
+    }
+
+    fn event(sender: &str, room_id: &str, event_type: &str, state_key: Option<&str>) -> RoomEvent {
+        RoomEvent {
+            event_id: "$event".to_string(),
+            room_id: room_id.to_string(),
+            user_id: sender.to_string(),
+            event_type: event_type.to_string(),
+            content: json!({}),
+            state_key: state_key.map(str::to_string),
+            depth: 1,
+            origin_server_ts: 0,
+            processed_ts: 0,
+            not_before: 0,
+            status: None,
+            reference_image: None,
+            origin: "example.com".to_string(),
+            stream_ordering: 0,
+        }
+    }
+
+    #[test]
+    fn test_interest_in_namespaced_sender_target_and_room() {
+        let service = service();
+        let none: &[String] = &[];
+
+        let from_bridged_user = event("@irc_alice:example.com", "!room:example.com", "m.room.message", None);
+        assert!(is_interested_in_event(&service, &from_bridged_user, none, none, "example.com"));
+
+        let from_sender = event("@bridgebot:example.com", "!room:example.com", "m.room.message", None);
+        assert!(is_interested_in_event(&service, &from_sender, none, none, "example.com"));
+
+        let invite = event("@bob:example.com", "!room:example.com", "m.room.member", Some("@irc_alice:example.com"));
+        assert!(is_interested_in_event(&service, &invite, none, none, "example.com"));
+
+        let in_room = event("@bob:example.com", "!bridged:example.com", "m.room.message", None);
+        assert!(is_interested_in_event(&service, &in_room, none, none, "example.com"));
+
+        let unrelated = event("@bob:example.com", "!room:example.com", "m.room.message", None);
+        assert!(!is_interested_in_event(&service, &unrelated, none, none, "example.com"));
+    }
+
+    #[test]
+    fn test_interest_through_room_aliases_and_members() {
+        let service = service();
+        let message = event("@bob:example.com", "!room:example.com", "m.room.message", None);
+
+        let aliases = vec!["#irc_channel:example.com".to_string()];
+        assert!(is_interested_in_event(&service, &message, &aliases, &[], "example.com"));
+
+        let members = vec!["@bob:example.com".to_string(), "@irc_carol:example.com".to_string()];
+        assert!(is_interested_in_event(&service, &message, &[], &members, "example.com"));
+
+        let members = vec!["@bob:example.com".to_string()];
+        assert!(!is_interested_in_event(&service, &message, &[], &members, "example.com"));
+    }
+
+    #[test]
+    fn test_transaction_body_omits_empty_sections() {
+        let transaction = QueuedAppServiceTransaction {
+            stream_ids: vec![4, 7],
+            events: vec![json!({"type": "m.room.message"})],
+            ephemeral: vec![],
+            to_device: vec![json!({"type": "m.room_key_request"})],
+        };
+        let body = AppServiceScheduler::transaction_body(&transaction);
+        assert_eq!(body.txn_id, "4-7-2");
+
+        let json = serde_json::to_value(&body).unwrap();
+        assert_eq!(json["events"].as_array().unwrap().len(), 1);
+        assert!(json.get("ephemeral").is_none());
+        assert!(json.get("txn_id").is_none());
+        assert_eq!(json["de.sorunome.msc2409.to_device"][0]["type"], "m.room_key_request");
+    }
+
+    #[test]
+    fn test_retry_interval_backs_off_exponentially() {
+        let config = AppServiceSchedulerConfig::default();
+        assert_eq!(config.next_retry_interval(0), 5_000);
+        assert_eq!(config.next_retry_interval(5_000), 10_000);
+        assert_eq!(config.next_retry_interval(config.max_retry_interval_ms), config.max_retry_interval_ms);
+    }
+}
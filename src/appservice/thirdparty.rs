@@ -0,0 +1,277 @@
+use super::{AppService, AppServiceError, AppServiceRegistry};
+use serde_json::{Map, Value};
+use tracing::{debug, warn};
+use url::form_urlencoded::{byte_serialize, Serializer};
+
+/// What a third-party lookup returns: portal rooms or bridged users.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ThirdPartyEntityKind {
+    Location,
+    User,
+}
+
+impl ThirdPartyEntityKind {
+    fn path(&self) -> &'static str {
+        match self {
+            ThirdPartyEntityKind::Location => "location",
+            ThirdPartyEntityKind::User => "user",
+        }
+    }
+
+    /// The Matrix ID field every result of this kind carries, which is also the
+    /// query parameter of the reverse lookup.
+    pub fn id_field(&self) -> &'static str {
+        match self {
+            ThirdPartyEntityKind::Location => "alias",
+            ThirdPartyEntityKind::User => "userid",
+        }
+    }
+
+    /// Whether an application service's result is well-formed; malformed ones are dropped.
+    pub fn is_valid_result(&self, result: &Value) -> bool {
+        result.get(self.id_field()).is_some_and(Value::is_string)
+            && result.get("protocol").is_some_and(Value::is_string)
+            && result
+                .get("fields")
+                .and_then(Value::as_object)
+                .is_some_and(|fields| fields.values().all(Value::is_string))
+    }
+}
+
+/// Adds one service's description of `protocol` to `protocols`. The first description is
+/// kept and later services only contribute their instances, each given an `instance_id`
+/// unique across services.
+pub fn merge_protocol(protocols: &mut Map<String, Value>, protocol: &str, as_id: &str, mut info: Value) {
+    let instances: Vec<Value> = info
+        .get_mut("instances")
+        .and_then(Value::as_array_mut)
+        .map(std::mem::take)
+        .unwrap_or_default()
+        .into_iter()
+        .map(|mut instance| {
+            if let Some(network_id) = instance.get("network_id").and_then(Value::as_str) {
+                instance["instance_id"] = Value::String(format!("{}|{}", as_id, network_id));
+            }
+            instance
+        })
+        .collect();
+
+    match protocols.get_mut(protocol).and_then(|p| p.get_mut("instances")).and_then(Value::as_array_mut) {
+        Some(existing) => existing.extend(instances),
+        None => {
+            info["instances"] = Value::Array(instances);
+            protocols.insert(protocol.to_string(), info);
+        }
+    }
+}
+
+impl AppService {
+    /// The service's description of a third-party protocol it declares.
+    pub async fn query_protocol(&self, protocol: &str) -> Result<Option<Value>, AppServiceError> {
+        if !self.has_url() {
+            return Ok(None);
+        }
+        let url = format!(
+            "{}/_matrix/app/v1/thirdparty/protocol/{}",
+            self.config.url,
+            byte_serialize(protocol.as_bytes()).collect::<String>()
+        );
+        let response = self
+            .http_client
+            .get(&url)
+            .header("Authorization", format!("Bearer {}", self.config.hs_token))
+            .send()
+            .await
+            .map_err(|e| AppServiceError::NetworkError(e.to_string()))?;
+
+        if response.status().as_u16() == 404 {
+            return Ok(None);
+        }
+        if !response.status().is_success() {
+            return Err(AppServiceError::HttpError(response.status().to_string()));
+        }
+
+        let info: Value = response
+            .json()
+            .await
+            .map_err(|e| AppServiceError::ParseError(e.to_string()))?;
+        Ok(info.is_object().then_some(info))
+    }
+
+    /// Looks up third-party locations or users. With a protocol, `fields` are that protocol's
+    /// search fields; without one, they hold the Matrix ID to look up in reverse.
+    pub async fn query_third_party(
+        &self,
+        kind: ThirdPartyEntityKind,
+        protocol: Option<&str>,
+        fields: &[(String, String)],
+    ) -> Result<Vec<Value>, AppServiceError> {
+        if !self.has_url() {
+            return Ok(Vec::new());
+        }
+        let mut url = format!("{}/_matrix/app/v1/thirdparty/{}", self.config.url, kind.path());
+        if let Some(protocol) = protocol {
+            url.push('/');
+            url.extend(byte_serialize(protocol.as_bytes()));
+        }
+        if !fields.is_empty() {
+            url.push('?');
+            url.push_str(&Serializer::new(String::new()).extend_pairs(fields).finish());
+        }
+
+        let response = self
+            .http_client
+            .get(&url)
+            .header("Authorization", format!("Bearer {}", self.config.hs_token))
+            .send()
+            .await
+            .map_err(|e| AppServiceError::NetworkError(e.to_string()))?;
+
+        if response.status().as_u16() == 404 {
+            return Ok(Vec::new());
+        }
+        if !response.status().is_success() {
+            return Err(AppServiceError::HttpError(response.status().to_string()));
+        }
+
+        let results: Vec<Value> = response
+            .json()
+            .await
+            .map_err(|e| AppServiceError::ParseError(e.to_string()))?;
+        Ok(results)
+    }
+}
+
+impl AppServiceRegistry {
+    /// Every protocol declared by a registered service, described by the services themselves.
+    pub async fn get_protocols(&self) -> Map<String, Value> {
+        let services = self.get_all().await;
+        let queries = services.iter().flat_map(|service| {
+            service.config.protocols.iter().map(move |protocol| async move {
+                let info = service.query_protocol(protocol).await.unwrap_or_else(|e| {
+                    warn!(appservice_id = %service.id(), protocol = %protocol, error = %e, "Protocol query failed");
+                    None
+                });
+                (service.id(), protocol.as_str(), info)
+            })
+        });
+
+        let mut protocols = Map::new();
+        for (as_id, protocol, info) in futures::future::join_all(queries).await {
+            if let Some(info) = info {
+                merge_protocol(&mut protocols, protocol, as_id, info);
+            }
+        }
+        protocols
+    }
+
+    /// One protocol's description, merged across the services declaring it.
+    pub async fn get_protocol(&self, protocol: &str) -> Option<Value> {
+        let services = self.get_services_for_protocol(protocol).await;
+        let queries = services.iter().map(|service| async move {
+            let info = service.query_protocol(protocol).await.unwrap_or_else(|e| {
+                warn!(appservice_id = %service.id(), protocol = %protocol, error = %e, "Protocol query failed");
+                None
+            });
+            (service.id(), info)
+        });
+
+        let mut protocols = Map::new();
+        for (as_id, info) in futures::future::join_all(queries).await {
+            if let Some(info) = info {
+                merge_protocol(&mut protocols, protocol, as_id, info);
+            }
+        }
+        protocols.remove(protocol)
+    }
+
+    /// Fans a third-party lookup out to the services declaring `protocol`, or to every
+    /// service for a reverse lookup, and concatenates their well-formed results.
+    pub async fn query_third_party(
+        &self,
+        kind: ThirdPartyEntityKind,
+        protocol: Option<&str>,
+        fields: &[(String, String)],
+    ) -> Vec<Value> {
+        let services = match protocol {
+            Some(protocol) => self.get_services_for_protocol(protocol).await,
+            None => self.get_all().await,
+        };
+        let queries = services.iter().map(|service| async move {
+            service.query_third_party(kind, protocol, fields).await.unwrap_or_else(|e| {
+                warn!(appservice_id = %service.id(), kind = kind.path(), error = %e, "Third-party lookup failed");
+                Vec::new()
+            })
+        });
+
+        futures::future::join_all(queries)
+            .await
+            .into_iter()
+            .flatten()
+            .filter(|result| {
+                let valid = kind.is_valid_result(result);
+                if !valid {
+                    debug!(kind = kind.path(), "Dropping malformed third-party lookup result");
+                }
+                valid
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn test_merge_protocol_keeps_first_description_and_combines_instances() {
+        let mut protocols = Map::new();
+        merge_protocol(
+            &mut protocols,
+            "irc",
+            "irc-a",
+            json!({
+                "user_fields": ["network", "nickname"],
+                "location_fields": ["network", "channel"],
+                "icon": "mxc://example.org/irc",
+                "instances": [{ "desc": "Libera", "network_id": "libera", "fields": {} }]
+            }),
+        );
+        merge_protocol(
+            &mut protocols,
+            "irc",
+            "irc-b",
+            json!({
+                "user_fields": ["nickname"],
+                "instances": [{ "desc": "OFTC", "network_id": "oftc", "fields": {} }]
+            }),
+        );
+
+        let irc = &protocols["irc"];
+        assert_eq!(irc["user_fields"], json!(["network", "nickname"]));
+        assert_eq!(irc["instances"][0]["instance_id"], "irc-a|libera");
+        assert_eq!(irc["instances"][1]["instance_id"], "irc-b|oftc");
+    }
+
+    #[test]
+    fn test_merge_protocol_without_instances() {
+        let mut protocols = Map::new();
+        merge_protocol(&mut protocols, "gitter", "gitter", json!({ "user_fields": [] }));
+        assert_eq!(protocols["gitter"]["instances"], json!([]));
+    }
+
+    #[test]
+    fn test_malformed_results_are_rejected() {
+        let location = json!({
+            "alias": "#freenode_#matrix:example.org",
+            "protocol": "irc",
+            "fields": { "network": "freenode", "channel": "#matrix" }
+        });
+        assert!(ThirdPartyEntityKind::Location.is_valid_result(&location));
+        assert!(!ThirdPartyEntityKind::User.is_valid_result(&location));
+
+        let user = json!({ "userid": "@irc_alice:example.org", "protocol": "irc", "fields": { "nick": 1 } });
+        assert!(!ThirdPartyEntityKind::User.is_valid_result(&user));
+    }
+}
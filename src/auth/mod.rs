@@ -143,18 +143,54 @@ impl AuthService {
             admin = admin
         );
 
+        let (access_token, refresh_token, device_id) =
+            self.create_session(&user_id, user.is_admin.unwrap_or(false)).await?;
+
+        Ok((user, access_token, refresh_token, device_id))
+    }
+
+    /// Registers a passwordless user in an application service's namespace.
+    pub async fn register_appservice_user(&self, username: &str, appservice_id: &str) -> ApiResult<User> {
+        self.validator.validate_username(username)?;
+
+        let existing_user = self
+            .user_storage
+            .get_user_by_username(username)
+            .await
+            .map_err(|e| ApiError::internal(format!("Database error: {}", e)))?;
+        if existing_user.is_some() {
+            return Err(ApiError::conflict("Username already taken".to_string()));
+        }
+
+        let user_id = format!("@{}:{}", username, self.server_name);
+        let user = self
+            .user_storage
+            .create_appservice_user(&user_id, username, appservice_id)
+            .await
+            .map_err(|e| ApiError::internal(format!("Failed to create user: {}", e)))?;
+
+        ::tracing::info!(
+            target: "security_audit",
+            event = "user_registered",
+            user_id = user_id,
+            appservice_id = appservice_id
+        );
+        Ok(user)
+    }
+
+    /// Creates a device for the user and issues its access and refresh tokens, returned with
+    /// the device ID.
+    pub async fn create_session(&self, user_id: &str, admin: bool) -> ApiResult<(String, String, String)> {
         let device_id = generate_token(16);
         self.device_storage
-            .create_device(&device_id, &user_id, None)
+            .create_device(&device_id, user_id, None)
             .await
             .map_err(|e| ApiError::internal(format!("Failed to create device: {}", e)))?;
 
-        let access_token = self
-            .generate_access_token(&user_id, &device_id, user.is_admin.unwrap_or(false))
-            .await?;
-        let refresh_token = self.generate_refresh_token(&user_id, &device_id).await?;
+        let access_token = self.generate_access_token(user_id, &device_id, admin).await?;
+        let refresh_token = self.generate_refresh_token(user_id, &device_id).await?;
 
-        Ok((user, access_token, refresh_token, device_id))
+        Ok((access_token, refresh_token, device_id))
     }
 
     pub async fn login(
@@ -194,6 +230,45 @@ impl AuthService {
         device_id: Option<&str>,
         _initial_display_name: Option<&str>,
     ) -> ApiResult<(User, String, String, String)> {
+        let user = self.check_password(username, password).await?;
+
+        ::tracing::info!(
+            target: "security_audit",
+            event = "login_success",
+            user_id = user.user_id(),
+            device_id = device_id
+        );
+
+        let device_id = match device_id {
+            Some(d) => d.to_string(),
+            _ => auth_generate_token(16),
+        };
+
+        if !self
+            .device_storage
+            .device_exists(&device_id)
+            .await
+            .map_err(|e| ApiError::internal(format!("Database error: {}", e)))?
+        {
+            self.device_storage
+                .create_device(&device_id, &user.user_id, None)
+                .await
+                .map_err(|e| ApiError::internal(format!("Failed to create device: {}", e)))?;
+        }
+
+        let access_token = self
+            .generate_access_token(&user.user_id, &device_id, user.is_admin.unwrap_or(false))
+            .await?;
+        let refresh_token = self
+            .generate_refresh_token(&user.user_id, &device_id)
+            .await?;
+
+        Ok((user, access_token, refresh_token, device_id))
+    }
+
+    /// Verifies `password` for the user `username` (a localpart, user ID or third-party
+    /// identifier) without creating a session, as for the `m.login.password` UIA stage.
+    pub async fn check_password(&self, username: &str, password: &str) -> ApiResult<User> {
         let user = self
             .user_storage
             .get_user_by_identifier(username)
@@ -234,38 +309,7 @@ impl AuthService {
             return Err(ApiError::unauthorized("User is deactivated".to_string()));
         }
 
-        ::tracing::info!(
-            target: "security_audit",
-            event = "login_success",
-            user_id = user.user_id(),
-            device_id = device_id
-        );
-
-        let device_id = match device_id {
-            Some(d) => d.to_string(),
-            _ => auth_generate_token(16),
-        };
-
-        if !self
-            .device_storage
-            .device_exists(&device_id)
-            .await
-            .map_err(|e| ApiError::internal(format!("Database error: {}", e)))?
-        {
-            self.device_storage
-                .create_device(&device_id, &user.user_id, None)
-                .await
-                .map_err(|e| ApiError::internal(format!("Failed to create device: {}", e)))?;
-        }
-
-        let access_token = self
-            .generate_access_token(&user.user_id, &device_id, user.is_admin.unwrap_or(false))
-            .await?;
-        let refresh_token = self
-            .generate_refresh_token(&user.user_id, &device_id)
-            .await?;
-
-        Ok((user, access_token, refresh_token, device_id))
+        Ok(user)
     }
 
     fn increment_counter(&self, name: &str) {
@@ -152,6 +152,10 @@ pub struct PushConfig {
     /// 推送超时（秒）
     #[serde(default = "default_push_timeout")]
     pub timeout: u64,
+
+    /// 通知记录保留天数（0 表示永久保留）
+    #[serde(default = "default_notification_retention_days")]
+    pub notification_retention_days: u64,
 }
 
 impl Default for PushConfig {
@@ -167,6 +171,7 @@ impl Default for PushConfig {
             push_gateway_url: None,
             retry_count: default_push_retry_count(),
             timeout: default_push_timeout(),
+            notification_retention_days: default_notification_retention_days(),
         }
     }
 }
@@ -209,6 +214,10 @@ fn default_push_timeout() -> u64 {
     10
 }
 
+fn default_notification_retention_days() -> u64 {
+    30
+}
+
 fn default_apns_production() -> bool {
     true
 }
@@ -377,6 +386,77 @@ impl OidcConfig {
     }
 }
 
+/// UI 认证配置。
+///
+/// 官方 Synapse 配置文档: https://matrix-org.github.io/synapse/latest/usage/configuration/config_documentation.html#ui_auth
+///
+/// 配置用户交互认证（UIA）会话参数。修改密码、删除设备、注销账户、替换交叉签名密钥
+/// 以及注册都通过 UIA 会话完成认证。
+///
+/// # 配置示例
+/// ```yaml
+/// ui_auth:
+///   session_timeout: "15m"
+///   maximum_sessions: 100
+///   terms_policies:
+///     - id: privacy_policy
+///       version: "1.0"
+///       name: Privacy Policy
+///       url: https://example.com/privacy
+/// ```
+#[derive(Debug, Clone, Deserialize)]
+pub struct UiAuthConfig {
+    /// 会话超时时间，超时未完成的会话被丢弃
+    #[serde(default = "default_ui_auth_session_timeout")]
+    pub session_timeout: String,
+
+    /// 每个用户同时保留的最大会话数
+    #[serde(default = "default_max_ui_auth_sessions")]
+    pub maximum_sessions: u32,
+
+    /// 注册时 `m.login.terms` 阶段要求同意的政策
+    #[serde(default)]
+    pub terms_policies: Vec<TermsPolicyConfig>,
+}
+
+impl Default for UiAuthConfig {
+    fn default() -> Self {
+        Self {
+            session_timeout: default_ui_auth_session_timeout(),
+            maximum_sessions: default_max_ui_auth_sessions(),
+            terms_policies: Vec::new(),
+        }
+    }
+}
+
+impl UiAuthConfig {
+    /// 会话超时时间（毫秒）
+    pub fn session_timeout_ms(&self) -> i64 {
+        parse_duration(&self.session_timeout).unwrap_or(15 * 60) * 1000
+    }
+}
+
+fn default_ui_auth_session_timeout() -> String {
+    "15m".to_string()
+}
+
+fn default_max_ui_auth_sessions() -> u32 {
+    100
+}
+
+/// `m.login.terms` 阶段展示的政策。
+#[derive(Debug, Clone, Deserialize)]
+pub struct TermsPolicyConfig {
+    /// 政策标识，如 `privacy_policy`
+    pub id: String,
+    /// 当前版本
+    pub version: String,
+    /// 展示名称
+    pub name: String,
+    /// 政策文本地址
+    pub url: String,
+}
+
 /// 服务器配置结构。
 ///
 /// Matrix Homeserver 的主配置类，包含所有配置子项。
@@ -424,6 +504,12 @@ pub struct Config {
     /// OIDC 单点登录配置
     #[serde(default)]
     pub oidc: OidcConfig,
+    /// 应用服务注册文件路径列表
+    #[serde(default)]
+    pub app_service_config_files: Vec<String>,
+    /// 用户交互认证配置
+    #[serde(default)]
+    pub ui_auth: UiAuthConfig,
 }
 
 /// 搜索服务配置。
@@ -435,6 +521,20 @@ pub struct SearchConfig {
     /// 是否启用搜索功能
     #[serde(default)]
     pub enabled: bool,
+    /// 房间消息搜索后端
+    #[serde(default)]
+    pub backend: SearchBackend,
+}
+
+/// 房间消息搜索后端。
+#[derive(Debug, Clone, Copy, Deserialize, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum SearchBackend {
+    /// PostgreSQL 全文检索
+    #[default]
+    Postgres,
+    /// Elasticsearch（需启用 `enabled`）
+    Elasticsearch,
 }
 
 /// 限流配置。
@@ -794,6 +894,17 @@ pub struct ServerConfig {
     /// 是否启用注册验证码
     pub enable_registration_captcha: bool,
 
+    /// 注册是否需要注册令牌（MSC3231）
+    ///
+    /// 启用后，`/register` 要求完成 `m.login.registration_token` 认证阶段，
+    /// 令牌通过管理 API `/_synapse/admin/v1/registration_tokens` 发放。
+    #[serde(default)]
+    pub registration_requires_token: bool,
+
+    /// 注册时必须验证的第三方标识类型（`email`、`msisdn`）
+    #[serde(default)]
+    pub registrations_require_3pid: Vec<String>,
+
     /// 后台任务执行间隔（秒）
     pub background_tasks_interval: u64,
 
@@ -1076,6 +1187,9 @@ pub struct WorkerConfig {
     pub stream_writers: StreamWriters,
     #[serde(default)]
     pub replication: ReplicationConfig,
+    /// Instances sharing outbound federation, each sending to its share of destinations
+    #[serde(default)]
+    pub federation_sender_instances: Vec<String>,
 }
 
 fn default_worker_instance_name() -> String {
@@ -1091,8 +1205,29 @@ impl Default for WorkerConfig {
             instance_map: HashMap::new(),
             stream_writers: StreamWriters::default(),
             replication: ReplicationConfig::default(),
+            federation_sender_instances: Vec::new(),
+        }
+    }
+}
+
+impl WorkerConfig {
+    /// Whether this process sends outbound federation: always in a single-process deployment,
+    /// otherwise on `federation_sender` workers, or on the main process when none are configured.
+    pub fn runs_federation_sender(&self) -> bool {
+        if !self.enabled {
+            return true;
+        }
+        match self.worker_app.as_deref() {
+            Some(app) => app == "federation_sender",
+            None => self.federation_sender_instances.is_empty(),
         }
     }
+
+    /// Whether this process pushes transactions to application services: always in a
+    /// single-process deployment, otherwise only on the main process.
+    pub fn runs_appservice_sender(&self) -> bool {
+        !self.enabled || self.worker_app.is_none()
+    }
 }
 
 #[derive(Debug, Clone, Deserialize)]
@@ -1342,6 +1477,8 @@ mod tests {
                 max_image_resolution: 1000000,
                 enable_registration: true,
                 enable_registration_captcha: false,
+                registration_requires_token: false,
+                registrations_require_3pid: Vec::new(),
                 background_tasks_interval: 60,
                 expire_access_token: true,
                 expire_access_token_lifetime: 3600,
@@ -1397,6 +1534,7 @@ mod tests {
             search: SearchConfig {
                 elasticsearch_url: "http://localhost:9200".to_string(),
                 enabled: false,
+                backend: SearchBackend::Postgres,
             },
             rate_limit: RateLimitConfig::default(),
             admin_registration: AdminRegistrationConfig::default(),
@@ -1413,6 +1551,8 @@ mod tests {
             push: PushConfig::default(),
             url_preview: UrlPreviewConfig::default(),
             oidc: OidcConfig::default(),
+            app_service_config_files: Vec::new(),
+            ui_auth: UiAuthConfig::default(),
         };
 
         let url = config.database_url();
@@ -1438,6 +1578,8 @@ mod tests {
                 max_image_resolution: 1000000,
                 enable_registration: true,
                 enable_registration_captcha: false,
+                registration_requires_token: false,
+                registrations_require_3pid: Vec::new(),
                 background_tasks_interval: 60,
                 expire_access_token: true,
                 expire_access_token_lifetime: 3600,
@@ -1493,6 +1635,7 @@ mod tests {
             search: SearchConfig {
                 elasticsearch_url: "http://localhost:9200".to_string(),
                 enabled: false,
+                backend: SearchBackend::Postgres,
             },
             rate_limit: RateLimitConfig::default(),
             admin_registration: AdminRegistrationConfig::default(),
@@ -1509,6 +1652,8 @@ mod tests {
             push: PushConfig::default(),
             url_preview: UrlPreviewConfig::default(),
             oidc: OidcConfig::default(),
+            app_service_config_files: Vec::new(),
+            ui_auth: UiAuthConfig::default(),
         };
 
         let url = config.redis_url();
@@ -1533,6 +1678,8 @@ mod tests {
             max_image_resolution: 8000000,
             enable_registration: true,
             enable_registration_captcha: true,
+            registration_requires_token: false,
+            registrations_require_3pid: Vec::new(),
             background_tasks_interval: 30,
             expire_access_token: true,
             expire_access_token_lifetime: 86400,
@@ -1630,6 +1777,37 @@ mod tests {
         assert!(config.secret.len() > 16);
         assert_eq!(config.argon2_m_cost, 4096);
     }
+
+    #[test]
+    fn test_worker_runs_federation_sender() {
+        let mut config = WorkerConfig::default();
+        assert!(config.runs_federation_sender());
+
+        config.enabled = true;
+        assert!(config.runs_federation_sender());
+
+        config.federation_sender_instances = vec!["federation_sender1".to_string()];
+        assert!(!config.runs_federation_sender());
+
+        config.worker_app = Some("federation_sender".to_string());
+        assert!(config.runs_federation_sender());
+
+        config.worker_app = Some("synchrotron".to_string());
+        assert!(!config.runs_federation_sender());
+    }
+
+    #[test]
+    fn test_ui_auth_session_timeout() {
+        let mut config = UiAuthConfig::default();
+        assert_eq!(config.session_timeout_ms(), 15 * 60 * 1000);
+        assert_eq!(config.maximum_sessions, 100);
+
+        config.session_timeout = "30s".to_string();
+        assert_eq!(config.session_timeout_ms(), 30_000);
+
+        config.session_timeout = "soon".to_string();
+        assert_eq!(config.session_timeout_ms(), 15 * 60 * 1000);
+    }
 }
 
 /// SMTP邮件服务配置。
@@ -2707,44 +2885,6 @@ impl Default for SamlAttributeMapping {
 }
 */
 
-/*
-/// UI 认证配置。
-///
-/// 官方 Synapse 配置文档: https://matrix-org.github.io/synapse/latest/usage/configuration/config_documentation.html#ui_auth
-///
-/// 配置用户交互认证（UIAA）会话参数。
-///
-/// # 待实现功能
-/// - 会话超时配置
-/// - 认证流程配置
-/// - 重试策略
-///
-/// # 配置示例
-/// ```yaml
-/// ui_auth:
-///   session_timeout: "15m"
-///   maximum_sessions: 100
-/// ```
-#[derive(Debug, Clone, Deserialize)]
-pub struct UiAuthConfig {
-    /// 会话超时时间
-    #[serde(default = "default_ui_auth_session_timeout")]
-    pub session_timeout: String,
-
-    /// 最大会话数
-    #[serde(default = "default_max_ui_auth_sessions")]
-    pub maximum_sessions: u32,
-}
-
-fn default_ui_auth_session_timeout() -> String {
-    "15m".to_string()
-}
-
-fn default_max_ui_auth_sessions() -> u32 {
-    100
-}
-*/
-
 /*
 /// 房间配置。
 ///
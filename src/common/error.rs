@@ -38,6 +38,60 @@ impl<T> ApiResponse<T> {
     }
 }
 
+impl From<crate::spaces::SpaceError> for ApiError {
+    fn from(err: crate::spaces::SpaceError) -> Self {
+        match err {
+            crate::spaces::SpaceError::SpaceNotFound => ApiError::NotFound(err.to_string()),
+            crate::spaces::SpaceError::PermissionDenied => {
+                ApiError::Forbidden("You are not allowed to see this room".to_string())
+            }
+            crate::spaces::SpaceError::InvalidChild => ApiError::BadRequest(err.to_string()),
+            crate::spaces::SpaceError::InvalidPaginationToken => ApiError::Validation(err.to_string()),
+            crate::spaces::SpaceError::Database(e) => e.into(),
+        }
+    }
+}
+
+impl From<crate::services::KnockError> for ApiError {
+    fn from(err: crate::services::KnockError) -> Self {
+        use crate::services::KnockError;
+        match err {
+            KnockError::RoomNotFound | KnockError::KnockNotFound => ApiError::NotFound(err.to_string()),
+            KnockError::KnockDisabled | KnockError::NotAllowed(_) => ApiError::Forbidden(err.to_string()),
+            KnockError::RoomKnockLimitExceeded | KnockError::UserKnockLimitExceeded => ApiError::RateLimited,
+            KnockError::ReasonRequired => ApiError::BadRequest(err.to_string()),
+            KnockError::Database(e) => e.into(),
+        }
+    }
+}
+
+impl From<crate::services::TokenError> for ApiError {
+    fn from(err: crate::services::TokenError) -> Self {
+        use crate::services::TokenError;
+        match err {
+            TokenError::InvalidToken => ApiError::Unauthorized(err.to_string()),
+            TokenError::TokenNotFound => ApiError::NotFound(err.to_string()),
+            TokenError::TokenExists | TokenError::InvalidParam(_) => ApiError::Validation(err.to_string()),
+            TokenError::Database(e) => e.into(),
+        }
+    }
+}
+
+impl From<crate::services::PhoneError> for ApiError {
+    fn from(err: crate::services::PhoneError) -> Self {
+        use crate::services::PhoneError;
+        match err {
+            PhoneError::InvalidPhoneNumber => ApiError::InvalidInput(err.to_string()),
+            PhoneError::VerificationNotFound => ApiError::NotFound(err.to_string()),
+            PhoneError::CodeExpired | PhoneError::MaxAttemptsExceeded | PhoneError::AlreadyVerified => {
+                ApiError::BadRequest(err.to_string())
+            }
+            PhoneError::CooldownActive(_) => ApiError::RateLimited,
+            PhoneError::SmsSendFailed(_) => ApiError::Internal(err.to_string()),
+        }
+    }
+}
+
 impl<T> IntoResponse for ApiResponse<T>
 where
     T: Serialize,
@@ -51,7 +105,9 @@ where
                 Some("M_FORBIDDEN") => StatusCode::FORBIDDEN,
                 Some("M_UNAUTHORIZED") | Some("M_UNKNOWN_TOKEN") => StatusCode::UNAUTHORIZED,
                 Some("M_LIMIT_EXCEEDED") => StatusCode::TOO_MANY_REQUESTS,
-                Some("M_BAD_JSON") | Some("M_INVALID_PARAM") | Some("M_INVALID_INPUT") => {
+                Some("M_BAD_JSON") | Some("M_INVALID_PARAM") | Some("M_INVALID_INPUT")
+                | Some("M_UNKNOWN_POS") | Some("M_UNSUPPORTED_ROOM_VERSION")
+                | Some("M_INCOMPATIBLE_ROOM_VERSION") | Some("M_EXCLUSIVE") => {
                     StatusCode::BAD_REQUEST
                 }
                 Some("M_USER_IN_USE") => StatusCode::CONFLICT,
@@ -111,6 +167,22 @@ pub enum ApiError {
 
     #[error("Gone: {0}")]
     Gone(String),
+
+    #[error("Unknown position: {0}")]
+    UnknownPos(String),
+
+    #[error("Unsupported room version: {0}")]
+    UnsupportedRoomVersion(String),
+
+    #[error("Incompatible room version: {0}")]
+    IncompatibleRoomVersion(String),
+
+    #[error("Exclusive: {0}")]
+    Exclusive(String),
+
+    /// A user-interactive authentication response: the flows, params and session.
+    #[error("User-interactive authentication required")]
+    AuthRequired(serde_json::Value),
 }
 
 impl ApiError {
@@ -150,6 +222,28 @@ impl ApiError {
         Self::Gone(message.into())
     }
 
+    pub fn unknown_pos(message: impl Into<String>) -> Self {
+        Self::UnknownPos(message.into())
+    }
+
+    pub fn unsupported_room_version(message: impl Into<String>) -> Self {
+        Self::UnsupportedRoomVersion(message.into())
+    }
+
+    pub fn incompatible_room_version(message: impl Into<String>) -> Self {
+        Self::IncompatibleRoomVersion(message.into())
+    }
+
+    /// The ID or alias is reserved by an application service.
+    pub fn exclusive(message: impl Into<String>) -> Self {
+        Self::Exclusive(message.into())
+    }
+
+    /// Asks the client to complete user-interactive authentication stages.
+    pub fn auth_required(body: serde_json::Value) -> Self {
+        Self::AuthRequired(body)
+    }
+
     pub fn authentication(message: impl Into<String>) -> Self {
         Self::Authentication(message.into())
     }
@@ -184,6 +278,11 @@ impl ApiError {
             ApiError::EncryptionError(_) => "M_ENCRYPTION_FAILED",
             ApiError::Crypto(_) => "M_CRYPTO_ERROR",
             ApiError::Gone(_) => "M_GONE",
+            ApiError::UnknownPos(_) => "M_UNKNOWN_POS",
+            ApiError::UnsupportedRoomVersion(_) => "M_UNSUPPORTED_ROOM_VERSION",
+            ApiError::IncompatibleRoomVersion(_) => "M_INCOMPATIBLE_ROOM_VERSION",
+            ApiError::Exclusive(_) => "M_EXCLUSIVE",
+            ApiError::AuthRequired(_) => "M_UNAUTHORIZED",
         }
     }
 
@@ -205,6 +304,14 @@ impl ApiError {
             ApiError::EncryptionError(msg) => msg.clone(),
             ApiError::Crypto(msg) => msg.clone(),
             ApiError::Gone(msg) => msg.clone(),
+            ApiError::UnknownPos(msg) => msg.clone(),
+            ApiError::UnsupportedRoomVersion(msg) => msg.clone(),
+            ApiError::IncompatibleRoomVersion(msg) => msg.clone(),
+            ApiError::Exclusive(msg) => msg.clone(),
+            ApiError::AuthRequired(body) => body["error"]
+                .as_str()
+                .unwrap_or("User-interactive authentication required")
+                .to_string(),
         }
     }
 }
@@ -220,6 +327,11 @@ impl IntoResponse for ApiError {
             }))).into_response();
         }
 
+        // User-interactive authentication responses carry the flows as the body
+        if let ApiError::AuthRequired(body) = self {
+            return (StatusCode::UNAUTHORIZED, Json(body)).into_response();
+        }
+
         let errcode = self.code().to_string();
         let error_msg = self.message();
 
@@ -415,6 +527,17 @@ mod tests {
         assert_eq!(response.status(), StatusCode::INTERNAL_SERVER_ERROR);
     }
 
+    #[test]
+    fn test_into_response_auth_required() {
+        let error = ApiError::auth_required(json!({
+            "session": "abc",
+            "flows": [{ "stages": ["m.login.registration_token"] }],
+            "params": {}
+        }));
+        let response = error.into_response();
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+    }
+
     #[test]
     fn test_from_sqlx_error() {
         let sqlx_error = sqlx::Error::RowNotFound;
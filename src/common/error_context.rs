@@ -64,6 +64,7 @@ impl ErrorContext for ApiError {
             | ApiError::NotFound(_)
             | ApiError::Gone(_)
             | ApiError::Conflict(_)
+            | ApiError::AuthRequired(_)
             | ApiError::RateLimited => ErrorLayer::BusinessLogic,
             ApiError::Internal(_)
             | ApiError::Authentication(_)
@@ -72,7 +73,11 @@ impl ErrorContext for ApiError {
             | ApiError::DecryptionError(_)
             | ApiError::EncryptionError(_)
             | ApiError::Crypto(_)
-            | ApiError::BadRequest(_) => ErrorLayer::Application,
+            | ApiError::BadRequest(_)
+            | ApiError::UnknownPos(_)
+            | ApiError::UnsupportedRoomVersion(_)
+            | ApiError::IncompatibleRoomVersion(_)
+            | ApiError::Exclusive(_) => ErrorLayer::Application,
         }
     }
 
@@ -87,7 +92,15 @@ impl ErrorContext for ApiError {
             ApiError::Gone(_) | ApiError::NotFound(_) | ApiError::Conflict(_) | ApiError::Validation(_) => {
                 ErrorSeverity::Medium
             }
-            ApiError::BadRequest(_) | ApiError::InvalidInput(_) => ErrorSeverity::Low,
+            ApiError::BadRequest(_)
+            | ApiError::InvalidInput(_)
+            | ApiError::UnknownPos(_)
+            | ApiError::UnsupportedRoomVersion(_)
+            | ApiError::IncompatibleRoomVersion(_)
+            | ApiError::Exclusive(_)
+            | ApiError::AuthRequired(_) => {
+                ErrorSeverity::Low
+            }
             ApiError::Authentication(_)
             | ApiError::DecryptionError(_)
             | ApiError::EncryptionError(_)
@@ -102,6 +115,11 @@ impl ErrorContext for ApiError {
             ApiError::Forbidden(msg) => msg.clone(),
             ApiError::NotFound(msg) => msg.clone(),
             ApiError::Gone(msg) => msg.clone(),
+            ApiError::UnknownPos(msg) => msg.clone(),
+            ApiError::UnsupportedRoomVersion(msg) => msg.clone(),
+            ApiError::IncompatibleRoomVersion(msg) => msg.clone(),
+            ApiError::Exclusive(msg) => msg.clone(),
+            ApiError::AuthRequired(_) => self.message(),
             ApiError::Conflict(msg) => msg.clone(),
             ApiError::RateLimited => "Too many requests. Please try again later.".to_string(),
             ApiError::Internal(_) => {
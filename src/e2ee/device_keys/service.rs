@@ -66,6 +66,22 @@ impl DeviceKeyService {
     pub async fn upload_keys(
         &self,
         request: KeyUploadRequest,
+    ) -> Result<KeyUploadResponse, ApiError> {
+        let (user_id, device_id) = request
+            .device_keys
+            .as_ref()
+            .map(|dk| (dk.user_id.clone(), dk.device_id.clone()))
+            .unwrap_or_default();
+        self.upload_keys_for_device(&user_id, &device_id, request)
+            .await
+    }
+
+    /// 上传密钥，一次性密钥归属于已认证的设备，而不依赖请求中是否带有 `device_keys`。
+    pub async fn upload_keys_for_device(
+        &self,
+        user_id: &str,
+        device_id: &str,
+        request: KeyUploadRequest,
     ) -> Result<KeyUploadResponse, ApiError> {
         let mut one_time_key_counts = serde_json::Map::new();
 
@@ -102,11 +118,7 @@ impl DeviceKeyService {
         }
 
         if let Some(ref one_time_keys) = request.one_time_keys {
-            let (user_id, device_id) = if let Some(ref dk) = request.device_keys {
-                (dk.user_id.clone(), dk.device_id.clone())
-            } else {
-                (String::new(), String::new())
-            };
+            let (user_id, device_id) = (user_id.to_string(), device_id.to_string());
 
             if let Some(keys) = one_time_keys.as_object() {
                 for (key_id, key_data) in keys {
@@ -139,16 +151,17 @@ impl DeviceKeyService {
                 }
             }
 
-            if !user_id.is_empty() {
-                let count = self
-                    .storage
-                    .get_one_time_keys_count(&user_id, &device_id)
-                    .await?;
-                one_time_key_counts.insert(
-                    "signed_curve25519".to_string(),
-                    serde_json::Value::Number(count.into()),
-                );
-            }
+        }
+
+        if !user_id.is_empty() {
+            let count = self
+                .storage
+                .get_one_time_keys_count(user_id, device_id)
+                .await?;
+            one_time_key_counts.insert(
+                "signed_curve25519".to_string(),
+                serde_json::Value::Number(count.into()),
+            );
         }
 
         Ok(KeyUploadResponse {
@@ -167,20 +180,36 @@ impl DeviceKeyService {
                 if let Some(keys) = device_keys.as_object() {
                     for (device_id, algorithm) in keys {
                         if let Some(algo_str) = algorithm.as_str() {
-                            if let Some(key) = self
+                            let (key, fallback) = match self
                                 .storage
                                 .claim_one_time_key(user_id, device_id, algo_str)
                                 .await?
                             {
-                                let key_data = serde_json::json!({
+                                Some(key) => (Some(key), false),
+                                None => (
+                                    self.storage
+                                        .claim_fallback_key(user_id, device_id, algo_str)
+                                        .await?,
+                                    true,
+                                ),
+                            };
+
+                            if let Some(key) = key {
+                                let mut key_data = serde_json::json!({
                                     "key": key.public_key,
                                     "signatures": key.signatures,
                                 });
+                                if fallback {
+                                    key_data["fallback"] = serde_json::Value::Bool(true);
+                                }
+                                let key_id = if key.key_id.contains(':') {
+                                    key.key_id
+                                } else {
+                                    format!("{}:{}", algo_str, key.key_id)
+                                };
                                 user_keys.insert(
                                     device_id.clone(),
-                                    serde_json::json!({
-                                        format!("{}:{}", algo_str, key.key_id): key_data
-                                    }),
+                                    serde_json::json!({ key_id: key_data }),
                                 );
                             }
                         }
@@ -206,6 +235,74 @@ impl DeviceKeyService {
         Ok(())
     }
 
+    /// 上传备用密钥，格式与 `one_time_keys` 相同，例如 `signed_curve25519:AAAAGj`。
+    pub async fn upload_fallback_keys(
+        &self,
+        user_id: &str,
+        device_id: &str,
+        fallback_keys: &serde_json::Value,
+    ) -> Result<(), ApiError> {
+        let Some(keys) = fallback_keys.as_object() else {
+            return Ok(());
+        };
+
+        for (key_id, key_data) in keys {
+            let algorithm = key_id.split(':').next().unwrap_or_default().to_string();
+            let key = DeviceKey {
+                id: 0, // 数据库自动生成
+                user_id: user_id.to_string(),
+                device_id: device_id.to_string(),
+                display_name: None,
+                algorithm,
+                key_id: key_id.clone(),
+                public_key: key_data
+                    .as_str()
+                    .or_else(|| key_data["key"].as_str())
+                    .unwrap_or_default()
+                    .to_string(),
+                signatures: if key_data.is_object() {
+                    key_data["signatures"].clone()
+                } else {
+                    serde_json::json!({})
+                },
+                created_at: Utc::now(),
+                updated_at: Utc::now(),
+            };
+
+            self.storage.replace_fallback_key(&key).await?;
+        }
+
+        Ok(())
+    }
+
+    pub async fn get_one_time_key_counts(
+        &self,
+        user_id: &str,
+        device_id: &str,
+    ) -> Result<serde_json::Value, ApiError> {
+        let mut counts = serde_json::Map::new();
+        // 客户端依赖该字段判断是否需要补充密钥，因此即使为 0 也要返回
+        counts.insert("signed_curve25519".to_string(), serde_json::json!(0));
+        for (algorithm, count) in self
+            .storage
+            .get_one_time_key_counts(user_id, device_id)
+            .await?
+        {
+            counts.insert(algorithm, serde_json::json!(count));
+        }
+        Ok(serde_json::Value::Object(counts))
+    }
+
+    pub async fn get_unused_fallback_key_types(
+        &self,
+        user_id: &str,
+        device_id: &str,
+    ) -> Result<Vec<String>, ApiError> {
+        self.storage
+            .get_unused_fallback_key_types(user_id, device_id)
+            .await
+    }
+
     pub async fn get_key_changes(
         &self,
         from: &str,
@@ -3,6 +3,10 @@ use crate::error::ApiError;
 use sqlx::{PgPool, Row};
 use std::sync::Arc;
 
+/// 备用密钥以带前缀的算法名存储，以便与一次性密钥区分。
+const FALLBACK_PREFIX: &str = "fallback:";
+const USED_FALLBACK_PREFIX: &str = "fallback_used:";
+
 #[derive(Clone)]
 pub struct DeviceKeyStorage {
     pub pool: Arc<PgPool>,
@@ -246,6 +250,117 @@ impl DeviceKeyStorage {
         Ok(row.get("count"))
     }
 
+    /// 按算法统计未被领取的一次性密钥数量（不含设备身份密钥和备用密钥）。
+    pub async fn get_one_time_key_counts(
+        &self,
+        user_id: &str,
+        device_id: &str,
+    ) -> Result<Vec<(String, i64)>, ApiError> {
+        let rows = sqlx::query(
+            r#"
+            SELECT algorithm, COUNT(*) as count
+            FROM device_keys
+            WHERE user_id = $1 AND device_id = $2
+              AND algorithm NOT IN ('curve25519', 'ed25519')
+              AND algorithm NOT LIKE 'fallback%'
+            GROUP BY algorithm
+            "#,
+        )
+        .bind(user_id)
+        .bind(device_id)
+        .fetch_all(&*self.pool)
+        .await?;
+
+        Ok(rows
+            .into_iter()
+            .map(|row| (row.get("algorithm"), row.get("count")))
+            .collect())
+    }
+
+    /// 替换某个算法的备用密钥。新上传的备用密钥总是处于未使用状态。
+    pub async fn replace_fallback_key(&self, key: &DeviceKey) -> Result<(), ApiError> {
+        sqlx::query(
+            r#"
+            DELETE FROM device_keys
+            WHERE user_id = $1 AND device_id = $2 AND algorithm IN ($3, $4)
+            "#,
+        )
+        .bind(&key.user_id)
+        .bind(&key.device_id)
+        .bind(format!("{}{}", FALLBACK_PREFIX, key.algorithm))
+        .bind(format!("{}{}", USED_FALLBACK_PREFIX, key.algorithm))
+        .execute(&*self.pool)
+        .await?;
+
+        self.create_device_key(&DeviceKey {
+            algorithm: format!("{}{}", FALLBACK_PREFIX, key.algorithm),
+            ..key.clone()
+        })
+        .await
+    }
+
+    pub async fn get_unused_fallback_key_types(
+        &self,
+        user_id: &str,
+        device_id: &str,
+    ) -> Result<Vec<String>, ApiError> {
+        let rows = sqlx::query(
+            r#"
+            SELECT DISTINCT algorithm
+            FROM device_keys
+            WHERE user_id = $1 AND device_id = $2 AND algorithm LIKE 'fallback:%'
+            "#,
+        )
+        .bind(user_id)
+        .bind(device_id)
+        .fetch_all(&*self.pool)
+        .await?;
+
+        Ok(rows
+            .into_iter()
+            .filter_map(|row| {
+                let algorithm: String = row.get("algorithm");
+                algorithm.strip_prefix(FALLBACK_PREFIX).map(str::to_string)
+            })
+            .collect())
+    }
+
+    /// 领取备用密钥。备用密钥不会被删除，只会被标记为已使用，直到客户端上传新的备用密钥。
+    pub async fn claim_fallback_key(
+        &self,
+        user_id: &str,
+        device_id: &str,
+        algorithm: &str,
+    ) -> Result<Option<DeviceKey>, ApiError> {
+        let row = sqlx::query(
+            r#"
+            UPDATE device_keys
+            SET algorithm = $4
+            WHERE user_id = $1 AND device_id = $2 AND algorithm IN ($3, $4)
+            RETURNING id, user_id, device_id, display_name, algorithm, key_id, public_key, signatures, created_at, updated_at
+            "#,
+        )
+        .bind(user_id)
+        .bind(device_id)
+        .bind(format!("{}{}", FALLBACK_PREFIX, algorithm))
+        .bind(format!("{}{}", USED_FALLBACK_PREFIX, algorithm))
+        .fetch_optional(&*self.pool)
+        .await?;
+
+        Ok(row.map(|row| DeviceKey {
+            id: row.get("id"),
+            user_id: row.get("user_id"),
+            device_id: row.get("device_id"),
+            display_name: row.get("display_name"),
+            algorithm: algorithm.to_string(),
+            key_id: row.get("key_id"),
+            public_key: row.get("public_key"),
+            signatures: row.get("signatures"),
+            created_at: row.get("created_at"),
+            updated_at: row.get("updated_at"),
+        }))
+    }
+
     pub async fn claim_one_time_key(
         &self,
         user_id: &str,
@@ -255,7 +370,12 @@ impl DeviceKeyStorage {
         let row = sqlx::query(
             r#"
             DELETE FROM device_keys
-            WHERE user_id = $1 AND device_id = $2 AND algorithm = $3
+            WHERE user_id = $1 AND device_id = $2 AND key_id = (
+                SELECT key_id FROM device_keys
+                WHERE user_id = $1 AND device_id = $2 AND algorithm = $3
+                ORDER BY ts_added_ms ASC
+                LIMIT 1
+            )
             RETURNING id, user_id, device_id, display_name, algorithm, key_id, public_key, signatures, created_at, updated_at
             "#
         )
@@ -1,12 +1,21 @@
 use super::storage::ToDeviceStorage;
+use crate::appservice::AppServiceScheduler;
 use crate::error::ApiError;
-use crate::storage::UserStorage;
-use serde_json::Value;
+use crate::services::notifier::{Notifier, NotifierStream};
+use crate::storage::{FederationQueueStorage, UserStorage};
+use serde_json::{json, Map, Value};
+use std::collections::HashMap;
+use std::sync::Arc;
+
+const MAX_TO_DEVICE_PER_SYNC: i64 = 100;
 
 #[derive(Clone)]
 pub struct ToDeviceService {
     storage: ToDeviceStorage,
     user_storage: Option<UserStorage>, // Made optional to avoid breaking tests if any
+    notifier: Option<Notifier>,
+    federation_queue: Option<FederationQueueStorage>,
+    appservices: Option<Arc<AppServiceScheduler>>,
 }
 
 impl ToDeviceService {
@@ -14,6 +23,9 @@ impl ToDeviceService {
         Self {
             storage,
             user_storage: None,
+            notifier: None,
+            federation_queue: None,
+            appservices: None,
         }
     }
 
@@ -22,9 +34,46 @@ impl ToDeviceService {
         self
     }
 
-    pub async fn send_messages(&self, _sender_id: &str, messages: &Value) -> Result<(), ApiError> {
+    pub fn with_notifier(mut self, notifier: Notifier) -> Self {
+        self.notifier = Some(notifier);
+        self
+    }
+
+    /// Messages for users on other servers are sent as `m.direct_to_device` EDUs.
+    pub fn with_federation_queue(mut self, federation_queue: FederationQueueStorage) -> Self {
+        self.federation_queue = Some(federation_queue);
+        self
+    }
+
+    /// Messages for users in an application service's namespace are also pushed to it.
+    pub fn with_appservices(mut self, appservices: Arc<AppServiceScheduler>) -> Self {
+        self.appservices = Some(appservices);
+        self
+    }
+
+    pub async fn send_messages(
+        &self,
+        sender_id: &str,
+        event_type: &str,
+        messages: &Value,
+    ) -> Result<(), ApiError> {
+        let mut recipients = Vec::new();
+        let mut remote: HashMap<&str, Map<String, Value>> = HashMap::new();
         if let Some(msg_map) = messages.as_object() {
             for (user_id, devices) in msg_map {
+                if let Some(queue) = &self.federation_queue {
+                    match user_id.split_once(':') {
+                        Some((_, server)) if server != queue.server_name() => {
+                            remote
+                                .entry(server)
+                                .or_default()
+                                .insert(user_id.clone(), devices.clone());
+                            continue;
+                        }
+                        _ => {}
+                    }
+                }
+
                 // Check if user exists if user_storage is available
                 if let Some(user_storage) = &self.user_storage {
                     if !user_storage
@@ -42,27 +91,81 @@ impl ToDeviceService {
 
                 if let Some(device_map) = devices.as_object() {
                     for (device_id, content) in device_map {
-                        // In a real implementation, we might want to extract the message type
-                        // from the content or pass it separately.
-                        let msg_type = content
-                            .get("type")
-                            .and_then(|t| t.as_str())
-                            .unwrap_or("m.room.message");
-                        self.storage
-                            .add_message(user_id, device_id, msg_type, content.clone())
-                            .await?;
+                        if device_id == "*" {
+                            self.storage
+                                .add_message_for_all_devices(sender_id, user_id, event_type, content.clone())
+                                .await?;
+                        } else {
+                            self.storage
+                                .add_message(sender_id, user_id, device_id, event_type, content.clone())
+                                .await?;
+                        }
+                        if let Some(appservices) = &self.appservices {
+                            appservices
+                                .handle_to_device(sender_id, user_id, device_id, event_type, content)
+                                .await;
+                        }
                     }
+                    recipients.push(user_id.clone());
                 }
             }
         }
+
+        if let Some(queue) = &self.federation_queue {
+            for (server, messages) in remote {
+                let edu = json!({
+                    "edu_type": "m.direct_to_device",
+                    "content": {
+                        "sender": sender_id,
+                        "type": event_type,
+                        "message_id": uuid::Uuid::new_v4().to_string(),
+                        "messages": messages,
+                    }
+                });
+                queue
+                    .enqueue_edu(&[server.to_string()], &edu)
+                    .await
+                    .map_err(|e| ApiError::internal(format!("Failed to queue to-device messages: {}", e)))?;
+            }
+        }
+
+        if let Some(notifier) = &self.notifier {
+            notifier
+                .notify_users(NotifierStream::ToDevice, &recipients)
+                .await;
+        }
         Ok(())
     }
 
+    /// Returns the device's pending to-device events for /sync.
+    ///
+    /// Messages are only deleted once acknowledged: everything delivered with a response is
+    /// tagged with its `next_batch` position, and removed when the client syncs with a since
+    /// token at or past that position. Unacknowledged messages are delivered again.
     pub async fn get_messages_for_sync(
         &self,
         user_id: &str,
         device_id: &str,
+        since: Option<i64>,
+        next_batch: i64,
     ) -> Result<Vec<Value>, ApiError> {
-        self.storage.get_messages(user_id, device_id).await
+        if let Some(since) = since {
+            self.storage
+                .delete_acknowledged(user_id, device_id, since)
+                .await?;
+        }
+
+        let pending = self
+            .storage
+            .get_pending_messages(user_id, device_id, MAX_TO_DEVICE_PER_SYNC)
+            .await?;
+        if pending.is_empty() {
+            return Ok(vec![]);
+        }
+
+        let ids: Vec<i64> = pending.iter().map(|(id, _)| *id).collect();
+        self.storage.mark_delivered(&ids, next_batch).await?;
+
+        Ok(pending.into_iter().map(|(_, message)| message).collect())
     }
 }
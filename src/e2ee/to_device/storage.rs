@@ -15,6 +15,7 @@ impl ToDeviceStorage {
 
     pub async fn add_message(
         &self,
+        sender: &str,
         user_id: &str,
         device_id: &str,
         message_type: &str,
@@ -23,10 +24,11 @@ impl ToDeviceStorage {
         let now = chrono::Utc::now().timestamp_millis();
         sqlx::query(
             r#"
-            INSERT INTO to_device_messages (user_id, device_id, message_type, content, created_ts)
-            VALUES ($1, $2, $3, $4, $5)
+            INSERT INTO to_device_messages (sender, user_id, device_id, message_type, content, created_ts)
+            VALUES ($1, $2, $3, $4, $5, $6)
             "#,
         )
+        .bind(sender)
         .bind(user_id)
         .bind(device_id)
         .bind(message_type)
@@ -39,38 +41,128 @@ impl ToDeviceStorage {
         Ok(())
     }
 
+    /// Fans a message out to every device the user currently has, for the `*` device ID.
+    pub async fn add_message_for_all_devices(
+        &self,
+        sender: &str,
+        user_id: &str,
+        message_type: &str,
+        content: Value,
+    ) -> Result<(), ApiError> {
+        let now = chrono::Utc::now().timestamp_millis();
+        sqlx::query(
+            r#"
+            INSERT INTO to_device_messages (sender, user_id, device_id, message_type, content, created_ts)
+            SELECT $1, user_id, device_id, $3, $4, $5 FROM devices WHERE user_id = $2
+            "#,
+        )
+        .bind(sender)
+        .bind(user_id)
+        .bind(message_type)
+        .bind(content)
+        .bind(now)
+        .execute(&*self.pool)
+        .await
+        .map_err(|e| ApiError::internal(format!("Database error: {}", e)))?;
+
+        Ok(())
+    }
+
     pub async fn get_messages(
         &self,
         user_id: &str,
         device_id: &str,
     ) -> Result<Vec<Value>, ApiError> {
+        Ok(self
+            .get_pending_messages(user_id, device_id, i64::MAX)
+            .await?
+            .into_iter()
+            .map(|(_, message)| message)
+            .collect())
+    }
+
+    /// Returns up to `limit` messages still in the inbox, oldest first, with their row IDs.
+    pub async fn get_pending_messages(
+        &self,
+        user_id: &str,
+        device_id: &str,
+        limit: i64,
+    ) -> Result<Vec<(i64, Value)>, ApiError> {
         let rows = sqlx::query(
             r#"
-            SELECT id, message_type, content, created_ts
+            SELECT id, sender, message_type, content
             FROM to_device_messages
             WHERE user_id = $1 AND device_id = $2
-            ORDER BY created_ts ASC
+            ORDER BY id ASC
+            LIMIT $3
             "#,
         )
         .bind(user_id)
         .bind(device_id)
+        .bind(limit)
         .fetch_all(&*self.pool)
         .await
         .map_err(|e| ApiError::internal(format!("Database error: {}", e)))?;
 
         let mut messages = Vec::new();
         for row in rows {
+            let id: i64 = row.get("id");
+            let sender: Option<String> = row.get("sender");
             let msg_type: String = row.get("message_type");
             let content: Value = row.get("content");
-            messages.push(serde_json::json!({
-                "type": msg_type,
-                "content": content
-            }));
+            messages.push((
+                id,
+                serde_json::json!({
+                    "sender": sender.unwrap_or_default(),
+                    "type": msg_type,
+                    "content": content
+                }),
+            ));
         }
 
         Ok(messages)
     }
 
+    /// Records the sync position a batch of messages was delivered at.
+    pub async fn mark_delivered(&self, ids: &[i64], stream_id: i64) -> Result<(), ApiError> {
+        sqlx::query(
+            r#"
+            UPDATE to_device_messages SET processed_ts = $2 WHERE id = ANY($1)
+            "#,
+        )
+        .bind(ids)
+        .bind(stream_id)
+        .execute(&*self.pool)
+        .await
+        .map_err(|e| ApiError::internal(format!("Database error: {}", e)))?;
+
+        Ok(())
+    }
+
+    /// Deletes messages delivered at or before `stream_id`. A client syncing with a since
+    /// token acknowledges everything that was returned alongside that token.
+    pub async fn delete_acknowledged(
+        &self,
+        user_id: &str,
+        device_id: &str,
+        stream_id: i64,
+    ) -> Result<u64, ApiError> {
+        let result = sqlx::query(
+            r#"
+            DELETE FROM to_device_messages
+            WHERE user_id = $1 AND device_id = $2 AND processed_ts IS NOT NULL AND processed_ts <= $3
+            "#,
+        )
+        .bind(user_id)
+        .bind(device_id)
+        .bind(stream_id)
+        .execute(&*self.pool)
+        .await
+        .map_err(|e| ApiError::internal(format!("Database error: {}", e)))?;
+
+        Ok(result.rows_affected())
+    }
+
     pub async fn delete_messages(&self, ids: &[i64]) -> Result<(), ApiError> {
         sqlx::query(
             r#"
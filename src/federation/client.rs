@@ -0,0 +1,881 @@
+//! Outbound federation requests: server discovery, `X-Matrix` request signing, access control
+//! and per-destination backoff.
+
+use crate::common::ApiError;
+use crate::federation::access_control::FederationAccessControl;
+use crate::federation::discovery::{DnsResolver, ServerResolver, SystemDnsResolver};
+use crate::federation::event_builder::decode_base64_32;
+use crate::federation::key_rotation::KeyRotationManager;
+use crate::federation::pdu;
+use base64::Engine;
+use ed25519_dalek::Signer;
+use reqwest::{Method, StatusCode};
+use serde_json::{json, Value};
+use std::collections::HashMap;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::RwLock;
+
+const BACKOFF_INITIAL_MS: i64 = 5_000;
+const BACKOFF_MAX_MS: i64 = 60 * 60 * 1000;
+
+#[derive(Debug, Clone)]
+pub struct FederationClientConfig {
+    /// Plain HTTP is only meant for local test stand-ins.
+    pub use_tls: bool,
+    /// Port `.well-known/matrix/server` is fetched from.
+    pub well_known_port: u16,
+    pub request_timeout: Duration,
+    /// Extra CA certificate (PEM) trusted for remote servers.
+    pub ca_file: Option<PathBuf>,
+}
+
+impl Default for FederationClientConfig {
+    fn default() -> Self {
+        Self {
+            use_tls: true,
+            well_known_port: 443,
+            request_timeout: Duration::from_secs(30),
+            ca_file: None,
+        }
+    }
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum FederationClientError {
+    #[error("Federation with {0} is not allowed")]
+    Forbidden(String),
+    #[error("Backing off from {destination} for {retry_after_ms}ms")]
+    BackingOff { destination: String, retry_after_ms: i64 },
+    #[error("Failed to resolve {0}")]
+    Discovery(String),
+    #[error("No signing key available: {0}")]
+    Signing(String),
+    #[error("Request failed: {0}")]
+    Request(String),
+    #[error("Remote server returned {status}")]
+    Http { status: u16, body: Value },
+    #[error("Invalid response: {0}")]
+    InvalidResponse(String),
+}
+
+impl FederationClientError {
+    pub fn status(&self) -> Option<u16> {
+        match self {
+            Self::Http { status, .. } => Some(*status),
+            _ => None,
+        }
+    }
+}
+
+impl From<FederationClientError> for ApiError {
+    fn from(e: FederationClientError) -> Self {
+        match &e {
+            FederationClientError::Forbidden(_) => ApiError::forbidden(e.to_string()),
+            FederationClientError::Http { status: 404, .. } => ApiError::not_found(e.to_string()),
+            FederationClientError::Http { status: 403, body } => ApiError::forbidden(remote_error(body, &e)),
+            FederationClientError::Http { status: 400, body }
+                if body.get("errcode").and_then(|c| c.as_str()) == Some("M_INCOMPATIBLE_ROOM_VERSION") =>
+            {
+                ApiError::incompatible_room_version(remote_error(body, &e))
+            }
+            _ => ApiError::internal(e.to_string()),
+        }
+    }
+}
+
+/// The membership handshakes made through a resident server when we are not in a room.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MembershipHandshake {
+    Join,
+    Leave,
+    Knock,
+}
+
+impl MembershipHandshake {
+    pub fn membership(&self) -> &'static str {
+        match self {
+            Self::Join => "join",
+            Self::Leave => "leave",
+            Self::Knock => "knock",
+        }
+    }
+
+    /// The `send_*` endpoint; join and leave use v2, which returns the response unwrapped.
+    fn send_path(&self, room_id: &str, event_id: &str) -> String {
+        let (version, endpoint, query) = match self {
+            Self::Join => ("v2", "send_join", "?omit_members=true"),
+            Self::Leave => ("v2", "send_leave", ""),
+            Self::Knock => ("v1", "send_knock", ""),
+        };
+        format!(
+            "/_matrix/federation/{}/{}/{}/{}{}",
+            version,
+            endpoint,
+            encode_path_segment(room_id),
+            encode_path_segment(event_id),
+            query
+        )
+    }
+}
+
+/// The `error` a remote server gave, falling back to the status line.
+fn remote_error(body: &Value, e: &FederationClientError) -> String {
+    body.get("error")
+        .and_then(|v| v.as_str())
+        .map(String::from)
+        .unwrap_or_else(|| e.to_string())
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Backoff {
+    failures: u32,
+    retry_at: i64,
+}
+
+/// Bytes covered by the `X-Matrix` signature of a request.
+pub fn request_signing_bytes(
+    method: &str,
+    uri: &str,
+    origin: &str,
+    destination: &str,
+    content: Option<&Value>,
+) -> Vec<u8> {
+    let mut obj = serde_json::Map::new();
+    obj.insert("method".to_string(), Value::String(method.to_string()));
+    obj.insert("uri".to_string(), Value::String(uri.to_string()));
+    obj.insert("origin".to_string(), Value::String(origin.to_string()));
+    obj.insert("destination".to_string(), Value::String(destination.to_string()));
+    if let Some(content) = content {
+        obj.insert("content".to_string(), content.clone());
+    }
+    pdu::canonical_json_bytes(&Value::Object(obj))
+}
+
+/// Shared client for every request this server makes to other homeservers.
+pub struct FederationClient {
+    server_name: String,
+    config: FederationClientConfig,
+    http: reqwest::Client,
+    resolver: Arc<ServerResolver>,
+    key_rotation_manager: KeyRotationManager,
+    access_control: Arc<FederationAccessControl>,
+    backoff: RwLock<HashMap<String, Backoff>>,
+}
+
+impl FederationClient {
+    pub fn new(
+        server_name: &str,
+        key_rotation_manager: KeyRotationManager,
+        access_control: Arc<FederationAccessControl>,
+        config: FederationClientConfig,
+    ) -> Self {
+        let (resolver, http) = Self::build_transport(&config, Arc::new(SystemDnsResolver::new()));
+        Self {
+            server_name: server_name.to_string(),
+            config,
+            http,
+            resolver,
+            key_rotation_manager,
+            access_control,
+            backoff: RwLock::new(HashMap::new()),
+        }
+    }
+
+    /// Replaces system DNS, e.g. with a local stand-in.
+    pub fn with_dns_resolver(mut self, dns: Arc<dyn DnsResolver>) -> Self {
+        let (resolver, http) = Self::build_transport(&self.config, dns);
+        self.resolver = resolver;
+        self.http = http;
+        self
+    }
+
+    fn build_transport(
+        config: &FederationClientConfig,
+        dns: Arc<dyn DnsResolver>,
+    ) -> (Arc<ServerResolver>, reqwest::Client) {
+        let resolver = Arc::new(ServerResolver::new(dns, config.use_tls, config.well_known_port));
+
+        let mut builder = reqwest::Client::builder()
+            .timeout(config.request_timeout)
+            .dns_resolver(resolver.connector_dns());
+        if let Some(ca_file) = &config.ca_file {
+            match std::fs::read(ca_file).map(|pem| reqwest::Certificate::from_pem(&pem)) {
+                Ok(Ok(cert)) => builder = builder.add_root_certificate(cert),
+                Ok(Err(e)) => tracing::warn!("Invalid federation CA file {}: {}", ca_file.display(), e),
+                Err(e) => tracing::warn!("Failed to read federation CA file {}: {}", ca_file.display(), e),
+            }
+        }
+        let http = builder.build().unwrap_or_else(|e| {
+            tracing::warn!("Failed to build federation HTTP client, using default: {}", e);
+            reqwest::Client::new()
+        });
+        (resolver, http)
+    }
+
+    pub fn server_name(&self) -> &str {
+        &self.server_name
+    }
+
+    pub fn resolver(&self) -> &ServerResolver {
+        &self.resolver
+    }
+
+    pub async fn get_json(&self, destination: &str, path: &str) -> Result<Value, FederationClientError> {
+        self.send(Method::GET, destination, path, None, true).await
+    }
+
+    pub async fn put_json(&self, destination: &str, path: &str, body: &Value) -> Result<Value, FederationClientError> {
+        self.send(Method::PUT, destination, path, Some(body), true).await
+    }
+
+    pub async fn post_json(&self, destination: &str, path: &str, body: &Value) -> Result<Value, FederationClientError> {
+        self.send(Method::POST, destination, path, Some(body), true).await
+    }
+
+    /// Fetches `/_matrix/key/v2/server`, which is served without authentication.
+    pub async fn get_server_keys(&self, destination: &str) -> Result<Value, FederationClientError> {
+        self.send(Method::GET, destination, "/_matrix/key/v2/server", None, false)
+            .await
+    }
+
+    /// Sends PDUs and EDUs as a `/send` transaction.
+    pub async fn send_transaction(
+        &self,
+        destination: &str,
+        transaction_id: &str,
+        pdus: &[Value],
+        edus: &[Value],
+    ) -> Result<Value, FederationClientError> {
+        let body = json!({
+            "origin": self.server_name,
+            "origin_server_ts": chrono::Utc::now().timestamp_millis(),
+            "pdus": pdus,
+            "edus": edus,
+        });
+        let path = format!("/_matrix/federation/v1/send/{}", transaction_id);
+        self.put_json(destination, &path, &body).await
+    }
+
+    /// Asks `destination` for the events between `earliest_events` and `latest_events`, oldest
+    /// first, down to `min_depth`.
+    pub async fn get_missing_events(
+        &self,
+        destination: &str,
+        room_id: &str,
+        earliest_events: &[String],
+        latest_events: &[String],
+        limit: usize,
+        min_depth: i64,
+    ) -> Result<Vec<Value>, FederationClientError> {
+        let body = json!({
+            "earliest_events": earliest_events,
+            "latest_events": latest_events,
+            "limit": limit,
+            "min_depth": min_depth,
+        });
+        let path = format!("/_matrix/federation/v1/get_missing_events/{}", encode_path_segment(room_id));
+        let response = self.post_json(destination, &path, &body).await?;
+        pdus_from_response(&response, "events")
+    }
+
+    /// Fetches up to `limit` events preceding (and including) `from` in the room DAG.
+    pub async fn backfill(
+        &self,
+        destination: &str,
+        room_id: &str,
+        from: &[String],
+        limit: usize,
+    ) -> Result<Vec<Value>, FederationClientError> {
+        let query = url::form_urlencoded::Serializer::new(String::new())
+            .extend_pairs(from.iter().map(|event_id| ("v", event_id.as_str())))
+            .append_pair("limit", &limit.to_string())
+            .finish();
+        let path = format!("/_matrix/federation/v1/backfill/{}?{}", encode_path_segment(room_id), query);
+        let response = self.get_json(destination, &path).await?;
+        pdus_from_response(&response, "pdus")
+    }
+
+    /// The IDs of the room state before `event_id` and of its auth chain.
+    pub async fn get_state_ids(
+        &self,
+        destination: &str,
+        room_id: &str,
+        event_id: &str,
+    ) -> Result<(Vec<String>, Vec<String>), FederationClientError> {
+        let query: String = url::form_urlencoded::Serializer::new(String::new())
+            .append_pair("event_id", event_id)
+            .finish();
+        let path = format!("/_matrix/federation/v1/state_ids/{}?{}", encode_path_segment(room_id), query);
+        let response = self.get_json(destination, &path).await?;
+
+        let ids = |key: &str| -> Result<Vec<String>, FederationClientError> {
+            response
+                .get(key)
+                .and_then(|v| v.as_array())
+                .map(|ids| ids.iter().filter_map(|id| id.as_str().map(String::from)).collect())
+                .ok_or_else(|| FederationClientError::InvalidResponse(format!("missing {}", key)))
+        };
+        Ok((ids("pdu_ids")?, ids("auth_chain_ids")?))
+    }
+
+    /// Fetches a single event as a PDU.
+    pub async fn get_event(&self, destination: &str, event_id: &str) -> Result<Value, FederationClientError> {
+        let path = format!("/_matrix/federation/v1/event/{}", encode_path_segment(event_id));
+        let response = self.get_json(destination, &path).await?;
+        pdus_from_response(&response, "pdus")?
+            .into_iter()
+            .next()
+            .ok_or_else(|| FederationClientError::InvalidResponse("no PDU in response".to_string()))
+    }
+
+    /// The room state and its auth chain at `event_id`, as full PDUs.
+    pub async fn get_room_state(
+        &self,
+        destination: &str,
+        room_id: &str,
+        event_id: &str,
+    ) -> Result<(Vec<Value>, Vec<Value>), FederationClientError> {
+        let query: String = url::form_urlencoded::Serializer::new(String::new())
+            .append_pair("event_id", event_id)
+            .finish();
+        let path = format!("/_matrix/federation/v1/state/{}?{}", encode_path_segment(room_id), query);
+        let response = self.get_json(destination, &path).await?;
+        Ok((pdus_from_response(&response, "pdus")?, pdus_from_response(&response, "auth_chain")?))
+    }
+
+    /// The summary of a space and of its direct children, as seen by a server in the space.
+    pub async fn get_space_hierarchy(
+        &self,
+        destination: &str,
+        room_id: &str,
+        suggested_only: bool,
+    ) -> Result<Value, FederationClientError> {
+        let query: String = url::form_urlencoded::Serializer::new(String::new())
+            .append_pair("suggested_only", if suggested_only { "true" } else { "false" })
+            .finish();
+        let path = format!("/_matrix/federation/v1/hierarchy/{}?{}", encode_path_segment(room_id), query);
+        let response = self.get_json(destination, &path).await?;
+        if !response.get("room").is_some_and(Value::is_object) {
+            return Err(FederationClientError::InvalidResponse("missing room".to_string()));
+        }
+        Ok(response)
+    }
+
+    /// Resolves `alias` through the server that owns it, returning the room ID and the servers
+    /// suggested for joining it.
+    pub async fn query_room_alias(
+        &self,
+        destination: &str,
+        alias: &str,
+    ) -> Result<(String, Vec<String>), FederationClientError> {
+        let query: String = url::form_urlencoded::Serializer::new(String::new())
+            .append_pair("room_alias", alias)
+            .finish();
+        let path = format!("/_matrix/federation/v1/query/directory?{}", query);
+        let response = self.get_json(destination, &path).await?;
+
+        let room_id = response
+            .get("room_id")
+            .and_then(|v| v.as_str())
+            .ok_or_else(|| FederationClientError::InvalidResponse("missing room_id".to_string()))?;
+        let servers = response
+            .get("servers")
+            .and_then(|v| v.as_array())
+            .map(|servers| servers.iter().filter_map(|s| s.as_str().map(String::from)).collect())
+            .unwrap_or_default();
+        Ok((room_id.to_string(), servers))
+    }
+
+    /// Asks a resident server for the membership event template to sign, through `make_join`,
+    /// `make_leave` or `make_knock`. Returns the room version with the template.
+    pub async fn make_membership_event(
+        &self,
+        destination: &str,
+        handshake: MembershipHandshake,
+        room_id: &str,
+        user_id: &str,
+        supported_versions: &[&str],
+    ) -> Result<(String, Value), FederationClientError> {
+        let mut path = format!(
+            "/_matrix/federation/v1/make_{}/{}/{}",
+            handshake.membership(),
+            encode_path_segment(room_id),
+            encode_path_segment(user_id)
+        );
+        if handshake != MembershipHandshake::Leave {
+            let query = url::form_urlencoded::Serializer::new(String::new())
+                .extend_pairs(supported_versions.iter().map(|version| ("ver", *version)))
+                .finish();
+            path = format!("{}?{}", path, query);
+        }
+        let response = self.get_json(destination, &path).await?;
+
+        let event = response
+            .get("event")
+            .filter(|event| event.is_object())
+            .cloned()
+            .ok_or_else(|| FederationClientError::InvalidResponse("missing event".to_string()))?;
+        // Servers that predate room versions leave it out
+        let room_version = response
+            .get("room_version")
+            .and_then(|v| v.as_str())
+            .unwrap_or("1")
+            .to_string();
+        Ok((room_version, event))
+    }
+
+    /// Hands the signed membership event back through `send_join`, `send_leave` or
+    /// `send_knock`. Joins ask for the member list to be omitted from the returned state.
+    pub async fn send_membership_event(
+        &self,
+        destination: &str,
+        handshake: MembershipHandshake,
+        room_id: &str,
+        event_id: &str,
+        event: &Value,
+    ) -> Result<Value, FederationClientError> {
+        let path = handshake.send_path(room_id, event_id);
+        self.put_json(destination, &path, event).await
+    }
+
+    /// Sends an invite to the invitee's server through the v2 `invite` API and returns the
+    /// event as counter-signed by that server.
+    pub async fn send_invite(
+        &self,
+        destination: &str,
+        room_id: &str,
+        event_id: &str,
+        room_version: &str,
+        event: &Value,
+        invite_room_state: &[Value],
+    ) -> Result<Value, FederationClientError> {
+        let path = format!(
+            "/_matrix/federation/v2/invite/{}/{}",
+            encode_path_segment(room_id),
+            encode_path_segment(event_id)
+        );
+        let body = json!({
+            "event": event,
+            "room_version": room_version,
+            "invite_room_state": invite_room_state,
+        });
+        let response = self.put_json(destination, &path, &body).await?;
+        response
+            .get("event")
+            .filter(|event| event.is_object())
+            .cloned()
+            .ok_or_else(|| FederationClientError::InvalidResponse("missing event".to_string()))
+    }
+
+    /// Asks the room's server to turn a bound third-party invite into a membership invite.
+    pub async fn exchange_third_party_invite(
+        &self,
+        destination: &str,
+        room_id: &str,
+        event: &Value,
+    ) -> Result<(), FederationClientError> {
+        let path = format!(
+            "/_matrix/federation/v1/exchange_third_party_invite/{}",
+            encode_path_segment(room_id)
+        );
+        self.put_json(destination, &path, event).await.map(|_| ())
+    }
+
+    /// Milliseconds until `destination` may be contacted again, if it is backed off.
+    pub async fn retry_after_ms(&self, destination: &str) -> Option<i64> {
+        let now = chrono::Utc::now().timestamp_millis();
+        self.backoff
+            .read()
+            .await
+            .get(destination)
+            .map(|backoff| backoff.retry_at - now)
+            .filter(|remaining| *remaining > 0)
+    }
+
+    pub async fn reset_backoff(&self, destination: &str) {
+        self.backoff.write().await.remove(destination);
+    }
+
+    /// The `Authorization` header for a request, signed with the current server key.
+    pub async fn authorization_header(
+        &self,
+        method: &str,
+        uri: &str,
+        destination: &str,
+        content: Option<&Value>,
+    ) -> Result<String, FederationClientError> {
+        let key = self
+            .key_rotation_manager
+            .get_current_key()
+            .await
+            .map_err(|e| FederationClientError::Signing(e.to_string()))?
+            .ok_or_else(|| FederationClientError::Signing("no current key".to_string()))?;
+        let seed = decode_base64_32(&key.secret_key)
+            .ok_or_else(|| FederationClientError::Signing(format!("invalid key {}", key.key_id)))?;
+
+        let signed = request_signing_bytes(method, uri, &self.server_name, destination, content);
+        let signature = ed25519_dalek::SigningKey::from_bytes(&seed).sign(&signed);
+        Ok(format!(
+            "X-Matrix origin=\"{}\",destination=\"{}\",key=\"{}\",sig=\"{}\"",
+            self.server_name,
+            destination,
+            key.key_id,
+            base64::engine::general_purpose::STANDARD_NO_PAD.encode(signature.to_bytes())
+        ))
+    }
+
+    async fn send(
+        &self,
+        method: Method,
+        destination: &str,
+        path: &str,
+        body: Option<&Value>,
+        signed: bool,
+    ) -> Result<Value, FederationClientError> {
+        if !self.access_control.is_allowed(destination).await {
+            return Err(FederationClientError::Forbidden(destination.to_string()));
+        }
+        if let Some(retry_after_ms) = self.retry_after_ms(destination).await {
+            return Err(FederationClientError::BackingOff {
+                destination: destination.to_string(),
+                retry_after_ms,
+            });
+        }
+
+        let resolved = match self.resolver.resolve(destination).await {
+            Ok(resolved) => resolved,
+            Err(e) => {
+                self.record_failure(destination).await;
+                return Err(FederationClientError::Discovery(e));
+            }
+        };
+
+        let url = format!("{}{}", resolved.base_url(self.resolver.scheme()), path);
+        let mut request = self
+            .http
+            .request(method.clone(), &url)
+            .header(reqwest::header::HOST, &resolved.host_header);
+        if signed {
+            let authorization = self.authorization_header(method.as_str(), path, destination, body).await?;
+            request = request.header(reqwest::header::AUTHORIZATION, authorization);
+        }
+        if let Some(body) = body {
+            request = request.json(body);
+        }
+
+        let response = match request.send().await {
+            Ok(response) => response,
+            Err(e) => {
+                self.record_failure(destination).await;
+                // The cached route may be what failed.
+                self.resolver.invalidate(destination);
+                return Err(FederationClientError::Request(e.to_string()));
+            }
+        };
+
+        let status = response.status();
+        if status.is_server_error() || status == StatusCode::TOO_MANY_REQUESTS {
+            self.record_failure(destination).await;
+        } else {
+            self.reset_backoff(destination).await;
+        }
+
+        let bytes = response
+            .bytes()
+            .await
+            .map_err(|e| FederationClientError::Request(e.to_string()))?;
+        if !status.is_success() {
+            let body = serde_json::from_slice(&bytes).unwrap_or(Value::Null);
+            return Err(FederationClientError::Http {
+                status: status.as_u16(),
+                body,
+            });
+        }
+        serde_json::from_slice(&bytes).map_err(|e| FederationClientError::InvalidResponse(e.to_string()))
+    }
+
+    async fn record_failure(&self, destination: &str) {
+        let now = chrono::Utc::now().timestamp_millis();
+        let mut backoff = self.backoff.write().await;
+        let entry = backoff.entry(destination.to_string()).or_insert(Backoff {
+            failures: 0,
+            retry_at: now,
+        });
+        entry.failures += 1;
+        let delay = BACKOFF_INITIAL_MS
+            .saturating_mul(1i64 << (entry.failures - 1).min(20))
+            .min(BACKOFF_MAX_MS);
+        entry.retry_at = now + delay;
+        tracing::debug!("Backing off from {} for {}ms", destination, delay);
+    }
+}
+
+fn encode_path_segment(segment: &str) -> String {
+    url::form_urlencoded::byte_serialize(segment.as_bytes()).collect()
+}
+
+fn pdus_from_response(response: &Value, key: &str) -> Result<Vec<Value>, FederationClientError> {
+    response
+        .get(key)
+        .and_then(|v| v.as_array())
+        .cloned()
+        .ok_or_else(|| FederationClientError::InvalidResponse(format!("missing {}", key)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::federation::access_control::FederationPolicy;
+    use crate::federation::discovery::SrvRecord;
+    use async_trait::async_trait;
+    use axum::http::HeaderMap;
+    use std::net::IpAddr;
+
+    struct LoopbackDns;
+
+    #[async_trait]
+    impl DnsResolver for LoopbackDns {
+        async fn lookup_srv(&self, _name: &str) -> Vec<SrvRecord> {
+            Vec::new()
+        }
+
+        async fn lookup_ip(&self, _host: &str) -> std::io::Result<Vec<IpAddr>> {
+            Ok(vec![IpAddr::from([127, 0, 0, 1])])
+        }
+    }
+
+    const SEED: [u8; 32] = [9u8; 32];
+
+    async fn client(access_control: FederationAccessControl) -> FederationClient {
+        let pool = Arc::new(sqlx::PgPool::connect_lazy("postgres://localhost/unused").unwrap());
+        let keys = KeyRotationManager::new(&pool, "origin.test");
+        keys.set_current_key(
+            &base64::engine::general_purpose::STANDARD_NO_PAD.encode(SEED),
+            "ed25519:t",
+        )
+        .await
+        .unwrap();
+
+        let config = FederationClientConfig {
+            use_tls: false,
+            ..Default::default()
+        };
+        FederationClient::new("origin.test", keys, Arc::new(access_control), config)
+            .with_dns_resolver(Arc::new(LoopbackDns))
+    }
+
+    /// A remote server that checks the `X-Matrix` signature of each `/send` request.
+    async fn remote_server() -> u16 {
+        async fn send(
+            axum::extract::Path(txn_id): axum::extract::Path<String>,
+            headers: HeaderMap,
+            axum::Json(body): axum::Json<Value>,
+        ) -> Result<axum::Json<Value>, StatusCode> {
+            let authorization = headers["authorization"].to_str().unwrap();
+            let sig = authorization.split("sig=\"").nth(1).unwrap().trim_end_matches('"');
+            let sig = base64::engine::general_purpose::STANDARD_NO_PAD.decode(sig).unwrap();
+            let destination = headers["host"].to_str().unwrap();
+            let signed = request_signing_bytes(
+                "PUT",
+                &format!("/_matrix/federation/v1/send/{}", txn_id),
+                "origin.test",
+                destination,
+                Some(&body),
+            );
+            let key = ed25519_dalek::SigningKey::from_bytes(&SEED).verifying_key();
+            key.verify_strict(&signed, &ed25519_dalek::Signature::from_slice(&sig).unwrap())
+                .map_err(|_| StatusCode::UNAUTHORIZED)?;
+            Ok(axum::Json(json!({ "pdus": {}, "txn": txn_id })))
+        }
+
+        async fn backfill(
+            axum::extract::Path(room_id): axum::extract::Path<String>,
+            axum::extract::RawQuery(query): axum::extract::RawQuery,
+        ) -> axum::Json<Value> {
+            axum::Json(json!({
+                "origin": "remote.test",
+                "pdus": [{"room_id": room_id, "query": query}]
+            }))
+        }
+
+        async fn make_join(
+            axum::extract::Path((room_id, user_id)): axum::extract::Path<(String, String)>,
+            axum::extract::RawQuery(query): axum::extract::RawQuery,
+        ) -> axum::Json<Value> {
+            axum::Json(json!({
+                "room_version": "10",
+                "event": {"room_id": room_id, "state_key": user_id, "query": query}
+            }))
+        }
+
+        async fn send_join(
+            axum::extract::Path((room_id, event_id)): axum::extract::Path<(String, String)>,
+            axum::extract::RawQuery(query): axum::extract::RawQuery,
+            axum::Json(event): axum::Json<Value>,
+        ) -> axum::Json<Value> {
+            axum::Json(json!({
+                "room_id": room_id,
+                "event_id": event_id,
+                "query": query,
+                "event": event
+            }))
+        }
+
+        async fn invite(
+            axum::extract::Path((room_id, event_id)): axum::extract::Path<(String, String)>,
+            axum::Json(body): axum::Json<Value>,
+        ) -> axum::Json<Value> {
+            let mut event = body["event"].clone();
+            event["signatures"]["remote.test"] = json!({ "ed25519:r": "sig" });
+            event["unsigned"] = json!({
+                "room_id": room_id,
+                "event_id": event_id,
+                "room_version": body["room_version"],
+                "stripped": body["invite_room_state"].as_array().map(Vec::len),
+            });
+            axum::Json(json!({ "event": event }))
+        }
+
+        let app = axum::Router::new()
+            .route("/_matrix/federation/v1/send/{txn_id}", axum::routing::put(send))
+            .route(
+                "/_matrix/federation/v2/invite/{room_id}/{event_id}",
+                axum::routing::put(invite),
+            )
+            .route(
+                "/_matrix/federation/v1/make_join/{room_id}/{user_id}",
+                axum::routing::get(make_join),
+            )
+            .route(
+                "/_matrix/federation/v2/send_join/{room_id}/{event_id}",
+                axum::routing::put(send_join),
+            )
+            .route("/_matrix/federation/v1/backfill/{room_id}", axum::routing::get(backfill))
+            .route(
+                "/_matrix/federation/v1/version",
+                axum::routing::get(|| async { StatusCode::SERVICE_UNAVAILABLE }),
+            );
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        tokio::spawn(async move {
+            axum::serve(listener, app).await.unwrap();
+        });
+        port
+    }
+
+    #[tokio::test]
+    async fn test_transaction_is_signed_for_destination() {
+        let port = remote_server().await;
+        let client = client(FederationAccessControl::new(FederationPolicy::default())).await;
+        let destination = format!("remote.test:{}", port);
+
+        let response = client
+            .send_transaction(&destination, "txn1", &[json!({"type": "m.room.message"})], &[])
+            .await
+            .unwrap();
+        assert_eq!(response["txn"], "txn1");
+    }
+
+    #[tokio::test]
+    async fn test_backfill_encodes_room_and_events() {
+        let port = remote_server().await;
+        let client = client(FederationAccessControl::new(FederationPolicy::default())).await;
+        let destination = format!("remote.test:{}", port);
+
+        let pdus = client
+            .backfill(&destination, "!room:remote.test", &["$a+b/c".to_string(), "$d".to_string()], 20)
+            .await
+            .unwrap();
+        assert_eq!(pdus.len(), 1);
+        assert_eq!(pdus[0]["room_id"], "!room:remote.test");
+        assert_eq!(pdus[0]["query"], "v=%24a%2Bb%2Fc&v=%24d&limit=20");
+    }
+
+    #[tokio::test]
+    async fn test_join_handshake_lists_versions_and_omits_members() {
+        let port = remote_server().await;
+        let client = client(FederationAccessControl::new(FederationPolicy::default())).await;
+        let destination = format!("remote.test:{}", port);
+
+        let (room_version, template) = client
+            .make_membership_event(
+                &destination,
+                MembershipHandshake::Join,
+                "!room:remote.test",
+                "@alice:origin.test",
+                &["9", "10"],
+            )
+            .await
+            .unwrap();
+        assert_eq!(room_version, "10");
+        assert_eq!(template["state_key"], "@alice:origin.test");
+        assert_eq!(template["query"], "ver=9&ver=10");
+
+        let response = client
+            .send_membership_event(
+                &destination,
+                MembershipHandshake::Join,
+                "!room:remote.test",
+                "$join",
+                &json!({"type": "m.room.member"}),
+            )
+            .await
+            .unwrap();
+        assert_eq!(response["event_id"], "$join");
+        assert_eq!(response["query"], "omit_members=true");
+        assert_eq!(response["event"]["type"], "m.room.member");
+    }
+
+    #[tokio::test]
+    async fn test_invite_returns_counter_signed_event() {
+        let port = remote_server().await;
+        let client = client(FederationAccessControl::new(FederationPolicy::default())).await;
+        let destination = format!("remote.test:{}", port);
+
+        let event = json!({
+            "type": "m.room.member",
+            "state_key": "@bob:remote.test",
+            "signatures": { "origin.test": { "ed25519:t": "ours" } },
+        });
+        let stripped = [json!({ "type": "m.room.name", "state_key": "", "content": { "name": "Lobby" } })];
+        let signed = client
+            .send_invite(&destination, "!room:origin.test", "$invite", "10", &event, &stripped)
+            .await
+            .unwrap();
+        assert_eq!(signed["signatures"]["origin.test"]["ed25519:t"], "ours");
+        assert_eq!(signed["signatures"]["remote.test"]["ed25519:r"], "sig");
+        assert_eq!(signed["unsigned"]["room_id"], "!room:origin.test");
+        assert_eq!(signed["unsigned"]["event_id"], "$invite");
+        assert_eq!(signed["unsigned"]["room_version"], "10");
+        assert_eq!(signed["unsigned"]["stripped"], 1);
+    }
+
+    #[tokio::test]
+    async fn test_blocked_destination_is_not_contacted() {
+        let access_control = FederationAccessControl::new(FederationPolicy::default());
+        access_control.add_to_blacklist("blocked.test", "spam", "admin", None).await;
+        let client = client(access_control).await;
+
+        let result = client.get_json("blocked.test", "/_matrix/federation/v1/version").await;
+        assert!(matches!(result, Err(FederationClientError::Forbidden(_))));
+    }
+
+    #[tokio::test]
+    async fn test_server_errors_back_off_destination() {
+        let port = remote_server().await;
+        let client = client(FederationAccessControl::new(FederationPolicy::default())).await;
+        let destination = format!("remote.test:{}", port);
+
+        let result = client.get_json(&destination, "/_matrix/federation/v1/version").await;
+        assert_eq!(result.unwrap_err().status(), Some(503));
+        assert!(client.retry_after_ms(&destination).await.is_some());
+
+        let result = client.get_json(&destination, "/_matrix/federation/v1/version").await;
+        assert!(matches!(result, Err(FederationClientError::BackingOff { .. })));
+
+        client.reset_backoff(&destination).await;
+        assert!(client.retry_after_ms(&destination).await.is_none());
+    }
+}
@@ -3,7 +3,7 @@ use crate::common::task_queue::RedisTaskQueue;
 use crate::common::background_job::BackgroundJob;
 use crate::common::ApiError;
 use chrono::{Duration, TimeZone, Utc};
-use reqwest::{Client, StatusCode};
+use crate::federation::client::FederationClient;
 use serde::{Deserialize, Serialize};
 use serde_json::{json, Value};
 use sqlx::{Pool, Postgres};
@@ -32,7 +32,7 @@ pub struct DeviceInfo {
 #[derive(Clone)]
 pub struct DeviceSyncManager {
     pool: Arc<Pool<Postgres>>,
-    http_client: Client,
+    federation_client: Option<Arc<FederationClient>>,
     local_cache: Arc<RwLock<DeviceCache>>,
     cache_manager: Option<Arc<CacheManager>>,
     task_queue: Option<Arc<RedisTaskQueue>>,
@@ -44,23 +44,26 @@ impl DeviceSyncManager {
         cache_manager: Option<Arc<CacheManager>>,
         task_queue: Option<Arc<RedisTaskQueue>>
     ) -> Self {
-        let http_client = Client::builder()
-            .timeout(std::time::Duration::from_secs(10))
-            .build()
-            .unwrap_or_else(|e| {
-                tracing::warn!("Failed to build HTTP client, using default: {}", e);
-                Client::new()
-            });
-
         Self {
             pool: pool.clone(),
-            http_client,
+            federation_client: None,
             local_cache: Arc::new(RwLock::new(HashMap::new())),
             cache_manager,
             task_queue,
         }
     }
 
+    pub fn with_federation_client(mut self, federation_client: Arc<FederationClient>) -> Self {
+        self.federation_client = Some(federation_client);
+        self
+    }
+
+    fn federation_client(&self) -> Result<&FederationClient, ApiError> {
+        self.federation_client
+            .as_deref()
+            .ok_or_else(|| ApiError::internal("Federation client not configured".to_string()))
+    }
+
     async fn get_cached_devices(&self, origin: &str, user_id: &str) -> Option<Vec<DeviceInfo>> {
         let cache_key = format!("remote_devices:{}:{}", origin, user_id);
 
@@ -117,60 +120,25 @@ impl DeviceSyncManager {
             return Ok(devices);
         }
 
-        let urls = vec![
-            format!(
-                "https://{}/_matrix/federation/v1/user/devices/{}",
-                origin, user_id
-            ),
-            format!(
-                "http://{}:8448/_matrix/federation/v1/user/devices/{}",
-                origin, user_id
-            ),
-        ];
-
-        for url in urls {
-            match self.fetch_devices_from_url(&url).await {
-                Ok(devices) => {
-                    self.cache_devices(origin, user_id, &devices).await;
-                    return Ok(devices);
-                }
-                Err(e) => {
-                    tracing::warn!("Failed to fetch devices from {}: {}", url, e);
-                    continue;
-                }
+        let path = format!("/_matrix/federation/v1/user/devices/{}", user_id);
+        let body = match self.federation_client()?.get_json(origin, &path).await {
+            Ok(body) => body,
+            Err(e) if e.status() == Some(404) => return Ok(vec![]),
+            Err(e) => {
+                tracing::warn!("Failed to fetch devices for {} from {}: {}", user_id, origin, e);
+                return Err(ApiError::not_found(format!(
+                    "Failed to fetch devices for user {} from {}",
+                    user_id, origin
+                )));
             }
-        }
+        };
 
-        Err(ApiError::not_found(format!(
-            "Failed to fetch devices for user {} from {}",
-            user_id, origin
-        )))
+        let devices = Self::parse_devices(&body)?;
+        self.cache_devices(origin, user_id, &devices).await;
+        Ok(devices)
     }
 
-    async fn fetch_devices_from_url(&self, url: &str) -> Result<Vec<DeviceInfo>, ApiError> {
-        let response = self
-            .http_client
-            .get(url)
-            .send()
-            .await
-            .map_err(|e| ApiError::internal(format!("HTTP request failed: {}", e)))?;
-
-        if response.status() == StatusCode::NOT_FOUND {
-            return Ok(vec![]);
-        }
-
-        if !response.status().is_success() {
-            return Err(ApiError::internal(format!(
-                "Remote server returned error: {}",
-                response.status()
-            )));
-        }
-
-        let body: Value = response
-            .json()
-            .await
-            .map_err(|e| ApiError::internal(format!("Failed to parse response: {}", e)))?;
-
+    fn parse_devices(body: &Value) -> Result<Vec<DeviceInfo>, ApiError> {
         let devices_json = body
             .get("devices")
             .and_then(|v| v.as_array())
@@ -247,46 +215,27 @@ impl DeviceSyncManager {
             }
         }
 
-        let payload = json!({
-            "type": "m.device_list_update",
-            "sender": user_id,
+        let edu = json!({
+            "edu_type": "m.device_list_update",
             "content": {
+                "user_id": user_id,
                 "device_id": device_id,
-                "deleted": true
+                "deleted": true,
+                "stream_id": Utc::now().timestamp_millis(),
+                "prev_id": []
             }
         });
+        let transaction_id = uuid::Uuid::new_v4().to_string();
 
-        let urls = vec![
-            format!(
-                "https://{}/_matrix/federation/v1/send/{}",
-                origin,
-                uuid::Uuid::new_v4()
-            ),
-            format!(
-                "http://{}:8448/_matrix/federation/v1/send/{}",
-                origin,
-                uuid::Uuid::new_v4()
-            ),
-        ];
-
-        for url in urls {
-            match self.http_client.put(&url).json(&payload).send().await {
-                Ok(response) => {
-                    if response.status().is_success() {
-                        tracing::info!("Successfully notified device revocation to {}", origin);
-                        return Ok(());
-                    }
-                }
-                Err(e) => {
-                    tracing::warn!("Failed to notify revocation to {}: {}", url, e);
-                    continue;
-                }
-            }
-        }
-
-        Err(ApiError::internal(
-            "Failed to notify device revocation to remote server".to_string(),
-        ))
+        self.federation_client()?
+            .send_transaction(origin, &transaction_id, &[], &[edu])
+            .await
+            .map_err(|e| {
+                tracing::warn!("Failed to notify revocation to {}: {}", origin, e);
+                ApiError::internal("Failed to notify device revocation to remote server".to_string())
+            })?;
+        tracing::info!("Successfully notified device revocation to {}", origin);
+        Ok(())
     }
 
     pub async fn get_local_devices(&self, user_id: &str) -> Result<Vec<DeviceInfo>, ApiError> {
@@ -0,0 +1,458 @@
+//! Server discovery for outbound federation: maps a server name to the host, port and TLS name
+//! to use, following the resolution steps of the server-server API.
+
+use async_trait::async_trait;
+use hickory_resolver::TokioAsyncResolver;
+use parking_lot::RwLock;
+use serde_json::Value;
+use std::collections::HashMap;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::Arc;
+use std::time::Duration;
+
+pub const DEFAULT_FEDERATION_PORT: u16 = 8448;
+
+/// Delegations found through `.well-known` are cached for a day, as the spec suggests.
+const WELL_KNOWN_CACHE_MS: i64 = 24 * 60 * 60 * 1000;
+const RESOLUTION_CACHE_MS: i64 = 60 * 60 * 1000;
+const WELL_KNOWN_TIMEOUT: Duration = Duration::from_secs(10);
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SrvRecord {
+    pub priority: u16,
+    pub weight: u16,
+    pub port: u16,
+    pub target: String,
+}
+
+/// DNS lookups used by discovery and by the HTTP connector.
+#[async_trait]
+pub trait DnsResolver: Send + Sync {
+    async fn lookup_srv(&self, name: &str) -> Vec<SrvRecord>;
+
+    async fn lookup_ip(&self, host: &str) -> std::io::Result<Vec<IpAddr>>;
+}
+
+/// Resolves through the system's configured name servers.
+pub struct SystemDnsResolver {
+    resolver: TokioAsyncResolver,
+}
+
+impl SystemDnsResolver {
+    pub fn new() -> Self {
+        let resolver = TokioAsyncResolver::tokio_from_system_conf().unwrap_or_else(|e| {
+            tracing::warn!("Failed to read system DNS config, using defaults: {}", e);
+            TokioAsyncResolver::tokio(Default::default(), Default::default())
+        });
+        Self { resolver }
+    }
+}
+
+impl Default for SystemDnsResolver {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[async_trait]
+impl DnsResolver for SystemDnsResolver {
+    async fn lookup_srv(&self, name: &str) -> Vec<SrvRecord> {
+        match self.resolver.srv_lookup(name).await {
+            Ok(lookup) => lookup
+                .iter()
+                .map(|srv| SrvRecord {
+                    priority: srv.priority(),
+                    weight: srv.weight(),
+                    port: srv.port(),
+                    target: srv.target().to_utf8(),
+                })
+                .collect(),
+            Err(e) => {
+                tracing::debug!("SRV lookup for {} failed: {}", name, e);
+                Vec::new()
+            }
+        }
+    }
+
+    async fn lookup_ip(&self, host: &str) -> std::io::Result<Vec<IpAddr>> {
+        let lookup = self
+            .resolver
+            .lookup_ip(host)
+            .await
+            .map_err(|e| std::io::Error::new(std::io::ErrorKind::NotFound, e.to_string()))?;
+        Ok(lookup.iter().collect())
+    }
+}
+
+/// Where requests for a server name are sent.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ResolvedServer {
+    /// Host in the request URL, which the TLS certificate must be valid for.
+    pub tls_name: String,
+    /// Host actually connected to; differs from `tls_name` after an SRV lookup.
+    pub connect_host: String,
+    pub port: u16,
+    /// Value of the `Host` header.
+    pub host_header: String,
+}
+
+impl ResolvedServer {
+    fn direct(host: &str, port: u16, host_header: &str) -> Self {
+        Self {
+            tls_name: host.to_string(),
+            connect_host: host.to_string(),
+            port,
+            host_header: host_header.to_string(),
+        }
+    }
+
+    pub fn base_url(&self, scheme: &str) -> String {
+        if self.tls_name.contains(':') {
+            format!("{}://[{}]:{}", scheme, self.tls_name, self.port)
+        } else {
+            format!("{}://{}:{}", scheme, self.tls_name, self.port)
+        }
+    }
+}
+
+/// Splits a server name into host and optional port. IPv6 literals must be bracketed.
+pub fn split_server_name(server_name: &str) -> Option<(String, Option<u16>)> {
+    if server_name.is_empty() {
+        return None;
+    }
+    if let Some(rest) = server_name.strip_prefix('[') {
+        let (host, rest) = rest.split_once(']')?;
+        host.parse::<std::net::Ipv6Addr>().ok()?;
+        return match rest {
+            "" => Some((host.to_string(), None)),
+            _ => Some((host.to_string(), Some(rest.strip_prefix(':')?.parse().ok()?))),
+        };
+    }
+    match server_name.rsplit_once(':') {
+        Some((host, port)) if !host.is_empty() && !host.contains(':') => {
+            Some((host.to_string(), Some(port.parse().ok()?)))
+        }
+        Some(_) => None,
+        None => Some((server_name.to_string(), None)),
+    }
+}
+
+/// Resolves and caches server names. The same instance feeds the HTTP connector, so that an
+/// SRV target is connected to while the URL and certificate keep the delegated host name.
+pub struct ServerResolver {
+    dns: Arc<dyn DnsResolver>,
+    http: reqwest::Client,
+    scheme: &'static str,
+    well_known_port: u16,
+    cache: RwLock<HashMap<String, (ResolvedServer, i64)>>,
+    connect_overrides: Arc<RwLock<HashMap<String, String>>>,
+}
+
+impl ServerResolver {
+    pub fn new(dns: Arc<dyn DnsResolver>, use_tls: bool, well_known_port: u16) -> Self {
+        // `.well-known` is always fetched from the server name itself, never an SRV target.
+        let connector = Arc::new(ConnectorDns {
+            dns: dns.clone(),
+            overrides: Arc::new(RwLock::new(HashMap::new())),
+        });
+        let http = reqwest::Client::builder()
+            .timeout(WELL_KNOWN_TIMEOUT)
+            .dns_resolver(connector)
+            .build()
+            .unwrap_or_else(|e| {
+                tracing::warn!("Failed to build well-known HTTP client, using default: {}", e);
+                reqwest::Client::new()
+            });
+
+        Self {
+            dns,
+            http,
+            scheme: if use_tls { "https" } else { "http" },
+            well_known_port,
+            cache: RwLock::new(HashMap::new()),
+            connect_overrides: Arc::new(RwLock::new(HashMap::new())),
+        }
+    }
+
+    pub fn scheme(&self) -> &'static str {
+        self.scheme
+    }
+
+    /// The resolver HTTP clients must use to honour SRV targets.
+    pub fn connector_dns(&self) -> Arc<ConnectorDns> {
+        Arc::new(ConnectorDns {
+            dns: self.dns.clone(),
+            overrides: self.connect_overrides.clone(),
+        })
+    }
+
+    pub async fn resolve(&self, server_name: &str) -> Result<ResolvedServer, String> {
+        let now = chrono::Utc::now().timestamp_millis();
+        if let Some((resolved, expires_at)) = self.cache.read().get(server_name) {
+            if *expires_at > now {
+                return Ok(resolved.clone());
+            }
+        }
+
+        let (resolved, ttl) = self.discover(server_name).await?;
+        if resolved.connect_host != resolved.tls_name {
+            self.connect_overrides
+                .write()
+                .insert(resolved.tls_name.clone(), resolved.connect_host.clone());
+        }
+        self.cache
+            .write()
+            .insert(server_name.to_string(), (resolved.clone(), now + ttl));
+        Ok(resolved)
+    }
+
+    pub fn invalidate(&self, server_name: &str) {
+        self.cache.write().remove(server_name);
+    }
+
+    async fn discover(&self, server_name: &str) -> Result<(ResolvedServer, i64), String> {
+        let (host, port) =
+            split_server_name(server_name).ok_or_else(|| format!("Invalid server name {}", server_name))?;
+
+        if host.parse::<IpAddr>().is_ok() {
+            let port = port.unwrap_or(DEFAULT_FEDERATION_PORT);
+            return Ok((ResolvedServer::direct(&host, port, server_name), RESOLUTION_CACHE_MS));
+        }
+        if let Some(port) = port {
+            return Ok((ResolvedServer::direct(&host, port, server_name), RESOLUTION_CACHE_MS));
+        }
+
+        if let Some(delegated) = self.fetch_well_known(&host).await {
+            if let Some((delegated_host, delegated_port)) = split_server_name(&delegated) {
+                let resolved = if delegated_host.parse::<IpAddr>().is_ok() || delegated_port.is_some() {
+                    let port = delegated_port.unwrap_or(DEFAULT_FEDERATION_PORT);
+                    ResolvedServer::direct(&delegated_host, port, &delegated)
+                } else {
+                    self.resolve_srv(&delegated_host).await.unwrap_or_else(|| {
+                        ResolvedServer::direct(&delegated_host, DEFAULT_FEDERATION_PORT, &delegated_host)
+                    })
+                };
+                return Ok((resolved, WELL_KNOWN_CACHE_MS));
+            }
+            tracing::warn!("Ignoring invalid m.server delegation {} for {}", delegated, host);
+        }
+
+        let resolved = self
+            .resolve_srv(&host)
+            .await
+            .unwrap_or_else(|| ResolvedServer::direct(&host, DEFAULT_FEDERATION_PORT, &host));
+        Ok((resolved, RESOLUTION_CACHE_MS))
+    }
+
+    async fn fetch_well_known(&self, host: &str) -> Option<String> {
+        let default_port = if self.scheme == "https" { 443 } else { 80 };
+        let url = if self.well_known_port == default_port {
+            format!("{}://{}/.well-known/matrix/server", self.scheme, host)
+        } else {
+            format!("{}://{}:{}/.well-known/matrix/server", self.scheme, host, self.well_known_port)
+        };
+
+        let response = match self.http.get(&url).send().await {
+            Ok(response) if response.status().is_success() => response,
+            Ok(response) => {
+                tracing::debug!("No well-known for {}: {}", host, response.status());
+                return None;
+            }
+            Err(e) => {
+                tracing::debug!("Failed to fetch well-known for {}: {}", host, e);
+                return None;
+            }
+        };
+        let body: Value = response.json().await.ok()?;
+        body.get("m.server")
+            .and_then(|v| v.as_str())
+            .map(str::to_string)
+    }
+
+    /// Tries `_matrix-fed._tcp` first, then the deprecated `_matrix._tcp` record.
+    async fn resolve_srv(&self, host: &str) -> Option<ResolvedServer> {
+        for service in ["_matrix-fed._tcp", "_matrix._tcp"] {
+            let mut records = self.dns.lookup_srv(&format!("{}.{}", service, host)).await;
+            records.retain(|record| !record.target.is_empty() && record.target != ".");
+            records.sort_by(|a, b| a.priority.cmp(&b.priority).then(b.weight.cmp(&a.weight)));
+
+            if let Some(record) = records.first() {
+                return Some(ResolvedServer {
+                    tls_name: host.to_string(),
+                    connect_host: record.target.trim_end_matches('.').to_string(),
+                    port: record.port,
+                    host_header: host.to_string(),
+                });
+            }
+        }
+        None
+    }
+}
+
+/// reqwest DNS hook: looks up the SRV target recorded for a host, or the host itself.
+pub struct ConnectorDns {
+    dns: Arc<dyn DnsResolver>,
+    overrides: Arc<RwLock<HashMap<String, String>>>,
+}
+
+impl reqwest::dns::Resolve for ConnectorDns {
+    fn resolve(&self, name: reqwest::dns::Name) -> reqwest::dns::Resolving {
+        let host = self
+            .overrides
+            .read()
+            .get(name.as_str())
+            .cloned()
+            .unwrap_or_else(|| name.as_str().to_string());
+        let dns = self.dns.clone();
+
+        Box::pin(async move {
+            let addrs: Vec<SocketAddr> = match host.parse::<IpAddr>() {
+                Ok(ip) => vec![SocketAddr::new(ip, 0)],
+                Err(_) => dns
+                    .lookup_ip(&host)
+                    .await?
+                    .into_iter()
+                    .map(|ip| SocketAddr::new(ip, 0))
+                    .collect(),
+            };
+            let addrs: reqwest::dns::Addrs = Box::new(addrs.into_iter());
+            Ok(addrs)
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    /// Answers every host with loopback and SRV queries from a fixed table.
+    #[derive(Default)]
+    struct StubDns {
+        srv: HashMap<String, SrvRecord>,
+        srv_queries: AtomicUsize,
+    }
+
+    #[async_trait]
+    impl DnsResolver for StubDns {
+        async fn lookup_srv(&self, name: &str) -> Vec<SrvRecord> {
+            self.srv_queries.fetch_add(1, Ordering::SeqCst);
+            self.srv.get(name).cloned().into_iter().collect()
+        }
+
+        async fn lookup_ip(&self, _host: &str) -> std::io::Result<Vec<IpAddr>> {
+            Ok(vec![IpAddr::from([127, 0, 0, 1])])
+        }
+    }
+
+    fn srv(target: &str, port: u16) -> SrvRecord {
+        SrvRecord {
+            priority: 10,
+            weight: 0,
+            port,
+            target: format!("{}.", target),
+        }
+    }
+
+    /// Serves `.well-known/matrix/server` delegating to `m_server`, on a loopback port.
+    async fn well_known_server(m_server: Option<&'static str>) -> u16 {
+        let app = axum::Router::new().route(
+            "/.well-known/matrix/server",
+            axum::routing::get(move || async move {
+                match m_server {
+                    Some(server) => Ok(axum::Json(serde_json::json!({ "m.server": server }))),
+                    None => Err(axum::http::StatusCode::NOT_FOUND),
+                }
+            }),
+        );
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        tokio::spawn(async move {
+            axum::serve(listener, app).await.unwrap();
+        });
+        port
+    }
+
+    fn resolver(dns: StubDns, well_known_port: u16) -> (Arc<StubDns>, ServerResolver) {
+        let dns = Arc::new(dns);
+        (dns.clone(), ServerResolver::new(dns, false, well_known_port))
+    }
+
+    #[test]
+    fn test_split_server_name() {
+        assert_eq!(split_server_name("example.org"), Some(("example.org".into(), None)));
+        assert_eq!(split_server_name("example.org:8000"), Some(("example.org".into(), Some(8000))));
+        assert_eq!(split_server_name("[::1]:8448"), Some(("::1".into(), Some(8448))));
+        assert_eq!(split_server_name("[::1]"), Some(("::1".into(), None)));
+        assert_eq!(split_server_name("example.org:port"), None);
+        assert_eq!(split_server_name("::1"), None);
+        assert_eq!(split_server_name(""), None);
+    }
+
+    #[tokio::test]
+    async fn test_ip_literal_and_explicit_port_skip_lookups() {
+        let (dns, resolver) = resolver(StubDns::default(), 1);
+
+        let ip = resolver.resolve("[::1]").await.unwrap();
+        assert_eq!(ip.base_url("https"), "https://[::1]:8448");
+        assert_eq!(ip.host_header, "[::1]");
+
+        let explicit = resolver.resolve("remote.test:8000").await.unwrap();
+        assert_eq!(explicit, ResolvedServer::direct("remote.test", 8000, "remote.test:8000"));
+        assert_eq!(dns.srv_queries.load(Ordering::SeqCst), 0);
+    }
+
+    #[tokio::test]
+    async fn test_well_known_delegation_then_srv() {
+        let port = well_known_server(Some("delegated.test")).await;
+        let mut stub = StubDns::default();
+        stub.srv.insert("_matrix-fed._tcp.delegated.test".into(), srv("fed.delegated.test", 8500));
+        let (_, resolver) = resolver(stub, port);
+
+        let resolved = resolver.resolve("remote.test").await.unwrap();
+        assert_eq!(resolved.tls_name, "delegated.test");
+        assert_eq!(resolved.connect_host, "fed.delegated.test");
+        assert_eq!(resolved.port, 8500);
+        assert_eq!(resolved.host_header, "delegated.test");
+    }
+
+    #[tokio::test]
+    async fn test_well_known_with_port_is_used_directly() {
+        let port = well_known_server(Some("delegated.test:9000")).await;
+        let (dns, resolver) = resolver(StubDns::default(), port);
+
+        let resolved = resolver.resolve("remote.test").await.unwrap();
+        assert_eq!(resolved, ResolvedServer::direct("delegated.test", 9000, "delegated.test:9000"));
+        assert_eq!(dns.srv_queries.load(Ordering::SeqCst), 0);
+    }
+
+    #[tokio::test]
+    async fn test_srv_then_default_port_without_well_known() {
+        let port = well_known_server(None).await;
+        let mut stub = StubDns::default();
+        stub.srv.insert("_matrix._tcp.legacy.test".into(), srv("host.legacy.test", 8449));
+        let (_, resolver) = resolver(stub, port);
+
+        let legacy = resolver.resolve("legacy.test").await.unwrap();
+        assert_eq!(legacy.connect_host, "host.legacy.test");
+        assert_eq!(legacy.port, 8449);
+
+        let plain = resolver.resolve("plain.test").await.unwrap();
+        assert_eq!(plain, ResolvedServer::direct("plain.test", DEFAULT_FEDERATION_PORT, "plain.test"));
+    }
+
+    #[tokio::test]
+    async fn test_resolution_is_cached() {
+        let port = well_known_server(None).await;
+        let (dns, resolver) = resolver(StubDns::default(), port);
+
+        resolver.resolve("plain.test").await.unwrap();
+        let queries = dns.srv_queries.load(Ordering::SeqCst);
+        resolver.resolve("plain.test").await.unwrap();
+        assert_eq!(dns.srv_queries.load(Ordering::SeqCst), queries);
+
+        resolver.invalidate("plain.test");
+        resolver.resolve("plain.test").await.unwrap();
+        assert!(dns.srv_queries.load(Ordering::SeqCst) > queries);
+    }
+}
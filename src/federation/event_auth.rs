@@ -1,3 +1,4 @@
+use crate::federation::pdu;
 use lru::LruCache;
 use serde_json::{json, Value};
 use std::collections::{HashMap, HashSet, VecDeque};
@@ -156,16 +157,18 @@ impl EventAuthChain {
             if let Some(prev_events) = &event.prev_events {
                 if let Some(prev_array) = prev_events.as_array() {
                     for prev_entry in prev_array {
-                        if let Some(inner_array) = prev_entry.as_array() {
-                            if let Some(prev_id) = inner_array.first().and_then(|v| v.as_str()) {
-                                if event_map.contains_key(prev_id) {
-                                    graph
-                                        .entry(prev_id.to_string())
-                                        .or_default()
-                                        .push(event.event_id.clone());
-
-                                    *in_degree.entry(event.event_id.clone()).or_default() += 1;
-                                }
+                        // Room v1/v2 reference `[event_id, hashes]` pairs, later versions bare IDs
+                        let prev_id = prev_entry.as_str().or_else(|| {
+                            prev_entry.as_array().and_then(|pair| pair.first()).and_then(|v| v.as_str())
+                        });
+                        if let Some(prev_id) = prev_id {
+                            if event_map.contains_key(prev_id) {
+                                graph
+                                    .entry(prev_id.to_string())
+                                    .or_default()
+                                    .push(event.event_id.clone());
+
+                                *in_degree.entry(event.event_id.clone()).or_default() += 1;
                             }
                         }
                     }
@@ -254,69 +257,6 @@ impl EventAuthChain {
         conflicts
     }
 
-    pub fn resolve_conflicts_power_based(
-        &self,
-        state_events: &[Value],
-        power_levels: &HashMap<String, i64>,
-    ) -> Vec<ConflictInfo> {
-        let mut conflicts = Vec::new();
-        let mut state_by_key: HashMap<String, Vec<(i64, String, i64)>> = HashMap::new();
-
-        for event in state_events {
-            let event_type = event.get("type").and_then(|v| v.as_str()).unwrap_or("");
-            let state_key = event
-                .get("state_key")
-                .and_then(|v| v.as_str())
-                .unwrap_or("");
-            let event_id = event.get("event_id").and_then(|v| v.as_str()).unwrap_or("");
-            let origin_server_ts = event
-                .get("origin_server_ts")
-                .and_then(|v| v.as_i64())
-                .unwrap_or(0);
-            let sender = event.get("sender").and_then(|v| v.as_str()).unwrap_or("");
-
-            if state_key.is_empty() {
-                continue;
-            }
-
-            let sender_power = power_levels.get(sender).copied().unwrap_or(0);
-            let key = format!("{}:{}", event_type, state_key);
-            state_by_key.entry(key.clone()).or_default().push((
-                origin_server_ts,
-                event_id.to_string(),
-                sender_power,
-            ));
-        }
-
-        for (key, events) in &state_by_key {
-            if events.len() > 1 {
-                let mut sorted_events = events.clone();
-                sorted_events.sort_by(|a, b| b.2.cmp(&a.2).then_with(|| b.0.cmp(&a.0)));
-
-                let winner = &sorted_events[0];
-                let losers: Vec<String> = sorted_events[1..]
-                    .iter()
-                    .map(|(_, eid, _)| eid.clone())
-                    .collect();
-
-                let reason = if winner.2 > 0 {
-                    format!("Power-based resolution: sender power={}", winner.2)
-                } else {
-                    "Timestamp-based resolution: equal power levels".to_string()
-                };
-
-                conflicts.push(ConflictInfo {
-                    state_key: key.clone(),
-                    winning_event: winner.1.clone(),
-                    losing_events: losers,
-                    resolution_reason: reason,
-                });
-            }
-        }
-
-        conflicts
-    }
-
     pub async fn calculate_event_depth_with_cache(
         &self,
         events: &[EventInfo],
@@ -424,6 +364,8 @@ impl EventAuthChain {
         }
     }
 
+    /// Collects state content reachable through the auth chains of `event_ids`. This is not
+    /// state resolution; forks are resolved by [`crate::federation::state_res::resolve_state`].
     pub fn resolve_state_with_auth_chain<'a>(
         &'a self,
         events: &'a HashMap<String, EventData>,
@@ -604,6 +546,453 @@ impl EventAuthChain {
     }
 }
 
+/// State an event is authorised against, keyed by `(type, state_key)`.
+pub type AuthState = HashMap<(String, String), Value>;
+
+const DEFAULT_BAN_LEVEL: i64 = 50;
+const DEFAULT_KICK_LEVEL: i64 = 50;
+const DEFAULT_INVITE_LEVEL: i64 = 0;
+const DEFAULT_STATE_LEVEL: i64 = 50;
+
+fn auth_key(event_type: &str, state_key: &str) -> (String, String) {
+    (event_type.to_string(), state_key.to_string())
+}
+
+fn int_value(value: &Value) -> Option<i64> {
+    value
+        .as_i64()
+        .or_else(|| value.as_str().and_then(|s| s.trim().parse().ok()))
+}
+
+fn room_creator(create: &Value) -> &str {
+    create["content"]["creator"]
+        .as_str()
+        .or_else(|| create["sender"].as_str())
+        .unwrap_or_default()
+}
+
+fn membership_in<'a>(auth_state: &'a AuthState, user_id: &str) -> Option<&'a str> {
+    auth_state
+        .get(&auth_key("m.room.member", user_id))
+        .and_then(|e| e["content"]["membership"].as_str())
+}
+
+pub(crate) fn user_power_level(user_id: &str, power_levels: Option<&Value>, create: &Value) -> i64 {
+    match power_levels {
+        Some(pl) => pl["content"]["users"]
+            .get(user_id)
+            .and_then(int_value)
+            .or_else(|| int_value(&pl["content"]["users_default"]))
+            .unwrap_or(0),
+        None if room_creator(create) == user_id => 100,
+        None => 0,
+    }
+}
+
+fn named_power_level(power_levels: Option<&Value>, name: &str, default: i64) -> i64 {
+    power_levels
+        .and_then(|pl| int_value(&pl["content"][name]))
+        .unwrap_or(default)
+}
+
+fn required_event_level(event_type: &str, is_state: bool, power_levels: Option<&Value>) -> i64 {
+    let Some(pl) = power_levels else {
+        return 0;
+    };
+    pl["content"]["events"]
+        .get(event_type)
+        .and_then(int_value)
+        .unwrap_or_else(|| {
+            if is_state {
+                named_power_level(power_levels, "state_default", DEFAULT_STATE_LEVEL)
+            } else {
+                named_power_level(power_levels, "events_default", 0)
+            }
+        })
+}
+
+impl EventAuthChain {
+    /// The `(type, state_key)` pairs whose current values authorise `event`.
+    pub fn auth_types_for_event(event: &Value) -> Vec<(String, String)> {
+        let event_type = event["type"].as_str().unwrap_or_default();
+        if event_type == "m.room.create" {
+            return Vec::new();
+        }
+
+        let sender = event["sender"].as_str().unwrap_or_default();
+        let mut types = vec![
+            auth_key("m.room.create", ""),
+            auth_key("m.room.power_levels", ""),
+            auth_key("m.room.member", sender),
+        ];
+
+        if event_type == "m.room.member" {
+            let content = &event["content"];
+            if let Some(target) = event["state_key"].as_str() {
+                types.push(auth_key("m.room.member", target));
+            }
+            let membership = content["membership"].as_str().unwrap_or_default();
+            if matches!(membership, "join" | "invite" | "knock") {
+                types.push(auth_key("m.room.join_rules", ""));
+            }
+            if membership == "invite" {
+                if let Some(token) = content["third_party_invite"]["signed"]["token"].as_str() {
+                    types.push(auth_key("m.room.third_party_invite", token));
+                }
+            }
+            if membership == "join" {
+                if let Some(user) = content["join_authorised_via_users_server"].as_str() {
+                    types.push(auth_key("m.room.member", user));
+                }
+            }
+        }
+
+        let mut seen = HashSet::new();
+        types.retain(|key| seen.insert(key.clone()));
+        types
+    }
+
+    /// Applies the Matrix authorization rules to `event` given the state in `auth_state`.
+    pub fn check_auth_rules(event: &Value, auth_state: &AuthState) -> Result<(), String> {
+        let event_type = event["type"].as_str().ok_or("Event has no type")?;
+        let sender = event["sender"].as_str().ok_or("Event has no sender")?;
+
+        if event_type == "m.room.create" {
+            if !pdu::referenced_event_ids(event.get("prev_events")).is_empty() {
+                return Err("m.room.create must not have prev_events".to_string());
+            }
+            let room_server = event["room_id"].as_str().and_then(pdu::server_name_of);
+            if room_server.is_none() || room_server != pdu::server_name_of(sender) {
+                return Err("Room ID and sender are on different servers".to_string());
+            }
+            if let Some(version) = event["content"]["room_version"].as_str() {
+                if !pdu::is_supported_room_version(version) {
+                    return Err(format!("Unsupported room version {}", version));
+                }
+            }
+            return Ok(());
+        }
+
+        let create = auth_state
+            .get(&auth_key("m.room.create", ""))
+            .ok_or("Missing m.room.create auth event")?;
+        if create["content"]["m.federate"] == false
+            && pdu::server_name_of(sender) != pdu::server_name_of(room_creator(create))
+        {
+            return Err("Room does not allow federation".to_string());
+        }
+
+        let power_levels = auth_state.get(&auth_key("m.room.power_levels", ""));
+        if event_type == "m.room.member" {
+            return Self::check_member_auth(event, create, power_levels, auth_state);
+        }
+
+        if membership_in(auth_state, sender) != Some("join") {
+            return Err("Sender is not joined to the room".to_string());
+        }
+
+        let sender_level = user_power_level(sender, power_levels, create);
+        if event_type == "m.room.third_party_invite"
+            && sender_level < named_power_level(power_levels, "invite", DEFAULT_INVITE_LEVEL)
+        {
+            return Err("Sender cannot invite".to_string());
+        }
+
+        let state_key = event["state_key"].as_str();
+        if sender_level < required_event_level(event_type, state_key.is_some(), power_levels) {
+            return Err(format!("Sender lacks power to send {}", event_type));
+        }
+
+        if state_key.is_some_and(|key| key.starts_with('@') && key != sender) {
+            return Err("State key is another user's ID".to_string());
+        }
+
+        if event_type == "m.room.power_levels" {
+            Self::check_power_levels_change(event, power_levels, sender, sender_level)?;
+        }
+
+        Ok(())
+    }
+
+    fn check_member_auth(
+        event: &Value,
+        create: &Value,
+        power_levels: Option<&Value>,
+        auth_state: &AuthState,
+    ) -> Result<(), String> {
+        let sender = event["sender"].as_str().unwrap_or_default();
+        let target = event["state_key"]
+            .as_str()
+            .ok_or("Member event has no state_key")?;
+        let content = &event["content"];
+        let membership = content["membership"]
+            .as_str()
+            .ok_or("Member event has no membership")?;
+
+        let sender_membership = membership_in(auth_state, sender);
+        let target_membership = membership_in(auth_state, target);
+        let sender_level = user_power_level(sender, power_levels, create);
+        let target_level = user_power_level(target, power_levels, create);
+        let join_rule = auth_state
+            .get(&auth_key("m.room.join_rules", ""))
+            .and_then(|e| e["content"]["join_rule"].as_str())
+            .unwrap_or("invite");
+
+        match membership {
+            "join" => {
+                // The creator's own join straight after the create event
+                let prev_events = pdu::referenced_event_ids(event.get("prev_events"));
+                if prev_events.len() == 1
+                    && create["event_id"].as_str() == Some(prev_events[0].as_str())
+                    && target == room_creator(create)
+                {
+                    return Ok(());
+                }
+                if sender != target {
+                    return Err("Cannot join on behalf of another user".to_string());
+                }
+                if target_membership == Some("ban") {
+                    return Err("User is banned from the room".to_string());
+                }
+                let already_allowed = matches!(target_membership, Some("join") | Some("invite"));
+                match join_rule {
+                    "public" => Ok(()),
+                    "invite" | "knock" if already_allowed => Ok(()),
+                    "restricted" | "knock_restricted" if already_allowed => Ok(()),
+                    "restricted" | "knock_restricted" => {
+                        let authoriser = content["join_authorised_via_users_server"]
+                            .as_str()
+                            .ok_or("Restricted join has no authorising user")?;
+                        if membership_in(auth_state, authoriser) != Some("join") {
+                            return Err("Authorising user is not joined".to_string());
+                        }
+                        if user_power_level(authoriser, power_levels, create)
+                            < named_power_level(power_levels, "invite", DEFAULT_INVITE_LEVEL)
+                        {
+                            return Err("Authorising user cannot invite".to_string());
+                        }
+                        Ok(())
+                    }
+                    _ => Err("Join rules do not allow this join".to_string()),
+                }
+            }
+            "invite" => {
+                if content["third_party_invite"].is_object() {
+                    if target_membership == Some("ban") {
+                        return Err("User is banned from the room".to_string());
+                    }
+                    return Self::check_third_party_invite(event, target, sender, auth_state);
+                }
+                if sender_membership != Some("join") {
+                    return Err("Sender is not joined to the room".to_string());
+                }
+                if matches!(target_membership, Some("join") | Some("ban")) {
+                    return Err("Target is already joined or banned".to_string());
+                }
+                if sender_level < named_power_level(power_levels, "invite", DEFAULT_INVITE_LEVEL) {
+                    return Err("Sender cannot invite".to_string());
+                }
+                Ok(())
+            }
+            "leave" => {
+                if sender == target {
+                    return match sender_membership {
+                        Some("invite") | Some("join") | Some("knock") => Ok(()),
+                        _ => Err("Sender is not in the room".to_string()),
+                    };
+                }
+                if sender_membership != Some("join") {
+                    return Err("Sender is not joined to the room".to_string());
+                }
+                if target_membership == Some("ban")
+                    && sender_level < named_power_level(power_levels, "ban", DEFAULT_BAN_LEVEL)
+                {
+                    return Err("Sender cannot unban".to_string());
+                }
+                if sender_level >= named_power_level(power_levels, "kick", DEFAULT_KICK_LEVEL)
+                    && target_level < sender_level
+                {
+                    Ok(())
+                } else {
+                    Err("Sender cannot kick this user".to_string())
+                }
+            }
+            "ban" => {
+                if sender_membership != Some("join") {
+                    return Err("Sender is not joined to the room".to_string());
+                }
+                if sender_level >= named_power_level(power_levels, "ban", DEFAULT_BAN_LEVEL)
+                    && target_level < sender_level
+                {
+                    Ok(())
+                } else {
+                    Err("Sender cannot ban this user".to_string())
+                }
+            }
+            "knock" => {
+                let room_version = create["content"]["room_version"].as_str().unwrap_or("1");
+                if !pdu::supports_knocking(room_version) {
+                    return Err(format!("Room version {} does not support knocking", room_version));
+                }
+                let knockable = match join_rule {
+                    "knock" => true,
+                    "knock_restricted" => pdu::supports_knock_restricted(room_version),
+                    _ => false,
+                };
+                if !knockable {
+                    return Err("Join rules do not allow knocking".to_string());
+                }
+                if sender != target {
+                    return Err("Cannot knock on behalf of another user".to_string());
+                }
+                match target_membership {
+                    Some("ban") | Some("join") | Some("invite") => {
+                        Err("User cannot knock from their current membership".to_string())
+                    }
+                    _ => Ok(()),
+                }
+            }
+            other => Err(format!("Unknown membership {}", other)),
+        }
+    }
+
+    fn check_third_party_invite(
+        event: &Value,
+        target: &str,
+        sender: &str,
+        auth_state: &AuthState,
+    ) -> Result<(), String> {
+        let signed = &event["content"]["third_party_invite"]["signed"];
+        if signed["mxid"].as_str() != Some(target) {
+            return Err("Third-party invite is for another user".to_string());
+        }
+        let token = signed["token"]
+            .as_str()
+            .ok_or("Third-party invite has no token")?;
+        let invite = auth_state
+            .get(&auth_key("m.room.third_party_invite", token))
+            .ok_or("Unknown third-party invite token")?;
+        if invite["sender"].as_str() != Some(sender) {
+            return Err("Third-party invite was sent by another user".to_string());
+        }
+
+        let mut public_keys: Vec<&str> = invite["content"]["public_keys"]
+            .as_array()
+            .map(|keys| keys.iter().filter_map(|k| k["public_key"].as_str()).collect())
+            .unwrap_or_default();
+        if let Some(key) = invite["content"]["public_key"].as_str() {
+            public_keys.push(key);
+        }
+
+        let mut unsigned = signed.clone();
+        if let Some(map) = unsigned.as_object_mut() {
+            map.remove("signatures");
+        }
+        let signed_bytes = pdu::canonical_json_bytes(&unsigned);
+        let signatures = signed["signatures"]
+            .as_object()
+            .into_iter()
+            .flat_map(|servers| servers.values())
+            .filter_map(|keys| keys.as_object())
+            .flat_map(|keys| keys.values())
+            .filter_map(|sig| sig.as_str());
+
+        for signature in signatures {
+            if public_keys
+                .iter()
+                .any(|key| verify_ed25519(key, signature, &signed_bytes))
+            {
+                return Ok(());
+            }
+        }
+        Err("Third-party invite signature is invalid".to_string())
+    }
+
+    fn check_power_levels_change(
+        event: &Value,
+        current: Option<&Value>,
+        sender: &str,
+        sender_level: i64,
+    ) -> Result<(), String> {
+        let new = &event["content"];
+        for field in ["users", "events"] {
+            if let Some(entries) = new[field].as_object() {
+                if entries.values().any(|v| int_value(v).is_none()) {
+                    return Err(format!("Power levels {} must be integers", field));
+                }
+            }
+        }
+
+        let Some(current) = current else {
+            return Ok(());
+        };
+        let old = &current["content"];
+        let exceeds_sender = |level: Option<i64>| level.is_some_and(|l| l > sender_level);
+
+        for field in [
+            "users_default",
+            "events_default",
+            "state_default",
+            "ban",
+            "redact",
+            "kick",
+            "invite",
+        ] {
+            let (before, after) = (int_value(&old[field]), int_value(&new[field]));
+            if before != after && (exceeds_sender(before) || exceeds_sender(after)) {
+                return Err(format!("Sender cannot change {}", field));
+            }
+        }
+
+        for field in ["events", "users"] {
+            let mut keys: HashSet<&String> = HashSet::new();
+            keys.extend(old[field].as_object().into_iter().flat_map(|m| m.keys()));
+            keys.extend(new[field].as_object().into_iter().flat_map(|m| m.keys()));
+
+            for key in keys {
+                let before = old[field].get(key).and_then(int_value);
+                let after = new[field].get(key).and_then(int_value);
+                if before == after {
+                    continue;
+                }
+                if exceeds_sender(before) || exceeds_sender(after) {
+                    return Err(format!("Sender cannot change {} level of {}", field, key));
+                }
+                if field == "users"
+                    && key != sender
+                    && before.is_some_and(|level| level >= sender_level)
+                {
+                    return Err(format!("Sender cannot change the level of {}", key));
+                }
+            }
+        }
+
+        Ok(())
+    }
+}
+
+pub(crate) fn verify_ed25519(public_key: &str, signature: &str, message: &[u8]) -> bool {
+    use base64::Engine;
+    let decode = |value: &str| {
+        base64::engine::general_purpose::STANDARD_NO_PAD
+            .decode(value.trim_end_matches('='))
+            .or_else(|_| base64::engine::general_purpose::URL_SAFE_NO_PAD.decode(value))
+            .ok()
+    };
+
+    let Some(key) = decode(public_key).and_then(|k| <[u8; 32]>::try_from(k).ok()) else {
+        return false;
+    };
+    let Some(signature) = decode(signature)
+        .and_then(|s| ed25519_dalek::Signature::from_slice(&s).ok())
+    else {
+        return false;
+    };
+    ed25519_dalek::VerifyingKey::from_bytes(&key)
+        .map(|key| key.verify_strict(message, &signature).is_ok())
+        .unwrap_or(false)
+}
+
 #[derive(Debug, Clone)]
 pub struct EventData {
     pub event_id: String,
@@ -676,6 +1065,30 @@ mod tests {
         assert_eq!(depth_map.get("$3"), Some(&3));
     }
 
+    #[test]
+    fn test_calculate_event_depth_bare_event_ids() {
+        let events = vec![
+            EventInfo {
+                event_id: "$3".to_string(),
+                prev_events: Some(serde_json::json!(["$2", "$1"])),
+            },
+            EventInfo {
+                event_id: "$2".to_string(),
+                prev_events: Some(serde_json::json!(["$1"])),
+            },
+            EventInfo {
+                event_id: "$1".to_string(),
+                prev_events: Some(serde_json::json!(["$unknown"])),
+            },
+        ];
+
+        let depth_map = EventAuthChain::new().calculate_event_depth(&events);
+
+        assert_eq!(depth_map.get("$1"), Some(&1));
+        assert_eq!(depth_map.get("$2"), Some(&2));
+        assert_eq!(depth_map.get("$3"), Some(&3));
+    }
+
     #[test]
     fn test_calculate_event_depth_empty() {
         let events: Vec<EventInfo> = vec![];
@@ -816,4 +1229,217 @@ mod tests {
 
         assert!(!result);
     }
+
+    fn state_event(event_type: &str, state_key: &str, sender: &str, content: Value) -> Value {
+        json!({
+            "event_id": format!("${}{}", event_type, state_key),
+            "type": event_type,
+            "state_key": state_key,
+            "sender": sender,
+            "content": content
+        })
+    }
+
+    fn room_state(join_rule: &str) -> AuthState {
+        let mut state = AuthState::new();
+        for event in [
+            state_event("m.room.create", "", "@alice:a.org", json!({"creator": "@alice:a.org"})),
+            state_event(
+                "m.room.member",
+                "@alice:a.org",
+                "@alice:a.org",
+                json!({"membership": "join"}),
+            ),
+            state_event(
+                "m.room.power_levels",
+                "",
+                "@alice:a.org",
+                json!({"users": {"@alice:a.org": 100}, "users_default": 0, "kick": 50, "ban": 50}),
+            ),
+            state_event("m.room.join_rules", "", "@alice:a.org", json!({"join_rule": join_rule})),
+        ] {
+            let key = (
+                event["type"].as_str().unwrap().to_string(),
+                event["state_key"].as_str().unwrap().to_string(),
+            );
+            state.insert(key, event);
+        }
+        state
+    }
+
+    fn member_event(sender: &str, target: &str, membership: &str) -> Value {
+        json!({
+            "type": "m.room.member",
+            "room_id": "!room:a.org",
+            "sender": sender,
+            "state_key": target,
+            "content": {"membership": membership}
+        })
+    }
+
+    #[test]
+    fn test_auth_types_for_member_event() {
+        let types = EventAuthChain::auth_types_for_event(&member_event(
+            "@alice:a.org",
+            "@bob:b.org",
+            "invite",
+        ));
+        assert!(types.contains(&("m.room.create".to_string(), String::new())));
+        assert!(types.contains(&("m.room.member".to_string(), "@bob:b.org".to_string())));
+        assert!(types.contains(&("m.room.join_rules".to_string(), String::new())));
+    }
+
+    #[test]
+    fn test_auth_rules_join_follows_join_rule() {
+        let join = member_event("@bob:b.org", "@bob:b.org", "join");
+
+        assert!(EventAuthChain::check_auth_rules(&join, &room_state("public")).is_ok());
+        assert!(EventAuthChain::check_auth_rules(&join, &room_state("invite")).is_err());
+
+        let mut invited = room_state("invite");
+        invited.insert(
+            ("m.room.member".to_string(), "@bob:b.org".to_string()),
+            state_event("m.room.member", "@bob:b.org", "@alice:a.org", json!({"membership": "invite"})),
+        );
+        assert!(EventAuthChain::check_auth_rules(&join, &invited).is_ok());
+
+        let on_behalf = member_event("@mallory:b.org", "@bob:b.org", "join");
+        assert!(EventAuthChain::check_auth_rules(&on_behalf, &room_state("public")).is_err());
+    }
+
+    #[test]
+    fn test_auth_rules_reject_non_member_messages() {
+        let message = json!({
+            "type": "m.room.message",
+            "room_id": "!room:a.org",
+            "sender": "@mallory:evil.org",
+            "content": {"body": "spam"}
+        });
+        assert!(EventAuthChain::check_auth_rules(&message, &room_state("public")).is_err());
+
+        let mut from_member = message.clone();
+        from_member["sender"] = json!("@alice:a.org");
+        assert!(EventAuthChain::check_auth_rules(&from_member, &room_state("public")).is_ok());
+    }
+
+    #[test]
+    fn test_auth_rules_require_create_event() {
+        let mut state = room_state("public");
+        state.remove(&("m.room.create".to_string(), String::new()));
+        let join = member_event("@bob:b.org", "@bob:b.org", "join");
+        assert!(EventAuthChain::check_auth_rules(&join, &state).is_err());
+    }
+
+    #[test]
+    fn test_auth_rules_kick_and_ban_need_power() {
+        let mut state = room_state("public");
+        state.insert(
+            ("m.room.member".to_string(), "@bob:b.org".to_string()),
+            state_event("m.room.member", "@bob:b.org", "@bob:b.org", json!({"membership": "join"})),
+        );
+
+        let kick_by_admin = member_event("@alice:a.org", "@bob:b.org", "leave");
+        assert!(EventAuthChain::check_auth_rules(&kick_by_admin, &state).is_ok());
+
+        let ban_by_user = member_event("@bob:b.org", "@alice:a.org", "ban");
+        assert!(EventAuthChain::check_auth_rules(&ban_by_user, &state).is_err());
+
+        let leave_self = member_event("@bob:b.org", "@bob:b.org", "leave");
+        assert!(EventAuthChain::check_auth_rules(&leave_self, &state).is_ok());
+    }
+
+    #[test]
+    fn test_auth_rules_knock_follows_join_rule_and_room_version() {
+        let knock = member_event("@bob:b.org", "@bob:b.org", "knock");
+        let versioned = |join_rule: &str, room_version: &str| {
+            let mut state = room_state(join_rule);
+            state.insert(
+                ("m.room.create".to_string(), String::new()),
+                state_event(
+                    "m.room.create",
+                    "",
+                    "@alice:a.org",
+                    json!({"creator": "@alice:a.org", "room_version": room_version}),
+                ),
+            );
+            state
+        };
+
+        assert!(EventAuthChain::check_auth_rules(&knock, &versioned("knock", "7")).is_ok());
+        assert!(EventAuthChain::check_auth_rules(&knock, &versioned("knock", "6")).is_err());
+        assert!(EventAuthChain::check_auth_rules(&knock, &versioned("knock_restricted", "10")).is_ok());
+        assert!(EventAuthChain::check_auth_rules(&knock, &versioned("knock_restricted", "9")).is_err());
+        assert!(EventAuthChain::check_auth_rules(&knock, &versioned("invite", "10")).is_err());
+
+        let on_behalf = member_event("@alice:a.org", "@bob:b.org", "knock");
+        assert!(EventAuthChain::check_auth_rules(&on_behalf, &versioned("knock", "10")).is_err());
+
+        let mut banned = versioned("knock", "10");
+        banned.insert(
+            ("m.room.member".to_string(), "@bob:b.org".to_string()),
+            state_event("m.room.member", "@bob:b.org", "@alice:a.org", json!({"membership": "ban"})),
+        );
+        assert!(EventAuthChain::check_auth_rules(&knock, &banned).is_err());
+    }
+
+    #[test]
+    fn test_auth_rules_power_level_escalation() {
+        let mut state = room_state("public");
+        state.insert(
+            ("m.room.member".to_string(), "@bob:b.org".to_string()),
+            state_event("m.room.member", "@bob:b.org", "@bob:b.org", json!({"membership": "join"})),
+        );
+        state.insert(
+            ("m.room.power_levels".to_string(), String::new()),
+            state_event(
+                "m.room.power_levels",
+                "",
+                "@alice:a.org",
+                json!({
+                    "users": {"@alice:a.org": 100, "@bob:b.org": 50},
+                    "events": {"m.room.power_levels": 50}
+                }),
+            ),
+        );
+
+        let escalate = json!({
+            "type": "m.room.power_levels",
+            "room_id": "!room:a.org",
+            "sender": "@bob:b.org",
+            "state_key": "",
+            "content": {
+                "users": {"@alice:a.org": 100, "@bob:b.org": 100},
+                "events": {"m.room.power_levels": 50}
+            }
+        });
+        assert!(EventAuthChain::check_auth_rules(&escalate, &state).is_err());
+
+        let demote_admin = json!({
+            "type": "m.room.power_levels",
+            "room_id": "!room:a.org",
+            "sender": "@bob:b.org",
+            "state_key": "",
+            "content": {
+                "users": {"@alice:a.org": 0, "@bob:b.org": 50},
+                "events": {"m.room.power_levels": 50}
+            }
+        });
+        assert!(EventAuthChain::check_auth_rules(&demote_admin, &state).is_err());
+    }
+
+    #[test]
+    fn test_auth_rules_create_event() {
+        let create = json!({
+            "type": "m.room.create",
+            "room_id": "!room:a.org",
+            "sender": "@alice:a.org",
+            "state_key": "",
+            "content": {"room_version": "10"}
+        });
+        assert!(EventAuthChain::check_auth_rules(&create, &AuthState::new()).is_ok());
+
+        let mut foreign = create.clone();
+        foreign["sender"] = json!("@mallory:evil.org");
+        assert!(EventAuthChain::check_auth_rules(&foreign, &AuthState::new()).is_err());
+    }
 }
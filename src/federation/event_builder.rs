@@ -0,0 +1,335 @@
+//! Builds the federation form of locally created events: DAG references, depth, content
+//! hash, server signature and, from room v3 onwards, the reference-hash event ID.
+
+use crate::federation::pdu;
+use base64::Engine;
+use ed25519_dalek::Signer;
+use serde_json::{json, Map, Value};
+
+/// Room version used for rooms created without an explicit `room_version`.
+pub const DEFAULT_ROOM_VERSION: &str = "10";
+
+/// Upper bound on `prev_events` referenced by a single event.
+pub const MAX_PREV_EVENTS: usize = 10;
+
+/// The server's ed25519 key used to sign outgoing events.
+#[derive(Clone)]
+pub struct EventSigningKey {
+    server_name: String,
+    key_id: String,
+    key: ed25519_dalek::SigningKey,
+}
+
+impl std::fmt::Debug for EventSigningKey {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("EventSigningKey")
+            .field("server_name", &self.server_name)
+            .field("key_id", &self.key_id)
+            .finish_non_exhaustive()
+    }
+}
+
+impl EventSigningKey {
+    /// Accepts the 32-byte seed in any of the base64 alphabets used for `signing_key`.
+    pub fn from_base64(server_name: &str, key_id: &str, secret: &str) -> Option<Self> {
+        let seed = decode_base64_32(secret)?;
+        Some(Self {
+            server_name: server_name.to_string(),
+            key_id: key_id.to_string(),
+            key: ed25519_dalek::SigningKey::from_bytes(&seed),
+        })
+    }
+
+    pub fn server_name(&self) -> &str {
+        &self.server_name
+    }
+
+    pub fn key_id(&self) -> &str {
+        &self.key_id
+    }
+
+    pub fn verify_key_base64(&self) -> String {
+        base64::engine::general_purpose::STANDARD_NO_PAD.encode(self.key.verifying_key().as_bytes())
+    }
+
+    /// Adds this server's signature over the redacted event to `signatures`.
+    pub fn sign_event(&self, event: &mut Value, room_version: &str) {
+        let signature = self.key.sign(&pdu::signing_bytes(event, room_version));
+        let encoded = base64::engine::general_purpose::STANDARD_NO_PAD.encode(signature.to_bytes());
+
+        if !event.get("signatures").is_some_and(Value::is_object) {
+            event["signatures"] = Value::Object(Map::new());
+        }
+        event["signatures"][self.server_name.as_str()][self.key_id.as_str()] = Value::String(encoded);
+    }
+}
+
+/// Decodes a 32-byte key written in standard or URL-safe base64, padded or not.
+pub(crate) fn decode_base64_32(value: &str) -> Option<[u8; 32]> {
+    let value = value.trim();
+    let engines = [
+        base64::engine::general_purpose::STANDARD,
+        base64::engine::general_purpose::STANDARD_NO_PAD,
+        base64::engine::general_purpose::URL_SAFE,
+        base64::engine::general_purpose::URL_SAFE_NO_PAD,
+    ];
+
+    for engine in engines {
+        if let Ok(bytes) = engine.decode(value) {
+            if bytes.len() == 32 {
+                let mut out = [0u8; 32];
+                out.copy_from_slice(&bytes);
+                return Some(out);
+            }
+        }
+    }
+    None
+}
+
+/// Where a new event attaches to the room DAG.
+#[derive(Debug, Clone, Default)]
+pub struct EventDagContext {
+    pub room_version: String,
+    pub prev_events: Vec<String>,
+    pub auth_events: Vec<String>,
+    pub depth: i64,
+}
+
+/// A locally created event, before it is placed in the DAG.
+#[derive(Debug, Clone)]
+pub struct EventBuilder {
+    pub room_id: String,
+    pub sender: String,
+    pub event_type: String,
+    pub content: Value,
+    pub state_key: Option<String>,
+    pub origin_server_ts: i64,
+}
+
+impl EventBuilder {
+    pub fn new(room_id: &str, sender: &str, event_type: &str, content: Value) -> Self {
+        Self {
+            room_id: room_id.to_string(),
+            sender: sender.to_string(),
+            event_type: event_type.to_string(),
+            content,
+            state_key: None,
+            origin_server_ts: chrono::Utc::now().timestamp_millis(),
+        }
+    }
+
+    pub fn with_state_key(mut self, state_key: impl Into<String>) -> Self {
+        self.state_key = Some(state_key.into());
+        self
+    }
+
+    pub fn with_origin_server_ts(mut self, origin_server_ts: i64) -> Self {
+        self.origin_server_ts = origin_server_ts;
+        self
+    }
+
+    /// The fields `auth_types_for_event` needs, before the DAG context is known.
+    pub fn auth_probe(&self) -> Value {
+        let mut event = json!({
+            "type": self.event_type,
+            "sender": self.sender,
+            "content": self.content,
+        });
+        if let Some(state_key) = &self.state_key {
+            event["state_key"] = json!(state_key);
+        }
+        event
+    }
+
+    /// The unsigned, unhashed event, as handed out by `make_join`/`make_leave`.
+    pub fn template(&self, origin: &str, ctx: &EventDagContext) -> Value {
+        let mut event = json!({
+            "room_id": self.room_id,
+            "sender": self.sender,
+            "type": self.event_type,
+            "content": self.content,
+            "origin": origin,
+            "origin_server_ts": self.origin_server_ts,
+            "depth": ctx.depth,
+            "prev_events": event_refs(&ctx.prev_events, &ctx.room_version),
+            "auth_events": event_refs(&ctx.auth_events, &ctx.room_version),
+        });
+        if let Some(state_key) = &self.state_key {
+            event["state_key"] = json!(state_key);
+        }
+        // Before v11 the redacted event ID is a top-level key covered by the signature.
+        if self.event_type == "m.room.redaction" && !is_v11_redaction_format(&ctx.room_version) {
+            if let Some(redacts) = self.content.get("redacts") {
+                event["redacts"] = redacts.clone();
+            }
+        }
+        event
+    }
+
+    /// Hashes and signs the event, returning its final ID and PDU. `event_id` is only used for
+    /// room v1/v2; later versions derive the ID from the reference hash.
+    pub fn build(
+        &self,
+        origin: &str,
+        ctx: &EventDagContext,
+        event_id: &str,
+        signing_key: Option<&EventSigningKey>,
+    ) -> (String, Value) {
+        finish_event(self.template(origin, ctx), &ctx.room_version, event_id, signing_key)
+    }
+}
+
+/// Hashes and signs an event template, such as one returned by a remote `make_join`, and
+/// returns its final ID and PDU. `event_id` is only used for room v1/v2.
+pub fn finish_event(
+    mut event: Value,
+    room_version: &str,
+    event_id: &str,
+    signing_key: Option<&EventSigningKey>,
+) -> (String, Value) {
+    if let Some(fields) = event.as_object_mut() {
+        fields.remove("hashes");
+        fields.remove("signatures");
+        fields.remove("unsigned");
+    }
+    if pdu::has_explicit_event_id(room_version) {
+        event["event_id"] = json!(event_id);
+    }
+    event["hashes"] = json!({ "sha256": pdu::compute_content_hash(&event, room_version) });
+    if let Some(key) = signing_key {
+        key.sign_event(&mut event, room_version);
+    }
+
+    let event_id = pdu::compute_event_id(&event, room_version).unwrap_or_else(|| event_id.to_string());
+    (event_id, event)
+}
+
+fn is_v11_redaction_format(room_version: &str) -> bool {
+    room_version.parse::<u32>().is_ok_and(|v| v >= 11)
+}
+
+/// Event references are `[id, hashes]` pairs in v1/v2 and bare IDs afterwards.
+fn event_refs(event_ids: &[String], room_version: &str) -> Value {
+    if pdu::has_explicit_event_id(room_version) {
+        Value::Array(event_ids.iter().map(|id| json!([id, {}])).collect())
+    } else {
+        json!(event_ids)
+    }
+}
+
+/// The room version stated by an `m.room.create` event; absent means v1.
+pub fn room_version_from_create(content: &Value) -> String {
+    content
+        .get("room_version")
+        .and_then(|v| v.as_str())
+        .unwrap_or("1")
+        .to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn test_key() -> EventSigningKey {
+        let seed = base64::engine::general_purpose::STANDARD.encode([7u8; 32]);
+        EventSigningKey::from_base64("example.org", "ed25519:a_test", &seed).unwrap()
+    }
+
+    fn message() -> EventBuilder {
+        EventBuilder::new(
+            "!room:example.org",
+            "@alice:example.org",
+            "m.room.message",
+            json!({"msgtype": "m.text", "body": "hi"}),
+        )
+        .with_origin_server_ts(1_700_000_000_000)
+    }
+
+    fn ctx(room_version: &str) -> EventDagContext {
+        EventDagContext {
+            room_version: room_version.to_string(),
+            prev_events: vec!["$prev".to_string()],
+            auth_events: vec!["$create".to_string(), "$member".to_string()],
+            depth: 5,
+        }
+    }
+
+    #[test]
+    fn test_build_v10_derives_event_id_and_verifies() {
+        let key = test_key();
+        let (event_id, event) = message().build("example.org", &ctx("10"), "$ignored:example.org", Some(&key));
+
+        assert!(event.get("event_id").is_none());
+        assert_eq!(Some(event_id.clone()), pdu::compute_event_id(&event, "10"));
+        assert!(!event_id.contains(':'));
+        assert!(pdu::verify_content_hash(&event, "10"));
+        assert_eq!(event["prev_events"], json!(["$prev"]));
+        assert_eq!(event["depth"], 5);
+
+        let signature = event["signatures"]["example.org"]["ed25519:a_test"]
+            .as_str()
+            .unwrap();
+        let signature = base64::engine::general_purpose::STANDARD_NO_PAD
+            .decode(signature)
+            .unwrap();
+        let signature = ed25519_dalek::Signature::from_slice(&signature).unwrap();
+        key.key
+            .verifying_key()
+            .verify_strict(&pdu::signing_bytes(&event, "10"), &signature)
+            .unwrap();
+    }
+
+    #[test]
+    fn test_build_v1_keeps_event_id_and_pairs_references() {
+        let (event_id, event) = message().build("example.org", &ctx("1"), "$abc:example.org", None);
+
+        assert_eq!(event_id, "$abc:example.org");
+        assert_eq!(event["event_id"], "$abc:example.org");
+        assert_eq!(event["auth_events"], json!([["$create", {}], ["$member", {}]]));
+        assert!(event.get("signatures").is_none());
+        assert!(pdu::verify_content_hash(&event, "1"));
+    }
+
+    #[test]
+    fn test_build_v3_uses_standard_base64_event_id() {
+        let (v3_id, _) = message().build("example.org", &ctx("3"), "", None);
+        let (v4_id, _) = message().build("example.org", &ctx("4"), "", None);
+
+        assert_ne!(v3_id, v4_id);
+        assert!(!v4_id.contains('+') && !v4_id.contains('/'));
+    }
+
+    #[test]
+    fn test_redaction_keeps_top_level_redacts_before_v11() {
+        let redaction = EventBuilder::new(
+            "!room:example.org",
+            "@alice:example.org",
+            "m.room.redaction",
+            json!({"redacts": "$target"}),
+        );
+
+        assert_eq!(redaction.template("example.org", &ctx("10"))["redacts"], "$target");
+        assert!(redaction.template("example.org", &ctx("11")).get("redacts").is_none());
+    }
+
+    #[test]
+    fn test_finish_event_replaces_remote_hashes_and_signatures() {
+        let key = test_key();
+        let mut template = message().template("remote.org", &ctx("10"));
+        template["hashes"] = json!({"sha256": "stale"});
+        template["signatures"] = json!({"remote.org": {"ed25519:x": "stale"}});
+
+        let (event_id, event) = finish_event(template, "10", "", Some(&key));
+
+        assert!(pdu::verify_content_hash(&event, "10"));
+        assert!(event["signatures"].get("remote.org").is_none());
+        assert!(event["signatures"]["example.org"]["ed25519:a_test"].is_string());
+        assert_eq!(Some(event_id), pdu::compute_event_id(&event, "10"));
+    }
+
+    #[test]
+    fn test_signing_key_rejects_bad_seed() {
+        assert!(EventSigningKey::from_base64("example.org", "ed25519:1", "not a key").is_none());
+        assert_eq!(test_key().verify_key_base64().len(), 43);
+    }
+}
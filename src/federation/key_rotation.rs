@@ -63,21 +63,7 @@ impl KeyRotationManager {
     }
 
     pub async fn initialize(&self, secret_key: &str, key_id: &str) -> Result<(), anyhow::Error> {
-        let created_at = Utc::now().timestamp_millis();
-        let expires_at =
-            (Utc::now() + Duration::days(KEY_ROTATION_INTERVAL_DAYS)).timestamp_millis();
-
-        let public_key = self.derive_public_key(secret_key).await?;
-
-        let signing_key = SigningKey {
-            key_id: key_id.to_string(),
-            secret_key: secret_key.to_string(),
-            public_key,
-            created_at,
-            expires_at,
-        };
-
-        *self.current_key.write().await = Some(signing_key.clone());
+        let signing_key = self.set_current_key(secret_key, key_id).await?;
 
         let key_json = json!({
             "secret_key": signing_key.secret_key,
@@ -113,6 +99,26 @@ impl KeyRotationManager {
         Ok(())
     }
 
+    /// Makes `secret_key` the key used for signing without persisting it.
+    pub async fn set_current_key(&self, secret_key: &str, key_id: &str) -> Result<SigningKey, anyhow::Error> {
+        let created_at = Utc::now().timestamp_millis();
+        let expires_at =
+            (Utc::now() + Duration::days(KEY_ROTATION_INTERVAL_DAYS)).timestamp_millis();
+
+        let public_key = self.derive_public_key(secret_key).await?;
+
+        let signing_key = SigningKey {
+            key_id: key_id.to_string(),
+            secret_key: secret_key.to_string(),
+            public_key,
+            created_at,
+            expires_at,
+        };
+
+        *self.current_key.write().await = Some(signing_key.clone());
+        Ok(signing_key)
+    }
+
     pub async fn should_rotate_keys(&self) -> bool {
         if let Some(key) = &*self.current_key.read().await {
             let now = Utc::now().timestamp_millis();
@@ -219,8 +225,9 @@ impl KeyRotationManager {
         let verifying_key = ed25519_dalek::VerifyingKey::from_bytes(&pub_key_bytes)
             .map_err(|_| anyhow::anyhow!("Invalid verifying key"))?;
 
-        let sig_bytes = base64::engine::general_purpose::STANDARD
-            .decode(signature)
+        // Matrix signatures are unpadded, but tolerate padding from older peers
+        let sig_bytes = base64::engine::general_purpose::STANDARD_NO_PAD
+            .decode(signature.trim_end_matches('='))
             .map_err(|_| anyhow::anyhow!("Invalid signature format"))?;
 
         let dalek_signature = ed25519_dalek::Signature::from_slice(&sig_bytes)
@@ -368,4 +375,24 @@ mod tests {
 
         assert!(manager.is_within_grace_period(&expired_key).await);
     }
+
+    #[tokio::test]
+    async fn test_verify_signature_accepts_unpadded_base64() {
+        use ed25519_dalek::Signer;
+
+        let pool = create_test_pool().await;
+        let manager = KeyRotationManager::new(&pool, "test.example.com");
+
+        let signing_key = ed25519_dalek::SigningKey::from_bytes(&[7u8; 32]);
+        let public_key = base64::engine::general_purpose::STANDARD_NO_PAD
+            .encode(signing_key.verifying_key().as_bytes());
+        let signature = signing_key.sign(b"payload").to_bytes();
+
+        let unpadded = base64::engine::general_purpose::STANDARD_NO_PAD.encode(signature);
+        let padded = base64::engine::general_purpose::STANDARD.encode(signature);
+
+        assert!(manager.verify_signature(&public_key, &unpadded, b"payload").await.is_ok());
+        assert!(manager.verify_signature(&public_key, &padded, b"payload").await.is_ok());
+        assert!(manager.verify_signature(&public_key, &unpadded, b"other").await.is_err());
+    }
 }
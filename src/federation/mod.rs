@@ -1,13 +1,22 @@
 pub mod access_control;
+pub mod client;
 pub mod device_sync;
+pub mod discovery;
 pub mod event_auth;
+pub mod event_builder;
 pub mod friend;
 pub mod key_rotation;
 pub mod memory_tracker;
+pub mod pdu;
+pub mod state_res;
 
 pub use access_control::{FederationAccessControl, FederationPolicy};
+pub use client::{FederationClient, FederationClientConfig, FederationClientError, MembershipHandshake};
 pub use device_sync::DeviceSyncManager;
-pub use event_auth::EventAuthChain;
+pub use discovery::{DnsResolver, ResolvedServer, ServerResolver};
+pub use event_auth::{AuthState, EventAuthChain, EventInfo};
+pub use event_builder::{EventBuilder, EventDagContext, EventSigningKey};
 pub use friend::*;
 pub use key_rotation::KeyRotationManager;
 pub use memory_tracker::{FederationMemoryReport, FederationMemoryTracker, MemoryStats};
+pub use state_res::StateMap;
@@ -0,0 +1,390 @@
+//! Wire-format helpers for PDUs: canonical JSON, redaction, content and reference hashes,
+//! and the bytes a server signs, following the rules of each room version.
+
+use base64::Engine;
+use serde_json::{Map, Value};
+use sha2::{Digest, Sha256};
+
+pub const SUPPORTED_ROOM_VERSIONS: [&str; 11] =
+    ["1", "2", "3", "4", "5", "6", "7", "8", "9", "10", "11"];
+
+fn version_number(room_version: &str) -> u32 {
+    room_version.parse().unwrap_or(0)
+}
+
+pub fn is_supported_room_version(room_version: &str) -> bool {
+    SUPPORTED_ROOM_VERSIONS.contains(&room_version)
+}
+
+/// Knocking arrived in room v7.
+pub fn supports_knocking(room_version: &str) -> bool {
+    version_number(room_version) >= 7
+}
+
+/// The `knock_restricted` join rule arrived in room v10.
+pub fn supports_knock_restricted(room_version: &str) -> bool {
+    version_number(room_version) >= 10
+}
+
+/// Rooms v1 and v2 carry the event ID on the wire; later versions derive it from the
+/// reference hash.
+pub fn has_explicit_event_id(room_version: &str) -> bool {
+    version_number(room_version) <= 2
+}
+
+/// Returns the server part of a user, room or event ID.
+pub fn server_name_of(id: &str) -> Option<&str> {
+    id.split_once(':').map(|(_, server)| server).filter(|s| !s.is_empty())
+}
+
+pub fn canonical_json(value: &Value) -> String {
+    match value {
+        Value::Null => "null".to_string(),
+        Value::Bool(b) => b.to_string(),
+        Value::Number(n) => n.to_string(),
+        Value::String(s) => serde_json::to_string(s).unwrap_or_else(|_| "\"\"".to_string()),
+        Value::Array(arr) => {
+            let items: Vec<String> = arr.iter().map(canonical_json).collect();
+            format!("[{}]", items.join(","))
+        }
+        Value::Object(map) => {
+            let mut keys: Vec<&String> = map.keys().collect();
+            keys.sort();
+
+            let items: Vec<String> = keys
+                .into_iter()
+                .map(|k| {
+                    format!(
+                        "{}:{}",
+                        serde_json::to_string(k).unwrap_or_else(|_| "\"\"".to_string()),
+                        canonical_json(&map[k])
+                    )
+                })
+                .collect();
+            format!("{{{}}}", items.join(","))
+        }
+    }
+}
+
+pub fn canonical_json_bytes(value: &Value) -> Vec<u8> {
+    canonical_json(value).into_bytes()
+}
+
+fn without_keys(event: &Value, keys: &[&str]) -> Value {
+    match event {
+        Value::Object(map) => {
+            let mut map = map.clone();
+            for key in keys {
+                map.remove(*key);
+            }
+            Value::Object(map)
+        }
+        other => other.clone(),
+    }
+}
+
+/// Strips an event down to the keys that survive redaction in `room_version`.
+pub fn redact(event: &Value, room_version: &str) -> Value {
+    let version = version_number(room_version);
+    let Some(map) = event.as_object() else {
+        return event.clone();
+    };
+
+    let mut kept_keys = vec![
+        "event_id",
+        "type",
+        "room_id",
+        "sender",
+        "state_key",
+        "content",
+        "hashes",
+        "signatures",
+        "depth",
+        "prev_events",
+        "auth_events",
+        "origin_server_ts",
+    ];
+    if version <= 10 {
+        kept_keys.extend(["prev_state", "origin", "membership"]);
+    }
+
+    let mut redacted = Map::new();
+    for key in kept_keys {
+        if let Some(value) = map.get(key) {
+            redacted.insert(key.to_string(), value.clone());
+        }
+    }
+
+    let content = map.get("content").and_then(|c| c.as_object());
+    let event_type = map.get("type").and_then(|t| t.as_str()).unwrap_or_default();
+    let mut kept_content = Map::new();
+    if let Some(content) = content {
+        let keep: Vec<&str> = match event_type {
+            "m.room.member" => {
+                let mut keep = vec!["membership"];
+                if version >= 9 {
+                    keep.push("join_authorised_via_users_server");
+                }
+                keep
+            }
+            "m.room.create" if version >= 11 => content.keys().map(String::as_str).collect(),
+            "m.room.create" => vec!["creator"],
+            "m.room.join_rules" if version >= 8 => vec!["join_rule", "allow"],
+            "m.room.join_rules" => vec!["join_rule"],
+            "m.room.power_levels" => {
+                let mut keep = vec![
+                    "ban",
+                    "events",
+                    "events_default",
+                    "kick",
+                    "redact",
+                    "state_default",
+                    "users",
+                    "users_default",
+                ];
+                if version >= 11 {
+                    keep.push("invite");
+                }
+                keep
+            }
+            "m.room.aliases" if version <= 5 => vec!["aliases"],
+            "m.room.history_visibility" => vec!["history_visibility"],
+            "m.room.redaction" if version >= 11 => vec!["redacts"],
+            _ => Vec::new(),
+        };
+        for key in keep {
+            if let Some(value) = content.get(key) {
+                kept_content.insert(key.to_string(), value.clone());
+            }
+        }
+
+        if event_type == "m.room.member" && version >= 11 {
+            if let Some(signed) = content.get("third_party_invite").and_then(|t| t.get("signed")) {
+                kept_content.insert(
+                    "third_party_invite".to_string(),
+                    serde_json::json!({ "signed": signed }),
+                );
+            }
+        }
+    }
+    if map.contains_key("content") {
+        redacted.insert("content".to_string(), Value::Object(kept_content));
+    }
+
+    Value::Object(redacted)
+}
+
+/// Event ids are not part of the hashed form from room v3 onwards.
+fn hashable_form(event: &Value, room_version: &str) -> Value {
+    if has_explicit_event_id(room_version) {
+        event.clone()
+    } else {
+        without_keys(event, &["event_id"])
+    }
+}
+
+/// SHA-256 over the event without `unsigned`, `signatures` and `hashes`.
+pub fn compute_content_hash(event: &Value, room_version: &str) -> String {
+    let hashable = without_keys(
+        &hashable_form(event, room_version),
+        &["unsigned", "signatures", "hashes"],
+    );
+    let digest = Sha256::digest(canonical_json_bytes(&hashable));
+    base64::engine::general_purpose::STANDARD_NO_PAD.encode(digest)
+}
+
+pub fn verify_content_hash(event: &Value, room_version: &str) -> bool {
+    event
+        .get("hashes")
+        .and_then(|h| h.get("sha256"))
+        .and_then(|h| h.as_str())
+        .is_some_and(|expected| {
+            let expected = expected.trim_end_matches('=');
+            expected == compute_content_hash(event, room_version)
+        })
+}
+
+fn reference_hash(event: &Value, room_version: &str) -> [u8; 32] {
+    let redacted = redact(&hashable_form(event, room_version), room_version);
+    let hashable = without_keys(&redacted, &["signatures", "unsigned", "age_ts"]);
+    Sha256::digest(canonical_json_bytes(&hashable)).into()
+}
+
+/// Derives the event ID for room v3+ (`None` for v1/v2, which carry it on the wire).
+pub fn compute_event_id(event: &Value, room_version: &str) -> Option<String> {
+    if has_explicit_event_id(room_version) {
+        return None;
+    }
+
+    let hash = reference_hash(event, room_version);
+    let encoded = if version_number(room_version) == 3 {
+        base64::engine::general_purpose::STANDARD_NO_PAD.encode(hash)
+    } else {
+        base64::engine::general_purpose::URL_SAFE_NO_PAD.encode(hash)
+    };
+    Some(format!("${}", encoded))
+}
+
+/// The bytes covered by event signatures: the redacted event without `signatures` and
+/// `unsigned`.
+pub fn signing_bytes(event: &Value, room_version: &str) -> Vec<u8> {
+    let redacted = redact(&hashable_form(event, room_version), room_version);
+    canonical_json_bytes(&without_keys(&redacted, &["signatures", "unsigned"]))
+}
+
+/// Servers whose signature an event needs: the sender's, the event ID's for v1/v2, and the
+/// authorising server of a restricted join.
+pub fn required_signers(event: &Value, room_version: &str) -> Vec<String> {
+    let mut servers = Vec::new();
+    let mut add = |id: Option<&str>| {
+        if let Some(server) = id.and_then(server_name_of) {
+            if !servers.iter().any(|s| s == server) {
+                servers.push(server.to_string());
+            }
+        }
+    };
+
+    add(event.get("sender").and_then(|v| v.as_str()));
+    if has_explicit_event_id(room_version) {
+        add(event.get("event_id").and_then(|v| v.as_str()));
+    }
+    if version_number(room_version) >= 8
+        && event.get("type").and_then(|v| v.as_str()) == Some("m.room.member")
+        && event["content"]["membership"] == "join"
+    {
+        add(event["content"]["join_authorised_via_users_server"].as_str());
+    }
+    servers
+}
+
+/// Event IDs referenced from `auth_events`/`prev_events`, which are `[id, hashes]` pairs in
+/// v1/v2 and plain IDs afterwards.
+pub fn referenced_event_ids(value: Option<&Value>) -> Vec<String> {
+    value
+        .and_then(|v| v.as_array())
+        .map(|items| {
+            items
+                .iter()
+                .filter_map(|item| match item {
+                    Value::String(id) => Some(id.clone()),
+                    Value::Array(pair) => pair.first().and_then(|id| id.as_str()).map(str::to_string),
+                    _ => None,
+                })
+                .collect()
+        })
+        .unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn test_canonical_json_sorts_keys_and_strips_whitespace() {
+        let value = json!({"b": "2", "a": 1, "nested": {"z": [1, 2], "y": null}});
+        assert_eq!(
+            canonical_json(&value),
+            r#"{"a":1,"b":"2","nested":{"y":null,"z":[1,2]}}"#
+        );
+        assert_eq!(canonical_json(&json!({"日本語": "日本語"})), r#"{"日本語":"日本語"}"#);
+    }
+
+    #[test]
+    fn test_redact_member_event() {
+        let event = json!({
+            "type": "m.room.member",
+            "room_id": "!room:example.org",
+            "sender": "@alice:example.org",
+            "state_key": "@alice:example.org",
+            "content": {"membership": "join", "displayname": "Alice"},
+            "unsigned": {"age": 5},
+            "origin": "example.org"
+        });
+
+        let redacted = redact(&event, "10");
+        assert_eq!(redacted["content"], json!({"membership": "join"}));
+        assert!(redacted.get("unsigned").is_none());
+        assert_eq!(redacted["origin"], "example.org");
+
+        assert!(redact(&event, "11").get("origin").is_none());
+    }
+
+    #[test]
+    fn test_redact_create_event_by_version() {
+        let event = json!({
+            "type": "m.room.create",
+            "content": {"creator": "@alice:example.org", "room_version": "11", "m.federate": false}
+        });
+
+        assert_eq!(redact(&event, "10")["content"], json!({"creator": "@alice:example.org"}));
+        assert_eq!(redact(&event, "11")["content"], event["content"]);
+    }
+
+    #[test]
+    fn test_content_hash_roundtrip() {
+        let mut event = json!({
+            "type": "m.room.message",
+            "room_id": "!room:example.org",
+            "sender": "@alice:example.org",
+            "content": {"body": "hello"},
+            "origin_server_ts": 1000,
+            "unsigned": {"age": 1}
+        });
+        let hash = compute_content_hash(&event, "10");
+        event["hashes"] = json!({"sha256": hash});
+        assert!(verify_content_hash(&event, "10"));
+
+        event["content"]["body"] = json!("tampered");
+        assert!(!verify_content_hash(&event, "10"));
+    }
+
+    #[test]
+    fn test_event_id_format_by_version() {
+        let event = json!({
+            "type": "m.room.message",
+            "room_id": "!room:example.org",
+            "sender": "@alice:example.org",
+            "content": {"body": "hello"},
+            "origin_server_ts": 1000
+        });
+
+        assert!(compute_event_id(&event, "1").is_none());
+        let v3 = compute_event_id(&event, "3").unwrap();
+        let v4 = compute_event_id(&event, "4").unwrap();
+        assert!(v3.starts_with('$') && v4.starts_with('$'));
+        assert_eq!(v4.len(), 44);
+        assert!(!v4.contains('+') && !v4.contains('/'));
+
+        // Content is redacted away, so the id survives redaction
+        let mut edited = event.clone();
+        edited["content"]["body"] = json!("other");
+        assert_eq!(compute_event_id(&edited, "4").unwrap(), v4);
+        assert_eq!(compute_event_id(&redact(&event, "4"), "4").unwrap(), v4);
+    }
+
+    #[test]
+    fn test_required_signers() {
+        let join = json!({
+            "type": "m.room.member",
+            "sender": "@bob:remote.org",
+            "event_id": "$abc:origin.org",
+            "content": {
+                "membership": "join",
+                "join_authorised_via_users_server": "@alice:example.org"
+            }
+        });
+
+        assert_eq!(required_signers(&join, "1"), vec!["remote.org", "origin.org"]);
+        assert_eq!(required_signers(&join, "10"), vec!["remote.org", "example.org"]);
+    }
+
+    #[test]
+    fn test_referenced_event_ids() {
+        let v1 = json!([["$a:example.org", {"sha256": "x"}], ["$b:example.org", {}]]);
+        let v4 = json!(["$a", "$b"]);
+        assert_eq!(referenced_event_ids(Some(&v1)), vec!["$a:example.org", "$b:example.org"]);
+        assert_eq!(referenced_event_ids(Some(&v4)), vec!["$a", "$b"]);
+        assert!(referenced_event_ids(None).is_empty());
+    }
+}
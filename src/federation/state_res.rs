@@ -0,0 +1,378 @@
+//! State resolution v2, used by room versions 2 and later.
+//!
+//! Given the state sets at the tips of a fork, the algorithm picks one event per state key so
+//! that every server holding the same events arrives at the same state.
+
+use crate::federation::event_auth::{user_power_level, AuthState, EventAuthChain};
+use crate::federation::pdu;
+use serde_json::Value;
+use std::cmp::Reverse;
+use std::collections::{BTreeMap, BinaryHeap, HashMap, HashSet};
+
+/// Resolved room state: `(type, state_key)` to event ID.
+pub type StateMap = BTreeMap<(String, String), String>;
+
+/// Resolves `state_sets` using the events in `events`, which must include the events referenced
+/// by the state sets and as much of their auth chains as is known.
+pub fn resolve_state(
+    room_version: &str,
+    state_sets: &[StateMap],
+    events: &HashMap<String, Value>,
+) -> Result<StateMap, String> {
+    if !pdu::is_supported_room_version(room_version) {
+        return Err(format!("Unsupported room version {}", room_version));
+    }
+    if room_version == "1" {
+        return Err("State resolution v1 is not supported".to_string());
+    }
+    Ok(resolve_v2(state_sets, events))
+}
+
+fn resolve_v2(state_sets: &[StateMap], events: &HashMap<String, Value>) -> StateMap {
+    let (unconflicted, conflicted) = separate(state_sets);
+    if conflicted.is_empty() {
+        return unconflicted;
+    }
+
+    let mut full_conflicted: HashSet<String> = conflicted.into_values().flatten().collect();
+    full_conflicted.extend(auth_difference(state_sets, events));
+    full_conflicted.retain(|event_id| events.contains_key(event_id));
+
+    let power_events: Vec<String> = full_conflicted
+        .iter()
+        .filter(|event_id| is_power_event(&events[event_id.as_str()]))
+        .cloned()
+        .collect();
+    let sorted_power_events = reverse_topological_power_sort(&power_events, events, &full_conflicted);
+    let mut resolved = iterative_auth_checks(&sorted_power_events, &unconflicted, events);
+
+    let sorted_power_set: HashSet<&String> = sorted_power_events.iter().collect();
+    let leftover: Vec<String> = full_conflicted
+        .iter()
+        .filter(|event_id| !sorted_power_set.contains(event_id))
+        .cloned()
+        .collect();
+    let power_levels = resolved
+        .get(&("m.room.power_levels".to_string(), String::new()))
+        .cloned();
+    let leftover = mainline_sort(leftover, power_levels.as_deref(), events);
+    resolved = iterative_auth_checks(&leftover, &resolved, events);
+
+    resolved.extend(unconflicted);
+    resolved
+}
+
+/// Keys with the same event in every state set are unconflicted; the rest, including keys
+/// missing from some sets, are conflicted.
+fn separate(state_sets: &[StateMap]) -> (StateMap, BTreeMap<(String, String), HashSet<String>>) {
+    let mut unconflicted = StateMap::new();
+    let mut conflicted = BTreeMap::new();
+
+    let keys: HashSet<&(String, String)> = state_sets.iter().flat_map(|set| set.keys()).collect();
+    for key in keys {
+        let values: HashSet<Option<&String>> = state_sets.iter().map(|set| set.get(key)).collect();
+        if values.len() == 1 {
+            if let Some(Some(event_id)) = values.into_iter().next() {
+                unconflicted.insert(key.clone(), event_id.clone());
+            }
+        } else {
+            let event_ids: HashSet<String> = values.into_iter().flatten().cloned().collect();
+            conflicted.insert(key.clone(), event_ids);
+        }
+    }
+    (unconflicted, conflicted)
+}
+
+fn auth_event_ids(event: &Value) -> Vec<String> {
+    pdu::referenced_event_ids(event.get("auth_events"))
+}
+
+fn state_key_of(event: &Value) -> Option<(String, String)> {
+    Some((
+        event["type"].as_str()?.to_string(),
+        event["state_key"].as_str()?.to_string(),
+    ))
+}
+
+/// The given events and everything reachable through their `auth_events`.
+fn auth_chain(start: impl IntoIterator<Item = String>, events: &HashMap<String, Value>) -> HashSet<String> {
+    let mut chain = HashSet::new();
+    let mut stack: Vec<String> = start.into_iter().collect();
+    while let Some(event_id) = stack.pop() {
+        if !chain.insert(event_id.clone()) {
+            continue;
+        }
+        if let Some(event) = events.get(&event_id) {
+            stack.extend(auth_event_ids(event));
+        }
+    }
+    chain
+}
+
+/// Events in the auth chain of some, but not all, state sets.
+fn auth_difference(state_sets: &[StateMap], events: &HashMap<String, Value>) -> HashSet<String> {
+    let chains: Vec<HashSet<String>> = state_sets
+        .iter()
+        .map(|set| auth_chain(set.values().cloned(), events))
+        .collect();
+    let union: HashSet<String> = chains.iter().flatten().cloned().collect();
+    union
+        .into_iter()
+        .filter(|event_id| !chains.iter().all(|chain| chain.contains(event_id)))
+        .collect()
+}
+
+/// Events that change who may do what: power levels, join rules, the create event, and
+/// kicks and bans.
+fn is_power_event(event: &Value) -> bool {
+    let state_key = event["state_key"].as_str();
+    match (event["type"].as_str(), state_key) {
+        (Some("m.room.power_levels" | "m.room.join_rules" | "m.room.create"), Some("")) => true,
+        (Some("m.room.member"), Some(target)) => {
+            matches!(event["content"]["membership"].as_str(), Some("leave" | "ban"))
+                && event["sender"].as_str() != Some(target)
+        }
+        _ => false,
+    }
+}
+
+fn find_auth_event<'a>(
+    event: &Value,
+    events: &'a HashMap<String, Value>,
+    event_type: &str,
+) -> Option<(String, &'a Value)> {
+    auth_event_ids(event).into_iter().find_map(|event_id| {
+        let auth_event = events.get(&event_id)?;
+        (auth_event["type"] == event_type && auth_event["state_key"] == "").then_some((event_id, auth_event))
+    })
+}
+
+/// The sender's power level according to the event's own auth events.
+fn sender_power_level(event: &Value, events: &HashMap<String, Value>) -> i64 {
+    let sender = event["sender"].as_str().unwrap_or_default();
+    let power_levels = find_auth_event(event, events, "m.room.power_levels").map(|(_, pl)| pl);
+    let create = find_auth_event(event, events, "m.room.create")
+        .map(|(_, create)| create.clone())
+        .unwrap_or(Value::Null);
+    user_power_level(sender, power_levels, &create)
+}
+
+/// Orders `power_events` and their auth ancestors within `conflicted` so that auth events come
+/// first, breaking ties by higher sender power, then earlier timestamp, then event ID.
+fn reverse_topological_power_sort(
+    power_events: &[String],
+    events: &HashMap<String, Value>,
+    conflicted: &HashSet<String>,
+) -> Vec<String> {
+    let mut graph: HashMap<String, HashSet<String>> = HashMap::new();
+    for event_id in power_events {
+        let mut stack = vec![event_id.clone()];
+        while let Some(current) = stack.pop() {
+            graph.entry(current.clone()).or_default();
+            let Some(event) = events.get(&current) else {
+                continue;
+            };
+            for auth_id in auth_event_ids(event) {
+                if conflicted.contains(&auth_id) {
+                    if !graph.contains_key(&auth_id) {
+                        stack.push(auth_id.clone());
+                    }
+                    graph.entry(current.clone()).or_default().insert(auth_id);
+                }
+            }
+        }
+    }
+
+    let order: HashMap<String, (i64, i64)> = graph
+        .keys()
+        .map(|event_id| {
+            let event = &events[event_id.as_str()];
+            let power = sender_power_level(event, events);
+            (event_id.clone(), (-power, origin_server_ts(event)))
+        })
+        .collect();
+
+    lexicographical_topological_sort(&graph, |event_id| {
+        let (power, ts) = order[event_id];
+        (power, ts, event_id.to_string())
+    })
+}
+
+fn origin_server_ts(event: &Value) -> i64 {
+    event["origin_server_ts"].as_i64().unwrap_or(0)
+}
+
+/// Topologically sorts `graph` (event to the events it references) so that referenced events
+/// come first, taking the smallest `key` whenever several events are ready.
+fn lexicographical_topological_sort<K: Ord>(
+    graph: &HashMap<String, HashSet<String>>,
+    key: impl Fn(&str) -> K,
+) -> Vec<String> {
+    let mut outdegree: HashMap<&str, usize> = HashMap::new();
+    let mut referenced_by: HashMap<&str, Vec<&str>> = HashMap::new();
+    for (event_id, references) in graph {
+        let references: Vec<&String> = references.iter().filter(|r| graph.contains_key(*r)).collect();
+        outdegree.insert(event_id, references.len());
+        for reference in references {
+            referenced_by.entry(reference).or_default().push(event_id);
+        }
+    }
+
+    let mut ready: BinaryHeap<Reverse<(K, &str)>> = outdegree
+        .iter()
+        .filter(|(_, degree)| **degree == 0)
+        .map(|(event_id, _)| Reverse((key(event_id), *event_id)))
+        .collect();
+
+    let mut sorted = Vec::with_capacity(graph.len());
+    while let Some(Reverse((_, event_id))) = ready.pop() {
+        sorted.push(event_id.to_string());
+        for parent in referenced_by.get(event_id).into_iter().flatten() {
+            if let Some(degree) = outdegree.get_mut(parent) {
+                *degree -= 1;
+                if *degree == 0 {
+                    ready.push(Reverse((key(parent), parent)));
+                }
+            }
+        }
+    }
+    sorted
+}
+
+/// Applies `event_ids` in order on top of `base`, keeping each event that passes the auth rules
+/// against its own auth events overlaid with the state resolved so far.
+fn iterative_auth_checks(event_ids: &[String], base: &StateMap, events: &HashMap<String, Value>) -> StateMap {
+    let mut resolved = base.clone();
+
+    for event_id in event_ids {
+        let Some(event) = events.get(event_id) else {
+            continue;
+        };
+        let Some(key) = state_key_of(event) else {
+            continue;
+        };
+
+        let mut auth_state = AuthState::new();
+        for auth_id in auth_event_ids(event) {
+            if let Some(auth_event) = events.get(&auth_id) {
+                if let Some(auth_key) = state_key_of(auth_event) {
+                    auth_state.insert(auth_key, auth_event.clone());
+                }
+            }
+        }
+        for auth_key in EventAuthChain::auth_types_for_event(event) {
+            if let Some(auth_event) = resolved.get(&auth_key).and_then(|id| events.get(id)) {
+                auth_state.insert(auth_key, auth_event.clone());
+            }
+        }
+
+        if EventAuthChain::check_auth_rules(event, &auth_state).is_ok() {
+            resolved.insert(key, event_id.clone());
+        }
+    }
+    resolved
+}
+
+/// Orders events by their position relative to the chain of power level events ending at the
+/// resolved one, then by timestamp and event ID.
+fn mainline_sort(
+    mut event_ids: Vec<String>,
+    resolved_power_levels: Option<&str>,
+    events: &HashMap<String, Value>,
+) -> Vec<String> {
+    let mut mainline = Vec::new();
+    let mut current = resolved_power_levels.map(str::to_string);
+    while let Some(event_id) = current {
+        current = events
+            .get(&event_id)
+            .and_then(|event| find_auth_event(event, events, "m.room.power_levels"))
+            .map(|(id, _)| id);
+        mainline.push(event_id);
+    }
+    let mainline_depth: HashMap<String, usize> = mainline
+        .into_iter()
+        .rev()
+        .enumerate()
+        .map(|(index, event_id)| (event_id, index + 1))
+        .collect();
+
+    let depth_of = |event_id: &str| -> usize {
+        let mut current = events.get(event_id).map(|event| (event_id.to_string(), event));
+        while let Some((id, event)) = current {
+            if let Some(depth) = mainline_depth.get(&id) {
+                return *depth;
+            }
+            current = find_auth_event(event, events, "m.room.power_levels");
+        }
+        0
+    };
+
+    let order: HashMap<String, (usize, i64)> = event_ids
+        .iter()
+        .filter_map(|event_id| {
+            let event = events.get(event_id)?;
+            Some((event_id.clone(), (depth_of(event_id), origin_server_ts(event))))
+        })
+        .collect();
+    event_ids.sort_by(|a, b| {
+        let order_a = order.get(a).copied().unwrap_or_default();
+        let order_b = order.get(b).copied().unwrap_or_default();
+        order_a.cmp(&order_b).then_with(|| a.cmp(b))
+    });
+    event_ids
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn key(event_type: &str, state_key: &str) -> (String, String) {
+        (event_type.to_string(), state_key.to_string())
+    }
+
+    #[test]
+    fn test_separate_treats_missing_keys_as_conflicted() {
+        let a = StateMap::from([(key("m.room.create", ""), "$c".to_string()), (key("m.room.topic", ""), "$t1".to_string())]);
+        let b = StateMap::from([(key("m.room.create", ""), "$c".to_string())]);
+
+        let (unconflicted, conflicted) = separate(&[a, b]);
+
+        assert_eq!(unconflicted.len(), 1);
+        assert_eq!(conflicted[&key("m.room.topic", "")], HashSet::from(["$t1".to_string()]));
+    }
+
+    #[test]
+    fn test_lexicographical_topological_sort_breaks_ties_by_key() {
+        let graph = HashMap::from([
+            ("a".to_string(), HashSet::new()),
+            ("b".to_string(), HashSet::from(["a".to_string()])),
+            ("c".to_string(), HashSet::from(["a".to_string()])),
+            ("d".to_string(), HashSet::from(["b".to_string(), "c".to_string()])),
+        ]);
+
+        let sorted = lexicographical_topological_sort(&graph, |id| match id {
+            "c" => 0,
+            _ => 1,
+        });
+
+        assert_eq!(sorted, vec!["a", "c", "b", "d"]);
+    }
+
+    #[test]
+    fn test_is_power_event() {
+        let kick = json!({"type": "m.room.member", "state_key": "@bob:x", "sender": "@alice:x", "content": {"membership": "leave"}});
+        let leave = json!({"type": "m.room.member", "state_key": "@bob:x", "sender": "@bob:x", "content": {"membership": "leave"}});
+        let topic = json!({"type": "m.room.topic", "state_key": "", "sender": "@alice:x", "content": {}});
+
+        assert!(is_power_event(&kick));
+        assert!(!is_power_event(&leave));
+        assert!(!is_power_event(&topic));
+    }
+
+    #[test]
+    fn test_resolve_state_rejects_v1() {
+        assert!(resolve_state("1", &[], &HashMap::new()).is_err());
+        assert_eq!(resolve_state("10", &[], &HashMap::new()), Ok(StateMap::new()));
+    }
+}
@@ -11,6 +11,8 @@ use tracing::info;
 
 use crate::cache::*;
 use crate::common::config::Config;
+use crate::federation::event_builder::decode_base64_32;
+use base64::Engine;
 use crate::services::*;
 use crate::storage::*;
 use crate::tasks::{ScheduledTasks, TaskMetricsCollector};
@@ -179,12 +181,62 @@ impl SynapseServer {
             tracing::warn!("Warmup encountered minor errors: {}", e);
         }
 
-        // Start key rotation scheduler
-        self.app_state
-            .services
-            .key_rotation_manager
-            .start_auto_rotation()
-            .await;
+        // Outbound federation requests are signed with the configured key, which is also the
+        // one published at /_matrix/key/v2/server, so it is not rotated away.
+        let services = &self.app_state.services;
+        let federation = &services.config.federation;
+        match federation.signing_key.as_deref().and_then(decode_base64_32) {
+            Some(seed) => {
+                let secret_key = base64::engine::general_purpose::STANDARD_NO_PAD.encode(seed);
+                let key_id = federation.key_id.as_deref().unwrap_or("ed25519:1");
+                if let Err(e) = services.key_rotation_manager.initialize(&secret_key, key_id).await {
+                    tracing::warn!("Failed to persist federation signing key: {}", e);
+                }
+                services.key_rotation_manager.set_rotation_enabled(false).await;
+            }
+            None => {
+                // Start key rotation scheduler
+                services.key_rotation_manager.start_auto_rotation().await;
+            }
+        }
+
+        // Cross-worker /sync wake-ups go through the Redis replication channels
+        if let Err(e) = self.app_state.services.replication_service.start_redis_bridge() {
+            tracing::warn!("Failed to start Redis replication bridge: {}", e);
+        }
+        self.app_state.services.notifier.start_replication_listener();
+
+        if services.config.worker.runs_federation_sender() {
+            services.federation_sender.start();
+        }
+        // Application services act as their sender user, which must exist
+        for service in services.app_services.get_all().await {
+            let user_id = service.sender_user_id(&services.server_name);
+            match services.user_storage.user_exists(&user_id).await {
+                Ok(true) => {}
+                Ok(false) => {
+                    if let Err(e) = services
+                        .user_storage
+                        .create_appservice_user(&user_id, service.sender_localpart(), service.id())
+                        .await
+                    {
+                        tracing::warn!("Failed to create application service user {}: {}", user_id, e);
+                    }
+                }
+                Err(e) => tracing::warn!("Failed to look up application service user {}: {}", user_id, e),
+            }
+        }
+        if services.config.worker.runs_appservice_sender() {
+            services.appservice_scheduler.start();
+        }
+        // Notifications are queued in memory by the process that persisted the event
+        services.push_service.start();
+        services.push_actions_service.start_cleanup(Duration::from_secs(
+            services.config.push.notification_retention_days * 24 * 3600,
+        ));
+        if services.config.federation.enabled {
+            crate::web::routes::federation::resume_partial_state_syncs((*self.app_state).clone());
+        }
 
         info!("Starting scheduled database monitoring and maintenance tasks...");
         self.scheduled_tasks.start_all().await;
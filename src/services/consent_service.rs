@@ -43,6 +43,19 @@ impl ConsentType {
             ConsentType::Custom(s) => s,
         }
     }
+
+    /// Inverse of `as_str`; unknown names become `Custom`.
+    pub fn parse(s: &str) -> Self {
+        match s {
+            "terms_of_service" => ConsentType::TermsOfService,
+            "privacy_policy" => ConsentType::PrivacyPolicy,
+            "email_marketing" => ConsentType::EmailMarketing,
+            "data_processing" => ConsentType::DataProcessing,
+            "third_party_sharing" => ConsentType::ThirdPartySharing,
+            "cookie_usage" => ConsentType::CookieUsage,
+            other => ConsentType::Custom(other.to_string()),
+        }
+    }
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -167,6 +180,17 @@ impl ConsentService {
         }
     }
 
+    pub fn with_policies(self, policies: Vec<ConsentPolicy>) -> Self {
+        let policies = policies
+            .into_iter()
+            .map(|policy| (policy.consent_type.clone(), policy))
+            .collect();
+        Self {
+            policies: Arc::new(RwLock::new(policies)),
+            ..self
+        }
+    }
+
     pub async fn register_policy(&self, policy: ConsentPolicy) {
         let consent_type = policy.consent_type.clone();
         self.policies.write().await.insert(consent_type, policy);
@@ -1,10 +1,25 @@
+use crate::federation::{FederationClient, FederationClientError};
+use crate::storage::FederationQueueStorage;
+use crate::worker::WorkerType;
 use chrono::Utc;
+use futures::StreamExt;
 use serde::{Deserialize, Serialize};
-use std::collections::{HashMap, VecDeque};
+use serde_json::{json, Value};
+use sha2::{Digest, Sha256};
+use std::collections::{HashMap, HashSet, VecDeque};
 use std::sync::Arc;
+use std::time::Duration;
 use tokio::sync::RwLock;
+use tokio::task::JoinHandle;
 use tracing::{debug, info, warn};
 
+/// Typing notifications older than this are dropped rather than sent to a destination
+/// catching up.
+const TYPING_EDU_TTL_MS: i64 = 30_000;
+
+/// How many ready destinations are picked up per pass over the queue.
+const READY_DESTINATIONS_PER_PASS: i64 = 1000;
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct FederationTransaction {
     pub transaction_id: String,
@@ -121,6 +136,19 @@ pub struct FederationSenderConfig {
     pub max_edu_per_transaction: usize,
     pub transaction_timeout_ms: u64,
     pub max_retry_count: u32,
+    pub min_retry_interval_ms: i64,
+    pub max_retry_interval_ms: i64,
+    pub poll_interval_ms: u64,
+    pub max_concurrent_destinations: usize,
+}
+
+impl FederationSenderConfig {
+    /// Exponential backoff for the durable queue, doubling from the minimum up to the maximum.
+    pub fn next_retry_interval(&self, previous_ms: i64) -> i64 {
+        previous_ms
+            .saturating_mul(2)
+            .clamp(self.min_retry_interval_ms, self.max_retry_interval_ms)
+    }
 }
 
 impl Default for FederationSenderConfig {
@@ -131,6 +159,10 @@ impl Default for FederationSenderConfig {
             max_edu_per_transaction: 100,
             transaction_timeout_ms: 30000,
             max_retry_count: 5,
+            min_retry_interval_ms: 5_000,
+            max_retry_interval_ms: 24 * 60 * 60 * 1000,
+            poll_interval_ms: 1_000,
+            max_concurrent_destinations: WorkerType::FederationSender.default_concurrency(),
         }
     }
 }
@@ -149,6 +181,11 @@ pub struct FederationSenderService {
     destinations: Arc<RwLock<HashMap<String, FederationDestination>>>,
     pending_transactions: Arc<RwLock<HashMap<String, VecDeque<FederationTransaction>>>>,
     stats: Arc<RwLock<FederationStats>>,
+    client: Option<Arc<FederationClient>>,
+    queue: Option<FederationQueueStorage>,
+    instance_name: String,
+    sender_instances: Vec<String>,
+    in_flight: Arc<parking_lot::Mutex<HashSet<String>>>,
 }
 
 impl FederationSenderService {
@@ -158,6 +195,214 @@ impl FederationSenderService {
             destinations: Arc::new(RwLock::new(HashMap::new())),
             pending_transactions: Arc::new(RwLock::new(HashMap::new())),
             stats: Arc::new(RwLock::new(FederationStats::default())),
+            client: None,
+            queue: None,
+            instance_name: String::new(),
+            sender_instances: Vec::new(),
+            in_flight: Arc::new(parking_lot::Mutex::new(HashSet::new())),
+        }
+    }
+
+    /// Delivers transactions over federation. Without a client they are only tracked locally.
+    pub fn with_client(mut self, client: Arc<FederationClient>) -> Self {
+        self.client = Some(client);
+        self
+    }
+
+    /// Queues PDUs and EDUs durably per destination; `start` delivers them in the background.
+    pub fn with_queue(mut self, queue: FederationQueueStorage) -> Self {
+        self.queue = Some(queue);
+        self
+    }
+
+    /// Shards destinations across several federation sender instances. Each destination is
+    /// owned by exactly one of `instances`; an empty list means this instance sends to all.
+    pub fn with_instances(mut self, instance_name: &str, instances: Vec<String>) -> Self {
+        self.instance_name = instance_name.to_string();
+        self.sender_instances = instances;
+        self
+    }
+
+    pub fn owns_destination(&self, destination: &str) -> bool {
+        if self.sender_instances.is_empty() {
+            return true;
+        }
+        let digest = Sha256::digest(destination.as_bytes());
+        let mut prefix = [0u8; 8];
+        prefix.copy_from_slice(&digest[..8]);
+        let index = u64::from_be_bytes(prefix) % self.sender_instances.len() as u64;
+        self.sender_instances[index as usize] == self.instance_name
+    }
+
+    /// Queues an EDU for the given servers.
+    pub async fn queue_edu(&self, destinations: &[String], edu_type: &str, content: Value) -> Result<(), FederationError> {
+        if let Some(queue) = &self.queue {
+            queue.enqueue_edu(destinations, &Self::edu(edu_type, content)).await?;
+        }
+        Ok(())
+    }
+
+    /// Queues an EDU for every other server in the room.
+    pub async fn queue_room_edu(&self, room_id: &str, edu_type: &str, content: Value) -> Result<(), FederationError> {
+        if let Some(queue) = &self.queue {
+            queue.enqueue_room_edu(room_id, &Self::edu(edu_type, content)).await?;
+        }
+        Ok(())
+    }
+
+    /// Queues an EDU for every other server sharing a room with the user.
+    pub async fn queue_user_edu(&self, user_id: &str, edu_type: &str, content: Value) -> Result<(), FederationError> {
+        if let Some(queue) = &self.queue {
+            queue.enqueue_user_edu(user_id, &Self::edu(edu_type, content)).await?;
+        }
+        Ok(())
+    }
+
+    fn edu(edu_type: &str, content: Value) -> Value {
+        json!({ "edu_type": edu_type, "content": content })
+    }
+
+    /// Called when a remote server contacts us: if it was backing off it is evidently back,
+    /// so its queue is retried straight away.
+    pub async fn on_remote_online(&self, destination: &str) {
+        let Some(queue) = &self.queue else {
+            return;
+        };
+        match queue.reset_backoff(destination).await {
+            Ok(true) => {
+                if let Some(client) = &self.client {
+                    client.reset_backoff(destination).await;
+                }
+                info!(destination = %destination, "Destination is back online, catching up");
+            }
+            Ok(false) => {}
+            Err(e) => warn!(destination = %destination, error = %e, "Failed to reset destination backoff"),
+        }
+    }
+
+    /// Runs the durable queue until the process exits, waking on new entries and polling
+    /// for ones queued by other processes or whose backoff has expired.
+    pub fn start(self: &Arc<Self>) -> Option<JoinHandle<()>> {
+        let wakeup = self.queue.as_ref()?.wakeup();
+        let sender = self.clone();
+        let poll_interval = Duration::from_millis(self.config.poll_interval_ms);
+
+        Some(tokio::spawn(async move {
+            info!(
+                instance = %sender.instance_name,
+                instances = sender.sender_instances.len(),
+                "Federation sender started"
+            );
+            loop {
+                if let Err(e) = sender.process_queue().await {
+                    warn!(error = %e, "Failed to process federation queue");
+                }
+                tokio::select! {
+                    _ = wakeup.notified() => {}
+                    _ = tokio::time::sleep(poll_interval) => {}
+                }
+            }
+        }))
+    }
+
+    /// Sends queued transactions to every ready destination this instance owns. Returns the
+    /// number of transactions delivered.
+    pub async fn process_queue(&self) -> Result<usize, FederationError> {
+        let (Some(queue), Some(client)) = (&self.queue, &self.client) else {
+            return Ok(0);
+        };
+
+        let destinations: Vec<String> = queue
+            .get_ready_destinations(Utc::now().timestamp_millis(), READY_DESTINATIONS_PER_PASS)
+            .await?
+            .into_iter()
+            .filter(|destination| self.owns_destination(destination))
+            .collect();
+
+        let delivered = futures::stream::iter(destinations)
+            .map(|destination| async move {
+                if !self.in_flight.lock().insert(destination.clone()) {
+                    return 0;
+                }
+                let delivered = self.send_queued(queue, client, &destination).await;
+                self.in_flight.lock().remove(&destination);
+                delivered.unwrap_or_else(|e| {
+                    warn!(destination = %destination, error = %e, "Failed to send queued transactions");
+                    0
+                })
+            })
+            .buffer_unordered(self.config.max_concurrent_destinations.max(1))
+            .fold(0, |total, delivered| async move { total + delivered })
+            .await;
+
+        if delivered > 0 {
+            let mut stats = self.stats.write().await;
+            stats.total_transactions += delivered as u64;
+            stats.successful_transactions += delivered as u64;
+        }
+        Ok(delivered)
+    }
+
+    /// Sends the destination's queue oldest first until it is empty or a send fails.
+    async fn send_queued(
+        &self,
+        queue: &FederationQueueStorage,
+        client: &FederationClient,
+        destination: &str,
+    ) -> Result<usize, FederationError> {
+        let mut delivered = 0;
+        loop {
+            let stale_before = Utc::now().timestamp_millis() - TYPING_EDU_TTL_MS;
+            queue.delete_stale_edus(destination, "m.typing", stale_before).await?;
+
+            let transaction = queue
+                .get_next_transaction(
+                    destination,
+                    self.config.max_pdu_per_transaction as i64,
+                    self.config.max_edu_per_transaction as i64,
+                )
+                .await?;
+            if transaction.is_empty() {
+                return Ok(delivered);
+            }
+
+            let transaction_id = transaction.transaction_id();
+            match client
+                .send_transaction(destination, &transaction_id, &transaction.pdus, &transaction.edus)
+                .await
+            {
+                Ok(_) => {
+                    queue.complete_transaction(destination, &transaction).await?;
+                    delivered += 1;
+                    debug!(
+                        transaction_id = %transaction_id,
+                        destination = %destination,
+                        pdu_count = transaction.pdus.len(),
+                        edu_count = transaction.edus.len(),
+                        "Queued transaction delivered"
+                    );
+                }
+                // The client is already backing off; try again once it allows it.
+                Err(FederationClientError::BackingOff { .. }) => return Ok(delivered),
+                Err(e) => {
+                    let previous = queue
+                        .get_destination(destination)
+                        .await?
+                        .map(|state| state.retry_interval_ms)
+                        .unwrap_or(0);
+                    let retry_interval_ms = self.config.next_retry_interval(previous);
+                    queue.record_failure(destination, retry_interval_ms).await?;
+                    self.stats.write().await.failed_transactions += 1;
+                    warn!(
+                        transaction_id = %transaction_id,
+                        destination = %destination,
+                        retry_interval_ms,
+                        error = %e,
+                        "Queued transaction delivery failed"
+                    );
+                    return Ok(delivered);
+                }
+            }
         }
     }
 
@@ -167,10 +412,12 @@ impl FederationSenderService {
         pdus: Vec<serde_json::Value>,
         edus: Vec<serde_json::Value>,
     ) -> Result<String, FederationError> {
-        let mut transaction = FederationTransaction::new(
-            "self".to_string(),
-            destination.to_string(),
-        );
+        let origin = self
+            .client
+            .as_ref()
+            .map(|client| client.server_name().to_string())
+            .unwrap_or_else(|| "self".to_string());
+        let mut transaction = FederationTransaction::new(origin, destination.to_string());
         transaction.pdus = pdus;
         transaction.edus = edus;
 
@@ -215,16 +462,22 @@ impl FederationSenderService {
 
     pub async fn process_pending(&self) -> Result<usize, FederationError> {
         let mut processed = 0;
-        let mut pending = self.pending_transactions.write().await;
-        let mut destinations = self.destinations.write().await;
-
-        for (server_name, queue) in pending.iter_mut() {
-            let destination = destinations.get_mut(server_name).unwrap();
-            
-            if !destination.can_send() {
-                continue;
-            }
-
+        let mut failed = 0;
+
+        // Sending happens outside the locks, so queues are taken and failures put back.
+        let batches: Vec<(String, VecDeque<FederationTransaction>)> = {
+            let mut pending = self.pending_transactions.write().await;
+            let destinations = self.destinations.read().await;
+            pending
+                .iter_mut()
+                .filter(|(server_name, queue)| {
+                    !queue.is_empty() && destinations.get(*server_name).is_some_and(|d| d.can_send())
+                })
+                .map(|(server_name, queue)| (server_name.clone(), std::mem::take(queue)))
+                .collect()
+        };
+
+        for (server_name, mut queue) in batches {
             while let Some(mut transaction) = queue.pop_front() {
                 if transaction.retry_count >= self.config.max_retry_count {
                     warn!(
@@ -232,29 +485,75 @@ impl FederationSenderService {
                         destination = %server_name,
                         "Transaction exceeded max retries"
                     );
+                    failed += 1;
                     continue;
                 }
 
                 transaction.mark_sent();
-                processed += 1;
-
-                destination.record_success();
-                transaction.mark_delivered();
+                match self.deliver(&transaction).await {
+                    Ok(()) => {
+                        transaction.mark_delivered();
+                        processed += 1;
+                        if let Some(destination) = self.destinations.write().await.get_mut(&server_name) {
+                            destination.record_success();
+                        }
+                        debug!(
+                            transaction_id = %transaction.transaction_id,
+                            destination = %server_name,
+                            "Transaction processed"
+                        );
+                    }
+                    Err(e) => {
+                        warn!(
+                            transaction_id = %transaction.transaction_id,
+                            destination = %server_name,
+                            error = %e,
+                            "Transaction delivery failed"
+                        );
+                        transaction.mark_failed();
+                        if let Some(destination) = self.destinations.write().await.get_mut(&server_name) {
+                            destination.record_failure();
+                        }
+                        queue.push_front(transaction);
+                        break;
+                    }
+                }
+            }
 
-                debug!(
-                    transaction_id = %transaction.transaction_id,
-                    destination = %server_name,
-                    "Transaction processed"
-                );
+            if !queue.is_empty() {
+                let mut pending = self.pending_transactions.write().await;
+                let current = pending.entry(server_name).or_default();
+                queue.append(current);
+                *current = queue;
             }
         }
 
+        let pending = self.pending_transactions.read().await;
+        let destinations = self.destinations.read().await;
         let mut stats = self.stats.write().await;
         stats.successful_transactions += processed as u64;
+        stats.failed_transactions += failed;
         stats.pending_transactions = pending.values().map(|q| q.len() as u64).sum();
         stats.active_destinations = destinations.values().filter(|d| d.is_active).count();
+        drop((pending, destinations, stats));
+
+        Ok(processed + self.process_queue().await?)
+    }
 
-        Ok(processed)
+    async fn deliver(&self, transaction: &FederationTransaction) -> Result<(), FederationError> {
+        let Some(client) = &self.client else {
+            return Ok(());
+        };
+        client
+            .send_transaction(
+                &transaction.destination,
+                &transaction.transaction_id,
+                &transaction.pdus,
+                &transaction.edus,
+            )
+            .await
+            .map(|_| ())
+            .map_err(|e| FederationError::SendFailed(e.to_string()))
     }
 
     pub async fn get_destination(&self, server_name: &str) -> Option<FederationDestination> {
@@ -289,6 +588,8 @@ impl FederationSenderService {
     }
 
     pub async fn retry_destination(&self, server_name: &str) -> bool {
+        self.on_remote_online(server_name).await;
+
         let mut destinations = self.destinations.write().await;
         if let Some(destination) = destinations.get_mut(server_name) {
             destination.is_active = true;
@@ -302,6 +603,11 @@ impl FederationSenderService {
     }
 
     pub async fn clear_destination(&self, server_name: &str) {
+        if let Some(queue) = &self.queue {
+            if let Err(e) = queue.clear_destination(server_name).await {
+                warn!(server_name = %server_name, error = %e, "Failed to clear queued transactions");
+            }
+        }
         self.pending_transactions.write().await.remove(server_name);
         self.destinations.write().await.remove(server_name);
         info!(server_name = %server_name, "Destination cleared");
@@ -326,6 +632,8 @@ pub enum FederationError {
     Timeout,
     #[error("Send failed: {0}")]
     SendFailed(String),
+    #[error("Storage error: {0}")]
+    Storage(#[from] sqlx::Error),
 }
 
 #[cfg(test)]
@@ -374,6 +682,72 @@ mod tests {
         assert_eq!(sender.get_pending_count("server.example.com").await, 0);
     }
 
+    #[tokio::test]
+    async fn test_failed_delivery_is_requeued() {
+        use crate::federation::{FederationAccessControl, FederationClientConfig, FederationPolicy, KeyRotationManager};
+
+        let pool = Arc::new(sqlx::PgPool::connect_lazy("postgres://localhost/unused").unwrap());
+        let access_control = FederationAccessControl::new(FederationPolicy::default());
+        access_control
+            .add_to_blacklist("server.example.com", "test", "admin", None)
+            .await;
+        let client = FederationClient::new(
+            "origin.example.com",
+            KeyRotationManager::new(&pool, "origin.example.com"),
+            Arc::new(access_control),
+            FederationClientConfig::default(),
+        );
+        let sender = FederationSenderService::default().with_client(Arc::new(client));
+
+        sender
+            .send_transaction("server.example.com", vec![json!({"test": 1})], vec![])
+            .await
+            .unwrap();
+
+        assert_eq!(sender.process_pending().await.unwrap(), 0);
+        assert_eq!(sender.get_pending_count("server.example.com").await, 1);
+        let dest = sender.get_destination("server.example.com").await.unwrap();
+        assert_eq!(dest.consecutive_failures, 1);
+    }
+
+    #[test]
+    fn test_retry_interval_backs_off_exponentially() {
+        let config = FederationSenderConfig::default();
+
+        assert_eq!(config.next_retry_interval(0), 5_000);
+        assert_eq!(config.next_retry_interval(5_000), 10_000);
+        assert_eq!(config.next_retry_interval(config.max_retry_interval_ms), config.max_retry_interval_ms);
+    }
+
+    #[test]
+    fn test_destinations_are_sharded_across_instances() {
+        let instances = vec!["sender1".to_string(), "sender2".to_string(), "sender3".to_string()];
+        let senders: Vec<FederationSenderService> = instances
+            .iter()
+            .map(|name| FederationSenderService::default().with_instances(name, instances.clone()))
+            .collect();
+
+        for i in 0..50 {
+            let destination = format!("server{}.example.com", i);
+            let owners = senders.iter().filter(|s| s.owns_destination(&destination)).count();
+            assert_eq!(owners, 1, "{} should have exactly one sender", destination);
+        }
+        assert!(FederationSenderService::default().owns_destination("server.example.com"));
+    }
+
+    #[test]
+    fn test_queued_transaction_id_is_stable() {
+        let transaction = crate::storage::QueuedTransaction {
+            stream_ids: vec![7, 3, 9],
+            pdus: vec![json!({"test": 1})],
+            edus: vec![json!({"edu_type": "m.typing"}), json!({"edu_type": "m.receipt"})],
+        };
+
+        assert_eq!(transaction.transaction_id(), "3-9-3");
+        assert_eq!(transaction.transaction_id(), transaction.clone().transaction_id());
+        assert_eq!(transaction.last_stream_id(), 9);
+    }
+
     #[tokio::test]
     async fn test_destination_tracking() {
         let sender = FederationSenderService::default();
@@ -2,6 +2,7 @@ use crate::common::{generate_event_id, ApiError, ApiResult};
 use crate::services::RoomService;
 use crate::storage::{CreateEventParams, EventStorage, FriendRoomStorage};
 use crate::federation::friend::FriendFederationClient;
+use crate::federation::FederationClient;
 use serde_json::json;
 use std::sync::Arc;
 
@@ -19,8 +20,9 @@ impl FriendRoomService {
         room_service: Arc<RoomService>,
         event_storage: EventStorage,
         server_name: String,
+        federation_client: Arc<FederationClient>,
     ) -> Self {
-        let federation_client = Arc::new(FriendFederationClient::new(server_name.clone()));
+        let federation_client = Arc::new(FriendFederationClient::new(federation_client));
         Self {
             friend_storage,
             room_service,
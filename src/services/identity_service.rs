@@ -0,0 +1,414 @@
+//! Client for the identity server API, used to invite users by email address or phone number.
+
+use crate::common::error::ApiError;
+use base64::Engine;
+use serde::Deserialize;
+use serde_json::{json, Value};
+use sha2::{Digest, Sha256};
+use std::time::Duration;
+
+const IDENTITY_REQUEST_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// What the identity server is told about an invite it stores for a third-party ID.
+#[derive(Debug, Clone, Default)]
+pub struct StoreInviteRequest {
+    pub medium: String,
+    pub address: String,
+    pub room_id: String,
+    pub sender: String,
+    pub sender_display_name: Option<String>,
+    pub room_alias: Option<String>,
+    pub room_name: Option<String>,
+    pub room_avatar_url: Option<String>,
+    pub room_join_rules: Option<String>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct InvitePublicKey {
+    pub public_key: String,
+    pub key_validity_url: String,
+}
+
+/// An invite stored by the identity server, to be published as `m.room.third_party_invite`.
+#[derive(Debug, Clone, Deserialize)]
+pub struct StoredInvite {
+    pub token: String,
+    pub public_keys: Vec<InvitePublicKey>,
+    pub display_name: String,
+}
+
+impl StoredInvite {
+    /// The `m.room.third_party_invite` content for this invite.
+    pub fn event_content(&self) -> Value {
+        let first = self.public_keys.first();
+        json!({
+            "display_name": self.display_name,
+            "key_validity_url": first.map(|key| key.key_validity_url.as_str()),
+            "public_key": first.map(|key| key.public_key.as_str()),
+            "public_keys": self.public_keys.iter().map(|key| json!({
+                "public_key": key.public_key,
+                "key_validity_url": key.key_validity_url,
+            })).collect::<Vec<_>>(),
+        })
+    }
+}
+
+pub struct IdentityService {
+    http_client: reqwest::Client,
+}
+
+impl Default for IdentityService {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl IdentityService {
+    pub fn new() -> Self {
+        let http_client = reqwest::Client::builder()
+            .timeout(IDENTITY_REQUEST_TIMEOUT)
+            .build()
+            .unwrap_or_else(|_| reqwest::Client::new());
+        Self { http_client }
+    }
+
+    /// The base URL of an identity server. Clients give a bare server name, so HTTPS is assumed
+    /// unless a scheme is included.
+    pub fn base_url(id_server: &str) -> String {
+        let id_server = id_server.trim_end_matches('/');
+        if id_server.starts_with("https://") || id_server.starts_with("http://") {
+            id_server.to_string()
+        } else {
+            format!("https://{}", id_server)
+        }
+    }
+
+    /// Looks up the Matrix user ID bound to a third-party ID, hashing the address when the
+    /// identity server supports it.
+    pub async fn lookup(
+        &self,
+        id_server: &str,
+        id_access_token: &str,
+        medium: &str,
+        address: &str,
+    ) -> Result<Option<String>, ApiError> {
+        let base_url = Self::base_url(id_server);
+        let details = self
+            .request(
+                self.http_client.get(format!("{}/_matrix/identity/v2/hash_details", base_url)),
+                id_access_token,
+            )
+            .await?;
+        let algorithms: Vec<&str> = details
+            .get("algorithms")
+            .and_then(|v| v.as_array())
+            .map(|algorithms| algorithms.iter().filter_map(|a| a.as_str()).collect())
+            .unwrap_or_default();
+        let pepper = details.get("lookup_pepper").and_then(|v| v.as_str()).unwrap_or_default();
+
+        let (algorithm, lookup_address) = if algorithms.contains(&"sha256") {
+            ("sha256", lookup_hash(medium, address, pepper))
+        } else if algorithms.contains(&"none") {
+            ("none", format!("{} {}", address, medium))
+        } else {
+            return Err(ApiError::internal(format!(
+                "Identity server {} offers no supported lookup algorithm",
+                id_server
+            )));
+        };
+
+        let body = json!({
+            "addresses": [lookup_address],
+            "algorithm": algorithm,
+            "pepper": pepper,
+        });
+        let response = self
+            .request(
+                self.http_client.post(format!("{}/_matrix/identity/v2/lookup", base_url)).json(&body),
+                id_access_token,
+            )
+            .await?;
+        Ok(response
+            .get("mappings")
+            .and_then(|mappings| mappings.get(&lookup_address))
+            .and_then(|v| v.as_str())
+            .map(String::from))
+    }
+
+    /// Has the identity server hold an invite until the third-party ID is bound to a user.
+    pub async fn store_invite(
+        &self,
+        id_server: &str,
+        id_access_token: &str,
+        invite: &StoreInviteRequest,
+    ) -> Result<StoredInvite, ApiError> {
+        let mut body = json!({
+            "medium": invite.medium,
+            "address": invite.address,
+            "room_id": invite.room_id,
+            "sender": invite.sender,
+        });
+        let optional = [
+            ("sender_display_name", &invite.sender_display_name),
+            ("room_alias", &invite.room_alias),
+            ("room_name", &invite.room_name),
+            ("room_avatar_url", &invite.room_avatar_url),
+            ("room_join_rules", &invite.room_join_rules),
+        ];
+        for (field, value) in optional {
+            if let Some(value) = value {
+                body[field] = json!(value);
+            }
+        }
+
+        let url = format!("{}/_matrix/identity/v2/store-invite", Self::base_url(id_server));
+        let response = self
+            .request(self.http_client.post(url).json(&body), id_access_token)
+            .await?;
+        let stored: StoredInvite = serde_json::from_value(response)
+            .map_err(|e| ApiError::internal(format!("Invalid store-invite response: {}", e)))?;
+        if stored.public_keys.is_empty() {
+            return Err(ApiError::internal("Identity server returned no public keys".to_string()));
+        }
+        Ok(stored)
+    }
+
+    /// Asks the identity server behind `key_validity_url` whether `public_key` is still valid.
+    pub async fn is_public_key_valid(&self, key_validity_url: &str, public_key: &str) -> bool {
+        let response = self
+            .http_client
+            .get(key_validity_url)
+            .query(&[("public_key", public_key)])
+            .send()
+            .await;
+        match response {
+            Ok(response) if response.status().is_success() => response
+                .json::<Value>()
+                .await
+                .ok()
+                .and_then(|body| body.get("valid").and_then(|v| v.as_bool()))
+                .unwrap_or(false),
+            Ok(response) => {
+                tracing::warn!("Key validity check at {} returned {}", key_validity_url, response.status());
+                false
+            }
+            Err(e) => {
+                tracing::warn!("Key validity check at {} failed: {}", key_validity_url, e);
+                false
+            }
+        }
+    }
+
+    /// Checks that the `signed` block of a third-party invite carries a valid signature from
+    /// an identity server, using the public key that server publishes for the signing key.
+    pub async fn verify_signed(&self, signed: &Value) -> bool {
+        let mut unsigned = signed.clone();
+        if let Some(fields) = unsigned.as_object_mut() {
+            fields.remove("signatures");
+        }
+        let signed_bytes = crate::federation::pdu::canonical_json_bytes(&unsigned);
+
+        let Some(servers) = signed.get("signatures").and_then(|v| v.as_object()) else {
+            return false;
+        };
+        for (id_server, keys) in servers {
+            let Some(keys) = keys.as_object() else {
+                continue;
+            };
+            for (key_id, signature) in keys {
+                let Some(signature) = signature.as_str() else {
+                    continue;
+                };
+                let Some(public_key) = self.public_key(id_server, key_id).await else {
+                    continue;
+                };
+                if crate::federation::event_auth::verify_ed25519(&public_key, signature, &signed_bytes) {
+                    return true;
+                }
+            }
+        }
+        false
+    }
+
+    /// The public key an identity server publishes for `key_id`.
+    async fn public_key(&self, id_server: &str, key_id: &str) -> Option<String> {
+        let url = format!("{}/_matrix/identity/v2/pubkey/{}", Self::base_url(id_server), key_id);
+        match self.http_client.get(&url).send().await {
+            Ok(response) if response.status().is_success() => response
+                .json::<Value>()
+                .await
+                .ok()
+                .and_then(|body| body.get("public_key").and_then(|v| v.as_str()).map(String::from)),
+            Ok(response) => {
+                tracing::warn!("Public key lookup at {} returned {}", url, response.status());
+                None
+            }
+            Err(e) => {
+                tracing::warn!("Public key lookup at {} failed: {}", url, e);
+                None
+            }
+        }
+    }
+
+    async fn request(&self, request: reqwest::RequestBuilder, id_access_token: &str) -> Result<Value, ApiError> {
+        let response = request
+            .bearer_auth(id_access_token)
+            .send()
+            .await
+            .map_err(|e| ApiError::internal(format!("Identity server request failed: {}", e)))?;
+        let status = response.status();
+        let body: Value = response.json().await.unwrap_or(Value::Null);
+        if !status.is_success() {
+            let error = body.get("error").and_then(|v| v.as_str()).unwrap_or("no error given");
+            return Err(match status.as_u16() {
+                401 | 403 => ApiError::forbidden(format!("Identity server refused the request: {}", error)),
+                _ => ApiError::internal(format!("Identity server returned {}: {}", status, error)),
+            });
+        }
+        Ok(body)
+    }
+}
+
+/// The hashed lookup address for a third-party ID, as defined by the v2 lookup API.
+pub fn lookup_hash(medium: &str, address: &str, pepper: &str) -> String {
+    let digest = Sha256::digest(format!("{} {} {}", address, medium, pepper).as_bytes());
+    base64::engine::general_purpose::URL_SAFE_NO_PAD.encode(digest)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::extract::{Json, Query};
+    use axum::http::{HeaderMap, StatusCode};
+    use axum::routing::{get, post};
+    use std::collections::HashMap;
+
+    const TOKEN: &str = "id_token";
+    const SEED: [u8; 32] = [3u8; 32];
+
+    fn authorized(headers: &HeaderMap) -> Result<(), StatusCode> {
+        match headers.get("authorization").and_then(|v| v.to_str().ok()) {
+            Some(value) if value == format!("Bearer {}", TOKEN) => Ok(()),
+            _ => Err(StatusCode::UNAUTHORIZED),
+        }
+    }
+
+    /// An identity server with one bound address and one valid key.
+    async fn identity_server() -> String {
+        async fn hash_details(headers: HeaderMap) -> Result<Json<Value>, StatusCode> {
+            authorized(&headers)?;
+            Ok(Json(json!({ "algorithms": ["none", "sha256"], "lookup_pepper": "pep" })))
+        }
+
+        async fn lookup(headers: HeaderMap, Json(body): Json<Value>) -> Result<Json<Value>, StatusCode> {
+            authorized(&headers)?;
+            let bound = lookup_hash("email", "bound@example.org", "pep");
+            let mut mappings = serde_json::Map::new();
+            if body["algorithm"] == "sha256" && body["addresses"][0] == bound {
+                mappings.insert(bound, json!("@bound:example.org"));
+            }
+            Ok(Json(json!({ "mappings": mappings })))
+        }
+
+        async fn store_invite(headers: HeaderMap, Json(body): Json<Value>) -> Result<Json<Value>, StatusCode> {
+            authorized(&headers)?;
+            assert_eq!(body["room_name"], "Lobby");
+            Ok(Json(json!({
+                "token": "tok",
+                "display_name": "n...@e...",
+                "public_keys": [{ "public_key": "pk", "key_validity_url": "https://id.test/isvalid" }],
+            })))
+        }
+
+        async fn is_valid(Query(query): Query<HashMap<String, String>>) -> Json<Value> {
+            Json(json!({ "valid": query.get("public_key").map(String::as_str) == Some("pk") }))
+        }
+
+        async fn pubkey(axum::extract::Path(key_id): axum::extract::Path<String>) -> Result<Json<Value>, StatusCode> {
+            if key_id != "ed25519:0" {
+                return Err(StatusCode::NOT_FOUND);
+            }
+            let key = ed25519_dalek::SigningKey::from_bytes(&SEED).verifying_key();
+            let public_key = base64::engine::general_purpose::STANDARD_NO_PAD.encode(key.to_bytes());
+            Ok(Json(json!({ "public_key": public_key })))
+        }
+
+        let app = axum::Router::new()
+            .route("/_matrix/identity/v2/pubkey/{key_id}", get(pubkey))
+            .route("/_matrix/identity/v2/hash_details", get(hash_details))
+            .route("/_matrix/identity/v2/lookup", post(lookup))
+            .route("/_matrix/identity/v2/store-invite", post(store_invite))
+            .route("/_matrix/identity/v2/pubkey/isvalid", get(is_valid));
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        tokio::spawn(async move {
+            axum::serve(listener, app).await.unwrap();
+        });
+        format!("http://127.0.0.1:{}", port)
+    }
+
+    #[test]
+    fn test_base_url_defaults_to_https() {
+        assert_eq!(IdentityService::base_url("id.example.org"), "https://id.example.org");
+        assert_eq!(IdentityService::base_url("http://127.0.0.1:8090/"), "http://127.0.0.1:8090");
+    }
+
+    #[tokio::test]
+    async fn test_lookup_uses_hashed_addresses() {
+        let id_server = identity_server().await;
+        let service = IdentityService::new();
+
+        let bound = service.lookup(&id_server, TOKEN, "email", "bound@example.org").await.unwrap();
+        assert_eq!(bound.as_deref(), Some("@bound:example.org"));
+        let unbound = service.lookup(&id_server, TOKEN, "email", "new@example.org").await.unwrap();
+        assert_eq!(unbound, None);
+        assert!(service.lookup(&id_server, "wrong", "email", "bound@example.org").await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_store_invite_and_check_key() {
+        let id_server = identity_server().await;
+        let service = IdentityService::new();
+
+        let invite = StoreInviteRequest {
+            medium: "email".to_string(),
+            address: "new@example.org".to_string(),
+            room_id: "!room:example.org".to_string(),
+            sender: "@alice:example.org".to_string(),
+            room_name: Some("Lobby".to_string()),
+            ..Default::default()
+        };
+        let stored = service.store_invite(&id_server, TOKEN, &invite).await.unwrap();
+        assert_eq!(stored.token, "tok");
+        let content = stored.event_content();
+        assert_eq!(content["public_key"], "pk");
+        assert_eq!(content["public_keys"][0]["key_validity_url"], "https://id.test/isvalid");
+
+        let validity_url = format!("{}/_matrix/identity/v2/pubkey/isvalid", id_server);
+        assert!(service.is_public_key_valid(&validity_url, "pk").await);
+        assert!(!service.is_public_key_valid(&validity_url, "revoked").await);
+    }
+
+    #[tokio::test]
+    async fn test_verify_signed_checks_identity_server_key() {
+        use ed25519_dalek::Signer;
+
+        let id_server = identity_server().await;
+        let service = IdentityService::new();
+        let mut signed = json!({ "mxid": "@bound:example.org", "token": "tok" });
+        let bytes = crate::federation::pdu::canonical_json_bytes(&signed);
+        let signature = ed25519_dalek::SigningKey::from_bytes(&SEED).sign(&bytes);
+        let signature = base64::engine::general_purpose::STANDARD_NO_PAD.encode(signature.to_bytes());
+
+        signed["signatures"] = json!({ id_server.clone(): { "ed25519:0": signature } });
+        assert!(service.verify_signed(&signed).await);
+
+        let mut forged = signed.clone();
+        forged["mxid"] = json!("@mallory:example.org");
+        assert!(!service.verify_signed(&forged).await);
+        let mut unknown_key = json!({ "mxid": "@bound:example.org", "token": "tok" });
+        unknown_key["signatures"] = json!({ id_server: { "ed25519:1": signature } });
+        assert!(!service.verify_signed(&unknown_key).await);
+        assert!(!service.verify_signed(&json!({ "mxid": "@bound:example.org" })).await);
+    }
+}
@@ -1,93 +1,25 @@
-use chrono::Utc;
+use crate::federation::{AuthState, EventAuthChain};
+use crate::storage::{CreateEventParams, EventStorage, Room, RoomMember, RoomMemberStorage, RoomStorage};
 use serde::{Deserialize, Serialize};
-use std::collections::HashMap;
-use std::sync::Arc;
-use tokio::sync::RwLock;
-use tracing::{debug, info};
-
-#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
-pub enum KnockState {
-    Pending,
-    Approved,
-    Rejected,
-    Expired,
-    Cancelled,
-}
-
-#[derive(Debug, Clone, Serialize, Deserialize)]
-pub struct KnockRequest {
-    pub knock_id: String,
-    pub room_id: String,
-    pub user_id: String,
-    pub reason: Option<String>,
-    pub state: KnockState,
-    pub created_at: i64,
-    pub updated_at: i64,
-    pub expires_at: Option<i64>,
-    pub reviewed_by: Option<String>,
-    pub rejection_reason: Option<String>,
-}
-
-impl KnockRequest {
-    pub fn new(room_id: String, user_id: String, reason: Option<String>) -> Self {
-        let now = Utc::now().timestamp_millis();
-        let expires_at = now + (7 * 24 * 3600 * 1000);
-
-        Self {
-            knock_id: uuid::Uuid::new_v4().to_string(),
-            room_id,
-            user_id,
-            reason,
-            state: KnockState::Pending,
-            created_at: now,
-            updated_at: now,
-            expires_at: Some(expires_at),
-            reviewed_by: None,
-            rejection_reason: None,
-        }
-    }
-
-    pub fn is_expired(&self) -> bool {
-        if let Some(expires) = self.expires_at {
-            return Utc::now().timestamp_millis() > expires;
-        }
-        false
-    }
-
-    pub fn is_pending(&self) -> bool {
-        self.state == KnockState::Pending && !self.is_expired()
-    }
-
-    pub fn approve(&mut self, reviewed_by: String) {
-        self.state = KnockState::Approved;
-        self.reviewed_by = Some(reviewed_by);
-        self.updated_at = Utc::now().timestamp_millis();
-    }
-
-    pub fn reject(&mut self, reviewed_by: String, reason: Option<String>) {
-        self.state = KnockState::Rejected;
-        self.reviewed_by = Some(reviewed_by);
-        self.rejection_reason = reason;
-        self.updated_at = Utc::now().timestamp_millis();
-    }
-
-    pub fn cancel(&mut self) {
-        self.state = KnockState::Cancelled;
-        self.updated_at = Utc::now().timestamp_millis();
-    }
-
-    pub fn expire(&mut self) {
-        self.state = KnockState::Expired;
-        self.updated_at = Utc::now().timestamp_millis();
-    }
-}
+use serde_json::{json, Value};
+use tracing::info;
+
+/// State shown to users outside a room, invitees and knockers, alongside their membership.
+pub const STRIPPED_STATE_TYPES: [&str; 7] = [
+    "m.room.create",
+    "m.room.join_rules",
+    "m.room.name",
+    "m.room.canonical_alias",
+    "m.room.avatar",
+    "m.room.encryption",
+    "m.room.topic",
+];
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct KnockConfig {
     pub enabled: bool,
     pub max_pending_per_room: usize,
     pub max_pending_per_user: usize,
-    pub default_expiry_hours: u64,
     pub require_reason: bool,
 }
 
@@ -97,395 +29,327 @@ impl Default for KnockConfig {
             enabled: true,
             max_pending_per_room: 100,
             max_pending_per_user: 10,
-            default_expiry_hours: 168,
             require_reason: false,
         }
     }
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
-pub struct KnockStats {
-    pub total_knocks: u64,
-    pub pending_knocks: u64,
-    pub approved_knocks: u64,
-    pub rejected_knocks: u64,
-    pub expired_knocks: u64,
-}
-
+/// Knocks on rooms this server holds the state of. A knock is an `m.room.member` event with
+/// `knock` membership; it is approved by inviting the knocker and rejected by kicking them.
+/// Every membership change here is held to the room's authorization rules.
 pub struct KnockService {
-    knocks: Arc<RwLock<HashMap<String, KnockRequest>>>,
+    room_storage: RoomStorage,
+    member_storage: RoomMemberStorage,
+    event_storage: EventStorage,
     config: KnockConfig,
 }
 
 impl KnockService {
-    pub fn new(config: KnockConfig) -> Self {
+    pub fn new(
+        room_storage: RoomStorage,
+        member_storage: RoomMemberStorage,
+        event_storage: EventStorage,
+        config: KnockConfig,
+    ) -> Self {
         Self {
-            knocks: Arc::new(RwLock::new(HashMap::new())),
+            room_storage,
+            member_storage,
+            event_storage,
             config,
         }
     }
 
-    pub async fn knock(
-        &self,
-        room_id: &str,
-        user_id: &str,
-        reason: Option<String>,
-    ) -> Result<KnockRequest, KnockError> {
+    /// Knocks on `room_id` as `user_id`. `content` is the membership content, carrying the
+    /// knocker's profile and reason. Returns the knock event ID.
+    pub async fn knock(&self, room_id: &str, user_id: &str, content: &Value) -> Result<String, KnockError> {
         if !self.config.enabled {
             return Err(KnockError::KnockDisabled);
         }
-
-        if self.config.require_reason && reason.is_none() {
+        if self.config.require_reason && content["reason"].as_str().is_none_or(|r| r.is_empty()) {
             return Err(KnockError::ReasonRequired);
         }
 
-        let knocks = self.knocks.read().await;
-        let pending_for_room = knocks
-            .values()
-            .filter(|k| k.room_id == room_id && k.is_pending())
-            .count();
-
-        if pending_for_room >= self.config.max_pending_per_room {
+        if self.get_pending_for_room(room_id).await?.len() >= self.config.max_pending_per_room {
             return Err(KnockError::RoomKnockLimitExceeded);
         }
-
-        let pending_for_user = knocks
-            .values()
-            .filter(|k| k.user_id == user_id && k.is_pending())
-            .count();
-
-        if pending_for_user >= self.config.max_pending_per_user {
+        if self.get_pending_for_user(user_id).await?.len() >= self.config.max_pending_per_user {
             return Err(KnockError::UserKnockLimitExceeded);
         }
 
-        let existing = knocks.values().find(|k| {
-            k.room_id == room_id && k.user_id == user_id && k.is_pending()
-        });
-
-        if existing.is_some() {
-            return Err(KnockError::KnockAlreadyExists);
-        }
-        drop(knocks);
-
-        let knock = KnockRequest::new(room_id.to_string(), user_id.to_string(), reason);
-
-        self.knocks.write().await.insert(knock.knock_id.clone(), knock.clone());
-
-        info!(
-            knock_id = %knock.knock_id,
-            room_id = %room_id,
-            user_id = %user_id,
-            "New knock request created"
-        );
-
-        Ok(knock)
+        let mut content = content.clone();
+        content["membership"] = json!("knock");
+        let event_id = self.change_membership(room_id, user_id, user_id, content).await?;
+        info!(room_id = %room_id, user_id = %user_id, event_id = %event_id, "User knocked on room");
+        Ok(event_id)
     }
 
-    pub async fn approve(&self, knock_id: &str, reviewer: &str) -> Result<KnockRequest, KnockError> {
-        let mut knocks = self.knocks.write().await;
-        
-        let knock = knocks
-            .get_mut(knock_id)
-            .ok_or(KnockError::KnockNotFound)?;
-
-        if !knock.is_pending() {
-            return Err(KnockError::InvalidKnockState);
-        }
-
-        knock.approve(reviewer.to_string());
-
-        info!(
-            knock_id = %knock_id,
-            room_id = %knock.room_id,
-            user_id = %knock.user_id,
-            reviewer = %reviewer,
-            "Knock request approved"
-        );
-
-        Ok(knock.clone())
+    /// Lets a knocker in by inviting them. Returns the invite event ID.
+    pub async fn approve(
+        &self,
+        room_id: &str,
+        user_id: &str,
+        reviewer: &str,
+        reason: Option<&str>,
+    ) -> Result<String, KnockError> {
+        self.review(room_id, user_id, reviewer, "invite", reason).await
     }
 
+    /// Turns a knocker away by kicking them. Returns the leave event ID.
     pub async fn reject(
         &self,
-        knock_id: &str,
+        room_id: &str,
+        user_id: &str,
         reviewer: &str,
-        reason: Option<String>,
-    ) -> Result<KnockRequest, KnockError> {
-        let mut knocks = self.knocks.write().await;
-        
-        let knock = knocks
-            .get_mut(knock_id)
-            .ok_or(KnockError::KnockNotFound)?;
-
-        if !knock.is_pending() {
-            return Err(KnockError::InvalidKnockState);
-        }
-
-        knock.reject(reviewer.to_string(), reason);
-
-        info!(
-            knock_id = %knock_id,
-            room_id = %knock.room_id,
-            user_id = %knock.user_id,
-            reviewer = %reviewer,
-            "Knock request rejected"
-        );
-
-        Ok(knock.clone())
+        reason: Option<&str>,
+    ) -> Result<String, KnockError> {
+        self.review(room_id, user_id, reviewer, "leave", reason).await
     }
 
-    pub async fn cancel(&self, knock_id: &str, user_id: &str) -> Result<(), KnockError> {
-        let mut knocks = self.knocks.write().await;
-        
-        let knock = knocks
-            .get_mut(knock_id)
-            .ok_or(KnockError::KnockNotFound)?;
-
-        if knock.user_id != user_id {
-            return Err(KnockError::NotAuthorized);
+    async fn review(
+        &self,
+        room_id: &str,
+        user_id: &str,
+        reviewer: &str,
+        membership: &str,
+        reason: Option<&str>,
+    ) -> Result<String, KnockError> {
+        if self.member_storage.get_membership(room_id, user_id).await?.as_deref() != Some("knock") {
+            return Err(KnockError::KnockNotFound);
         }
 
-        if !knock.is_pending() {
-            return Err(KnockError::InvalidKnockState);
+        let mut content = json!({ "membership": membership });
+        if let Some(reason) = reason {
+            content["reason"] = json!(reason);
         }
-
-        knock.cancel();
-
+        let event_id = self.change_membership(room_id, reviewer, user_id, content).await?;
         info!(
-            knock_id = %knock_id,
+            room_id = %room_id,
             user_id = %user_id,
-            "Knock request cancelled"
+            reviewer = %reviewer,
+            membership = %membership,
+            "Knock reviewed"
         );
-
-        Ok(())
+        Ok(event_id)
     }
 
-    pub async fn get_knock(&self, knock_id: &str) -> Option<KnockRequest> {
-        self.knocks.read().await.get(knock_id).cloned()
+    /// Users knocking on `room_id`.
+    pub async fn get_pending_for_room(&self, room_id: &str) -> Result<Vec<RoomMember>, KnockError> {
+        Ok(self.member_storage.get_room_members(room_id, "knock").await?)
     }
 
-    pub async fn get_pending_for_room(&self, room_id: &str) -> Vec<KnockRequest> {
-        self.knocks
-            .read()
-            .await
-            .values()
-            .filter(|k| k.room_id == room_id && k.is_pending())
-            .cloned()
-            .collect()
+    /// Rooms `user_id` is knocking on.
+    pub async fn get_pending_for_user(&self, user_id: &str) -> Result<Vec<String>, KnockError> {
+        Ok(self.member_storage.get_knocked_rooms(user_id, 0).await?)
     }
 
-    pub async fn get_pending_for_user(&self, user_id: &str) -> Vec<KnockRequest> {
-        self.knocks
-            .read()
-            .await
-            .values()
-            .filter(|k| k.user_id == user_id && k.is_pending())
-            .cloned()
-            .collect()
+    /// The stripped state a knocker sees of the room, ending with their own membership. Knocks
+    /// on other servers' rooms keep the state the resident server sent back with the knock.
+    pub async fn knock_room_state(&self, room_id: &str, user_id: &str) -> Result<Vec<Value>, KnockError> {
+        Ok(stripped_room_state(&self.event_storage, room_id, user_id, "knock_room_state").await?)
     }
 
-    pub async fn cleanup_expired(&self) -> usize {
-        let mut knocks = self.knocks.write().await;
-
-        let expired: Vec<String> = knocks
-            .iter_mut()
-            .filter(|(_, k)| k.state == KnockState::Pending && k.is_expired())
-            .map(|(id, k)| {
-                k.expire();
-                id.clone()
-            })
-            .collect();
-
-        let count = expired.len();
-        if count > 0 {
-            debug!(count = count, "Expired knock requests cleaned up");
-        }
-
-        count
-    }
-
-    pub async fn get_stats(&self) -> KnockStats {
-        let knocks = self.knocks.read().await;
-
-        let mut stats = KnockStats {
-            total_knocks: knocks.len() as u64,
-            pending_knocks: 0,
-            approved_knocks: 0,
-            rejected_knocks: 0,
-            expired_knocks: 0,
+    /// Builds the membership event `sender` sets for `target`, checks it against the room's
+    /// current state and stores it.
+    async fn change_membership(
+        &self,
+        room_id: &str,
+        sender: &str,
+        target: &str,
+        content: Value,
+    ) -> Result<String, KnockError> {
+        let room = self
+            .room_storage
+            .get_room(room_id)
+            .await?
+            .ok_or(KnockError::RoomNotFound)?;
+
+        let params = CreateEventParams {
+            event_id: crate::common::crypto::generate_event_id(&self.member_storage.server_name),
+            room_id: room_id.to_string(),
+            user_id: sender.to_string(),
+            event_type: "m.room.member".to_string(),
+            content: content.clone(),
+            state_key: Some(target.to_string()),
+            origin_server_ts: chrono::Utc::now().timestamp_millis(),
         };
+        let (event_id, event) = self.event_storage.build_event(params).await?;
+
+        let auth_state = self
+            .auth_state(&room, &EventAuthChain::auth_types_for_event(&event))
+            .await?;
+        EventAuthChain::check_auth_rules(&event, &auth_state).map_err(KnockError::NotAllowed)?;
+
+        self.event_storage.create_built_event(&event_id, &event).await?;
+        self.member_storage
+            .apply_member_event(room_id, &event_id, sender, target, &content)
+            .await?;
+        Ok(event_id)
+    }
 
-        for knock in knocks.values() {
-            match knock.state {
-                KnockState::Pending => stats.pending_knocks += 1,
-                KnockState::Approved => stats.approved_knocks += 1,
-                KnockState::Rejected => stats.rejected_knocks += 1,
-                KnockState::Expired => stats.expired_knocks += 1,
-                KnockState::Cancelled => {}
+    /// The room's current state for `keys`. Memberships come from the membership table, which
+    /// local joins and invites update without a state event; the create event and join rules
+    /// fall back to the room's creation settings.
+    async fn auth_state(&self, room: &Room, keys: &[(String, String)]) -> Result<AuthState, KnockError> {
+        let mut auth_state = AuthState::new();
+        for (event_type, state_key) in keys {
+            let event = if event_type == "m.room.member" {
+                self.member_storage
+                    .get_member(&room.room_id, state_key)
+                    .await?
+                    .map(|member| {
+                        json!({
+                            "type": "m.room.member",
+                            "state_key": member.user_id,
+                            "sender": member.sender.unwrap_or_else(|| member.user_id.clone()),
+                            "content": { "membership": member.membership }
+                        })
+                    })
+            } else {
+                match self.event_storage.get_state_event(&room.room_id, event_type, state_key).await? {
+                    Some(event) => Some(json!({
+                        "event_id": event.event_id,
+                        "type": event.event_type,
+                        "state_key": event.state_key,
+                        "sender": event.sender,
+                        "content": event.content
+                    })),
+                    None => room_fallback_state(room, event_type),
+                }
+            };
+            if let Some(event) = event {
+                auth_state.insert((event_type.clone(), state_key.clone()), event);
             }
         }
+        Ok(auth_state)
+    }
+}
 
-        stats
+/// State events of rooms created before state events were stored, rebuilt from the room row.
+fn room_fallback_state(room: &Room, event_type: &str) -> Option<Value> {
+    match event_type {
+        "m.room.create" => Some(json!({
+            "type": "m.room.create",
+            "state_key": "",
+            "sender": room.creator,
+            "content": { "creator": room.creator, "room_version": room.version }
+        })),
+        "m.room.join_rules" => Some(json!({
+            "type": "m.room.join_rules",
+            "state_key": "",
+            "sender": room.creator,
+            "content": { "join_rule": room.join_rule }
+        })),
+        _ => None,
     }
 }
 
-impl Default for KnockService {
-    fn default() -> Self {
-        Self::new(KnockConfig::default())
+/// The stripped state a user outside the room sees of it, ending with their own membership.
+/// A membership received from another server keeps the state that server sent along with it,
+/// stored under `unsigned[remote_key]`.
+pub async fn stripped_room_state(
+    event_storage: &EventStorage,
+    room_id: &str,
+    user_id: &str,
+    remote_key: &str,
+) -> Result<Vec<Value>, sqlx::Error> {
+    let own_membership = event_storage.get_state_event(room_id, "m.room.member", user_id).await?;
+    if let Some(remote_state) = own_membership
+        .as_ref()
+        .and_then(|event| event.unsigned.as_ref())
+        .and_then(|unsigned| unsigned.get(remote_key))
+        .and_then(|state| state.as_array())
+    {
+        return Ok(remote_state.clone());
+    }
+
+    let mut stripped: Vec<Value> = event_storage
+        .get_current_state_by_types(room_id, &STRIPPED_STATE_TYPES)
+        .await?
+        .into_iter()
+        .map(|event| stripped_state_event(&event.event_type, event.state_key.as_deref(), &event.sender, &event.content))
+        .collect();
+    if let Some(event) = own_membership {
+        stripped.push(stripped_state_event(&event.event_type, event.state_key.as_deref(), &event.sender, &event.content));
     }
+    Ok(stripped)
+}
+
+/// A state event as shown to users outside the room.
+pub fn stripped_state_event(event_type: &str, state_key: Option<&str>, sender: &str, content: &Value) -> Value {
+    json!({
+        "type": event_type,
+        "state_key": state_key.unwrap_or_default(),
+        "sender": sender,
+        "content": content,
+    })
 }
 
 #[derive(Debug, thiserror::Error)]
 pub enum KnockError {
     #[error("Knock feature is disabled")]
     KnockDisabled,
-    #[error("Knock request not found")]
+    #[error("Room not found")]
+    RoomNotFound,
+    #[error("User is not knocking on this room")]
     KnockNotFound,
-    #[error("Knock already exists")]
-    KnockAlreadyExists,
-    #[error("Invalid knock state")]
-    InvalidKnockState,
     #[error("Room knock limit exceeded")]
     RoomKnockLimitExceeded,
     #[error("User knock limit exceeded")]
     UserKnockLimitExceeded,
     #[error("Reason is required")]
     ReasonRequired,
-    #[error("Not authorized")]
-    NotAuthorized,
+    #[error("{0}")]
+    NotAllowed(String),
+    #[error("Database error: {0}")]
+    Database(#[from] sqlx::Error),
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
 
-    #[tokio::test]
-    async fn test_create_knock() {
-        let service = KnockService::default();
-
-        let knock = service
-            .knock("!room:example.com", "@user:example.com", Some("I want to join".to_string()))
-            .await
-            .unwrap();
-
-        assert_eq!(knock.room_id, "!room:example.com");
-        assert_eq!(knock.user_id, "@user:example.com");
-        assert_eq!(knock.state, KnockState::Pending);
-    }
-
-    #[tokio::test]
-    async fn test_approve_knock() {
-        let service = KnockService::default();
-
-        let knock = service
-            .knock("!room:example.com", "@user:example.com", None)
-            .await
-            .unwrap();
-
-        let approved = service
-            .approve(&knock.knock_id, "@admin:example.com")
-            .await
-            .unwrap();
-
-        assert_eq!(approved.state, KnockState::Approved);
-        assert_eq!(approved.reviewed_by, Some("@admin:example.com".to_string()));
-    }
-
-    #[tokio::test]
-    async fn test_reject_knock() {
-        let service = KnockService::default();
-
-        let knock = service
-            .knock("!room:example.com", "@user:example.com", None)
-            .await
-            .unwrap();
-
-        let rejected = service
-            .reject(&knock.knock_id, "@admin:example.com", Some("Not allowed".to_string()))
-            .await
-            .unwrap();
-
-        assert_eq!(rejected.state, KnockState::Rejected);
-        assert_eq!(rejected.rejection_reason, Some("Not allowed".to_string()));
-    }
-
-    #[tokio::test]
-    async fn test_cancel_knock() {
-        let service = KnockService::default();
-
-        let knock = service
-            .knock("!room:example.com", "@user:example.com", None)
-            .await
-            .unwrap();
-
-        service
-            .cancel(&knock.knock_id, "@user:example.com")
-            .await
-            .unwrap();
-
-        let cancelled = service.get_knock(&knock.knock_id).await.unwrap();
-        assert_eq!(cancelled.state, KnockState::Cancelled);
-    }
-
-    #[tokio::test]
-    async fn test_duplicate_knock() {
-        let service = KnockService::default();
-
-        service
-            .knock("!room:example.com", "@user:example.com", None)
-            .await
-            .unwrap();
-
-        let result = service
-            .knock("!room:example.com", "@user:example.com", None)
-            .await;
-
-        assert!(matches!(result, Err(KnockError::KnockAlreadyExists)));
+    #[test]
+    fn test_room_fallback_state_for_old_rooms() {
+        let room = Room {
+            room_id: "!room:example.com".to_string(),
+            name: None,
+            topic: None,
+            avatar_url: None,
+            canonical_alias: None,
+            join_rule: "knock".to_string(),
+            creator: "@alice:example.com".to_string(),
+            version: "10".to_string(),
+            encryption: None,
+            is_public: false,
+            member_count: 1,
+            history_visibility: "shared".to_string(),
+            creation_ts: 0,
+        };
+        let create = room_fallback_state(&room, "m.room.create").unwrap();
+        assert_eq!(create["content"]["room_version"], "10");
+        let join_rules = room_fallback_state(&room, "m.room.join_rules").unwrap();
+        assert_eq!(join_rules["content"]["join_rule"], "knock");
+        assert!(room_fallback_state(&room, "m.room.power_levels").is_none());
     }
 
-    #[tokio::test]
-    async fn test_get_pending_for_room() {
-        let service = KnockService::default();
-
-        service
-            .knock("!room1:example.com", "@user1:example.com", None)
-            .await
-            .unwrap();
-
-        service
-            .knock("!room1:example.com", "@user2:example.com", None)
-            .await
-            .unwrap();
-
-        service
-            .knock("!room2:example.com", "@user3:example.com", None)
-            .await
-            .unwrap();
-
-        let pending = service.get_pending_for_room("!room1:example.com").await;
-        assert_eq!(pending.len(), 2);
+    #[test]
+    fn test_stripped_state_event_has_no_event_id() {
+        let stripped = stripped_state_event(
+            "m.room.name",
+            Some(""),
+            "@alice:example.com",
+            &json!({ "name": "Lobby" }),
+        );
+        assert_eq!(stripped["state_key"], "");
+        assert_eq!(stripped["content"]["name"], "Lobby");
+        assert!(stripped.get("event_id").is_none());
     }
 
-    #[tokio::test]
-    async fn test_stats() {
-        let service = KnockService::default();
-
-        let knock1 = service
-            .knock("!room:example.com", "@user1:example.com", None)
-            .await
-            .unwrap();
-
-        let knock2 = service
-            .knock("!room:example.com", "@user2:example.com", None)
-            .await
-            .unwrap();
-
-        service.approve(&knock1.knock_id, "@admin:example.com").await.unwrap();
-        service.reject(&knock2.knock_id, "@admin:example.com", None).await.unwrap();
-
-        let stats = service.get_stats().await;
-        assert_eq!(stats.total_knocks, 2);
-        assert_eq!(stats.approved_knocks, 1);
-        assert_eq!(stats.rejected_knocks, 1);
+    #[test]
+    fn test_knock_config_default() {
+        let config = KnockConfig::default();
+        assert!(config.enabled);
+        assert!(!config.require_reason);
+        assert_eq!(config.max_pending_per_user, 10);
     }
 }
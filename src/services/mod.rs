@@ -1,3 +1,4 @@
+use crate::appservice::{AppServiceRegistry, AppServiceScheduler};
 use crate::auth::*;
 use crate::cache::*;
 use crate::common::metrics::MetricsCollector;
@@ -6,7 +7,7 @@ use crate::common::*;
 // Import config types explicitly to avoid conflicts with the external `config` crate
 use crate::common::config::{
     AdminRegistrationConfig, Config, CorsConfig, DatabaseConfig, FederationConfig,
-    RateLimitConfig, RedisConfig, SearchConfig, SecurityConfig, ServerConfig, SmtpConfig,
+    RateLimitConfig, RedisConfig, SearchBackend, SearchConfig, SecurityConfig, ServerConfig, SmtpConfig,
     WorkerConfig,
 };
 use crate::e2ee::backup::KeyBackupService;
@@ -14,7 +15,10 @@ use crate::e2ee::cross_signing::CrossSigningService;
 use crate::e2ee::device_keys::DeviceKeyService;
 use crate::e2ee::megolm::MegolmService;
 use crate::e2ee::to_device::ToDeviceService;
-use crate::federation::{DeviceSyncManager, EventAuthChain, KeyRotationManager, FriendFederation};
+use crate::federation::{
+    DeviceSyncManager, EventAuthChain, EventSigningKey, FederationAccessControl, FederationClient,
+    FederationClientConfig, FederationPolicy, FriendFederation, KeyRotationManager,
+};
 use crate::storage::email_verification::EmailVerificationStorage;
 use crate::storage::*;
 use sqlx::{Pool, Postgres};
@@ -59,12 +63,40 @@ pub struct ServiceContainer {
     pub voice_service: VoiceService,
     /// 注册服务
     pub registration_service: Arc<RegistrationService>,
+    /// 注册令牌服务
+    pub registration_token_service: Arc<RegistrationTokenService>,
+    /// 验证码服务
+    pub captcha_service: Arc<CaptchaService>,
+    /// 手机号验证服务
+    pub phone_verification_service: Arc<PhoneVerificationService>,
+    /// 用户协议同意服务
+    pub consent_service: Arc<ConsentService>,
+    /// 用户交互认证服务
+    pub ui_auth_service: Arc<UiAuthService>,
     /// 房间服务
     pub room_service: Arc<RoomService>,
     /// 同步服务
     pub sync_service: Arc<SyncService>,
+    /// 敲门服务
+    pub knock_service: Arc<KnockService>,
+    /// 推送规则服务
+    pub push_rule_service: Arc<PushRuleService>,
+    /// 推送动作服务
+    pub push_actions_service: Arc<PushActionsService>,
+    /// 推送网关投递服务
+    pub push_service: Arc<PushService>,
+    /// 线程与关联事件服务
+    pub thread_service: Arc<ThreadService>,
+    /// 空间层级服务
+    pub space_manager: Arc<crate::spaces::SpaceManager>,
+    /// 滑动同步服务
+    pub sliding_sync_service: Arc<SlidingSyncService>,
+    /// 状态解析服务
+    pub state_resolution_service: Arc<StateResolutionService>,
     /// 搜索服务
     pub search_service: Arc<crate::services::search_service::SearchService>,
+    /// 房间消息搜索服务
+    pub room_search_service: Arc<RoomSearchService>,
     /// 媒体服务
     pub media_service: MediaService,
     /// 缓存管理器
@@ -87,12 +119,28 @@ pub struct ServiceContainer {
     pub key_rotation_manager: KeyRotationManager,
     /// 设备同步管理服务
     pub device_sync_manager: DeviceSyncManager,
+    /// 联邦访问控制
+    pub federation_access_control: Arc<FederationAccessControl>,
+    /// 联邦客户端
+    pub federation_client: Arc<FederationClient>,
+    /// 联邦事务发送服务
+    pub federation_sender: Arc<FederationSenderService>,
     /// 好友存储
     pub friend_storage: FriendRoomStorage,
     /// 好友房间服务
     pub friend_room_service: Arc<FriendRoomService>,
     /// 好友联邦服务
     pub friend_federation: Arc<FriendFederation>,
+    /// 身份服务器客户端
+    pub identity_service: Arc<IdentityService>,
+    /// 同步唤醒器
+    pub notifier: Notifier,
+    /// Redis 复制服务
+    pub replication_service: Arc<RedisReplicationService>,
+    /// 应用服务注册表
+    pub app_services: Arc<AppServiceRegistry>,
+    /// 应用服务事务调度器
+    pub appservice_scheduler: Arc<AppServiceScheduler>,
 }
 
 impl ServiceContainer {
@@ -126,6 +174,16 @@ impl ServiceContainer {
             &config.security,
             &config.server.name,
         );
+        let replication_service = Arc::new(RedisReplicationService::new(
+            RedisReplicationConfig {
+                enabled: config.worker.enabled && config.redis.enabled,
+                host: config.redis.host.clone(),
+                port: config.redis.port,
+                ..Default::default()
+            },
+            config.worker.instance_name.clone(),
+        ));
+        let notifier = Notifier::new().with_replication(replication_service.clone());
         let device_key_storage = crate::e2ee::device_keys::DeviceKeyStorage::new(pool);
         let device_keys_service = DeviceKeyService::new(device_key_storage, cache.clone());
         let megolm_storage = crate::e2ee::megolm::MegolmSessionStorage::new(pool);
@@ -137,8 +195,16 @@ impl ServiceContainer {
         let backup_service = KeyBackupService::new(key_backup_storage);
         let to_device_storage = crate::e2ee::to_device::ToDeviceStorage::new(pool);
         let user_storage = UserStorage::new(pool, cache.clone());
-        let to_device_service =
-            ToDeviceService::new(to_device_storage).with_user_storage(user_storage.clone());
+        let federation_queue = config
+            .federation
+            .enabled
+            .then(|| FederationQueueStorage::new(pool, &config.server.name));
+        let mut to_device_service = ToDeviceService::new(to_device_storage)
+            .with_user_storage(user_storage.clone())
+            .with_notifier(notifier.clone());
+        if let Some(queue) = &federation_queue {
+            to_device_service = to_device_service.with_federation_queue(queue.clone());
+        }
         let presence_service = PresenceStorage::new(presence_pool.clone(), cache.clone());
         let voice_service = VoiceService::new(pool, cache.clone(), "/app/data/media/voice");
         let search_service = Arc::new(crate::services::search_service::SearchService::new(
@@ -149,16 +215,65 @@ impl ServiceContainer {
         let server_name_for_storage = config.server.get_server_name().to_string();
         let member_storage = RoomMemberStorage::new(pool, &server_name_for_storage);
         let room_storage = RoomStorage::new(pool);
-        let event_storage = EventStorage::new(pool);
+        let mut event_storage = EventStorage::new(pool).with_notifier(notifier.clone());
+        let event_signing_key = config.federation.signing_key.as_deref().and_then(|secret| {
+            let key_id = config.federation.key_id.as_deref().unwrap_or("ed25519:1");
+            EventSigningKey::from_base64(&config.server.name, key_id, secret)
+        });
+        match event_signing_key {
+            Some(signing_key) => event_storage = event_storage.with_signing_key(signing_key),
+            None => ::tracing::warn!("No valid federation signing key configured; local events will be unsigned"),
+        }
+        if let Some(queue) = &federation_queue {
+            event_storage = event_storage.with_federation_queue(queue.clone());
+        }
+        let app_services = Arc::new(
+            AppServiceRegistry::load(&config.app_service_config_files).unwrap_or_else(|e| {
+                ::tracing::error!("Failed to load application service registrations: {}", e);
+                AppServiceRegistry::new()
+            }),
+        );
+        let appservice_scheduler = Arc::new(AppServiceScheduler::new(
+            app_services.clone(),
+            AppServiceQueueStorage::new(pool),
+            member_storage.clone(),
+            room_storage.clone(),
+            &config.server.name,
+        ));
+        event_storage = event_storage.with_appservices(appservice_scheduler.clone());
+        to_device_service = to_device_service.with_appservices(appservice_scheduler.clone());
+        let push_rule_service = Arc::new(
+            PushRuleService::new(PushRuleStorage::new(pool)).with_notifier(notifier.clone()),
+        );
+        let push_service = Arc::new(
+            PushService::new(Arc::new(config.push.clone())).with_pusher_storage(PusherStorage::new(pool)),
+        );
+        let push_actions_service = Arc::new(
+            PushActionsService::new(
+                EventPushActionsStorage::new(pool),
+                member_storage.clone(),
+                event_storage.clone(),
+                push_rule_service.clone(),
+                &config.server.name,
+            )
+            .with_push_service(push_service.clone()),
+        );
+        event_storage = event_storage.with_push_actions(push_actions_service.clone());
         let presence_storage = PresenceStorage::new(presence_pool.clone(), cache.clone());
 
-        let registration_service = Arc::new(RegistrationService::new(
-            user_storage.clone(),
-            auth_service.clone(),
-            metrics.clone(),
-            config.server.name.clone(),
-            config.server.enable_registration,
-            task_queue.clone(),
+        let registration_service = Arc::new(
+            RegistrationService::new(
+                user_storage.clone(),
+                auth_service.clone(),
+                metrics.clone(),
+                config.server.name.clone(),
+                config.server.enable_registration,
+                task_queue.clone(),
+            )
+            .with_app_services(app_services.clone()),
+        );
+        let registration_token_service = Arc::new(RegistrationTokenService::new(
+            RegistrationTokenStorage::new(pool),
         ));
         let room_service = Arc::new(RoomService::new(
             room_storage.clone(),
@@ -169,12 +284,45 @@ impl ServiceContainer {
             config.server.name.clone(),
             task_queue.clone(),
         ));
-        let sync_service = Arc::new(SyncService::new(
-            presence_storage.clone(),
-            member_storage.clone(),
+        let thread_service = Arc::new(ThreadService::new(
+            RelationsStorage::new(pool),
             event_storage.clone(),
+        ));
+        let knock_service = Arc::new(KnockService::new(
             room_storage.clone(),
-            DeviceStorage::new(pool),
+            member_storage.clone(),
+            event_storage.clone(),
+            KnockConfig::default(),
+        ));
+        let sync_service = Arc::new(
+            SyncService::new(
+                presence_storage.clone(),
+                member_storage.clone(),
+                event_storage.clone(),
+                room_storage.clone(),
+                DeviceStorage::new(pool),
+            )
+            .with_notifier(notifier.clone())
+            .with_user_storage(user_storage.clone())
+            .with_to_device_service(to_device_service.clone())
+            .with_device_keys_service(device_keys_service.clone())
+            .with_push_rule_service(push_rule_service.clone())
+            .with_push_actions_service(push_actions_service.clone())
+            .with_thread_service(thread_service.clone())
+            .with_knock_service(knock_service.clone()),
+        );
+        let sliding_sync_service = Arc::new(
+            SlidingSyncService::new(
+                sync_service.clone(),
+                member_storage.clone(),
+                event_storage.clone(),
+                SlidingSyncStorage::new(pool),
+            )
+            .with_notifier(notifier.clone()),
+        );
+        let state_resolution_service = Arc::new(StateResolutionService::new(
+            event_storage.clone(),
+            StateGroupStorage::new(pool),
         ));
         let media_service = MediaService::new("/app/data/media", task_queue.clone());
         let admin_registration_service = AdminRegistrationService::new(
@@ -185,11 +333,79 @@ impl ServiceContainer {
         );
 
         let email_verification_storage = EmailVerificationStorage::new(pool);
+        let captcha_service = Arc::new(CaptchaService::new(CaptchaConfig {
+            enabled: config.server.enable_registration_captcha,
+            ..Default::default()
+        }));
+        let phone_verification_service = Arc::new(PhoneVerificationService::default());
+        let consent_service = Arc::new(ConsentService::new().with_policies(
+            config
+                .ui_auth
+                .terms_policies
+                .iter()
+                .map(|policy| {
+                    ConsentPolicy::new(ConsentType::parse(&policy.id), policy.version.clone(), true)
+                        .with_description(policy.name.clone())
+                        .with_url(policy.url.clone())
+                })
+                .collect(),
+        ));
+        let mut ui_auth_service = UiAuthService::new(UiAuthStorage::new(pool), auth_service.clone(), &config)
+            .with_captcha_service(captcha_service.clone())
+            .with_phone_verification_service(phone_verification_service.clone())
+            .with_consent_service(consent_service.clone())
+            .with_registration_token_service(registration_token_service.clone())
+            .with_email_verification_storage(email_verification_storage.clone());
+        if config.oidc.is_enabled() {
+            ui_auth_service = ui_auth_service
+                .with_oidc_service(Arc::new(OidcService::new(Arc::new(config.oidc.clone()))));
+        }
+        let ui_auth_service = Arc::new(ui_auth_service);
 
         let event_auth_chain = EventAuthChain::new();
         let server_name = config.server.name.clone();
         let key_rotation_manager = KeyRotationManager::new(pool, &server_name);
-        let device_sync_manager = DeviceSyncManager::new(pool, Some(cache.clone()), task_queue.clone());
+        let federation_access_control = Arc::new(FederationAccessControl::new(FederationPolicy::default()));
+        let federation_client = Arc::new(FederationClient::new(
+            &server_name,
+            key_rotation_manager.clone(),
+            federation_access_control.clone(),
+            FederationClientConfig {
+                ca_file: config.federation.ca_file.clone(),
+                ..Default::default()
+            },
+        ));
+        let mut federation_sender = FederationSenderService::new(FederationSenderConfig::default())
+            .with_client(federation_client.clone())
+            .with_instances(
+                &config.worker.instance_name,
+                config.worker.federation_sender_instances.clone(),
+            );
+        if let Some(queue) = federation_queue {
+            federation_sender = federation_sender.with_queue(queue);
+        }
+        let federation_sender = Arc::new(federation_sender);
+        let device_sync_manager = DeviceSyncManager::new(pool, Some(cache.clone()), task_queue.clone())
+            .with_federation_client(federation_client.clone());
+
+        let space_manager = Arc::new(
+            crate::spaces::SpaceManager::new(
+                room_storage.clone(),
+                member_storage.clone(),
+                event_storage.clone(),
+                &server_name,
+            )
+            .with_federation_client(federation_client.clone()),
+        );
+        let search_backend: Arc<dyn MessageSearchBackend> = match config.search.backend {
+            SearchBackend::Postgres => Arc::new(PostgresSearchBackend::new(EventSearchStorage::new(pool))),
+            SearchBackend::Elasticsearch => search_service.clone(),
+        };
+        let room_search_service = Arc::new(RoomSearchService::new(
+            search_backend,
+            event_storage.clone(),
+            member_storage.clone(),
+        ));
 
         let friend_storage = FriendRoomStorage::new(pool.clone());
         let friend_room_service = Arc::new(FriendRoomService::new(
@@ -197,6 +413,7 @@ impl ServiceContainer {
             room_service.clone(),
             event_storage.clone(),
             config.server.name.clone(),
+            federation_client.clone(),
         ));
         let friend_federation = Arc::new(FriendFederation::new(friend_room_service.clone()));
 
@@ -217,9 +434,23 @@ impl ServiceContainer {
             to_device_service,
             voice_service,
             registration_service,
+            registration_token_service,
+            captcha_service,
+            phone_verification_service,
+            consent_service,
+            ui_auth_service,
             room_service,
             sync_service,
+            knock_service,
+            push_rule_service,
+            push_actions_service,
+            push_service,
+            thread_service,
+            space_manager,
+            sliding_sync_service,
+            state_resolution_service,
             search_service,
+            room_search_service,
             media_service,
             cache,
             task_queue,
@@ -231,9 +462,17 @@ impl ServiceContainer {
             event_auth_chain,
             key_rotation_manager,
             device_sync_manager,
+            federation_access_control,
+            federation_client,
+            federation_sender,
             friend_storage,
             friend_room_service,
             friend_federation,
+            identity_service: Arc::new(IdentityService::new()),
+            notifier,
+            replication_service,
+            app_services,
+            appservice_scheduler,
         }
     }
 
@@ -276,6 +515,8 @@ impl ServiceContainer {
                 max_image_resolution: 1000000,
                 enable_registration: true,
                 enable_registration_captcha: false,
+                registration_requires_token: false,
+                registrations_require_3pid: Vec::new(),
                 background_tasks_interval: 60,
                 expire_access_token: true,
                 expire_access_token_lifetime: 3600,
@@ -331,6 +572,7 @@ impl ServiceContainer {
             search: SearchConfig {
                 elasticsearch_url: "http://localhost:9200".to_string(),
                 enabled: false,
+                backend: SearchBackend::Postgres,
             },
             rate_limit: RateLimitConfig::default(),
             admin_registration: AdminRegistrationConfig {
@@ -345,6 +587,8 @@ impl ServiceContainer {
             push: crate::common::config::PushConfig::default(),
             url_preview: crate::common::config::UrlPreviewConfig::default(),
             oidc: crate::common::config::OidcConfig::default(),
+            app_service_config_files: Vec::new(),
+            ui_auth: crate::common::config::UiAuthConfig::default(),
         };
         Self::new(&pool, cache, config, None)
     }
@@ -358,6 +602,8 @@ fn generate_encryption_key() -> [u8; 32] {
     key
 }
 
+const TYPING_TIMEOUT_MS: i64 = 30_000;
+
 #[derive(Clone)]
 pub struct PresenceStorage {
     pool: Arc<Pool<Postgres>>,
@@ -414,6 +660,58 @@ impl PresenceStorage {
         Ok(result.map(|r| (r.0.unwrap_or_default(), r.1)))
     }
 
+    /// 同步时更新在线状态。仅在状态值变化时更新 `updated_ts`，返回状态是否发生了变化。
+    pub async fn touch_presence(&self, user_id: &str, presence: &str) -> Result<bool, sqlx::Error> {
+        let now = chrono::Utc::now().timestamp_millis();
+        sqlx::query_scalar::<_, bool>(
+            r#"
+            INSERT INTO presence (user_id, presence, last_active_ts, created_ts, updated_ts)
+            VALUES ($1, $2, $3, $3, $3)
+            ON CONFLICT (user_id) DO UPDATE SET
+                presence = EXCLUDED.presence,
+                last_active_ts = EXCLUDED.last_active_ts,
+                updated_ts = CASE
+                    WHEN presence.presence IS DISTINCT FROM EXCLUDED.presence THEN EXCLUDED.updated_ts
+                    ELSE presence.updated_ts
+                END
+            RETURNING updated_ts = $3
+            "#,
+        )
+        .bind(user_id)
+        .bind(presence)
+        .bind(now)
+        .fetch_one(&*self.pool)
+        .await
+    }
+
+    /// 返回与指定用户共享房间的用户（包括其自身）在 `since_ts` 之后变化的在线状态。
+    pub async fn get_presence_changes(
+        &self,
+        user_id: &str,
+        since_ts: i64,
+    ) -> Result<Vec<(String, String, Option<String>, i64)>, sqlx::Error> {
+        sqlx::query_as::<_, (String, String, Option<String>, i64)>(
+            r#"
+            SELECT p.user_id, COALESCE(p.presence, 'offline'), p.status_msg, p.last_active_ts
+            FROM presence p
+            WHERE COALESCE(p.updated_ts, p.last_active_ts) > $2
+              AND (
+                p.user_id = $1
+                OR p.user_id IN (
+                    SELECT other.user_id
+                    FROM room_memberships mine
+                    JOIN room_memberships other ON other.room_id = mine.room_id
+                    WHERE mine.user_id = $1 AND mine.membership = 'join' AND other.membership = 'join'
+                )
+              )
+            "#,
+        )
+        .bind(user_id)
+        .bind(since_ts)
+        .fetch_all(&*self.pool)
+        .await
+    }
+
     pub async fn set_typing(
         &self,
         room_id: &str,
@@ -449,6 +747,21 @@ impl PresenceStorage {
         }
         Ok(())
     }
+
+    /// 返回房间内仍在输入且未超时的用户。
+    pub async fn get_typing_users(&self, room_id: &str) -> Result<Vec<String>, sqlx::Error> {
+        let expiry = chrono::Utc::now().timestamp_millis() - TYPING_TIMEOUT_MS;
+        sqlx::query_scalar::<_, String>(
+            r#"
+            SELECT user_id FROM typing
+            WHERE room_id = $1 AND typing = TRUE AND last_active_ts > $2
+            "#,
+        )
+        .bind(room_id)
+        .bind(expiry)
+        .fetch_all(&*self.pool)
+        .await
+    }
 }
 
 pub mod admin_api_v2_service;
@@ -463,14 +776,18 @@ pub mod event_persister_service;
 pub mod federation_sender_service;
 pub mod friend_room_service;
 pub mod guest_access_service;
+pub mod identity_service;
 pub mod jwt_service;
 pub mod knock_service;
 pub mod manhole_service;
 pub mod media_service;
 pub mod moderation_service;
+pub mod notifier;
 pub mod oidc_service;
 pub mod phone_verification_service;
+pub mod push_actions_service;
 pub mod push_rule_evaluator;
+pub mod push_rule_service;
 pub mod push_service;
 pub mod read_receipt_service;
 pub mod redis_integration_service;
@@ -478,14 +795,18 @@ pub mod redis_replication_service;
 pub mod registration_service;
 pub mod registration_token_service;
 pub mod retention_service;
+pub mod room_search_service;
 pub mod room_service;
 pub mod saml_service;
 pub mod search_service;
+pub mod sliding_sync_service;
+pub mod state_resolution_service;
 pub mod server_notification_service;
 pub mod stream_writer_service;
 pub mod sync_service;
 pub mod thread_service;
 pub mod thumbnail_service;
+pub mod ui_auth_service;
 pub mod url_preview_service;
 pub mod voice_service;
 pub mod voip_service;
@@ -502,14 +823,18 @@ pub use event_persister_service::*;
 pub use federation_sender_service::*;
 pub use friend_room_service::*;
 pub use guest_access_service::*;
+pub use identity_service::*;
 pub use jwt_service::*;
 pub use knock_service::*;
 pub use manhole_service::*;
 pub use media_service::*;
 pub use moderation_service::*;
+pub use notifier::*;
 pub use oidc_service::*;
 pub use phone_verification_service::*;
+pub use push_actions_service::*;
 pub use push_rule_evaluator::*;
+pub use push_rule_service::*;
 pub use push_service::*;
 pub use read_receipt_service::*;
 pub use redis_integration_service::*;
@@ -517,14 +842,18 @@ pub use redis_replication_service::*;
 pub use registration_service::*;
 pub use registration_token_service::*;
 pub use retention_service::*;
+pub use room_search_service::*;
 pub use room_service::*;
 pub use saml_service::*;
 pub use search_service::*;
 pub use server_notification_service::*;
+pub use sliding_sync_service::*;
+pub use state_resolution_service::*;
 pub use stream_writer_service::*;
 pub use sync_service::*;
 pub use thread_service::*;
 pub use thumbnail_service::*;
+pub use ui_auth_service::*;
 pub use url_preview_service::*;
 pub use voice_service::*;
 pub use voip_service::*;
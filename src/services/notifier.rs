@@ -0,0 +1,537 @@
+use crate::services::redis_replication_service::{
+    RedisReplicationService, ReplicationChannel, ReplicationMessage,
+};
+use lru::LruCache;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::num::NonZeroUsize;
+use std::sync::atomic::{AtomicI64, Ordering};
+use std::sync::Arc;
+use tokio::sync::{broadcast, Notify};
+use tokio::time::Instant;
+
+/// A stream of updates, matching a section of the /sync response.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum NotifierStream {
+    Events,
+    Receipts,
+    Typing,
+    ToDevice,
+    AccountData,
+    Presence,
+    DeviceLists,
+}
+
+impl NotifierStream {
+    pub fn replication_channel(&self) -> ReplicationChannel {
+        match self {
+            NotifierStream::Events => ReplicationChannel::Events,
+            NotifierStream::Receipts => ReplicationChannel::Receipts,
+            NotifierStream::Typing => ReplicationChannel::Typing,
+            NotifierStream::ToDevice => ReplicationChannel::ToDevice,
+            NotifierStream::AccountData => ReplicationChannel::AccountData,
+            NotifierStream::Presence => ReplicationChannel::Presence,
+            NotifierStream::DeviceLists => ReplicationChannel::DeviceLists,
+        }
+    }
+}
+
+/// A wake-up forwarded between workers over the replication channel.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct NotifierPayload {
+    pub stream: NotifierStream,
+    pub position: i64,
+    #[serde(default)]
+    pub room_id: Option<String>,
+    #[serde(default)]
+    pub user_ids: Vec<String>,
+}
+
+struct UserStream {
+    position: AtomicI64,
+    notify: Notify,
+}
+
+/// How many rooms have their latest notified position remembered.
+const ROOM_POSITIONS_CAPACITY: usize = 10_000;
+
+struct Listeners {
+    users: HashMap<String, (Arc<UserStream>, usize)>,
+    rooms: HashMap<String, HashMap<String, usize>>,
+    room_positions: LruCache<(NotifierStream, String), i64>,
+    /// The highest position evicted from `room_positions`; rooms without an entry may have
+    /// changed up to this position.
+    evicted_position: i64,
+}
+
+impl Default for Listeners {
+    fn default() -> Self {
+        Self {
+            users: HashMap::new(),
+            rooms: HashMap::new(),
+            room_positions: LruCache::new(NonZeroUsize::new(ROOM_POSITIONS_CAPACITY).unwrap()),
+            evicted_position: 0,
+        }
+    }
+}
+
+/// Wakes up long-polling /sync requests.
+///
+/// Each user with a /sync in progress has a stream to wait on. Writers of room events,
+/// receipts, typing, to-device messages and account data call `notify_*` to wake the users
+/// concerned. Positions are on the same millisecond timeline as a `SyncToken`'s `stream_id`.
+#[derive(Clone)]
+pub struct Notifier {
+    listeners: Arc<parking_lot::Mutex<Listeners>>,
+    current_position: Arc<AtomicI64>,
+    replication: Option<Arc<RedisReplicationService>>,
+}
+
+impl Default for Notifier {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Notifier {
+    pub fn new() -> Self {
+        Self {
+            listeners: Arc::new(parking_lot::Mutex::new(Listeners::default())),
+            current_position: Arc::new(AtomicI64::new(chrono::Utc::now().timestamp_millis())),
+            replication: None,
+        }
+    }
+
+    pub fn with_replication(mut self, replication: Arc<RedisReplicationService>) -> Self {
+        self.replication = Some(replication);
+        self
+    }
+
+    /// The current position, never behind the clock, which can be encoded as `next_batch`.
+    pub fn current_position(&self) -> i64 {
+        self.current_position
+            .load(Ordering::SeqCst)
+            .max(chrono::Utc::now().timestamp_millis())
+    }
+
+    fn advance(&self) -> i64 {
+        let now = chrono::Utc::now().timestamp_millis();
+        let previous = self
+            .current_position
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |current| {
+                Some(now.max(current + 1))
+            })
+            .unwrap_or(now);
+        now.max(previous + 1)
+    }
+
+    /// Registers a long-poll listener that is woken for the user and their rooms until dropped.
+    pub fn listen(&self, user_id: &str, room_ids: &[String]) -> NotifierListener {
+        let mut listeners = self.listeners.lock();
+        let stream = {
+            let entry = listeners.users.entry(user_id.to_string()).or_insert_with(|| {
+                (
+                    Arc::new(UserStream {
+                        position: AtomicI64::new(0),
+                        notify: Notify::new(),
+                    }),
+                    0,
+                )
+            });
+            entry.1 += 1;
+            entry.0.clone()
+        };
+        for room_id in room_ids {
+            *listeners
+                .rooms
+                .entry(room_id.clone())
+                .or_default()
+                .entry(user_id.to_string())
+                .or_insert(0) += 1;
+        }
+
+        NotifierListener {
+            listeners: self.listeners.clone(),
+            user_id: user_id.to_string(),
+            room_ids: room_ids.to_vec(),
+            stream,
+        }
+    }
+
+    /// New data in a room: wakes the users listening on it, plus `user_ids`, such as the
+    /// target of a membership event who is not listening on the room yet.
+    pub async fn notify_room(&self, stream: NotifierStream, room_id: &str, user_ids: &[String]) {
+        let payload = NotifierPayload {
+            stream,
+            position: self.advance(),
+            room_id: Some(room_id.to_string()),
+            user_ids: user_ids.to_vec(),
+        };
+        self.wake(&payload);
+        self.publish(payload).await;
+    }
+
+    /// New data for the given users only, such as to-device messages and account data.
+    pub async fn notify_users(&self, stream: NotifierStream, user_ids: &[String]) {
+        if user_ids.is_empty() {
+            return;
+        }
+        let payload = NotifierPayload {
+            stream,
+            position: self.advance(),
+            room_id: None,
+            user_ids: user_ids.to_vec(),
+        };
+        self.wake(&payload);
+        self.publish(payload).await;
+    }
+
+    fn wake(&self, payload: &NotifierPayload) {
+        self.current_position
+            .fetch_max(payload.position, Ordering::SeqCst);
+
+        let mut listeners = self.listeners.lock();
+        if let Some(room_id) = &payload.room_id {
+            let key = (payload.stream, room_id.clone());
+            let position = listeners
+                .room_positions
+                .peek(&key)
+                .map_or(payload.position, |&position| position.max(payload.position));
+            if let Some((evicted_key, evicted)) = listeners.room_positions.push(key.clone(), position) {
+                if evicted_key != key {
+                    listeners.evicted_position = listeners.evicted_position.max(evicted);
+                }
+            }
+        }
+        let room_users = payload
+            .room_id
+            .as_ref()
+            .and_then(|room_id| listeners.rooms.get(room_id))
+            .into_iter()
+            .flat_map(|users| users.keys());
+
+        for user_id in room_users.chain(payload.user_ids.iter()) {
+            if let Some((stream, _)) = listeners.users.get(user_id) {
+                stream.position.fetch_max(payload.position, Ordering::SeqCst);
+                stream.notify.notify_waiters();
+            }
+        }
+    }
+
+    /// The position of the room's latest notification on `stream`, telling whether ephemeral
+    /// data such as typing changed. Rooms no longer remembered report the highest evicted
+    /// position, so a change may be reported twice but never missed.
+    pub fn room_position(&self, stream: NotifierStream, room_id: &str) -> Option<i64> {
+        let listeners = self.listeners.lock();
+        listeners
+            .room_positions
+            .peek(&(stream, room_id.to_string()))
+            .copied()
+            .or((listeners.evicted_position > 0).then_some(listeners.evicted_position))
+    }
+
+    async fn publish(&self, payload: NotifierPayload) {
+        let Some(replication) = &self.replication else {
+            return;
+        };
+        let channel = payload.stream.replication_channel();
+        match serde_json::to_value(&payload) {
+            Ok(data) => {
+                if let Err(e) = replication.publish(channel, data).await {
+                    ::tracing::warn!("Failed to replicate {:?} notification: {}", payload.stream, e);
+                }
+            }
+            Err(e) => ::tracing::warn!("Failed to serialize notification: {}", e),
+        }
+    }
+
+    /// Handles a replication message from another worker; our own messages are ignored.
+    pub fn handle_replication_message(&self, message: &ReplicationMessage) {
+        let Some(replication) = &self.replication else {
+            return;
+        };
+        if message.instance_name == replication.instance_name() {
+            return;
+        }
+        if let Ok(payload) = serde_json::from_value::<NotifierPayload>(message.data.clone()) {
+            self.wake(&payload);
+        }
+    }
+
+    /// Subscribes to the replication channel, turning writes on other workers into local wake-ups.
+    pub fn start_replication_listener(&self) -> Option<tokio::task::JoinHandle<()>> {
+        let replication = self.replication.as_ref().filter(|r| r.is_enabled())?;
+        let mut receiver = replication.subscribe();
+        let notifier = self.clone();
+
+        Some(tokio::spawn(async move {
+            loop {
+                match receiver.recv().await {
+                    Ok(message) => notifier.handle_replication_message(&message),
+                    Err(broadcast::error::RecvError::Lagged(skipped)) => {
+                        ::tracing::warn!("Notifier lagged behind replication by {} messages", skipped);
+                    }
+                    Err(broadcast::error::RecvError::Closed) => break,
+                }
+            }
+        }))
+    }
+}
+
+/// The listener of one /sync request, unregistered when dropped.
+pub struct NotifierListener {
+    listeners: Arc<parking_lot::Mutex<Listeners>>,
+    user_id: String,
+    room_ids: Vec<String>,
+    stream: Arc<UserStream>,
+}
+
+impl NotifierListener {
+    /// The position the user was last woken at.
+    pub fn position(&self) -> i64 {
+        self.stream.position.load(Ordering::SeqCst)
+    }
+
+    /// Waits until the position passes `from` or `deadline` is reached. Returns true if woken,
+    /// false on timeout.
+    pub async fn wait_for_change(&self, from: i64, deadline: Instant) -> bool {
+        loop {
+            let notified = self.stream.notify.notified();
+            tokio::pin!(notified);
+            notified.as_mut().enable();
+
+            if self.position() > from {
+                return true;
+            }
+            if tokio::time::timeout_at(deadline, notified).await.is_err() {
+                return self.position() > from;
+            }
+        }
+    }
+}
+
+impl Drop for NotifierListener {
+    fn drop(&mut self) {
+        let mut listeners = self.listeners.lock();
+        for room_id in &self.room_ids {
+            if let Some(users) = listeners.rooms.get_mut(room_id) {
+                if let Some(count) = users.get_mut(&self.user_id) {
+                    *count -= 1;
+                    if *count == 0 {
+                        users.remove(&self.user_id);
+                    }
+                }
+                if users.is_empty() {
+                    listeners.rooms.remove(room_id);
+                }
+            }
+        }
+        if let Some(entry) = listeners.users.get_mut(&self.user_id) {
+            entry.1 -= 1;
+            if entry.1 == 0 {
+                listeners.users.remove(&self.user_id);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::services::redis_replication_service::RedisReplicationConfig;
+    use std::time::Duration;
+
+    fn deadline(ms: u64) -> Instant {
+        Instant::now() + Duration::from_millis(ms)
+    }
+
+    #[tokio::test]
+    async fn test_room_event_wakes_listener() {
+        let notifier = Notifier::new();
+        let listener = notifier.listen("@alice:localhost", &["!room:localhost".to_string()]);
+        let from = listener.position();
+
+        let waiter = notifier.clone();
+        tokio::spawn(async move {
+            tokio::time::sleep(Duration::from_millis(20)).await;
+            waiter
+                .notify_room(NotifierStream::Events, "!room:localhost", &[])
+                .await;
+        });
+
+        assert!(listener.wait_for_change(from, deadline(2000)).await);
+        assert!(listener.position() > from);
+    }
+
+    #[tokio::test]
+    async fn test_unrelated_room_does_not_wake() {
+        let notifier = Notifier::new();
+        let listener = notifier.listen("@alice:localhost", &["!room:localhost".to_string()]);
+        let from = listener.position();
+
+        notifier
+            .notify_room(NotifierStream::Events, "!other:localhost", &[])
+            .await;
+
+        assert!(!listener.wait_for_change(from, deadline(50)).await);
+    }
+
+    #[tokio::test]
+    async fn test_notify_users_wakes_listener() {
+        let notifier = Notifier::new();
+        let listener = notifier.listen("@bob:localhost", &[]);
+        let from = listener.position();
+
+        notifier
+            .notify_users(NotifierStream::ToDevice, &["@bob:localhost".to_string()])
+            .await;
+
+        assert!(listener.wait_for_change(from, deadline(50)).await);
+    }
+
+    #[tokio::test]
+    async fn test_member_event_wakes_target_user() {
+        let notifier = Notifier::new();
+        let listener = notifier.listen("@bob:localhost", &[]);
+        let from = listener.position();
+
+        notifier
+            .notify_room(
+                NotifierStream::Events,
+                "!room:localhost",
+                &["@bob:localhost".to_string()],
+            )
+            .await;
+
+        assert!(listener.wait_for_change(from, deadline(50)).await);
+    }
+
+    #[tokio::test]
+    async fn test_listener_unregisters_on_drop() {
+        let notifier = Notifier::new();
+        {
+            let _listener = notifier.listen("@alice:localhost", &["!room:localhost".to_string()]);
+            assert_eq!(notifier.listeners.lock().users.len(), 1);
+            assert_eq!(notifier.listeners.lock().rooms.len(), 1);
+        }
+        assert!(notifier.listeners.lock().users.is_empty());
+        assert!(notifier.listeners.lock().rooms.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_room_position_tracks_latest_notification() {
+        let notifier = Notifier::new();
+        assert!(notifier
+            .room_position(NotifierStream::Typing, "!room:localhost")
+            .is_none());
+
+        notifier
+            .notify_room(NotifierStream::Typing, "!room:localhost", &[])
+            .await;
+        let first = notifier
+            .room_position(NotifierStream::Typing, "!room:localhost")
+            .unwrap();
+        notifier
+            .notify_room(NotifierStream::Typing, "!room:localhost", &[])
+            .await;
+        let second = notifier
+            .room_position(NotifierStream::Typing, "!room:localhost")
+            .unwrap();
+
+        assert!(second > first);
+        assert!(notifier
+            .room_position(NotifierStream::Events, "!room:localhost")
+            .is_none());
+    }
+
+    #[tokio::test]
+    async fn test_room_positions_are_bounded() {
+        let notifier = Notifier::new();
+        for i in 0..=ROOM_POSITIONS_CAPACITY {
+            notifier
+                .notify_room(NotifierStream::Typing, &format!("!room{}:localhost", i), &[])
+                .await;
+        }
+        assert_eq!(notifier.listeners.lock().room_positions.len(), ROOM_POSITIONS_CAPACITY);
+
+        // The evicted room may have changed as late as its last notification
+        let evicted = notifier
+            .room_position(NotifierStream::Typing, "!room0:localhost")
+            .unwrap();
+        let latest = notifier
+            .room_position(NotifierStream::Typing, "!room1:localhost")
+            .unwrap();
+        assert!(evicted > 0);
+        assert!(latest > evicted);
+    }
+
+    #[test]
+    fn test_positions_are_monotonic() {
+        let notifier = Notifier::new();
+        let first = notifier.advance();
+        let second = notifier.advance();
+        assert!(second > first);
+        assert!(notifier.current_position() >= second);
+    }
+
+    #[tokio::test]
+    async fn test_replicated_notification_wakes_listener() {
+        let config = RedisReplicationConfig {
+            enabled: true,
+            ..Default::default()
+        };
+        let local = Notifier::new().with_replication(Arc::new(RedisReplicationService::new(
+            config,
+            "worker1".to_string(),
+        )));
+        let listener = local.listen("@alice:localhost", &["!room:localhost".to_string()]);
+        let from = listener.position();
+
+        let payload = NotifierPayload {
+            stream: NotifierStream::Receipts,
+            position: local.current_position() + 1,
+            room_id: Some("!room:localhost".to_string()),
+            user_ids: vec![],
+        };
+        let message = ReplicationMessage::new(
+            ReplicationChannel::Receipts,
+            "worker2".to_string(),
+            serde_json::to_value(&payload).unwrap(),
+            1,
+        );
+        local.handle_replication_message(&message);
+
+        assert!(listener.wait_for_change(from, deadline(50)).await);
+    }
+
+    #[tokio::test]
+    async fn test_own_replicated_notification_is_ignored() {
+        let config = RedisReplicationConfig {
+            enabled: true,
+            ..Default::default()
+        };
+        let local = Notifier::new().with_replication(Arc::new(RedisReplicationService::new(
+            config,
+            "worker1".to_string(),
+        )));
+        let listener = local.listen("@alice:localhost", &[]);
+        let from = listener.position();
+
+        let payload = NotifierPayload {
+            stream: NotifierStream::AccountData,
+            position: local.current_position() + 1,
+            room_id: None,
+            user_ids: vec!["@alice:localhost".to_string()],
+        };
+        let message = ReplicationMessage::new(
+            ReplicationChannel::AccountData,
+            "worker1".to_string(),
+            serde_json::to_value(&payload).unwrap(),
+            1,
+        );
+        local.handle_replication_message(&message);
+
+        assert!(!listener.wait_for_change(from, deadline(50)).await);
+    }
+}
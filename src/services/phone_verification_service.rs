@@ -113,10 +113,19 @@ pub struct PhoneVerificationResult {
     pub expires_in_seconds: Option<u64>,
 }
 
+/// A client's `requestToken` session: the `sid` handed out maps to the number being
+/// verified and the `client_secret` that must accompany it.
+#[derive(Debug, Clone)]
+struct PhoneSession {
+    client_secret: String,
+    phone_number: String,
+}
+
 pub struct PhoneVerificationService {
     verifications: Arc<RwLock<HashMap<String, PhoneVerification>>>,
     config: PhoneConfig,
     last_sent: Arc<RwLock<HashMap<String, i64>>>,
+    sessions: Arc<RwLock<HashMap<String, PhoneSession>>>,
 }
 
 impl PhoneVerificationService {
@@ -125,9 +134,62 @@ impl PhoneVerificationService {
             verifications: Arc::new(RwLock::new(HashMap::new())),
             config,
             last_sent: Arc::new(RwLock::new(HashMap::new())),
+            sessions: Arc::new(RwLock::new(HashMap::new())),
         }
     }
 
+    /// Sends a code to `phone_number` and opens a session for it, returning the `sid`.
+    pub async fn request_session(
+        &self,
+        phone_number: &str,
+        client_secret: &str,
+    ) -> Result<String, PhoneError> {
+        self.send_verification_code(phone_number).await?;
+
+        let sid = crate::common::crypto::generate_token(24);
+        self.sessions.write().await.insert(
+            sid.clone(),
+            PhoneSession {
+                client_secret: client_secret.to_string(),
+                phone_number: Self::normalize_phone(phone_number)?,
+            },
+        );
+        Ok(sid)
+    }
+
+    /// Checks the code the user received for the session `sid`.
+    pub async fn submit_session_code(
+        &self,
+        sid: &str,
+        client_secret: &str,
+        code: &str,
+    ) -> Result<bool, PhoneError> {
+        let phone_number = self
+            .session_phone(sid, client_secret)
+            .await
+            .ok_or(PhoneError::VerificationNotFound)?;
+        self.verify_code(&phone_number, code).await
+    }
+
+    /// The number behind `sid` if the user has verified it.
+    pub async fn validated_phone(&self, sid: &str, client_secret: &str) -> Option<String> {
+        let phone_number = self.session_phone(sid, client_secret).await?;
+        if self.is_verified(&phone_number).await {
+            Some(phone_number)
+        } else {
+            None
+        }
+    }
+
+    async fn session_phone(&self, sid: &str, client_secret: &str) -> Option<String> {
+        self.sessions
+            .read()
+            .await
+            .get(sid)
+            .filter(|session| session.client_secret == client_secret)
+            .map(|session| session.phone_number.clone())
+    }
+
     pub async fn send_verification_code(
         &self,
         phone_number: &str,
@@ -262,6 +324,10 @@ impl PhoneVerificationService {
         let before = verifications.len();
         
         verifications.retain(|_, v| !v.is_expired() || v.verified);
+        self.sessions
+            .write()
+            .await
+            .retain(|_, session| verifications.contains_key(&session.phone_number));
         
         before - verifications.len()
     }
@@ -370,4 +436,25 @@ mod tests {
 
         assert!(service.is_verified("+1234567890").await);
     }
+
+    #[tokio::test]
+    async fn test_session_requires_client_secret_and_code() {
+        let service = PhoneVerificationService::default();
+
+        let sid = service.request_session("+1234567890", "secret").await.unwrap();
+        assert!(service.validated_phone(&sid, "secret").await.is_none());
+
+        let code = service.verifications.read().await.get("+1234567890").unwrap().code.clone();
+        assert!(matches!(
+            service.submit_session_code(&sid, "other", &code).await,
+            Err(PhoneError::VerificationNotFound)
+        ));
+        assert!(service.submit_session_code(&sid, "secret", &code).await.unwrap());
+
+        assert_eq!(
+            service.validated_phone(&sid, "secret").await.as_deref(),
+            Some("+1234567890")
+        );
+        assert!(service.validated_phone(&sid, "other").await.is_none());
+    }
 }
@@ -0,0 +1,464 @@
+//! Push rule evaluation of new events. Each joined local user's rules are run against an event
+//! as it is persisted, the events that notify someone are stored with their actions, and
+//! /sync counts them as unread until the user's read receipt passes them.
+
+use crate::common::error::ApiError;
+use crate::common::ApiResult;
+use crate::federation::event_auth::user_power_level;
+use crate::federation::pdu;
+use crate::services::push_rule_evaluator::{ActionKind, EventContext, PushRuleEvaluator};
+use crate::services::push_rule_service::PushRuleService;
+use crate::services::push_service::{NotificationCounts, PushNotification, PushService};
+use crate::storage::event::{EventStorage, RoomEvent};
+use crate::storage::event_push_actions::{
+    EventPushActionsStorage, NotificationRecord, ThreadUnreadCounts, UserPushActions, MAIN_THREAD_ID,
+};
+use crate::storage::membership::{RoomMember, RoomMemberStorage};
+use serde_json::{json, Value};
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::task::JoinHandle;
+
+/// How often push actions past their retention are deleted.
+const PUSH_ACTIONS_CLEANUP_INTERVAL: Duration = Duration::from_secs(3600);
+
+/// A user's unread counts in one room, per thread.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct RoomUnreadCounts {
+    pub threads: Vec<ThreadUnreadCounts>,
+}
+
+impl RoomUnreadCounts {
+    /// `(highlight_count, notification_count)` over the whole room, threads included.
+    pub fn total(&self) -> (i64, i64) {
+        self.threads.iter().fold((0, 0), |(highlights, notifications), thread| {
+            (highlights + thread.highlight_count, notifications + thread.notification_count)
+        })
+    }
+
+    /// `(highlight_count, notification_count)` of the main timeline only, for clients that
+    /// get thread counts separately.
+    pub fn main_timeline(&self) -> (i64, i64) {
+        self.threads
+            .iter()
+            .find(|thread| thread.thread_id == MAIN_THREAD_ID)
+            .map_or((0, 0), |thread| (thread.highlight_count, thread.notification_count))
+    }
+
+    /// The `unread_thread_notifications` of a joined room in /sync, keyed by thread root.
+    pub fn thread_notifications(&self) -> Value {
+        let threads: serde_json::Map<String, Value> = self
+            .threads
+            .iter()
+            .filter(|thread| thread.thread_id != MAIN_THREAD_ID)
+            .map(|thread| {
+                (
+                    thread.thread_id.clone(),
+                    json!({
+                        "highlight_count": thread.highlight_count,
+                        "notification_count": thread.notification_count
+                    }),
+                )
+            })
+            .collect();
+        Value::Object(threads)
+    }
+}
+
+/// One page of `/notifications`, newest first.
+#[derive(Debug, Clone, Default)]
+pub struct NotificationsPage {
+    pub notifications: Vec<Value>,
+    /// Where the next page starts; `None` on the last page.
+    pub next_token: Option<String>,
+}
+
+pub struct PushActionsService {
+    storage: EventPushActionsStorage,
+    member_storage: RoomMemberStorage,
+    event_storage: EventStorage,
+    push_rule_service: Arc<PushRuleService>,
+    push_service: Option<Arc<PushService>>,
+    server_name: String,
+}
+
+impl PushActionsService {
+    pub fn new(
+        storage: EventPushActionsStorage,
+        member_storage: RoomMemberStorage,
+        event_storage: EventStorage,
+        push_rule_service: Arc<PushRuleService>,
+        server_name: &str,
+    ) -> Self {
+        Self {
+            storage,
+            member_storage,
+            event_storage,
+            push_rule_service,
+            push_service: None,
+            server_name: server_name.to_string(),
+        }
+    }
+
+    /// Sends the notifications to the users' pushers as well.
+    pub fn with_push_service(mut self, push_service: Arc<PushService>) -> Self {
+        self.push_service = Some(push_service);
+        self
+    }
+
+    /// Evaluates a newly persisted event against the rules of every joined local user other
+    /// than its sender, stores the actions of those it notifies and pushes the event to them.
+    pub async fn handle_new_event(&self, event: &RoomEvent) -> ApiResult<()> {
+        let members = self
+            .member_storage
+            .get_joined_members(&event.room_id)
+            .await
+            .map_err(|e| ApiError::internal(format!("Failed to get room members: {}", e)))?;
+        let recipients: Vec<_> = members
+            .iter()
+            .filter(|member| member.user_id != event.user_id)
+            .filter(|member| pdu::server_name_of(&member.user_id) == Some(self.server_name.as_str()))
+            .collect();
+        if recipients.is_empty() {
+            return Ok(());
+        }
+
+        let mut ctx = EventContext {
+            event_type: event.event_type.clone(),
+            sender: event.user_id.clone(),
+            room_id: event.room_id.clone(),
+            content: event.content.clone(),
+            state_key: event.state_key.clone(),
+            display_name: None,
+            room_member_count: members.len(),
+            user_power_level: self.sender_power_level(&event.room_id, &event.user_id).await?,
+        };
+
+        let mut actions = Vec::new();
+        for member in recipients {
+            let rules = self.push_rule_service.get_rules_for_evaluation(&member.user_id).await?;
+            ctx.display_name = member.display_name.clone();
+            let evaluator = PushRuleEvaluator::new(rules);
+            if let Some(user_actions) = evaluator
+                .evaluate_actions(&ctx)
+                .and_then(|matched| user_push_actions(&member.user_id, matched))
+            {
+                actions.push(user_actions);
+            }
+        }
+        if actions.is_empty() {
+            return Ok(());
+        }
+
+        self.storage
+            .add_push_actions(
+                &event.room_id,
+                &event.event_id,
+                &thread_id_of(&event.content),
+                event.processed_ts,
+                &actions,
+            )
+            .await
+            .map_err(|e| ApiError::internal(format!("Failed to store push actions: {}", e)))?;
+
+        if let Some(push_service) = self.push_service.as_ref().filter(|p| p.delivers_notifications()) {
+            self.push_event(push_service, event, &members, &actions).await?;
+        }
+        Ok(())
+    }
+
+    async fn push_event(
+        &self,
+        push_service: &PushService,
+        event: &RoomEvent,
+        members: &[RoomMember],
+        actions: &[UserPushActions],
+    ) -> ApiResult<()> {
+        let sender_display_name = members
+            .iter()
+            .find(|member| member.user_id == event.user_id)
+            .and_then(|member| member.display_name.clone());
+        let room_name = self
+            .event_storage
+            .get_state_event(&event.room_id, "m.room.name", "")
+            .await
+            .map_err(|e| ApiError::internal(format!("Failed to get room state: {}", e)))?
+            .and_then(|name| name.content["name"].as_str().map(str::to_string));
+
+        for user_actions in actions {
+            let (notifications, rooms) = self
+                .storage
+                .get_badge_counts(&user_actions.user_id)
+                .await
+                .map_err(|e| ApiError::internal(format!("Failed to get unread counts: {}", e)))?;
+            let unread = if push_service.groups_unread_count_by_room() { rooms } else { notifications };
+            let notification = PushNotification {
+                event_id: event.event_id.clone(),
+                room_id: event.room_id.clone(),
+                event_type: event.event_type.clone(),
+                sender: event.user_id.clone(),
+                content: event.content.clone(),
+                counts: NotificationCounts {
+                    unread: u32::try_from(unread).unwrap_or(u32::MAX),
+                    missed_calls: 0,
+                },
+                sender_display_name: sender_display_name.clone(),
+                room_name: room_name.clone(),
+                tweaks: tweaks_of(&user_actions.actions),
+            };
+            if let Err(e) = push_service.notify_user(&user_actions.user_id, &notification).await {
+                tracing::warn!(user_id = %user_actions.user_id, "Failed to queue push: {}", e);
+            }
+        }
+        Ok(())
+    }
+
+    /// Records a read receipt, clearing the counts of the events up to `event_id` in the
+    /// receipt's thread, or in the whole room when it is unthreaded.
+    pub async fn mark_read(
+        &self,
+        user_id: &str,
+        room_id: &str,
+        thread_id: Option<&str>,
+        event_id: &str,
+    ) -> ApiResult<bool> {
+        self.storage
+            .set_receipt(user_id, room_id, thread_id, event_id)
+            .await
+            .map_err(|e| ApiError::internal(format!("Failed to store read position: {}", e)))
+    }
+
+    pub async fn get_unread_counts(&self, user_id: &str, room_id: &str) -> ApiResult<RoomUnreadCounts> {
+        let threads = self
+            .storage
+            .get_unread_counts(user_id, room_id)
+            .await
+            .map_err(|e| ApiError::internal(format!("Failed to get unread counts: {}", e)))?;
+        Ok(RoomUnreadCounts { threads })
+    }
+
+    /// The user's past notifications, starting after the `from` token of a previous page.
+    pub async fn get_notifications(
+        &self,
+        user_id: &str,
+        from: Option<&str>,
+        limit: i64,
+        only_highlight: bool,
+    ) -> ApiResult<NotificationsPage> {
+        let before = from
+            .map(|token| {
+                parse_notifications_token(token).ok_or_else(|| ApiError::bad_request("Invalid from token"))
+            })
+            .transpose()?;
+        let records = self
+            .storage
+            .get_notifications(
+                user_id,
+                before.as_ref().map(|(ordering, event_id)| (*ordering, event_id.as_str())),
+                limit,
+                only_highlight,
+            )
+            .await
+            .map_err(|e| ApiError::internal(format!("Failed to get notifications: {}", e)))?;
+
+        let event_ids: Vec<String> = records.iter().map(|record| record.event_id.clone()).collect();
+        let events: HashMap<String, RoomEvent> = self
+            .event_storage
+            .get_events_by_ids(&event_ids)
+            .await
+            .map_err(|e| ApiError::internal(format!("Failed to get events: {}", e)))?
+            .into_iter()
+            .map(|event| (event.event_id.clone(), event))
+            .collect();
+
+        let next_token = match records.last() {
+            Some(last) if records.len() as i64 >= limit => Some(notifications_token(last)),
+            _ => None,
+        };
+        let notifications = records
+            .iter()
+            .filter_map(|record| {
+                let event = events.get(&record.event_id)?;
+                Some(json!({
+                    "actions": record.actions,
+                    "event": event.to_client_event(),
+                    "read": record.read,
+                    "room_id": record.room_id,
+                    "ts": record.stream_ordering
+                }))
+            })
+            .collect();
+        Ok(NotificationsPage { notifications, next_token })
+    }
+
+    /// Deletes push actions once they are older than `retention`, hourly. Unread counts and
+    /// `/notifications` stop covering such events. Does nothing for a zero retention.
+    pub fn start_cleanup(self: &Arc<Self>, retention: Duration) -> Option<JoinHandle<()>> {
+        if retention.is_zero() {
+            return None;
+        }
+        let service = self.clone();
+        Some(tokio::spawn(async move {
+            let mut interval = tokio::time::interval(PUSH_ACTIONS_CLEANUP_INTERVAL);
+            interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Skip);
+            loop {
+                interval.tick().await;
+                let before = chrono::Utc::now().timestamp_millis() - retention.as_millis() as i64;
+                match service.storage.delete_push_actions_before(before).await {
+                    Ok(0) => {}
+                    Ok(deleted) => tracing::info!(deleted, "Deleted expired push actions"),
+                    Err(e) => tracing::warn!("Failed to delete expired push actions: {}", e),
+                }
+            }
+        }))
+    }
+
+    async fn sender_power_level(&self, room_id: &str, sender: &str) -> ApiResult<i64> {
+        let state_event = |event_type: &'static str| async move {
+            self.event_storage
+                .get_state_event(room_id, event_type, "")
+                .await
+                .map_err(|e| ApiError::internal(format!("Failed to get room state: {}", e)))
+        };
+        let power_levels = state_event("m.room.power_levels")
+            .await?
+            .map(|event| json!({ "content": event.content }));
+        let create = state_event("m.room.create")
+            .await?
+            .map(|event| json!({ "sender": event.sender, "content": event.content }))
+            .unwrap_or_default();
+        Ok(user_power_level(sender, power_levels.as_ref(), &create))
+    }
+}
+
+/// The actions to store for a user, if the matching rule notifies them.
+fn user_push_actions(user_id: &str, actions: &[ActionKind]) -> Option<UserPushActions> {
+    if !actions.iter().any(|action| matches!(action, ActionKind::Notify)) {
+        return None;
+    }
+    Some(UserPushActions {
+        user_id: user_id.to_string(),
+        actions: Value::Array(actions.iter().map(ActionKind::to_json).collect()),
+        notif: true,
+        highlight: actions.iter().any(ActionKind::is_highlight),
+    })
+}
+
+/// The position after `record`, as a `/notifications` pagination token.
+fn notifications_token(record: &NotificationRecord) -> String {
+    format!("{}_{}", record.stream_ordering, record.event_id)
+}
+
+fn parse_notifications_token(token: &str) -> Option<(i64, String)> {
+    let (ordering, event_id) = token.split_once('_')?;
+    Some((ordering.parse().ok()?, event_id.to_string()))
+}
+
+/// The tweaks among stored actions. A `highlight` tweak without a value means `true`.
+fn tweaks_of(actions: &Value) -> serde_json::Map<String, Value> {
+    actions
+        .as_array()
+        .into_iter()
+        .flatten()
+        .filter_map(|action| {
+            let name = action["set_tweak"].as_str()?;
+            let value = match action.get("value") {
+                Some(value) if !value.is_null() => value.clone(),
+                _ if name == "highlight" => Value::Bool(true),
+                _ => return None,
+            };
+            Some((name.to_string(), value))
+        })
+        .collect()
+}
+
+/// The thread an event belongs to: its root for threaded replies, `main` for everything else,
+/// thread roots included.
+fn thread_id_of(content: &Value) -> String {
+    let relation = &content["m.relates_to"];
+    match (relation["rel_type"].as_str(), relation["event_id"].as_str()) {
+        (Some("m.thread"), Some(root)) => root.to_string(),
+        _ => MAIN_THREAD_ID.to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn thread(thread_id: &str, highlight_count: i64, notification_count: i64) -> ThreadUnreadCounts {
+        ThreadUnreadCounts {
+            thread_id: thread_id.to_string(),
+            notification_count,
+            highlight_count,
+        }
+    }
+
+    #[test]
+    fn test_only_notifying_rules_store_actions() {
+        assert!(user_push_actions("@bob:localhost", &[ActionKind::DontNotify]).is_none());
+
+        let highlight = ActionKind::SetTweak { set_tweak: "highlight".to_string(), value: Value::Null };
+        let stored = user_push_actions("@bob:localhost", &[ActionKind::Notify, highlight]).unwrap();
+        assert!(stored.notif);
+        assert!(stored.highlight);
+        assert_eq!(stored.actions, json!(["notify", { "set_tweak": "highlight" }]));
+
+        let no_highlight = ActionKind::SetTweak { set_tweak: "highlight".to_string(), value: json!(false) };
+        assert!(!user_push_actions("@bob:localhost", &[ActionKind::Notify, no_highlight]).unwrap().highlight);
+    }
+
+    #[test]
+    fn test_tweaks_of_stored_actions() {
+        let tweaks = tweaks_of(&json!(["notify", { "set_tweak": "sound", "value": "default" }, { "set_tweak": "highlight" }]));
+        assert_eq!(tweaks["sound"], "default");
+        assert_eq!(tweaks["highlight"], true);
+        assert!(tweaks_of(&json!(["notify"])).is_empty());
+    }
+
+    #[test]
+    fn test_notifications_token_round_trip() {
+        let record = NotificationRecord {
+            event_id: "$abc_def:localhost".to_string(),
+            room_id: "!room:localhost".to_string(),
+            stream_ordering: 1700000000000,
+            actions: json!(["notify"]),
+            highlight: false,
+            read: false,
+        };
+        assert_eq!(
+            parse_notifications_token(&notifications_token(&record)),
+            Some((1700000000000, "$abc_def:localhost".to_string()))
+        );
+        assert_eq!(parse_notifications_token("garbage"), None);
+        assert_eq!(parse_notifications_token("x_$event"), None);
+    }
+
+    #[test]
+    fn test_thread_of_threaded_reply() {
+        let reply = json!({
+            "body": "in thread",
+            "m.relates_to": { "rel_type": "m.thread", "event_id": "$root" }
+        });
+        assert_eq!(thread_id_of(&reply), "$root");
+
+        let annotation = json!({ "m.relates_to": { "rel_type": "m.annotation", "event_id": "$root" } });
+        assert_eq!(thread_id_of(&annotation), MAIN_THREAD_ID);
+        assert_eq!(thread_id_of(&json!({ "body": "hi" })), MAIN_THREAD_ID);
+    }
+
+    #[test]
+    fn test_room_counts_split_main_timeline_and_threads() {
+        let counts = RoomUnreadCounts {
+            threads: vec![thread(MAIN_THREAD_ID, 1, 3), thread("$root", 1, 2)],
+        };
+        assert_eq!(counts.total(), (2, 5));
+        assert_eq!(counts.main_timeline(), (1, 3));
+        assert_eq!(
+            counts.thread_notifications(),
+            json!({ "$root": { "highlight_count": 1, "notification_count": 2 } })
+        );
+        assert_eq!(RoomUnreadCounts::default().main_timeline(), (0, 0));
+    }
+}
@@ -1,4 +1,5 @@
 use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
 use std::borrow::Cow;
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
@@ -57,6 +58,35 @@ impl PushRule {
     }
 }
 
+impl PushRule {
+    /// The rule as it appears in a client push ruleset. Content rules carry their `pattern`,
+    /// and room and sender rules are identified by their rule ID alone.
+    pub fn to_json(&self) -> Value {
+        let mut rule = json!({
+            "rule_id": self.rule_id,
+            "default": self.default,
+            "enabled": self.enabled,
+            "actions": self.actions.iter().map(ActionKind::to_json).collect::<Vec<_>>(),
+        });
+        match self.priority_class {
+            PriorityClass::Override | PriorityClass::Underride => {
+                rule["conditions"] = serde_json::to_value(&self.conditions).unwrap_or_else(|_| json!([]));
+            }
+            PriorityClass::ContentSpecific => {
+                let pattern = self.conditions.iter().find_map(|condition| match condition {
+                    Condition::EventMatch { key, pattern } if key == "content.body" => Some(pattern),
+                    _ => None,
+                });
+                if let Some(pattern) = pattern {
+                    rule["pattern"] = json!(pattern);
+                }
+            }
+            PriorityClass::RoomSpecific | PriorityClass::SenderSpecific => {}
+        }
+        rule
+    }
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub enum ActionKind {
     #[serde(rename = "notify")]
@@ -69,6 +99,51 @@ pub enum ActionKind {
     SetTweak { set_tweak: String, value: serde_json::Value },
 }
 
+impl ActionKind {
+    /// The action in push rule JSON: a bare string, or an object for tweaks.
+    pub fn to_json(&self) -> Value {
+        match self {
+            ActionKind::Notify => json!("notify"),
+            ActionKind::DontNotify => json!("dont_notify"),
+            ActionKind::Coalesce => json!("coalesce"),
+            ActionKind::SetTweak { set_tweak, value } => {
+                let mut tweak = json!({ "set_tweak": set_tweak });
+                // A highlight tweak without a value means `true`
+                if !value.is_null() {
+                    tweak["value"] = value.clone();
+                }
+                tweak
+            }
+        }
+    }
+
+    pub fn from_json(action: &Value) -> Option<Self> {
+        match action {
+            Value::String(name) => match name.as_str() {
+                "notify" => Some(ActionKind::Notify),
+                "dont_notify" => Some(ActionKind::DontNotify),
+                "coalesce" => Some(ActionKind::Coalesce),
+                _ => None,
+            },
+            Value::Object(tweak) => Some(ActionKind::SetTweak {
+                set_tweak: tweak.get("set_tweak")?.as_str()?.to_string(),
+                value: tweak.get("value").cloned().unwrap_or(Value::Null),
+            }),
+            _ => None,
+        }
+    }
+
+    /// Whether this is a `highlight` tweak that is not switched off.
+    pub fn is_highlight(&self) -> bool {
+        match self {
+            ActionKind::SetTweak { set_tweak, value } => {
+                set_tweak == "highlight" && (value.is_null() || value.as_bool() == Some(true))
+            }
+            _ => false,
+        }
+    }
+}
+
 #[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
 pub enum PriorityClass {
     Override = 0,
@@ -78,6 +153,32 @@ pub enum PriorityClass {
     Underride = 4,
 }
 
+impl PriorityClass {
+    /// Every class, in evaluation order.
+    pub const ALL: [PriorityClass; 5] = [
+        PriorityClass::Override,
+        PriorityClass::ContentSpecific,
+        PriorityClass::RoomSpecific,
+        PriorityClass::SenderSpecific,
+        PriorityClass::Underride,
+    ];
+
+    /// The rule kind used for the class in the push rules API.
+    pub fn kind(&self) -> &'static str {
+        match self {
+            PriorityClass::Override => "override",
+            PriorityClass::ContentSpecific => "content",
+            PriorityClass::RoomSpecific => "room",
+            PriorityClass::SenderSpecific => "sender",
+            PriorityClass::Underride => "underride",
+        }
+    }
+
+    pub fn from_kind(kind: &str) -> Option<Self> {
+        Self::ALL.into_iter().find(|class| class.kind() == kind)
+    }
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 #[serde(tag = "kind")]
 pub enum Condition {
@@ -97,6 +198,9 @@ pub enum Condition {
     SenderNotificationPermission {
         key: String,
     },
+    /// A condition this server does not implement; rules using it never match.
+    #[serde(other)]
+    Unsupported,
 }
 
 #[derive(Debug, Clone)]
@@ -151,32 +255,24 @@ impl PushRuleEvaluator {
     }
 
     pub fn evaluate(&self, ctx: &EventContext) -> Option<PushAction> {
-        if let Some(action) = self.evaluate_rules(&self.override_rules, ctx) {
-            return Some(action);
-        }
-
-        if let Some(action) = self.evaluate_rules(&self.content_rules, ctx) {
-            return Some(action);
-        }
-
-        if let Some(action) = self.evaluate_rules(&self.room_rules, ctx) {
-            return Some(action);
-        }
-
-        if let Some(action) = self.evaluate_rules(&self.sender_rules, ctx) {
-            return Some(action);
-        }
+        self.matching_rule(ctx).map(|rule| self.action_from_rule(rule))
+    }
 
-        self.evaluate_rules(&self.underride_rules, ctx)
+    /// The actions of the first rule that matches, tweaks included.
+    pub fn evaluate_actions(&self, ctx: &EventContext) -> Option<&[ActionKind]> {
+        self.matching_rule(ctx).map(|rule| rule.actions.as_slice())
     }
 
-    fn evaluate_rules(&self, rules: &[PushRule], ctx: &EventContext) -> Option<PushAction> {
-        for rule in rules {
-            if self.matches_rule(rule, ctx) {
-                return Some(self.action_from_rule(rule));
-            }
-        }
-        None
+    fn matching_rule(&self, ctx: &EventContext) -> Option<&PushRule> {
+        [
+            &self.override_rules,
+            &self.content_rules,
+            &self.room_rules,
+            &self.sender_rules,
+            &self.underride_rules,
+        ]
+        .into_iter()
+        .find_map(|rules| rules.iter().find(|rule| self.matches_rule(rule, ctx)))
     }
 
     fn matches_rule(&self, rule: &PushRule, ctx: &EventContext) -> bool {
@@ -215,6 +311,7 @@ impl PushRuleEvaluator {
             Condition::SenderNotificationPermission { key: _ } => {
                 ctx.user_power_level >= 50
             }
+            Condition::Unsupported => false,
         }
     }
 
@@ -225,7 +322,11 @@ impl PushRuleEvaluator {
             "room_id" => ctx.room_id.as_str(),
             "state_key" => ctx.state_key.as_deref().unwrap_or(""),
             _ => {
-                if let Some(v) = self.get_nested_value(&ctx.content, key) {
+                // Only the content is held, so `content.body` is looked up as `body`
+                let Some(content_key) = key.strip_prefix("content.") else {
+                    return false;
+                };
+                if let Some(v) = self.get_nested_value(&ctx.content, content_key) {
                     return self.glob_match(v, pattern);
                 }
                 return false;
@@ -353,6 +454,17 @@ impl PushRuleEvaluator {
                 ],
                 PriorityClass::Override,
             ),
+            PushRule::static_rule(
+                ".m.rule.member_event",
+                true,
+                true,
+                vec![ActionKind::DontNotify],
+                vec![Condition::EventMatch {
+                    key: "type".to_string(),
+                    pattern: "m.room.member".to_string(),
+                }],
+                PriorityClass::Override,
+            ),
             PushRule::static_rule(
                 ".m.rule.contains_display_name",
                 true,
@@ -362,7 +474,7 @@ impl PushRuleEvaluator {
                     value: serde_json::json!(true),
                 }],
                 vec![Condition::ContainsDisplayName],
-                PriorityClass::ContentSpecific,
+                PriorityClass::Override,
             ),
             PushRule::static_rule(
                 ".m.rule.room_one_to_one",
@@ -372,7 +484,13 @@ impl PushRuleEvaluator {
                     set_tweak: "sound".to_string(),
                     value: serde_json::json!("default"),
                 }],
-                vec![Condition::RoomMemberCount { is: Some("2".to_string()) }],
+                vec![
+                    Condition::RoomMemberCount { is: Some("2".to_string()) },
+                    Condition::EventMatch {
+                        key: "type".to_string(),
+                        pattern: "m.room.message".to_string(),
+                    },
+                ],
                 PriorityClass::Underride,
             ),
             PushRule::static_rule(
@@ -449,6 +567,27 @@ mod tests {
         assert_eq!(result, Some(PushAction::Notify));
     }
 
+    #[test]
+    fn test_notices_are_suppressed() {
+        let evaluator = PushRuleEvaluator::new(PushRuleEvaluator::get_default_rules());
+        let mut ctx = create_test_context();
+        ctx.content = json!({ "msgtype": "m.notice", "body": "Hello Bob!" });
+
+        assert_eq!(evaluator.evaluate(&ctx), Some(PushAction::DontNotify));
+    }
+
+    #[test]
+    fn test_evaluate_actions_returns_tweaks() {
+        let evaluator = PushRuleEvaluator::new(PushRuleEvaluator::get_default_rules());
+        let actions = evaluator.evaluate_actions(&create_test_context()).unwrap();
+        assert!(actions.iter().any(ActionKind::is_highlight));
+
+        let mut ctx = create_test_context();
+        ctx.event_type = "m.room.member".to_string();
+        ctx.room_member_count = 2;
+        assert_eq!(evaluator.evaluate(&ctx), Some(PushAction::DontNotify));
+    }
+
     #[test]
     fn test_glob_match_exact() {
         let evaluator = PushRuleEvaluator::new(vec![]);
@@ -476,6 +615,56 @@ mod tests {
         assert!(evaluator.match_member_count("<5", 4));
     }
 
+    #[test]
+    fn test_actions_round_trip_through_json() {
+        let actions = json!(["notify", {"set_tweak": "sound", "value": "default"}, {"set_tweak": "highlight"}]);
+        let parsed: Vec<ActionKind> = actions
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|action| ActionKind::from_json(action).unwrap())
+            .collect();
+        assert!(matches!(parsed[0], ActionKind::Notify));
+        let back: Vec<Value> = parsed.iter().map(ActionKind::to_json).collect();
+        assert_eq!(json!(back), actions);
+        assert!(ActionKind::from_json(&json!("ring")).is_none());
+    }
+
+    #[test]
+    fn test_unsupported_condition_never_matches() {
+        let condition: Condition =
+            serde_json::from_value(json!({"kind": "event_property_is", "key": "type", "value": 1})).unwrap();
+        let rule = PushRule::dynamic_rule(
+            "future".to_string(),
+            false,
+            true,
+            vec![ActionKind::DontNotify],
+            vec![condition],
+            PriorityClass::Override,
+        );
+        let evaluator = PushRuleEvaluator::new(vec![rule]);
+        assert_eq!(evaluator.evaluate(&create_test_context()), None);
+    }
+
+    #[test]
+    fn test_content_rule_json_has_pattern() {
+        let rule = PushRule::dynamic_rule(
+            "cake".to_string(),
+            false,
+            true,
+            vec![ActionKind::Notify],
+            vec![Condition::EventMatch {
+                key: "content.body".to_string(),
+                pattern: "cake*".to_string(),
+            }],
+            PriorityClass::ContentSpecific,
+        );
+        let json = rule.to_json();
+        assert_eq!(json["pattern"], "cake*");
+        assert!(json.get("conditions").is_none());
+        assert_eq!(PriorityClass::from_kind(PriorityClass::ContentSpecific.kind()), Some(PriorityClass::ContentSpecific));
+    }
+
     #[test]
     fn test_early_exit_on_override() {
         let rules = vec![
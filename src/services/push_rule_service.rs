@@ -0,0 +1,438 @@
+//! Per-user push rules: the server defaults from [`PushRuleEvaluator::get_default_rules`]
+//! merged with the rules and overrides users store through the push rules API.
+
+use crate::common::error::ApiError;
+use crate::common::ApiResult;
+use crate::services::notifier::{Notifier, NotifierStream};
+use crate::services::push_rule_evaluator::{ActionKind, Condition, PriorityClass, PushRule, PushRuleEvaluator};
+use crate::storage::push_rules::{PushRulePosition, PushRuleStorage, StoredPushRule};
+use serde_json::{json, Value};
+use std::collections::HashMap;
+
+/// The only push rule scope.
+pub const PUSH_RULE_SCOPE: &str = "global";
+
+/// The one server-default rule that takes precedence over user-defined override rules.
+const MASTER_RULE_ID: &str = ".m.rule.master";
+
+/// A rule of a user's ruleset, both as sent to clients and as evaluated.
+#[derive(Debug, Clone)]
+pub struct UserPushRule {
+    pub json: Value,
+    pub rule: PushRule,
+}
+
+pub struct PushRuleService {
+    storage: PushRuleStorage,
+    notifier: Option<Notifier>,
+}
+
+impl PushRuleService {
+    pub fn new(storage: PushRuleStorage) -> Self {
+        Self { storage, notifier: None }
+    }
+
+    pub fn with_notifier(mut self, notifier: Notifier) -> Self {
+        self.notifier = Some(notifier);
+        self
+    }
+
+    /// The user's rules in evaluation order.
+    pub async fn get_user_rules(&self, user_id: &str) -> ApiResult<Vec<UserPushRule>> {
+        let stored = self
+            .storage
+            .get_rules(user_id)
+            .await
+            .map_err(|e| ApiError::internal(format!("Failed to get push rules: {}", e)))?;
+        let enabled = self
+            .storage
+            .get_enabled_states(user_id)
+            .await
+            .map_err(|e| ApiError::internal(format!("Failed to get push rules: {}", e)))?;
+        Ok(merge_rules(stored, enabled))
+    }
+
+    /// The user's rules for [`PushRuleEvaluator::new`].
+    pub async fn get_rules_for_evaluation(&self, user_id: &str) -> ApiResult<Vec<PushRule>> {
+        Ok(self
+            .get_user_rules(user_id)
+            .await?
+            .into_iter()
+            .map(|user_rule| user_rule.rule)
+            .collect())
+    }
+
+    /// The ruleset as returned by `GET /pushrules/` and sent as `m.push_rules` account data.
+    pub async fn get_ruleset(&self, user_id: &str) -> ApiResult<Value> {
+        let mut global: serde_json::Map<String, Value> = PriorityClass::ALL
+            .iter()
+            .map(|class| (class.kind().to_string(), json!([])))
+            .collect();
+        for user_rule in self.get_user_rules(user_id).await? {
+            if let Some(Value::Array(rules)) = global.get_mut(user_rule.rule.priority_class.kind()) {
+                rules.push(user_rule.json);
+            }
+        }
+        Ok(json!({ PUSH_RULE_SCOPE: global }))
+    }
+
+    /// The `m.push_rules` account data event.
+    pub async fn get_account_data_event(&self, user_id: &str) -> ApiResult<Value> {
+        Ok(json!({
+            "type": "m.push_rules",
+            "content": self.get_ruleset(user_id).await?
+        }))
+    }
+
+    /// Whether the user's rules changed after stream position `since`.
+    pub async fn changed_since(&self, user_id: &str, since: i64) -> ApiResult<bool> {
+        let updated_ts = self
+            .storage
+            .get_updated_ts(user_id)
+            .await
+            .map_err(|e| ApiError::internal(format!("Failed to get push rules: {}", e)))?;
+        Ok(updated_ts.is_some_and(|ts| ts > since))
+    }
+
+    pub async fn get_rule(&self, user_id: &str, scope: &str, kind: &str, rule_id: &str) -> ApiResult<Value> {
+        Ok(self.find_rule(user_id, scope, kind, rule_id).await?.json)
+    }
+
+    /// Adds or replaces a user-defined rule from the body of `PUT /pushrules/global/{kind}/{ruleId}`.
+    #[allow(clippy::too_many_arguments)]
+    pub async fn put_rule(
+        &self,
+        user_id: &str,
+        scope: &str,
+        kind: &str,
+        rule_id: &str,
+        body: &Value,
+        before: Option<&str>,
+        after: Option<&str>,
+    ) -> ApiResult<()> {
+        let class = parse_kind(scope, kind)?;
+        if rule_id.is_empty() || rule_id.starts_with('.') {
+            return Err(ApiError::bad_request(
+                "Rule IDs starting with '.' are reserved for server-default rules".to_string(),
+            ));
+        }
+        if rule_id.contains('/') || rule_id.contains('\\') {
+            return Err(ApiError::bad_request("Rule IDs may not contain slashes".to_string()));
+        }
+        let relative = before.or(after);
+        if relative.is_some_and(|relative| relative.starts_with('.')) {
+            return Err(ApiError::bad_request(
+                "Rules cannot be placed relative to server-default rules".to_string(),
+            ));
+        }
+
+        let actions = parse_actions(body.get("actions"))?;
+        let (conditions, pattern) = match class {
+            PriorityClass::Override | PriorityClass::Underride => {
+                let conditions = body.get("conditions").cloned().unwrap_or_else(|| json!([]));
+                let valid = conditions
+                    .as_array()
+                    .is_some_and(|conditions| conditions.iter().all(|c| c.get("kind").is_some_and(Value::is_string)));
+                if !valid {
+                    return Err(ApiError::bad_request("conditions must be a list of conditions".to_string()));
+                }
+                (conditions, None)
+            }
+            PriorityClass::ContentSpecific => {
+                let pattern = body
+                    .get("pattern")
+                    .and_then(|v| v.as_str())
+                    .ok_or_else(|| ApiError::bad_request("Content rules require a pattern".to_string()))?;
+                (json!([]), Some(pattern.to_string()))
+            }
+            PriorityClass::RoomSpecific | PriorityClass::SenderSpecific => (json!([]), None),
+        };
+
+        let rule = StoredPushRule {
+            kind: kind.to_string(),
+            rule_id: rule_id.to_string(),
+            priority: 0,
+            conditions,
+            pattern,
+            actions,
+        };
+        let position = match (before, after) {
+            (Some(before), _) => PushRulePosition::Before(before),
+            (None, Some(after)) => PushRulePosition::After(after),
+            (None, None) => PushRulePosition::First,
+        };
+        let placed = self
+            .storage
+            .put_rule(user_id, &rule, position)
+            .await
+            .map_err(|e| ApiError::internal(format!("Failed to store push rule: {}", e)))?;
+        if !placed {
+            return Err(ApiError::not_found(format!("Unknown rule {}", relative.unwrap_or_default())));
+        }
+        self.notify(user_id).await;
+        Ok(())
+    }
+
+    pub async fn delete_rule(&self, user_id: &str, scope: &str, kind: &str, rule_id: &str) -> ApiResult<()> {
+        parse_kind(scope, kind)?;
+        if rule_id.starts_with('.') {
+            return Err(ApiError::bad_request("Server-default rules cannot be deleted".to_string()));
+        }
+        let deleted = self
+            .storage
+            .delete_rule(user_id, kind, rule_id)
+            .await
+            .map_err(|e| ApiError::internal(format!("Failed to delete push rule: {}", e)))?;
+        if !deleted {
+            return Err(ApiError::not_found(format!("Unknown rule {}", rule_id)));
+        }
+        self.notify(user_id).await;
+        Ok(())
+    }
+
+    pub async fn is_enabled(&self, user_id: &str, scope: &str, kind: &str, rule_id: &str) -> ApiResult<bool> {
+        Ok(self.find_rule(user_id, scope, kind, rule_id).await?.rule.enabled)
+    }
+
+    pub async fn set_enabled(&self, user_id: &str, scope: &str, kind: &str, rule_id: &str, enabled: bool) -> ApiResult<()> {
+        self.find_rule(user_id, scope, kind, rule_id).await?;
+        self.storage
+            .set_enabled(user_id, kind, rule_id, enabled)
+            .await
+            .map_err(|e| ApiError::internal(format!("Failed to update push rule: {}", e)))?;
+        self.notify(user_id).await;
+        Ok(())
+    }
+
+    pub async fn get_actions(&self, user_id: &str, scope: &str, kind: &str, rule_id: &str) -> ApiResult<Value> {
+        let rule = self.find_rule(user_id, scope, kind, rule_id).await?;
+        Ok(rule.json["actions"].clone())
+    }
+
+    pub async fn set_actions(&self, user_id: &str, scope: &str, kind: &str, rule_id: &str, actions: Option<&Value>) -> ApiResult<()> {
+        let actions = parse_actions(actions)?;
+        self.find_rule(user_id, scope, kind, rule_id).await?;
+        self.storage
+            .set_actions(user_id, kind, rule_id, &actions)
+            .await
+            .map_err(|e| ApiError::internal(format!("Failed to update push rule: {}", e)))?;
+        self.notify(user_id).await;
+        Ok(())
+    }
+
+    async fn find_rule(&self, user_id: &str, scope: &str, kind: &str, rule_id: &str) -> ApiResult<UserPushRule> {
+        let class = parse_kind(scope, kind)?;
+        self.get_user_rules(user_id)
+            .await?
+            .into_iter()
+            .find(|user_rule| user_rule.rule.priority_class == class && user_rule.rule.rule_id == rule_id)
+            .ok_or_else(|| ApiError::not_found(format!("Unknown rule {}", rule_id)))
+    }
+
+    async fn notify(&self, user_id: &str) {
+        if let Some(notifier) = &self.notifier {
+            notifier
+                .notify_users(NotifierStream::AccountData, &[user_id.to_string()])
+                .await;
+        }
+    }
+}
+
+fn parse_kind(scope: &str, kind: &str) -> ApiResult<PriorityClass> {
+    if scope != PUSH_RULE_SCOPE {
+        return Err(ApiError::bad_request(format!("Unknown push rule scope {}", scope)));
+    }
+    PriorityClass::from_kind(kind).ok_or_else(|| ApiError::bad_request(format!("Unknown push rule kind {}", kind)))
+}
+
+fn parse_actions(actions: Option<&Value>) -> ApiResult<Value> {
+    let actions = actions
+        .and_then(|v| v.as_array())
+        .ok_or_else(|| ApiError::bad_request("actions must be a list".to_string()))?;
+    if let Some(invalid) = actions.iter().find(|action| ActionKind::from_json(action).is_none()) {
+        return Err(ApiError::bad_request(format!("Invalid push rule action {}", invalid)));
+    }
+    Ok(Value::Array(actions.clone()))
+}
+
+/// Merges stored rules and enabled flags into the server defaults. Within each kind the
+/// user's rules come first, except that the master rule stays ahead of everything.
+pub fn merge_rules(stored: Vec<StoredPushRule>, enabled: Vec<(String, String, bool)>) -> Vec<UserPushRule> {
+    let enabled: HashMap<(String, String), bool> = enabled
+        .into_iter()
+        .map(|(kind, rule_id, enabled)| ((kind, rule_id), enabled))
+        .collect();
+    let is_enabled = |kind: &str, rule_id: &str, default: bool| {
+        enabled
+            .get(&(kind.to_string(), rule_id.to_string()))
+            .copied()
+            .unwrap_or(default)
+    };
+
+    let (default_actions, user_rules): (Vec<StoredPushRule>, Vec<StoredPushRule>) =
+        stored.into_iter().partition(|rule| rule.rule_id.starts_with('.'));
+    let default_actions: HashMap<(String, String), Value> = default_actions
+        .into_iter()
+        .map(|rule| ((rule.kind, rule.rule_id), rule.actions))
+        .collect();
+
+    let defaults: Vec<UserPushRule> = PushRuleEvaluator::get_default_rules()
+        .into_iter()
+        .map(|mut rule| {
+            let kind = rule.priority_class.kind();
+            rule.enabled = is_enabled(kind, &rule.rule_id, rule.enabled);
+            if let Some(actions) = default_actions.get(&(kind.to_string(), rule.rule_id.to_string())) {
+                rule.actions = actions_of(actions);
+            }
+            UserPushRule { json: rule.to_json(), rule }
+        })
+        .collect();
+    let user_rules: Vec<UserPushRule> = user_rules
+        .into_iter()
+        .filter_map(|stored| {
+            let class = PriorityClass::from_kind(&stored.kind)?;
+            let enabled = is_enabled(&stored.kind, &stored.rule_id, true);
+            Some(user_rule(stored, class, enabled))
+        })
+        .collect();
+
+    let mut merged = Vec::with_capacity(defaults.len() + user_rules.len());
+    for class in PriorityClass::ALL {
+        let of_class = |rule: &&UserPushRule| rule.rule.priority_class == class;
+        let (master, other_defaults): (Vec<&UserPushRule>, Vec<&UserPushRule>) =
+            defaults.iter().filter(of_class).partition(|rule| rule.rule.rule_id == MASTER_RULE_ID);
+        merged.extend(master.into_iter().cloned());
+        merged.extend(user_rules.iter().filter(of_class).cloned());
+        merged.extend(other_defaults.into_iter().cloned());
+    }
+    merged
+}
+
+fn user_rule(stored: StoredPushRule, class: PriorityClass, enabled: bool) -> UserPushRule {
+    let mut json = json!({
+        "rule_id": stored.rule_id,
+        "default": false,
+        "enabled": enabled,
+        "actions": stored.actions,
+    });
+    let event_match = |key: &str, pattern: &str| Condition::EventMatch {
+        key: key.to_string(),
+        pattern: pattern.to_string(),
+    };
+    let conditions = match class {
+        PriorityClass::Override | PriorityClass::Underride => {
+            json["conditions"] = stored.conditions.clone();
+            stored
+                .conditions
+                .as_array()
+                .map(|conditions| {
+                    conditions
+                        .iter()
+                        .map(|c| serde_json::from_value(c.clone()).unwrap_or(Condition::Unsupported))
+                        .collect()
+                })
+                .unwrap_or_default()
+        }
+        PriorityClass::ContentSpecific => {
+            let pattern = stored.pattern.clone().unwrap_or_default();
+            json["pattern"] = json!(pattern);
+            vec![event_match("content.body", &pattern)]
+        }
+        PriorityClass::RoomSpecific => vec![event_match("room_id", &stored.rule_id)],
+        PriorityClass::SenderSpecific => vec![event_match("sender", &stored.rule_id)],
+    };
+
+    let rule = PushRule::dynamic_rule(
+        stored.rule_id,
+        false,
+        enabled,
+        actions_of(&stored.actions),
+        conditions,
+        class,
+    );
+    UserPushRule { json, rule }
+}
+
+fn actions_of(actions: &Value) -> Vec<ActionKind> {
+    actions
+        .as_array()
+        .map(|actions| actions.iter().filter_map(ActionKind::from_json).collect())
+        .unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::services::push_rule_evaluator::{EventContext, PushAction};
+
+    fn stored(kind: &str, rule_id: &str, actions: Value) -> StoredPushRule {
+        StoredPushRule {
+            kind: kind.to_string(),
+            rule_id: rule_id.to_string(),
+            priority: 0,
+            conditions: json!([]),
+            pattern: None,
+            actions,
+        }
+    }
+
+    fn message_in(room_id: &str) -> EventContext {
+        EventContext {
+            event_type: "m.room.message".to_string(),
+            sender: "@alice:example.com".to_string(),
+            room_id: room_id.to_string(),
+            content: json!({ "msgtype": "m.text", "body": "lunch?" }),
+            state_key: None,
+            display_name: Some("Bob".to_string()),
+            room_member_count: 5,
+            user_power_level: 0,
+        }
+    }
+
+    #[test]
+    fn test_user_override_rules_follow_master_rule() {
+        let merged = merge_rules(vec![stored("override", "mine", json!([]))], vec![]);
+        let overrides: Vec<&str> = merged
+            .iter()
+            .filter(|r| r.rule.priority_class == PriorityClass::Override)
+            .map(|r| r.json["rule_id"].as_str().unwrap())
+            .collect();
+        assert_eq!(overrides[0], MASTER_RULE_ID);
+        assert_eq!(overrides[1], "mine");
+        assert!(overrides.len() > 2);
+    }
+
+    #[test]
+    fn test_room_rule_mutes_room() {
+        let merged = merge_rules(vec![stored("room", "!muted:example.com", json!(["dont_notify"]))], vec![]);
+        let evaluator = PushRuleEvaluator::new(merged.into_iter().map(|r| r.rule).collect());
+
+        assert_eq!(evaluator.evaluate(&message_in("!muted:example.com")), Some(PushAction::DontNotify));
+        assert_eq!(evaluator.evaluate(&message_in("!other:example.com")), Some(PushAction::Notify));
+    }
+
+    #[test]
+    fn test_default_rule_overrides_apply() {
+        let merged = merge_rules(
+            vec![stored("underride", ".m.rule.message", json!(["dont_notify"]))],
+            vec![("override".to_string(), MASTER_RULE_ID.to_string(), true)],
+        );
+        let rule = |rule_id: &str| merged.iter().find(|r| r.json["rule_id"] == rule_id).unwrap();
+
+        assert_eq!(rule(MASTER_RULE_ID).json["enabled"], true);
+        assert_eq!(rule(".m.rule.message").json["actions"], json!(["dont_notify"]));
+        assert_eq!(rule(".m.rule.message").json["default"], true);
+    }
+
+    #[test]
+    fn test_content_rule_matches_keyword() {
+        let mut keyword = stored("content", "lunch", json!(["notify", {"set_tweak": "highlight"}]));
+        keyword.pattern = Some("lunch*".to_string());
+        let merged = merge_rules(vec![keyword], vec![]);
+
+        let rule = merged.iter().find(|r| r.json["rule_id"] == "lunch").unwrap();
+        assert_eq!(rule.json["pattern"], "lunch*");
+        let evaluator = PushRuleEvaluator::new(vec![rule.rule.clone()]);
+        assert_eq!(evaluator.evaluate(&message_in("!room:example.com")), Some(PushAction::Notify));
+    }
+}
@@ -1,12 +1,22 @@
 use crate::common::config::PushConfig;
 use crate::common::error::ApiError;
+use crate::common::ApiResult;
+use crate::storage::pusher::{Pusher, PusherStorage};
 use chrono::Utc;
 use serde::{Deserialize, Serialize};
 use std::collections::{HashMap, VecDeque};
 use std::sync::Arc;
-use tokio::sync::RwLock;
+use std::time::Duration;
+use tokio::sync::{Notify, RwLock};
+use tokio::task::JoinHandle;
 use tracing::{debug, error, info, warn};
 
+/// Delay before the first retry of a failed push; it doubles with each further attempt.
+const PUSH_RETRY_BASE_DELAY_MS: i64 = 1000;
+
+/// How often the queue is checked for retries that have become due.
+const PUSH_QUEUE_POLL_INTERVAL: Duration = Duration::from_secs(1);
+
 /// Push notification data
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct PushNotification {
@@ -16,6 +26,28 @@ pub struct PushNotification {
     pub sender: String,
     pub content: serde_json::Value,
     pub counts: NotificationCounts,
+    #[serde(default)]
+    pub sender_display_name: Option<String>,
+    #[serde(default)]
+    pub room_name: Option<String>,
+    /// Tweaks of the push rule that matched, such as `sound` and `highlight`
+    #[serde(default)]
+    pub tweaks: serde_json::Map<String, serde_json::Value>,
+}
+
+impl PushNotification {
+    /// `high` for notifications that make a sound or highlight, `low` for the rest.
+    pub fn prio(&self) -> &'static str {
+        let highlight = self
+            .tweaks
+            .get("highlight")
+            .is_some_and(|value| value.as_bool() != Some(false));
+        if highlight || self.tweaks.contains_key("sound") {
+            "high"
+        } else {
+            "low"
+        }
+    }
 }
 
 /// Notification counts
@@ -40,6 +72,35 @@ pub struct PushDevice {
     pub data: Option<serde_json::Value>,
 }
 
+impl PushDevice {
+    /// The device a pusher delivers to, if its kind is one we can deliver to.
+    pub fn from_pusher(pusher: &Pusher) -> Option<Self> {
+        let kind = match pusher.kind.as_str() {
+            "http" => PushDeviceKind::Http,
+            _ => return None,
+        };
+        Some(Self {
+            pushkey: pusher.pushkey.clone(),
+            kind,
+            app_id: pusher.app_id.clone(),
+            app_display_name: pusher.app_display_name.clone(),
+            device_display_name: pusher.device_display_name.clone(),
+            profile_tag: pusher.profile_tag.clone(),
+            lang: pusher.lang.clone(),
+            data: Some(pusher.data.clone()),
+        })
+    }
+
+    /// Whether the gateway only gets the event and room IDs and the counts.
+    pub fn is_event_id_only(&self) -> bool {
+        self.data
+            .as_ref()
+            .and_then(|data| data.get("format"))
+            .and_then(|format| format.as_str())
+            == Some("event_id_only")
+    }
+}
+
 /// Push device kind
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub enum PushDeviceKind {
@@ -102,6 +163,14 @@ impl PushQueueItem {
     pub fn can_retry(&self, max_attempts: u32) -> bool {
         self.attempts < max_attempts && self.status == PushQueueStatus::Pending
     }
+
+    /// Whether the backoff after the last failed attempt has passed.
+    pub fn is_due(&self, now: i64) -> bool {
+        self.last_attempt.is_none_or(|last| {
+            let backoff = PUSH_RETRY_BASE_DELAY_MS << self.attempts.saturating_sub(1).min(16);
+            now >= last + backoff
+        })
+    }
 }
 
 /// Push queue status
@@ -130,6 +199,8 @@ pub struct PushService {
     http_client: reqwest::Client,
     queue: Arc<RwLock<VecDeque<PushQueueItem>>>,
     stats: Arc<RwLock<PushStats>>,
+    pusher_storage: Option<PusherStorage>,
+    wakeup: Arc<Notify>,
 }
 
 impl PushService {
@@ -144,13 +215,102 @@ impl PushService {
             http_client,
             queue: Arc::new(RwLock::new(VecDeque::new())),
             stats: Arc::new(RwLock::new(PushStats::default())),
+            pusher_storage: None,
+            wakeup: Arc::new(Notify::new()),
         }
     }
 
+    /// Delivers to the pushers users register, and removes those the gateway rejects.
+    pub fn with_pusher_storage(mut self, pusher_storage: PusherStorage) -> Self {
+        self.pusher_storage = Some(pusher_storage);
+        self
+    }
+
+    fn pusher_storage(&self) -> ApiResult<&PusherStorage> {
+        self.pusher_storage
+            .as_ref()
+            .ok_or_else(|| ApiError::internal("Pushers are not available"))
+    }
+
+    pub async fn get_pushers(&self, user_id: &str) -> ApiResult<Vec<Pusher>> {
+        self.pusher_storage()?
+            .get_pushers(user_id)
+            .await
+            .map_err(|e| ApiError::internal(format!("Failed to get pushers: {}", e)))
+    }
+
+    pub async fn set_pusher(&self, pusher: &Pusher, append: bool) -> ApiResult<()> {
+        self.pusher_storage()?
+            .set_pusher(pusher, append)
+            .await
+            .map_err(|e| ApiError::internal(format!("Failed to set pusher: {}", e)))
+    }
+
+    pub async fn delete_pusher(&self, user_id: &str, app_id: &str, pushkey: &str) -> ApiResult<bool> {
+        self.pusher_storage()?
+            .delete_pusher(user_id, app_id, pushkey)
+            .await
+            .map_err(|e| ApiError::internal(format!("Failed to delete pusher: {}", e)))
+    }
+
+    /// Removes the pushers registered by devices that are being deleted.
+    pub async fn delete_device_pushers(&self, user_id: &str, device_ids: &[String]) -> ApiResult<u64> {
+        self.pusher_storage()?
+            .delete_device_pushers(user_id, device_ids)
+            .await
+            .map_err(|e| ApiError::internal(format!("Failed to delete pushers: {}", e)))
+    }
+
+    /// Whether notifications for registered pushers are delivered at all.
+    pub fn delivers_notifications(&self) -> bool {
+        self.config.enabled && self.pusher_storage.is_some()
+    }
+
+    /// Queues the notification for each of the user's pushers. Returns how many were queued.
+    pub async fn notify_user(&self, user_id: &str, notification: &PushNotification) -> ApiResult<usize> {
+        if !self.delivers_notifications() {
+            return Ok(0);
+        }
+        let devices: Vec<PushDevice> = self
+            .get_pushers(user_id)
+            .await?
+            .iter()
+            .filter_map(PushDevice::from_pusher)
+            .collect();
+        for device in &devices {
+            self.queue_push(user_id.to_string(), device.clone(), notification.clone()).await;
+        }
+        Ok(devices.len())
+    }
+
+    /// Runs the queue until the process exits, waking on new notifications and polling for
+    /// retries that have become due. Does nothing when push is disabled.
+    pub fn start(self: &Arc<Self>) -> Option<JoinHandle<()>> {
+        if !self.config.enabled {
+            return None;
+        }
+        let service = self.clone();
+        Some(tokio::spawn(async move {
+            info!("Push sender started");
+            loop {
+                service.process_queue().await;
+                tokio::select! {
+                    _ = service.wakeup.notified() => {}
+                    _ = tokio::time::sleep(PUSH_QUEUE_POLL_INTERVAL) => {}
+                }
+            }
+        }))
+    }
+
     pub fn is_enabled(&self) -> bool {
         self.config.is_enabled()
     }
 
+    /// Whether the `unread` count sent to gateways counts rooms rather than events.
+    pub fn groups_unread_count_by_room(&self) -> bool {
+        self.config.group_unread_count_by_room
+    }
+
     pub async fn queue_push(
         &self,
         user_id: String,
@@ -161,19 +321,30 @@ impl PushService {
         let id = item.id.clone();
         
         self.queue.write().await.push_back(item);
+        self.wakeup.notify_one();
         
         debug!(id = %id, "Push notification queued");
         
         id
     }
 
+    /// Sends every queued notification whose retry is due. A notification the gateway cannot
+    /// be reached for is retried with backoff up to `retry_count` attempts; a pushkey the
+    /// gateway rejects is removed. Returns `(sent, failed)`.
     pub async fn process_queue(&self) -> (u64, u64) {
         let mut sent = 0u64;
         let mut failed = 0u64;
 
-        let mut queue = self.queue.write().await;
-        
-        while let Some(mut item) = queue.pop_front() {
+        let now = Utc::now().timestamp_millis();
+        let due: Vec<PushQueueItem> = {
+            let mut queue = self.queue.write().await;
+            let (due, waiting): (Vec<_>, Vec<_>) = queue.drain(..).partition(|item| item.is_due(now));
+            queue.extend(waiting);
+            due
+        };
+
+        let mut retries = Vec::new();
+        for mut item in due {
             if !item.can_retry(self.config.retry_count) {
                 item.mark_failed();
                 failed += 1;
@@ -185,24 +356,31 @@ impl PushService {
             match self.send_notification(&item.device, &item.notification).await {
                 Ok(response) => {
                     if response.rejected.contains(&item.device.pushkey) {
+                        self.remove_rejected_pusher(&item).await;
                         item.mark_failed();
                         failed += 1;
                     } else {
+                        self.record_delivery(&item, true).await;
                         item.mark_sent();
                         sent += 1;
                     }
                 }
-                Err(_) => {
+                Err(e) => {
+                    warn!(pushkey = %item.device.pushkey, attempt = item.attempts, "Push failed: {}", e);
+                    self.record_delivery(&item, false).await;
                     if item.attempts >= self.config.retry_count {
                         item.mark_failed();
                         failed += 1;
                     } else {
-                        queue.push_back(item);
+                        retries.push(item);
                     }
                 }
             }
         }
 
+        let mut queue = self.queue.write().await;
+        queue.extend(retries);
+
         let mut stats = self.stats.write().await;
         stats.total_sent += sent;
         stats.total_failed += failed;
@@ -211,6 +389,30 @@ impl PushService {
         (sent, failed)
     }
 
+    async fn remove_rejected_pusher(&self, item: &PushQueueItem) {
+        let Some(storage) = &self.pusher_storage else {
+            return;
+        };
+        info!(pushkey = %item.device.pushkey, "Removing pusher rejected by its gateway");
+        if let Err(e) = storage
+            .delete_pusher(&item.user_id, &item.device.app_id, &item.device.pushkey)
+            .await
+        {
+            warn!("Failed to remove rejected pusher: {}", e);
+        }
+    }
+
+    async fn record_delivery(&self, item: &PushQueueItem, success: bool) {
+        if let Some(storage) = &self.pusher_storage {
+            if let Err(e) = storage
+                .record_delivery(&item.user_id, &item.device.app_id, &item.device.pushkey, success)
+                .await
+            {
+                warn!("Failed to record push delivery: {}", e);
+            }
+        }
+    }
+
     pub async fn get_queue_size(&self) -> usize {
         self.queue.read().await.len()
     }
@@ -257,7 +459,7 @@ impl PushService {
         device: &PushDevice,
         notification: &PushNotification,
     ) -> Result<PushResponse, ApiError> {
-        if !self.is_enabled() {
+        if !self.config.enabled {
             debug!("Push notifications are disabled");
             return Ok(PushResponse { rejected: vec![] });
         }
@@ -270,45 +472,42 @@ impl PushService {
         }
     }
 
+    /// Sends the notification to the pusher's gateway, or the configured one when the pusher
+    /// has no URL. Errors mean the gateway could not be reached and the push can be retried.
     async fn send_http_push(
         &self,
         device: &PushDevice,
         notification: &PushNotification,
     ) -> Result<PushResponse, ApiError> {
-        let gateway_url: String = self.config.push_gateway_url.clone()
-            .or_else(|| device.data.as_ref().and_then(|d| d.get("url").and_then(|u| u.as_str().map(|s| s.to_string()))))
+        let gateway_url: String = device
+            .data
+            .as_ref()
+            .and_then(|d| d.get("url").and_then(|u| u.as_str().map(|s| s.to_string())))
+            .or_else(|| self.config.push_gateway_url.clone())
             .ok_or_else(|| ApiError::bad_request("No push gateway URL configured"))?;
 
         let payload = self.build_push_payload(device, notification);
 
-        for attempt in 0..self.config.retry_count {
-            match self.http_client
-                .post(&gateway_url)
-                .json(&payload)
-                .send()
-                .await
-            {
-                Ok(response) => {
-                    if response.status().is_success() {
-                        let response_data: PushResponse = response.json().await
-                            .unwrap_or(PushResponse { rejected: vec![] });
-                        info!("HTTP push sent successfully to {}", device.pushkey);
-                        return Ok(response_data);
-                    } else {
-                        warn!("HTTP push failed with status: {}", response.status());
-                    }
-                }
-                Err(e) => {
-                    warn!("HTTP push attempt {} failed: {}", attempt + 1, e);
-                    if attempt < self.config.retry_count - 1 {
-                        tokio::time::sleep(std::time::Duration::from_millis(100 * (attempt + 1) as u64)).await;
-                    }
-                }
-            }
+        let response = self
+            .http_client
+            .post(&gateway_url)
+            .json(&payload)
+            .send()
+            .await
+            .map_err(|e| ApiError::internal(format!("Push gateway unreachable: {}", e)))?;
+        if !response.status().is_success() {
+            return Err(ApiError::internal(format!(
+                "Push gateway returned {}",
+                response.status()
+            )));
         }
 
-        error!("HTTP push failed after {} attempts", self.config.retry_count);
-        Ok(PushResponse { rejected: vec![device.pushkey.clone()] })
+        let response_data: PushResponse = response
+            .json()
+            .await
+            .unwrap_or(PushResponse { rejected: vec![] });
+        info!("HTTP push sent successfully to {}", device.pushkey);
+        Ok(response_data)
     }
 
     async fn send_fcm_push(
@@ -453,28 +652,43 @@ impl PushService {
         Ok(PushResponse { rejected: vec![] })
     }
 
+    /// The request body of the Push Gateway API `/_matrix/push/v1/notify`.
     fn build_push_payload(&self, device: &PushDevice, notification: &PushNotification) -> serde_json::Value {
-        serde_json::json!({
-            "notification": {
-                "id": notification.event_id,
-                "type": notification.event_type,
-                "sender": notification.sender,
-                "room_id": notification.room_id,
-                "content": if self.config.include_content {
-                    notification.content.clone()
-                } else {
-                    serde_json::json!(null)
-                },
-                "counts": {
-                    "unread": notification.counts.unread,
-                    "missed_calls": notification.counts.missed_calls,
-                }
+        // The gateway gets the pusher's data except the URL it is being sent to
+        let mut data = device.data.clone().unwrap_or_else(|| serde_json::json!({}));
+        if let Some(data) = data.as_object_mut() {
+            data.remove("url");
+        }
+
+        let mut payload = serde_json::json!({
+            "event_id": notification.event_id,
+            "room_id": notification.room_id,
+            "prio": notification.prio(),
+            "counts": {
+                "unread": notification.counts.unread,
+                "missed_calls": notification.counts.missed_calls,
             },
             "devices": [{
-                "pushkey": device.pushkey,
                 "app_id": device.app_id,
+                "pushkey": device.pushkey,
+                "data": data,
+                "tweaks": notification.tweaks,
             }]
-        })
+        });
+        if !device.is_event_id_only() {
+            payload["type"] = serde_json::json!(notification.event_type);
+            payload["sender"] = serde_json::json!(notification.sender);
+            if let Some(name) = &notification.sender_display_name {
+                payload["sender_display_name"] = serde_json::json!(name);
+            }
+            if let Some(name) = &notification.room_name {
+                payload["room_name"] = serde_json::json!(name);
+            }
+            if self.config.include_content {
+                payload["content"] = notification.content.clone();
+            }
+        }
+        serde_json::json!({ "notification": payload })
     }
 
     fn get_notification_title(&self, notification: &PushNotification) -> String {
@@ -522,6 +736,7 @@ mod tests {
             push_gateway_url: Some("https://push.example.com/_matrix/push/v1/notify".to_string()),
             retry_count: 3,
             timeout: 10,
+            notification_retention_days: 30,
         }
     }
 
@@ -539,6 +754,9 @@ mod tests {
                 unread: 5,
                 missed_calls: 0,
             },
+            sender_display_name: Some("Alice".to_string()),
+            room_name: None,
+            tweaks: serde_json::Map::new(),
         }
     }
 
@@ -608,8 +826,111 @@ mod tests {
         let device = create_test_device();
 
         let payload = service.build_push_payload(&device, &notification);
-        assert_eq!(payload["notification"]["id"], "$event123");
+        assert_eq!(payload["notification"]["event_id"], "$event123");
         assert_eq!(payload["notification"]["room_id"], "!room123:example.com");
+        assert_eq!(payload["notification"]["sender_display_name"], "Alice");
+        assert_eq!(payload["notification"]["prio"], "low");
+        assert_eq!(payload["notification"]["devices"][0]["pushkey"], "device_token_123");
+        assert!(payload["notification"]["devices"][0]["data"].get("url").is_none());
+    }
+
+    #[test]
+    fn test_event_id_only_payload() {
+        let service = PushService::new(Arc::new(create_test_config()));
+        let mut notification = create_test_notification();
+        notification.tweaks.insert("sound".to_string(), serde_json::json!("default"));
+        let mut device = create_test_device();
+        device.data = Some(serde_json::json!({
+            "url": "https://push.example.com/_matrix/push/v1/notify",
+            "format": "event_id_only"
+        }));
+
+        let payload = &service.build_push_payload(&device, &notification)["notification"];
+        assert_eq!(payload["event_id"], "$event123");
+        assert_eq!(payload["counts"]["unread"], 5);
+        assert_eq!(payload["prio"], "high");
+        assert_eq!(payload["devices"][0]["tweaks"]["sound"], "default");
+        assert_eq!(payload["devices"][0]["data"]["format"], "event_id_only");
+        assert!(payload.get("sender").is_none());
+        assert!(payload.get("content").is_none());
+    }
+
+    #[test]
+    fn test_retries_back_off() {
+        let mut item = PushQueueItem::new(
+            "@bob:example.com".to_string(),
+            create_test_device(),
+            create_test_notification(),
+        );
+        let now = Utc::now().timestamp_millis();
+        assert!(item.is_due(now));
+        item.mark_attempted();
+        assert!(!item.is_due(now));
+        assert!(item.is_due(now + PUSH_RETRY_BASE_DELAY_MS));
+        item.mark_attempted();
+        assert!(!item.is_due(now + PUSH_RETRY_BASE_DELAY_MS));
+    }
+
+    /// A push gateway that rejects the pushkey `rejected`, fails for `unreachable` and
+    /// records every notification it accepts.
+    async fn push_gateway() -> (String, Arc<RwLock<Vec<serde_json::Value>>>) {
+        use axum::extract::State;
+        use axum::http::StatusCode;
+        use axum::Json;
+
+        type Received = Arc<RwLock<Vec<serde_json::Value>>>;
+
+        async fn notify(
+            State(received): State<Received>,
+            Json(body): Json<serde_json::Value>,
+        ) -> Result<Json<serde_json::Value>, StatusCode> {
+            match body["notification"]["devices"][0]["pushkey"].as_str() {
+                Some("unreachable") => Err(StatusCode::BAD_GATEWAY),
+                Some("rejected") => Ok(Json(serde_json::json!({ "rejected": ["rejected"] }))),
+                _ => {
+                    received.write().await.push(body);
+                    Ok(Json(serde_json::json!({ "rejected": [] })))
+                }
+            }
+        }
+
+        let received: Received = Arc::new(RwLock::new(Vec::new()));
+        let app = axum::Router::new()
+            .route("/_matrix/push/v1/notify", axum::routing::post(notify))
+            .with_state(received.clone());
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        tokio::spawn(async move {
+            axum::serve(listener, app).await.unwrap();
+        });
+        (format!("http://127.0.0.1:{}/_matrix/push/v1/notify", port), received)
+    }
+
+    #[tokio::test]
+    async fn test_queue_delivers_to_gateway() {
+        let (url, received) = push_gateway().await;
+        let mut config = create_test_config();
+        config.push_gateway_url = None;
+        let service = PushService::new(Arc::new(config));
+
+        for pushkey in ["good", "rejected", "unreachable"] {
+            let mut device = create_test_device();
+            device.pushkey = pushkey.to_string();
+            device.data = Some(serde_json::json!({ "url": url }));
+            service
+                .queue_push("@bob:example.com".to_string(), device, create_test_notification())
+                .await;
+        }
+
+        assert_eq!(service.process_queue().await, (1, 1));
+        // The unreachable one waits for its retry
+        assert_eq!(service.get_queue_size().await, 1);
+        assert_eq!(service.process_queue().await, (0, 0));
+
+        let received = received.read().await;
+        assert_eq!(received.len(), 1);
+        assert_eq!(received[0]["notification"]["event_id"], "$event123");
+        assert_eq!(received[0]["notification"]["devices"][0]["pushkey"], "good");
     }
 
     #[test]
@@ -1,4 +1,5 @@
 use crate::common::{generate_event_id, ApiError, ApiResult};
+use crate::services::push_actions_service::PushActionsService;
 use crate::storage::{CreateEventParams, EventStorage};
 use serde_json::json;
 use std::collections::HashMap;
@@ -34,6 +35,7 @@ pub struct ReadReceiptService {
     event_storage: EventStorage,
     receipts: Arc<RwLock<HashMap<String, Vec<ReadReceipt>>>>,
     server_name: String,
+    push_actions: Option<Arc<PushActionsService>>,
 }
 
 impl ReadReceiptService {
@@ -42,9 +44,16 @@ impl ReadReceiptService {
             event_storage,
             receipts: Arc::new(RwLock::new(HashMap::new())),
             server_name,
+            push_actions: None,
         }
     }
 
+    /// Clears the user's unread notification counts up to each receipt.
+    pub fn with_push_actions(mut self, push_actions: Arc<PushActionsService>) -> Self {
+        self.push_actions = Some(push_actions);
+        self
+    }
+
     pub async fn set_read_receipt(
         &self,
         user_id: &str,
@@ -102,6 +111,12 @@ impl ReadReceiptService {
                 }
             })?;
 
+        if let Some(push_actions) = &self.push_actions {
+            push_actions
+                .mark_read(user_id, room_id, receipt.thread_id.as_deref(), event_id)
+                .await?;
+        }
+
         let mut receipts = self.receipts.write().await;
         let room_receipts = receipts.entry(room_id.to_string()).or_insert_with(Vec::new);
         
@@ -1,4 +1,5 @@
 use chrono::Utc;
+use futures::StreamExt;
 use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
 use std::sync::Arc;
@@ -44,9 +45,22 @@ pub enum ReplicationChannel {
     Federation,
     Push,
     AccountData,
+    ToDevice,
 }
 
 impl ReplicationChannel {
+    pub const ALL: [ReplicationChannel; 9] = [
+        ReplicationChannel::Events,
+        ReplicationChannel::Presence,
+        ReplicationChannel::Typing,
+        ReplicationChannel::Receipts,
+        ReplicationChannel::DeviceLists,
+        ReplicationChannel::Federation,
+        ReplicationChannel::Push,
+        ReplicationChannel::AccountData,
+        ReplicationChannel::ToDevice,
+    ];
+
     pub fn as_str(&self) -> &'static str {
         match self {
             ReplicationChannel::Events => "events",
@@ -57,6 +71,7 @@ impl ReplicationChannel {
             ReplicationChannel::Federation => "federation",
             ReplicationChannel::Push => "push",
             ReplicationChannel::AccountData => "account_data",
+            ReplicationChannel::ToDevice => "to_device",
         }
     }
 
@@ -70,6 +85,7 @@ impl ReplicationChannel {
             "federation" => Some(ReplicationChannel::Federation),
             "push" => Some(ReplicationChannel::Push),
             "account_data" => Some(ReplicationChannel::AccountData),
+            "to_device" => Some(ReplicationChannel::ToDevice),
             _ => None,
         }
     }
@@ -145,6 +161,7 @@ pub struct RedisReplicationService {
     config: RedisReplicationConfig,
     instance_name: String,
     positions: Arc<RwLock<HashMap<ReplicationChannel, ReplicationPosition>>>,
+    remote_positions: Arc<RwLock<HashMap<(String, ReplicationChannel), u64>>>,
     stats: Arc<RwLock<ReplicationStats>>,
     sender: broadcast::Sender<ReplicationMessage>,
     _receiver: broadcast::Receiver<ReplicationMessage>,
@@ -155,16 +172,7 @@ impl RedisReplicationService {
         let (sender, receiver) = broadcast::channel(1000);
         
         let mut positions = HashMap::new();
-        for channel in [
-            ReplicationChannel::Events,
-            ReplicationChannel::Presence,
-            ReplicationChannel::Typing,
-            ReplicationChannel::Receipts,
-            ReplicationChannel::DeviceLists,
-            ReplicationChannel::Federation,
-            ReplicationChannel::Push,
-            ReplicationChannel::AccountData,
-        ] {
+        for channel in ReplicationChannel::ALL {
             positions.insert(channel, ReplicationPosition::new(channel, instance_name.clone()));
         }
         
@@ -172,6 +180,7 @@ impl RedisReplicationService {
             config,
             instance_name,
             positions: Arc::new(RwLock::new(positions)),
+            remote_positions: Arc::new(RwLock::new(HashMap::new())),
             stats: Arc::new(RwLock::new(ReplicationStats::default())),
             sender,
             _receiver: receiver,
@@ -182,6 +191,10 @@ impl RedisReplicationService {
         self.config.enabled
     }
 
+    pub fn instance_name(&self) -> &str {
+        &self.instance_name
+    }
+
     pub async fn publish(&self, channel: ReplicationChannel, data: serde_json::Value) -> Result<(), ReplicationError> {
         if !self.config.enabled {
             return Ok(());
@@ -235,19 +248,21 @@ impl RedisReplicationService {
         }
 
         {
-            let mut positions = self.positions.write().await;
-            if let Some(position) = positions.get_mut(&message.channel) {
-                if message.sequence <= position.position {
-                    debug!(
-                        channel = ?message.channel,
-                        seq = message.sequence,
-                        current = position.position,
-                        "Skipping old replication message"
-                    );
-                    return Ok(());
-                }
-                position.update(message.sequence);
+            // Sequences are assigned per publishing instance, so track them per instance
+            let mut positions = self.remote_positions.write().await;
+            let position = positions
+                .entry((message.instance_name.clone(), message.channel))
+                .or_insert(0);
+            if message.sequence <= *position {
+                debug!(
+                    channel = ?message.channel,
+                    seq = message.sequence,
+                    current = *position,
+                    "Skipping old replication message"
+                );
+                return Ok(());
             }
+            *position = message.sequence;
         }
 
         if let Err(e) = self.sender.send(message.clone()) {
@@ -312,6 +327,129 @@ impl RedisReplicationService {
     pub fn get_config(&self) -> &RedisReplicationConfig {
         &self.config
     }
+
+    fn redis_url(&self) -> String {
+        match &self.config.password {
+            Some(password) => format!(
+                "redis://:{}@{}:{}",
+                password, self.config.host, self.config.port
+            ),
+            None => format!("redis://{}:{}", self.config.host, self.config.port),
+        }
+    }
+
+    /// Bridge the in-process broadcast channel to Redis pub/sub: messages
+    /// published by this instance are forwarded to Redis, and messages from
+    /// other instances are fed through `process_incoming`.
+    pub fn start_redis_bridge(self: &Arc<Self>) -> Result<Vec<tokio::task::JoinHandle<()>>, ReplicationError> {
+        if !self.config.enabled {
+            return Ok(Vec::new());
+        }
+
+        let client = redis::Client::open(self.redis_url())
+            .map_err(|_| ReplicationError::ConnectionFailed)?;
+
+        let outbound = {
+            let service = self.clone();
+            let client = client.clone();
+            tokio::spawn(async move { service.run_outbound(client).await })
+        };
+        let inbound = {
+            let service = self.clone();
+            tokio::spawn(async move { service.run_inbound(client).await })
+        };
+
+        info!(instance = %self.instance_name, "Redis replication bridge started");
+        Ok(vec![outbound, inbound])
+    }
+
+    async fn run_outbound(&self, client: redis::Client) {
+        let mut receiver = self.subscribe();
+        let delay = std::time::Duration::from_millis(self.config.reconnect_delay_ms);
+
+        loop {
+            let mut conn = match client.get_multiplexed_async_connection().await {
+                Ok(conn) => conn,
+                Err(e) => {
+                    self.mark_disconnected(Some(e.to_string())).await;
+                    tokio::time::sleep(delay).await;
+                    continue;
+                }
+            };
+
+            loop {
+                let message = match receiver.recv().await {
+                    Ok(message) => message,
+                    Err(broadcast::error::RecvError::Lagged(skipped)) => {
+                        self.stats.write().await.messages_dropped += skipped;
+                        continue;
+                    }
+                    Err(broadcast::error::RecvError::Closed) => return,
+                };
+                if message.instance_name != self.instance_name {
+                    continue;
+                }
+
+                let payload = match serde_json::to_string(&message) {
+                    Ok(payload) => payload,
+                    Err(e) => {
+                        warn!(error = %e, "Failed to serialize replication message");
+                        continue;
+                    }
+                };
+                let result: redis::RedisResult<()> = redis::AsyncCommands::publish(
+                    &mut conn,
+                    self.get_channel_name(message.channel),
+                    payload,
+                )
+                .await;
+                if let Err(e) = result {
+                    warn!(error = %e, "Failed to publish replication message to Redis");
+                    self.mark_disconnected(Some(e.to_string())).await;
+                    break;
+                }
+            }
+
+            tokio::time::sleep(delay).await;
+        }
+    }
+
+    async fn run_inbound(&self, client: redis::Client) {
+        let delay = std::time::Duration::from_millis(self.config.reconnect_delay_ms);
+
+        loop {
+            if let Err(e) = self.subscribe_remote(&client).await {
+                warn!(error = %e, "Redis replication subscription failed");
+                self.mark_disconnected(Some(e.to_string())).await;
+            } else {
+                self.mark_disconnected(None).await;
+            }
+            tokio::time::sleep(delay).await;
+        }
+    }
+
+    async fn subscribe_remote(&self, client: &redis::Client) -> redis::RedisResult<()> {
+        let mut pubsub = client.get_async_pubsub().await?;
+        for channel in ReplicationChannel::ALL {
+            pubsub.subscribe(self.get_channel_name(channel)).await?;
+        }
+        self.mark_connected().await;
+
+        let mut messages = pubsub.on_message();
+        while let Some(msg) = messages.next().await {
+            let payload: String = msg.get_payload()?;
+            match serde_json::from_str::<ReplicationMessage>(&payload) {
+                Ok(message) => {
+                    if let Err(e) = self.process_incoming(message).await {
+                        warn!(error = %e, "Failed to process replication message");
+                    }
+                }
+                Err(e) => warn!(error = %e, "Discarding malformed replication message"),
+            }
+        }
+
+        Ok(())
+    }
 }
 
 /// Redis connection manager (simplified for demonstration)
@@ -502,6 +640,35 @@ mod tests {
         assert!(!manager.is_connected().await);
     }
 
+    #[tokio::test]
+    async fn test_process_incoming_tracks_positions_per_instance() {
+        let config = RedisReplicationConfig {
+            enabled: true,
+            ..Default::default()
+        };
+        let service = RedisReplicationService::new(config, "master".to_string());
+        let mut receiver = service.subscribe();
+
+        service.publish(ReplicationChannel::Events, json!({"n": 1})).await.unwrap();
+        service.publish(ReplicationChannel::Events, json!({"n": 2})).await.unwrap();
+        receiver.recv().await.unwrap();
+        receiver.recv().await.unwrap();
+
+        let remote = ReplicationMessage::new(
+            ReplicationChannel::Events,
+            "worker1".to_string(),
+            json!({"n": 3}),
+            1,
+        );
+        service.process_incoming(remote.clone()).await.unwrap();
+        let forwarded = receiver.recv().await.unwrap();
+        assert_eq!(forwarded.instance_name, "worker1");
+
+        service.process_incoming(remote).await.unwrap();
+        assert!(receiver.try_recv().is_err());
+        assert_eq!(service.get_stats().await.messages_received, 1);
+    }
+
     #[test]
     fn test_get_channel_name() {
         let config = RedisReplicationConfig {
@@ -1,3 +1,4 @@
+use crate::appservice::AppServiceRegistry;
 use crate::common::metrics::MetricsCollector;
 use crate::common::task_queue::RedisTaskQueue;
 use crate::common::background_job::BackgroundJob;
@@ -11,8 +12,10 @@ pub struct RegistrationService {
     metrics: Arc<MetricsCollector>,
     // HP-2 FIX: Make base URL configurable instead of hardcoded
     base_url: String,
+    server_name: String,
     enable_registration: bool,
     task_queue: Option<Arc<RedisTaskQueue>>,
+    app_services: Option<Arc<AppServiceRegistry>>,
 }
 
 impl RegistrationService {
@@ -34,11 +37,32 @@ impl RegistrationService {
             auth_service,
             metrics,
             base_url,
+            server_name,
             enable_registration,
             task_queue,
+            app_services: None,
         }
     }
 
+    /// User IDs in an application service's exclusive namespace can only be registered by it.
+    pub fn with_app_services(mut self, app_services: Arc<AppServiceRegistry>) -> Self {
+        self.app_services = Some(app_services);
+        self
+    }
+
+    /// Fails with `M_EXCLUSIVE` if an application service has reserved the user ID.
+    pub async fn check_not_exclusive(&self, user_id: &str) -> ApiResult<()> {
+        if let Some(app_services) = &self.app_services {
+            if app_services.has_exclusive_user_namespace(user_id).await {
+                return Err(ApiError::exclusive(format!(
+                    "User ID {} is reserved by an application service",
+                    user_id
+                )));
+            }
+        }
+        Ok(())
+    }
+
     pub async fn register_user(
         &self,
         username: &str,
@@ -49,6 +73,8 @@ impl RegistrationService {
         if !self.enable_registration {
             return Err(ApiError::forbidden("Registration is disabled".to_string()));
         }
+        self.check_not_exclusive(&format!("@{}:{}", username, self.server_name))
+            .await?;
 
         let start = std::time::Instant::now();
         let result = self
@@ -93,18 +119,63 @@ impl RegistrationService {
             }
         }
 
-        Ok(serde_json::json!({
+        Ok(self.session_response(&user.user_id(), &access_token, &refresh_token, &device_id))
+    }
+
+    /// Registers a user for the application service that owns `as_token`. The user must be
+    /// in the service's namespace; registration being disabled does not apply.
+    pub async fn register_appservice_user(
+        &self,
+        as_token: &str,
+        username: &str,
+        inhibit_login: bool,
+    ) -> ApiResult<serde_json::Value> {
+        let service = match &self.app_services {
+            Some(app_services) => app_services.get_by_token(as_token).await,
+            None => None,
+        }
+        .ok_or_else(|| ApiError::unauthorized("Unrecognised application service token".to_string()))?;
+
+        let user_id = format!("@{}:{}", username, self.server_name);
+        if !service.is_user_in_namespace(&user_id) {
+            return Err(ApiError::exclusive(format!(
+                "User ID {} is not in the application service's namespace",
+                user_id
+            )));
+        }
+
+        let user = self
+            .auth_service
+            .register_appservice_user(username, service.id())
+            .await?;
+        if inhibit_login {
+            return Ok(serde_json::json!({ "user_id": user.user_id() }));
+        }
+
+        let (access_token, refresh_token, device_id) =
+            self.auth_service.create_session(&user.user_id(), false).await?;
+        Ok(self.session_response(&user.user_id(), &access_token, &refresh_token, &device_id))
+    }
+
+    fn session_response(
+        &self,
+        user_id: &str,
+        access_token: &str,
+        refresh_token: &str,
+        device_id: &str,
+    ) -> serde_json::Value {
+        serde_json::json!({
             "access_token": access_token,
             "refresh_token": refresh_token,
             "expires_in": self.auth_service.token_expiry,
             "device_id": device_id,
-            "user_id": user.user_id(),
+            "user_id": user_id,
             "well_known": {
                 "m.homeserver": {
                     "base_url": self.base_url
                 }
             }
-        }))
+        })
     }
 
     pub async fn login(
@@ -141,18 +212,7 @@ impl RegistrationService {
             counter.inc();
         }
 
-        Ok(serde_json::json!({
-            "access_token": access_token,
-            "refresh_token": refresh_token,
-            "expires_in": self.auth_service.token_expiry,
-            "device_id": device_id,
-            "user_id": user.user_id(),
-            "well_known": {
-                "m.homeserver": {
-                    "base_url": self.base_url
-                }
-            }
-        }))
+        Ok(self.session_response(&user.user_id(), &access_token, &refresh_token, &device_id))
     }
 
     pub async fn change_password(&self, user_id: &str, new_password: &str) -> ApiResult<()> {
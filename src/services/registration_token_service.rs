@@ -1,360 +1,234 @@
+use crate::storage::{RegistrationToken, RegistrationTokenStorage, RegistrationTokenUpdate};
 use chrono::Utc;
-use serde::{Deserialize, Serialize};
-use std::collections::HashMap;
-use std::sync::Arc;
-use tokio::sync::RwLock;
+use rand::Rng;
 use tracing::info;
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
-pub struct RegistrationToken {
-    pub token: String,
-    pub uses_allowed: Option<u32>,
-    pub pending: u32,
-    pub completed: u32,
-    pub expiry_time: Option<i64>,
-    pub created_at: i64,
-    pub created_by: String,
-}
-
-impl RegistrationToken {
-    pub fn new(
-        token: String,
-        uses_allowed: Option<u32>,
-        expiry_time: Option<i64>,
-        created_by: String,
-    ) -> Self {
-        Self {
-            token,
-            uses_allowed,
-            pending: 0,
-            completed: 0,
-            expiry_time,
-            created_at: Utc::now().timestamp_millis(),
-            created_by,
-        }
-    }
-
-    pub fn is_valid(&self) -> bool {
-        if let Some(expiry) = self.expiry_time {
-            if Utc::now().timestamp_millis() > expiry {
-                return false;
-            }
-        }
-
-        if let Some(max_uses) = self.uses_allowed {
-            if self.completed >= max_uses {
-                return false;
-            }
-        }
+/// Longest token the admin API accepts or generates.
+pub const MAX_REGISTRATION_TOKEN_LENGTH: usize = 64;
 
-        true
-    }
+const DEFAULT_TOKEN_LENGTH: usize = 16;
 
-    pub fn remaining_uses(&self) -> Option<u32> {
-        self.uses_allowed.map(|max| max.saturating_sub(self.completed))
-    }
+/// Characters allowed in tokens, as in the Synapse admin API.
+const TOKEN_CHARSET: &[u8] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789._~-";
 
-    pub fn can_use(&self) -> bool {
-        self.is_valid() && self.remaining_uses().map(|r| r > 0).unwrap_or(true)
-    }
-}
+/// Attempts at a fresh random token before giving up on collisions.
+const GENERATE_ATTEMPTS: usize = 5;
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
-pub struct TokenUsage {
-    pub token: String,
-    pub user_id: String,
-    pub used_at: i64,
-}
-
-#[derive(Debug, Clone, Serialize, Deserialize)]
-pub struct TokenStats {
-    pub total_tokens: usize,
-    pub active_tokens: usize,
-    pub expired_tokens: usize,
-    pub exhausted_tokens: usize,
-    pub total_registrations: u32,
+/// A token to create: either `token` as given, or a random one of `length` characters.
+#[derive(Debug, Clone, Default)]
+pub struct NewRegistrationToken {
+    pub token: Option<String>,
+    pub length: Option<usize>,
+    pub uses_allowed: Option<i32>,
+    pub expiry_time: Option<i64>,
 }
 
+/// Registration tokens (MSC3231), stored in Postgres. A registration counts as pending on its
+/// token from the `m.login.registration_token` stage until the account is created, so a
+/// limited token is never handed out more times than allowed.
 pub struct RegistrationTokenService {
-    tokens: Arc<RwLock<HashMap<String, RegistrationToken>>>,
-    usage: Arc<RwLock<Vec<TokenUsage>>>,
+    storage: RegistrationTokenStorage,
 }
 
 impl RegistrationTokenService {
-    pub fn new() -> Self {
-        Self {
-            tokens: Arc::new(RwLock::new(HashMap::new())),
-            usage: Arc::new(RwLock::new(Vec::new())),
-        }
+    pub fn new(storage: RegistrationTokenStorage) -> Self {
+        Self { storage }
     }
 
     pub async fn create_token(
         &self,
-        length: usize,
-        uses_allowed: Option<u32>,
-        expiry_time: Option<i64>,
+        new_token: NewRegistrationToken,
         created_by: &str,
     ) -> Result<RegistrationToken, TokenError> {
-        let token = Self::generate_token(length);
-        
-        let reg_token = RegistrationToken::new(
-            token.clone(),
-            uses_allowed,
-            expiry_time,
-            created_by.to_string(),
-        );
+        validate_limits(new_token.uses_allowed, new_token.expiry_time)?;
 
-        self.tokens.write().await.insert(token.clone(), reg_token.clone());
+        let mut token = RegistrationToken {
+            token: String::new(),
+            uses_allowed: new_token.uses_allowed,
+            pending: 0,
+            completed: 0,
+            expiry_time: new_token.expiry_time,
+            created_ts: Utc::now().timestamp_millis(),
+            created_by: Some(created_by.to_string()),
+        };
+
+        if let Some(given) = new_token.token {
+            if !is_well_formed(&given) {
+                return Err(TokenError::InvalidParam(format!(
+                    "token must consist of characters matching [A-Za-z0-9._~-] and be at most {} characters",
+                    MAX_REGISTRATION_TOKEN_LENGTH
+                )));
+            }
+            token.token = given;
+            if !self.storage.create_token(&token).await? {
+                return Err(TokenError::TokenExists);
+            }
+        } else {
+            let length = new_token.length.unwrap_or(DEFAULT_TOKEN_LENGTH);
+            if !(1..=MAX_REGISTRATION_TOKEN_LENGTH).contains(&length) {
+                return Err(TokenError::InvalidParam(format!(
+                    "length must be an integer between 1 and {}",
+                    MAX_REGISTRATION_TOKEN_LENGTH
+                )));
+            }
+            let mut created = false;
+            for _ in 0..GENERATE_ATTEMPTS {
+                token.token = generate_token(length);
+                if self.storage.create_token(&token).await? {
+                    created = true;
+                    break;
+                }
+            }
+            if !created {
+                return Err(TokenError::TokenExists);
+            }
+        }
 
         info!(
-            token = %token,
-            uses_allowed = ?uses_allowed,
-            expiry_time = ?expiry_time,
+            token = %token.token,
+            uses_allowed = ?token.uses_allowed,
+            expiry_time = ?token.expiry_time,
             created_by = %created_by,
             "Registration token created"
         );
-
-        Ok(reg_token)
+        Ok(token)
     }
 
-    fn generate_token(length: usize) -> String {
-        use rand::Rng;
-        const CHARSET: &[u8] = b"ABCDEFGHJKLMNPQRSTUVWXYZabcdefghjkmnpqrstuvwxyz23456789";
-        let mut rng = rand::thread_rng();
-        
-        (0..length)
-            .map(|_| {
-                let idx = rng.gen_range(0..CHARSET.len());
-                CHARSET[idx] as char
-            })
-            .collect()
+    pub async fn get_token(&self, token: &str) -> Result<RegistrationToken, TokenError> {
+        self.storage.get_token(token).await?.ok_or(TokenError::TokenNotFound)
     }
 
-    pub async fn validate_token(&self, token: &str) -> Result<RegistrationToken, TokenError> {
-        let tokens = self.tokens.read().await;
-        
-        let reg_token = tokens
-            .get(token)
-            .ok_or(TokenError::InvalidToken)?
-            .clone();
-
-        if !reg_token.is_valid() {
-            if let Some(expiry) = reg_token.expiry_time {
-                if Utc::now().timestamp_millis() > expiry {
-                    return Err(TokenError::TokenExpired);
-                }
-            }
-
-            if let Some(max_uses) = reg_token.uses_allowed {
-                if reg_token.completed >= max_uses {
-                    return Err(TokenError::TokenExhausted);
-                }
-            }
-
-            return Err(TokenError::InvalidToken);
-        }
-
-        Ok(reg_token)
+    /// All tokens, or only the valid or invalid ones.
+    pub async fn list_tokens(&self, valid: Option<bool>) -> Result<Vec<RegistrationToken>, TokenError> {
+        Ok(self
+            .storage
+            .list_tokens(valid, Utc::now().timestamp_millis())
+            .await?)
     }
 
-    pub async fn use_token(&self, token: &str, user_id: &str) -> Result<(), TokenError> {
-        let mut tokens = self.tokens.write().await;
-        
-        let reg_token = tokens
-            .get_mut(token)
-            .ok_or(TokenError::InvalidToken)?;
-
-        if !reg_token.can_use() {
-            if let Some(expiry) = reg_token.expiry_time {
-                if Utc::now().timestamp_millis() > expiry {
-                    return Err(TokenError::TokenExpired);
-                }
-            }
-
-            return Err(TokenError::TokenExhausted);
-        }
-
-        reg_token.pending += 1;
-        reg_token.completed += 1;
-
-        let usage = TokenUsage {
-            token: token.to_string(),
-            user_id: user_id.to_string(),
-            used_at: Utc::now().timestamp_millis(),
-        };
-
-        self.usage.write().await.push(usage);
-
-        info!(
-            token = %token,
-            user_id = %user_id,
-            remaining = ?reg_token.remaining_uses(),
-            "Registration token used"
-        );
-
-        Ok(())
+    pub async fn update_token(
+        &self,
+        token: &str,
+        update: RegistrationTokenUpdate,
+    ) -> Result<RegistrationToken, TokenError> {
+        validate_limits(update.uses_allowed.flatten(), update.expiry_time.flatten())?;
+        let updated = self
+            .storage
+            .update_token(token, &update)
+            .await?
+            .ok_or(TokenError::TokenNotFound)?;
+        info!(token = %token, "Registration token updated");
+        Ok(updated)
     }
 
     pub async fn delete_token(&self, token: &str) -> Result<(), TokenError> {
-        if self.tokens.write().await.remove(token).is_some() {
-            info!(token = %token, "Registration token deleted");
-            Ok(())
-        } else {
-            Err(TokenError::InvalidToken)
+        if !self.storage.delete_token(token).await? {
+            return Err(TokenError::TokenNotFound);
         }
+        info!(token = %token, "Registration token deleted");
+        Ok(())
     }
 
-    pub async fn get_token(&self, token: &str) -> Option<RegistrationToken> {
-        self.tokens.read().await.get(token).cloned()
+    /// Whether `token` can be used to register, for the validity endpoint.
+    pub async fn is_valid(&self, token: &str) -> Result<bool, TokenError> {
+        if !is_well_formed(token) {
+            return Ok(false);
+        }
+        Ok(self.storage.is_valid(token, Utc::now().timestamp_millis()).await?)
     }
 
-    pub async fn list_tokens(&self) -> Vec<RegistrationToken> {
-        self.tokens.read().await.values().cloned().collect()
+    /// Completes the `m.login.registration_token` stage, holding one of the token's uses
+    /// until `finish_registration`.
+    pub async fn begin_registration(&self, token: &str) -> Result<(), TokenError> {
+        if !is_well_formed(token) || !self.storage.add_pending(token, Utc::now().timestamp_millis()).await? {
+            return Err(TokenError::InvalidToken);
+        }
+        Ok(())
     }
 
-    pub async fn get_stats(&self) -> TokenStats {
-        let tokens = self.tokens.read().await;
-        let usage = self.usage.read().await;
-
-        let now = Utc::now().timestamp_millis();
-        
-        let active = tokens.values().filter(|t| t.is_valid()).count();
-        let expired = tokens.values().filter(|t| {
-            t.expiry_time.map(|exp| exp < now).unwrap_or(false)
-        }).count();
-        let exhausted = tokens.values().filter(|t| {
-            t.uses_allowed.map(|max| t.completed >= max).unwrap_or(false)
-        }).count();
-
-        TokenStats {
-            total_tokens: tokens.len(),
-            active_tokens: active,
-            expired_tokens: expired,
-            exhausted_tokens: exhausted,
-            total_registrations: usage.len() as u32,
+    /// Counts the held use as spent if the account was created, or gives it back.
+    pub async fn finish_registration(&self, token: &str, registered: bool) -> Result<(), TokenError> {
+        if registered {
+            self.storage.complete_pending(token).await?;
+            info!(token = %token, "Registration token used");
+        } else {
+            self.storage.remove_pending(token).await?;
         }
+        Ok(())
     }
+}
 
-    pub async fn cleanup_expired(&self) -> usize {
-        let now = Utc::now().timestamp_millis();
-        let mut tokens = self.tokens.write().await;
-
-        let expired: Vec<String> = tokens
-            .iter()
-            .filter(|(_, t)| {
-                t.expiry_time.map(|exp| exp < now).unwrap_or(false)
-            })
-            .map(|(k, _)| k.clone())
-            .collect();
-
-        let count = expired.len();
-        for token in expired {
-            tokens.remove(&token);
-            info!(token = %token, "Expired token removed");
-        }
+fn is_well_formed(token: &str) -> bool {
+    !token.is_empty()
+        && token.len() <= MAX_REGISTRATION_TOKEN_LENGTH
+        && token.bytes().all(|b| TOKEN_CHARSET.contains(&b))
+}
 
-        count
+fn validate_limits(uses_allowed: Option<i32>, expiry_time: Option<i64>) -> Result<(), TokenError> {
+    if uses_allowed.is_some_and(|uses| uses < 0) {
+        return Err(TokenError::InvalidParam(
+            "uses_allowed must be a non-negative integer or null".to_string(),
+        ));
     }
-
-    pub async fn get_usage_for_token(&self, token: &str) -> Vec<TokenUsage> {
-        self.usage
-            .read()
-            .await
-            .iter()
-            .filter(|u| u.token == token)
-            .cloned()
-            .collect()
+    if expiry_time.is_some_and(|expiry| expiry < Utc::now().timestamp_millis()) {
+        return Err(TokenError::InvalidParam(
+            "expiry_time must not be in the past".to_string(),
+        ));
     }
+    Ok(())
 }
 
-impl Default for RegistrationTokenService {
-    fn default() -> Self {
-        Self::new()
-    }
+fn generate_token(length: usize) -> String {
+    let mut rng = rand::thread_rng();
+    (0..length)
+        .map(|_| TOKEN_CHARSET[rng.gen_range(0..TOKEN_CHARSET.len())] as char)
+        .collect()
 }
 
 #[derive(Debug, thiserror::Error)]
 pub enum TokenError {
-    #[error("Invalid token")]
+    #[error("Invalid registration token")]
     InvalidToken,
-    #[error("Token has expired")]
-    TokenExpired,
-    #[error("Token has been exhausted")]
-    TokenExhausted,
-    #[error("Token already used")]
-    TokenAlreadyUsed,
+    #[error("No such registration token")]
+    TokenNotFound,
+    #[error("Token already exists")]
+    TokenExists,
+    #[error("{0}")]
+    InvalidParam(String),
+    #[error("Database error: {0}")]
+    Database(#[from] sqlx::Error),
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
 
-    #[tokio::test]
-    async fn test_create_token() {
-        let service = RegistrationTokenService::new();
-        
-        let token = service.create_token(16, Some(5), None, "@admin:example.com").await.unwrap();
-        
-        assert_eq!(token.token.len(), 16);
-        assert_eq!(token.uses_allowed, Some(5));
-        assert!(token.is_valid());
-    }
-
-    #[tokio::test]
-    async fn test_validate_token() {
-        let service = RegistrationTokenService::new();
-        
-        let token = service.create_token(16, None, None, "@admin:example.com").await.unwrap();
-        
-        let validated = service.validate_token(&token.token).await.unwrap();
-        assert!(validated.is_valid());
-    }
-
-    #[tokio::test]
-    async fn test_use_token() {
-        let service = RegistrationTokenService::new();
-        
-        let token = service.create_token(16, Some(2), None, "@admin:example.com").await.unwrap();
-        
-        service.use_token(&token.token, "@user1:example.com").await.unwrap();
-        service.use_token(&token.token, "@user2:example.com").await.unwrap();
-        
-        let result = service.use_token(&token.token, "@user3:example.com").await;
-        assert!(matches!(result, Err(TokenError::TokenExhausted)));
-    }
-
-    #[tokio::test]
-    async fn test_expired_token() {
-        let service = RegistrationTokenService::new();
-        
-        let past_time = Utc::now().timestamp_millis() - 1000;
-        let token = service.create_token(16, None, Some(past_time), "@admin:example.com").await.unwrap();
-        
-        let result = service.validate_token(&token.token).await;
-        assert!(matches!(result, Err(TokenError::TokenExpired)));
-    }
-
-    #[tokio::test]
-    async fn test_delete_token() {
-        let service = RegistrationTokenService::new();
-        
-        let token = service.create_token(16, None, None, "@admin:example.com").await.unwrap();
-        
-        service.delete_token(&token.token).await.unwrap();
-        
-        let result = service.validate_token(&token.token).await;
-        assert!(matches!(result, Err(TokenError::InvalidToken)));
+    #[test]
+    fn test_generated_tokens_are_well_formed() {
+        for length in [1, DEFAULT_TOKEN_LENGTH, MAX_REGISTRATION_TOKEN_LENGTH] {
+            let token = generate_token(length);
+            assert_eq!(token.len(), length);
+            assert!(is_well_formed(&token));
+        }
     }
 
-    #[tokio::test]
-    async fn test_stats() {
-        let service = RegistrationTokenService::new();
-        
-        service.create_token(16, Some(1), None, "@admin:example.com").await.unwrap();
-        service.create_token(16, None, None, "@admin:example.com").await.unwrap();
-        
-        let stats = service.get_stats().await;
-        assert_eq!(stats.total_tokens, 2);
-        assert_eq!(stats.active_tokens, 2);
+    #[test]
+    fn test_token_format() {
+        assert!(is_well_formed("Abc.123_~-"));
+        assert!(!is_well_formed(""));
+        assert!(!is_well_formed("has space"));
+        assert!(!is_well_formed("slash/"));
+        assert!(!is_well_formed(&"a".repeat(MAX_REGISTRATION_TOKEN_LENGTH + 1)));
+    }
+
+    #[test]
+    fn test_limits_validation() {
+        assert!(validate_limits(None, None).is_ok());
+        assert!(validate_limits(Some(0), None).is_ok());
+        assert!(matches!(validate_limits(Some(-1), None), Err(TokenError::InvalidParam(_))));
+        let past = Utc::now().timestamp_millis() - 1000;
+        assert!(matches!(validate_limits(None, Some(past)), Err(TokenError::InvalidParam(_))));
+        let future = Utc::now().timestamp_millis() + 60_000;
+        assert!(validate_limits(Some(3), Some(future)).is_ok());
     }
 }
@@ -0,0 +1,519 @@
+use crate::common::error::ApiError;
+use crate::common::ApiResult;
+use crate::services::sync_service::SyncFilter;
+use crate::storage::event::{EventStorage, RoomEvent};
+use crate::storage::membership::RoomMemberStorage;
+use crate::storage::search::{EventSearchQuery, EventSearchStorage};
+use async_trait::async_trait;
+use serde::Deserialize;
+use serde_json::{json, Map, Value};
+use std::collections::{BTreeSet, HashMap};
+use std::sync::Arc;
+
+/// The keys `/search` looks in when the request names none.
+pub const SEARCH_KEYS: [&str; 3] = ["content.body", "content.name", "content.topic"];
+const DEFAULT_SEARCH_LIMIT: i64 = 10;
+const MAX_SEARCH_LIMIT: i64 = 100;
+const DEFAULT_CONTEXT_LIMIT: i64 = 5;
+const MAX_CONTEXT_LIMIT: i64 = 100;
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct SearchRequest {
+    pub search_categories: SearchCategories,
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct SearchCategories {
+    #[serde(default)]
+    pub room_events: Option<RoomEventsCriteria>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct RoomEventsCriteria {
+    pub search_term: String,
+    #[serde(default)]
+    pub keys: Option<Vec<String>>,
+    #[serde(default)]
+    pub filter: Option<SyncFilter>,
+    #[serde(default)]
+    pub order_by: SearchOrder,
+    #[serde(default)]
+    pub event_context: Option<EventContextRequest>,
+    #[serde(default)]
+    pub groupings: Option<Groupings>,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SearchOrder {
+    #[default]
+    Rank,
+    Recent,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct EventContextRequest {
+    #[serde(default = "default_context_limit")]
+    pub before_limit: i64,
+    #[serde(default = "default_context_limit")]
+    pub after_limit: i64,
+    #[serde(default)]
+    pub include_profile: bool,
+}
+
+fn default_context_limit() -> i64 {
+    DEFAULT_CONTEXT_LIMIT
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct Groupings {
+    #[serde(default)]
+    pub group_by: Vec<GroupBy>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct GroupBy {
+    pub key: String,
+}
+
+/// Where the next page of results starts: an offset into rank order, or the
+/// `(stream_ordering, event_id)` of the last result in recent order.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SearchPosition {
+    Rank(i64),
+    Recent(i64, String),
+}
+
+impl SearchPosition {
+    pub fn to_token(&self) -> String {
+        match self {
+            SearchPosition::Rank(offset) => format!("rank_{}", offset),
+            SearchPosition::Recent(stream, event_id) => format!("recent_{}_{}", stream, event_id),
+        }
+    }
+
+    pub fn parse(token: &str) -> Option<Self> {
+        let mut parts = token.splitn(3, '_');
+        match (parts.next()?, parts.next()?.parse().ok()?, parts.next()) {
+            ("rank", offset, None) if offset >= 0 => Some(SearchPosition::Rank(offset)),
+            ("recent", stream, Some(event_id)) if !event_id.is_empty() => {
+                Some(SearchPosition::Recent(stream, event_id.to_string()))
+            }
+            _ => None,
+        }
+    }
+
+    fn order(&self) -> SearchOrder {
+        match self {
+            SearchPosition::Rank(_) => SearchOrder::Rank,
+            SearchPosition::Recent(..) => SearchOrder::Recent,
+        }
+    }
+}
+
+/// What a backend searches for, already limited to rooms the user may search.
+#[derive(Debug, Clone)]
+pub struct MessageSearchQuery {
+    pub search_term: String,
+    pub keys: Vec<String>,
+    pub room_ids: Vec<String>,
+    pub senders: Option<Vec<String>>,
+    pub not_senders: Vec<String>,
+    pub order_by: SearchOrder,
+    pub limit: i64,
+}
+
+#[derive(Debug, Clone)]
+pub struct MessageSearchHit {
+    pub event_id: String,
+    pub room_id: String,
+    pub rank: f64,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct MessageSearchPage {
+    pub hits: Vec<MessageSearchHit>,
+    pub count: i64,
+    /// The words that matched, for clients to highlight.
+    pub highlights: Vec<String>,
+    pub next: Option<SearchPosition>,
+}
+
+/// Full-text search over room events, either built in or backed by an external index.
+#[async_trait]
+pub trait MessageSearchBackend: Send + Sync {
+    async fn search(
+        &self,
+        query: &MessageSearchQuery,
+        from: Option<&SearchPosition>,
+    ) -> ApiResult<MessageSearchPage>;
+}
+
+/// The built-in backend, on the `event_search` table.
+pub struct PostgresSearchBackend {
+    storage: EventSearchStorage,
+}
+
+impl PostgresSearchBackend {
+    pub fn new(storage: EventSearchStorage) -> Self {
+        Self { storage }
+    }
+}
+
+#[async_trait]
+impl MessageSearchBackend for PostgresSearchBackend {
+    async fn search(
+        &self,
+        query: &MessageSearchQuery,
+        from: Option<&SearchPosition>,
+    ) -> ApiResult<MessageSearchPage> {
+        let Some(tsquery) = to_tsquery(&query.search_term) else {
+            return Ok(MessageSearchPage::default());
+        };
+        let search = EventSearchQuery {
+            tsquery: &tsquery,
+            room_ids: &query.room_ids,
+            keys: &query.keys,
+            senders: query.senders.as_deref(),
+            not_senders: &query.not_senders,
+        };
+        let (offset, before) = match from {
+            Some(SearchPosition::Rank(offset)) => (*offset, None),
+            Some(SearchPosition::Recent(stream, event_id)) => (0, Some((*stream, event_id.as_str()))),
+            None => (0, None),
+        };
+        let recent = query.order_by == SearchOrder::Recent;
+        let rows = self.storage.search(&search, recent, offset, before, query.limit).await?;
+        let count = self.storage.count(&search).await?;
+
+        let next = match rows.last() {
+            Some(last) if rows.len() as i64 == query.limit => Some(if recent {
+                SearchPosition::Recent(last.stream_ordering, last.event_id.clone())
+            } else {
+                SearchPosition::Rank(offset + query.limit)
+            }),
+            _ => None,
+        };
+        Ok(MessageSearchPage {
+            highlights: highlighted_words(rows.iter().map(|r| r.headline.as_str()), "<<<", ">>>"),
+            hits: rows
+                .into_iter()
+                .map(|r| MessageSearchHit { event_id: r.event_id, room_id: r.room_id, rank: r.rank })
+                .collect(),
+            count,
+            next,
+        })
+    }
+}
+
+/// Turns a search term into `to_tsquery` syntax. Words match as prefixes and must all be
+/// present, unless separated by `or`; `"quoted words"` must be adjacent and `-word` must be
+/// absent. Returns `None` when nothing searchable is left.
+pub fn to_tsquery(search_term: &str) -> Option<String> {
+    let mut clauses: Vec<(bool, String)> = Vec::new();
+    let mut or_next = false;
+    let mut rest = search_term;
+    loop {
+        rest = rest.trim_start();
+        if rest.is_empty() {
+            break;
+        }
+        let clause = if let Some(quoted) = rest.strip_prefix('"') {
+            let end = quoted.find('"').unwrap_or(quoted.len());
+            let words = lexemes(&quoted[..end]);
+            rest = quoted.get(end + 1..).unwrap_or("");
+            (!words.is_empty()).then(|| format!("({})", words.join(" <-> ")))
+        } else {
+            let end = rest.find(char::is_whitespace).unwrap_or(rest.len());
+            let word = &rest[..end];
+            rest = &rest[end..];
+            if word.eq_ignore_ascii_case("or") {
+                or_next = !clauses.is_empty();
+                continue;
+            }
+            match word.strip_prefix('-') {
+                Some(negated) => {
+                    let words = lexemes(negated);
+                    (!words.is_empty()).then(|| format!("!({})", words.join(" <-> ")))
+                }
+                None => {
+                    let words: Vec<String> = lexemes(word).into_iter().map(|w| format!("{}:*", w)).collect();
+                    (!words.is_empty()).then(|| format!("({})", words.join(" & ")))
+                }
+            }
+        };
+        if let Some(clause) = clause {
+            clauses.push((std::mem::take(&mut or_next), clause));
+        }
+    }
+
+    let mut query = String::new();
+    for (or, clause) in clauses {
+        if !query.is_empty() {
+            query.push_str(if or { " | " } else { " & " });
+        }
+        query.push_str(&clause);
+    }
+    (!query.is_empty()).then_some(query)
+}
+
+fn lexemes(text: &str) -> Vec<String> {
+    text.split(|c: char| !c.is_alphanumeric())
+        .filter(|w| !w.is_empty())
+        .map(str::to_lowercase)
+        .collect()
+}
+
+/// The distinct words found between `start` and `stop` markers in highlighted snippets.
+pub fn highlighted_words<'a>(
+    snippets: impl IntoIterator<Item = &'a str>,
+    start: &str,
+    stop: &str,
+) -> Vec<String> {
+    let mut words = BTreeSet::new();
+    for snippet in snippets {
+        for marked in snippet.split(start).skip(1) {
+            if let Some(word) = marked.split(stop).next().filter(|w| !w.is_empty()) {
+                words.insert(word.to_lowercase());
+            }
+        }
+    }
+    words.into_iter().collect()
+}
+
+/// Serves `/search` over the rooms a user is joined to.
+pub struct RoomSearchService {
+    backend: Arc<dyn MessageSearchBackend>,
+    event_storage: EventStorage,
+    member_storage: RoomMemberStorage,
+}
+
+impl RoomSearchService {
+    pub fn new(
+        backend: Arc<dyn MessageSearchBackend>,
+        event_storage: EventStorage,
+        member_storage: RoomMemberStorage,
+    ) -> Self {
+        Self { backend, event_storage, member_storage }
+    }
+
+    pub async fn search(
+        &self,
+        user_id: &str,
+        request: &SearchRequest,
+        next_batch: Option<&str>,
+    ) -> ApiResult<Value> {
+        let Some(criteria) = &request.search_categories.room_events else {
+            return Ok(json!({ "search_categories": {} }));
+        };
+        let room_events = self.search_room_events(user_id, criteria, next_batch).await?;
+        Ok(json!({ "search_categories": { "room_events": room_events } }))
+    }
+
+    async fn search_room_events(
+        &self,
+        user_id: &str,
+        criteria: &RoomEventsCriteria,
+        next_batch: Option<&str>,
+    ) -> ApiResult<Value> {
+        let keys = match &criteria.keys {
+            Some(keys) => {
+                if let Some(key) = keys.iter().find(|k| !SEARCH_KEYS.contains(&k.as_str())) {
+                    return Err(ApiError::validation(format!("Cannot search on key {}", key)));
+                }
+                keys.clone()
+            }
+            None => SEARCH_KEYS.iter().map(|k| k.to_string()).collect(),
+        };
+        let group_by: Vec<&str> = criteria
+            .groupings
+            .iter()
+            .flat_map(|g| &g.group_by)
+            .map(|g| g.key.as_str())
+            .collect();
+        if let Some(key) = group_by.iter().find(|k| !matches!(**k, "room_id" | "sender")) {
+            return Err(ApiError::validation(format!("Cannot group by {}", key)));
+        }
+        let from = next_batch
+            .map(|token| {
+                SearchPosition::parse(token)
+                    .filter(|p| p.order() == criteria.order_by)
+                    .ok_or_else(|| ApiError::validation("Invalid next_batch token"))
+            })
+            .transpose()?;
+
+        let filter = criteria
+            .filter
+            .clone()
+            .unwrap_or(SyncFilter { limit: None, ..Default::default() });
+        let room_ids: Vec<String> = self
+            .member_storage
+            .get_joined_rooms(user_id)
+            .await?
+            .into_iter()
+            .filter(|room_id| filter.matches_room(room_id))
+            .collect();
+        let page = if room_ids.is_empty() {
+            MessageSearchPage::default()
+        } else {
+            let query = MessageSearchQuery {
+                search_term: criteria.search_term.clone(),
+                keys,
+                room_ids,
+                senders: filter.senders.clone(),
+                not_senders: filter.not_senders.clone().unwrap_or_default(),
+                order_by: criteria.order_by,
+                limit: filter.limit.unwrap_or(DEFAULT_SEARCH_LIMIT).clamp(1, MAX_SEARCH_LIMIT),
+            };
+            self.backend.search(&query, from.as_ref()).await?
+        };
+
+        let event_ids: Vec<String> = page.hits.iter().map(|h| h.event_id.clone()).collect();
+        let mut events: HashMap<String, RoomEvent> = self
+            .event_storage
+            .get_events_by_ids(&event_ids)
+            .await?
+            .into_iter()
+            .map(|e| (e.event_id.clone(), e))
+            .collect();
+
+        let mut results = Vec::new();
+        let mut groups: HashMap<&str, Map<String, Value>> = HashMap::new();
+        for hit in &page.hits {
+            let Some(event) = events.remove(&hit.event_id) else {
+                continue;
+            };
+            let client_event = event.to_client_event();
+            if !filter.matches(&client_event, Some(&event.room_id)) {
+                continue;
+            }
+            for key in &group_by {
+                let group_key = if *key == "room_id" { &event.room_id } else { &event.user_id };
+                let group = groups.entry(key).or_default();
+                let order = group.len() + 1;
+                let entry = group
+                    .entry(group_key.clone())
+                    .or_insert_with(|| json!({ "results": [], "order": order }));
+                if let Some(results) = entry["results"].as_array_mut() {
+                    results.push(json!(event.event_id));
+                }
+            }
+            let mut result = json!({ "rank": hit.rank, "result": client_event });
+            if let Some(context) = &criteria.event_context {
+                result["context"] = self.event_context(&event, context).await?;
+            }
+            results.push(result);
+        }
+
+        let mut room_events = json!({
+            "count": page.count,
+            "results": results,
+            "highlights": page.highlights,
+        });
+        if !group_by.is_empty() {
+            room_events["groups"] = json!(groups);
+        }
+        if let Some(next) = &page.next {
+            room_events["next_batch"] = json!(next.to_token());
+        }
+        Ok(room_events)
+    }
+
+    async fn event_context(&self, event: &RoomEvent, request: &EventContextRequest) -> ApiResult<Value> {
+        let before = self
+            .event_storage
+            .get_events_before(&event.room_id, event.origin_server_ts, request.before_limit.clamp(0, MAX_CONTEXT_LIMIT))
+            .await?;
+        let after = self
+            .event_storage
+            .get_events_after(&event.room_id, event.origin_server_ts, request.after_limit.clamp(0, MAX_CONTEXT_LIMIT))
+            .await?;
+        let start = before.last().map_or(event.origin_server_ts, |e| e.origin_server_ts);
+        let end = after.last().map_or(event.origin_server_ts, |e| e.origin_server_ts);
+
+        let mut context = json!({
+            "events_before": before.iter().map(RoomEvent::to_client_event).collect::<Vec<_>>(),
+            "events_after": after.iter().map(RoomEvent::to_client_event).collect::<Vec<_>>(),
+            "start": format!("t{}", start),
+            "end": format!("t{}", end),
+        });
+        if request.include_profile {
+            let senders: BTreeSet<&str> = std::iter::once(event)
+                .chain(&before)
+                .chain(&after)
+                .map(|e| e.user_id.as_str())
+                .collect();
+            let mut profile_info = Map::new();
+            for sender in senders {
+                if let Some(member) = self.member_storage.get_member(&event.room_id, sender).await? {
+                    profile_info.insert(
+                        sender.to_string(),
+                        json!({ "displayname": member.display_name, "avatar_url": member.avatar_url }),
+                    );
+                }
+            }
+            context["profile_info"] = Value::Object(profile_info);
+        }
+        Ok(context)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_words_are_prefixes_joined_by_and() {
+        assert_eq!(to_tsquery("Hello  world").as_deref(), Some("(hello:*) & (world:*)"));
+        assert_eq!(to_tsquery("e-mail").as_deref(), Some("(e:* & mail:*)"));
+    }
+
+    #[test]
+    fn test_or_phrases_and_negation() {
+        assert_eq!(to_tsquery("cats OR dogs").as_deref(), Some("(cats:*) | (dogs:*)"));
+        assert_eq!(
+            to_tsquery("\"big cat\" -dog").as_deref(),
+            Some("(big <-> cat) & !(dog)")
+        );
+        assert_eq!(to_tsquery("\"unterminated phrase").as_deref(), Some("(unterminated <-> phrase)"));
+    }
+
+    #[test]
+    fn test_nothing_searchable() {
+        assert_eq!(to_tsquery(""), None);
+        assert_eq!(to_tsquery("or -  !!! \"\""), None);
+        assert_eq!(to_tsquery("or cats").as_deref(), Some("(cats:*)"));
+    }
+
+    #[test]
+    fn test_position_tokens_round_trip() {
+        for position in [
+            SearchPosition::Rank(20),
+            SearchPosition::Recent(1234, "$abc_def:example.com".to_string()),
+        ] {
+            assert_eq!(SearchPosition::parse(&position.to_token()), Some(position));
+        }
+        assert_eq!(SearchPosition::parse("rank_-1"), None);
+        assert_eq!(SearchPosition::parse("recent_12"), None);
+        assert_eq!(SearchPosition::parse("t123"), None);
+    }
+
+    #[test]
+    fn test_highlighted_words() {
+        let words = highlighted_words(["<em>Cats</em> and <em>cats</em>", "big <em>dog</em>"], "<em>", "</em>");
+        assert_eq!(words, vec!["cats", "dog"]);
+    }
+
+    #[test]
+    fn test_criteria_defaults() {
+        let criteria: RoomEventsCriteria = serde_json::from_value(json!({
+            "search_term": "hello",
+            "event_context": {}
+        }))
+        .unwrap();
+        assert_eq!(criteria.order_by, SearchOrder::Rank);
+        let context = criteria.event_context.unwrap();
+        assert_eq!((context.before_limit, context.after_limit), (5, 5));
+        assert!(!context.include_profile);
+    }
+}
@@ -3,6 +3,8 @@ use crate::common::constants::BURN_AFTER_READ_DELAY_SECS;
 use crate::common::task_queue::RedisTaskQueue;
 use crate::common::validation::Validator;
 use crate::common::{generate_event_id, generate_room_id};
+use crate::federation::event_builder::DEFAULT_ROOM_VERSION;
+use crate::federation::pdu;
 use crate::services::*;
 use crate::storage::CreateEventParams;
 use crate::storage::UserStorage;
@@ -22,6 +24,11 @@ pub struct CreateRoomConfig {
     pub encryption: Option<String>,
     pub history_visibility: Option<String>,
     pub is_direct: Option<bool>,
+    pub room_version: Option<String>,
+    /// Extra `m.room.create` content, such as `type: m.space`.
+    pub creation_content: Option<serde_json::Value>,
+    /// State events, as `{type, state_key, content}`, sent after those of the preset.
+    pub initial_state: Option<Vec<serde_json::Value>>,
 }
 
 pub struct RoomService {
@@ -101,6 +108,17 @@ impl RoomService {
             }
         }
 
+        let room_version = config
+            .room_version
+            .clone()
+            .unwrap_or_else(|| DEFAULT_ROOM_VERSION.to_string());
+        if !pdu::is_supported_room_version(&room_version) {
+            return Err(ApiError::unsupported_room_version(format!(
+                "Room version {} is not supported",
+                room_version
+            )));
+        }
+
         let room_id = self.generate_room_id();
         let mut join_rule = self.determine_join_rule(config.preset.as_deref());
         let is_public = self.is_public_visibility(config.visibility.as_deref());
@@ -114,56 +132,94 @@ impl RoomService {
         let mut tx = self.room_storage.pool.begin().await
             .map_err(|e| ApiError::internal(format!("Failed to start transaction: {}", e)))?;
 
-        self.create_room_in_db(&room_id, user_id, join_rule, is_public, Some(&mut tx))
+        self.create_room_in_db(&room_id, user_id, join_rule, &room_version, is_public, Some(&mut tx))
             .await?;
-        self.add_creator_to_room(&room_id, user_id, Some(&mut tx)).await?;
         self.set_room_metadata(&room_id, config.name.as_deref(), config.topic.as_deref(), Some(&mut tx))
             .await?;
-        self.process_invites(&room_id, config.invite_list.as_ref(), Some(&mut tx))
-            .await?;
 
-        // Handle trusted private chat specific logic
+        // The initial state in the order the spec gives it, each event authorised by those
+        // before it: create, the creator's join, power levels, the preset's rules, then the
+        // requested state, name, topic and invites
+        let mut create_content = config.creation_content.clone().unwrap_or_else(|| json!({}));
+        create_content["creator"] = json!(user_id);
+        create_content["room_version"] = json!(room_version);
+        let mut events = vec![
+            self.send_initial_state(&mut tx, &room_id, user_id, "m.room.create", create_content)
+                .await?,
+        ];
+        let creator_content = self.membership_content(user_id, "join", None).await?;
+        events.push(
+            self.send_membership(&room_id, user_id, user_id, creator_content, Some(&mut tx))
+                .await?,
+        );
+
+        let mut initial_state = vec![
+            (
+                "m.room.power_levels".to_string(),
+                String::new(),
+                self.initial_power_levels(user_id, config.preset.as_deref(), config.invite_list.as_deref()),
+            ),
+            ("m.room.join_rules".to_string(), String::new(), json!({ "join_rule": join_rule })),
+        ];
+        let history_visibility = match (config.history_visibility.as_deref(), is_trusted_private) {
+            (Some(history_visibility), _) => history_visibility,
+            (None, true) => "invited",
+            (None, false) => "shared",
+        };
+        initial_state.push((
+            "m.room.history_visibility".to_string(),
+            String::new(),
+            json!({ "history_visibility": history_visibility }),
+        ));
         if is_trusted_private {
-            // Set history visibility to invited
-            let now = chrono::Utc::now().timestamp_millis();
-            let history_content = json!({ "history_visibility": "invited" });
-            self.event_storage.create_event(CreateEventParams {
-                event_id: generate_event_id(&self.server_name),
-                room_id: room_id.clone(),
-                user_id: user_id.to_string(),
-                event_type: "m.room.history_visibility".to_string(),
-                content: history_content,
-                state_key: Some("".to_string()),
-                origin_server_ts: now,
-            }, Some(&mut tx)).await.map_err(|e| ApiError::internal(format!("Failed to set history visibility: {}", e)))?;
-
-            // Set guest access to forbidden
-            let guest_content = json!({ "guest_access": "forbidden" });
-            self.event_storage.create_event(CreateEventParams {
-                event_id: generate_event_id(&self.server_name),
-                room_id: room_id.clone(),
-                user_id: user_id.to_string(),
-                event_type: "m.room.guest_access".to_string(),
-                content: guest_content,
-                state_key: Some("".to_string()),
-                origin_server_ts: now,
-            }, Some(&mut tx)).await.map_err(|e| ApiError::internal(format!("Failed to set guest access: {}", e)))?;
-
-            // Set privacy marker for anti-screenshot
-            let privacy_content = json!({ "action": "block_screenshot" });
-            self.event_storage.create_event(CreateEventParams {
-                event_id: generate_event_id(&self.server_name),
-                room_id: room_id.clone(),
-                user_id: user_id.to_string(),
-                event_type: "com.hula.privacy".to_string(),
-                content: privacy_content,
-                state_key: Some("".to_string()),
-                origin_server_ts: now,
-            }, Some(&mut tx)).await.map_err(|e| ApiError::internal(format!("Failed to set privacy marker: {}", e)))?;
+            initial_state.push(("m.room.guest_access".to_string(), String::new(), json!({ "guest_access": "forbidden" })));
+            // Privacy marker for anti-screenshot
+            initial_state.push(("com.hula.privacy".to_string(), String::new(), json!({ "action": "block_screenshot" })));
         }
+        if let Some(algorithm) = &config.encryption {
+            initial_state.push(("m.room.encryption".to_string(), String::new(), json!({ "algorithm": algorithm })));
+        }
+        for event in config.initial_state.iter().flatten() {
+            let event_type = event.get("type").and_then(|v| v.as_str()).unwrap_or_default();
+            // The events above that the room's authorisation rests on cannot be replaced here
+            if matches!(event_type, "" | "m.room.create" | "m.room.member" | "m.room.power_levels") {
+                continue;
+            }
+            let state_key = event.get("state_key").and_then(|v| v.as_str()).unwrap_or_default();
+            let content = event.get("content").cloned().unwrap_or_else(|| json!({}));
+            initial_state.push((event_type.to_string(), state_key.to_string(), content));
+        }
+        if let Some(name) = &config.name {
+            initial_state.push(("m.room.name".to_string(), String::new(), json!({ "name": name })));
+        }
+        if let Some(topic) = &config.topic {
+            initial_state.push(("m.room.topic".to_string(), String::new(), json!({ "topic": topic })));
+        }
+        for (event_type, state_key, content) in initial_state {
+            events.push(
+                self.create_event(CreateEventParams {
+                    event_id: generate_event_id(&self.server_name),
+                    room_id: room_id.clone(),
+                    user_id: user_id.to_string(),
+                    event_type,
+                    content,
+                    state_key: Some(state_key),
+                    origin_server_ts: chrono::Utc::now().timestamp_millis(),
+                }, Some(&mut tx))
+                .await?,
+            );
+        }
+        events.extend(
+            self.process_invites(&room_id, user_id, config.invite_list.as_ref(), &mut tx)
+                .await?,
+        );
 
         tx.commit().await.map_err(|e| ApiError::internal(format!("Failed to commit transaction: {}", e)))?;
 
+        for event in &events {
+            self.event_storage.event_persisted(event).await;
+        }
+
         let room_alias = self.format_room_alias(config.room_alias_name.as_deref());
         Ok(self.build_room_response(&room_id, room_alias))
     }
@@ -183,58 +239,125 @@ impl RoomService {
         visibility.unwrap_or("private") == "public"
     }
 
+    /// Power levels giving the creator, and for `trusted_private_chat` everyone invited at
+    /// creation, full control. Anyone may invite unless the room is public.
+    fn initial_power_levels(
+        &self,
+        creator: &str,
+        preset: Option<&str>,
+        invite_list: Option<&[String]>,
+    ) -> serde_json::Value {
+        let mut users = serde_json::Map::new();
+        users.insert(creator.to_string(), json!(100));
+        if preset == Some("trusted_private_chat") {
+            for invitee in invite_list.unwrap_or_default() {
+                users.insert(invitee.clone(), json!(100));
+            }
+        }
+        json!({
+            "users": users,
+            "users_default": 0,
+            "events": {
+                "m.room.name": 50,
+                "m.room.power_levels": 100,
+                "m.room.history_visibility": 100,
+                "m.room.canonical_alias": 50,
+                "m.room.avatar": 50,
+                "m.room.tombstone": 100,
+                "m.room.server_acl": 100,
+                "m.room.encryption": 100
+            },
+            "events_default": 0,
+            "state_default": 50,
+            "ban": 50,
+            "kick": 50,
+            "redact": 50,
+            "invite": if preset == Some("public_chat") { 50 } else { 0 }
+        })
+    }
+
     async fn create_room_in_db(
         &self,
         room_id: &str,
         user_id: &str,
         join_rule: &str,
+        room_version: &str,
         is_public: bool,
         tx: Option<&mut sqlx::Transaction<'_, sqlx::Postgres>>,
     ) -> ApiResult<()> {
         self.room_storage
-            .create_room(room_id, user_id, join_rule, "1", is_public, tx)
+            .create_room(room_id, user_id, join_rule, room_version, is_public, tx)
             .await
             .map(|_| ())
             .map_err(|e| ApiError::internal(format!("Failed to create room: {}", e)))
     }
 
-    async fn add_creator_to_room(&self, room_id: &str, user_id: &str, tx: Option<&mut sqlx::Transaction<'_, sqlx::Postgres>>) -> ApiResult<()> {
+    async fn send_initial_state(
+        &self,
+        tx: &mut sqlx::Transaction<'_, sqlx::Postgres>,
+        room_id: &str,
+        user_id: &str,
+        event_type: &str,
+        content: serde_json::Value,
+    ) -> ApiResult<crate::storage::RoomEvent> {
+        self.create_event(CreateEventParams {
+            event_id: generate_event_id(&self.server_name),
+            room_id: room_id.to_string(),
+            user_id: user_id.to_string(),
+            event_type: event_type.to_string(),
+            content,
+            state_key: Some(String::new()),
+            origin_server_ts: chrono::Utc::now().timestamp_millis(),
+        }, Some(tx))
+        .await
+    }
+
+    /// Sends the member event `sender` sets for `target` and records the membership under its
+    /// event ID, in `tx` when given and otherwise in a transaction of its own.
+    pub async fn send_membership(
+        &self,
+        room_id: &str,
+        sender: &str,
+        target: &str,
+        content: serde_json::Value,
+        tx: Option<&mut sqlx::Transaction<'_, sqlx::Postgres>>,
+    ) -> ApiResult<crate::storage::RoomEvent> {
+        if let Some(tx) = tx {
+            return self.insert_membership(tx, room_id, sender, target, content).await;
+        }
+        let mut tx = self.room_storage.pool.begin().await
+            .map_err(|e| ApiError::internal(format!("Failed to start transaction: {}", e)))?;
+        let event = self.insert_membership(&mut tx, room_id, sender, target, content).await?;
+        tx.commit().await.map_err(|e| ApiError::internal(format!("Failed to commit transaction: {}", e)))?;
+        self.event_storage.event_persisted(&event).await;
+        Ok(event)
+    }
+
+    async fn insert_membership(
+        &self,
+        tx: &mut sqlx::Transaction<'_, sqlx::Postgres>,
+        room_id: &str,
+        sender: &str,
+        target: &str,
+        content: serde_json::Value,
+    ) -> ApiResult<crate::storage::RoomEvent> {
+        let membership = content["membership"].as_str().unwrap_or("leave").to_string();
+        let display_name = content["displayname"].as_str().map(|s| s.to_string());
+        let event = self.create_event(CreateEventParams {
+            event_id: generate_event_id(&self.server_name),
+            room_id: room_id.to_string(),
+            user_id: sender.to_string(),
+            event_type: "m.room.member".to_string(),
+            content,
+            state_key: Some(target.to_string()),
+            origin_server_ts: chrono::Utc::now().timestamp_millis(),
+        }, Some(&mut *tx))
+        .await?;
         self.member_storage
-            .add_member(room_id, user_id, "join", None, None, tx)
+            .add_member(room_id, target, &membership, &event.event_id, display_name.as_deref(), None, Some(tx))
             .await
-            .map_err(|e| ApiError::internal(format!("Failed to add room member: {}", e)))?;
-
-        // Note: increment_member_count doesn't support transaction yet, but it's a simple update.
-        // Ideally it should also be transactional. 
-        // For now we skip transaction for this or need to update RoomStorage again.
-        // Actually create_room sets member_count to 1 initially. 
-        // add_creator_to_room is called right after create_room.
-        // So we don't need to increment it if create_room sets it to 1?
-        // Let's check create_room impl. It sets member_count to 1.
-        // So increment_member_count here would make it 2? 
-        // No, create_room sets it to 1. If we add member, it's 1 member.
-        // The original code called increment_member_count after add_member.
-        // If create_room sets it to 1, and increment adds 1, it becomes 2. That seems wrong for 1 creator.
-        // Let's assume create_room initializes to 1 (creator joined), and we add the membership event.
-        // So we might not need to increment if create_room already set it to 1.
-        // However, to keep behavior consistent with previous logic:
-        // Previous logic: create_room (sets 1) -> add_member -> increment (becomes 2??)
-        // Wait, let's check create_room SQL.
-        // VALUES (..., 1, ...) -> member_count is 1.
-        // increment_member_count: member_count = member_count + 1.
-        // So it becomes 2. This seems like a bug in original code or my understanding.
-        // Usually creator is the first member.
-        // Let's assume we don't need to increment if we just created it with count 1.
-        
-        // But to be safe and strictly follow previous logic, we should probably not change behavior 
-        // unless we are sure.
-        // However, since we can't pass tx to increment_member_count easily without updating it,
-        // and create_room sets it to 1, let's verify if we need to update it.
-        // If I remove increment_member_count, I fix a potential bug where count starts at 2.
-        // If I keep it, I need to make it transactional.
-        
-        // Let's leave it out for now as create_room sets it to 1 which is correct for 1 member.
-        Ok(())
+            .map_err(|e| ApiError::internal(format!("Failed to update membership: {}", e)))?;
+        Ok(event)
     }
 
     async fn set_room_metadata(
@@ -279,47 +402,33 @@ impl RoomService {
         Ok(())
     }
 
+    /// Invites the local users named at creation; the others are invited over federation once
+    /// the room exists.
     async fn process_invites(
         &self,
         room_id: &str,
+        inviter: &str,
         invite_list: Option<&Vec<String>>,
-        mut tx: Option<&mut sqlx::Transaction<'_, sqlx::Postgres>>,
-    ) -> ApiResult<()> {
+        tx: &mut sqlx::Transaction<'_, sqlx::Postgres>,
+    ) -> ApiResult<Vec<crate::storage::RoomEvent>> {
+        let mut events = Vec::new();
         if let Some(invites) = invite_list {
             let existing_users = self.user_storage.filter_existing_users(invites).await.map_err(|e| {
                 ApiError::internal(format!("Failed to check users existence: {}", e))
             })?;
-            
-            // We need to handle tx carefully.
-            // If we have a transaction, we need to pass a mutable reference to it for each iteration.
-            // But Option<&mut T> is not Copy.
-            
-            // If tx is Some, we extract the inner mutable reference, and we can reborrow it.
-            if let Some(ref mut t) = tx {
-                for invitee in invites {
-                     if !existing_users.contains(invitee) {
-                        ::tracing::warn!("Skipping invite for non-existent user: {}", invitee);
-                        continue;
-                    }
-                    // We need to reborrow *t which is &mut Transaction
-                    self.member_storage
-                        .add_member(room_id, invitee, "invite", None, None, Some(&mut **t))
-                        .await
-                        .map_err(|e| ApiError::internal(format!("Failed to invite user: {}", e)))?;
-                }
-            } else {
-                 for invitee in invites {
-                    if !existing_users.contains(invitee) {
-                        continue;
-                    }
-                    self.member_storage
-                        .add_member(room_id, invitee, "invite", None, None, None)
-                        .await
-                        .map_err(|e| ApiError::internal(format!("Failed to invite user: {}", e)))?;
+
+            for invitee in invites {
+                if !existing_users.contains(invitee) {
+                    ::tracing::warn!("Skipping invite for non-existent user: {}", invitee);
+                    continue;
                 }
+                events.push(
+                    self.send_membership(room_id, inviter, invitee, json!({ "membership": "invite" }), Some(&mut *tx))
+                        .await?,
+                );
             }
         }
-        Ok(())
+        Ok(events)
     }
 
     fn format_room_alias(&self, room_alias_name: Option<&str>) -> Option<String> {
@@ -351,7 +460,6 @@ impl RoomService {
             ));
         }
 
-        let event_id = generate_event_id(&self.server_name);
         let now = chrono::Utc::now().timestamp_millis();
 
         let event_content = json!({
@@ -359,9 +467,10 @@ impl RoomService {
             "body": content
         });
 
-        self.event_storage
+        let event = self
+            .event_storage
             .create_event(CreateEventParams {
-                event_id: event_id.clone(),
+                event_id: generate_event_id(&self.server_name),
                 room_id: room_id.to_string(),
                 user_id: user_id.to_string(),
                 event_type: "m.room.message".to_string(),
@@ -373,7 +482,45 @@ impl RoomService {
             .map_err(|e| ApiError::internal(format!("Failed to send message: {}", e)))?;
 
         Ok(json!({
-            "event_id": event_id
+            "event_id": event.event_id
+        }))
+    }
+
+    /// Sends a non-state event with the content as given by the client.
+    pub async fn send_event(
+        &self,
+        room_id: &str,
+        user_id: &str,
+        event_type: &str,
+        content: serde_json::Value,
+    ) -> ApiResult<serde_json::Value> {
+        if !self
+            .member_storage
+            .is_member(room_id, user_id)
+            .await
+            .map_err(|e| ApiError::internal(format!("Failed to check membership: {}", e)))?
+        {
+            return Err(ApiError::forbidden(
+                "You are not a member of this room".to_string(),
+            ));
+        }
+
+        let event = self
+            .event_storage
+            .create_event(CreateEventParams {
+                event_id: generate_event_id(&self.server_name),
+                room_id: room_id.to_string(),
+                user_id: user_id.to_string(),
+                event_type: event_type.to_string(),
+                content,
+                state_key: None,
+                origin_server_ts: chrono::Utc::now().timestamp_millis(),
+            }, None)
+            .await
+            .map_err(|e| ApiError::internal(format!("Failed to send event: {}", e)))?;
+
+        Ok(json!({
+            "event_id": event.event_id
         }))
     }
 
@@ -413,7 +560,7 @@ impl RoomService {
             }
             Some("invite") => {
             }
-            Some("leave") | None => {
+            Some("leave") | Some("knock") | None => {
                 let room = self
                     .room_storage
                     .get_room(room_id)
@@ -421,8 +568,30 @@ impl RoomService {
                     .map_err(|e| ApiError::internal(format!("Failed to get room: {}", e)))?;
 
                 if let Some(room) = room {
-                    let join_rule = room.join_rule.as_str();
-                    if join_rule != "public" && join_rule != "knock" {
+                    // Join rules sent as state take precedence over the room's creation setting.
+                    let join_rules = self
+                        .event_storage
+                        .get_state_event(room_id, "m.room.join_rules", "")
+                        .await
+                        .map_err(|e| ApiError::internal(format!("Failed to get join rules: {}", e)))?
+                        .map(|event| event.content);
+                    let join_rule = join_rules
+                        .as_ref()
+                        .and_then(|content| content["join_rule"].as_str())
+                        .unwrap_or(room.join_rule.as_str());
+                    // Knock rooms are joined once a knock has been answered with an invite
+                    let allowed = match join_rule {
+                        "public" => true,
+                        "restricted" | "knock_restricted" => {
+                            self.is_member_of_any(
+                                user_id,
+                                &join_rules.as_ref().map(crate::spaces::allowed_room_ids).unwrap_or_default(),
+                            )
+                            .await?
+                        }
+                        _ => false,
+                    };
+                    if !allowed {
                         return Err(ApiError::forbidden(
                             "You do not have permission to join this room".to_string(),
                         ));
@@ -432,10 +601,8 @@ impl RoomService {
             _ => {}
         }
 
-        self.member_storage
-            .add_member(room_id, user_id, "join", None, None, None)
-            .await
-            .map_err(|e| ApiError::internal(format!("Failed to join room: {}", e)))?;
+        let content = self.membership_content(user_id, "join", None).await?;
+        self.send_membership(room_id, user_id, user_id, content, None).await?;
 
         self.room_storage
             .increment_member_count(room_id)
@@ -445,6 +612,69 @@ impl RoomService {
         Ok(())
     }
 
+    /// Whether the user is joined to one of `room_ids`, as a `restricted` join rule requires.
+    async fn is_member_of_any(&self, user_id: &str, room_ids: &[String]) -> ApiResult<bool> {
+        for room_id in room_ids {
+            if self
+                .member_storage
+                .is_member(room_id, user_id)
+                .await
+                .map_err(|e| ApiError::internal(format!("Failed to check membership: {}", e)))?
+            {
+                return Ok(true);
+            }
+        }
+        Ok(false)
+    }
+
+    /// Whether membership changes in `room_id` have to go through another server: the room
+    /// was not created here and none of our users is joined, so we do not hold its state.
+    pub async fn needs_remote_membership(&self, room_id: &str) -> ApiResult<bool> {
+        if self
+            .member_storage
+            .has_local_joined_members(room_id)
+            .await
+            .map_err(|e| ApiError::internal(format!("Failed to check membership: {}", e)))?
+        {
+            return Ok(false);
+        }
+
+        let room = self
+            .room_storage
+            .get_room(room_id)
+            .await
+            .map_err(|e| ApiError::internal(format!("Failed to get room: {}", e)))?;
+        let local_suffix = format!(":{}", self.server_name);
+        Ok(room.is_none_or(|room| !room.creator.ends_with(&local_suffix)))
+    }
+
+    /// The content of a membership event sent on a user's behalf, carrying their profile.
+    pub async fn membership_content(
+        &self,
+        user_id: &str,
+        membership: &str,
+        reason: Option<&str>,
+    ) -> ApiResult<serde_json::Value> {
+        let user = self
+            .user_storage
+            .get_user_by_id(user_id)
+            .await
+            .map_err(|e| ApiError::internal(format!("Failed to get user: {}", e)))?
+            .ok_or_else(|| ApiError::not_found("User not found".to_string()))?;
+
+        let mut content = json!({ "membership": membership });
+        if let Some(displayname) = user.displayname {
+            content["displayname"] = json!(displayname);
+        }
+        if let Some(avatar_url) = user.avatar_url {
+            content["avatar_url"] = json!(avatar_url);
+        }
+        if let Some(reason) = reason {
+            content["reason"] = json!(reason);
+        }
+        Ok(content)
+    }
+
     pub async fn leave_room(&self, room_id: &str, user_id: &str) -> ApiResult<()> {
         self.member_storage
             .remove_member(room_id, user_id)
@@ -648,10 +878,8 @@ impl RoomService {
             return Err(ApiError::not_found("User not found".to_string()));
         }
 
-        self.member_storage
-            .add_member(room_id, invitee_id, "invite", None, Some(inviter_id), None)
-            .await
-            .map_err(|e| ApiError::internal(format!("Failed to create invite event: {}", e)))?;
+        self.send_membership(room_id, inviter_id, invitee_id, json!({ "membership": "invite" }), None)
+            .await?;
         Ok(())
     }
 
@@ -839,6 +1067,13 @@ impl RoomService {
             ));
         }
 
+        if !pdu::is_supported_room_version(new_version) {
+            return Err(ApiError::unsupported_room_version(format!(
+                "Room version {} is not supported",
+                new_version
+            )));
+        }
+
         let new_room_id = format!("!{}:{}", uuid::Uuid::new_v4(), self.server_name);
 
         self.room_storage
@@ -861,17 +1096,15 @@ impl RoomService {
 
         for member in members {
             if member.user_id != user_id {
+                let content = json!({ "membership": "join" });
                 let _ = self
-                    .member_storage
-                    .add_member(&new_room_id, &member.user_id, "join", None, None, None)
+                    .send_membership(&new_room_id, &member.user_id, &member.user_id, content, None)
                     .await;
             }
         }
 
-        self.member_storage
-            .add_member(&new_room_id, user_id, "join", None, None, None)
-            .await
-            .map_err(|e| ApiError::internal(format!("Failed to add creator to new room: {}", e)))?;
+        self.send_membership(&new_room_id, user_id, user_id, json!({ "membership": "join" }), None)
+            .await?;
 
         self.room_storage
             .set_room_tombstone(room_id, &new_room_id)
@@ -947,17 +1180,19 @@ impl RoomService {
             .map_err(|e| ApiError::internal(format!("Failed to create event: {}", e)))
     }
 
+    #[allow(clippy::too_many_arguments)]
     pub async fn add_member(
         &self,
         room_id: &str,
         user_id: &str,
         membership: &str,
+        event_id: &str,
         display_name: Option<&str>,
         join_reason: Option<&str>,
         tx: Option<&mut sqlx::Transaction<'_, sqlx::Postgres>>,
     ) -> ApiResult<crate::storage::RoomMember> {
         self.member_storage
-            .add_member(room_id, user_id, membership, display_name, join_reason, tx)
+            .add_member(room_id, user_id, membership, event_id, display_name, join_reason, tx)
             .await
             .map_err(|e| ApiError::internal(format!("Failed to add member: {}", e)))
     }
@@ -1,4 +1,9 @@
 use crate::common::*;
+use crate::services::room_search_service::{
+    highlighted_words, MessageSearchBackend, MessageSearchHit, MessageSearchPage,
+    MessageSearchQuery, SearchOrder, SearchPosition,
+};
+use async_trait::async_trait;
 use elasticsearch::{
     http::transport::Transport,
     Elasticsearch, SearchParts,
@@ -332,3 +337,101 @@ impl SearchService {
         self.enabled
     }
 }
+
+/// Room event search on the `events` index, whose documents carry `event_id`, `room_id`,
+/// `sender`, `stream_ordering` and the searchable `content` fields.
+#[async_trait]
+impl MessageSearchBackend for SearchService {
+    async fn search(
+        &self,
+        query: &MessageSearchQuery,
+        from: Option<&SearchPosition>,
+    ) -> ApiResult<MessageSearchPage> {
+        if !self.enabled {
+            return Err(ApiError::internal("Elasticsearch is disabled".to_string()));
+        }
+        let client = self.client.as_ref()
+            .ok_or_else(|| ApiError::internal("Elasticsearch client not initialized".to_string()))?;
+
+        let mut filter_clauses = vec![json!({ "terms": { "room_id": query.room_ids } })];
+        if let Some(senders) = &query.senders {
+            filter_clauses.push(json!({ "terms": { "sender": senders } }));
+        }
+        let sort = match query.order_by {
+            SearchOrder::Rank => json!(["_score", { "stream_ordering": "desc" }, { "event_id": "desc" }]),
+            SearchOrder::Recent => json!([{ "stream_ordering": "desc" }, { "event_id": "desc" }]),
+        };
+        let highlight_fields: serde_json::Map<String, Value> =
+            query.keys.iter().map(|key| (key.clone(), json!({}))).collect();
+        let mut search_body = json!({
+            "query": {
+                "bool": {
+                    "must": [{ "multi_match": { "query": query.search_term, "fields": query.keys } }],
+                    "filter": filter_clauses,
+                    "must_not": [{ "terms": { "sender": query.not_senders } }]
+                }
+            },
+            "size": query.limit,
+            "sort": sort,
+            "track_scores": true,
+            "track_total_hits": true,
+            "highlight": {
+                "fields": highlight_fields,
+                "pre_tags": ["<em>"],
+                "post_tags": ["</em>"]
+            }
+        });
+        let offset = match from {
+            Some(SearchPosition::Rank(offset)) => *offset,
+            Some(SearchPosition::Recent(stream, event_id)) => {
+                search_body["search_after"] = json!([stream, event_id]);
+                0
+            }
+            None => 0,
+        };
+        search_body["from"] = json!(offset);
+
+        let response = client
+            .search(SearchParts::Index(&["events"]))
+            .body(search_body)
+            .send()
+            .await
+            .map_err(|e| ApiError::internal(format!("ES search error: {}", e)))?;
+
+        let body: Value = response
+            .json()
+            .await
+            .map_err(|e| ApiError::internal(format!("Failed to parse ES response: {}", e)))?;
+
+        let hits = body["hits"]["hits"].as_array().cloned().unwrap_or_default();
+        let mut page = MessageSearchPage {
+            count: body["hits"]["total"]["value"].as_i64().unwrap_or(0),
+            ..Default::default()
+        };
+        let mut snippets = Vec::new();
+        let mut last = None;
+        for hit in &hits {
+            let source = &hit["_source"];
+            let (Some(event_id), Some(room_id)) = (source["event_id"].as_str(), source["room_id"].as_str()) else {
+                continue;
+            };
+            if let Some(highlight) = hit["highlight"].as_object() {
+                snippets.extend(highlight.values().filter_map(|v| v.as_array()).flatten().filter_map(|v| v.as_str()));
+            }
+            last = Some((source["stream_ordering"].as_i64().unwrap_or(0), event_id.to_string()));
+            page.hits.push(MessageSearchHit {
+                event_id: event_id.to_string(),
+                room_id: room_id.to_string(),
+                rank: hit["_score"].as_f64().unwrap_or(0.0),
+            });
+        }
+        page.highlights = highlighted_words(snippets, "<em>", "</em>");
+        if hits.len() as i64 == query.limit {
+            page.next = match query.order_by {
+                SearchOrder::Rank => Some(SearchPosition::Rank(offset + query.limit)),
+                SearchOrder::Recent => last.map(|(stream, event_id)| SearchPosition::Recent(stream, event_id)),
+            };
+        }
+        Ok(page)
+    }
+}
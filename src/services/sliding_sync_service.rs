@@ -0,0 +1,826 @@
+use crate::common::*;
+use crate::services::*;
+use serde::Deserialize;
+use serde_json::json;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::sync::Arc;
+
+const MAX_TIMELINE_LIMIT: i64 = 100;
+
+/// Request body of the simplified sliding sync (MSC4186) endpoint.
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct SlidingSyncRequest {
+    #[serde(default)]
+    pub conn_id: Option<String>,
+    #[serde(default)]
+    pub lists: BTreeMap<String, SlidingSyncList>,
+    #[serde(default)]
+    pub room_subscriptions: BTreeMap<String, RoomSubscription>,
+    #[serde(default)]
+    pub extensions: SlidingSyncExtensions,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Deserialize)]
+pub struct RoomSubscription {
+    #[serde(default)]
+    pub required_state: Vec<(String, String)>,
+    #[serde(default)]
+    pub timeline_limit: i64,
+}
+
+impl RoomSubscription {
+    /// Combines the configs of every list and subscription a room appears in.
+    pub fn merge(&mut self, other: &RoomSubscription) {
+        for entry in &other.required_state {
+            if !self.required_state.contains(entry) {
+                self.required_state.push(entry.clone());
+            }
+        }
+        self.timeline_limit = self.timeline_limit.max(other.timeline_limit);
+    }
+
+    /// Stable identifier of the config; a room is re-sent from scratch when it changes.
+    pub fn fingerprint(&self) -> String {
+        let mut required_state = self.required_state.clone();
+        required_state.sort();
+        format!("{}|{:?}", self.timeline_limit, required_state)
+    }
+
+    /// Whether a state event is covered by `required_state`. `$LAZY` members are limited to
+    /// `lazy_members`, and `$ME` to the syncing user.
+    pub fn wants_state(
+        &self,
+        event_type: &str,
+        state_key: &str,
+        user_id: &str,
+        lazy_members: &HashSet<&str>,
+    ) -> bool {
+        self.required_state.iter().any(|(wanted_type, wanted_key)| {
+            if wanted_type != "*" && wanted_type != event_type {
+                return false;
+            }
+            match wanted_key.as_str() {
+                "*" => true,
+                "$ME" => state_key == user_id,
+                "$LAZY" => event_type == "m.room.member" && lazy_members.contains(state_key),
+                key => key == state_key,
+            }
+        })
+    }
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct SlidingSyncList {
+    #[serde(default)]
+    pub ranges: Vec<(usize, usize)>,
+    #[serde(flatten)]
+    pub room: RoomSubscription,
+    #[serde(default)]
+    pub filters: Option<SlidingSyncListFilters>,
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct SlidingSyncListFilters {
+    #[serde(default)]
+    pub is_encrypted: Option<bool>,
+    #[serde(default)]
+    pub is_invite: Option<bool>,
+    /// Room types to include; `null` entries stand for rooms without a type.
+    #[serde(default)]
+    pub room_types: Option<Vec<Option<String>>>,
+    #[serde(default)]
+    pub not_room_types: Option<Vec<Option<String>>>,
+}
+
+impl SlidingSyncListFilters {
+    fn needs_state(&self) -> bool {
+        self.is_encrypted.is_some() || self.room_types.is_some() || self.not_room_types.is_some()
+    }
+
+    fn matches(&self, is_invite: bool, state: &[serde_json::Value]) -> bool {
+        if self.is_invite.is_some_and(|wanted| wanted != is_invite) {
+            return false;
+        }
+
+        if let Some(wanted) = self.is_encrypted {
+            let encrypted = state.iter().any(|e| e["type"] == "m.room.encryption");
+            if encrypted != wanted {
+                return false;
+            }
+        }
+
+        let room_type = state
+            .iter()
+            .find(|e| e["type"] == "m.room.create")
+            .and_then(|e| e["content"]["type"].as_str())
+            .map(str::to_string);
+        if self
+            .not_room_types
+            .as_ref()
+            .is_some_and(|types| types.contains(&room_type))
+        {
+            return false;
+        }
+        if self
+            .room_types
+            .as_ref()
+            .is_some_and(|types| !types.contains(&room_type))
+        {
+            return false;
+        }
+
+        true
+    }
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct ExtensionConfig {
+    #[serde(default)]
+    pub enabled: Option<bool>,
+    #[serde(default)]
+    pub since: Option<String>,
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct SlidingSyncExtensions {
+    #[serde(default)]
+    pub to_device: Option<ExtensionConfig>,
+    #[serde(default)]
+    pub e2ee: Option<ExtensionConfig>,
+    #[serde(default)]
+    pub account_data: Option<ExtensionConfig>,
+    #[serde(default)]
+    pub receipts: Option<ExtensionConfig>,
+    #[serde(default)]
+    pub typing: Option<ExtensionConfig>,
+}
+
+fn extension_enabled(extension: &Option<ExtensionConfig>) -> bool {
+    extension
+        .as_ref()
+        .and_then(|e| e.enabled)
+        .unwrap_or(false)
+}
+
+fn token_at(stream_id: i64) -> SyncToken {
+    SyncToken {
+        stream_id,
+        event_stream: None,
+        room_id: None,
+        event_type: None,
+    }
+}
+
+/// Server-side room lists for sliding sync, layered on top of [`SyncService`].
+///
+/// Each `(user, device, conn_id)` connection remembers what it has been sent per room, so a
+/// request carrying a known `pos` only returns what changed since.
+pub struct SlidingSyncService {
+    sync_service: Arc<SyncService>,
+    member_storage: RoomMemberStorage,
+    event_storage: EventStorage,
+    storage: SlidingSyncStorage,
+    notifier: Notifier,
+}
+
+impl SlidingSyncService {
+    pub fn new(
+        sync_service: Arc<SyncService>,
+        member_storage: RoomMemberStorage,
+        event_storage: EventStorage,
+        storage: SlidingSyncStorage,
+    ) -> Self {
+        Self {
+            sync_service,
+            member_storage,
+            event_storage,
+            storage,
+            notifier: Notifier::new(),
+        }
+    }
+
+    pub fn with_notifier(mut self, notifier: Notifier) -> Self {
+        self.notifier = notifier;
+        self
+    }
+
+    pub async fn sync(
+        &self,
+        user_id: &str,
+        device_id: &str,
+        pos: Option<&str>,
+        timeout: u64,
+        request: &SlidingSyncRequest,
+    ) -> ApiResult<serde_json::Value> {
+        let conn_id = request.conn_id.as_deref().unwrap_or_default();
+
+        let (since, room_states) = match pos {
+            Some(pos) => {
+                let since: i64 = pos
+                    .parse()
+                    .map_err(|_| ApiError::unknown_pos(format!("Invalid pos: {}", pos)))?;
+                let states = self
+                    .storage
+                    .get_connection_state(user_id, device_id, conn_id, since)
+                    .await
+                    .map_err(|e| ApiError::internal(format!("Failed to load connection: {}", e)))?
+                    .ok_or_else(|| ApiError::unknown_pos("Unknown position".to_string()))?;
+                (Some(since), states)
+            }
+            None => {
+                self.storage
+                    .delete_connection(user_id, device_id, conn_id)
+                    .await
+                    .map_err(|e| ApiError::internal(format!("Failed to reset connection: {}", e)))?;
+                (None, SlidingRoomStates::new())
+            }
+        };
+
+        let listener = match since {
+            Some(_) if timeout > 0 => {
+                let room_ids = self
+                    .member_storage
+                    .get_joined_rooms(user_id)
+                    .await
+                    .map_err(|e| ApiError::internal(format!("Failed to get rooms: {}", e)))?;
+                Some(self.notifier.listen(user_id, &room_ids))
+            }
+            _ => None,
+        };
+        let deadline = tokio::time::Instant::now() + std::time::Duration::from_millis(timeout);
+
+        loop {
+            let position = listener.as_ref().map(|l| l.position());
+            let (response, new_pos, new_states, has_updates) = self
+                .build_response(user_id, device_id, since, &room_states, request)
+                .await?;
+
+            let done = match (&listener, position) {
+                (Some(listener), Some(position)) => {
+                    has_updates || !listener.wait_for_change(position, deadline).await
+                }
+                _ => true,
+            };
+            if done {
+                self.storage
+                    .save_connection_state(user_id, device_id, conn_id, since, new_pos, &new_states)
+                    .await
+                    .map_err(|e| ApiError::internal(format!("Failed to save connection: {}", e)))?;
+                return Ok(response);
+            }
+        }
+    }
+
+    async fn build_response(
+        &self,
+        user_id: &str,
+        device_id: &str,
+        since: Option<i64>,
+        prev_states: &SlidingRoomStates,
+        request: &SlidingSyncRequest,
+    ) -> ApiResult<(serde_json::Value, i64, SlidingRoomStates, bool)> {
+        // Taken before reading so that anything written during the read is picked up next time
+        let pos = self.notifier.current_position();
+        let since_token = since.map(token_at);
+
+        let mut invites: HashMap<String, bool> = HashMap::new();
+        for room_id in self
+            .member_storage
+            .get_invited_rooms(user_id)
+            .await
+            .map_err(|e| ApiError::internal(format!("Failed to get invites: {}", e)))?
+        {
+            invites.insert(room_id, true);
+        }
+        for room_id in self
+            .member_storage
+            .get_joined_rooms(user_id)
+            .await
+            .map_err(|e| ApiError::internal(format!("Failed to get rooms: {}", e)))?
+        {
+            invites.insert(room_id, false);
+        }
+
+        let mut all_rooms: Vec<String> = invites.keys().cloned().collect();
+        all_rooms.sort();
+        let bump_stamps = self
+            .event_storage
+            .get_rooms_latest_ts(&all_rooms)
+            .await
+            .map_err(|e| ApiError::internal(format!("Failed to get room activity: {}", e)))?;
+        let bump_stamp = |room_id: &str| bump_stamps.get(room_id).copied().unwrap_or(0);
+
+        let mut state_cache: HashMap<String, Vec<serde_json::Value>> = HashMap::new();
+        let mut room_configs: BTreeMap<String, RoomSubscription> = BTreeMap::new();
+        let mut lists = serde_json::Map::new();
+
+        for (name, list) in &request.lists {
+            let mut rooms = Vec::new();
+            for room_id in &all_rooms {
+                let matches = match &list.filters {
+                    Some(filters) if filters.needs_state() => {
+                        let state = self.room_state(&mut state_cache, room_id).await?;
+                        filters.matches(invites[room_id], state)
+                    }
+                    Some(filters) => filters.matches(invites[room_id], &[]),
+                    None => true,
+                };
+                if matches {
+                    rooms.push(room_id.clone());
+                }
+            }
+            rooms.sort_by(|a, b| bump_stamp(b).cmp(&bump_stamp(a)).then_with(|| a.cmp(b)));
+
+            for &(start, end) in &list.ranges {
+                for room_id in rooms.iter().take(end.saturating_add(1)).skip(start) {
+                    room_configs
+                        .entry(room_id.clone())
+                        .or_default()
+                        .merge(&list.room);
+                }
+            }
+            lists.insert(name.clone(), json!({ "count": rooms.len() }));
+        }
+
+        for (room_id, subscription) in &request.room_subscriptions {
+            // Subscriptions never leak rooms the user cannot see
+            if invites.contains_key(room_id) {
+                room_configs
+                    .entry(room_id.clone())
+                    .or_default()
+                    .merge(subscription);
+            }
+        }
+
+        let mut new_states: SlidingRoomStates = prev_states
+            .iter()
+            .filter(|(room_id, _)| invites.contains_key(*room_id))
+            .map(|(room_id, state)| (room_id.clone(), state.clone()))
+            .collect();
+        let mut rooms = serde_json::Map::new();
+
+        for (room_id, config) in &room_configs {
+            let fingerprint = config.fingerprint();
+            let prev = prev_states.get(room_id).filter(|s| s.config == fingerprint);
+            let stamp = bump_stamp(room_id);
+
+            let (room, last_ts) = if invites[room_id] {
+                if prev.is_some() {
+                    continue;
+                }
+                let room = self
+                    .build_invite_room(user_id, room_id, stamp, &mut state_cache)
+                    .await?;
+                (Some(room), stamp)
+            } else {
+                self.build_joined_room(user_id, room_id, config, prev, stamp, &mut state_cache)
+                    .await?
+            };
+
+            if let Some(room) = room {
+                rooms.insert(room_id.clone(), room);
+            }
+            new_states.insert(
+                room_id.clone(),
+                SlidingRoomState {
+                    last_ts,
+                    config: fingerprint,
+                },
+            );
+        }
+
+        let extensions = self
+            .build_extensions(user_id, device_id, pos, &since_token, &room_configs, request)
+            .await?;
+
+        let has_updates = !rooms.is_empty()
+            || extensions.values().any(Self::extension_has_updates);
+
+        let response = json!({
+            "pos": pos.to_string(),
+            "lists": lists,
+            "rooms": rooms,
+            "extensions": extensions
+        });
+        Ok((response, pos, new_states, has_updates))
+    }
+
+    async fn room_state<'a>(
+        &self,
+        cache: &'a mut HashMap<String, Vec<serde_json::Value>>,
+        room_id: &str,
+    ) -> ApiResult<&'a Vec<serde_json::Value>> {
+        if !cache.contains_key(room_id) {
+            let state = self.sync_service.get_room_state_events(room_id).await?;
+            cache.insert(room_id.to_string(), state);
+        }
+        Ok(&cache[room_id])
+    }
+
+    fn room_summary(state: &[serde_json::Value], room: &mut serde_json::Value) {
+        for event in state {
+            match event["type"].as_str() {
+                Some("m.room.name") => {
+                    if let Some(name) = event["content"]["name"].as_str() {
+                        room["name"] = json!(name);
+                    }
+                }
+                Some("m.room.avatar") => {
+                    if let Some(url) = event["content"]["url"].as_str() {
+                        room["avatar"] = json!(url);
+                    }
+                }
+                _ => {}
+            }
+        }
+    }
+
+    async fn build_invite_room(
+        &self,
+        user_id: &str,
+        room_id: &str,
+        bump_stamp: i64,
+        state_cache: &mut HashMap<String, Vec<serde_json::Value>>,
+    ) -> ApiResult<serde_json::Value> {
+        const STRIPPED_STATE_TYPES: [&str; 6] = [
+            "m.room.create",
+            "m.room.name",
+            "m.room.avatar",
+            "m.room.topic",
+            "m.room.join_rules",
+            "m.room.encryption",
+        ];
+
+        let state = self.room_state(state_cache, room_id).await?;
+        let invite_state: Vec<serde_json::Value> = state
+            .iter()
+            .filter(|e| {
+                let event_type = e["type"].as_str().unwrap_or_default();
+                STRIPPED_STATE_TYPES.contains(&event_type)
+                    || (event_type == "m.room.member" && e["state_key"] == user_id)
+            })
+            .map(|e| {
+                json!({
+                    "type": e["type"],
+                    "state_key": e["state_key"],
+                    "sender": e["sender"],
+                    "content": e["content"]
+                })
+            })
+            .collect();
+
+        let mut room = json!({
+            "initial": true,
+            "invite_state": invite_state,
+            "bump_stamp": bump_stamp
+        });
+        Self::room_summary(state, &mut room);
+        Ok(room)
+    }
+
+    /// Builds the entry for a joined room, returning `None` when nothing changed since the
+    /// connection last saw it, along with the newest event timestamp now delivered.
+    async fn build_joined_room(
+        &self,
+        user_id: &str,
+        room_id: &str,
+        config: &RoomSubscription,
+        prev: Option<&SlidingRoomState>,
+        bump_stamp: i64,
+        state_cache: &mut HashMap<String, Vec<serde_json::Value>>,
+    ) -> ApiResult<(Option<serde_json::Value>, i64)> {
+        let initial = prev.is_none();
+        let last_ts = prev.map(|p| p.last_ts).unwrap_or(0);
+        if !initial && bump_stamp <= last_ts {
+            return Ok((None, last_ts));
+        }
+
+        let limit = config.timeline_limit.clamp(0, MAX_TIMELINE_LIMIT);
+        // Newest first; one extra event tells us whether the timeline is limited
+        let recent: Vec<RoomEvent> = self
+            .event_storage
+            .get_events_before(room_id, i64::MAX, limit + 1)
+            .await
+            .map_err(|e| ApiError::internal(format!("Failed to get room events: {}", e)))?
+            .into_iter()
+            .filter(|e| initial || e.origin_server_ts > last_ts)
+            .collect();
+        if !initial && recent.is_empty() {
+            return Ok((None, last_ts.max(bump_stamp)));
+        }
+
+        let newest_ts = recent
+            .first()
+            .map(|e| e.origin_server_ts)
+            .unwrap_or(0)
+            .max(bump_stamp)
+            .max(last_ts);
+        let limited = recent.len() as i64 > limit;
+        let timeline: Vec<serde_json::Value> = recent
+            .iter()
+            .take(limit as usize)
+            .rev()
+            .map(|e| self.sync_service.event_to_json(e))
+            .collect();
+
+        let lazy_members: HashSet<&str> = timeline
+            .iter()
+            .filter_map(|e| e["sender"].as_str())
+            .collect();
+        let state = self.room_state(state_cache, room_id).await?;
+        let required_state: Vec<serde_json::Value> = state
+            .iter()
+            .filter(|e| {
+                let event_type = e["type"].as_str().unwrap_or_default();
+                let state_key = e["state_key"].as_str().unwrap_or_default();
+                let lazy_member = event_type == "m.room.member" && lazy_members.contains(state_key);
+                let changed = initial || e["origin_server_ts"].as_i64().unwrap_or(0) > last_ts;
+                (changed || lazy_member)
+                    && config.wants_state(event_type, state_key, user_id, &lazy_members)
+            })
+            .cloned()
+            .collect();
+
+        let (joined_count, invited_count) = self
+            .member_storage
+            .get_member_counts(room_id)
+            .await
+            .map_err(|e| ApiError::internal(format!("Failed to count members: {}", e)))?;
+        let (highlight_count, notification_count) = self
+            .sync_service
+            .get_unread_counts(room_id, user_id)
+            .await?;
+
+        let prev_batch = timeline
+            .first()
+            .and_then(|e| e["origin_server_ts"].as_i64())
+            .map(|ts| format!("t{}", ts));
+
+        let mut room = json!({
+            "required_state": required_state,
+            "timeline": timeline,
+            "limited": limited,
+            "num_live": if initial { 0 } else { timeline.len() },
+            "bump_stamp": bump_stamp,
+            "joined_count": joined_count,
+            "invited_count": invited_count,
+            "notification_count": notification_count,
+            "highlight_count": highlight_count
+        });
+        if initial {
+            room["initial"] = json!(true);
+        }
+        if let Some(prev_batch) = prev_batch {
+            room["prev_batch"] = json!(prev_batch);
+        }
+        Self::room_summary(state, &mut room);
+
+        Ok((Some(room), newest_ts))
+    }
+
+    async fn build_extensions(
+        &self,
+        user_id: &str,
+        device_id: &str,
+        pos: i64,
+        since_token: &Option<SyncToken>,
+        room_configs: &BTreeMap<String, RoomSubscription>,
+        request: &SlidingSyncRequest,
+    ) -> ApiResult<serde_json::Map<String, serde_json::Value>> {
+        let extensions = &request.extensions;
+        let mut response = serde_json::Map::new();
+
+        if let Some(to_device) = extensions
+            .to_device
+            .as_ref()
+            .filter(|_| extension_enabled(&extensions.to_device))
+        {
+            // The to-device stream carries its own token so it survives connection resets
+            let since = to_device
+                .since
+                .as_deref()
+                .and_then(|s| s.parse::<i64>().ok())
+                .map(token_at);
+            let events = self
+                .sync_service
+                .get_to_device_events(user_id, Some(device_id), &since, pos)
+                .await?;
+            response.insert(
+                "to_device".to_string(),
+                json!({
+                    "next_batch": pos.to_string(),
+                    "events": events
+                }),
+            );
+        }
+
+        if extension_enabled(&extensions.e2ee) {
+            let device_lists = self
+                .sync_service
+                .get_device_lists(user_id, since_token, pos)
+                .await?;
+            let (one_time_keys_count, unused_fallback_key_types) = self
+                .sync_service
+                .get_device_key_counts(user_id, Some(device_id))
+                .await?;
+            response.insert(
+                "e2ee".to_string(),
+                json!({
+                    "device_lists": device_lists,
+                    "device_one_time_keys_count": one_time_keys_count,
+                    "device_unused_fallback_key_types": unused_fallback_key_types
+                }),
+            );
+        }
+
+        if extension_enabled(&extensions.account_data) {
+            let global = self.sync_service.get_account_data_events(user_id, since_token).await?;
+            let mut rooms = serde_json::Map::new();
+            for room_id in room_configs.keys() {
+                let events = self
+                    .sync_service
+                    .get_room_account_data_events(room_id, user_id)
+                    .await?;
+                if !events.is_empty() {
+                    rooms.insert(room_id.clone(), json!(events));
+                }
+            }
+            response.insert(
+                "account_data".to_string(),
+                json!({
+                    "global": global,
+                    "rooms": rooms
+                }),
+            );
+        }
+
+        if extension_enabled(&extensions.receipts) {
+            let mut rooms = serde_json::Map::new();
+            for room_id in room_configs.keys() {
+                if let Some(event) = self
+                    .sync_service
+                    .get_room_receipt_event(room_id, user_id, since_token)
+                    .await?
+                {
+                    rooms.insert(room_id.clone(), event);
+                }
+            }
+            response.insert("receipts".to_string(), json!({ "rooms": rooms }));
+        }
+
+        if extension_enabled(&extensions.typing) {
+            let mut rooms = serde_json::Map::new();
+            for room_id in room_configs.keys() {
+                if let Some(event) = self
+                    .sync_service
+                    .get_room_typing_event(room_id, since_token)
+                    .await?
+                {
+                    rooms.insert(room_id.clone(), event);
+                }
+            }
+            response.insert("typing".to_string(), json!({ "rooms": rooms }));
+        }
+
+        Ok(response)
+    }
+
+    fn extension_has_updates(extension: &serde_json::Value) -> bool {
+        let non_empty = |value: &serde_json::Value| match value {
+            serde_json::Value::Array(items) => !items.is_empty(),
+            serde_json::Value::Object(map) => !map.is_empty(),
+            _ => false,
+        };
+
+        non_empty(&extension["events"])
+            || non_empty(&extension["rooms"])
+            || non_empty(&extension["global"])
+            || ["changed", "left"]
+                .iter()
+                .any(|k| non_empty(&extension["device_lists"][k]))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn subscription(required_state: &[(&str, &str)], timeline_limit: i64) -> RoomSubscription {
+        RoomSubscription {
+            required_state: required_state
+                .iter()
+                .map(|(t, k)| (t.to_string(), k.to_string()))
+                .collect(),
+            timeline_limit,
+        }
+    }
+
+    #[test]
+    fn test_request_deserialization() {
+        let request: SlidingSyncRequest = serde_json::from_value(json!({
+            "conn_id": "main",
+            "lists": {
+                "all": {
+                    "ranges": [[0, 19]],
+                    "required_state": [["m.room.name", ""], ["m.room.member", "$LAZY"]],
+                    "timeline_limit": 1,
+                    "filters": {"is_invite": false, "not_room_types": ["m.space"]}
+                }
+            },
+            "room_subscriptions": {
+                "!room:example.com": {"required_state": [["*", "*"]], "timeline_limit": 20}
+            },
+            "extensions": {"to_device": {"enabled": true, "since": "123"}, "e2ee": {"enabled": true}}
+        }))
+        .unwrap();
+
+        let list = &request.lists["all"];
+        assert_eq!(list.ranges, vec![(0, 19)]);
+        assert_eq!(list.room.timeline_limit, 1);
+        assert_eq!(list.room.required_state.len(), 2);
+        assert_eq!(
+            list.filters.as_ref().unwrap().not_room_types,
+            Some(vec![Some("m.space".to_string())])
+        );
+        assert_eq!(request.room_subscriptions["!room:example.com"].timeline_limit, 20);
+        assert!(extension_enabled(&request.extensions.to_device));
+        assert!(!extension_enabled(&request.extensions.typing));
+    }
+
+    #[test]
+    fn test_room_subscription_merge_and_fingerprint() {
+        let mut merged = subscription(&[("m.room.name", "")], 1);
+        merged.merge(&subscription(&[("m.room.topic", ""), ("m.room.name", "")], 10));
+
+        assert_eq!(merged.timeline_limit, 10);
+        assert_eq!(merged.required_state.len(), 2);
+
+        let reordered = subscription(&[("m.room.topic", ""), ("m.room.name", "")], 10);
+        assert_eq!(merged.fingerprint(), reordered.fingerprint());
+        assert_ne!(merged.fingerprint(), subscription(&[("m.room.name", "")], 10).fingerprint());
+    }
+
+    #[test]
+    fn test_wants_state() {
+        let config = subscription(
+            &[("m.room.name", ""), ("m.room.member", "$LAZY"), ("m.room.member", "$ME"), ("m.space.child", "*")],
+            1,
+        );
+        let lazy: HashSet<&str> = ["@bob:example.com"].into_iter().collect();
+
+        assert!(config.wants_state("m.room.name", "", "@alice:example.com", &lazy));
+        assert!(config.wants_state("m.room.member", "@bob:example.com", "@alice:example.com", &lazy));
+        assert!(config.wants_state("m.room.member", "@alice:example.com", "@alice:example.com", &lazy));
+        assert!(!config.wants_state("m.room.member", "@carol:example.com", "@alice:example.com", &lazy));
+        assert!(config.wants_state("m.space.child", "!child:example.com", "@alice:example.com", &lazy));
+        assert!(!config.wants_state("m.room.topic", "", "@alice:example.com", &lazy));
+        assert!(subscription(&[("*", "*")], 0).wants_state("m.room.topic", "", "@alice:example.com", &lazy));
+    }
+
+    #[test]
+    fn test_list_filters() {
+        let space_state = vec![
+            json!({"type": "m.room.create", "content": {"type": "m.space"}}),
+            json!({"type": "m.room.encryption", "content": {}}),
+        ];
+        let room_state = vec![json!({"type": "m.room.create", "content": {}})];
+
+        let no_spaces = SlidingSyncListFilters {
+            not_room_types: Some(vec![Some("m.space".to_string())]),
+            ..Default::default()
+        };
+        assert!(!no_spaces.matches(false, &space_state));
+        assert!(no_spaces.matches(false, &room_state));
+
+        let plain_rooms = SlidingSyncListFilters {
+            room_types: Some(vec![None]),
+            ..Default::default()
+        };
+        assert!(plain_rooms.matches(false, &room_state));
+        assert!(!plain_rooms.matches(false, &space_state));
+
+        let encrypted = SlidingSyncListFilters {
+            is_encrypted: Some(true),
+            ..Default::default()
+        };
+        assert!(encrypted.matches(false, &space_state));
+        assert!(!encrypted.matches(false, &room_state));
+
+        let invites = SlidingSyncListFilters {
+            is_invite: Some(true),
+            ..Default::default()
+        };
+        assert!(invites.matches(true, &[]));
+        assert!(!invites.matches(false, &[]));
+    }
+
+    #[test]
+    fn test_extension_has_updates() {
+        assert!(!SlidingSyncService::extension_has_updates(&json!({"next_batch": "1", "events": []})));
+        assert!(SlidingSyncService::extension_has_updates(&json!({"events": [{"type": "m.foo"}]})));
+        assert!(!SlidingSyncService::extension_has_updates(&json!({"rooms": {}})));
+        assert!(SlidingSyncService::extension_has_updates(&json!({
+            "device_lists": {"changed": ["@a:example.com"], "left": []}
+        })));
+    }
+}
@@ -0,0 +1,203 @@
+use crate::common::*;
+use crate::federation::state_res::{self, StateMap};
+use crate::storage::{EventStorage, StateGroupStorage};
+use serde_json::Value;
+
+/// Tracks the room state at each event as state groups, resolving forks with state res v2.
+#[derive(Clone)]
+pub struct StateResolutionService {
+    event_storage: EventStorage,
+    state_group_storage: StateGroupStorage,
+}
+
+/// The state before an event, and the group it is stored as.
+#[derive(Debug, Clone)]
+pub struct StateSnapshot {
+    pub state_group: i64,
+    pub state: StateMap,
+}
+
+impl StateResolutionService {
+    pub fn new(event_storage: EventStorage, state_group_storage: StateGroupStorage) -> Self {
+        Self {
+            event_storage,
+            state_group_storage,
+        }
+    }
+
+    /// Resolves the state after each of `prev_events`. When none of them has a known state
+    /// group (events from before state groups existed), the room's current state is used.
+    pub async fn state_before_event(
+        &self,
+        room_id: &str,
+        room_version: &str,
+        prev_events: &[String],
+    ) -> Result<StateSnapshot, ApiError> {
+        let mut groups = Vec::new();
+        for prev_event in prev_events {
+            let group = self
+                .state_group_storage
+                .get_state_group_for_event(prev_event)
+                .await
+                .map_err(|e| ApiError::internal(format!("Failed to get state group: {}", e)))?;
+            groups.extend(group);
+        }
+
+        if groups.is_empty() {
+            return self.current_state_snapshot(room_id).await;
+        }
+        self.resolve_state_groups(room_id, room_version, groups).await
+    }
+
+    /// Resolves the given groups into a single group. Results are cached per set of groups,
+    /// so a fork is only resolved once however many events descend from it.
+    pub async fn resolve_state_groups(
+        &self,
+        room_id: &str,
+        room_version: &str,
+        mut groups: Vec<i64>,
+    ) -> Result<StateSnapshot, ApiError> {
+        groups.sort_unstable();
+        groups.dedup();
+
+        let state_group = match groups.as_slice() {
+            [] => return self.current_state_snapshot(room_id).await,
+            [group] => Some(*group),
+            _ => self
+                .state_group_storage
+                .get_resolved_group(room_id, &groups)
+                .await
+                .map_err(|e| ApiError::internal(format!("Failed to get resolved state: {}", e)))?,
+        };
+        if let Some(state_group) = state_group {
+            let state = self.load_group(state_group).await?;
+            return Ok(StateSnapshot { state_group, state });
+        }
+
+        let mut state_sets = Vec::with_capacity(groups.len());
+        for group in &groups {
+            state_sets.push(self.load_group(*group).await?);
+        }
+
+        let state = if room_version == "1" {
+            // Room v1 predates state res v2; keep the room's current state as before.
+            self.current_state(room_id).await?
+        } else {
+            let event_ids: Vec<String> = state_sets
+                .iter()
+                .flat_map(|set| set.values().cloned())
+                .collect();
+            let events = self
+                .event_storage
+                .get_events_with_auth_chains(&event_ids)
+                .await
+                .map_err(|e| ApiError::internal(format!("Failed to load auth chains: {}", e)))?;
+            state_res::resolve_state(room_version, &state_sets, &events)
+                .map_err(ApiError::unsupported_room_version)?
+        };
+
+        let state_group = self.store_group(room_id, None, &state).await?;
+        self.state_group_storage
+            .save_resolved_group(room_id, &groups, state_group)
+            .await
+            .map_err(|e| ApiError::internal(format!("Failed to cache resolved state: {}", e)))?;
+        Ok(StateSnapshot { state_group, state })
+    }
+
+    /// Records the state after `event`: a new group for state events, otherwise the group the
+    /// event was sent in.
+    pub async fn record_event_state(
+        &self,
+        room_id: &str,
+        event_id: &str,
+        event: &Value,
+        before: StateSnapshot,
+    ) -> Result<i64, ApiError> {
+        let state_key = event.get("state_key").and_then(|k| k.as_str());
+        let event_type = event.get("type").and_then(|t| t.as_str());
+
+        let state_group = match (event_type, state_key) {
+            (Some(event_type), Some(state_key)) => {
+                let mut state = before.state;
+                state.insert((event_type.to_string(), state_key.to_string()), event_id.to_string());
+                self.store_group(room_id, Some(event_id), &state).await?
+            }
+            _ => before.state_group,
+        };
+
+        self.state_group_storage
+            .set_event_state_group(event_id, state_group)
+            .await
+            .map_err(|e| ApiError::internal(format!("Failed to record event state: {}", e)))?;
+        Ok(state_group)
+    }
+
+    /// Records the state after `event` given the state before it as reported by another
+    /// server, for events at a gap in the DAG whose own prev events we do not hold.
+    pub async fn record_state_at_gap(
+        &self,
+        room_id: &str,
+        event_id: &str,
+        event: &Value,
+        state_before: StateMap,
+    ) -> Result<i64, ApiError> {
+        let state_group = self.store_group(room_id, None, &state_before).await?;
+        let before = StateSnapshot {
+            state_group,
+            state: state_before,
+        };
+        self.record_event_state(room_id, event_id, event, before).await
+    }
+
+    /// The room state after `event_id`, if it has been recorded.
+    pub async fn get_state_at_event(&self, event_id: &str) -> Result<Option<StateMap>, ApiError> {
+        let group = self
+            .state_group_storage
+            .get_state_group_for_event(event_id)
+            .await
+            .map_err(|e| ApiError::internal(format!("Failed to get state group: {}", e)))?;
+        match group {
+            Some(group) => Ok(Some(self.load_group(group).await?)),
+            None => Ok(None),
+        }
+    }
+
+    async fn current_state_snapshot(&self, room_id: &str) -> Result<StateSnapshot, ApiError> {
+        let state = self.current_state(room_id).await?;
+        let state_group = self.store_group(room_id, None, &state).await?;
+        Ok(StateSnapshot { state_group, state })
+    }
+
+    async fn current_state(&self, room_id: &str) -> Result<StateMap, ApiError> {
+        let events = self
+            .event_storage
+            .get_state_events(room_id)
+            .await
+            .map_err(|e| ApiError::internal(format!("Failed to get room state: {}", e)))?;
+
+        // Newest first, so the first event seen for a key is the current one.
+        let mut state = StateMap::new();
+        for event in events {
+            if let Some(state_key) = event.state_key {
+                state
+                    .entry((event.event_type, state_key))
+                    .or_insert(event.event_id);
+            }
+        }
+        Ok(state)
+    }
+
+    async fn load_group(&self, group: i64) -> Result<StateMap, ApiError> {
+        self.state_group_storage
+            .get_state_group(group)
+            .await
+            .map_err(|e| ApiError::internal(format!("Failed to load state group: {}", e)))
+    }
+
+    async fn store_group(&self, room_id: &str, event_id: Option<&str>, state: &StateMap) -> Result<i64, ApiError> {
+        self.state_group_storage
+            .create_state_group(room_id, event_id, state)
+            .await
+            .map_err(|e| ApiError::internal(format!("Failed to store state group: {}", e)))
+    }
+}
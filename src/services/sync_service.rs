@@ -2,30 +2,48 @@ use crate::common::*;
 use crate::services::*;
 use serde::{Deserialize, Serialize};
 use serde_json::json;
-use std::collections::HashMap;
-
+use std::collections::{HashMap, HashSet};
+
+const DEFAULT_TIMELINE_LIMIT: i64 = 50;
+/// How many events to fetch per requested event when a filter may drop some of them.
+const FILTER_OVERFETCH_FACTOR: i64 = 4;
+const MAX_FILTER_FETCH: i64 = 1000;
+
+/// A `/sync` position, encoded as `s{stream_id}_{event_stream}`.
+///
+/// `stream_id` is the notifier position (milliseconds) that receipts, typing, presence,
+/// to-device messages and account data are compared against; `event_stream` is the events
+/// table's `stream_ordering`, so room events are never skipped or repeated. Tokens issued
+/// before events had a stream ordering carry only `stream_id`.
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct SyncToken {
     pub stream_id: i64,
+    #[serde(default)]
+    pub event_stream: Option<i64>,
     pub room_id: Option<String>,
     pub event_type: Option<String>,
 }
 
 impl SyncToken {
     pub fn parse(token: &str) -> Option<Self> {
-        if let Some(stripped) = token.strip_prefix('s') {
-            stripped.parse::<i64>().ok().map(|stream_id| Self {
-                stream_id,
-                room_id: None,
-                event_type: None,
-            })
-        } else {
-            None
-        }
+        let stripped = token.strip_prefix('s')?;
+        let (stream_id, event_stream) = match stripped.split_once('_') {
+            Some((stream_id, event_stream)) => (stream_id, Some(event_stream.parse::<i64>().ok()?)),
+            None => (stripped, None),
+        };
+        stream_id.parse::<i64>().ok().map(|stream_id| Self {
+            stream_id,
+            event_stream,
+            room_id: None,
+            event_type: None,
+        })
     }
 
     pub fn encode(&self) -> String {
-        format!("s{}", self.stream_id)
+        match self.event_stream {
+            Some(event_stream) => format!("s{}_{}", self.stream_id, event_stream),
+            None => format!("s{}", self.stream_id),
+        }
     }
 }
 
@@ -36,6 +54,20 @@ pub struct SyncFilter {
     pub not_types: Option<Vec<String>>,
     pub senders: Option<Vec<String>>,
     pub not_senders: Option<Vec<String>>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub rooms: Option<Vec<String>>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub not_rooms: Option<Vec<String>>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub contains_url: Option<bool>,
+    #[serde(default)]
+    pub lazy_load_members: bool,
+    #[serde(default)]
+    pub include_redundant_members: bool,
+    /// Timeline filters only: report thread unread counts in `unread_thread_notifications`,
+    /// keeping `unread_notifications` to the main timeline.
+    #[serde(default)]
+    pub unread_thread_notifications: bool,
 }
 
 impl Default for SyncFilter {
@@ -46,16 +78,119 @@ impl Default for SyncFilter {
             not_types: None,
             senders: None,
             not_senders: None,
+            rooms: None,
+            not_rooms: None,
+            contains_url: None,
+            lazy_load_members: false,
+            include_redundant_members: false,
+            unread_thread_notifications: false,
         }
     }
 }
 
+impl SyncFilter {
+    /// Whether the filter can drop events, in which case callers over-fetch to fill `limit`.
+    pub fn is_restrictive(&self) -> bool {
+        self.types.is_some()
+            || self.not_types.as_ref().is_some_and(|t| !t.is_empty())
+            || self.senders.is_some()
+            || self.not_senders.as_ref().is_some_and(|s| !s.is_empty())
+            || self.contains_url.is_some()
+    }
+
+    pub fn matches_room(&self, room_id: &str) -> bool {
+        if self.not_rooms.as_ref().is_some_and(|r| r.iter().any(|x| x == room_id)) {
+            return false;
+        }
+        self.rooms.as_ref().is_none_or(|r| r.iter().any(|x| x == room_id))
+    }
+
+    /// Checks an event in client format against the filter. `not_*` lists take precedence.
+    pub fn matches(&self, event: &serde_json::Value, room_id: Option<&str>) -> bool {
+        if let Some(room_id) = room_id.or_else(|| event["room_id"].as_str()) {
+            if !self.matches_room(room_id) {
+                return false;
+            }
+        }
+
+        let event_type = event["type"].as_str().unwrap_or_default();
+        if self
+            .not_types
+            .as_ref()
+            .is_some_and(|t| t.iter().any(|p| type_matches(p, event_type)))
+        {
+            return false;
+        }
+        if self
+            .types
+            .as_ref()
+            .is_some_and(|t| !t.iter().any(|p| type_matches(p, event_type)))
+        {
+            return false;
+        }
+
+        let sender = event["sender"].as_str().unwrap_or_default();
+        if self.not_senders.as_ref().is_some_and(|s| s.iter().any(|x| x == sender)) {
+            return false;
+        }
+        if self.senders.as_ref().is_some_and(|s| !s.iter().any(|x| x == sender)) {
+            return false;
+        }
+
+        if let Some(contains_url) = self.contains_url {
+            if event["content"]["url"].is_string() != contains_url {
+                return false;
+            }
+        }
+
+        true
+    }
+
+    /// Filters a list of events, keeping at most `limit` from the front.
+    pub fn apply(&self, events: Vec<serde_json::Value>, room_id: Option<&str>) -> Vec<serde_json::Value> {
+        let limit = self.limit.map(|l| l.max(0) as usize).unwrap_or(usize::MAX);
+        events
+            .into_iter()
+            .filter(|e| self.matches(e, room_id))
+            .take(limit)
+            .collect()
+    }
+}
+
+/// Matches an event type against a filter pattern, where `*` matches any run of characters.
+fn type_matches(pattern: &str, event_type: &str) -> bool {
+    if !pattern.contains('*') {
+        return pattern == event_type;
+    }
+
+    let parts: Vec<&str> = pattern.split('*').collect();
+    let (first, last) = (parts[0], parts[parts.len() - 1]);
+    if !event_type.starts_with(first) || event_type.len() < first.len() + last.len() {
+        return false;
+    }
+
+    let mut rest = &event_type[first.len()..];
+    for part in &parts[1..parts.len() - 1] {
+        match rest.find(part) {
+            Some(idx) => rest = &rest[idx + part.len()..],
+            None => return false,
+        }
+    }
+    rest.ends_with(last)
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct RoomFilter {
     pub state: Option<SyncFilter>,
     pub timeline: Option<SyncFilter>,
     pub ephemeral: Option<SyncFilter>,
     pub account_data: Option<SyncFilter>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub rooms: Option<Vec<String>>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub not_rooms: Option<Vec<String>>,
+    #[serde(default)]
+    pub include_leave: bool,
 }
 
 impl Default for RoomFilter {
@@ -65,10 +200,165 @@ impl Default for RoomFilter {
             timeline: Some(SyncFilter { limit: Some(50), ..Default::default() }),
             ephemeral: Some(SyncFilter::default()),
             account_data: Some(SyncFilter::default()),
+            rooms: None,
+            not_rooms: None,
+            include_leave: false,
         }
     }
 }
 
+impl RoomFilter {
+    pub fn matches_room(&self, room_id: &str) -> bool {
+        if self.not_rooms.as_ref().is_some_and(|r| r.iter().any(|x| x == room_id)) {
+            return false;
+        }
+        self.rooms.as_ref().is_none_or(|r| r.iter().any(|x| x == room_id))
+    }
+
+    fn section(section: &Option<SyncFilter>) -> SyncFilter {
+        section.clone().unwrap_or(SyncFilter {
+            limit: None,
+            ..Default::default()
+        })
+    }
+
+    pub fn timeline_filter(&self) -> SyncFilter {
+        let mut filter = Self::section(&self.timeline);
+        filter.limit = Some(filter.limit.unwrap_or(DEFAULT_TIMELINE_LIMIT));
+        filter
+    }
+
+    pub fn state_filter(&self) -> SyncFilter {
+        // State is never truncated, otherwise clients end up with a broken view of the room
+        SyncFilter {
+            limit: None,
+            ..Self::section(&self.state)
+        }
+    }
+
+    pub fn ephemeral_filter(&self) -> SyncFilter {
+        Self::section(&self.ephemeral)
+    }
+
+    pub fn account_data_filter(&self) -> SyncFilter {
+        Self::section(&self.account_data)
+    }
+}
+
+/// A complete filter as uploaded to `/user/{user_id}/filter`.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct FilterDefinition {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub event_fields: Option<Vec<String>>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub event_format: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub presence: Option<SyncFilter>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub account_data: Option<SyncFilter>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub room: Option<RoomFilter>,
+}
+
+impl FilterDefinition {
+    pub fn parse(value: &serde_json::Value) -> ApiResult<Self> {
+        let filter: Self = serde_json::from_value(value.clone())
+            .map_err(|e| ApiError::bad_request(format!("Invalid filter: {}", e)))?;
+        if let Some(format) = &filter.event_format {
+            if format != "client" && format != "federation" {
+                return Err(ApiError::bad_request(format!(
+                    "Invalid event_format: {}",
+                    format
+                )));
+            }
+        }
+        Ok(filter)
+    }
+
+    pub fn room_filter(&self) -> RoomFilter {
+        self.room.clone().unwrap_or_default()
+    }
+
+    pub fn presence_filter(&self) -> SyncFilter {
+        RoomFilter::section(&self.presence)
+    }
+
+    pub fn account_data_filter(&self) -> SyncFilter {
+        RoomFilter::section(&self.account_data)
+    }
+
+    /// Projects an event down to the fields listed in `event_fields`, if any.
+    pub fn apply_event_fields(&self, event: serde_json::Value) -> serde_json::Value {
+        let Some(fields) = &self.event_fields else {
+            return event;
+        };
+
+        let mut projected = serde_json::Map::new();
+        for field in fields {
+            let path = split_field_path(field);
+            copy_field_path(&event, &mut projected, &path);
+        }
+        serde_json::Value::Object(projected)
+    }
+}
+
+/// Splits an `event_fields` entry on `.`, honouring `\.` and `\\` escapes.
+fn split_field_path(field: &str) -> Vec<String> {
+    let mut parts = Vec::new();
+    let mut current = String::new();
+    let mut chars = field.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' => match chars.next() {
+                Some(next) => current.push(next),
+                None => current.push('\\'),
+            },
+            '.' => parts.push(std::mem::take(&mut current)),
+            _ => current.push(c),
+        }
+    }
+    parts.push(current);
+    parts
+}
+
+fn copy_field_path(
+    source: &serde_json::Value,
+    target: &mut serde_json::Map<String, serde_json::Value>,
+    path: &[String],
+) {
+    let Some((key, rest)) = path.split_first() else {
+        return;
+    };
+    let Some(value) = source.get(key) else {
+        return;
+    };
+
+    if rest.is_empty() {
+        target.insert(key.clone(), value.clone());
+        return;
+    }
+    if !value.is_object() {
+        return;
+    }
+
+    let entry = target
+        .entry(key.clone())
+        .or_insert_with(|| serde_json::Value::Object(serde_json::Map::new()));
+    if let Some(nested) = entry.as_object_mut() {
+        copy_field_path(value, nested, rest);
+    }
+}
+
+/// Parses a `t`/`s` prefixed stream token, or a bare timestamp.
+fn parse_stream_token(token: &str) -> Option<i64> {
+    token
+        .strip_prefix('t')
+        .or_else(|| token.strip_prefix('s'))
+        .unwrap_or(token)
+        .parse()
+        .ok()
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct SyncRequest {
     pub since: Option<String>,
@@ -98,6 +388,14 @@ pub struct SyncService {
     room_storage: RoomStorage,
     #[allow(dead_code)]
     device_storage: DeviceStorage,
+    user_storage: Option<UserStorage>,
+    to_device_service: Option<ToDeviceService>,
+    device_keys_service: Option<DeviceKeyService>,
+    push_rule_service: Option<Arc<PushRuleService>>,
+    push_actions_service: Option<Arc<PushActionsService>>,
+    thread_service: Option<Arc<ThreadService>>,
+    knock_service: Option<Arc<KnockService>>,
+    notifier: Notifier,
 }
 
 impl SyncService {
@@ -114,138 +412,467 @@ impl SyncService {
             event_storage,
             room_storage,
             device_storage,
+            user_storage: None,
+            to_device_service: None,
+            device_keys_service: None,
+            push_rule_service: None,
+            push_actions_service: None,
+            thread_service: None,
+            knock_service: None,
+            notifier: Notifier::new(),
         }
     }
 
+    pub fn with_notifier(mut self, notifier: Notifier) -> Self {
+        self.notifier = notifier;
+        self
+    }
+
+    pub fn with_user_storage(mut self, user_storage: UserStorage) -> Self {
+        self.user_storage = Some(user_storage);
+        self
+    }
+
+    pub fn with_to_device_service(mut self, to_device_service: ToDeviceService) -> Self {
+        self.to_device_service = Some(to_device_service);
+        self
+    }
+
+    pub fn with_device_keys_service(mut self, device_keys_service: DeviceKeyService) -> Self {
+        self.device_keys_service = Some(device_keys_service);
+        self
+    }
+
+    pub fn with_push_rule_service(mut self, push_rule_service: Arc<PushRuleService>) -> Self {
+        self.push_rule_service = Some(push_rule_service);
+        self
+    }
+
+    pub fn with_push_actions_service(mut self, push_actions_service: Arc<PushActionsService>) -> Self {
+        self.push_actions_service = Some(push_actions_service);
+        self
+    }
+
+    pub fn with_thread_service(mut self, thread_service: Arc<ThreadService>) -> Self {
+        self.thread_service = Some(thread_service);
+        self
+    }
+
+    pub fn with_knock_service(mut self, knock_service: Arc<KnockService>) -> Self {
+        self.knock_service = Some(knock_service);
+        self
+    }
+
     pub async fn sync(
         &self,
         user_id: &str,
-        _timeout: u64,
+        timeout: u64,
         full_state: bool,
         set_presence: &str,
         since: Option<&str>,
     ) -> ApiResult<serde_json::Value> {
-        if let Some(presence) = set_presence.strip_prefix("online").or_else(|| set_presence.strip_prefix("unavailable")) {
-            self.presence_storage
-                .set_presence(user_id, presence, None)
-                .await
-                .ok();
+        self.sync_with_filter(
+            user_id,
+            None,
+            timeout,
+            full_state,
+            set_presence,
+            since,
+            &FilterDefinition::default(),
+        )
+        .await
+    }
+
+    /// Syncs on behalf of a device. `device_id` scopes the to-device inbox and key counts.
+    #[allow(clippy::too_many_arguments)]
+    pub async fn sync_with_filter(
+        &self,
+        user_id: &str,
+        device_id: Option<&str>,
+        timeout: u64,
+        full_state: bool,
+        set_presence: &str,
+        since: Option<&str>,
+        filter: &FilterDefinition,
+    ) -> ApiResult<serde_json::Value> {
+        if matches!(set_presence, "online" | "unavailable") {
+            match self.presence_storage.touch_presence(user_id, set_presence).await {
+                Ok(true) => self.notify_presence_change(user_id).await,
+                Ok(false) => {}
+                Err(e) => ::tracing::warn!("Failed to update presence for {}: {}", user_id, e),
+            }
         }
 
         let since_token = since.and_then(SyncToken::parse);
         let is_incremental = since_token.is_some() && !full_state;
 
+        if !is_incremental || timeout == 0 {
+            let (response, _) = self
+                .build_sync_response(user_id, device_id, &since_token, is_incremental, filter)
+                .await?;
+            return Ok(response);
+        }
+
         let room_ids = self
             .member_storage
             .get_joined_rooms(user_id)
             .await
             .map_err(|e| ApiError::internal(format!("Failed to get rooms: {}", e)))?;
 
-        let limit = 50i64;
+        // Register before the first query so that writes racing with it still wake us
+        let listener = self.notifier.listen(user_id, &room_ids);
+        let deadline = tokio::time::Instant::now() + std::time::Duration::from_millis(timeout);
+
+        loop {
+            let position = listener.position();
+            let (response, has_updates) = self
+                .build_sync_response(user_id, device_id, &since_token, true, filter)
+                .await?;
+            if has_updates || !listener.wait_for_change(position, deadline).await {
+                return Ok(response);
+            }
+        }
+    }
+
+    async fn notify_presence_change(&self, user_id: &str) {
+        let user_ids = [user_id.to_string()];
+        self.notifier
+            .notify_users(NotifierStream::Presence, &user_ids)
+            .await;
+        if let Ok(room_ids) = self.member_storage.get_joined_rooms(user_id).await {
+            for room_id in room_ids {
+                self.notifier
+                    .notify_room(NotifierStream::Presence, &room_id, &[])
+                    .await;
+            }
+        }
+    }
+
+    async fn build_sync_response(
+        &self,
+        user_id: &str,
+        device_id: Option<&str>,
+        since_token: &Option<SyncToken>,
+        is_incremental: bool,
+        filter: &FilterDefinition,
+    ) -> ApiResult<(serde_json::Value, bool)> {
+        // Taken before reading so that anything written during the read is picked up next time
+        let next_batch = SyncToken {
+            stream_id: self.notifier.current_position(),
+            event_stream: Some(
+                self.event_storage
+                    .get_current_stream_ordering()
+                    .await
+                    .map_err(|e| ApiError::internal(format!("Failed to get stream position: {}", e)))?,
+            ),
+            room_id: None,
+            event_type: None,
+        };
+
+        let room_filter = filter.room_filter();
+        let room_ids: Vec<String> = self
+            .member_storage
+            .get_joined_rooms(user_id)
+            .await
+            .map_err(|e| ApiError::internal(format!("Failed to get rooms: {}", e)))?
+            .into_iter()
+            .filter(|room_id| room_filter.matches_room(room_id))
+            .collect();
+
+        let fetch_limit = Self::timeline_fetch_limit(&room_filter.timeline_filter());
         let since_ts = since_token.as_ref().map(|t| t.stream_id).unwrap_or(0);
-        
-        let room_events = if is_incremental {
-            self.event_storage
-                .get_room_events_since_batch(&room_ids, since_ts, limit)
+        let since_stream = match since_token.as_ref().filter(|_| is_incremental) {
+            Some(since) => Some(self.since_event_stream(since).await?),
+            None => None,
+        };
+
+        let room_events = match since_stream {
+            Some(since_stream) => self
+                .event_storage
+                .get_room_events_since_batch(
+                    &room_ids,
+                    since_stream,
+                    next_batch.event_stream.unwrap_or_default(),
+                    fetch_limit,
+                )
                 .await
-                .map_err(|e| ApiError::internal(format!("Failed to get batch events: {}", e)))?
-        } else {
-            self.event_storage
-                .get_room_events_batch(&room_ids, limit)
+                .map_err(|e| ApiError::internal(format!("Failed to get batch events: {}", e)))?,
+            None => self
+                .event_storage
+                .get_room_events_batch(&room_ids, fetch_limit)
                 .await
-                .map_err(|e| ApiError::internal(format!("Failed to get batch events: {}", e)))?
+                .map_err(|e| ApiError::internal(format!("Failed to get batch events: {}", e)))?,
         };
 
         let mut rooms = serde_json::Map::new();
-        let invites = serde_json::Map::new();
-        let leaves = serde_json::Map::new();
+        let mut leaves = serde_json::Map::new();
 
         for room_id in &room_ids {
             let events = room_events.get(room_id).cloned().unwrap_or_default();
-            let room_sync = self.build_room_sync(room_id, user_id, events, is_incremental).await?;
+            let room_sync = self
+                .build_room_sync(room_id, user_id, events, since_token, since_stream, filter)
+                .await?;
             
+            if is_incremental && !Self::room_has_updates(&room_sync) {
+                continue;
+            }
             if room_sync.is_object() && !room_sync.as_object().is_some_and(|o| o.is_empty()) {
                 rooms.insert(room_id.clone(), room_sync);
             }
         }
 
-        let presence_events = self.get_presence_events(user_id, &since_token).await?;
+        if room_filter.include_leave {
+            let left_rooms = self
+                .member_storage
+                .get_left_rooms(user_id, since_ts)
+                .await
+                .map_err(|e| ApiError::internal(format!("Failed to get left rooms: {}", e)))?;
+
+            for (room_id, left_ts) in left_rooms {
+                if !room_filter.matches_room(&room_id) {
+                    continue;
+                }
+                let room_sync = self
+                    .build_left_room_sync(&room_id, user_id, since_ts, left_ts, is_incremental, filter)
+                    .await?;
+                leaves.insert(room_id, room_sync);
+            }
+        }
+
+        let invites = self.get_invited_rooms(user_id, since_ts, &room_filter).await?;
+        let knocks = self.get_knocked_rooms(user_id, since_ts, &room_filter).await?;
+
+        let presence_events = filter
+            .presence_filter()
+            .apply(self.get_presence_events(user_id, since_token).await?, None);
         
-        let account_data_events = self.get_account_data_events(user_id).await?;
+        let account_data_events = filter
+            .account_data_filter()
+            .apply(self.get_account_data_events(user_id, since_token).await?, None);
         
-        let to_device_events = self.get_to_device_events(user_id, &since_token).await?;
+        let to_device_events = self
+            .get_to_device_events(user_id, device_id, since_token, next_batch.stream_id)
+            .await?;
         
-        let device_lists = self.get_device_lists(user_id, &since_token).await?;
+        let device_lists = self
+            .get_device_lists(user_id, since_token, next_batch.stream_id)
+            .await?;
+
+        let (one_time_keys_count, unused_fallback_key_types) =
+            self.get_device_key_counts(user_id, device_id).await?;
+
+        let has_updates = !rooms.is_empty()
+            || !invites.is_empty()
+            || !leaves.is_empty()
+            || !knocks.is_empty()
+            || !presence_events.is_empty()
+            || !account_data_events.is_empty()
+            || !to_device_events.is_empty()
+            || ["changed", "left"]
+                .iter()
+                .any(|k| device_lists[k].as_array().is_some_and(|a| !a.is_empty()));
+
+        Ok((
+            json!({
+                "next_batch": next_batch.encode(),
+                "rooms": {
+                    "join": rooms,
+                    "invite": invites,
+                    "leave": leaves,
+                    "knock": knocks
+                },
+                "presence": json!({
+                    "events": presence_events
+                }),
+                "account_data": json!({
+                    "events": account_data_events
+                }),
+                "to_device": json!({
+                    "events": to_device_events
+                }),
+                "device_lists": device_lists,
+                "device_one_time_keys_count": one_time_keys_count,
+                "device_unused_fallback_key_types": unused_fallback_key_types
+            }),
+            has_updates,
+        ))
+    }
 
-        let next_batch = SyncToken {
-            stream_id: chrono::Utc::now().timestamp_millis(),
-            room_id: None,
-            event_type: None,
-        };
+    /// Where the room events of `since` end. Tokens without an event stream position are
+    /// mapped to the last event persisted by their timestamp.
+    async fn since_event_stream(&self, since: &SyncToken) -> ApiResult<i64> {
+        match since.event_stream {
+            Some(position) => Ok(position),
+            None => self
+                .event_storage
+                .get_stream_ordering_at(since.stream_id)
+                .await
+                .map_err(|e| ApiError::internal(format!("Failed to get stream position: {}", e))),
+        }
+    }
 
-        Ok(json!({
-            "next_batch": next_batch.encode(),
-            "rooms": {
-                "join": rooms,
-                "invite": invites,
-                "leave": leaves
-            },
-            "presence": json!({
-                "events": presence_events
-            }),
-            "account_data": json!({
-                "events": account_data_events
-            }),
-            "to_device": json!({
-                "events": to_device_events
-            }),
-            "device_lists": device_lists,
-            "device_one_time_keys_count": json!({})
-        }))
+    /// Rooms the user was invited to since the last sync, each with the stripped state they
+    /// may see of it.
+    async fn get_invited_rooms(
+        &self,
+        user_id: &str,
+        since_ts: i64,
+        room_filter: &RoomFilter,
+    ) -> ApiResult<serde_json::Map<String, serde_json::Value>> {
+        let invited_rooms = self
+            .member_storage
+            .get_invited_rooms_since(user_id, since_ts)
+            .await
+            .map_err(|e| ApiError::internal(format!("Failed to get invited rooms: {}", e)))?;
+
+        let mut invites = serde_json::Map::new();
+        for room_id in invited_rooms {
+            if !room_filter.matches_room(&room_id) {
+                continue;
+            }
+            let invite_state = stripped_room_state(&self.event_storage, &room_id, user_id, "invite_room_state")
+                .await
+                .map_err(|e| ApiError::internal(format!("Failed to get invite state: {}", e)))?;
+            invites.insert(room_id, json!({ "invite_state": { "events": invite_state } }));
+        }
+        Ok(invites)
     }
 
-    async fn build_room_sync(
+    /// Rooms the user knocked on since the last sync, each with the stripped state they may
+    /// see of it.
+    async fn get_knocked_rooms(
         &self,
-        room_id: &str,
         user_id: &str,
-        events: Vec<RoomEvent>,
-        is_incremental: bool,
-    ) -> ApiResult<serde_json::Value> {
-        let state_events = if is_incremental {
-            vec![]
-        } else {
-            self.get_room_state_events(room_id).await?
+        since_ts: i64,
+        room_filter: &RoomFilter,
+    ) -> ApiResult<serde_json::Map<String, serde_json::Value>> {
+        let mut knocks = serde_json::Map::new();
+        let Some(knock_service) = &self.knock_service else {
+            return Ok(knocks);
         };
+        let knocked_rooms = self
+            .member_storage
+            .get_knocked_rooms(user_id, since_ts)
+            .await
+            .map_err(|e| ApiError::internal(format!("Failed to get knocked rooms: {}", e)))?;
+
+        for room_id in knocked_rooms {
+            if !room_filter.matches_room(&room_id) {
+                continue;
+            }
+            let knock_state = knock_service.knock_room_state(&room_id, user_id).await?;
+            knocks.insert(room_id, json!({ "knock_state": { "events": knock_state } }));
+        }
+        Ok(knocks)
+    }
+
+    fn timeline_fetch_limit(timeline_filter: &SyncFilter) -> i64 {
+        let limit = timeline_filter.limit.unwrap_or(DEFAULT_TIMELINE_LIMIT).max(1);
+        if timeline_filter.is_restrictive() {
+            (limit * FILTER_OVERFETCH_FACTOR).min(MAX_FILTER_FETCH)
+        } else {
+            limit
+        }
+    }
 
-        let event_list: Vec<serde_json::Value> = events
+    fn room_has_updates(room_sync: &serde_json::Value) -> bool {
+        ["timeline", "state", "ephemeral", "account_data"]
             .iter()
-            .map(|e| self.event_to_json(e))
-            .collect();
+            .any(|section| {
+                room_sync[section]["events"]
+                    .as_array()
+                    .is_some_and(|events| !events.is_empty())
+            })
+    }
 
-        let state_list: Vec<serde_json::Value> = state_events
+    /// Filters a newest-first batch of events down to the timeline, returned oldest-first.
+    fn build_timeline(
+        &self,
+        room_id: &str,
+        events: &[RoomEvent],
+        fetch_limit: i64,
+        timeline_filter: &SyncFilter,
+    ) -> (Vec<serde_json::Value>, bool) {
+        let limit = timeline_filter.limit.unwrap_or(DEFAULT_TIMELINE_LIMIT).max(0) as usize;
+        let mut matched: Vec<serde_json::Value> = events
             .iter()
             .map(|e| self.event_to_json(e))
+            .filter(|e| timeline_filter.matches(e, Some(room_id)))
             .collect();
 
-        let ephemeral_events = self.get_room_ephemeral_events(room_id, user_id).await?;
-        
-        let account_data_events = self.get_room_account_data_events(room_id, user_id).await?;
+        let limited = matched.len() > limit || events.len() as i64 >= fetch_limit;
+        matched.truncate(limit);
+        matched.reverse();
+        (matched, limited)
+    }
+
+    async fn build_room_sync(
+        &self,
+        room_id: &str,
+        user_id: &str,
+        events: Vec<RoomEvent>,
+        since_token: &Option<SyncToken>,
+        since_stream: Option<i64>,
+        filter: &FilterDefinition,
+    ) -> ApiResult<serde_json::Value> {
+        let room_filter = filter.room_filter();
+        let timeline_filter = room_filter.timeline_filter();
+        let state_filter = room_filter.state_filter();
+
+        let (mut timeline, limited) = self.build_timeline(
+            room_id,
+            &events,
+            Self::timeline_fetch_limit(&timeline_filter),
+            &timeline_filter,
+        );
+        self.bundle_aggregations(user_id, &mut timeline).await?;
+        // The stream position of the first timeline event, where the timeline's state applies
+        let timeline_start = timeline
+            .first()
+            .and_then(|first| events.iter().find(|e| first["event_id"] == e.event_id.as_str()))
+            .map(|e| e.stream_ordering);
+
+        let state_list = match since_stream {
+            Some(since) if !self.joined_since(room_id, user_id, since).await? => {
+                self.incremental_state(room_id, user_id, since, limited, timeline_start, &timeline, &state_filter)
+                    .await?
+            }
+            // Initial syncs and rooms joined since the last sync get the full current state
+            _ => {
+                let state_events = self.get_room_state_events(room_id).await?;
+                self.filter_state_events(room_id, user_id, state_events, &timeline, &state_filter, false)
+            }
+        };
 
-        let (highlight_count, notification_count) = self.get_unread_counts(room_id, user_id).await?;
+        let ephemeral_events = room_filter.ephemeral_filter().apply(
+            self.get_room_ephemeral_events(room_id, user_id, since_token).await?,
+            Some(room_id),
+        );
+        
+        let account_data_events = room_filter.account_data_filter().apply(
+            self.get_room_account_data_events(room_id, user_id).await?,
+            Some(room_id),
+        );
+
+        let unread_counts = self.get_room_unread_counts(room_id, user_id).await?;
+        let (highlight_count, notification_count) = if timeline_filter.unread_thread_notifications {
+            unread_counts.main_timeline()
+        } else {
+            unread_counts.total()
+        };
 
-        let prev_batch = events
+        let prev_batch = timeline
             .first()
-            .map(|e| format!("t{}", e.origin_server_ts))
+            .and_then(|e| e["origin_server_ts"].as_i64())
+            .map(|ts| format!("t{}", ts))
             .unwrap_or_else(|| format!("t{}", chrono::Utc::now().timestamp_millis()));
 
-        let limited = event_list.len() as i64 >= 50;
-
-        Ok(json!({
+        let mut room_sync = json!({
             "state": {
-                "events": state_list
+                "events": Self::project(filter, state_list)
             },
             "timeline": {
-                "events": event_list,
+                "events": Self::project(filter, timeline),
                 "limited": limited,
                 "prev_batch": prev_batch
             },
@@ -259,52 +886,461 @@ impl SyncService {
                 "highlight_count": highlight_count,
                 "notification_count": notification_count
             }
+        });
+        if timeline_filter.unread_thread_notifications {
+            room_sync["unread_thread_notifications"] = unread_counts.thread_notifications();
+        }
+        Ok(room_sync)
+    }
+
+    /// Builds the `rooms.leave` entry for a room the user left or was banned from.
+    async fn build_left_room_sync(
+        &self,
+        room_id: &str,
+        user_id: &str,
+        since_ts: i64,
+        left_ts: i64,
+        is_incremental: bool,
+        filter: &FilterDefinition,
+    ) -> ApiResult<serde_json::Value> {
+        let room_filter = filter.room_filter();
+        let timeline_filter = room_filter.timeline_filter();
+        let fetch_limit = Self::timeline_fetch_limit(&timeline_filter);
+
+        // Nothing after the leave is visible to the user
+        let events: Vec<RoomEvent> = self
+            .event_storage
+            .get_events_before(room_id, left_ts + 1, fetch_limit)
+            .await
+            .map_err(|e| ApiError::internal(format!("Failed to get room events: {}", e)))?
+            .into_iter()
+            .filter(|e| !is_incremental || e.origin_server_ts > since_ts)
+            .collect();
+
+        let (mut timeline, limited) = self.build_timeline(room_id, &events, fetch_limit, &timeline_filter);
+        self.bundle_aggregations(user_id, &mut timeline).await?;
+
+        let state_filter = room_filter.state_filter();
+        let state_events: Vec<serde_json::Value> = self
+            .get_room_state_events(room_id)
+            .await?
+            .into_iter()
+            .filter(|e| e["origin_server_ts"].as_i64().unwrap_or(0) <= left_ts)
+            .collect();
+        let state_list = self.filter_state_events(room_id, user_id, state_events, &timeline, &state_filter, false);
+
+        let prev_batch = timeline
+            .first()
+            .and_then(|e| e["origin_server_ts"].as_i64())
+            .map(|ts| format!("t{}", ts))
+            .unwrap_or_else(|| format!("t{}", left_ts));
+
+        Ok(json!({
+            "state": {
+                "events": Self::project(filter, state_list)
+            },
+            "timeline": {
+                "events": Self::project(filter, timeline),
+                "limited": limited,
+                "prev_batch": prev_batch
+            },
+            "account_data": {
+                "events": []
+            }
         }))
     }
 
-    fn event_to_json(&self, event: &RoomEvent) -> serde_json::Value {
-        json!({
+    /// Whether the user's current join to the room was persisted after stream position `since`.
+    async fn joined_since(&self, room_id: &str, user_id: &str, since: i64) -> ApiResult<bool> {
+        let changes = self
+            .event_storage
+            .get_state_events_between(room_id, since, i64::MAX)
+            .await
+            .map_err(|e| ApiError::internal(format!("Failed to get state events: {}", e)))?;
+        Ok(changes
+            .iter()
+            .find(|e| e.event_type == "m.room.member" && e.state_key.as_deref() == Some(user_id))
+            .is_some_and(|e| e.content["membership"] == "join"))
+    }
+
+    /// The `state` of an incremental sync. A limited timeline gets the state that changed
+    /// between `since` and the start of the timeline, as the client missed those events;
+    /// lazy-loading adds the members of the timeline's senders.
+    #[allow(clippy::too_many_arguments)]
+    async fn incremental_state(
+        &self,
+        room_id: &str,
+        user_id: &str,
+        since: i64,
+        limited: bool,
+        timeline_start: Option<i64>,
+        timeline: &[serde_json::Value],
+        state_filter: &SyncFilter,
+    ) -> ApiResult<Vec<serde_json::Value>> {
+        let mut state_events = Vec::new();
+        if let (true, Some(timeline_start)) = (limited, timeline_start) {
+            let gap = self
+                .event_storage
+                .get_state_events_between(room_id, since, timeline_start)
+                .await
+                .map_err(|e| ApiError::internal(format!("Failed to get state events: {}", e)))?;
+            let mut seen = HashSet::new();
+            state_events = gap
+                .iter()
+                .filter(|e| seen.insert((e.event_type.clone(), e.state_key.clone().unwrap_or_default())))
+                .map(|e| self.state_event_to_json(e))
+                .collect();
+            state_events = self.filter_state_events(room_id, user_id, state_events, timeline, state_filter, false);
+        }
+
+        if state_filter.lazy_load_members && !timeline.is_empty() {
+            let members = self.get_room_state_events(room_id).await?;
+            for member in self.filter_state_events(room_id, user_id, members, timeline, state_filter, true) {
+                let already_sent = state_events
+                    .iter()
+                    .any(|e| e["type"] == member["type"] && e["state_key"] == member["state_key"]);
+                if !already_sent {
+                    state_events.push(member);
+                }
+            }
+        }
+        Ok(state_events)
+    }
+
+    /// Applies the state filter, trimming membership to the timeline senders when lazy-loading.
+    fn filter_state_events(
+        &self,
+        room_id: &str,
+        user_id: &str,
+        state_events: Vec<serde_json::Value>,
+        timeline: &[serde_json::Value],
+        state_filter: &SyncFilter,
+        members_only: bool,
+    ) -> Vec<serde_json::Value> {
+        let mut wanted_members: HashSet<&str> = timeline
+            .iter()
+            .filter_map(|e| e["sender"].as_str())
+            .collect();
+        if !members_only {
+            wanted_members.insert(user_id);
+        }
+
+        state_events
+            .into_iter()
+            .filter(|e| {
+                let is_member = e["type"] == "m.room.member";
+                if members_only && !is_member {
+                    return false;
+                }
+                if state_filter.lazy_load_members && is_member {
+                    let state_key = e["state_key"].as_str().unwrap_or_default();
+                    if !wanted_members.contains(state_key) {
+                        return false;
+                    }
+                }
+                state_filter.matches(e, Some(room_id))
+            })
+            .collect()
+    }
+
+    fn project(filter: &FilterDefinition, events: Vec<serde_json::Value>) -> Vec<serde_json::Value> {
+        if filter.event_fields.is_none() {
+            return events;
+        }
+        events
+            .into_iter()
+            .map(|e| filter.apply_event_fields(e))
+            .collect()
+    }
+
+    /// Bundles thread summaries, edits and references into the events' `unsigned`.
+    async fn bundle_aggregations(&self, user_id: &str, events: &mut [serde_json::Value]) -> ApiResult<()> {
+        match &self.thread_service {
+            Some(thread_service) => thread_service.bundle_aggregations(user_id, events).await,
+            None => Ok(()),
+        }
+    }
+
+    pub(crate) fn event_to_json(&self, event: &RoomEvent) -> serde_json::Value {
+        let mut value = json!({
             "type": event.event_type,
             "content": event.content,
             "sender": event.user_id,
             "origin_server_ts": event.origin_server_ts,
             "event_id": event.event_id
+        });
+        if let Some(state_key) = &event.state_key {
+            value["state_key"] = json!(state_key);
+        }
+        value
+    }
+
+    pub(crate) fn state_event_to_json(&self, event: &StateEvent) -> serde_json::Value {
+        json!({
+            "type": event.event_type,
+            "content": event.content,
+            "sender": event.sender,
+            "state_key": event.state_key,
+            "origin_server_ts": event.origin_server_ts,
+            "event_id": event.event_id
         })
     }
 
-    async fn get_room_state_events(&self, _room_id: &str) -> ApiResult<Vec<RoomEvent>> {
-        Ok(vec![])
+    /// Current room state: the latest event for each `(type, state_key)` pair.
+    pub(crate) async fn get_room_state_events(&self, room_id: &str) -> ApiResult<Vec<serde_json::Value>> {
+        let events = self
+            .event_storage
+            .get_state_events(room_id)
+            .await
+            .map_err(|e| ApiError::internal(format!("Failed to get state events: {}", e)))?;
+
+        let mut seen = HashSet::new();
+        Ok(events
+            .iter()
+            .filter(|e| seen.insert((e.event_type.clone(), e.state_key.clone().unwrap_or_default())))
+            .map(|e| self.state_event_to_json(e))
+            .collect())
     }
 
-    async fn get_presence_events(&self, _user_id: &str, _since: &Option<SyncToken>) -> ApiResult<Vec<serde_json::Value>> {
-        Ok(vec![])
+    async fn get_presence_events(&self, user_id: &str, since: &Option<SyncToken>) -> ApiResult<Vec<serde_json::Value>> {
+        let since_ts = since.as_ref().map(|t| t.stream_id).unwrap_or(0);
+        let changes = self
+            .presence_storage
+            .get_presence_changes(user_id, since_ts)
+            .await
+            .map_err(|e| ApiError::internal(format!("Failed to get presence: {}", e)))?;
+
+        let now = chrono::Utc::now().timestamp_millis();
+        Ok(changes
+            .into_iter()
+            .map(|(sender, presence, status_msg, last_active_ts)| {
+                let mut content = json!({
+                    "presence": presence,
+                    "last_active_ago": (now - last_active_ts).max(0),
+                    "currently_active": presence == "online"
+                });
+                if let Some(status_msg) = status_msg {
+                    content["status_msg"] = json!(status_msg);
+                }
+                json!({
+                    "type": "m.presence",
+                    "sender": sender,
+                    "content": content
+                })
+            })
+            .collect())
     }
 
-    async fn get_account_data_events(&self, _user_id: &str) -> ApiResult<Vec<serde_json::Value>> {
-        Ok(vec![])
+    /// Global account data. Push rules are only sent again once they have changed.
+    pub(crate) async fn get_account_data_events(
+        &self,
+        user_id: &str,
+        since: &Option<SyncToken>,
+    ) -> ApiResult<Vec<serde_json::Value>> {
+        let mut events = Vec::new();
+        if let Some(push_rule_service) = &self.push_rule_service {
+            let changed = match since {
+                Some(token) => push_rule_service.changed_since(user_id, token.stream_id).await?,
+                None => true,
+            };
+            if changed {
+                events.push(push_rule_service.get_account_data_event(user_id).await?);
+            }
+        }
+        Ok(events)
     }
 
-    async fn get_to_device_events(&self, _user_id: &str, _since: &Option<SyncToken>) -> ApiResult<Vec<serde_json::Value>> {
-        Ok(vec![])
+    pub(crate) async fn get_to_device_events(
+        &self,
+        user_id: &str,
+        device_id: Option<&str>,
+        since: &Option<SyncToken>,
+        next_batch: i64,
+    ) -> ApiResult<Vec<serde_json::Value>> {
+        let (Some(service), Some(device_id)) = (&self.to_device_service, device_id) else {
+            return Ok(vec![]);
+        };
+
+        service
+            .get_messages_for_sync(user_id, device_id, since.as_ref().map(|t| t.stream_id), next_batch)
+            .await
     }
 
-    async fn get_device_lists(&self, _user_id: &str, _since: &Option<SyncToken>) -> ApiResult<serde_json::Value> {
+    /// Users whose device keys changed (`changed`) or who no longer share a room (`left`).
+    /// Only reported for incremental syncs; an initial sync starts from a full key query.
+    pub(crate) async fn get_device_lists(
+        &self,
+        user_id: &str,
+        since: &Option<SyncToken>,
+        next_batch: i64,
+    ) -> ApiResult<serde_json::Value> {
+        let Some(since) = since else {
+            return Ok(json!({
+                "changed": [],
+                "left": []
+            }));
+        };
+
+        let shared: HashSet<String> = self
+            .member_storage
+            .get_users_sharing_rooms(user_id)
+            .await
+            .map_err(|e| ApiError::internal(format!("Failed to get shared users: {}", e)))?
+            .into_iter()
+            .chain(std::iter::once(user_id.to_string()))
+            .collect();
+
+        let mut changed = HashSet::new();
+        let mut left = HashSet::new();
+
+        if let Some(service) = &self.device_keys_service {
+            let (key_changes, _) = service
+                .get_key_changes(&since.stream_id.to_string(), &next_batch.to_string())
+                .await?;
+            changed.extend(key_changes.into_iter().filter(|u| shared.contains(u)));
+        }
+
+        let membership_changes = self
+            .member_storage
+            .get_membership_changes_since(user_id, since.stream_id)
+            .await
+            .map_err(|e| ApiError::internal(format!("Failed to get membership changes: {}", e)))?;
+        for (other, joined) in membership_changes {
+            if shared.contains(&other) {
+                if joined {
+                    changed.insert(other);
+                }
+            } else {
+                left.insert(other);
+            }
+        }
+
+        let mut changed: Vec<String> = changed.into_iter().collect();
+        let mut left: Vec<String> = left.into_iter().collect();
+        changed.sort();
+        left.sort();
+
         Ok(json!({
-            "changed": [],
-            "left": []
+            "changed": changed,
+            "left": left
         }))
     }
 
-    async fn get_room_ephemeral_events(&self, _room_id: &str, _user_id: &str) -> ApiResult<Vec<serde_json::Value>> {
-        Ok(vec![])
+    pub(crate) async fn get_device_key_counts(
+        &self,
+        user_id: &str,
+        device_id: Option<&str>,
+    ) -> ApiResult<(serde_json::Value, Vec<String>)> {
+        let (Some(service), Some(device_id)) = (&self.device_keys_service, device_id) else {
+            return Ok((json!({}), vec![]));
+        };
+
+        let counts = service.get_one_time_key_counts(user_id, device_id).await?;
+        let fallback_types = service
+            .get_unused_fallback_key_types(user_id, device_id)
+            .await?;
+        Ok((counts, fallback_types))
+    }
+
+    async fn get_room_ephemeral_events(
+        &self,
+        room_id: &str,
+        user_id: &str,
+        since: &Option<SyncToken>,
+    ) -> ApiResult<Vec<serde_json::Value>> {
+        let mut events = Vec::new();
+        if let Some(typing) = self.get_room_typing_event(room_id, since).await? {
+            events.push(typing);
+        }
+        if let Some(receipts) = self.get_room_receipt_event(room_id, user_id, since).await? {
+            events.push(receipts);
+        }
+        Ok(events)
+    }
+
+    pub(crate) async fn get_room_typing_event(
+        &self,
+        room_id: &str,
+        since: &Option<SyncToken>,
+    ) -> ApiResult<Option<serde_json::Value>> {
+        let typing_changed = match since {
+            Some(token) => self
+                .notifier
+                .room_position(NotifierStream::Typing, room_id)
+                .is_some_and(|position| position > token.stream_id),
+            None => true,
+        };
+        if !typing_changed {
+            return Ok(None);
+        }
+
+        let typing_users = self
+            .presence_storage
+            .get_typing_users(room_id)
+            .await
+            .map_err(|e| ApiError::internal(format!("Failed to get typing users: {}", e)))?;
+
+        if since.is_none() && typing_users.is_empty() {
+            return Ok(None);
+        }
+
+        Ok(Some(json!({
+            "type": "m.typing",
+            "content": {
+                "user_ids": typing_users
+            }
+        })))
     }
 
-    async fn get_room_account_data_events(&self, _room_id: &str, _user_id: &str) -> ApiResult<Vec<serde_json::Value>> {
+    /// Builds an `m.receipt` event from receipts updated since the token. Private read
+    /// receipts are only ever shown to their owner.
+    pub(crate) async fn get_room_receipt_event(
+        &self,
+        room_id: &str,
+        user_id: &str,
+        since: &Option<SyncToken>,
+    ) -> ApiResult<Option<serde_json::Value>> {
+        let since_ts = since.as_ref().map(|t| t.stream_id).unwrap_or(0);
+        let receipts = self
+            .room_storage
+            .get_receipts_since(room_id, since_ts)
+            .await
+            .map_err(|e| ApiError::internal(format!("Failed to get receipts: {}", e)))?;
+
+        let mut content = serde_json::Map::new();
+        for (receipt_type, event_id, reader, ts) in receipts {
+            if receipt_type == "m.read.private" && reader != user_id {
+                continue;
+            }
+            let by_type = content
+                .entry(event_id)
+                .or_insert_with(|| json!({}));
+            by_type[receipt_type.as_str()][reader.as_str()] = json!({ "ts": ts });
+        }
+
+        if content.is_empty() {
+            return Ok(None);
+        }
+        Ok(Some(json!({
+            "type": "m.receipt",
+            "content": content
+        })))
+    }
+
+    pub(crate) async fn get_room_account_data_events(&self, _room_id: &str, _user_id: &str) -> ApiResult<Vec<serde_json::Value>> {
         Ok(vec![])
     }
 
-    async fn get_unread_counts(&self, _room_id: &str, _user_id: &str) -> ApiResult<(i64, i64)> {
-        Ok((0, 0))
+    /// `(highlight_count, notification_count)` of the user's unread events in the room.
+    pub(crate) async fn get_unread_counts(&self, room_id: &str, user_id: &str) -> ApiResult<(i64, i64)> {
+        Ok(self.get_room_unread_counts(room_id, user_id).await?.total())
+    }
+
+    async fn get_room_unread_counts(&self, room_id: &str, user_id: &str) -> ApiResult<RoomUnreadCounts> {
+        match &self.push_actions_service {
+            Some(push_actions) => push_actions.get_unread_counts(user_id, room_id).await,
+            None => Ok(RoomUnreadCounts::default()),
+        }
     }
 
     pub async fn get_room_messages(
@@ -313,7 +1349,8 @@ impl SyncService {
         user_id: &str,
         from: &str,
         limit: i64,
-        _dir: &str,
+        dir: &str,
+        filter: &SyncFilter,
     ) -> ApiResult<serde_json::Value> {
         if !self
             .member_storage
@@ -326,27 +1363,71 @@ impl SyncService {
             ));
         }
 
-        let events = self
-            .event_storage
-            .get_room_events(room_id, limit)
-            .await
-            .map_err(|e| ApiError::internal(format!("Failed to get messages: {}", e)))?;
+        let forwards = dir == "f";
+        let limit = limit.max(1);
+        let batch_size = if filter.is_restrictive() {
+            (limit * FILTER_OVERFETCH_FACTOR).min(MAX_FILTER_FETCH)
+        } else {
+            limit
+        };
 
-        let event_list: Vec<serde_json::Value> = events
-            .iter()
-            .map(|e| self.event_to_json(e))
-            .collect();
+        let mut cursor = parse_stream_token(from).unwrap_or(if forwards { 0 } else { i64::MAX });
+        let mut chunk = Vec::new();
+        let mut scanned = 0i64;
 
-        let end_token = events
-            .last()
-            .map(|e| format!("t{}", e.origin_server_ts))
-            .unwrap_or_else(|| format!("t{}", chrono::Utc::now().timestamp_millis()));
+        'outer: while scanned < MAX_FILTER_FETCH {
+            let batch = if forwards {
+                self.event_storage.get_events_after(room_id, cursor, batch_size).await
+            } else {
+                self.event_storage.get_events_before(room_id, cursor, batch_size).await
+            }
+            .map_err(|e| ApiError::internal(format!("Failed to get messages: {}", e)))?;
 
-        Ok(json!({
-            "chunk": event_list,
+            let exhausted = (batch.len() as i64) < batch_size;
+            for event in &batch {
+                scanned += 1;
+                cursor = event.origin_server_ts;
+                let value = self.event_to_json(event);
+                if filter.matches(&value, Some(room_id)) {
+                    chunk.push(value);
+                    if chunk.len() as i64 >= limit {
+                        break 'outer;
+                    }
+                }
+            }
+            if exhausted {
+                break;
+            }
+        }
+
+        self.bundle_aggregations(user_id, &mut chunk).await?;
+        let mut response = json!({
             "start": from,
-            "end": end_token
-        }))
+            "end": format!("t{}", cursor)
+        });
+
+        if filter.lazy_load_members {
+            let senders: HashSet<&str> = chunk.iter().filter_map(|e| e["sender"].as_str()).collect();
+            let members = self
+                .event_storage
+                .get_state_events_by_type(room_id, "m.room.member")
+                .await
+                .map_err(|e| ApiError::internal(format!("Failed to get member events: {}", e)))?;
+
+            let mut seen = HashSet::new();
+            let state: Vec<serde_json::Value> = members
+                .iter()
+                .filter(|e| {
+                    let state_key = e.state_key.as_deref().unwrap_or_default();
+                    senders.contains(state_key) && seen.insert(state_key.to_string())
+                })
+                .map(|e| self.state_event_to_json(e))
+                .collect();
+            response["state"] = json!(state);
+        }
+
+        response["chunk"] = json!(chunk);
+        Ok(response)
     }
 
     pub async fn get_public_rooms(
@@ -392,12 +1473,45 @@ impl SyncService {
         Ok(response)
     }
 
-    pub async fn get_filter(&self, _user_id: &str, _filter_id: &str) -> ApiResult<serde_json::Value> {
-        Ok(json!({}))
+    fn filter_storage(&self) -> ApiResult<&UserStorage> {
+        self.user_storage
+            .as_ref()
+            .ok_or_else(|| ApiError::internal("Filter storage is not configured".to_string()))
     }
 
-    pub async fn set_filter(&self, _user_id: &str, _filter: &serde_json::Value) -> ApiResult<String> {
-        Ok(format!("filter_{}", chrono::Utc::now().timestamp_millis()))
+    pub async fn get_filter(&self, user_id: &str, filter_id: &str) -> ApiResult<Option<serde_json::Value>> {
+        self.filter_storage()?
+            .get_filter(user_id, filter_id)
+            .await
+            .map_err(|e| ApiError::internal(format!("Failed to get filter: {}", e)))
+    }
+
+    pub async fn set_filter(&self, user_id: &str, filter: &serde_json::Value) -> ApiResult<String> {
+        FilterDefinition::parse(filter)?;
+
+        self.filter_storage()?
+            .create_filter(user_id, filter)
+            .await
+            .map_err(|e| ApiError::internal(format!("Failed to create filter: {}", e)))
+    }
+
+    /// Resolves the `filter` parameter of `/sync`, which is either inline JSON or a filter ID.
+    pub async fn resolve_filter(&self, user_id: &str, filter: Option<&str>) -> ApiResult<FilterDefinition> {
+        let Some(filter) = filter.map(str::trim).filter(|f| !f.is_empty()) else {
+            return Ok(FilterDefinition::default());
+        };
+
+        if filter.starts_with('{') {
+            let value: serde_json::Value = serde_json::from_str(filter)
+                .map_err(|e| ApiError::bad_request(format!("Invalid filter JSON: {}", e)))?;
+            return FilterDefinition::parse(&value);
+        }
+
+        let value = self
+            .get_filter(user_id, filter)
+            .await?
+            .ok_or_else(|| ApiError::not_found(format!("Filter {} not found", filter)))?;
+        FilterDefinition::parse(&value)
     }
 }
 
@@ -417,6 +1531,7 @@ mod tests {
     fn test_sync_token_encode() {
         let token = SyncToken {
             stream_id: 1234567890,
+            event_stream: None,
             room_id: None,
             event_type: None,
         };
@@ -427,6 +1542,7 @@ mod tests {
     fn test_sync_token_roundtrip() {
         let original = SyncToken {
             stream_id: 9876543210,
+            event_stream: None,
             room_id: None,
             event_type: None,
         };
@@ -435,6 +1551,22 @@ mod tests {
         assert_eq!(original.stream_id, parsed.stream_id);
     }
 
+    #[test]
+    fn test_sync_token_carries_event_stream() {
+        let original = SyncToken {
+            stream_id: 9876543210,
+            event_stream: Some(42),
+            room_id: None,
+            event_type: None,
+        };
+        let encoded = original.encode();
+        assert_eq!(encoded, "s9876543210_42");
+        let parsed = SyncToken::parse(&encoded).unwrap();
+        assert_eq!(parsed.stream_id, 9876543210);
+        assert_eq!(parsed.event_stream, Some(42));
+        assert!(SyncToken::parse("s9876543210_x").is_none());
+    }
+
     #[test]
     fn test_sync_filter_default() {
         let filter = SyncFilter::default();
@@ -580,4 +1712,119 @@ mod tests {
         assert!(response.get("total_room_count_estimate").is_some());
         assert!(response.get("next_batch").is_some());
     }
+
+    #[test]
+    fn test_type_matches_wildcards() {
+        assert!(type_matches("m.room.message", "m.room.message"));
+        assert!(type_matches("m.room.*", "m.room.member"));
+        assert!(type_matches("*", "m.presence"));
+        assert!(type_matches("m.*.member", "m.room.member"));
+        assert!(!type_matches("m.room.*", "m.presence"));
+        assert!(!type_matches("m.room", "m.room.message"));
+    }
+
+    #[test]
+    fn test_sync_filter_matches() {
+        let filter = SyncFilter {
+            types: Some(vec!["m.room.*".to_string()]),
+            not_types: Some(vec!["m.room.member".to_string()]),
+            not_senders: Some(vec!["@spam:example.com".to_string()]),
+            not_rooms: Some(vec!["!hidden:example.com".to_string()]),
+            ..Default::default()
+        };
+        let message = json!({"type": "m.room.message", "sender": "@alice:example.com", "content": {}});
+
+        assert!(filter.matches(&message, Some("!room:example.com")));
+        assert!(!filter.matches(&message, Some("!hidden:example.com")));
+        assert!(!filter.matches(&json!({"type": "m.room.member", "sender": "@alice:example.com"}), None));
+        assert!(!filter.matches(&json!({"type": "m.room.message", "sender": "@spam:example.com"}), None));
+        assert!(!filter.matches(&json!({"type": "m.typing"}), None));
+    }
+
+    #[test]
+    fn test_sync_filter_contains_url() {
+        let filter = SyncFilter {
+            contains_url: Some(true),
+            ..Default::default()
+        };
+        assert!(filter.matches(&json!({"type": "m.room.message", "content": {"url": "mxc://a/b"}}), None));
+        assert!(!filter.matches(&json!({"type": "m.room.message", "content": {"body": "hi"}}), None));
+    }
+
+    #[test]
+    fn test_sync_filter_apply_respects_limit() {
+        let filter = SyncFilter {
+            limit: Some(2),
+            ..Default::default()
+        };
+        let events = vec![json!({"type": "a"}), json!({"type": "b"}), json!({"type": "c"})];
+        assert_eq!(filter.apply(events, None).len(), 2);
+    }
+
+    #[test]
+    fn test_room_filter_sections() {
+        let filter: RoomFilter = serde_json::from_value(json!({
+            "rooms": ["!a:example.com"],
+            "include_leave": true,
+            "state": {"lazy_load_members": true, "limit": 1}
+        }))
+        .unwrap();
+
+        assert!(filter.matches_room("!a:example.com"));
+        assert!(!filter.matches_room("!b:example.com"));
+        assert!(filter.include_leave);
+        assert_eq!(filter.timeline_filter().limit, Some(DEFAULT_TIMELINE_LIMIT));
+        assert!(filter.state_filter().lazy_load_members);
+        assert_eq!(filter.state_filter().limit, None);
+    }
+
+    #[test]
+    fn test_filter_definition_parse() {
+        assert!(FilterDefinition::parse(&json!({})).is_ok());
+        assert!(FilterDefinition::parse(&json!({"event_format": "client"})).is_ok());
+        assert!(FilterDefinition::parse(&json!({"event_format": "xml"})).is_err());
+        assert!(FilterDefinition::parse(&json!({"room": {"timeline": {"limit": "ten"}}})).is_err());
+    }
+
+    #[test]
+    fn test_event_fields_projection() {
+        let filter = FilterDefinition {
+            event_fields: Some(vec![
+                "type".to_string(),
+                "content.body".to_string(),
+                "content.m\\.relates_to".to_string(),
+                "unsigned.age".to_string(),
+            ]),
+            ..Default::default()
+        };
+        let event = json!({
+            "type": "m.room.message",
+            "sender": "@alice:example.com",
+            "content": {"body": "hi", "msgtype": "m.text", "m.relates_to": {"rel_type": "m.thread"}},
+            "unsigned": 5
+        });
+
+        assert_eq!(
+            filter.apply_event_fields(event),
+            json!({
+                "type": "m.room.message",
+                "content": {"body": "hi", "m.relates_to": {"rel_type": "m.thread"}}
+            })
+        );
+    }
+
+    #[test]
+    fn test_split_field_path_escapes() {
+        assert_eq!(split_field_path("content.body"), vec!["content", "body"]);
+        assert_eq!(split_field_path("content.m\\.relates_to"), vec!["content", "m.relates_to"]);
+        assert_eq!(split_field_path("a\\\\b"), vec!["a\\b"]);
+    }
+
+    #[test]
+    fn test_parse_stream_token() {
+        assert_eq!(parse_stream_token("t123"), Some(123));
+        assert_eq!(parse_stream_token("s456"), Some(456));
+        assert_eq!(parse_stream_token("789"), Some(789));
+        assert_eq!(parse_stream_token(""), None);
+    }
 }
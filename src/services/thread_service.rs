@@ -1,9 +1,15 @@
+use crate::common::error::ApiError;
+use crate::common::ApiResult;
+use crate::storage::event::{EventStorage, RoomEvent};
+use crate::storage::relations::{EventRelation, RelationsStorage, ThreadSubscriptionRow, ThreadSummaryRow};
 use chrono::Utc;
 use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
 use std::collections::HashMap;
-use std::sync::Arc;
-use tokio::sync::RwLock;
-use tracing::{debug, info};
+use tracing::debug;
+
+/// How deep `/relations?recurse=true` follows relations of relations.
+const MAX_RELATIONS_RECURSION_DEPTH: i32 = 3;
 
 /// Thread relation types (MSC3440)
 #[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
@@ -45,7 +51,7 @@ impl ThreadSummary {
     pub fn new(room_id: String, root_event_id: String) -> Self {
         let now = Utc::now().timestamp_millis();
         Self {
-            thread_id: format!("thread_{}", root_event_id),
+            thread_id: root_event_id.clone(),
             room_id,
             root_event_id: root_event_id.clone(),
             latest_event_id: root_event_id,
@@ -157,99 +163,290 @@ impl ThreadEvent {
     }
 }
 
-/// Thread service for managing threads
+/// Which relations of an event `/relations` lists, and from where.
+#[derive(Debug, Clone, Default)]
+pub struct RelationsQuery {
+    pub rel_type: Option<String>,
+    pub event_type: Option<String>,
+    pub from: Option<String>,
+    pub limit: i64,
+    pub forwards: bool,
+    pub recurse: bool,
+}
+
+/// Thread service for managing threads. Threads and other relations are recorded by
+/// `EventStorage` as events are persisted; this service reads them back for the client API.
 pub struct ThreadService {
-    threads: Arc<RwLock<HashMap<String, ThreadSummary>>>,
-    subscriptions: Arc<RwLock<HashMap<String, Vec<ThreadSubscription>>>>,
-    events: Arc<RwLock<HashMap<String, Vec<ThreadEvent>>>>,
+    storage: RelationsStorage,
+    event_storage: EventStorage,
 }
 
 impl ThreadService {
-    pub fn new() -> Self {
+    pub fn new(storage: RelationsStorage, event_storage: EventStorage) -> Self {
         Self {
-            threads: Arc::new(RwLock::new(HashMap::new())),
-            subscriptions: Arc::new(RwLock::new(HashMap::new())),
-            events: Arc::new(RwLock::new(HashMap::new())),
+            storage,
+            event_storage,
         }
     }
 
-    pub async fn create_thread(
+    /// The events relating to `event_id`, with the `chunk`, `next_batch` and, when recursing,
+    /// `recursion_depth` of a `/relations` response.
+    pub async fn get_relations(
         &self,
+        user_id: &str,
         room_id: &str,
-        root_event_id: &str,
-    ) -> ThreadSummary {
-        let thread = ThreadSummary::new(room_id.to_string(), root_event_id.to_string());
-        let thread_id = thread.thread_id.clone();
-        
-        self.threads.write().await.insert(thread_id.clone(), thread.clone());
-        
-        info!(
-            thread_id = %thread_id,
-            room_id = %room_id,
-            root_event_id = %root_event_id,
-            "Thread created"
-        );
-        
-        thread
+        event_id: &str,
+        query: &RelationsQuery,
+    ) -> ApiResult<Value> {
+        match self.get_event(event_id).await? {
+            Some(parent) if parent.room_id == room_id => {}
+            _ => return Err(ApiError::not_found("Event not found")),
+        }
+        let from = query
+            .from
+            .as_deref()
+            .map(|token| parse_position_token(token).ok_or_else(|| ApiError::bad_request("Invalid from token")))
+            .transpose()?;
+        let max_depth = if query.recurse { MAX_RELATIONS_RECURSION_DEPTH } else { 1 };
+        let rows = self
+            .storage
+            .get_relations(
+                room_id,
+                event_id,
+                query.rel_type.as_deref(),
+                query.event_type.as_deref(),
+                from.as_ref().map(|(ordering, event_id)| (*ordering, event_id.as_str())),
+                query.limit,
+                query.forwards,
+                max_depth,
+            )
+            .await
+            .map_err(|e| ApiError::internal(format!("Failed to get relations: {}", e)))?;
+
+        let event_ids: Vec<String> = rows.iter().map(|row| row.event_id.clone()).collect();
+        let mut chunk = self.client_events(&event_ids).await?;
+        self.bundle_aggregations(user_id, &mut chunk).await?;
+
+        let mut response = json!({ "chunk": chunk });
+        if let Some(last) = rows.last().filter(|_| rows.len() as i64 >= query.limit) {
+            response["next_batch"] = json!(position_token(last.stream_ordering, &last.event_id));
+        }
+        if query.recurse {
+            response["recursion_depth"] = json!(rows.iter().map(|row| row.depth).max().unwrap_or(0));
+        }
+        Ok(response)
     }
 
-    pub async fn add_event_to_thread(
+    /// The room's thread roots, most recently active first, with their thread summaries
+    /// bundled. `participated_only` keeps the threads the user started or replied to.
+    pub async fn get_threads(
         &self,
-        _room_id: &str,
-        root_event_id: &str,
-        event: ThreadEvent,
-    ) -> Option<ThreadSummary> {
-        let thread_id = format!("thread_{}", root_event_id);
-        
-        let mut threads = self.threads.write().await;
-        
-        if let Some(thread) = threads.get_mut(&thread_id) {
-            thread.increment(event.event_id.clone(), event.sender.clone());
-            
-            let mut events = self.events.write().await;
-            events
-                .entry(thread_id.clone())
+        user_id: &str,
+        room_id: &str,
+        participated_only: bool,
+        from: Option<&str>,
+        limit: i64,
+    ) -> ApiResult<Value> {
+        let before = from
+            .map(|token| parse_position_token(token).ok_or_else(|| ApiError::bad_request("Invalid from token")))
+            .transpose()?;
+        let threads = self
+            .storage
+            .get_threads(
+                room_id,
+                participated_only.then_some(user_id),
+                before.as_ref().map(|(ordering, root)| (*ordering, root.as_str())),
+                limit,
+            )
+            .await
+            .map_err(|e| ApiError::internal(format!("Failed to get threads: {}", e)))?;
+
+        let roots: Vec<String> = threads.iter().map(|thread| thread.root_event_id.clone()).collect();
+        let mut chunk = self.client_events(&roots).await?;
+        self.bundle_aggregations(user_id, &mut chunk).await?;
+
+        let mut response = json!({ "chunk": chunk });
+        if let Some(last) = threads.last().filter(|_| threads.len() as i64 >= limit) {
+            response["next_batch"] = json!(position_token(last.latest_ordering, &last.root_event_id));
+        }
+        Ok(response)
+    }
+
+    /// Adds `unsigned.m.relations` to client events: the summary of the thread they are the
+    /// root of, their latest edit by their own sender, the events referencing them and their
+    /// annotations counted by key.
+    pub async fn bundle_aggregations(&self, user_id: &str, events: &mut [Value]) -> ApiResult<()> {
+        let event_ids: Vec<String> = events
+            .iter()
+            .filter_map(|event| event["event_id"].as_str().map(str::to_string))
+            .collect();
+        if event_ids.is_empty() {
+            return Ok(());
+        }
+        let storage_error = |e: sqlx::Error| ApiError::internal(format!("Failed to get relations: {}", e));
+        let summaries: HashMap<String, ThreadSummaryRow> = self
+            .storage
+            .get_thread_summaries(&event_ids, user_id)
+            .await
+            .map_err(storage_error)?
+            .into_iter()
+            .map(|summary| (summary.root_event_id.clone(), summary))
+            .collect();
+        let edits: HashMap<String, String> = self
+            .storage
+            .get_latest_edits(&event_ids)
+            .await
+            .map_err(storage_error)?
+            .into_iter()
+            .collect();
+        let mut references: HashMap<String, Vec<Value>> = HashMap::new();
+        for (referenced, referencing) in self.storage.get_references(&event_ids).await.map_err(storage_error)? {
+            references
+                .entry(referenced)
                 .or_default()
-                .push(event);
-            
-            debug!(
-                thread_id = %thread_id,
-                count = thread.count,
-                "Event added to thread"
-            );
-            
-            return Some(thread.clone());
+                .push(json!({ "event_id": referencing }));
         }
-        
-        None
-    }
 
-    pub async fn get_thread(&self, thread_id: &str) -> Option<ThreadSummary> {
-        self.threads.read().await.get(thread_id).cloned()
-    }
+        let mut annotations: HashMap<String, Vec<Value>> = HashMap::new();
+        for annotation in self.storage.get_annotation_counts(&event_ids).await.map_err(storage_error)? {
+            annotations.entry(annotation.relates_to_id).or_default().push(json!({
+                "type": annotation.event_type,
+                "key": annotation.aggregation_key,
+                "count": annotation.count
+            }));
+        }
 
-    pub async fn get_thread_by_root_event(&self, root_event_id: &str) -> Option<ThreadSummary> {
-        let thread_id = format!("thread_{}", root_event_id);
-        self.get_thread(&thread_id).await
+        let related_ids: Vec<String> = summaries
+            .values()
+            .map(|summary| summary.latest_event_id.clone())
+            .chain(edits.values().cloned())
+            .collect();
+        let related: HashMap<String, Value> = self
+            .client_events(&related_ids)
+            .await?
+            .into_iter()
+            .filter_map(|event| Some((event["event_id"].as_str()?.to_string(), event)))
+            .collect();
+
+        for event in events.iter_mut() {
+            let Some(event_id) = event["event_id"].as_str().map(str::to_string) else {
+                continue;
+            };
+            let mut relations = serde_json::Map::new();
+            if let Some(summary) = summaries.get(&event_id) {
+                if let Some(latest_event) = related.get(&summary.latest_event_id) {
+                    let participated = summary.participated || event["sender"] == user_id;
+                    relations.insert(
+                        "m.thread".to_string(),
+                        json!({
+                            "latest_event": latest_event,
+                            "count": summary.count,
+                            "current_user_participated": participated
+                        }),
+                    );
+                }
+            }
+            if let Some(edit) = edits.get(&event_id).and_then(|edit_id| related.get(edit_id)) {
+                relations.insert("m.replace".to_string(), edit.clone());
+            }
+            if let Some(chunk) = references.remove(&event_id) {
+                relations.insert("m.reference".to_string(), json!({ "chunk": chunk }));
+            }
+            if let Some(chunk) = annotations.remove(&event_id) {
+                relations.insert("m.annotation".to_string(), json!({ "chunk": chunk }));
+            }
+            if !relations.is_empty() {
+                event["unsigned"]["m.relations"] = Value::Object(relations);
+            }
+        }
+        Ok(())
     }
 
-    pub async fn get_thread_events(&self, thread_id: &str) -> Vec<ThreadEvent> {
-        self.events
-            .read()
-            .await
-            .get(thread_id)
-            .cloned()
-            .unwrap_or_default()
+    /// Refuses relations a client may not send: edits of state events, of other senders'
+    /// events or across event types, and the same annotation twice.
+    pub async fn check_relation(
+        &self,
+        room_id: &str,
+        sender: &str,
+        event_type: &str,
+        content: &Value,
+    ) -> ApiResult<()> {
+        let Some(relation) = EventRelation::from_content(content) else {
+            return Ok(());
+        };
+        let target = match self.get_event(&relation.relates_to_id).await? {
+            Some(target) if target.room_id == room_id => target,
+            _ => return Err(ApiError::bad_request("Related event not found in this room")),
+        };
+
+        match relation.rel_type.as_str() {
+            "m.replace" => {
+                if target.state_key.is_some() {
+                    return Err(ApiError::bad_request("State events cannot be edited"));
+                }
+                if target.user_id != sender {
+                    return Err(ApiError::forbidden("Only the sender of an event can edit it".to_string()));
+                }
+                if target.event_type != event_type {
+                    return Err(ApiError::bad_request("An edit must have the type of the original event"));
+                }
+                if EventRelation::from_content(&target.content).is_some_and(|r| r.rel_type == "m.replace") {
+                    return Err(ApiError::bad_request("Edits cannot be edited"));
+                }
+                if !content.get("m.new_content").is_some_and(Value::is_object) {
+                    return Err(ApiError::bad_request("An edit needs m.new_content"));
+                }
+            }
+            "m.annotation" => {
+                let key = relation
+                    .aggregation_key
+                    .ok_or_else(|| ApiError::bad_request("An annotation needs a key"))?;
+                let duplicate = self
+                    .storage
+                    .has_annotation(&target.event_id, sender, event_type, &key)
+                    .await
+                    .map_err(|e| ApiError::internal(format!("Failed to check annotations: {}", e)))?;
+                if duplicate {
+                    return Err(ApiError::bad_request("Cannot send the same annotation twice"));
+                }
+            }
+            _ => {}
+        }
+        Ok(())
     }
 
-    pub async fn get_room_threads(&self, room_id: &str) -> Vec<ThreadSummary> {
-        self.threads
-            .read()
+    /// The summary of the thread rooted at `root_event_id`, if it has any replies.
+    pub async fn get_thread_by_root_event(
+        &self,
+        user_id: &str,
+        root_event_id: &str,
+    ) -> ApiResult<Option<ThreadSummary>> {
+        let Some(root) = self.get_event(root_event_id).await? else {
+            return Ok(None);
+        };
+        let Some(summary) = self
+            .storage
+            .get_thread_summaries(&[root_event_id.to_string()], user_id)
             .await
-            .values()
-            .filter(|t| t.room_id == room_id)
-            .cloned()
-            .collect()
+            .map_err(|e| ApiError::internal(format!("Failed to get thread: {}", e)))?
+            .pop()
+        else {
+            return Ok(None);
+        };
+        let Some(latest) = self.get_event(&summary.latest_event_id).await? else {
+            return Ok(None);
+        };
+        Ok(Some(ThreadSummary {
+            thread_id: root.event_id.clone(),
+            room_id: root.room_id,
+            root_event_id: root.event_id,
+            latest_event_id: latest.event_id,
+            latest_sender: latest.user_id,
+            count: summary.count as u64,
+            current_user_participated: summary.participated || root.user_id == user_id,
+            created_at: root.origin_server_ts,
+            updated_at: latest.origin_server_ts,
+        }))
     }
 
     pub async fn subscribe_to_thread(
@@ -257,108 +454,114 @@ impl ThreadService {
         user_id: &str,
         thread_id: &str,
         room_id: &str,
-    ) -> ThreadSubscription {
-        let subscription = ThreadSubscription::new(
-            user_id.to_string(),
-            thread_id.to_string(),
-            room_id.to_string(),
-        );
-        
-        self.subscriptions
-            .write()
+    ) -> ApiResult<ThreadSubscription> {
+        let row = self
+            .storage
+            .subscribe_to_thread(user_id, room_id, thread_id, true)
             .await
-            .entry(user_id.to_string())
-            .or_default()
-            .push(subscription.clone());
-        
+            .map_err(|e| ApiError::internal(format!("Failed to subscribe to thread: {}", e)))?;
+
         debug!(
             user_id = %user_id,
             thread_id = %thread_id,
             "User subscribed to thread"
         );
-        
-        subscription
+
+        Ok(row.into())
     }
 
-    pub async fn unsubscribe_from_thread(&self, user_id: &str, thread_id: &str) -> bool {
-        let mut subscriptions = self.subscriptions.write().await;
-        
-        if let Some(user_subs) = subscriptions.get_mut(user_id) {
-            let before = user_subs.len();
-            user_subs.retain(|s| s.thread_id != thread_id);
-            return user_subs.len() < before;
-        }
-        
-        false
+    pub async fn unsubscribe_from_thread(&self, user_id: &str, thread_id: &str) -> ApiResult<bool> {
+        self.storage
+            .unsubscribe_from_thread(user_id, thread_id)
+            .await
+            .map_err(|e| ApiError::internal(format!("Failed to unsubscribe from thread: {}", e)))
     }
 
-    pub async fn get_user_subscriptions(&self, user_id: &str) -> Vec<ThreadSubscription> {
-        self.subscriptions
-            .read()
+    pub async fn get_user_subscriptions(&self, user_id: &str) -> ApiResult<Vec<ThreadSubscription>> {
+        let rows = self
+            .storage
+            .get_user_subscriptions(user_id)
             .await
-            .get(user_id)
-            .cloned()
-            .unwrap_or_default()
+            .map_err(|e| ApiError::internal(format!("Failed to get thread subscriptions: {}", e)))?;
+        Ok(rows.into_iter().map(ThreadSubscription::from).collect())
     }
 
-    pub async fn get_thread_subscribers(&self, thread_id: &str) -> Vec<String> {
-        self.subscriptions
-            .read()
+    pub async fn get_thread_subscribers(&self, thread_id: &str) -> ApiResult<Vec<String>> {
+        self.storage
+            .get_thread_subscribers(thread_id)
             .await
-            .values()
-            .flat_map(|subs| {
-                subs.iter()
-                    .filter(|s| s.thread_id == thread_id && s.notify)
-                    .map(|s| s.user_id.clone())
-            })
-            .collect()
+            .map_err(|e| ApiError::internal(format!("Failed to get thread subscribers: {}", e)))
     }
 
-    pub async fn mark_user_participated(&self, thread_id: &str, user_id: &str) {
-        if let Some(thread) = self.threads.write().await.get_mut(thread_id) {
-            thread.mark_participated();
-            debug!(
-                thread_id = %thread_id,
-                user_id = %user_id,
-                "User marked as participated"
-            );
-        }
+    async fn get_event(&self, event_id: &str) -> ApiResult<Option<RoomEvent>> {
+        self.event_storage
+            .get_event(event_id)
+            .await
+            .map_err(|e| ApiError::internal(format!("Failed to get event: {}", e)))
     }
 
-    pub async fn get_thread_count(&self, room_id: &str) -> u64 {
-        self.threads
-            .read()
+    /// The events in client format, in the order of `event_ids`, skipping unknown ones.
+    async fn client_events(&self, event_ids: &[String]) -> ApiResult<Vec<Value>> {
+        if event_ids.is_empty() {
+            return Ok(Vec::new());
+        }
+        let events: HashMap<String, RoomEvent> = self
+            .event_storage
+            .get_events_by_ids(event_ids)
             .await
-            .values()
-            .filter(|t| t.room_id == room_id)
-            .count() as u64
+            .map_err(|e| ApiError::internal(format!("Failed to get events: {}", e)))?
+            .into_iter()
+            .map(|event| (event.event_id.clone(), event))
+            .collect();
+        Ok(event_ids
+            .iter()
+            .filter_map(|event_id| events.get(event_id))
+            .map(RoomEvent::to_client_event)
+            .collect())
     }
+}
 
-    pub async fn delete_thread(&self, thread_id: &str) -> bool {
-        let removed = self.threads.write().await.remove(thread_id).is_some();
-        
-        if removed {
-            self.events.write().await.remove(thread_id);
-            
-            self.subscriptions
-                .write()
-                .await
-                .values_mut()
-                .for_each(|subs| subs.retain(|s| s.thread_id != thread_id));
-            
-            info!(thread_id = %thread_id, "Thread deleted");
+impl From<ThreadSubscriptionRow> for ThreadSubscription {
+    fn from(row: ThreadSubscriptionRow) -> Self {
+        Self {
+            user_id: row.user_id,
+            thread_id: row.root_event_id,
+            room_id: row.room_id,
+            subscribed_at: row.subscribed_ts,
+            notify: row.notify,
         }
-        
-        removed
     }
 }
 
-impl Default for ThreadService {
-    fn default() -> Self {
-        Self::new()
+/// Shows events as last edited: the `m.new_content` of the edit bundled by
+/// `bundle_aggregations` replaces their content, keeping the original's own relation.
+pub fn apply_edits(events: &mut [Value]) {
+    for event in events.iter_mut() {
+        let Some(new_content) = event["unsigned"]["m.relations"]["m.replace"]["content"]
+            .get("m.new_content")
+            .filter(|content| content.is_object())
+            .cloned()
+        else {
+            continue;
+        };
+        let relates_to = event["content"].get("m.relates_to").cloned();
+        event["content"] = new_content;
+        if let Some(relates_to) = relates_to {
+            event["content"]["m.relates_to"] = relates_to;
+        }
     }
 }
 
+/// A `(stream_ordering, event_id)` position as a pagination token.
+fn position_token(stream_ordering: i64, event_id: &str) -> String {
+    format!("{}_{}", stream_ordering, event_id)
+}
+
+fn parse_position_token(token: &str) -> Option<(i64, String)> {
+    let (ordering, event_id) = token.split_once('_')?;
+    Some((ordering.parse().ok()?, event_id.to_string()))
+}
+
 #[derive(Debug, thiserror::Error)]
 pub enum ThreadError {
     #[error("Thread not found")]
@@ -432,93 +635,41 @@ mod tests {
         assert_eq!(event.get_root_event_id(), Some("$root123"));
     }
 
-    #[tokio::test]
-    async fn test_create_thread() {
-        let service = ThreadService::new();
-        
-        let thread = service.create_thread(
-            "!room:example.com",
-            "$root123",
-        ).await;
-        
-        assert_eq!(thread.room_id, "!room:example.com");
-        assert_eq!(thread.root_event_id, "$root123");
+    #[test]
+    fn test_apply_edits_keeps_original_relation() {
+        let mut events = [
+            json!({
+                "event_id": "$original",
+                "content": {
+                    "body": "helo",
+                    "m.relates_to": { "rel_type": "m.thread", "event_id": "$root" }
+                },
+                "unsigned": { "m.relations": { "m.replace": {
+                    "event_id": "$edit",
+                    "content": {
+                        "body": "* hello",
+                        "m.new_content": { "body": "hello" },
+                        "m.relates_to": { "rel_type": "m.replace", "event_id": "$original" }
+                    }
+                } } }
+            }),
+            json!({ "event_id": "$unedited", "content": { "body": "as sent" } }),
+        ];
+        apply_edits(&mut events);
+
+        assert_eq!(events[0]["content"]["body"], "hello");
+        assert_eq!(events[0]["content"]["m.relates_to"]["rel_type"], "m.thread");
+        assert_eq!(events[0]["unsigned"]["m.relations"]["m.replace"]["event_id"], "$edit");
+        assert_eq!(events[1]["content"], json!({ "body": "as sent" }));
     }
 
-    #[tokio::test]
-    async fn test_add_event_to_thread() {
-        let service = ThreadService::new();
-        
-        service.create_thread("!room:example.com", "$root123").await;
-        
-        let content = json!({
-            "msgtype": "m.text",
-            "body": "Reply",
-            "m.relates_to": {
-                "rel_type": "m.thread",
-                "event_id": "$root123"
-            }
-        });
-        
-        let event = ThreadEvent::new(
-            "$event456".to_string(),
-            "!room:example.com".to_string(),
-            "@alice:example.com".to_string(),
-            "m.room.message".to_string(),
-            content,
+    #[test]
+    fn test_position_token_round_trip() {
+        let token = position_token(1700000000000, "$root_1:example.com");
+        assert_eq!(
+            parse_position_token(&token),
+            Some((1700000000000, "$root_1:example.com".to_string()))
         );
-        
-        let result = service.add_event_to_thread(
-            "!room:example.com",
-            "$root123",
-            event,
-        ).await;
-        
-        assert!(result.is_some());
-        let thread = result.unwrap();
-        assert_eq!(thread.count, 2);
-    }
-
-    #[tokio::test]
-    async fn test_subscribe_to_thread() {
-        let service = ThreadService::new();
-        
-        let thread = service.create_thread("!room:example.com", "$root123").await;
-        
-        let subscription = service.subscribe_to_thread(
-            "@alice:example.com",
-            &thread.thread_id,
-            "!room:example.com",
-        ).await;
-        
-        assert_eq!(subscription.user_id, "@alice:example.com");
-        assert!(subscription.notify);
-    }
-
-    #[tokio::test]
-    async fn test_get_thread_subscribers() {
-        let service = ThreadService::new();
-        
-        let thread = service.create_thread("!room:example.com", "$root123").await;
-        
-        service.subscribe_to_thread("@alice:example.com", &thread.thread_id, "!room:example.com").await;
-        service.subscribe_to_thread("@bob:example.com", &thread.thread_id, "!room:example.com").await;
-        
-        let subscribers = service.get_thread_subscribers(&thread.thread_id).await;
-        
-        assert_eq!(subscribers.len(), 2);
-    }
-
-    #[tokio::test]
-    async fn test_get_room_threads() {
-        let service = ThreadService::new();
-        
-        service.create_thread("!room:example.com", "$root1").await;
-        service.create_thread("!room:example.com", "$root2").await;
-        service.create_thread("!other:example.com", "$root3").await;
-        
-        let threads = service.get_room_threads("!room:example.com").await;
-        
-        assert_eq!(threads.len(), 2);
+        assert_eq!(parse_position_token("t1700000000000"), None);
     }
 }
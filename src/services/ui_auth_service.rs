@@ -0,0 +1,736 @@
+use crate::auth::AuthService;
+use crate::common::config::Config;
+use crate::common::ApiError;
+use crate::services::{
+    CaptchaAction, CaptchaService, ConsentPolicy, ConsentService, ConsentSource, ConsentType,
+    OidcService, PhoneVerificationService, RegistrationTokenService, TokenError,
+};
+use crate::storage::email_verification::EmailVerificationStorage;
+use crate::storage::{UiAuthSession, UiAuthStorage};
+use chrono::Utc;
+use serde_json::{json, Map, Value};
+use std::sync::Arc;
+use tracing::{info, warn};
+
+/// A stage of user-interactive authentication.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AuthStage {
+    Password,
+    EmailIdentity,
+    Msisdn,
+    Recaptcha,
+    Terms,
+    RegistrationToken,
+    Dummy,
+    Sso,
+}
+
+impl AuthStage {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            AuthStage::Password => "m.login.password",
+            AuthStage::EmailIdentity => "m.login.email.identity",
+            AuthStage::Msisdn => "m.login.msisdn",
+            AuthStage::Recaptcha => "m.login.recaptcha",
+            AuthStage::Terms => "m.login.terms",
+            AuthStage::RegistrationToken => "m.login.registration_token",
+            AuthStage::Dummy => "m.login.dummy",
+            AuthStage::Sso => "m.login.sso",
+        }
+    }
+
+    pub fn parse(s: &str) -> Option<Self> {
+        match s {
+            "m.login.password" => Some(AuthStage::Password),
+            "m.login.email.identity" => Some(AuthStage::EmailIdentity),
+            "m.login.msisdn" => Some(AuthStage::Msisdn),
+            "m.login.recaptcha" => Some(AuthStage::Recaptcha),
+            "m.login.terms" => Some(AuthStage::Terms),
+            "m.login.registration_token" => Some(AuthStage::RegistrationToken),
+            "m.login.dummy" => Some(AuthStage::Dummy),
+            "m.login.sso" => Some(AuthStage::Sso),
+            _ => None,
+        }
+    }
+}
+
+/// The request a UIA session authorizes. A session started for one action cannot be
+/// used to complete another.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum UiAuthAction {
+    Register,
+    ChangePassword,
+    DeactivateAccount,
+    DeleteDevices,
+    UploadCrossSigningKeys,
+}
+
+impl UiAuthAction {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            UiAuthAction::Register => "register",
+            UiAuthAction::ChangePassword => "change_password",
+            UiAuthAction::DeactivateAccount => "deactivate_account",
+            UiAuthAction::DeleteDevices => "delete_devices",
+            UiAuthAction::UploadCrossSigningKeys => "upload_cross_signing_keys",
+        }
+    }
+}
+
+/// User-interactive authentication (UIA). Each endpoint asks `authenticate` for its action;
+/// the client is answered with the flows still open until one flow's stages are all
+/// completed. Sessions, along with any SSO fallback in progress, live in Postgres so they
+/// survive across workers, and are dropped after `ui_auth.session_timeout`.
+pub struct UiAuthService {
+    storage: UiAuthStorage,
+    auth_service: AuthService,
+    server_name: String,
+    session_timeout_ms: i64,
+    maximum_sessions: u32,
+    registration_requires_token: bool,
+    registrations_require_3pid: Vec<String>,
+    captcha_service: Option<Arc<CaptchaService>>,
+    phone_verification_service: Option<Arc<PhoneVerificationService>>,
+    consent_service: Option<Arc<ConsentService>>,
+    registration_token_service: Option<Arc<RegistrationTokenService>>,
+    email_verification_storage: Option<EmailVerificationStorage>,
+    oidc_service: Option<Arc<OidcService>>,
+}
+
+impl UiAuthService {
+    pub fn new(storage: UiAuthStorage, auth_service: AuthService, config: &Config) -> Self {
+        Self {
+            storage,
+            auth_service,
+            server_name: config.server.name.clone(),
+            session_timeout_ms: config.ui_auth.session_timeout_ms(),
+            maximum_sessions: config.ui_auth.maximum_sessions.max(1),
+            registration_requires_token: config.server.registration_requires_token,
+            registrations_require_3pid: config.server.registrations_require_3pid.clone(),
+            captcha_service: None,
+            phone_verification_service: None,
+            consent_service: None,
+            registration_token_service: None,
+            email_verification_storage: None,
+            oidc_service: None,
+        }
+    }
+
+    pub fn with_captcha_service(mut self, captcha_service: Arc<CaptchaService>) -> Self {
+        self.captcha_service = Some(captcha_service);
+        self
+    }
+
+    pub fn with_phone_verification_service(
+        mut self,
+        phone_verification_service: Arc<PhoneVerificationService>,
+    ) -> Self {
+        self.phone_verification_service = Some(phone_verification_service);
+        self
+    }
+
+    pub fn with_consent_service(mut self, consent_service: Arc<ConsentService>) -> Self {
+        self.consent_service = Some(consent_service);
+        self
+    }
+
+    pub fn with_registration_token_service(
+        mut self,
+        registration_token_service: Arc<RegistrationTokenService>,
+    ) -> Self {
+        self.registration_token_service = Some(registration_token_service);
+        self
+    }
+
+    pub fn with_email_verification_storage(mut self, storage: EmailVerificationStorage) -> Self {
+        self.email_verification_storage = Some(storage);
+        self
+    }
+
+    pub fn with_oidc_service(mut self, oidc_service: Arc<OidcService>) -> Self {
+        self.oidc_service = Some(oidc_service);
+        self
+    }
+
+    /// The flows that complete `action`. Registration that needs no other stage is offered
+    /// `m.login.dummy`. Fails if registration requires a token but registration tokens are
+    /// not available.
+    pub async fn flows(&self, action: UiAuthAction) -> Result<Vec<Vec<AuthStage>>, ApiError> {
+        match action {
+            UiAuthAction::Register => {
+                let mut stages = Vec::new();
+                if self.registration_requires_token {
+                    if self.registration_token_service.is_none() {
+                        return Err(ApiError::internal(
+                            "Registration requires a token but registration tokens are not available"
+                                .to_string(),
+                        ));
+                    }
+                    stages.push(AuthStage::RegistrationToken);
+                }
+                if self
+                    .captcha_service
+                    .as_ref()
+                    .is_some_and(|captcha| captcha.requires_captcha(&CaptchaAction::Register))
+                {
+                    stages.push(AuthStage::Recaptcha);
+                }
+                if !self.required_policies().await.is_empty() {
+                    stages.push(AuthStage::Terms);
+                }
+                stages.extend(required_3pid_stages(&self.registrations_require_3pid));
+                if stages.is_empty() {
+                    stages.push(AuthStage::Dummy);
+                }
+                Ok(vec![stages])
+            }
+            _ => {
+                let mut flows = vec![vec![AuthStage::Password]];
+                if self.oidc_service.as_ref().is_some_and(|oidc| oidc.is_enabled()) {
+                    flows.push(vec![AuthStage::Sso]);
+                }
+                Ok(flows)
+            }
+        }
+    }
+
+    /// Runs a step of UIA for `action` by `user_id` (`None` while registering) with the
+    /// request's `auth` dict. Returns the completed session, or `None` if the action needs
+    /// no UIA; otherwise fails with the 401 response telling the client what is left.
+    /// After the request succeeds, pass the session to `finish`.
+    pub async fn authenticate(
+        &self,
+        action: UiAuthAction,
+        user_id: Option<&str>,
+        auth: Option<&Value>,
+    ) -> Result<Option<UiAuthSession>, ApiError> {
+        let flows = self.flows(action).await?;
+        if flows.is_empty() {
+            return Ok(None);
+        }
+
+        self.expire_sessions().await?;
+
+        let session_id = auth.and_then(|a| a.get("session")).and_then(|s| s.as_str());
+        let mut session = match session_id {
+            Some(session_id) => {
+                let session = self
+                    .storage
+                    .get_session(session_id)
+                    .await?
+                    .ok_or_else(|| ApiError::bad_request("Unknown UIA session".to_string()))?;
+                if session.action != action.as_str() || session.user_id.as_deref() != user_id {
+                    return Err(ApiError::forbidden(
+                        "UIA session was started for a different request".to_string(),
+                    ));
+                }
+                session
+            }
+            None => self.create_session(action, user_id).await?,
+        };
+
+        let mut stage_error = None;
+        if let Some(stage_type) = auth.and_then(|a| a.get("type")).and_then(|t| t.as_str()) {
+            let auth = auth.unwrap_or(&Value::Null);
+            match AuthStage::parse(stage_type).filter(|stage| is_offered(&flows, *stage)) {
+                Some(stage) if session.is_completed(stage.as_str()) => {}
+                Some(stage) => match self.check_stage(stage, user_id, auth).await {
+                    Ok(result) => {
+                        session = self
+                            .storage
+                            .add_completed(&session.session_id, stage.as_str(), &result)
+                            .await?
+                            .ok_or_else(|| ApiError::bad_request("Unknown UIA session".to_string()))?;
+                    }
+                    Err(e) => stage_error = Some(e),
+                },
+                None => {
+                    stage_error = Some(ApiError::bad_request(format!(
+                        "Unsupported authentication type: {}",
+                        stage_type
+                    )))
+                }
+            }
+        }
+
+        if stage_error.is_none() && is_complete(&flows, &session) {
+            return Ok(Some(session));
+        }
+
+        let mut body = challenge(&session, &flows, self.params(&flows).await);
+        if let Some(e) = stage_error {
+            body["errcode"] = json!(e.code());
+            body["error"] = json!(e.message());
+        }
+        Err(ApiError::auth_required(body))
+    }
+
+    /// Settles a session whose request went through: spends its registration token, records
+    /// the policies agreed to for `user_id`, and deletes the session. A failed request keeps
+    /// its session so the client can retry until it times out.
+    pub async fn finish(&self, session: &UiAuthSession, user_id: &str) -> Result<(), ApiError> {
+        if let (Some(token), Some(service)) = (
+            session
+                .stage_result(AuthStage::RegistrationToken.as_str())
+                .and_then(|t| t.as_str()),
+            &self.registration_token_service,
+        ) {
+            service.finish_registration(token, true).await?;
+        }
+
+        if let (Some(Value::Object(agreed)), Some(consent)) = (
+            session.stage_result(AuthStage::Terms.as_str()),
+            &self.consent_service,
+        ) {
+            for (id, version) in agreed {
+                let version = version.as_str().unwrap_or_default().to_string();
+                if let Err(e) = consent
+                    .grant_consent(
+                        user_id,
+                        ConsentType::parse(id),
+                        version,
+                        ConsentSource::Api,
+                    )
+                    .await
+                {
+                    warn!(user_id = %user_id, policy = %id, "Failed to record consent: {}", e);
+                }
+            }
+        }
+
+        self.storage.delete_session(&session.session_id).await?;
+        Ok(())
+    }
+
+    /// Starts the SSO fallback for `session_id`, returning the identity provider URL to
+    /// send the user to. The provider comes back to `redirect_uri` with the state.
+    pub async fn start_sso(&self, session_id: &str, redirect_uri: &str) -> Result<String, ApiError> {
+        let oidc = self
+            .oidc_service
+            .as_ref()
+            .filter(|oidc| oidc.is_enabled())
+            .ok_or_else(|| ApiError::not_found("SSO is not configured".to_string()))?;
+        let session = self
+            .storage
+            .get_session(session_id)
+            .await?
+            .ok_or_else(|| ApiError::not_found("Unknown UIA session".to_string()))?;
+        if session.user_id.is_none() {
+            return Err(ApiError::bad_request(
+                "SSO is not offered for this session".to_string(),
+            ));
+        }
+
+        let request = oidc.create_auth_request(redirect_uri);
+        self.storage
+            .set_pending_sso(&session.session_id, &request.state, redirect_uri, &request.code_verifier)
+            .await?;
+        Ok(request.url)
+    }
+
+    /// Completes the `m.login.sso` stage when the identity provider redirects back, if it
+    /// vouched for the user the session belongs to.
+    pub async fn complete_sso(&self, state: &str, code: &str) -> Result<(), ApiError> {
+        let oidc = self
+            .oidc_service
+            .as_ref()
+            .ok_or_else(|| ApiError::not_found("SSO is not configured".to_string()))?;
+        // The pending SSO goes with its session when that expires
+        self.expire_sessions().await?;
+        let pending = self
+            .storage
+            .take_pending_sso(state)
+            .await?
+            .ok_or_else(|| ApiError::bad_request("Unknown or expired SSO state".to_string()))?;
+
+        let tokens = oidc
+            .exchange_code_with_pkce(code, &pending.redirect_uri, &pending.code_verifier)
+            .await?;
+        let user_info = oidc.get_user_info(&tokens.access_token).await?;
+        let sso_user = oidc.map_user(&user_info);
+        let sso_user_id = format!("@{}:{}", sso_user.localpart, self.server_name);
+
+        let session = self
+            .storage
+            .get_session(&pending.session_id)
+            .await?
+            .ok_or_else(|| ApiError::bad_request("Unknown UIA session".to_string()))?;
+        if session.user_id.as_deref() != Some(sso_user_id.as_str()) {
+            return Err(ApiError::forbidden(
+                "Identity provider authenticated a different user".to_string(),
+            ));
+        }
+
+        self.storage
+            .add_completed(&session.session_id, AuthStage::Sso.as_str(), &json!(sso_user.subject))
+            .await?;
+        info!(user_id = %sso_user_id, "UIA SSO stage completed");
+        Ok(())
+    }
+
+    async fn create_session(
+        &self,
+        action: UiAuthAction,
+        user_id: Option<&str>,
+    ) -> Result<UiAuthSession, ApiError> {
+        if let Some(user_id) = user_id {
+            let evicted = self
+                .storage
+                .delete_oldest_for_user(user_id, i64::from(self.maximum_sessions) - 1)
+                .await?;
+            self.release(&evicted).await;
+        }
+
+        let session = UiAuthSession {
+            session_id: crate::common::crypto::random_string(24),
+            user_id: user_id.map(str::to_string),
+            action: action.as_str().to_string(),
+            completed: json!({}),
+            created_ts: Utc::now().timestamp_millis(),
+        };
+        self.storage.create_session(&session).await?;
+        Ok(session)
+    }
+
+    async fn expire_sessions(&self) -> Result<(), ApiError> {
+        let before = Utc::now().timestamp_millis() - self.session_timeout_ms;
+        let expired = self.storage.delete_expired(before).await?;
+        self.release(&expired).await;
+        Ok(())
+    }
+
+    /// Gives back the registration token uses held by abandoned sessions.
+    async fn release(&self, sessions: &[UiAuthSession]) {
+        let Some(service) = &self.registration_token_service else {
+            return;
+        };
+        for session in sessions {
+            if let Some(token) = session
+                .stage_result(AuthStage::RegistrationToken.as_str())
+                .and_then(|t| t.as_str())
+            {
+                if let Err(e) = service.finish_registration(token, false).await {
+                    warn!(token = %token, "Failed to release registration token: {}", e);
+                }
+            }
+        }
+    }
+
+    /// Checks the `auth` dict for `stage`, returning what the stage established.
+    async fn check_stage(
+        &self,
+        stage: AuthStage,
+        user_id: Option<&str>,
+        auth: &Value,
+    ) -> Result<Value, ApiError> {
+        match stage {
+            AuthStage::Password => {
+                let identifier = auth
+                    .get("identifier")
+                    .and_then(|i| i.get("user"))
+                    .or_else(|| auth.get("user"))
+                    .and_then(|u| u.as_str())
+                    .ok_or_else(|| ApiError::bad_request("Missing user identifier".to_string()))?;
+                let password = auth
+                    .get("password")
+                    .and_then(|p| p.as_str())
+                    .ok_or_else(|| ApiError::bad_request("Missing password".to_string()))?;
+                let user = self
+                    .auth_service
+                    .check_password(identifier, password)
+                    .await
+                    .map_err(|e| match e {
+                        ApiError::Unauthorized(_) => ApiError::forbidden("Invalid password".to_string()),
+                        other => other,
+                    })?;
+                if Some(user.user_id.as_str()) != user_id {
+                    return Err(ApiError::forbidden(
+                        "Password belongs to a different user".to_string(),
+                    ));
+                }
+                Ok(json!(user.user_id))
+            }
+            AuthStage::EmailIdentity => {
+                let (sid, client_secret) = threepid_creds(auth)?;
+                let storage = self
+                    .email_verification_storage
+                    .as_ref()
+                    .ok_or_else(|| ApiError::internal("Email verification is not available".to_string()))?;
+                let token = match sid.parse::<i64>() {
+                    Ok(id) => storage.get_verification_token_by_id(id).await?,
+                    Err(_) => None,
+                };
+                match token {
+                    Some(token)
+                        if token.used
+                            && token.session_data.as_ref().and_then(|s| s.as_str())
+                                == Some(client_secret) =>
+                    {
+                        Ok(json!({ "medium": "email", "address": token.email }))
+                    }
+                    _ => Err(ApiError::unauthorized(
+                        "Email address has not been validated".to_string(),
+                    )),
+                }
+            }
+            AuthStage::Msisdn => {
+                let (sid, client_secret) = threepid_creds(auth)?;
+                let phone_verification = self
+                    .phone_verification_service
+                    .as_ref()
+                    .ok_or_else(|| ApiError::internal("Phone verification is not available".to_string()))?;
+                match phone_verification.validated_phone(sid, client_secret).await {
+                    Some(phone) => Ok(json!({ "medium": "msisdn", "address": phone })),
+                    None => Err(ApiError::unauthorized(
+                        "Phone number has not been validated".to_string(),
+                    )),
+                }
+            }
+            AuthStage::Recaptcha => {
+                let response = auth
+                    .get("response")
+                    .and_then(|r| r.as_str())
+                    .ok_or_else(|| ApiError::bad_request("Missing captcha response".to_string()))?;
+                let captcha = self
+                    .captcha_service
+                    .as_ref()
+                    .ok_or_else(|| ApiError::internal("Captcha is not available".to_string()))?;
+                match captcha.verify(response, Some(CaptchaAction::Register), None).await {
+                    Ok(result) if result.success => Ok(json!(true)),
+                    _ => Err(ApiError::unauthorized("Captcha verification failed".to_string())),
+                }
+            }
+            AuthStage::Terms => {
+                let agreed: Map<String, Value> = self
+                    .required_policies()
+                    .await
+                    .iter()
+                    .map(|policy| {
+                        (
+                            policy.consent_type.as_str().to_string(),
+                            json!(policy.current_version),
+                        )
+                    })
+                    .collect();
+                Ok(Value::Object(agreed))
+            }
+            AuthStage::RegistrationToken => {
+                let token = auth
+                    .get("token")
+                    .and_then(|t| t.as_str())
+                    .ok_or_else(|| ApiError::bad_request("Missing registration token".to_string()))?;
+                let service = self
+                    .registration_token_service
+                    .as_ref()
+                    .ok_or_else(|| ApiError::internal("Registration tokens are not available".to_string()))?;
+                match service.begin_registration(token).await {
+                    Ok(()) => Ok(json!(token)),
+                    Err(TokenError::InvalidToken) => {
+                        Err(ApiError::unauthorized("Invalid registration token".to_string()))
+                    }
+                    Err(e) => Err(e.into()),
+                }
+            }
+            AuthStage::Dummy => Ok(json!(true)),
+            AuthStage::Sso => Err(ApiError::unauthorized(
+                "Complete SSO through the fallback page first".to_string(),
+            )),
+        }
+    }
+
+    async fn required_policies(&self) -> Vec<ConsentPolicy> {
+        let Some(consent) = &self.consent_service else {
+            return Vec::new();
+        };
+        let mut policies: Vec<ConsentPolicy> = consent
+            .get_all_policies()
+            .await
+            .into_iter()
+            .filter(|policy| policy.required)
+            .collect();
+        policies.sort_by(|a, b| a.consent_type.as_str().cmp(b.consent_type.as_str()));
+        policies
+    }
+
+    /// The `params` of the stages offered in `flows`.
+    async fn params(&self, flows: &[Vec<AuthStage>]) -> Value {
+        let mut params = Map::new();
+        if is_offered(flows, AuthStage::Recaptcha) {
+            let public_key = self
+                .captcha_service
+                .as_ref()
+                .and_then(|captcha| captcha.get_site_key())
+                .unwrap_or_default();
+            params.insert(
+                AuthStage::Recaptcha.as_str().to_string(),
+                json!({ "public_key": public_key }),
+            );
+        }
+        if is_offered(flows, AuthStage::Terms) {
+            params.insert(
+                AuthStage::Terms.as_str().to_string(),
+                terms_params(&self.required_policies().await),
+            );
+        }
+        Value::Object(params)
+    }
+}
+
+/// The stages `registrations_require_3pid` adds to registration.
+fn required_3pid_stages(media: &[String]) -> Vec<AuthStage> {
+    let mut stages = Vec::new();
+    if media.iter().any(|m| m == "email") {
+        stages.push(AuthStage::EmailIdentity);
+    }
+    if media.iter().any(|m| m == "msisdn") {
+        stages.push(AuthStage::Msisdn);
+    }
+    stages
+}
+
+fn threepid_creds(auth: &Value) -> Result<(&str, &str), ApiError> {
+    let creds = auth
+        .get("threepid_creds")
+        .or_else(|| auth.get("threepidCreds"))
+        .ok_or_else(|| ApiError::bad_request("Missing threepid_creds".to_string()))?;
+    let sid = creds.get("sid").and_then(|s| s.as_str());
+    let client_secret = creds.get("client_secret").and_then(|s| s.as_str());
+    match (sid, client_secret) {
+        (Some(sid), Some(client_secret)) => Ok((sid, client_secret)),
+        _ => Err(ApiError::bad_request(
+            "threepid_creds needs sid and client_secret".to_string(),
+        )),
+    }
+}
+
+fn is_offered(flows: &[Vec<AuthStage>], stage: AuthStage) -> bool {
+    flows.iter().any(|flow| flow.contains(&stage))
+}
+
+fn is_complete(flows: &[Vec<AuthStage>], session: &UiAuthSession) -> bool {
+    flows
+        .iter()
+        .any(|flow| flow.iter().all(|stage| session.is_completed(stage.as_str())))
+}
+
+fn challenge(session: &UiAuthSession, flows: &[Vec<AuthStage>], params: Value) -> Value {
+    let flows: Vec<Value> = flows
+        .iter()
+        .map(|flow| json!({ "stages": flow.iter().map(|s| s.as_str()).collect::<Vec<_>>() }))
+        .collect();
+    let mut completed = session.completed_stages();
+    completed.sort();
+    json!({
+        "session": session.session_id,
+        "flows": flows,
+        "params": params,
+        "completed": completed,
+    })
+}
+
+fn terms_params(policies: &[ConsentPolicy]) -> Value {
+    let policies: Map<String, Value> = policies
+        .iter()
+        .map(|policy| {
+            (
+                policy.consent_type.as_str().to_string(),
+                json!({
+                    "version": policy.current_version,
+                    "en": {
+                        "name": policy.description,
+                        "url": policy.url.clone().unwrap_or_default(),
+                    },
+                }),
+            )
+        })
+        .collect();
+    json!({ "policies": policies })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn session(completed: Value) -> UiAuthSession {
+        UiAuthSession {
+            session_id: "sess".to_string(),
+            user_id: Some("@alice:localhost".to_string()),
+            action: UiAuthAction::DeleteDevices.as_str().to_string(),
+            completed,
+            created_ts: 0,
+        }
+    }
+
+    #[test]
+    fn test_stage_names_round_trip() {
+        for stage in [
+            AuthStage::Password,
+            AuthStage::EmailIdentity,
+            AuthStage::Msisdn,
+            AuthStage::Recaptcha,
+            AuthStage::Terms,
+            AuthStage::RegistrationToken,
+            AuthStage::Dummy,
+            AuthStage::Sso,
+        ] {
+            assert_eq!(AuthStage::parse(stage.as_str()), Some(stage));
+        }
+        assert_eq!(AuthStage::parse("m.login.unknown"), None);
+    }
+
+    #[test]
+    fn test_any_fully_completed_flow_completes() {
+        let flows = vec![vec![AuthStage::Password], vec![AuthStage::Sso]];
+        assert!(!is_complete(&flows, &session(json!({}))));
+        assert!(is_complete(&flows, &session(json!({"m.login.sso": "sub"}))));
+
+        let flows = vec![vec![AuthStage::RegistrationToken, AuthStage::Terms]];
+        assert!(!is_complete(&flows, &session(json!({"m.login.terms": {}}))));
+        assert!(is_complete(
+            &flows,
+            &session(json!({"m.login.terms": {}, "m.login.registration_token": "tok"}))
+        ));
+    }
+
+    #[test]
+    fn test_challenge_lists_flows_and_completed_stages() {
+        let flows = vec![vec![AuthStage::Recaptcha, AuthStage::Terms]];
+        let body = challenge(&session(json!({"m.login.recaptcha": true})), &flows, json!({}));
+        assert_eq!(body["session"], "sess");
+        assert_eq!(body["flows"][0]["stages"], json!(["m.login.recaptcha", "m.login.terms"]));
+        assert_eq!(body["completed"], json!(["m.login.recaptcha"]));
+        assert!(body.get("errcode").is_none());
+    }
+
+    #[test]
+    fn test_required_3pid_stages() {
+        assert!(required_3pid_stages(&[]).is_empty());
+        assert_eq!(
+            required_3pid_stages(&["msisdn".to_string(), "email".to_string()]),
+            vec![AuthStage::EmailIdentity, AuthStage::Msisdn]
+        );
+    }
+
+    #[test]
+    fn test_terms_params() {
+        let policy = ConsentPolicy::new(ConsentType::PrivacyPolicy, "1.0".to_string(), true)
+            .with_description("Privacy Policy".to_string())
+            .with_url("https://example.com/privacy".to_string());
+        let params = terms_params(&[policy]);
+        assert_eq!(params["policies"]["privacy_policy"]["version"], "1.0");
+        assert_eq!(params["policies"]["privacy_policy"]["en"]["name"], "Privacy Policy");
+        assert_eq!(
+            params["policies"]["privacy_policy"]["en"]["url"],
+            "https://example.com/privacy"
+        );
+    }
+
+    #[test]
+    fn test_threepid_creds() {
+        let auth = json!({"threepid_creds": {"sid": "1", "client_secret": "s"}});
+        assert_eq!(threepid_creds(&auth).unwrap(), ("1", "s"));
+        let legacy = json!({"threepidCreds": {"sid": "1", "client_secret": "s"}});
+        assert_eq!(threepid_creds(&legacy).unwrap(), ("1", "s"));
+        assert!(threepid_creds(&json!({"threepid_creds": {"sid": "1"}})).is_err());
+    }
+}
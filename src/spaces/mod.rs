@@ -1,30 +1,42 @@
+use crate::federation::{pdu, FederationClient};
+use crate::storage::{CreateEventParams, EventStorage, RoomMemberStorage, RoomStorage, StateEvent};
 use serde::{Deserialize, Serialize};
-use std::collections::HashMap;
+use serde_json::{json, Value};
+use std::cmp::Ordering;
+use lru::LruCache;
+use std::collections::{HashMap, HashSet};
+use std::num::NonZeroUsize;
 use std::sync::Arc;
+use std::time::{Duration, Instant};
 use tokio::sync::RwLock;
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
-pub struct Space {
-    pub space_id: String,
-    pub name: String,
-    pub topic: Option<String>,
-    pub avatar_url: Option<String>,
-    pub creator: String,
-    pub is_public: bool,
-    pub join_rule: SpaceJoinRule,
-    pub created_at: i64,
-    pub updated_at: i64,
-}
-
-#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
-#[serde(rename_all = "lowercase")]
-pub enum SpaceJoinRule {
-    Public,
-    Knock,
-    Invite,
-    Restricted,
-}
-
+pub const SPACE_CHILD_EVENT_TYPE: &str = "m.space.child";
+pub const SPACE_PARENT_EVENT_TYPE: &str = "m.space.parent";
+pub const SPACE_ROOM_TYPE: &str = "m.space";
+
+const DEFAULT_HIERARCHY_LIMIT: usize = 50;
+const MAX_HIERARCHY_LIMIT: usize = 100;
+/// Longest `order` a child may have before it is treated as unordered.
+const MAX_ORDER_LENGTH: usize = 50;
+/// How long a `/hierarchy` pagination token stays valid.
+const PAGINATION_SESSION_TTL: Duration = Duration::from_secs(5 * 60);
+/// Most `/hierarchy` pagination sessions kept at once; the oldest are dropped beyond this.
+const MAX_PAGINATION_SESSIONS: usize = 10_000;
+
+/// The state a room summary is built from.
+const SUMMARY_STATE_TYPES: &[&str] = &[
+    "m.room.create",
+    "m.room.name",
+    "m.room.topic",
+    "m.room.avatar",
+    "m.room.canonical_alias",
+    "m.room.join_rules",
+    "m.room.history_visibility",
+    "m.room.guest_access",
+    SPACE_CHILD_EVENT_TYPE,
+];
+
+/// A room listed in a space by an `m.space.child` state event of the space.
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct SpaceChild {
     pub space_id: String,
@@ -36,55 +48,126 @@ pub struct SpaceChild {
     pub added_at: i64,
 }
 
+impl SpaceChild {
+    /// The child described by an `m.space.child` event; `None` once the event has no `via`,
+    /// which is how a child is removed.
+    pub fn from_state(space_id: &str, child_room_id: &str, sender: &str, ts: i64, content: &Value) -> Option<Self> {
+        let via_servers = via_servers(content)?;
+        Some(Self {
+            space_id: space_id.to_string(),
+            child_room_id: child_room_id.to_string(),
+            order: content
+                .get("order")
+                .and_then(Value::as_str)
+                .filter(|order| is_valid_order(order))
+                .map(String::from),
+            suggested: content.get("suggested").and_then(Value::as_bool).unwrap_or(false),
+            via_servers,
+            added_by: sender.to_string(),
+            added_at: ts,
+        })
+    }
+
+    /// The spec's ordering of children: by `order`, unordered children last, then by when they
+    /// were added and finally by room ID.
+    fn cmp_order(&self, other: &Self) -> Ordering {
+        match (&self.order, &other.order) {
+            (Some(a), Some(b)) => a.cmp(b),
+            (Some(_), None) => Ordering::Less,
+            (None, Some(_)) => Ordering::Greater,
+            (None, None) => Ordering::Equal,
+        }
+        .then(self.added_at.cmp(&other.added_at))
+        .then_with(|| self.child_room_id.cmp(&other.child_room_id))
+    }
+}
+
+/// A space claimed by a room through an `m.space.parent` state event of the room.
 #[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SpaceParent {
+    pub room_id: String,
+    pub parent_id: String,
+    pub canonical: bool,
+    pub via_servers: Vec<String>,
+    pub added_by: String,
+    pub added_at: i64,
+}
+
+/// A room in a `/hierarchy` response, in the shape shared by the client and federation APIs.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
 pub struct SpaceHierarchyRoomSummary {
     pub room_id: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
     pub name: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
     pub topic: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
     pub avatar_url: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub canonical_alias: Option<String>,
     pub num_joined_members: i64,
-    pub room_type: Option<RoomType>,
-    pub is_space: bool,
-    pub join_rule: SpaceJoinRule,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub room_type: Option<String>,
+    pub join_rule: String,
+    /// The rooms whose members may join a `restricted` room.
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub allowed_room_ids: Vec<String>,
     pub world_readable: bool,
     pub guest_can_join: bool,
+    /// The room's `m.space.child` events as stripped state.
+    pub children_state: Vec<Value>,
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
-#[serde(rename_all = "lowercase")]
-pub enum RoomType {
-    Space,
-    Room,
-}
+impl SpaceHierarchyRoomSummary {
+    pub fn is_space(&self) -> bool {
+        self.room_type.as_deref() == Some(SPACE_ROOM_TYPE)
+    }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
-pub struct SpaceHierarchy {
-    pub space: Space,
-    pub children: Vec<SpaceHierarchyChild>,
-    pub next_batch: Option<String>,
+    /// The children listed in `children_state`, in the spec's order.
+    pub fn children(&self) -> Vec<SpaceChild> {
+        let mut children: Vec<SpaceChild> = self
+            .children_state
+            .iter()
+            .filter(|event| event["type"] == SPACE_CHILD_EVENT_TYPE)
+            .filter_map(|event| {
+                SpaceChild::from_state(
+                    &self.room_id,
+                    event["state_key"].as_str()?,
+                    event["sender"].as_str().unwrap_or_default(),
+                    event["origin_server_ts"].as_i64().unwrap_or_default(),
+                    &event["content"],
+                )
+            })
+            .collect();
+        children.sort_by(SpaceChild::cmp_order);
+        children
+    }
+
+    /// Whether anyone may see the room regardless of membership.
+    fn is_peekable(&self) -> bool {
+        self.world_readable || matches!(self.join_rule.as_str(), "public" | "knock" | "knock_restricted")
+    }
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
-pub struct SpaceHierarchyChild {
-    pub room_id: String,
-    pub name: Option<String>,
-    pub topic: Option<String>,
-    pub avatar_url: Option<String>,
-    pub num_joined_members: i64,
-    pub room_type: Option<RoomType>,
-    pub via_servers: Vec<String>,
-    pub order: Option<String>,
-    pub suggested: bool,
+/// The query of a `/hierarchy` request.
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct HierarchyRequest {
+    pub from: Option<String>,
+    pub limit: Option<usize>,
+    pub max_depth: Option<usize>,
+    #[serde(default)]
+    pub suggested_only: bool,
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
-pub struct CreateSpaceRequest {
-    pub name: String,
-    pub topic: Option<String>,
-    pub avatar_url: Option<String>,
-    pub is_public: bool,
-    pub join_rule: SpaceJoinRule,
-    pub initial_children: Vec<String>,
+/// A page of a space hierarchy, walked depth first from the requested room.
+#[derive(Debug, Clone, Serialize)]
+pub struct SpaceHierarchy {
+    pub rooms: Vec<SpaceHierarchyRoomSummary>,
+    /// Where the walk stopped. The session behind it is held in memory, so the token only
+    /// works on the process that issued it.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub next_batch: Option<String>,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -98,344 +181,670 @@ pub struct AddChildRequest {
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct SpaceSummary {
     pub space_id: String,
-    pub name: String,
+    pub name: Option<String>,
     pub topic: Option<String>,
     pub avatar_url: Option<String>,
     pub member_count: i64,
     pub child_count: i64,
 }
 
-pub struct SpaceManager {
-    spaces: Arc<RwLock<HashMap<String, Space>>>,
-    children: Arc<RwLock<HashMap<String, Vec<SpaceChild>>>>,
-    user_spaces: Arc<RwLock<HashMap<String, Vec<String>>>>,
+#[derive(Debug, Clone)]
+struct QueueEntry {
+    room_id: String,
+    via: Vec<String>,
+    depth: usize,
 }
 
-impl SpaceManager {
-    pub fn new() -> Self {
+/// Where a paginated walk stopped, kept until the client asks for the next page.
+struct PaginationSession {
+    requester: String,
+    room_id: String,
+    suggested_only: bool,
+    max_depth: Option<usize>,
+    queue: Vec<QueueEntry>,
+    processed: HashSet<String>,
+    /// Summaries of remote rooms returned alongside their parent space.
+    remote_children: HashMap<String, SpaceHierarchyRoomSummary>,
+    created_at: Instant,
+}
+
+/// Pagination sessions by token, oldest first. Expired sessions are dropped whenever one is
+/// stored, and the oldest once there are `capacity` of them.
+struct PaginationSessions {
+    sessions: LruCache<String, PaginationSession>,
+}
+
+impl PaginationSessions {
+    fn new(capacity: usize) -> Self {
         Self {
-            spaces: Arc::new(RwLock::new(HashMap::new())),
-            children: Arc::new(RwLock::new(HashMap::new())),
-            user_spaces: Arc::new(RwLock::new(HashMap::new())),
+            sessions: LruCache::new(NonZeroUsize::new(capacity.max(1)).unwrap()),
         }
     }
 
-    pub async fn create_space(
-        &self,
-        creator: &str,
-        request: CreateSpaceRequest,
-    ) -> Result<Space, SpaceError> {
-        if request.name.is_empty() || request.name.len() > 255 {
-            return Err(SpaceError::InvalidName);
+    fn insert(&mut self, token: String, session: PaginationSession) {
+        while self
+            .sessions
+            .peek_lru()
+            .is_some_and(|(_, oldest)| oldest.created_at.elapsed() >= PAGINATION_SESSION_TTL)
+        {
+            self.sessions.pop_lru();
         }
+        self.sessions.push(token, session);
+    }
 
-        let space_id = format!("!space_{}:{}", uuid::Uuid::new_v4(), "example.com");
-        let now = chrono::Utc::now().timestamp_millis();
-
-        let space = Space {
-            space_id: space_id.clone(),
-            name: request.name,
-            topic: request.topic,
-            avatar_url: request.avatar_url,
-            creator: creator.to_string(),
-            is_public: request.is_public,
-            join_rule: request.join_rule,
-            created_at: now,
-            updated_at: now,
-        };
+    /// Removes and returns the session for `token` if it has not expired.
+    fn take(&mut self, token: &str) -> Option<PaginationSession> {
+        self.sessions
+            .pop(token)
+            .filter(|session| session.created_at.elapsed() < PAGINATION_SESSION_TTL)
+    }
 
-        self.spaces.write().await.insert(space_id.clone(), space.clone());
-
-        self.user_spaces
-            .write()
-            .await
-            .entry(creator.to_string())
-            .or_default()
-            .push(space_id.clone());
-
-        for child_room_id in request.initial_children {
-            let child = SpaceChild {
-                space_id: space_id.clone(),
-                child_room_id,
-                order: None,
-                suggested: false,
-                via_servers: vec!["example.com".to_string()],
-                added_by: creator.to_string(),
-                added_at: now,
-            };
-            self.children
-                .write()
-                .await
-                .entry(space_id.clone())
-                .or_default()
-                .push(child);
-        }
+    #[cfg(test)]
+    fn len(&self) -> usize {
+        self.sessions.len()
+    }
+}
 
-        Ok(space)
+/// Spaces as described by `m.space.child` and `m.space.parent` room state.
+#[derive(Clone)]
+pub struct SpaceManager {
+    room_storage: RoomStorage,
+    member_storage: RoomMemberStorage,
+    event_storage: EventStorage,
+    server_name: String,
+    federation_client: Option<Arc<FederationClient>>,
+    sessions: Arc<RwLock<PaginationSessions>>,
+}
+
+impl SpaceManager {
+    pub fn new(
+        room_storage: RoomStorage,
+        member_storage: RoomMemberStorage,
+        event_storage: EventStorage,
+        server_name: &str,
+    ) -> Self {
+        Self {
+            room_storage,
+            member_storage,
+            event_storage,
+            server_name: server_name.to_string(),
+            federation_client: None,
+            sessions: Arc::new(RwLock::new(PaginationSessions::new(MAX_PAGINATION_SESSIONS))),
+        }
     }
 
-    pub async fn get_space(&self, space_id: &str) -> Option<Space> {
-        self.spaces.read().await.get(space_id).cloned()
+    /// Lets the hierarchy of rooms this server is not in be fetched from servers that are.
+    pub fn with_federation_client(mut self, client: Arc<FederationClient>) -> Self {
+        self.federation_client = Some(client);
+        self
     }
 
+    /// Lists `child_room_id` in the space by sending an `m.space.child` event as `added_by`.
     pub async fn add_child(
         &self,
         space_id: &str,
         added_by: &str,
         request: AddChildRequest,
     ) -> Result<SpaceChild, SpaceError> {
-        let spaces = self.spaces.read().await;
-        if !spaces.contains_key(space_id) {
+        if !request.child_room_id.starts_with('!') || request.via_servers.is_empty() {
+            return Err(SpaceError::InvalidChild);
+        }
+        if request.order.as_deref().is_some_and(|order| !is_valid_order(order)) {
+            return Err(SpaceError::InvalidChild);
+        }
+        if !self.room_storage.room_exists(space_id).await? {
             return Err(SpaceError::SpaceNotFound);
         }
-        drop(spaces);
-
-        let now = chrono::Utc::now().timestamp_millis();
-        let child = SpaceChild {
-            space_id: space_id.to_string(),
-            child_room_id: request.child_room_id,
-            order: request.order,
-            suggested: request.suggested,
-            via_servers: request.via_servers,
-            added_by: added_by.to_string(),
-            added_at: now,
-        };
-
-        self.children
-            .write()
-            .await
-            .entry(space_id.to_string())
-            .or_default()
-            .push(child.clone());
 
-        Ok(child)
+        let mut content = json!({
+            "via": request.via_servers,
+            "suggested": request.suggested
+        });
+        if let Some(order) = &request.order {
+            content["order"] = json!(order);
+        }
+        let event = self
+            .send_state(space_id, added_by, SPACE_CHILD_EVENT_TYPE, &request.child_room_id, content)
+            .await?;
+        SpaceChild::from_state(space_id, &request.child_room_id, added_by, event.origin_server_ts, &event.content)
+            .ok_or(SpaceError::InvalidChild)
     }
 
-    pub async fn remove_child(&self, space_id: &str, child_room_id: &str) -> Result<(), SpaceError> {
-        let mut children = self.children.write().await;
-        if let Some(space_children) = children.get_mut(space_id) {
-            space_children.retain(|c| c.child_room_id != child_room_id);
-            Ok(())
-        } else {
-            Err(SpaceError::SpaceNotFound)
+    /// Removes a child by replacing its `m.space.child` event with one without `via`.
+    pub async fn remove_child(&self, space_id: &str, removed_by: &str, child_room_id: &str) -> Result<(), SpaceError> {
+        if !self.get_children(space_id).await?.iter().any(|c| c.child_room_id == child_room_id) {
+            return Err(SpaceError::InvalidChild);
         }
+        self.send_state(space_id, removed_by, SPACE_CHILD_EVENT_TYPE, child_room_id, json!({}))
+            .await?;
+        Ok(())
     }
 
-    pub async fn get_children(&self, space_id: &str) -> Vec<SpaceChild> {
-        self.children
-            .read()
-            .await
-            .get(space_id)
-            .cloned()
-            .unwrap_or_default()
+    async fn send_state(
+        &self,
+        room_id: &str,
+        sender: &str,
+        event_type: &str,
+        state_key: &str,
+        content: Value,
+    ) -> Result<crate::storage::RoomEvent, SpaceError> {
+        Ok(self
+            .event_storage
+            .create_event(
+                CreateEventParams {
+                    event_id: crate::common::crypto::generate_event_id(&self.server_name),
+                    room_id: room_id.to_string(),
+                    user_id: sender.to_string(),
+                    event_type: event_type.to_string(),
+                    content,
+                    state_key: Some(state_key.to_string()),
+                    origin_server_ts: chrono::Utc::now().timestamp_millis(),
+                },
+                None,
+            )
+            .await?)
     }
 
-    pub async fn get_hierarchy(
-        &self,
-        space_id: &str,
-        _max_depth: usize,
-        limit: usize,
-    ) -> Option<SpaceHierarchy> {
-        let space = self.get_space(space_id).await?;
-        let children = self.get_children(space_id).await;
+    /// The current children of a space, in the spec's order.
+    pub async fn get_children(&self, space_id: &str) -> Result<Vec<SpaceChild>, SpaceError> {
+        let events = self
+            .event_storage
+            .get_current_state_by_types(space_id, &[SPACE_CHILD_EVENT_TYPE])
+            .await?;
+        let mut children: Vec<SpaceChild> = events
+            .iter()
+            .filter_map(|event| {
+                SpaceChild::from_state(
+                    space_id,
+                    event.state_key.as_deref()?,
+                    &event.sender,
+                    event.origin_server_ts,
+                    &event.content,
+                )
+            })
+            .collect();
+        children.sort_by(SpaceChild::cmp_order);
+        Ok(children)
+    }
 
-        let hierarchy_children: Vec<SpaceHierarchyChild> = children
+    /// The spaces a room claims to belong to, the canonical one first.
+    pub async fn get_parents(&self, room_id: &str) -> Result<Vec<SpaceParent>, SpaceError> {
+        let events = self
+            .event_storage
+            .get_current_state_by_types(room_id, &[SPACE_PARENT_EVENT_TYPE])
+            .await?;
+        let mut parents: Vec<SpaceParent> = events
             .into_iter()
-            .take(limit)
-            .map(|c| SpaceHierarchyChild {
-                room_id: c.child_room_id,
-                name: None,
-                topic: None,
-                avatar_url: None,
-                num_joined_members: 0,
-                room_type: Some(RoomType::Room),
-                via_servers: c.via_servers,
-                order: c.order,
-                suggested: c.suggested,
+            .filter_map(|event| {
+                Some(SpaceParent {
+                    room_id: room_id.to_string(),
+                    parent_id: event.state_key.clone()?,
+                    canonical: event.content.get("canonical").and_then(Value::as_bool).unwrap_or(false),
+                    via_servers: via_servers(&event.content)?,
+                    added_by: event.sender,
+                    added_at: event.origin_server_ts,
+                })
             })
             .collect();
-
-        Some(SpaceHierarchy {
-            space,
-            children: hierarchy_children,
-            next_batch: None,
-        })
+        parents.sort_by(|a, b| b.canonical.cmp(&a.canonical).then_with(|| a.parent_id.cmp(&b.parent_id)));
+        Ok(parents)
     }
 
-    pub async fn get_user_spaces(&self, user_id: &str) -> Vec<SpaceSummary> {
-        let user_spaces = self
-            .user_spaces
-            .read()
-            .await
-            .get(user_id)
-            .cloned()
-            .unwrap_or_default();
+    /// The spaces the user is joined to.
+    pub async fn get_user_spaces(&self, user_id: &str) -> Result<Vec<SpaceSummary>, SpaceError> {
+        let mut spaces = Vec::new();
+        for room_id in self.member_storage.get_joined_rooms(user_id).await? {
+            let Some(summary) = self.get_room_summary(&room_id, false).await? else {
+                continue;
+            };
+            if summary.is_space() {
+                spaces.push(SpaceSummary {
+                    child_count: summary.children().len() as i64,
+                    space_id: summary.room_id,
+                    name: summary.name,
+                    topic: summary.topic,
+                    avatar_url: summary.avatar_url,
+                    member_count: summary.num_joined_members,
+                });
+            }
+        }
+        Ok(spaces)
+    }
 
-        let spaces = self.spaces.read().await;
-        let children = self.children.read().await;
+    /// The summary of a room this server knows, from its current state. Only spaces carry
+    /// their `children_state`, restricted to suggested children if asked.
+    pub async fn get_room_summary(
+        &self,
+        room_id: &str,
+        suggested_only: bool,
+    ) -> Result<Option<SpaceHierarchyRoomSummary>, SpaceError> {
+        let Some(room) = self.room_storage.get_room(room_id).await? else {
+            return Ok(None);
+        };
+        let state = self.event_storage.get_current_state_by_types(room_id, SUMMARY_STATE_TYPES).await?;
+        let content = |event_type: &str| {
+            state
+                .iter()
+                .find(|e| e.event_type == event_type && e.state_key.as_deref() == Some(""))
+                .map(|e| &e.content)
+        };
+        // Local rooms keep some of their state in `rooms` only, so fall back to it.
+        let field = |event_type: &str, name: &str, fallback: Option<String>| match content(event_type) {
+            Some(content) => content.get(name).and_then(Value::as_str).map(String::from),
+            None => fallback,
+        }
+        .filter(|value| !value.is_empty());
+
+        let join_rules = content("m.room.join_rules");
+        let join_rule = field("m.room.join_rules", "join_rule", Some(room.join_rule)).unwrap_or_else(|| "invite".to_string());
+        let history_visibility = field("m.room.history_visibility", "history_visibility", Some(room.history_visibility));
+        let room_type = content("m.room.create")
+            .and_then(|c| c.get("type"))
+            .and_then(Value::as_str)
+            .map(String::from);
+
+        let mut summary = SpaceHierarchyRoomSummary {
+            room_id: room_id.to_string(),
+            name: field("m.room.name", "name", room.name),
+            topic: field("m.room.topic", "topic", room.topic),
+            avatar_url: field("m.room.avatar", "url", room.avatar_url),
+            canonical_alias: field("m.room.canonical_alias", "alias", room.canonical_alias),
+            num_joined_members: self.member_storage.get_member_counts(room_id).await?.0,
+            room_type,
+            allowed_room_ids: join_rules.map(allowed_room_ids).unwrap_or_default(),
+            join_rule,
+            world_readable: history_visibility.as_deref() == Some("world_readable"),
+            guest_can_join: content("m.room.guest_access").and_then(|c| c["guest_access"].as_str()) == Some("can_join"),
+            children_state: Vec::new(),
+        };
+        if summary.is_space() {
+            let mut children: Vec<(SpaceChild, &StateEvent)> = state
+                .iter()
+                .filter(|e| e.event_type == SPACE_CHILD_EVENT_TYPE)
+                .filter_map(|e| {
+                    let child = SpaceChild::from_state(room_id, e.state_key.as_deref()?, &e.sender, e.origin_server_ts, &e.content)?;
+                    Some((child, e))
+                })
+                .filter(|(child, _)| !suggested_only || child.suggested)
+                .collect();
+            children.sort_by(|(a, _), (b, _)| a.cmp_order(b));
+            summary.children_state = children.into_iter().map(|(_, event)| stripped_state(event)).collect();
+        }
+        Ok(Some(summary))
+    }
 
-        user_spaces
-            .into_iter()
-            .filter_map(|space_id| {
-                spaces.get(&space_id).map(|space| SpaceSummary {
-                    space_id: space.space_id.clone(),
-                    name: space.name.clone(),
-                    topic: space.topic.clone(),
-                    avatar_url: space.avatar_url.clone(),
-                    member_count: 1,
-                    child_count: children.get(&space_id).map(|c| c.len() as i64).unwrap_or(0),
+    /// A page of the hierarchy below `room_id` that `requester` may see. Rooms this server is
+    /// not in are summarised by the servers named in their parent's `m.space.child` event.
+    /// `next_batch` tokens are only valid on this process, for `PAGINATION_SESSION_TTL`.
+    pub async fn get_hierarchy(
+        &self,
+        requester: &str,
+        room_id: &str,
+        request: &HierarchyRequest,
+    ) -> Result<SpaceHierarchy, SpaceError> {
+        let limit = request.limit.unwrap_or(DEFAULT_HIERARCHY_LIMIT).clamp(1, MAX_HIERARCHY_LIMIT);
+        let mut session = match &request.from {
+            Some(token) => self
+                .sessions
+                .write()
+                .await
+                .take(token)
+                .filter(|session| {
+                    session.requester == requester
+                        && session.room_id == room_id
+                        && session.suggested_only == request.suggested_only
+                        && session.max_depth == request.max_depth
                 })
-            })
-            .collect()
+                .ok_or(SpaceError::InvalidPaginationToken)?,
+            None => PaginationSession {
+                requester: requester.to_string(),
+                room_id: room_id.to_string(),
+                suggested_only: request.suggested_only,
+                max_depth: request.max_depth,
+                queue: vec![QueueEntry {
+                    room_id: room_id.to_string(),
+                    via: pdu::server_name_of(room_id).map(String::from).into_iter().collect(),
+                    depth: 0,
+                }],
+                processed: HashSet::new(),
+                remote_children: HashMap::new(),
+                created_at: Instant::now(),
+            },
+        };
+
+        let mut rooms = Vec::new();
+        while rooms.len() < limit {
+            let Some(entry) = session.queue.pop() else {
+                break;
+            };
+            if !session.processed.insert(entry.room_id.clone()) {
+                continue;
+            }
+            let summary = match self.summarize_for_user(requester, &entry, &mut session).await? {
+                Some(summary) => summary,
+                None if entry.depth == 0 => return Err(SpaceError::PermissionDenied),
+                None => continue,
+            };
+
+            if request.max_depth.is_none_or(|max_depth| entry.depth < max_depth) {
+                for child in summary.children().into_iter().rev() {
+                    if !session.processed.contains(&child.child_room_id) {
+                        session.queue.push(QueueEntry {
+                            room_id: child.child_room_id,
+                            via: child.via_servers,
+                            depth: entry.depth + 1,
+                        });
+                    }
+                }
+            }
+            rooms.push(summary);
+        }
+
+        let next_batch = if session.queue.is_empty() {
+            None
+        } else {
+            let token = uuid::Uuid::new_v4().simple().to_string();
+            self.sessions.write().await.insert(token.clone(), session);
+            Some(token)
+        };
+        Ok(SpaceHierarchy { rooms, next_batch })
     }
 
-    pub async fn update_space(
+    /// The summary of a queued room if the requester may see it.
+    async fn summarize_for_user(
         &self,
-        space_id: &str,
-        name: Option<String>,
-        topic: Option<String>,
-        avatar_url: Option<String>,
-    ) -> Result<Space, SpaceError> {
-        let mut spaces = self.spaces.write().await;
-        if let Some(space) = spaces.get_mut(space_id) {
-            if let Some(n) = name {
-                if n.is_empty() || n.len() > 255 {
-                    return Err(SpaceError::InvalidName);
+        requester: &str,
+        entry: &QueueEntry,
+        session: &mut PaginationSession,
+    ) -> Result<Option<SpaceHierarchyRoomSummary>, SpaceError> {
+        let summary = match self.get_room_summary(&entry.room_id, session.suggested_only).await? {
+            Some(summary) => summary,
+            None => match session.remote_children.remove(&entry.room_id) {
+                // A remote space has to be asked for its own children.
+                Some(summary) if !summary.is_space() => summary,
+                _ => match self.fetch_remote_summary(entry, session).await {
+                    Some(summary) => summary,
+                    None => return Ok(None),
+                },
+            },
+        };
+        Ok(self.is_accessible_to_user(requester, &summary).await?.then_some(summary))
+    }
+
+    async fn fetch_remote_summary(
+        &self,
+        entry: &QueueEntry,
+        session: &mut PaginationSession,
+    ) -> Option<SpaceHierarchyRoomSummary> {
+        let client = self.federation_client.as_ref()?;
+        for destination in entry.via.iter().filter(|server| **server != self.server_name) {
+            let response = match client
+                .get_space_hierarchy(destination, &entry.room_id, session.suggested_only)
+                .await
+            {
+                Ok(response) => response,
+                Err(e) => {
+                    ::tracing::debug!("Failed to fetch hierarchy of {} from {}: {}", entry.room_id, destination, e);
+                    continue;
                 }
-                space.name = n;
+            };
+            let Ok(mut summary) = serde_json::from_value::<SpaceHierarchyRoomSummary>(response["room"].clone()) else {
+                continue;
+            };
+            if summary.room_id != entry.room_id {
+                continue;
             }
-            if let Some(t) = topic {
-                space.topic = Some(t);
+            if session.suggested_only {
+                summary.children_state.retain(|event| event["content"]["suggested"] == true);
             }
-            if let Some(a) = avatar_url {
-                space.avatar_url = Some(a);
+            let children = response["children"].as_array().into_iter().flatten();
+            for child in children.filter_map(|child| serde_json::from_value::<SpaceHierarchyRoomSummary>(child.clone()).ok()) {
+                session.remote_children.insert(child.room_id.clone(), child);
             }
-            space.updated_at = chrono::Utc::now().timestamp_millis();
-            Ok(space.clone())
-        } else {
-            Err(SpaceError::SpaceNotFound)
+            return Some(summary);
         }
+        None
     }
 
-    pub async fn delete_space(&self, space_id: &str) -> Result<(), SpaceError> {
-        let mut spaces = self.spaces.write().await;
-        if spaces.remove(space_id).is_some() {
-            drop(spaces);
-            self.children.write().await.remove(space_id);
-            Ok(())
-        } else {
-            Err(SpaceError::SpaceNotFound)
+    /// Whether a user may see a room in a hierarchy: it is peekable, they are in it or invited
+    /// to it, or they are in a room its `restricted` join rule allows.
+    async fn is_accessible_to_user(&self, user_id: &str, summary: &SpaceHierarchyRoomSummary) -> Result<bool, SpaceError> {
+        if summary.is_peekable() {
+            return Ok(true);
+        }
+        let membership = self.member_storage.get_membership(&summary.room_id, user_id).await?;
+        if matches!(membership.as_deref(), Some("join" | "invite")) {
+            return Ok(true);
         }
+        if matches!(summary.join_rule.as_str(), "restricted" | "knock_restricted") {
+            for allowed in &summary.allowed_room_ids {
+                if self.member_storage.is_member(allowed, user_id).await? {
+                    return Ok(true);
+                }
+            }
+        }
+        Ok(false)
     }
-}
 
-impl Default for SpaceManager {
-    fn default() -> Self {
-        Self::new()
+    /// Whether a room may be described to another server. Restricted rooms are, with their
+    /// `allowed_room_ids`, so the other server can check its own users.
+    async fn is_accessible_to_server(&self, origin: &str, summary: &SpaceHierarchyRoomSummary) -> Result<bool, SpaceError> {
+        if summary.is_peekable() || summary.join_rule == "restricted" {
+            return Ok(true);
+        }
+        Ok(self.member_storage.has_server_member(&summary.room_id, origin).await?)
+    }
+
+    /// The federation `/hierarchy` response for a room of this server: its summary, the
+    /// summaries of its children we know and the IDs of the children `origin` may not see.
+    pub async fn get_federation_hierarchy(
+        &self,
+        origin: &str,
+        room_id: &str,
+        suggested_only: bool,
+    ) -> Result<Value, SpaceError> {
+        let summary = match self.get_room_summary(room_id, suggested_only).await? {
+            Some(summary) if self.is_accessible_to_server(origin, &summary).await? => summary,
+            _ => return Err(SpaceError::SpaceNotFound),
+        };
+
+        let mut children = Vec::new();
+        let mut inaccessible_children = Vec::new();
+        for child in summary.children() {
+            let Some(mut child_summary) = self.get_room_summary(&child.child_room_id, suggested_only).await? else {
+                continue;
+            };
+            if self.is_accessible_to_server(origin, &child_summary).await? {
+                child_summary.children_state.clear();
+                children.push(child_summary);
+            } else {
+                inaccessible_children.push(child.child_room_id);
+            }
+        }
+        Ok(json!({
+            "room": summary,
+            "children": children,
+            "inaccessible_children": inaccessible_children
+        }))
     }
 }
 
+/// The `via` servers of a space child or parent event, if it has any.
+fn via_servers(content: &Value) -> Option<Vec<String>> {
+    let via: Vec<String> = content
+        .get("via")?
+        .as_array()?
+        .iter()
+        .filter_map(|server| server.as_str().map(String::from))
+        .collect();
+    (!via.is_empty()).then_some(via)
+}
+
+/// An `order` has to be short printable ASCII to count.
+fn is_valid_order(order: &str) -> bool {
+    order.len() <= MAX_ORDER_LENGTH && order.chars().all(|c| (' '..='~').contains(&c))
+}
+
+/// The rooms named by the `m.room_membership` conditions of `m.room.join_rules` content.
+pub fn allowed_room_ids(join_rules: &Value) -> Vec<String> {
+    join_rules
+        .get("allow")
+        .and_then(Value::as_array)
+        .into_iter()
+        .flatten()
+        .filter(|condition| condition["type"] == "m.room_membership")
+        .filter_map(|condition| condition["room_id"].as_str().map(String::from))
+        .collect()
+}
+
+fn stripped_state(event: &StateEvent) -> Value {
+    json!({
+        "type": event.event_type,
+        "state_key": event.state_key,
+        "content": event.content,
+        "sender": event.sender,
+        "origin_server_ts": event.origin_server_ts
+    })
+}
+
 #[derive(Debug, thiserror::Error)]
 pub enum SpaceError {
     #[error("Space not found")]
     SpaceNotFound,
-    #[error("Invalid space name")]
-    InvalidName,
     #[error("Permission denied")]
     PermissionDenied,
-    #[error("Child already exists")]
-    ChildExists,
     #[error("Invalid child room")]
     InvalidChild,
+    #[error("Unknown or expired pagination token")]
+    InvalidPaginationToken,
+    #[error("Database error: {0}")]
+    Database(#[from] sqlx::Error),
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
 
-    #[tokio::test]
-    async fn test_create_space() {
-        let manager = SpaceManager::new();
-        let request = CreateSpaceRequest {
-            name: "Test Space".to_string(),
-            topic: Some("A test space".to_string()),
-            avatar_url: None,
-            is_public: true,
-            join_rule: SpaceJoinRule::Public,
-            initial_children: vec![],
-        };
-
-        let space = manager.create_space("@alice:example.com", request).await;
-        assert!(space.is_ok());
-        let space = space.unwrap();
-        assert_eq!(space.name, "Test Space");
-        assert!(space.space_id.starts_with("!space_"));
+    fn child_event(state_key: &str, content: Value, ts: i64) -> Value {
+        json!({
+            "type": SPACE_CHILD_EVENT_TYPE,
+            "state_key": state_key,
+            "sender": "@alice:example.com",
+            "origin_server_ts": ts,
+            "content": content
+        })
     }
 
-    #[tokio::test]
-    async fn test_add_child() {
-        let manager = SpaceManager::new();
-        let create_req = CreateSpaceRequest {
-            name: "Parent Space".to_string(),
-            topic: None,
-            avatar_url: None,
-            is_public: true,
-            join_rule: SpaceJoinRule::Public,
-            initial_children: vec![],
-        };
-
-        let space = manager.create_space("@alice:example.com", create_req).await.unwrap();
-
-        let add_req = AddChildRequest {
-            child_room_id: "!child:example.com".to_string(),
-            order: Some("001".to_string()),
-            suggested: true,
-            via_servers: vec!["example.com".to_string()],
-        };
-
-        let result = manager.add_child(&space.space_id, "@alice:example.com", add_req).await;
-        assert!(result.is_ok());
-
-        let children = manager.get_children(&space.space_id).await;
-        assert_eq!(children.len(), 1);
-        assert_eq!(children[0].child_room_id, "!child:example.com");
+    #[test]
+    fn test_child_without_via_is_removed() {
+        let child = SpaceChild::from_state("!space:example.com", "!a:example.com", "@alice:example.com", 1, &json!({}));
+        assert!(child.is_none());
+        let child = SpaceChild::from_state(
+            "!space:example.com",
+            "!a:example.com",
+            "@alice:example.com",
+            1,
+            &json!({ "via": ["example.com"], "suggested": true }),
+        )
+        .unwrap();
+        assert!(child.suggested);
+        assert_eq!(child.via_servers, vec!["example.com"]);
     }
 
-    #[tokio::test]
-    async fn test_get_hierarchy() {
-        let manager = SpaceManager::new();
-        let create_req = CreateSpaceRequest {
-            name: "Root Space".to_string(),
-            topic: None,
-            avatar_url: None,
-            is_public: true,
-            join_rule: SpaceJoinRule::Public,
-            initial_children: vec!["!child1:example.com".to_string()],
+    #[test]
+    fn test_children_are_sorted_by_order_then_timestamp() {
+        let summary = SpaceHierarchyRoomSummary {
+            room_id: "!space:example.com".to_string(),
+            room_type: Some(SPACE_ROOM_TYPE.to_string()),
+            children_state: vec![
+                child_event("!unordered_late:example.com", json!({ "via": ["example.com"] }), 30),
+                child_event("!b:example.com", json!({ "via": ["example.com"], "order": "b" }), 50),
+                child_event("!unordered_early:example.com", json!({ "via": ["example.com"] }), 10),
+                child_event("!a:example.com", json!({ "via": ["example.com"], "order": "a" }), 60),
+                child_event("!bad_order:example.com", json!({ "via": ["example.com"], "order": "\u{1F600}" }), 20),
+                child_event("!removed:example.com", json!({}), 5),
+            ],
+            ..Default::default()
         };
+        let order: Vec<String> = summary.children().into_iter().map(|c| c.child_room_id).collect();
+        assert_eq!(
+            order,
+            vec![
+                "!a:example.com",
+                "!b:example.com",
+                "!unordered_early:example.com",
+                "!bad_order:example.com",
+                "!unordered_late:example.com"
+            ]
+        );
+    }
 
-        let space = manager.create_space("@alice:example.com", create_req).await.unwrap();
+    #[test]
+    fn test_allowed_room_ids_only_lists_membership_conditions() {
+        let join_rules = json!({
+            "join_rule": "restricted",
+            "allow": [
+                { "type": "m.room_membership", "room_id": "!space:example.com" },
+                { "type": "org.example.custom", "room_id": "!other:example.com" }
+            ]
+        });
+        assert_eq!(allowed_room_ids(&join_rules), vec!["!space:example.com"]);
+        assert!(allowed_room_ids(&json!({ "join_rule": "public" })).is_empty());
+    }
 
-        let hierarchy = manager.get_hierarchy(&space.space_id, 1, 10).await;
-        assert!(hierarchy.is_some());
+    #[test]
+    fn test_remote_summary_deserializes_with_defaults() {
+        let summary: SpaceHierarchyRoomSummary = serde_json::from_value(json!({
+            "room_id": "!remote:other.org",
+            "name": "Remote",
+            "num_joined_members": 4,
+            "join_rule": "restricted",
+            "allowed_room_ids": ["!space:other.org"],
+            "world_readable": false,
+            "guest_can_join": false
+        }))
+        .unwrap();
+        assert!(!summary.is_space());
+        assert!(!summary.is_peekable());
+        assert!(summary.children_state.is_empty());
+        assert_eq!(summary.allowed_room_ids, vec!["!space:other.org"]);
+        let serialized = serde_json::to_value(&summary).unwrap();
+        assert_eq!(serialized["children_state"], json!([]));
+        assert!(serialized.get("topic").is_none());
+    }
 
-        let hierarchy = hierarchy.unwrap();
-        assert_eq!(hierarchy.space.name, "Root Space");
-        assert_eq!(hierarchy.children.len(), 1);
+    fn pagination_session(created_at: Instant) -> PaginationSession {
+        PaginationSession {
+            requester: "@alice:example.com".to_string(),
+            room_id: "!space:example.com".to_string(),
+            suggested_only: false,
+            max_depth: None,
+            queue: Vec::new(),
+            processed: HashSet::new(),
+            remote_children: HashMap::new(),
+            created_at,
+        }
     }
 
-    #[tokio::test]
-    async fn test_invalid_name() {
-        let manager = SpaceManager::new();
-        let request = CreateSpaceRequest {
-            name: "".to_string(),
-            topic: None,
-            avatar_url: None,
-            is_public: true,
-            join_rule: SpaceJoinRule::Public,
-            initial_children: vec![],
+    #[test]
+    fn test_pagination_sessions_are_bounded_and_expire() {
+        let mut sessions = PaginationSessions::new(2);
+        for token in ["a", "b", "c"] {
+            sessions.insert(token.to_string(), pagination_session(Instant::now()));
+        }
+        assert_eq!(sessions.len(), 2);
+        assert!(sessions.take("a").is_none());
+        assert!(sessions.take("b").is_some());
+        assert!(sessions.take("b").is_none());
+
+        // Expired sessions are dropped when the next one is stored
+        let Some(expired) = Instant::now().checked_sub(PAGINATION_SESSION_TTL) else {
+            return;
         };
-
-        let result = manager.create_space("@alice:example.com", request).await;
-        assert!(matches!(result, Err(SpaceError::InvalidName)));
+        let mut sessions = PaginationSessions::new(10);
+        sessions.insert("old".to_string(), pagination_session(expired));
+        sessions.insert("new".to_string(), pagination_session(Instant::now()));
+        assert_eq!(sessions.len(), 1);
+        assert!(sessions.take("new").is_some());
     }
 }
@@ -0,0 +1,228 @@
+use serde_json::Value;
+use sqlx::{Pool, Postgres};
+use std::sync::Arc;
+use tokio::sync::Notify;
+
+/// What a queued row carries, deciding the transaction field it is sent in.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AppServiceQueueKind {
+    Event,
+    Ephemeral,
+    ToDevice,
+}
+
+impl AppServiceQueueKind {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            AppServiceQueueKind::Event => "event",
+            AppServiceQueueKind::Ephemeral => "ephemeral",
+            AppServiceQueueKind::ToDevice => "to_device",
+        }
+    }
+}
+
+/// Delivery state of an application service.
+#[derive(Debug, Clone, sqlx::FromRow)]
+pub struct AppServiceState {
+    pub as_id: String,
+    pub failure_count: i32,
+    pub retry_last_ts: i64,
+    pub retry_interval_ms: i64,
+    pub last_successful_stream_id: i64,
+    pub last_success_ts: Option<i64>,
+}
+
+/// The queued rows making up one transaction to an application service.
+#[derive(Debug, Clone, Default)]
+pub struct QueuedAppServiceTransaction {
+    pub stream_ids: Vec<i64>,
+    pub events: Vec<Value>,
+    pub ephemeral: Vec<Value>,
+    pub to_device: Vec<Value>,
+}
+
+impl QueuedAppServiceTransaction {
+    pub fn is_empty(&self) -> bool {
+        self.stream_ids.is_empty()
+    }
+
+    /// Derived from the queued rows, so a retried transaction keeps its ID and the
+    /// application service can deduplicate it.
+    pub fn transaction_id(&self) -> String {
+        let first = self.stream_ids.iter().min().copied().unwrap_or_default();
+        format!("{}-{}-{}", first, self.last_stream_id(), self.stream_ids.len())
+    }
+
+    pub fn last_stream_id(&self) -> i64 {
+        self.stream_ids.iter().max().copied().unwrap_or_default()
+    }
+}
+
+/// Durable per-application-service queue of outgoing transactions.
+#[derive(Clone)]
+pub struct AppServiceQueueStorage {
+    pub pool: Arc<Pool<Postgres>>,
+    wakeup: Arc<Notify>,
+}
+
+impl AppServiceQueueStorage {
+    pub fn new(pool: &Arc<Pool<Postgres>>) -> Self {
+        Self {
+            pool: pool.clone(),
+            wakeup: Arc::new(Notify::new()),
+        }
+    }
+
+    /// Signalled whenever something is queued, so a sender in this process can start at once.
+    pub fn wakeup(&self) -> Arc<Notify> {
+        self.wakeup.clone()
+    }
+
+    /// Queues `payload` for each of `as_ids`.
+    pub async fn enqueue(
+        &self,
+        as_ids: &[String],
+        kind: AppServiceQueueKind,
+        payload: &Value,
+    ) -> Result<u64, sqlx::Error> {
+        if as_ids.is_empty() {
+            return Ok(0);
+        }
+        let queued = sqlx::query(
+            r#"
+            INSERT INTO appservice_outbound_queue (as_id, kind, payload, created_ts)
+            SELECT as_id, $2, $3, $4 FROM UNNEST($1::text[]) AS as_id
+            "#,
+        )
+        .bind(as_ids)
+        .bind(kind.as_str())
+        .bind(payload)
+        .bind(chrono::Utc::now().timestamp_millis())
+        .execute(&*self.pool)
+        .await?
+        .rows_affected();
+        if queued > 0 {
+            self.wakeup.notify_one();
+        }
+        Ok(queued)
+    }
+
+    /// Application services with something queued that are not backing off at `now`.
+    pub async fn get_ready_services(&self, now: i64) -> Result<Vec<String>, sqlx::Error> {
+        sqlx::query_scalar(
+            r#"
+            SELECT q.as_id FROM (SELECT DISTINCT as_id FROM appservice_outbound_queue) q
+            LEFT JOIN appservice_state s ON s.as_id = q.as_id
+            WHERE s.as_id IS NULL OR s.retry_last_ts + s.retry_interval_ms <= $1
+            "#,
+        )
+        .bind(now)
+        .fetch_all(&*self.pool)
+        .await
+    }
+
+    /// The oldest `limit` queued rows for `as_id`, in stream order.
+    pub async fn get_next_transaction(
+        &self,
+        as_id: &str,
+        limit: i64,
+    ) -> Result<QueuedAppServiceTransaction, sqlx::Error> {
+        let rows: Vec<(i64, String, Value)> = sqlx::query_as(
+            r#"
+            SELECT id, kind, payload FROM appservice_outbound_queue
+            WHERE as_id = $1
+            ORDER BY id LIMIT $2
+            "#,
+        )
+        .bind(as_id)
+        .bind(limit)
+        .fetch_all(&*self.pool)
+        .await?;
+
+        let mut transaction = QueuedAppServiceTransaction::default();
+        for (id, kind, payload) in rows {
+            transaction.stream_ids.push(id);
+            match kind.as_str() {
+                "ephemeral" => transaction.ephemeral.push(payload),
+                "to_device" => transaction.to_device.push(payload),
+                _ => transaction.events.push(payload),
+            }
+        }
+        Ok(transaction)
+    }
+
+    /// Removes a delivered transaction from the queue and clears the service's backoff.
+    pub async fn complete_transaction(
+        &self,
+        as_id: &str,
+        transaction: &QueuedAppServiceTransaction,
+    ) -> Result<(), sqlx::Error> {
+        let mut tx = self.pool.begin().await?;
+
+        sqlx::query("DELETE FROM appservice_outbound_queue WHERE as_id = $1 AND id = ANY($2)")
+            .bind(as_id)
+            .bind(&transaction.stream_ids)
+            .execute(&mut *tx)
+            .await?;
+        sqlx::query(
+            r#"
+            INSERT INTO appservice_state
+                (as_id, failure_count, retry_last_ts, retry_interval_ms, last_successful_stream_id, last_success_ts)
+            VALUES ($1, 0, 0, 0, $2, $3)
+            ON CONFLICT (as_id) DO UPDATE SET
+                failure_count = 0,
+                retry_last_ts = 0,
+                retry_interval_ms = 0,
+                last_successful_stream_id = GREATEST(appservice_state.last_successful_stream_id, EXCLUDED.last_successful_stream_id),
+                last_success_ts = EXCLUDED.last_success_ts
+            "#,
+        )
+        .bind(as_id)
+        .bind(transaction.last_stream_id())
+        .bind(chrono::Utc::now().timestamp_millis())
+        .execute(&mut *tx)
+        .await?;
+
+        tx.commit().await
+    }
+
+    /// Records a failed attempt; the service is not tried again for `retry_interval_ms`.
+    pub async fn record_failure(&self, as_id: &str, retry_interval_ms: i64) -> Result<(), sqlx::Error> {
+        sqlx::query(
+            r#"
+            INSERT INTO appservice_state (as_id, failure_count, retry_last_ts, retry_interval_ms)
+            VALUES ($1, 1, $2, $3)
+            ON CONFLICT (as_id) DO UPDATE SET
+                failure_count = appservice_state.failure_count + 1,
+                retry_last_ts = EXCLUDED.retry_last_ts,
+                retry_interval_ms = EXCLUDED.retry_interval_ms
+            "#,
+        )
+        .bind(as_id)
+        .bind(chrono::Utc::now().timestamp_millis())
+        .bind(retry_interval_ms)
+        .execute(&*self.pool)
+        .await?;
+        Ok(())
+    }
+
+    pub async fn get_state(&self, as_id: &str) -> Result<Option<AppServiceState>, sqlx::Error> {
+        sqlx::query_as(
+            r#"
+            SELECT as_id, failure_count, retry_last_ts, retry_interval_ms,
+                   last_successful_stream_id, last_success_ts
+            FROM appservice_state WHERE as_id = $1
+            "#,
+        )
+        .bind(as_id)
+        .fetch_optional(&*self.pool)
+        .await
+    }
+
+    pub async fn get_pending_count(&self, as_id: &str) -> Result<i64, sqlx::Error> {
+        sqlx::query_scalar("SELECT COUNT(*) FROM appservice_outbound_queue WHERE as_id = $1")
+            .bind(as_id)
+            .fetch_one(&*self.pool)
+            .await
+    }
+}
@@ -1,4 +1,12 @@
-use sqlx::{Pool, Postgres};
+use crate::appservice::AppServiceScheduler;
+use crate::federation::event_builder::{self, EventBuilder, EventDagContext, EventSigningKey};
+use crate::federation::{pdu, EventAuthChain};
+use crate::services::notifier::{Notifier, NotifierStream};
+use crate::services::push_actions_service::PushActionsService;
+use crate::storage::relations::RelationsStorage;
+use crate::storage::search::EventSearchStorage;
+use crate::storage::FederationQueueStorage;
+use sqlx::{PgConnection, Pool, Postgres};
 use std::sync::Arc;
 
 #[derive(Debug, Clone, sqlx::FromRow)]
@@ -16,6 +24,27 @@ pub struct RoomEvent {
     pub status: Option<String>,
     pub reference_image: Option<String>,
     pub origin: String,
+    /// Position in the order events were persisted; only filled by queries that select it.
+    #[sqlx(default)]
+    pub stream_ordering: i64,
+}
+
+impl RoomEvent {
+    /// The event in the client-server API format.
+    pub fn to_client_event(&self) -> serde_json::Value {
+        let mut value = serde_json::json!({
+            "type": self.event_type,
+            "content": self.content,
+            "sender": self.user_id,
+            "origin_server_ts": self.origin_server_ts,
+            "event_id": self.event_id,
+            "room_id": self.room_id
+        });
+        if let Some(state_key) = &self.state_key {
+            value["state_key"] = serde_json::json!(state_key);
+        }
+        value
+    }
 }
 
 #[derive(Debug, Clone, sqlx::FromRow)]
@@ -41,6 +70,11 @@ pub struct StateEvent {
 #[derive(Clone)]
 pub struct EventStorage {
     pub pool: Arc<Pool<Postgres>>,
+    notifier: Option<Notifier>,
+    signing_key: Option<EventSigningKey>,
+    federation_queue: Option<FederationQueueStorage>,
+    push_actions: Option<Arc<PushActionsService>>,
+    appservices: Option<Arc<AppServiceScheduler>>,
 }
 
 #[derive(Debug, Clone)]
@@ -56,46 +90,570 @@ pub struct CreateEventParams {
 
 impl EventStorage {
     pub fn new(pool: &Arc<Pool<Postgres>>) -> Self {
-        Self { pool: pool.clone() }
+        Self {
+            pool: pool.clone(),
+            notifier: None,
+            signing_key: None,
+            federation_queue: None,
+            push_actions: None,
+            appservices: None,
+        }
+    }
+
+    pub fn with_notifier(mut self, notifier: Notifier) -> Self {
+        self.notifier = Some(notifier);
+        self
+    }
+
+    /// Signs locally created events with the server key so other homeservers accept them.
+    pub fn with_signing_key(mut self, signing_key: EventSigningKey) -> Self {
+        self.signing_key = Some(signing_key);
+        self
+    }
+
+    /// Queues locally created events for the other servers in the room, in the same
+    /// transaction as the event itself.
+    pub fn with_federation_queue(mut self, federation_queue: FederationQueueStorage) -> Self {
+        self.federation_queue = Some(federation_queue);
+        self
+    }
+
+    /// Runs push rules over new events before anyone is woken up for them, so /sync sees the
+    /// unread counts along with the event.
+    pub fn with_push_actions(mut self, push_actions: Arc<PushActionsService>) -> Self {
+        self.push_actions = Some(push_actions);
+        self
+    }
+
+    /// Queues new events for the application services interested in them.
+    pub fn with_appservices(mut self, appservices: Arc<AppServiceScheduler>) -> Self {
+        self.appservices = Some(appservices);
+        self
     }
 
+    pub fn notifier(&self) -> Option<&Notifier> {
+        self.notifier.as_ref()
+    }
+
+    pub fn signing_key(&self) -> Option<&EventSigningKey> {
+        self.signing_key.as_ref()
+    }
+
+    /// Events written inside a transaction are not visible until commit, so the caller
+    /// passes each of them to `event_persisted` once the transaction has committed.
     pub async fn create_event(&self, params: CreateEventParams, tx: Option<&mut sqlx::Transaction<'_, sqlx::Postgres>>) -> Result<RoomEvent, sqlx::Error> {
+        if tx.is_some() {
+            return self.insert_event(params, tx).await;
+        }
+
+        let event = self.insert_event(params, None).await?;
+        self.event_persisted(&event).await;
+        Ok(event)
+    }
+
+    /// Local events are built into full PDUs: they reference the room's forward extremities,
+    /// carry their auth events, content hash and signature, and from room v3 onwards get the
+    /// reference-hash event ID in place of `params.event_id`.
+    async fn insert_event(&self, params: CreateEventParams, tx: Option<&mut sqlx::Transaction<'_, sqlx::Postgres>>) -> Result<RoomEvent, sqlx::Error> {
+        match tx {
+            Some(tx) => self.insert_local_event(tx, params).await,
+            None => {
+                let mut conn = self.pool.acquire().await?;
+                self.insert_local_event(&mut conn, params).await
+            }
+        }
+    }
+
+    async fn insert_local_event(&self, conn: &mut PgConnection, params: CreateEventParams) -> Result<RoomEvent, sqlx::Error> {
+        let (event_id, event) = self.build_event_on(conn, params).await?;
+        self.insert_built_event(conn, &event_id, &event).await
+    }
+
+    /// Builds and signs a local event at the room's current forward extremities without
+    /// storing it, for events another server has to counter-sign first (invites).
+    pub async fn build_event(&self, params: CreateEventParams) -> Result<(String, serde_json::Value), sqlx::Error> {
+        let mut conn = self.pool.acquire().await?;
+        self.build_event_on(&mut conn, params).await
+    }
+
+    /// Stores an event from `build_event`, with whatever signatures were added since, as if
+    /// it had been created by `create_event`.
+    pub async fn create_built_event(&self, event_id: &str, event: &serde_json::Value) -> Result<RoomEvent, sqlx::Error> {
+        let mut conn = self.pool.acquire().await?;
+        let inserted = self.insert_built_event(&mut conn, event_id, event).await?;
+        self.event_persisted(&inserted).await;
+        Ok(inserted)
+    }
+
+    /// Records the relation of a new event at the edge of the room, indexes it for search and
+    /// evaluates push rules for it, then wakes up the room's syncing users, and the target of a
+    /// membership change.
+    pub async fn event_persisted(&self, event: &RoomEvent) {
+        if let Err(e) = RelationsStorage::new(&self.pool).add_event_relation(event).await {
+            ::tracing::warn!("Failed to record relation of {}: {}", event.event_id, e);
+        }
+        if let Err(e) = EventSearchStorage::new(&self.pool).add_event(event).await {
+            ::tracing::warn!("Failed to index {} for search: {}", event.event_id, e);
+        }
+        if let Some(push_actions) = &self.push_actions {
+            if let Err(e) = push_actions.handle_new_event(event).await {
+                ::tracing::warn!("Failed to evaluate push rules for {}: {}", event.event_id, e);
+            }
+        }
+        if let Some(appservices) = &self.appservices {
+            appservices.handle_event(event).await;
+        }
+        if let Some(notifier) = &self.notifier {
+            let targets: Vec<String> = match (event.event_type.as_str(), &event.state_key) {
+                ("m.room.member", Some(state_key)) => vec![state_key.clone()],
+                _ => Vec::new(),
+            };
+            notifier
+                .notify_room(NotifierStream::Events, &event.room_id, &targets)
+                .await;
+        }
+    }
+
+    async fn build_event_on(
+        &self,
+        conn: &mut PgConnection,
+        params: CreateEventParams,
+    ) -> Result<(String, serde_json::Value), sqlx::Error> {
+        let mut builder = EventBuilder::new(&params.room_id, &params.user_id, &params.event_type, params.content)
+            .with_origin_server_ts(params.origin_server_ts);
+        if let Some(state_key) = params.state_key {
+            builder = builder.with_state_key(state_key);
+        }
+        let ctx = Self::dag_context_on(conn, &builder).await?;
+        let origin = match &self.signing_key {
+            Some(key) => key.server_name().to_string(),
+            None => pdu::server_name_of(&builder.sender).unwrap_or_default().to_string(),
+        };
+        Ok(builder.build(&origin, &ctx, &params.event_id, self.signing_key.as_ref()))
+    }
+
+    async fn insert_built_event(
+        &self,
+        conn: &mut PgConnection,
+        event_id: &str,
+        event: &serde_json::Value,
+    ) -> Result<RoomEvent, sqlx::Error> {
         let processed_ts = chrono::Utc::now().timestamp_millis();
-        
-        let query = r#"
-            INSERT INTO events (event_id, room_id, user_id, sender, event_type, content, state_key, origin_server_ts, processed_ts, unsigned)
-            VALUES ($1, $2, $3, $3, $4, $5, $6, $7, $8, '{}')
+        let field = |name: &str| event.get(name).and_then(|v| v.as_str()).unwrap_or_default();
+
+        let inserted: RoomEvent = sqlx::query_as(
+            r#"
+            INSERT INTO events (event_id, room_id, user_id, sender, event_type, content, state_key, origin_server_ts,
+                                processed_ts, unsigned, depth, auth_events, prev_events, hashes, signatures, pdu)
+            VALUES ($1, $2, $3, $3, $4, $5, $6, $7, $8, '{}', $9, $10, $11, $12, $13, $14)
             RETURNING event_id, room_id, user_id, event_type, content, state_key, 
                       COALESCE(depth, 0) as depth, origin_server_ts, processed_ts, 
                       COALESCE(not_before, 0) as not_before, status, reference_image, 
-                      COALESCE(origin, 'self') as origin
-            "#;
-
-        if let Some(tx) = tx {
-            sqlx::query_as(query)
-                .bind(&params.event_id)
-                .bind(&params.room_id)
-                .bind(&params.user_id)
-                .bind(&params.event_type)
-                .bind(&params.content)
-                .bind(params.state_key.as_deref())
-                .bind(params.origin_server_ts)
-                .bind(processed_ts)
-                .fetch_one(&mut **tx)
-                .await
+                      COALESCE(origin, 'self') as origin, stream_ordering
+            "#,
+        )
+        .bind(event_id)
+        .bind(field("room_id"))
+        .bind(field("sender"))
+        .bind(field("type"))
+        .bind(event.get("content"))
+        .bind(event.get("state_key").and_then(|v| v.as_str()))
+        .bind(event.get("origin_server_ts").and_then(|v| v.as_i64()).unwrap_or(processed_ts))
+        .bind(processed_ts)
+        .bind(event.get("depth").and_then(|v| v.as_i64()).unwrap_or(0))
+        .bind(event.get("auth_events"))
+        .bind(event.get("prev_events"))
+        .bind(event.get("hashes"))
+        .bind(event.get("signatures"))
+        .bind(event)
+        .fetch_one(&mut *conn)
+        .await?;
+
+        let prev_events = pdu::referenced_event_ids(event.get("prev_events"));
+        Self::update_forward_extremities(conn, &inserted.room_id, &inserted.event_id, &prev_events).await?;
+
+        if let Some(queue) = &self.federation_queue {
+            let target = match inserted.event_type.as_str() {
+                "m.room.member" => inserted.state_key.as_deref(),
+                _ => None,
+            };
+            queue.enqueue_room_pdu_on(conn, &inserted.room_id, event, target).await?;
+        }
+        Ok(inserted)
+    }
+
+    /// The room version from the `m.room.create` event, or from `rooms.version` for rooms
+    /// created before create events were stored.
+    pub async fn get_room_version(&self, room_id: &str) -> Result<String, sqlx::Error> {
+        let mut conn = self.pool.acquire().await?;
+        Self::room_version_on(&mut conn, room_id).await
+    }
+
+    /// Where an event built by `builder` would attach to the room DAG right now.
+    pub async fn get_event_dag_context(&self, builder: &EventBuilder) -> Result<EventDagContext, sqlx::Error> {
+        let mut conn = self.pool.acquire().await?;
+        Self::dag_context_on(&mut conn, builder).await
+    }
+
+    async fn room_version_on(conn: &mut PgConnection, room_id: &str) -> Result<String, sqlx::Error> {
+        sqlx::query_scalar(
+            r#"
+            SELECT COALESCE(
+                (SELECT COALESCE(content->>'room_version', '1') FROM events
+                 WHERE room_id = $1 AND event_type = 'm.room.create' AND state_key = '' AND NOT soft_failed
+                 ORDER BY origin_server_ts LIMIT 1),
+                (SELECT version FROM rooms WHERE room_id = $1),
+                '1'
+            )
+            "#,
+        )
+        .bind(room_id)
+        .fetch_one(&mut *conn)
+        .await
+    }
+
+    async fn dag_context_on(conn: &mut PgConnection, builder: &EventBuilder) -> Result<EventDagContext, sqlx::Error> {
+        let room_id = builder.room_id.as_str();
+        let room_version = if builder.event_type == "m.room.create" && builder.state_key.as_deref() == Some("") {
+            event_builder::room_version_from_create(&builder.content)
         } else {
-            sqlx::query_as(query)
-                .bind(&params.event_id)
-                .bind(&params.room_id)
-                .bind(&params.user_id)
-                .bind(&params.event_type)
-                .bind(&params.content)
-                .bind(params.state_key.as_deref())
-                .bind(params.origin_server_ts)
-                .bind(processed_ts)
-                .fetch_one(&*self.pool)
-                .await
+            Self::room_version_on(conn, room_id).await?
+        };
+
+        let mut prev: Vec<(String, i64)> = sqlx::query_as(
+            r#"
+            SELECT e.event_id, COALESCE(e.depth, 0) FROM event_forward_extremities f
+            JOIN events e ON e.event_id = f.event_id
+            WHERE f.room_id = $1
+            ORDER BY COALESCE(e.depth, 0) DESC, e.origin_server_ts DESC
+            LIMIT $2
+            "#,
+        )
+        .bind(room_id)
+        .bind(event_builder::MAX_PREV_EVENTS as i64)
+        .fetch_all(&mut *conn)
+        .await?;
+        if prev.is_empty() {
+            prev = sqlx::query_as(
+                r#"
+                SELECT event_id, COALESCE(depth, 0) FROM events
+                WHERE room_id = $1 AND NOT soft_failed
+                ORDER BY COALESCE(depth, 0) DESC, origin_server_ts DESC
+                LIMIT 1
+                "#,
+            )
+            .bind(room_id)
+            .fetch_all(&mut *conn)
+            .await?;
         }
+        let depth = prev.iter().map(|(_, depth)| *depth).max().unwrap_or(0) + 1;
+
+        let auth_types = EventAuthChain::auth_types_for_event(&builder.auth_probe());
+        let event_types: Vec<&str> = auth_types.iter().map(|(t, _)| t.as_str()).collect();
+        let state_keys: Vec<&str> = auth_types.iter().map(|(_, k)| k.as_str()).collect();
+        let current: Vec<(String, String, String)> = sqlx::query_as(
+            r#"
+            SELECT DISTINCT ON (event_type, state_key) event_type, state_key, event_id FROM events
+            WHERE room_id = $1 AND NOT soft_failed AND event_type = ANY($2) AND state_key = ANY($3)
+            ORDER BY event_type, state_key, origin_server_ts DESC
+            "#,
+        )
+        .bind(room_id)
+        .bind(&event_types)
+        .bind(&state_keys)
+        .fetch_all(&mut *conn)
+        .await?;
+        let auth_events = auth_types
+            .iter()
+            .filter_map(|(event_type, state_key)| {
+                current
+                    .iter()
+                    .find(|(t, k, _)| t == event_type && k == state_key)
+                    .map(|(_, _, event_id)| event_id.clone())
+            })
+            .collect();
+
+        Ok(EventDagContext {
+            room_version,
+            prev_events: prev.into_iter().map(|(event_id, _)| event_id).collect(),
+            auth_events,
+            depth,
+        })
+    }
+
+    /// A new event replaces the extremities it references, unless an event we already hold
+    /// points at it (as happens for events that arrive late).
+    async fn update_forward_extremities(
+        conn: &mut PgConnection,
+        room_id: &str,
+        event_id: &str,
+        prev_events: &[String],
+    ) -> Result<(), sqlx::Error> {
+        sqlx::query("DELETE FROM event_forward_extremities WHERE room_id = $1 AND event_id = ANY($2)")
+            .bind(room_id)
+            .bind(prev_events)
+            .execute(&mut *conn)
+            .await?;
+
+        sqlx::query(
+            r#"
+            INSERT INTO event_forward_extremities (room_id, event_id)
+            SELECT $1, $2
+            WHERE NOT EXISTS (
+                SELECT 1 FROM events WHERE room_id = $1 AND prev_events ? $2
+            )
+            ON CONFLICT DO NOTHING
+            "#,
+        )
+        .bind(room_id)
+        .bind(event_id)
+        .execute(&mut *conn)
+        .await?;
+        Ok(())
+    }
+
+    /// Persists a PDU received over federation with its DAG and signature fields. Soft-failed
+    /// events are stored but neither notified nor used as current state.
+    pub async fn create_federated_event(
+        &self,
+        params: CreateEventParams,
+        origin: &str,
+        pdu: &serde_json::Value,
+        soft_failed: bool,
+    ) -> Result<RoomEvent, sqlx::Error> {
+        let mut conn = self.pool.acquire().await?;
+        let event = Self::insert_federated_event(&mut conn, params, origin, pdu, soft_failed).await?;
+
+        if !soft_failed {
+            let prev_events = pdu::referenced_event_ids(pdu.get("prev_events"));
+            Self::update_forward_extremities(&mut conn, &event.room_id, &event.event_id, &prev_events).await?;
+            self.event_persisted(&event).await;
+        }
+        Ok(event)
+    }
+
+    /// Persists a PDU that is not at the edge of the room DAG: history fetched by backfill, or
+    /// state fetched to fill a gap. Forward extremities are left alone and nothing is notified,
+    /// but relations are recorded and the event is indexed so that older threads, edits and
+    /// messages show up.
+    pub async fn create_historical_event(
+        &self,
+        params: CreateEventParams,
+        origin: &str,
+        pdu: &serde_json::Value,
+    ) -> Result<RoomEvent, sqlx::Error> {
+        let mut conn = self.pool.acquire().await?;
+        let event = Self::insert_federated_event(&mut conn, params, origin, pdu, false).await?;
+        RelationsStorage::new(&self.pool).add_event_relation(&event).await?;
+        EventSearchStorage::new(&self.pool).add_event(&event).await?;
+        Ok(event)
+    }
+
+    async fn insert_federated_event(
+        conn: &mut PgConnection,
+        params: CreateEventParams,
+        origin: &str,
+        pdu: &serde_json::Value,
+        soft_failed: bool,
+    ) -> Result<RoomEvent, sqlx::Error> {
+        let processed_ts = chrono::Utc::now().timestamp_millis();
+        sqlx::query_as(
+            r#"
+            INSERT INTO events (event_id, room_id, user_id, sender, event_type, content, state_key, origin_server_ts,
+                                processed_ts, unsigned, depth, origin, auth_events, prev_events, hashes, signatures, soft_failed,
+                                pdu)
+            VALUES ($1, $2, $3, $3, $4, $5, $6, $7, $8, $9, $10, $11, $12, $13, $14, $15, $16, $17)
+            RETURNING event_id, room_id, user_id, event_type, content, state_key,
+                      COALESCE(depth, 0) as depth, origin_server_ts, processed_ts,
+                      COALESCE(not_before, 0) as not_before, status, reference_image,
+                      COALESCE(origin, 'self') as origin, stream_ordering
+            "#,
+        )
+        .bind(&params.event_id)
+        .bind(&params.room_id)
+        .bind(&params.user_id)
+        .bind(&params.event_type)
+        .bind(&params.content)
+        .bind(params.state_key.as_deref())
+        .bind(params.origin_server_ts)
+        .bind(processed_ts)
+        .bind(pdu.get("unsigned").cloned().unwrap_or_else(|| serde_json::json!({})))
+        .bind(pdu.get("depth").and_then(|d| d.as_i64()).unwrap_or(0))
+        .bind(origin)
+        .bind(pdu.get("auth_events"))
+        .bind(pdu.get("prev_events"))
+        .bind(pdu.get("hashes"))
+        .bind(pdu.get("signatures"))
+        .bind(soft_failed)
+        .bind(pdu)
+        .fetch_one(&mut *conn)
+        .await
+    }
+
+    /// The room's forward extremities with their depths.
+    pub async fn get_forward_extremities(&self, room_id: &str) -> Result<Vec<(String, i64)>, sqlx::Error> {
+        sqlx::query_as(
+            r#"
+            SELECT e.event_id, COALESCE(e.depth, 0) FROM event_forward_extremities f
+            JOIN events e ON e.event_id = f.event_id
+            WHERE f.room_id = $1
+            "#,
+        )
+        .bind(room_id)
+        .fetch_all(&*self.pool)
+        .await
+    }
+
+    /// Events referenced as prev events in the room that we do not hold, oldest first. These
+    /// are where backfill continues from.
+    pub async fn get_backward_extremities(&self, room_id: &str, limit: i64) -> Result<Vec<String>, sqlx::Error> {
+        sqlx::query_scalar(
+            r#"
+            SELECT prev.event_id FROM (
+                SELECT e.depth,
+                       CASE WHEN jsonb_typeof(p.elem) = 'array' THEN p.elem->>0 ELSE p.elem #>> '{}' END AS event_id
+                FROM events e
+                CROSS JOIN LATERAL jsonb_array_elements(
+                    CASE WHEN jsonb_typeof(e.prev_events) = 'array' THEN e.prev_events ELSE '[]'::jsonb END
+                ) AS p(elem)
+                WHERE e.room_id = $1
+            ) prev
+            WHERE NOT EXISTS (SELECT 1 FROM events x WHERE x.event_id = prev.event_id)
+            GROUP BY prev.event_id
+            ORDER BY MIN(COALESCE(prev.depth, 0))
+            LIMIT $2
+            "#,
+        )
+        .bind(room_id)
+        .bind(limit)
+        .fetch_all(&*self.pool)
+        .await
+    }
+
+    /// The subset of `event_ids` we do not hold.
+    pub async fn get_unknown_events(&self, event_ids: &[String]) -> Result<Vec<String>, sqlx::Error> {
+        sqlx::query_scalar(
+            r#"
+            SELECT id FROM UNNEST($1::text[]) AS id
+            WHERE NOT EXISTS (SELECT 1 FROM events WHERE event_id = id)
+            "#,
+        )
+        .bind(event_ids)
+        .fetch_all(&*self.pool)
+        .await
+    }
+
+    /// The state keys of those of `event_ids` that are state events we hold.
+    pub async fn get_state_keys(&self, event_ids: &[String]) -> Result<Vec<(String, String, String)>, sqlx::Error> {
+        sqlx::query_as(
+            r#"
+            SELECT event_id, event_type, state_key FROM events
+            WHERE event_id = ANY($1) AND state_key IS NOT NULL
+            "#,
+        )
+        .bind(event_ids)
+        .fetch_all(&*self.pool)
+        .await
+    }
+
+    /// Loads `event_ids` and their full auth chains as PDUs keyed by event ID, for state
+    /// resolution. Events we do not hold are silently absent from the result.
+    pub async fn get_events_with_auth_chains(
+        &self,
+        event_ids: &[String],
+    ) -> Result<std::collections::HashMap<String, serde_json::Value>, sqlx::Error> {
+        let rows: Vec<(String, serde_json::Value)> = sqlx::query_as(
+            r#"
+            WITH RECURSIVE chain(event_id) AS (
+                SELECT UNNEST($1::text[])
+                UNION
+                SELECT CASE WHEN jsonb_typeof(a.elem) = 'array' THEN a.elem->>0 ELSE a.elem #>> '{}' END
+                FROM chain
+                JOIN events e ON e.event_id = chain.event_id
+                CROSS JOIN LATERAL jsonb_array_elements(
+                    CASE WHEN jsonb_typeof(e.auth_events) = 'array' THEN e.auth_events ELSE '[]'::jsonb END
+                ) AS a(elem)
+            )
+            SELECT e.event_id, jsonb_strip_nulls(jsonb_build_object(
+                'event_id', e.event_id,
+                'room_id', e.room_id,
+                'sender', COALESCE(e.sender, e.user_id),
+                'type', e.event_type,
+                'state_key', e.state_key,
+                'content', e.content,
+                'origin_server_ts', e.origin_server_ts,
+                'depth', e.depth,
+                'auth_events', COALESCE(e.auth_events, '[]'::jsonb),
+                'prev_events', COALESCE(e.prev_events, '[]'::jsonb)
+            ))
+            FROM events e
+            WHERE e.event_id IN (SELECT event_id FROM chain)
+            "#,
+        )
+        .bind(event_ids)
+        .fetch_all(&*self.pool)
+        .await?;
+
+        Ok(rows.into_iter().collect())
+    }
+
+    /// The events among `event_ids` as PDUs to serve to other servers, keyed by event ID. Events
+    /// stored before PDUs were kept are rebuilt from their columns.
+    pub async fn get_pdus(
+        &self,
+        event_ids: &[String],
+    ) -> Result<std::collections::HashMap<String, serde_json::Value>, sqlx::Error> {
+        let rows: Vec<(String, serde_json::Value)> = sqlx::query_as(
+            r#"
+            SELECT event_id, COALESCE(pdu, jsonb_strip_nulls(jsonb_build_object(
+                'event_id', event_id,
+                'room_id', room_id,
+                'sender', COALESCE(sender, user_id),
+                'type', event_type,
+                'state_key', state_key,
+                'content', content,
+                'origin_server_ts', origin_server_ts,
+                'depth', depth,
+                'auth_events', COALESCE(auth_events, '[]'::jsonb),
+                'prev_events', COALESCE(prev_events, '[]'::jsonb),
+                'hashes', hashes,
+                'signatures', signatures
+            )))
+            FROM events WHERE event_id = ANY($1)
+            "#,
+        )
+        .bind(event_ids)
+        .fetch_all(&*self.pool)
+        .await?;
+        Ok(rows.into_iter().collect())
+    }
+
+    /// The IDs of the events in the auth chains of `event_ids`, not counting those events
+    /// themselves unless one authorises another.
+    pub async fn get_auth_chain_ids(&self, event_ids: &[String]) -> Result<Vec<String>, sqlx::Error> {
+        sqlx::query_scalar(
+            r#"
+            WITH RECURSIVE chain(event_id) AS (
+                SELECT CASE WHEN jsonb_typeof(a.elem) = 'array' THEN a.elem->>0 ELSE a.elem #>> '{}' END
+                FROM events e
+                CROSS JOIN LATERAL jsonb_array_elements(
+                    CASE WHEN jsonb_typeof(e.auth_events) = 'array' THEN e.auth_events ELSE '[]'::jsonb END
+                ) AS a(elem)
+                WHERE e.event_id = ANY($1)
+                UNION
+                SELECT CASE WHEN jsonb_typeof(a.elem) = 'array' THEN a.elem->>0 ELSE a.elem #>> '{}' END
+                FROM chain
+                JOIN events e ON e.event_id = chain.event_id
+                CROSS JOIN LATERAL jsonb_array_elements(
+                    CASE WHEN jsonb_typeof(e.auth_events) = 'array' THEN e.auth_events ELSE '[]'::jsonb END
+                ) AS a(elem)
+            )
+            SELECT event_id FROM chain
+            "#,
+        )
+        .bind(event_ids)
+        .fetch_all(&*self.pool)
+        .await
     }
 
     pub async fn get_event(&self, event_id: &str) -> Result<Option<RoomEvent>, sqlx::Error> {
@@ -113,6 +671,21 @@ impl EventStorage {
         Ok(event)
     }
 
+    /// The events among `event_ids` that exist, in no particular order.
+    pub async fn get_events_by_ids(&self, event_ids: &[String]) -> Result<Vec<RoomEvent>, sqlx::Error> {
+        sqlx::query_as(
+            r#"
+            SELECT event_id, room_id, user_id, event_type, content, state_key,
+                   COALESCE(depth, 0) as depth, origin_server_ts, COALESCE(processed_ts, 0) as processed_ts,
+                   COALESCE(not_before, 0) as not_before, status, reference_image, COALESCE(origin, '') as origin
+            FROM events WHERE event_id = ANY($1)
+            "#,
+        )
+        .bind(event_ids)
+        .fetch_all(&*self.pool)
+        .await
+    }
+
     pub async fn delete_events_before(
         &self,
         room_id: &str,
@@ -138,7 +711,7 @@ impl EventStorage {
             SELECT event_id, room_id, user_id, event_type, content, state_key, 
                    COALESCE(depth, 0) as depth, COALESCE(origin_server_ts, 0) as origin_server_ts, COALESCE(processed_ts, 0) as processed_ts, 
                    COALESCE(not_before, 0) as not_before, status, reference_image, COALESCE(origin, 'self') as origin
-            FROM events WHERE room_id = $1
+            FROM events WHERE room_id = $1 AND NOT soft_failed
             ORDER BY origin_server_ts DESC
             LIMIT $2
             "#,
@@ -161,7 +734,7 @@ impl EventStorage {
             SELECT event_id, room_id, user_id, event_type, content, state_key, 
                    COALESCE(depth, 0) as depth, COALESCE(origin_server_ts, 0) as origin_server_ts, COALESCE(processed_ts, 0) as processed_ts, 
                    COALESCE(not_before, 0) as not_before, status, reference_image, origin
-            FROM events WHERE room_id = $1 AND event_type = $2
+            FROM events WHERE room_id = $1 AND NOT soft_failed AND event_type = $2
             ORDER BY origin_server_ts DESC
             LIMIT $3
             "#,
@@ -234,10 +807,32 @@ impl EventStorage {
     pub async fn get_state_events(&self, room_id: &str) -> Result<Vec<StateEvent>, sqlx::Error> {
         sqlx::query_as::<_, StateEvent>(
             r#"
-            SELECT * FROM events WHERE room_id = $1 AND state_key IS NOT NULL ORDER BY origin_server_ts DESC
+            SELECT * FROM events WHERE room_id = $1 AND NOT soft_failed AND state_key IS NOT NULL ORDER BY origin_server_ts DESC
+            "#,
+        )
+        .bind(room_id)
+        .fetch_all(&*self.pool)
+        .await
+    }
+
+    /// State events persisted after stream position `after` and before `before`, newest first.
+    pub async fn get_state_events_between(
+        &self,
+        room_id: &str,
+        after: i64,
+        before: i64,
+    ) -> Result<Vec<StateEvent>, sqlx::Error> {
+        sqlx::query_as::<_, StateEvent>(
+            r#"
+            SELECT * FROM events
+            WHERE room_id = $1 AND NOT soft_failed AND state_key IS NOT NULL
+              AND stream_ordering > $2 AND stream_ordering < $3
+            ORDER BY stream_ordering DESC
             "#,
         )
         .bind(room_id)
+        .bind(after)
+        .bind(before)
         .fetch_all(&*self.pool)
         .await
     }
@@ -249,7 +844,7 @@ impl EventStorage {
     ) -> Result<Vec<StateEvent>, sqlx::Error> {
         sqlx::query_as::<_, StateEvent>(
             r#"
-            SELECT * FROM events WHERE room_id = $1 AND event_type = $2 AND state_key IS NOT NULL ORDER BY origin_server_ts DESC
+            SELECT * FROM events WHERE room_id = $1 AND NOT soft_failed AND event_type = $2 AND state_key IS NOT NULL ORDER BY origin_server_ts DESC
             "#,
         )
         .bind(room_id)
@@ -267,7 +862,7 @@ impl EventStorage {
         sqlx::query_as::<_, StateEvent>(
             r#"
             SELECT * FROM events 
-            WHERE room_id = $1 AND event_type = $2 AND state_key = $3 
+            WHERE room_id = $1 AND NOT soft_failed AND event_type = $2 AND state_key = $3 
             ORDER BY origin_server_ts DESC 
             LIMIT 1
             "#,
@@ -279,6 +874,25 @@ impl EventStorage {
         .await
     }
 
+    /// The current state events of the given types, one per `(type, state_key)`.
+    pub async fn get_current_state_by_types(
+        &self,
+        room_id: &str,
+        event_types: &[&str],
+    ) -> Result<Vec<StateEvent>, sqlx::Error> {
+        sqlx::query_as::<_, StateEvent>(
+            r#"
+            SELECT DISTINCT ON (event_type, state_key) * FROM events
+            WHERE room_id = $1 AND NOT soft_failed AND event_type = ANY($2) AND state_key IS NOT NULL
+            ORDER BY event_type, state_key, origin_server_ts DESC
+            "#,
+        )
+        .bind(room_id)
+        .bind(event_types)
+        .fetch_all(&*self.pool)
+        .await
+    }
+
     pub async fn report_event(
         &self,
         event_id: &str,
@@ -359,16 +973,32 @@ impl EventStorage {
         .await
     }
 
+    /// Strips the event's content, along with the content of its edits: an edit must not
+    /// bring back what was redacted. Neither relates to anything nor can be searched afterwards.
     pub async fn redact_event_content(&self, event_id: &str) -> Result<(), sqlx::Error> {
-        let redacted_content = serde_json::json!({});
-        sqlx::query(
-            "UPDATE events SET content = $1, redacted = true WHERE event_id = $2"
+        let mut tx = self.pool.begin().await?;
+        let edits: Vec<String> = sqlx::query_scalar(
+            "SELECT event_id FROM event_relations WHERE relates_to_id = $1 AND rel_type = 'm.replace'",
         )
-        .bind(redacted_content)
         .bind(event_id)
-        .execute(&*self.pool)
+        .fetch_all(&mut *tx)
         .await?;
-        Ok(())
+        let redacted: Vec<String> = std::iter::once(event_id.to_string()).chain(edits).collect();
+
+        sqlx::query("UPDATE events SET content = $1, redacted = true WHERE event_id = ANY($2)")
+            .bind(serde_json::json!({}))
+            .bind(&redacted)
+            .execute(&mut *tx)
+            .await?;
+        sqlx::query("DELETE FROM event_relations WHERE event_id = ANY($1)")
+            .bind(&redacted)
+            .execute(&mut *tx)
+            .await?;
+        sqlx::query("DELETE FROM event_search WHERE event_id = ANY($1)")
+            .bind(&redacted)
+            .execute(&mut *tx)
+            .await?;
+        tx.commit().await
     }
 
     pub async fn get_room_events_since(
@@ -382,7 +1012,7 @@ impl EventStorage {
             SELECT event_id, room_id, user_id, event_type, content, state_key, 
                    COALESCE(depth, 0) as depth, COALESCE(origin_server_ts, 0) as origin_server_ts, COALESCE(processed_ts, 0) as processed_ts, 
                    COALESCE(not_before, 0) as not_before, status, reference_image, COALESCE(origin, 'self') as origin
-            FROM events WHERE room_id = $1 AND origin_server_ts > $2
+            FROM events WHERE room_id = $1 AND NOT soft_failed AND origin_server_ts > $2
             ORDER BY origin_server_ts ASC
             LIMIT $3
             "#,
@@ -432,7 +1062,7 @@ impl EventStorage {
                    COALESCE(depth, 0) as depth, COALESCE(origin_server_ts, 0) as origin_server_ts, COALESCE(processed_ts, 0) as processed_ts, 
                    COALESCE(not_before, 0) as not_before, status, reference_image, COALESCE(origin, 'self') as origin
             FROM events 
-            WHERE room_id = ANY($1)
+            WHERE room_id = ANY($1) AND NOT soft_failed
             ORDER BY room_id, origin_server_ts DESC
             "#,
         )
@@ -454,10 +1084,12 @@ impl EventStorage {
         Ok(result)
     }
 
+    /// Events persisted after stream position `from` up to and including `to`, newest first.
     pub async fn get_room_events_since_batch(
         &self,
         room_ids: &[String],
-        since: i64,
+        from: i64,
+        to: i64,
         limit_per_room: i64,
     ) -> Result<std::collections::HashMap<String, Vec<RoomEvent>>, sqlx::Error> {
         if room_ids.is_empty() {
@@ -468,14 +1100,15 @@ impl EventStorage {
             r#"
             SELECT event_id, room_id, user_id, event_type, content, state_key, 
                    COALESCE(depth, 0) as depth, COALESCE(origin_server_ts, 0) as origin_server_ts, COALESCE(processed_ts, 0) as processed_ts, 
-                   COALESCE(not_before, 0) as not_before, status, reference_image, COALESCE(origin, 'self') as origin
+                   COALESCE(not_before, 0) as not_before, status, reference_image, COALESCE(origin, 'self') as origin, stream_ordering
             FROM events 
-            WHERE room_id = ANY($1) AND origin_server_ts > $2
-            ORDER BY room_id, origin_server_ts DESC
+            WHERE room_id = ANY($1) AND NOT soft_failed AND stream_ordering > $2 AND stream_ordering <= $3
+            ORDER BY room_id, stream_ordering DESC
             "#,
         )
         .bind(room_ids)
-        .bind(since)
+        .bind(from)
+        .bind(to)
         .fetch_all(&*self.pool)
         .await?;
 
@@ -493,6 +1126,47 @@ impl EventStorage {
         Ok(result)
     }
 
+    /// The position of the most recently persisted event, or 0 when there are none.
+    pub async fn get_current_stream_ordering(&self) -> Result<i64, sqlx::Error> {
+        sqlx::query_scalar("SELECT COALESCE(MAX(stream_ordering), 0) FROM events")
+            .fetch_one(&*self.pool)
+            .await
+    }
+
+    /// The position of the last event persisted at or before `ts`, for sync tokens that only
+    /// carry a timestamp.
+    pub async fn get_stream_ordering_at(&self, ts: i64) -> Result<i64, sqlx::Error> {
+        sqlx::query_scalar(
+            "SELECT COALESCE(MAX(stream_ordering), 0) FROM events WHERE COALESCE(processed_ts, origin_server_ts) <= $1",
+        )
+        .bind(ts)
+        .fetch_one(&*self.pool)
+        .await
+    }
+
+    /// Timestamp of the most recent event in each room, used to order room lists.
+    pub async fn get_rooms_latest_ts(
+        &self,
+        room_ids: &[String],
+    ) -> Result<std::collections::HashMap<String, i64>, sqlx::Error> {
+        if room_ids.is_empty() {
+            return Ok(std::collections::HashMap::new());
+        }
+
+        let rows: Vec<(String, i64)> = sqlx::query_as(
+            r#"
+            SELECT room_id, MAX(origin_server_ts) FROM events
+            WHERE room_id = ANY($1) AND NOT soft_failed
+            GROUP BY room_id
+            "#,
+        )
+        .bind(room_ids)
+        .fetch_all(&*self.pool)
+        .await?;
+
+        Ok(rows.into_iter().collect())
+    }
+
     pub async fn get_event_by_id(&self, event_id: &str) -> Result<Option<RoomEvent>, sqlx::Error> {
         sqlx::query_as::<_, RoomEvent>(
             r#"
@@ -519,7 +1193,7 @@ impl EventStorage {
                    COALESCE(depth, 0) as depth, origin_server_ts, processed_ts, 
                    COALESCE(not_before, 0) as not_before, status, reference_image, COALESCE(origin, '') as origin
             FROM events 
-            WHERE room_id = $1 AND origin_server_ts < $2 
+            WHERE room_id = $1 AND NOT soft_failed AND origin_server_ts < $2 
             ORDER BY origin_server_ts DESC 
             LIMIT $3
             "#,
@@ -543,7 +1217,7 @@ impl EventStorage {
                    COALESCE(depth, 0) as depth, origin_server_ts, processed_ts, 
                    COALESCE(not_before, 0) as not_before, status, reference_image, COALESCE(origin, '') as origin
             FROM events 
-            WHERE room_id = $1 AND origin_server_ts > $2 
+            WHERE room_id = $1 AND NOT soft_failed AND origin_server_ts > $2 
             ORDER BY origin_server_ts ASC 
             LIMIT $3
             "#,
@@ -566,7 +1240,7 @@ impl EventStorage {
                    COALESCE(depth, 0) as depth, origin_server_ts, processed_ts, 
                    COALESCE(not_before, 0) as not_before, status, reference_image, COALESCE(origin, '') as origin
             FROM events 
-            WHERE room_id = $1 
+            WHERE room_id = $1 AND NOT soft_failed 
             ORDER BY origin_server_ts DESC 
             LIMIT $2
             "#,
@@ -588,7 +1262,7 @@ impl EventStorage {
                    COALESCE(depth, 0) as depth, origin_server_ts, processed_ts, 
                    COALESCE(not_before, 0) as not_before, status, reference_image, COALESCE(origin, '') as origin
             FROM events 
-            WHERE room_id = $1 AND origin_server_ts <= $2 
+            WHERE room_id = $1 AND NOT soft_failed AND origin_server_ts <= $2 
             ORDER BY origin_server_ts DESC 
             LIMIT 1
             "#,
@@ -610,7 +1284,7 @@ impl EventStorage {
                    COALESCE(depth, 0) as depth, origin_server_ts, processed_ts, 
                    COALESCE(not_before, 0) as not_before, status, reference_image, COALESCE(origin, '') as origin
             FROM events 
-            WHERE room_id = $1 AND origin_server_ts >= $2 
+            WHERE room_id = $1 AND NOT soft_failed AND origin_server_ts >= $2 
             ORDER BY origin_server_ts ASC 
             LIMIT 1
             "#,
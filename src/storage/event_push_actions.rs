@@ -0,0 +1,197 @@
+use serde_json::Value;
+use sqlx::{Pool, Postgres};
+use std::sync::Arc;
+
+/// The thread ID of events that are not in a thread.
+pub const MAIN_THREAD_ID: &str = "main";
+
+/// The push rule outcome of one event for one user.
+#[derive(Debug, Clone)]
+pub struct UserPushActions {
+    pub user_id: String,
+    pub actions: Value,
+    pub notif: bool,
+    pub highlight: bool,
+}
+
+/// Unread counts of one thread of a room, `main` being the room timeline outside threads.
+#[derive(Debug, Clone, PartialEq, Eq, sqlx::FromRow)]
+pub struct ThreadUnreadCounts {
+    pub thread_id: String,
+    pub notification_count: i64,
+    pub highlight_count: i64,
+}
+
+/// A stored notification as listed by `/notifications`, newest first.
+#[derive(Debug, Clone, sqlx::FromRow)]
+pub struct NotificationRecord {
+    pub event_id: String,
+    pub room_id: String,
+    pub stream_ordering: i64,
+    pub actions: Value,
+    pub highlight: bool,
+    pub read: bool,
+}
+
+#[derive(Clone)]
+pub struct EventPushActionsStorage {
+    pub pool: Arc<Pool<Postgres>>,
+}
+
+impl EventPushActionsStorage {
+    pub fn new(pool: &Arc<Pool<Postgres>>) -> Self {
+        Self { pool: pool.clone() }
+    }
+
+    pub async fn add_push_actions(
+        &self,
+        room_id: &str,
+        event_id: &str,
+        thread_id: &str,
+        stream_ordering: i64,
+        actions: &[UserPushActions],
+    ) -> Result<(), sqlx::Error> {
+        let mut tx = self.pool.begin().await?;
+        for user_actions in actions {
+            sqlx::query(
+                r#"
+                INSERT INTO event_push_actions (user_id, event_id, room_id, thread_id, stream_ordering, actions, notif, highlight)
+                VALUES ($1, $2, $3, $4, $5, $6, $7, $8)
+                ON CONFLICT (user_id, event_id) DO NOTHING
+                "#,
+            )
+            .bind(&user_actions.user_id)
+            .bind(event_id)
+            .bind(room_id)
+            .bind(thread_id)
+            .bind(stream_ordering)
+            .bind(&user_actions.actions)
+            .bind(user_actions.notif)
+            .bind(user_actions.highlight)
+            .execute(&mut *tx)
+            .await?;
+        }
+        tx.commit().await
+    }
+
+    /// Moves the user's read position in the room, or in one thread of it, up to `event_id`.
+    /// Receipts for events before the current position are ignored. Returns whether the
+    /// position moved.
+    pub async fn set_receipt(
+        &self,
+        user_id: &str,
+        room_id: &str,
+        thread_id: Option<&str>,
+        event_id: &str,
+    ) -> Result<bool, sqlx::Error> {
+        let updated = sqlx::query(
+            r#"
+            INSERT INTO event_push_receipts (user_id, room_id, thread_id, event_id, stream_ordering)
+            SELECT $1, $2, $3, event_id, COALESCE(processed_ts, origin_server_ts)
+            FROM events WHERE event_id = $4 AND room_id = $2
+            ON CONFLICT (user_id, room_id, thread_id) DO UPDATE SET
+                event_id = EXCLUDED.event_id,
+                stream_ordering = EXCLUDED.stream_ordering
+            WHERE event_push_receipts.stream_ordering < EXCLUDED.stream_ordering
+            "#,
+        )
+        .bind(user_id)
+        .bind(room_id)
+        .bind(thread_id.unwrap_or_default())
+        .bind(event_id)
+        .execute(&*self.pool)
+        .await?
+        .rows_affected();
+        Ok(updated > 0)
+    }
+
+    /// Counts of the notifying events the user has not read yet, per thread. An event is read
+    /// once it is covered by an unthreaded receipt or by a receipt in its own thread.
+    pub async fn get_unread_counts(
+        &self,
+        user_id: &str,
+        room_id: &str,
+    ) -> Result<Vec<ThreadUnreadCounts>, sqlx::Error> {
+        sqlx::query_as(
+            r#"
+            SELECT a.thread_id,
+                   COUNT(*) FILTER (WHERE a.notif) AS notification_count,
+                   COUNT(*) FILTER (WHERE a.highlight) AS highlight_count
+            FROM event_push_actions a
+            WHERE a.user_id = $1 AND a.room_id = $2
+              AND a.stream_ordering > COALESCE((
+                  SELECT MAX(r.stream_ordering) FROM event_push_receipts r
+                  WHERE r.user_id = a.user_id AND r.room_id = a.room_id AND r.thread_id IN ('', a.thread_id)
+              ), 0)
+            GROUP BY a.thread_id
+            "#,
+        )
+        .bind(user_id)
+        .bind(room_id)
+        .fetch_all(&*self.pool)
+        .await
+    }
+
+    /// The user's badge: unread notifications over all rooms, and the number of rooms that
+    /// have any.
+    pub async fn get_badge_counts(&self, user_id: &str) -> Result<(i64, i64), sqlx::Error> {
+        sqlx::query_as(
+            r#"
+            SELECT COUNT(*), COUNT(DISTINCT a.room_id)
+            FROM event_push_actions a
+            WHERE a.user_id = $1 AND a.notif
+              AND a.stream_ordering > COALESCE((
+                  SELECT MAX(r.stream_ordering) FROM event_push_receipts r
+                  WHERE r.user_id = a.user_id AND r.room_id = a.room_id AND r.thread_id IN ('', a.thread_id)
+              ), 0)
+            "#,
+        )
+        .bind(user_id)
+        .fetch_one(&*self.pool)
+        .await
+    }
+
+    /// The user's notifications older than `before`, an `(stream_ordering, event_id)`
+    /// position, newest first. `read` tells whether a receipt covers the event.
+    pub async fn get_notifications(
+        &self,
+        user_id: &str,
+        before: Option<(i64, &str)>,
+        limit: i64,
+        only_highlight: bool,
+    ) -> Result<Vec<NotificationRecord>, sqlx::Error> {
+        let (before_ordering, before_event_id) = before.unzip();
+        sqlx::query_as(
+            r#"
+            SELECT a.event_id, a.room_id, a.stream_ordering, a.actions, a.highlight,
+                   a.stream_ordering <= COALESCE((
+                       SELECT MAX(r.stream_ordering) FROM event_push_receipts r
+                       WHERE r.user_id = a.user_id AND r.room_id = a.room_id AND r.thread_id IN ('', a.thread_id)
+                   ), 0) AS read
+            FROM event_push_actions a
+            WHERE a.user_id = $1 AND a.notif
+              AND (NOT $2 OR a.highlight)
+              AND ($3::BIGINT IS NULL OR (a.stream_ordering, a.event_id) < ($3, $4))
+            ORDER BY a.stream_ordering DESC, a.event_id DESC
+            LIMIT $5
+            "#,
+        )
+        .bind(user_id)
+        .bind(only_highlight)
+        .bind(before_ordering)
+        .bind(before_event_id)
+        .bind(limit)
+        .fetch_all(&*self.pool)
+        .await
+    }
+
+    /// Drops the push actions of events received before `before_ts`, read or not.
+    pub async fn delete_push_actions_before(&self, before_ts: i64) -> Result<u64, sqlx::Error> {
+        let deleted = sqlx::query("DELETE FROM event_push_actions WHERE stream_ordering < $1")
+            .bind(before_ts)
+            .execute(&*self.pool)
+            .await?
+            .rows_affected();
+        Ok(deleted)
+    }
+}
@@ -0,0 +1,371 @@
+use serde_json::Value;
+use sqlx::{PgConnection, Pool, Postgres};
+use std::sync::Arc;
+use tokio::sync::Notify;
+
+/// The server part of a user ID column, for fanning out to every server in a room.
+const SERVER_OF_USER_ID: &str = "substring(user_id from position(':' in user_id) + 1)";
+
+/// Durable per-destination queue of outgoing PDUs and EDUs.
+#[derive(Clone)]
+pub struct FederationQueueStorage {
+    pub pool: Arc<Pool<Postgres>>,
+    server_name: String,
+    wakeup: Arc<Notify>,
+}
+
+/// Delivery state of a destination.
+#[derive(Debug, Clone, sqlx::FromRow)]
+pub struct FederationDestinationState {
+    pub destination: String,
+    pub failure_count: i32,
+    pub retry_last_ts: i64,
+    pub retry_interval_ms: i64,
+    pub last_successful_stream_id: i64,
+    pub last_success_ts: Option<i64>,
+}
+
+impl FederationDestinationState {
+    /// When the destination may next be tried.
+    pub fn retry_at(&self) -> i64 {
+        self.retry_last_ts + self.retry_interval_ms
+    }
+}
+
+/// The queued rows making up one outgoing transaction.
+#[derive(Debug, Clone, Default)]
+pub struct QueuedTransaction {
+    pub stream_ids: Vec<i64>,
+    pub pdus: Vec<Value>,
+    pub edus: Vec<Value>,
+}
+
+impl QueuedTransaction {
+    pub fn is_empty(&self) -> bool {
+        self.stream_ids.is_empty()
+    }
+
+    /// Derived from the queued rows, so a retried transaction keeps its ID and the
+    /// destination can deduplicate it.
+    pub fn transaction_id(&self) -> String {
+        let first = self.stream_ids.iter().min().copied().unwrap_or_default();
+        format!("{}-{}-{}", first, self.last_stream_id(), self.stream_ids.len())
+    }
+
+    pub fn last_stream_id(&self) -> i64 {
+        self.stream_ids.iter().max().copied().unwrap_or_default()
+    }
+}
+
+impl FederationQueueStorage {
+    pub fn new(pool: &Arc<Pool<Postgres>>, server_name: &str) -> Self {
+        Self {
+            pool: pool.clone(),
+            server_name: server_name.to_string(),
+            wakeup: Arc::new(Notify::new()),
+        }
+    }
+
+    pub fn server_name(&self) -> &str {
+        &self.server_name
+    }
+
+    /// Signalled whenever something is queued, so a sender in this process can start at once.
+    pub fn wakeup(&self) -> Arc<Notify> {
+        self.wakeup.clone()
+    }
+
+    /// Queues `pdu` for every other server with a user joined to `room_id`, and for the server
+    /// of `target_user_id` (the subject of a membership event) even if it is not in the room.
+    /// While a room's member list is incomplete, the servers named at the join are included.
+    pub async fn enqueue_room_pdu_on(
+        &self,
+        conn: &mut PgConnection,
+        room_id: &str,
+        pdu: &Value,
+        target_user_id: Option<&str>,
+    ) -> Result<u64, sqlx::Error> {
+        let query = format!(
+            r#"
+            INSERT INTO federation_outbound_queue (destination, pdu, created_ts)
+            SELECT server, $2, $3 FROM (
+                SELECT {server} AS server FROM room_memberships
+                WHERE room_id = $1 AND membership = 'join'
+                UNION
+                SELECT UNNEST(servers_in_room) FROM partial_state_rooms WHERE room_id = $1
+                UNION
+                SELECT {server} FROM (SELECT $4::text AS user_id) target WHERE user_id IS NOT NULL
+            ) servers
+            WHERE server <> $5 AND server <> ''
+            "#,
+            server = SERVER_OF_USER_ID,
+        );
+        let queued = sqlx::query(&query)
+            .bind(room_id)
+            .bind(pdu)
+            .bind(chrono::Utc::now().timestamp_millis())
+            .bind(target_user_id)
+            .bind(&self.server_name)
+            .execute(&mut *conn)
+            .await?
+            .rows_affected();
+        self.wake(queued);
+        Ok(queued)
+    }
+
+    pub async fn enqueue_edu(&self, destinations: &[String], edu: &Value) -> Result<u64, sqlx::Error> {
+        let destinations: Vec<&str> = destinations
+            .iter()
+            .map(String::as_str)
+            .filter(|destination| *destination != self.server_name)
+            .collect();
+        if destinations.is_empty() {
+            return Ok(0);
+        }
+
+        let queued = sqlx::query(
+            r#"
+            INSERT INTO federation_outbound_queue (destination, edu, created_ts)
+            SELECT DISTINCT destination, $2::jsonb, $3::bigint FROM UNNEST($1::text[]) AS destination
+            "#,
+        )
+        .bind(&destinations)
+        .bind(edu)
+        .bind(chrono::Utc::now().timestamp_millis())
+        .execute(&*self.pool)
+        .await?
+        .rows_affected();
+        self.wake(queued);
+        Ok(queued)
+    }
+
+    /// Queues `edu` for every other server with a user joined to `room_id`.
+    pub async fn enqueue_room_edu(&self, room_id: &str, edu: &Value) -> Result<u64, sqlx::Error> {
+        let query = format!(
+            r#"
+            INSERT INTO federation_outbound_queue (destination, edu, created_ts)
+            SELECT DISTINCT {server}, $2::jsonb, $3::bigint FROM room_memberships
+            WHERE room_id = $1 AND membership = 'join' AND {server} <> $4
+            "#,
+            server = SERVER_OF_USER_ID,
+        );
+        let queued = sqlx::query(&query)
+            .bind(room_id)
+            .bind(edu)
+            .bind(chrono::Utc::now().timestamp_millis())
+            .bind(&self.server_name)
+            .execute(&*self.pool)
+            .await?
+            .rows_affected();
+        self.wake(queued);
+        Ok(queued)
+    }
+
+    /// Queues `edu` for every other server sharing a room with `user_id`.
+    pub async fn enqueue_user_edu(&self, user_id: &str, edu: &Value) -> Result<u64, sqlx::Error> {
+        let query = format!(
+            r#"
+            INSERT INTO federation_outbound_queue (destination, edu, created_ts)
+            SELECT DISTINCT {server}, $2::jsonb, $3::bigint FROM room_memberships
+            WHERE membership = 'join' AND {server} <> $4 AND room_id IN (
+                SELECT room_id FROM room_memberships WHERE user_id = $1 AND membership = 'join'
+            )
+            "#,
+            server = SERVER_OF_USER_ID,
+        );
+        let queued = sqlx::query(&query)
+            .bind(user_id)
+            .bind(edu)
+            .bind(chrono::Utc::now().timestamp_millis())
+            .bind(&self.server_name)
+            .execute(&*self.pool)
+            .await?
+            .rows_affected();
+        self.wake(queued);
+        Ok(queued)
+    }
+
+    /// Destinations with something queued that are not backing off at `now`.
+    pub async fn get_ready_destinations(&self, now: i64, limit: i64) -> Result<Vec<String>, sqlx::Error> {
+        sqlx::query_scalar(
+            r#"
+            SELECT q.destination FROM (SELECT DISTINCT destination FROM federation_outbound_queue) q
+            LEFT JOIN federation_destinations d ON d.destination = q.destination
+            WHERE d.destination IS NULL OR d.retry_last_ts + d.retry_interval_ms <= $1
+            ORDER BY COALESCE(d.last_success_ts, 0)
+            LIMIT $2
+            "#,
+        )
+        .bind(now)
+        .bind(limit)
+        .fetch_all(&*self.pool)
+        .await
+    }
+
+    /// The oldest queued PDUs and EDUs for `destination`, in stream order.
+    pub async fn get_next_transaction(
+        &self,
+        destination: &str,
+        max_pdus: i64,
+        max_edus: i64,
+    ) -> Result<QueuedTransaction, sqlx::Error> {
+        let pdus: Vec<(i64, Value)> = sqlx::query_as(
+            r#"
+            SELECT id, pdu FROM federation_outbound_queue
+            WHERE destination = $1 AND pdu IS NOT NULL
+            ORDER BY id LIMIT $2
+            "#,
+        )
+        .bind(destination)
+        .bind(max_pdus)
+        .fetch_all(&*self.pool)
+        .await?;
+        let edus: Vec<(i64, Value)> = sqlx::query_as(
+            r#"
+            SELECT id, edu FROM federation_outbound_queue
+            WHERE destination = $1 AND edu IS NOT NULL
+            ORDER BY id LIMIT $2
+            "#,
+        )
+        .bind(destination)
+        .bind(max_edus)
+        .fetch_all(&*self.pool)
+        .await?;
+
+        let mut transaction = QueuedTransaction::default();
+        for (id, pdu) in pdus {
+            transaction.stream_ids.push(id);
+            transaction.pdus.push(pdu);
+        }
+        for (id, edu) in edus {
+            transaction.stream_ids.push(id);
+            transaction.edus.push(edu);
+        }
+        Ok(transaction)
+    }
+
+    /// Drops queued EDUs of `edu_type` queued before `before_ts`, which are no longer worth
+    /// delivering to a destination catching up.
+    pub async fn delete_stale_edus(&self, destination: &str, edu_type: &str, before_ts: i64) -> Result<u64, sqlx::Error> {
+        Ok(sqlx::query(
+            r#"
+            DELETE FROM federation_outbound_queue
+            WHERE destination = $1 AND edu->>'edu_type' = $2 AND created_ts < $3
+            "#,
+        )
+        .bind(destination)
+        .bind(edu_type)
+        .bind(before_ts)
+        .execute(&*self.pool)
+        .await?
+        .rows_affected())
+    }
+
+    /// Removes a delivered transaction from the queue and clears the destination's backoff.
+    pub async fn complete_transaction(&self, destination: &str, transaction: &QueuedTransaction) -> Result<(), sqlx::Error> {
+        let mut tx = self.pool.begin().await?;
+
+        sqlx::query("DELETE FROM federation_outbound_queue WHERE destination = $1 AND id = ANY($2)")
+            .bind(destination)
+            .bind(&transaction.stream_ids)
+            .execute(&mut *tx)
+            .await?;
+        sqlx::query(
+            r#"
+            INSERT INTO federation_destinations
+                (destination, failure_count, retry_last_ts, retry_interval_ms, last_successful_stream_id, last_success_ts)
+            VALUES ($1, 0, 0, 0, $2, $3)
+            ON CONFLICT (destination) DO UPDATE SET
+                failure_count = 0,
+                retry_last_ts = 0,
+                retry_interval_ms = 0,
+                last_successful_stream_id = GREATEST(federation_destinations.last_successful_stream_id, EXCLUDED.last_successful_stream_id),
+                last_success_ts = EXCLUDED.last_success_ts
+            "#,
+        )
+        .bind(destination)
+        .bind(transaction.last_stream_id())
+        .bind(chrono::Utc::now().timestamp_millis())
+        .execute(&mut *tx)
+        .await?;
+
+        tx.commit().await
+    }
+
+    /// Records a failed attempt; the destination is not tried again for `retry_interval_ms`.
+    pub async fn record_failure(&self, destination: &str, retry_interval_ms: i64) -> Result<(), sqlx::Error> {
+        sqlx::query(
+            r#"
+            INSERT INTO federation_destinations (destination, failure_count, retry_last_ts, retry_interval_ms)
+            VALUES ($1, 1, $2, $3)
+            ON CONFLICT (destination) DO UPDATE SET
+                failure_count = federation_destinations.failure_count + 1,
+                retry_last_ts = EXCLUDED.retry_last_ts,
+                retry_interval_ms = EXCLUDED.retry_interval_ms
+            "#,
+        )
+        .bind(destination)
+        .bind(chrono::Utc::now().timestamp_millis())
+        .bind(retry_interval_ms)
+        .execute(&*self.pool)
+        .await?;
+        Ok(())
+    }
+
+    /// Clears a destination's backoff. Returns whether it was backing off.
+    pub async fn reset_backoff(&self, destination: &str) -> Result<bool, sqlx::Error> {
+        let reset = sqlx::query(
+            r#"
+            UPDATE federation_destinations SET failure_count = 0, retry_last_ts = 0, retry_interval_ms = 0
+            WHERE destination = $1 AND retry_interval_ms > 0
+            "#,
+        )
+        .bind(destination)
+        .execute(&*self.pool)
+        .await?
+        .rows_affected()
+            > 0;
+        if reset {
+            self.wakeup.notify_one();
+        }
+        Ok(reset)
+    }
+
+    pub async fn get_destination(&self, destination: &str) -> Result<Option<FederationDestinationState>, sqlx::Error> {
+        sqlx::query_as(
+            r#"
+            SELECT destination, failure_count, retry_last_ts, retry_interval_ms,
+                   last_successful_stream_id, last_success_ts
+            FROM federation_destinations WHERE destination = $1
+            "#,
+        )
+        .bind(destination)
+        .fetch_optional(&*self.pool)
+        .await
+    }
+
+    pub async fn get_pending_count(&self, destination: &str) -> Result<i64, sqlx::Error> {
+        sqlx::query_scalar("SELECT COUNT(*) FROM federation_outbound_queue WHERE destination = $1")
+            .bind(destination)
+            .fetch_one(&*self.pool)
+            .await
+    }
+
+    pub async fn clear_destination(&self, destination: &str) -> Result<(), sqlx::Error> {
+        sqlx::query("DELETE FROM federation_outbound_queue WHERE destination = $1")
+            .bind(destination)
+            .execute(&*self.pool)
+            .await?;
+        sqlx::query("DELETE FROM federation_destinations WHERE destination = $1")
+            .bind(destination)
+            .execute(&*self.pool)
+            .await?;
+        Ok(())
+    }
+
+    fn wake(&self, queued: u64) {
+        if queued > 0 {
+            self.wakeup.notify_one();
+        }
+    }
+}
@@ -1,4 +1,3 @@
-use crate::common::crypto::generate_event_id;
 use serde::{Deserialize, Serialize};
 use sqlx::{Pool, Postgres};
 use std::sync::Arc;
@@ -28,7 +27,7 @@ pub struct RoomMember {
 #[derive(Clone)]
 pub struct RoomMemberStorage {
     pub pool: Arc<Pool<Postgres>>,
-    /// 服务器名称，用于生成事件 ID
+    /// 服务器名称，用于识别本服务器的用户
     pub server_name: String,
 }
 
@@ -40,17 +39,20 @@ impl RoomMemberStorage {
         }
     }
 
+    /// Records the membership set by the member event `event_id`, which the caller has
+    /// persisted (or is persisting in the same transaction).
+    #[allow(clippy::too_many_arguments)]
     pub async fn add_member(
         &self,
         room_id: &str,
         user_id: &str,
         membership: &str,
+        event_id: &str,
         display_name: Option<&str>,
         join_reason: Option<&str>,
         tx: Option<&mut sqlx::Transaction<'_, sqlx::Postgres>>,
     ) -> Result<RoomMember, sqlx::Error> {
         let now = chrono::Utc::now().timestamp_millis();
-        let event_id = format!("${}", generate_event_id(&self.server_name));
         let sender = user_id;
 
         let query = r#"
@@ -59,6 +61,7 @@ impl RoomMemberStorage {
             ON CONFLICT (room_id, user_id) DO UPDATE SET
                 display_name = EXCLUDED.display_name,
                 membership = EXCLUDED.membership,
+                event_id = EXCLUDED.event_id,
                 join_reason = EXCLUDED.join_reason,
                 updated_ts = EXCLUDED.updated_ts
             RETURNING room_id, user_id, sender, membership, event_id, event_type, display_name, avatar_url, is_banned, invite_token, updated_ts, joined_ts, left_ts, reason, banned_by, ban_reason, ban_ts, join_reason
@@ -207,18 +210,22 @@ impl RoomMemberStorage {
         user_id: &str,
         banned_by: &str,
     ) -> Result<(), sqlx::Error> {
+        let now = chrono::Utc::now().timestamp_millis();
         sqlx::query(
             r#"
-            INSERT INTO room_memberships (room_id, user_id, membership, banned_by)
-            VALUES ($1, $2, 'ban', $3)
+            INSERT INTO room_memberships (room_id, user_id, membership, banned_by, ban_ts, updated_ts)
+            VALUES ($1, $2, 'ban', $3, $4, $4)
             ON CONFLICT (room_id, user_id) DO UPDATE SET
                 membership = 'ban',
-                banned_by = EXCLUDED.banned_by
+                banned_by = EXCLUDED.banned_by,
+                ban_ts = EXCLUDED.ban_ts,
+                updated_ts = EXCLUDED.updated_ts
             "#,
         )
         .bind(room_id)
         .bind(user_id)
         .bind(banned_by)
+        .bind(now)
         .execute(&*self.pool)
         .await?;
         Ok(())
@@ -250,6 +257,121 @@ impl RoomMemberStorage {
         Ok(rows)
     }
 
+    pub async fn get_left_rooms(
+        &self,
+        user_id: &str,
+        since_ts: i64,
+    ) -> Result<Vec<(String, i64)>, sqlx::Error> {
+        let rows: Vec<(String, i64)> = sqlx::query_as(
+            r#"
+            SELECT room_id,
+                   GREATEST(COALESCE(updated_ts, 0), COALESCE(left_ts, 0), COALESCE(ban_ts, 0)) AS left_at
+            FROM room_memberships
+            WHERE user_id = $1 AND membership IN ('leave', 'ban')
+              AND GREATEST(COALESCE(updated_ts, 0), COALESCE(left_ts, 0), COALESCE(ban_ts, 0)) > $2
+            "#,
+        )
+        .bind(user_id)
+        .bind(since_ts)
+        .fetch_all(&*self.pool)
+        .await?;
+        Ok(rows)
+    }
+
+    pub async fn get_invited_rooms(&self, user_id: &str) -> Result<Vec<String>, sqlx::Error> {
+        sqlx::query_scalar::<_, String>(
+            r#"
+            SELECT room_id FROM room_memberships WHERE user_id = $1 AND membership = 'invite'
+            "#,
+        )
+        .bind(user_id)
+        .fetch_all(&*self.pool)
+        .await
+    }
+
+    /// Rooms the user is invited to whose invite arrived after `since_ts`.
+    pub async fn get_invited_rooms_since(&self, user_id: &str, since_ts: i64) -> Result<Vec<String>, sqlx::Error> {
+        sqlx::query_scalar::<_, String>(
+            r#"
+            SELECT room_id FROM room_memberships
+            WHERE user_id = $1 AND membership = 'invite' AND COALESCE(updated_ts, 0) > $2
+            "#,
+        )
+        .bind(user_id)
+        .bind(since_ts)
+        .fetch_all(&*self.pool)
+        .await
+    }
+
+    /// Rooms the user is knocking on whose knock was sent after `since_ts`.
+    pub async fn get_knocked_rooms(&self, user_id: &str, since_ts: i64) -> Result<Vec<String>, sqlx::Error> {
+        sqlx::query_scalar::<_, String>(
+            r#"
+            SELECT room_id FROM room_memberships
+            WHERE user_id = $1 AND membership = 'knock' AND COALESCE(updated_ts, 0) > $2
+            "#,
+        )
+        .bind(user_id)
+        .bind(since_ts)
+        .fetch_all(&*self.pool)
+        .await
+    }
+
+    /// Returns the `(joined, invited)` member counts of a room.
+    pub async fn get_member_counts(&self, room_id: &str) -> Result<(i64, i64), sqlx::Error> {
+        sqlx::query_as::<_, (i64, i64)>(
+            r#"
+            SELECT COUNT(*) FILTER (WHERE membership = 'join'),
+                   COUNT(*) FILTER (WHERE membership = 'invite')
+            FROM room_memberships WHERE room_id = $1
+            "#,
+        )
+        .bind(room_id)
+        .fetch_one(&*self.pool)
+        .await
+    }
+
+    pub async fn get_users_sharing_rooms(&self, user_id: &str) -> Result<Vec<String>, sqlx::Error> {
+        sqlx::query_scalar::<_, String>(
+            r#"
+            SELECT DISTINCT other.user_id
+            FROM room_memberships mine
+            JOIN room_memberships other ON other.room_id = mine.room_id
+            WHERE mine.user_id = $1 AND mine.membership = 'join' AND other.membership = 'join'
+            "#,
+        )
+        .bind(user_id)
+        .fetch_all(&*self.pool)
+        .await
+    }
+
+    /// Users whose membership in a room shared with `user_id` changed after `since_ts`,
+    /// including everyone in rooms `user_id` has since left. The flag is true for joins.
+    pub async fn get_membership_changes_since(
+        &self,
+        user_id: &str,
+        since_ts: i64,
+    ) -> Result<Vec<(String, bool)>, sqlx::Error> {
+        sqlx::query_as::<_, (String, bool)>(
+            r#"
+            SELECT DISTINCT other.user_id, other.membership = 'join' AND mine.membership = 'join'
+            FROM room_memberships mine
+            JOIN room_memberships other ON other.room_id = mine.room_id
+            WHERE mine.user_id = $1 AND other.user_id <> $1
+              AND (
+                (mine.membership = 'join'
+                    AND GREATEST(COALESCE(other.updated_ts, 0), other.joined_ts, COALESCE(other.left_ts, 0)) > $2)
+                OR (mine.membership IN ('leave', 'ban')
+                    AND GREATEST(COALESCE(mine.updated_ts, 0), COALESCE(mine.left_ts, 0), COALESCE(mine.ban_ts, 0)) > $2)
+              )
+            "#,
+        )
+        .bind(user_id)
+        .bind(since_ts)
+        .fetch_all(&*self.pool)
+        .await
+    }
+
     pub async fn is_member(&self, room_id: &str, user_id: &str) -> Result<bool, sqlx::Error> {
         let result = sqlx::query_scalar::<_, i32>(
             r#"
@@ -263,6 +385,22 @@ impl RoomMemberStorage {
         Ok(result.is_some())
     }
 
+    /// Whether any user of `server_name` is joined to or invited into the room.
+    pub async fn has_server_member(&self, room_id: &str, server_name: &str) -> Result<bool, sqlx::Error> {
+        let result = sqlx::query_scalar::<_, i32>(
+            r#"
+            SELECT 1 AS "exists" FROM room_memberships
+            WHERE room_id = $1 AND membership IN ('join', 'invite') AND substring(user_id from position(':' in user_id) + 1) = $2
+            LIMIT 1
+            "#,
+        )
+        .bind(room_id)
+        .bind(server_name)
+        .fetch_optional(&*self.pool)
+        .await?;
+        Ok(result.is_some())
+    }
+
     pub async fn get_room_member(
         &self,
         room_id: &str,
@@ -354,6 +492,88 @@ impl RoomMemberStorage {
         Ok(memberships)
     }
 
+    /// Records the membership set by an `m.room.member` event, local or remote.
+    pub async fn apply_member_event(
+        &self,
+        room_id: &str,
+        event_id: &str,
+        sender: &str,
+        state_key: &str,
+        content: &serde_json::Value,
+    ) -> Result<(), sqlx::Error> {
+        let now = chrono::Utc::now().timestamp_millis();
+        let membership = content
+            .get("membership")
+            .and_then(|m| m.as_str())
+            .unwrap_or("leave");
+        sqlx::query(
+            r#"
+            INSERT INTO room_memberships (room_id, user_id, sender, membership, event_id, event_type, display_name,
+                                          avatar_url, reason, is_banned, updated_ts, joined_ts, left_ts)
+            VALUES ($1, $2, $3, $4, $5, 'm.room.member', $6, $7, $8, $4 = 'ban', $9, $9,
+                    CASE WHEN $4 IN ('leave', 'ban') THEN $9 END)
+            ON CONFLICT (room_id, user_id) DO UPDATE SET
+                sender = EXCLUDED.sender,
+                membership = EXCLUDED.membership,
+                event_id = EXCLUDED.event_id,
+                display_name = EXCLUDED.display_name,
+                avatar_url = EXCLUDED.avatar_url,
+                reason = EXCLUDED.reason,
+                is_banned = EXCLUDED.is_banned,
+                updated_ts = EXCLUDED.updated_ts,
+                left_ts = EXCLUDED.left_ts
+            "#,
+        )
+        .bind(room_id)
+        .bind(state_key)
+        .bind(sender)
+        .bind(membership)
+        .bind(event_id)
+        .bind(content.get("displayname").and_then(|v| v.as_str()))
+        .bind(content.get("avatar_url").and_then(|v| v.as_str()))
+        .bind(content.get("reason").and_then(|v| v.as_str()))
+        .bind(now)
+        .execute(&*self.pool)
+        .await?;
+        Ok(())
+    }
+
+    /// Whether any user of this server is joined to the room, that is whether we hold its
+    /// current state or have to go through a resident server.
+    pub async fn has_local_joined_members(&self, room_id: &str) -> Result<bool, sqlx::Error> {
+        let result = sqlx::query_scalar::<_, i32>(
+            r#"
+            SELECT 1 FROM room_memberships
+            WHERE room_id = $1 AND membership = 'join' AND user_id LIKE '%:' || $2
+            LIMIT 1
+            "#,
+        )
+        .bind(room_id)
+        .bind(&self.server_name)
+        .fetch_optional(&*self.pool)
+        .await?;
+        Ok(result.is_some())
+    }
+
+    /// Other servers with users joined to the room, most joined users first.
+    pub async fn get_joined_servers(&self, room_id: &str) -> Result<Vec<String>, sqlx::Error> {
+        sqlx::query_scalar(
+            r#"
+            SELECT server FROM (
+                SELECT substring(user_id from position(':' in user_id) + 1) AS server
+                FROM room_memberships WHERE room_id = $1 AND membership = 'join'
+            ) joined
+            WHERE server <> $2
+            GROUP BY server
+            ORDER BY COUNT(*) DESC, server
+            "#,
+        )
+        .bind(room_id)
+        .bind(&self.server_name)
+        .fetch_all(&*self.pool)
+        .await
+    }
+
     pub async fn get_members(&self, room_id: &str) -> Result<Vec<String>, sqlx::Error> {
         let members: Vec<(String,)> = sqlx::query_as(
             r#"
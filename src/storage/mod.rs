@@ -2,25 +2,39 @@ use sqlx::{Pool, Postgres};
 use std::sync::Arc;
 use tokio::sync::RwLock;
 
+pub mod appservice;
 pub mod device;
 pub mod email_verification;
 pub mod event;
+pub mod event_push_actions;
 pub mod external_service;
+pub mod federation_queue;
 pub mod friend_room;
 pub mod maintenance;
 pub mod media_storage;
 pub mod membership;
 pub mod monitoring;
 pub mod performance;
+pub mod push_rules;
+pub mod pusher;
+pub mod registration_token;
+pub mod relations;
 pub mod room;
 pub mod schema_validator;
+pub mod search;
+pub mod sliding_sync;
+pub mod state_group;
 pub mod token;
+pub mod ui_auth;
 pub mod user;
 pub mod voice;
 
+pub use self::appservice::*;
 pub use self::device::*;
 pub use self::event::*;
+pub use self::event_push_actions::*;
 pub use self::external_service::*;
+pub use self::federation_queue::*;
 pub use self::friend_room::*;
 pub use self::maintenance::*;
 pub use self::media_storage::*;
@@ -31,9 +45,17 @@ pub use self::monitoring::{
     PerformanceMetrics, VacuumStats,
 };
 pub use self::performance::{PerformanceMonitor, PoolStatistics, QueryMetrics, time_query};
+pub use self::push_rules::*;
+pub use self::pusher::*;
+pub use self::registration_token::*;
+pub use self::relations::*;
 pub use self::room::*;
 pub use self::schema_validator::*;
+pub use self::search::*;
+pub use self::sliding_sync::*;
+pub use self::state_group::*;
 pub use self::token::*;
+pub use self::ui_auth::*;
 pub use self::user::*;
 pub use self::voice::*;
 
@@ -277,6 +299,7 @@ mod tests {
             status: None,
             reference_image: None,
             origin: "example.com".to_string(),
+            stream_ordering: 1,
         };
         assert_eq!(event.event_id, "$test_event");
         assert_eq!(event.room_id, "!test:example.com");
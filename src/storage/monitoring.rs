@@ -364,7 +364,7 @@ impl DatabaseMonitor {
                         .0
                     } else {
                         sqlx::query_as::<_, (i64,)>(
-                            "SELECT COUNT(*) FROM events WHERE user_id IS NOT NULL AND user_id NOT IN (SELECT user_id FROM users)",
+                            "SELECT COUNT(*) FROM events WHERE user_id IS NOT NULL AND COALESCE(origin, 'self') = 'self' AND user_id NOT IN (SELECT user_id FROM users)",
                         )
                         .fetch_one(&self.pool)
                         .await?
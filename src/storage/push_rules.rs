@@ -0,0 +1,233 @@
+use serde_json::Value;
+use sqlx::{PgConnection, Pool, Postgres};
+use std::sync::Arc;
+
+/// A rule a user has added, or the actions they set on a server-default rule.
+#[derive(Debug, Clone, sqlx::FromRow)]
+pub struct StoredPushRule {
+    pub kind: String,
+    pub rule_id: String,
+    pub priority: i64,
+    pub conditions: Value,
+    pub pattern: Option<String>,
+    pub actions: Value,
+}
+
+/// Where a new rule goes among the user's rules of its kind.
+#[derive(Debug, Clone, Copy)]
+pub enum PushRulePosition<'a> {
+    /// Ahead of every other user-defined rule.
+    First,
+    Before(&'a str),
+    After(&'a str),
+}
+
+#[derive(Clone)]
+pub struct PushRuleStorage {
+    pub pool: Arc<Pool<Postgres>>,
+}
+
+impl PushRuleStorage {
+    pub fn new(pool: &Arc<Pool<Postgres>>) -> Self {
+        Self { pool: pool.clone() }
+    }
+
+    /// Every stored rule of the user, highest priority first within each kind.
+    pub async fn get_rules(&self, user_id: &str) -> Result<Vec<StoredPushRule>, sqlx::Error> {
+        sqlx::query_as(
+            r#"
+            SELECT kind, rule_id, priority, conditions, pattern, actions
+            FROM push_rules WHERE user_id = $1
+            ORDER BY kind, priority DESC, rule_id
+            "#,
+        )
+        .bind(user_id)
+        .fetch_all(&*self.pool)
+        .await
+    }
+
+    /// The rules the user has switched on or off, as `(kind, rule_id, enabled)`.
+    pub async fn get_enabled_states(&self, user_id: &str) -> Result<Vec<(String, String, bool)>, sqlx::Error> {
+        sqlx::query_as("SELECT kind, rule_id, enabled FROM push_rules_enable WHERE user_id = $1")
+            .bind(user_id)
+            .fetch_all(&*self.pool)
+            .await
+    }
+
+    /// Adds or replaces a user-defined rule. A replaced rule keeps its place unless a position
+    /// relative to another rule is given. Returns `false` if that other rule does not exist.
+    pub async fn put_rule(
+        &self,
+        user_id: &str,
+        rule: &StoredPushRule,
+        position: PushRulePosition<'_>,
+    ) -> Result<bool, sqlx::Error> {
+        let mut tx = self.pool.begin().await?;
+
+        let existing: Option<i64> =
+            sqlx::query_scalar("SELECT priority FROM push_rules WHERE user_id = $1 AND kind = $2 AND rule_id = $3")
+                .bind(user_id)
+                .bind(&rule.kind)
+                .bind(&rule.rule_id)
+                .fetch_optional(&mut *tx)
+                .await?;
+
+        let priority = match (position, existing) {
+            (PushRulePosition::First, Some(priority)) => priority,
+            (PushRulePosition::First, None) => {
+                let highest: Option<i64> = sqlx::query_scalar(
+                    "SELECT MAX(priority) FROM push_rules WHERE user_id = $1 AND kind = $2 AND rule_id NOT LIKE '.%'",
+                )
+                .bind(user_id)
+                .bind(&rule.kind)
+                .fetch_one(&mut *tx)
+                .await?;
+                highest.map_or(0, |p| p + 1)
+            }
+            (PushRulePosition::Before(relative) | PushRulePosition::After(relative), _) => {
+                sqlx::query("DELETE FROM push_rules WHERE user_id = $1 AND kind = $2 AND rule_id = $3")
+                    .bind(user_id)
+                    .bind(&rule.kind)
+                    .bind(&rule.rule_id)
+                    .execute(&mut *tx)
+                    .await?;
+                let relative_priority: Option<i64> = sqlx::query_scalar(
+                    "SELECT priority FROM push_rules WHERE user_id = $1 AND kind = $2 AND rule_id = $3",
+                )
+                .bind(user_id)
+                .bind(&rule.kind)
+                .bind(relative)
+                .fetch_optional(&mut *tx)
+                .await?;
+                let Some(relative_priority) = relative_priority else {
+                    return Ok(false);
+                };
+
+                // Make room by moving everything from the new rule's place upwards
+                let (priority, shift_from) = match position {
+                    PushRulePosition::Before(_) => (relative_priority + 1, relative_priority + 1),
+                    _ => (relative_priority, relative_priority),
+                };
+                sqlx::query(
+                    "UPDATE push_rules SET priority = priority + 1 WHERE user_id = $1 AND kind = $2 AND priority >= $3 AND rule_id NOT LIKE '.%'",
+                )
+                .bind(user_id)
+                .bind(&rule.kind)
+                .bind(shift_from)
+                .execute(&mut *tx)
+                .await?;
+                priority
+            }
+        };
+
+        sqlx::query(
+            r#"
+            INSERT INTO push_rules (user_id, kind, rule_id, priority, conditions, pattern, actions, created_ts)
+            VALUES ($1, $2, $3, $4, $5, $6, $7, $8)
+            ON CONFLICT (user_id, kind, rule_id) DO UPDATE SET
+                priority = EXCLUDED.priority,
+                conditions = EXCLUDED.conditions,
+                pattern = EXCLUDED.pattern,
+                actions = EXCLUDED.actions
+            "#,
+        )
+        .bind(user_id)
+        .bind(&rule.kind)
+        .bind(&rule.rule_id)
+        .bind(priority)
+        .bind(&rule.conditions)
+        .bind(&rule.pattern)
+        .bind(&rule.actions)
+        .bind(chrono::Utc::now().timestamp_millis())
+        .execute(&mut *tx)
+        .await?;
+
+        Self::touch(&mut tx, user_id).await?;
+        tx.commit().await?;
+        Ok(true)
+    }
+
+    /// Removes a user-defined rule. Returns whether it existed.
+    pub async fn delete_rule(&self, user_id: &str, kind: &str, rule_id: &str) -> Result<bool, sqlx::Error> {
+        let mut tx = self.pool.begin().await?;
+        let deleted = sqlx::query("DELETE FROM push_rules WHERE user_id = $1 AND kind = $2 AND rule_id = $3")
+            .bind(user_id)
+            .bind(kind)
+            .bind(rule_id)
+            .execute(&mut *tx)
+            .await?
+            .rows_affected()
+            > 0;
+        sqlx::query("DELETE FROM push_rules_enable WHERE user_id = $1 AND kind = $2 AND rule_id = $3")
+            .bind(user_id)
+            .bind(kind)
+            .bind(rule_id)
+            .execute(&mut *tx)
+            .await?;
+        Self::touch(&mut tx, user_id).await?;
+        tx.commit().await?;
+        Ok(deleted)
+    }
+
+    pub async fn set_enabled(&self, user_id: &str, kind: &str, rule_id: &str, enabled: bool) -> Result<(), sqlx::Error> {
+        let mut tx = self.pool.begin().await?;
+        sqlx::query(
+            r#"
+            INSERT INTO push_rules_enable (user_id, kind, rule_id, enabled)
+            VALUES ($1, $2, $3, $4)
+            ON CONFLICT (user_id, kind, rule_id) DO UPDATE SET enabled = EXCLUDED.enabled
+            "#,
+        )
+        .bind(user_id)
+        .bind(kind)
+        .bind(rule_id)
+        .bind(enabled)
+        .execute(&mut *tx)
+        .await?;
+        Self::touch(&mut tx, user_id).await?;
+        tx.commit().await
+    }
+
+    /// Sets the actions of a rule, storing them as an override when it is a server default.
+    pub async fn set_actions(&self, user_id: &str, kind: &str, rule_id: &str, actions: &Value) -> Result<(), sqlx::Error> {
+        let mut tx = self.pool.begin().await?;
+        sqlx::query(
+            r#"
+            INSERT INTO push_rules (user_id, kind, rule_id, actions, created_ts)
+            VALUES ($1, $2, $3, $4, $5)
+            ON CONFLICT (user_id, kind, rule_id) DO UPDATE SET actions = EXCLUDED.actions
+            "#,
+        )
+        .bind(user_id)
+        .bind(kind)
+        .bind(rule_id)
+        .bind(actions)
+        .bind(chrono::Utc::now().timestamp_millis())
+        .execute(&mut *tx)
+        .await?;
+        Self::touch(&mut tx, user_id).await?;
+        tx.commit().await
+    }
+
+    /// When the user's rules last changed, if they ever have.
+    pub async fn get_updated_ts(&self, user_id: &str) -> Result<Option<i64>, sqlx::Error> {
+        sqlx::query_scalar("SELECT updated_ts FROM push_rules_stream WHERE user_id = $1")
+            .bind(user_id)
+            .fetch_optional(&*self.pool)
+            .await
+    }
+
+    async fn touch(conn: &mut PgConnection, user_id: &str) -> Result<(), sqlx::Error> {
+        sqlx::query(
+            r#"
+            INSERT INTO push_rules_stream (user_id, updated_ts) VALUES ($1, $2)
+            ON CONFLICT (user_id) DO UPDATE SET updated_ts = EXCLUDED.updated_ts
+            "#,
+        )
+        .bind(user_id)
+        .bind(chrono::Utc::now().timestamp_millis())
+        .execute(conn)
+        .await?;
+        Ok(())
+    }
+}
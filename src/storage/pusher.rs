@@ -0,0 +1,153 @@
+use serde_json::{json, Value};
+use sqlx::{Pool, Postgres};
+use std::sync::Arc;
+
+/// A push gateway registration, as set through `/pushers/set`.
+#[derive(Debug, Clone, sqlx::FromRow)]
+pub struct Pusher {
+    pub user_id: String,
+    pub device_id: Option<String>,
+    pub kind: String,
+    pub app_id: String,
+    pub pushkey: String,
+    pub app_display_name: String,
+    pub device_display_name: String,
+    pub profile_tag: Option<String>,
+    pub lang: String,
+    pub data: Value,
+    pub created_ts: i64,
+}
+
+impl Pusher {
+    /// The pusher as listed by `GET /pushers`.
+    pub fn to_json(&self) -> Value {
+        let mut pusher = json!({
+            "pushkey": self.pushkey,
+            "kind": self.kind,
+            "app_id": self.app_id,
+            "app_display_name": self.app_display_name,
+            "device_display_name": self.device_display_name,
+            "lang": self.lang,
+            "data": self.data,
+        });
+        if let Some(profile_tag) = &self.profile_tag {
+            pusher["profile_tag"] = json!(profile_tag);
+        }
+        pusher
+    }
+}
+
+#[derive(Clone)]
+pub struct PusherStorage {
+    pub pool: Arc<Pool<Postgres>>,
+}
+
+impl PusherStorage {
+    pub fn new(pool: &Arc<Pool<Postgres>>) -> Self {
+        Self { pool: pool.clone() }
+    }
+
+    pub async fn get_pushers(&self, user_id: &str) -> Result<Vec<Pusher>, sqlx::Error> {
+        sqlx::query_as(
+            r#"
+            SELECT user_id, device_id, kind, app_id, pushkey, app_display_name, device_display_name,
+                   profile_tag, lang, data, created_ts
+            FROM pushers WHERE user_id = $1 ORDER BY created_ts
+            "#,
+        )
+        .bind(user_id)
+        .fetch_all(&*self.pool)
+        .await
+    }
+
+    /// Adds or updates the user's pusher for `(app_id, pushkey)`. Unless `append` is set, the
+    /// same pushkey is taken away from any other user it was registered for.
+    pub async fn set_pusher(&self, pusher: &Pusher, append: bool) -> Result<(), sqlx::Error> {
+        let mut tx = self.pool.begin().await?;
+        if !append {
+            sqlx::query("DELETE FROM pushers WHERE app_id = $1 AND pushkey = $2 AND user_id <> $3")
+                .bind(&pusher.app_id)
+                .bind(&pusher.pushkey)
+                .bind(&pusher.user_id)
+                .execute(&mut *tx)
+                .await?;
+        }
+        sqlx::query(
+            r#"
+            INSERT INTO pushers (user_id, device_id, kind, app_id, pushkey, app_display_name,
+                                 device_display_name, profile_tag, lang, data, created_ts)
+            VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10, $11)
+            ON CONFLICT (app_id, pushkey, user_id) DO UPDATE SET
+                device_id = EXCLUDED.device_id,
+                kind = EXCLUDED.kind,
+                app_display_name = EXCLUDED.app_display_name,
+                device_display_name = EXCLUDED.device_display_name,
+                profile_tag = EXCLUDED.profile_tag,
+                lang = EXCLUDED.lang,
+                data = EXCLUDED.data,
+                failing_since = NULL
+            "#,
+        )
+        .bind(&pusher.user_id)
+        .bind(&pusher.device_id)
+        .bind(&pusher.kind)
+        .bind(&pusher.app_id)
+        .bind(&pusher.pushkey)
+        .bind(&pusher.app_display_name)
+        .bind(&pusher.device_display_name)
+        .bind(&pusher.profile_tag)
+        .bind(&pusher.lang)
+        .bind(&pusher.data)
+        .bind(pusher.created_ts)
+        .execute(&mut *tx)
+        .await?;
+        tx.commit().await
+    }
+
+    /// Returns whether the pusher existed.
+    pub async fn delete_pusher(&self, user_id: &str, app_id: &str, pushkey: &str) -> Result<bool, sqlx::Error> {
+        let deleted = sqlx::query("DELETE FROM pushers WHERE user_id = $1 AND app_id = $2 AND pushkey = $3")
+            .bind(user_id)
+            .bind(app_id)
+            .bind(pushkey)
+            .execute(&*self.pool)
+            .await?
+            .rows_affected();
+        Ok(deleted > 0)
+    }
+
+    pub async fn delete_device_pushers(&self, user_id: &str, device_ids: &[String]) -> Result<u64, sqlx::Error> {
+        let deleted = sqlx::query("DELETE FROM pushers WHERE user_id = $1 AND device_id = ANY($2)")
+            .bind(user_id)
+            .bind(device_ids)
+            .execute(&*self.pool)
+            .await?
+            .rows_affected();
+        Ok(deleted)
+    }
+
+    /// Records the outcome of a delivery attempt: `failing_since` is kept from the first
+    /// failure in a row and cleared by the next success.
+    pub async fn record_delivery(
+        &self,
+        user_id: &str,
+        app_id: &str,
+        pushkey: &str,
+        success: bool,
+    ) -> Result<(), sqlx::Error> {
+        let now = chrono::Utc::now().timestamp_millis();
+        let query = if success {
+            "UPDATE pushers SET last_success_ts = $4, failing_since = NULL WHERE user_id = $1 AND app_id = $2 AND pushkey = $3"
+        } else {
+            "UPDATE pushers SET failing_since = COALESCE(failing_since, $4) WHERE user_id = $1 AND app_id = $2 AND pushkey = $3"
+        };
+        sqlx::query(query)
+            .bind(user_id)
+            .bind(app_id)
+            .bind(pushkey)
+            .bind(now)
+            .execute(&*self.pool)
+            .await?;
+        Ok(())
+    }
+}
@@ -0,0 +1,228 @@
+use serde_json::{json, Value};
+use sqlx::{Pool, Postgres};
+use std::sync::Arc;
+
+/// A token letting users register while open registration is closed (MSC3231).
+#[derive(Debug, Clone, sqlx::FromRow)]
+pub struct RegistrationToken {
+    pub token: String,
+    pub uses_allowed: Option<i32>,
+    pub pending: i32,
+    pub completed: i32,
+    pub expiry_time: Option<i64>,
+    pub created_ts: i64,
+    pub created_by: Option<String>,
+}
+
+impl RegistrationToken {
+    /// Whether the token can still start a registration at `now` (ms): it has not expired
+    /// and registrations underway or finished have not used it up.
+    pub fn is_valid(&self, now: i64) -> bool {
+        let expired = self.expiry_time.is_some_and(|expiry| expiry <= now);
+        let exhausted = self
+            .uses_allowed
+            .is_some_and(|allowed| self.pending + self.completed >= allowed);
+        !expired && !exhausted
+    }
+
+    /// The token as shown by the admin API.
+    pub fn to_json(&self) -> Value {
+        json!({
+            "token": self.token,
+            "uses_allowed": self.uses_allowed,
+            "pending": self.pending,
+            "completed": self.completed,
+            "expiry_time": self.expiry_time,
+        })
+    }
+}
+
+/// A change to a token's limits. `None` leaves the field alone, `Some(None)` lifts the limit.
+#[derive(Debug, Clone, Default)]
+pub struct RegistrationTokenUpdate {
+    pub uses_allowed: Option<Option<i32>>,
+    pub expiry_time: Option<Option<i64>>,
+}
+
+/// Condition on `registration_tokens` rows that can still start a registration at `$2`.
+const VALID_AT: &str = "(uses_allowed IS NULL OR pending + completed < uses_allowed) \
+                        AND (expiry_time IS NULL OR expiry_time > $2)";
+
+#[derive(Clone)]
+pub struct RegistrationTokenStorage {
+    pub pool: Arc<Pool<Postgres>>,
+}
+
+impl RegistrationTokenStorage {
+    pub fn new(pool: &Arc<Pool<Postgres>>) -> Self {
+        Self { pool: pool.clone() }
+    }
+
+    /// Returns false, leaving the existing token alone, if the token is taken.
+    pub async fn create_token(&self, token: &RegistrationToken) -> Result<bool, sqlx::Error> {
+        let inserted = sqlx::query(
+            r#"
+            INSERT INTO registration_tokens
+                (token, uses_allowed, pending, completed, expiry_time, created_ts, created_by)
+            VALUES ($1, $2, $3, $4, $5, $6, $7)
+            ON CONFLICT (token) DO NOTHING
+            "#,
+        )
+        .bind(&token.token)
+        .bind(token.uses_allowed)
+        .bind(token.pending)
+        .bind(token.completed)
+        .bind(token.expiry_time)
+        .bind(token.created_ts)
+        .bind(&token.created_by)
+        .execute(&*self.pool)
+        .await?
+        .rows_affected();
+        Ok(inserted > 0)
+    }
+
+    pub async fn get_token(&self, token: &str) -> Result<Option<RegistrationToken>, sqlx::Error> {
+        sqlx::query_as("SELECT * FROM registration_tokens WHERE token = $1")
+            .bind(token)
+            .fetch_optional(&*self.pool)
+            .await
+    }
+
+    /// All tokens, or with `valid` set only those that are (or are not) valid at `now`.
+    pub async fn list_tokens(&self, valid: Option<bool>, now: i64) -> Result<Vec<RegistrationToken>, sqlx::Error> {
+        sqlx::query_as(&format!(
+            "SELECT * FROM registration_tokens WHERE $1::BOOLEAN IS NULL OR ({}) = $1 ORDER BY created_ts",
+            VALID_AT
+        ))
+        .bind(valid)
+        .bind(now)
+        .fetch_all(&*self.pool)
+        .await
+    }
+
+    /// Returns the updated token, or `None` if there is no such token.
+    pub async fn update_token(
+        &self,
+        token: &str,
+        update: &RegistrationTokenUpdate,
+    ) -> Result<Option<RegistrationToken>, sqlx::Error> {
+        sqlx::query_as(
+            r#"
+            UPDATE registration_tokens SET
+                uses_allowed = CASE WHEN $2 THEN $3 ELSE uses_allowed END,
+                expiry_time = CASE WHEN $4 THEN $5 ELSE expiry_time END
+            WHERE token = $1
+            RETURNING *
+            "#,
+        )
+        .bind(token)
+        .bind(update.uses_allowed.is_some())
+        .bind(update.uses_allowed.flatten())
+        .bind(update.expiry_time.is_some())
+        .bind(update.expiry_time.flatten())
+        .fetch_optional(&*self.pool)
+        .await
+    }
+
+    /// Returns whether the token existed.
+    pub async fn delete_token(&self, token: &str) -> Result<bool, sqlx::Error> {
+        let deleted = sqlx::query("DELETE FROM registration_tokens WHERE token = $1")
+            .bind(token)
+            .execute(&*self.pool)
+            .await?
+            .rows_affected();
+        Ok(deleted > 0)
+    }
+
+    pub async fn is_valid(&self, token: &str, now: i64) -> Result<bool, sqlx::Error> {
+        sqlx::query_scalar(&format!(
+            "SELECT EXISTS (SELECT 1 FROM registration_tokens WHERE token = $1 AND {})",
+            VALID_AT
+        ))
+        .bind(token)
+        .bind(now)
+        .fetch_one(&*self.pool)
+        .await
+    }
+
+    /// Counts a registration as pending on the token if it is valid at `now`, in one statement
+    /// so concurrent registrations cannot overrun `uses_allowed`. Returns whether it was valid.
+    pub async fn add_pending(&self, token: &str, now: i64) -> Result<bool, sqlx::Error> {
+        let updated = sqlx::query(&format!(
+            "UPDATE registration_tokens SET pending = pending + 1 WHERE token = $1 AND {}",
+            VALID_AT
+        ))
+        .bind(token)
+        .bind(now)
+        .execute(&*self.pool)
+        .await?
+        .rows_affected();
+        Ok(updated > 0)
+    }
+
+    /// Moves a pending registration to completed.
+    pub async fn complete_pending(&self, token: &str) -> Result<(), sqlx::Error> {
+        sqlx::query(
+            r#"
+            UPDATE registration_tokens
+            SET pending = GREATEST(pending - 1, 0), completed = completed + 1
+            WHERE token = $1
+            "#,
+        )
+        .bind(token)
+        .execute(&*self.pool)
+        .await?;
+        Ok(())
+    }
+
+    /// Drops a pending registration that did not go through, freeing its use.
+    pub async fn remove_pending(&self, token: &str) -> Result<(), sqlx::Error> {
+        sqlx::query("UPDATE registration_tokens SET pending = GREATEST(pending - 1, 0) WHERE token = $1")
+            .bind(token)
+            .execute(&*self.pool)
+            .await?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn token(uses_allowed: Option<i32>, pending: i32, completed: i32, expiry_time: Option<i64>) -> RegistrationToken {
+        RegistrationToken {
+            token: "abcd".to_string(),
+            uses_allowed,
+            pending,
+            completed,
+            expiry_time,
+            created_ts: 0,
+            created_by: None,
+        }
+    }
+
+    #[test]
+    fn test_pending_registrations_count_towards_uses_allowed() {
+        assert!(token(None, 5, 5, None).is_valid(1000));
+        assert!(token(Some(2), 0, 1, None).is_valid(1000));
+        assert!(!token(Some(2), 1, 1, None).is_valid(1000));
+        assert!(!token(Some(0), 0, 0, None).is_valid(1000));
+    }
+
+    #[test]
+    fn test_token_expires_at_expiry_time() {
+        assert!(token(None, 0, 0, Some(1001)).is_valid(1000));
+        assert!(!token(None, 0, 0, Some(1000)).is_valid(1000));
+    }
+
+    #[test]
+    fn test_admin_json_keeps_unlimited_fields_as_null() {
+        let json = token(None, 1, 2, None).to_json();
+        assert_eq!(json["token"], "abcd");
+        assert!(json["uses_allowed"].is_null());
+        assert!(json["expiry_time"].is_null());
+        assert_eq!(json["pending"], 1);
+        assert_eq!(json["completed"], 2);
+        assert!(json.get("created_by").is_none());
+    }
+}
@@ -0,0 +1,371 @@
+use crate::storage::event::RoomEvent;
+use sqlx::{Pool, Postgres};
+use std::sync::Arc;
+
+/// The `m.relates_to` of an event, as stored in `event_relations`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct EventRelation {
+    pub relates_to_id: String,
+    pub rel_type: String,
+    pub aggregation_key: Option<String>,
+}
+
+impl EventRelation {
+    /// The relation in an event's content, if it has a usable one.
+    pub fn from_content(content: &serde_json::Value) -> Option<Self> {
+        let relates_to = content.get("m.relates_to")?;
+        Some(Self {
+            relates_to_id: relates_to.get("event_id")?.as_str()?.to_string(),
+            rel_type: relates_to.get("rel_type")?.as_str()?.to_string(),
+            aggregation_key: relates_to
+                .get("key")
+                .and_then(|key| key.as_str())
+                .map(str::to_string),
+        })
+    }
+}
+
+/// The summary of one thread: how many replies it has, the latest one and whether the user
+/// replied.
+#[derive(Debug, Clone, sqlx::FromRow)]
+pub struct ThreadSummaryRow {
+    pub root_event_id: String,
+    pub count: i64,
+    pub latest_event_id: String,
+    pub participated: bool,
+}
+
+/// A thread in a room's thread list, ordered by its latest reply.
+#[derive(Debug, Clone, sqlx::FromRow)]
+pub struct ThreadRow {
+    pub root_event_id: String,
+    pub latest_ordering: i64,
+}
+
+/// An event related to another, as returned by `/relations`.
+#[derive(Debug, Clone, sqlx::FromRow)]
+pub struct RelatedEventRow {
+    pub event_id: String,
+    pub stream_ordering: i64,
+    pub depth: i32,
+}
+
+/// How many times an event was annotated with one key, such as a reaction emoji.
+#[derive(Debug, Clone, sqlx::FromRow)]
+pub struct AnnotationCountRow {
+    pub relates_to_id: String,
+    pub event_type: String,
+    pub aggregation_key: String,
+    pub count: i64,
+}
+
+#[derive(Debug, Clone, sqlx::FromRow)]
+pub struct ThreadSubscriptionRow {
+    pub user_id: String,
+    pub room_id: String,
+    pub root_event_id: String,
+    pub notify: bool,
+    pub subscribed_ts: i64,
+}
+
+#[derive(Clone)]
+pub struct RelationsStorage {
+    pub pool: Arc<Pool<Postgres>>,
+}
+
+impl RelationsStorage {
+    pub fn new(pool: &Arc<Pool<Postgres>>) -> Self {
+        Self { pool: pool.clone() }
+    }
+
+    /// Records the event's relation, if it has one.
+    pub async fn add_event_relation(&self, event: &RoomEvent) -> Result<(), sqlx::Error> {
+        let Some(relation) = EventRelation::from_content(&event.content) else {
+            return Ok(());
+        };
+        sqlx::query(
+            r#"
+            INSERT INTO event_relations (event_id, room_id, relates_to_id, rel_type, aggregation_key,
+                                         sender, event_type, stream_ordering)
+            VALUES ($1, $2, $3, $4, $5, $6, $7, $8)
+            ON CONFLICT (event_id) DO NOTHING
+            "#,
+        )
+        .bind(&event.event_id)
+        .bind(&event.room_id)
+        .bind(&relation.relates_to_id)
+        .bind(&relation.rel_type)
+        .bind(&relation.aggregation_key)
+        .bind(&event.user_id)
+        .bind(&event.event_type)
+        .bind(event.processed_ts)
+        .execute(&*self.pool)
+        .await?;
+        Ok(())
+    }
+
+    /// The events relating to `parent_id`, and with `max_depth > 1` those relating to them in
+    /// turn, optionally narrowed to a relation and event type. `from` is an exclusive
+    /// `(stream_ordering, event_id)` position in the direction of travel.
+    #[allow(clippy::too_many_arguments)]
+    pub async fn get_relations(
+        &self,
+        room_id: &str,
+        parent_id: &str,
+        rel_type: Option<&str>,
+        event_type: Option<&str>,
+        from: Option<(i64, &str)>,
+        limit: i64,
+        forwards: bool,
+        max_depth: i32,
+    ) -> Result<Vec<RelatedEventRow>, sqlx::Error> {
+        let (from_ordering, from_event_id) = from.unzip();
+        let (comparison, order) = if forwards { (">", "ASC") } else { ("<", "DESC") };
+        let query = format!(
+            r#"
+            WITH RECURSIVE related(event_id, rel_type, event_type, stream_ordering, depth) AS (
+                SELECT r.event_id, r.rel_type, r.event_type, r.stream_ordering, 1
+                FROM event_relations r
+                WHERE r.relates_to_id = $2 AND r.room_id = $1
+                UNION ALL
+                SELECT r.event_id, r.rel_type, r.event_type, r.stream_ordering, related.depth + 1
+                FROM event_relations r JOIN related ON r.relates_to_id = related.event_id
+                WHERE r.room_id = $1 AND related.depth < $8
+            )
+            SELECT event_id, stream_ordering, depth FROM related
+            WHERE ($3::TEXT IS NULL OR rel_type = $3)
+              AND ($4::TEXT IS NULL OR event_type = $4)
+              AND ($5::BIGINT IS NULL OR (stream_ordering, event_id) {comparison} ($5, $6))
+            ORDER BY stream_ordering {order}, event_id {order}
+            LIMIT $7
+            "#
+        );
+        sqlx::query_as(&query)
+            .bind(room_id)
+            .bind(parent_id)
+            .bind(rel_type)
+            .bind(event_type)
+            .bind(from_ordering)
+            .bind(from_event_id)
+            .bind(limit)
+            .bind(max_depth)
+            .fetch_all(&*self.pool)
+            .await
+    }
+
+    /// The room's threads with the most recent activity first, starting after `before`, a
+    /// `(latest_ordering, root_event_id)` position. With `participant` set, only the threads
+    /// that user started or replied to.
+    pub async fn get_threads(
+        &self,
+        room_id: &str,
+        participant: Option<&str>,
+        before: Option<(i64, &str)>,
+        limit: i64,
+    ) -> Result<Vec<ThreadRow>, sqlx::Error> {
+        let (before_ordering, before_root) = before.unzip();
+        sqlx::query_as(
+            r#"
+            SELECT t.root_event_id, t.latest_ordering
+            FROM (
+                SELECT relates_to_id AS root_event_id, MAX(stream_ordering) AS latest_ordering
+                FROM event_relations
+                WHERE room_id = $1 AND rel_type = 'm.thread'
+                GROUP BY relates_to_id
+            ) t
+            WHERE ($2::TEXT IS NULL
+                   OR EXISTS (SELECT 1 FROM event_relations p
+                              WHERE p.relates_to_id = t.root_event_id AND p.rel_type = 'm.thread' AND p.sender = $2)
+                   OR EXISTS (SELECT 1 FROM events e WHERE e.event_id = t.root_event_id AND e.user_id = $2))
+              AND ($3::BIGINT IS NULL OR (t.latest_ordering, t.root_event_id) < ($3, $4))
+            ORDER BY t.latest_ordering DESC, t.root_event_id DESC
+            LIMIT $5
+            "#,
+        )
+        .bind(room_id)
+        .bind(participant)
+        .bind(before_ordering)
+        .bind(before_root)
+        .bind(limit)
+        .fetch_all(&*self.pool)
+        .await
+    }
+
+    /// Thread summaries of those of `root_event_ids` that have replies. `participated` only
+    /// covers replies; whether the user sent the root is up to the caller.
+    pub async fn get_thread_summaries(
+        &self,
+        root_event_ids: &[String],
+        user_id: &str,
+    ) -> Result<Vec<ThreadSummaryRow>, sqlx::Error> {
+        sqlx::query_as(
+            r#"
+            SELECT relates_to_id AS root_event_id,
+                   COUNT(*) AS count,
+                   (ARRAY_AGG(event_id ORDER BY stream_ordering DESC, event_id DESC))[1] AS latest_event_id,
+                   BOOL_OR(sender = $2) AS participated
+            FROM event_relations
+            WHERE relates_to_id = ANY($1) AND rel_type = 'm.thread'
+            GROUP BY relates_to_id
+            "#,
+        )
+        .bind(root_event_ids)
+        .bind(user_id)
+        .fetch_all(&*self.pool)
+        .await
+    }
+
+    /// `(original, edit)` pairs: the latest valid `m.replace` of each of `event_ids`, made by
+    /// the original's sender with the original's type. State events cannot be edited.
+    pub async fn get_latest_edits(&self, event_ids: &[String]) -> Result<Vec<(String, String)>, sqlx::Error> {
+        sqlx::query_as(
+            r#"
+            SELECT DISTINCT ON (r.relates_to_id) r.relates_to_id, r.event_id
+            FROM event_relations r JOIN events o ON o.event_id = r.relates_to_id
+            WHERE r.relates_to_id = ANY($1) AND r.rel_type = 'm.replace'
+              AND r.sender = o.user_id AND r.event_type = o.event_type AND o.state_key IS NULL
+            ORDER BY r.relates_to_id, r.stream_ordering DESC, r.event_id DESC
+            "#,
+        )
+        .bind(event_ids)
+        .fetch_all(&*self.pool)
+        .await
+    }
+
+    /// `(referenced, referencing)` pairs of the `m.reference` relations to `event_ids`, oldest
+    /// first.
+    pub async fn get_references(&self, event_ids: &[String]) -> Result<Vec<(String, String)>, sqlx::Error> {
+        sqlx::query_as(
+            r#"
+            SELECT relates_to_id, event_id FROM event_relations
+            WHERE relates_to_id = ANY($1) AND rel_type = 'm.reference'
+            ORDER BY stream_ordering, event_id
+            "#,
+        )
+        .bind(event_ids)
+        .fetch_all(&*self.pool)
+        .await
+    }
+
+    /// Annotations of `event_ids` grouped by type and key, the most used first.
+    pub async fn get_annotation_counts(&self, event_ids: &[String]) -> Result<Vec<AnnotationCountRow>, sqlx::Error> {
+        sqlx::query_as(
+            r#"
+            SELECT relates_to_id, event_type, aggregation_key, COUNT(*) AS count
+            FROM event_relations
+            WHERE relates_to_id = ANY($1) AND rel_type = 'm.annotation' AND aggregation_key IS NOT NULL
+            GROUP BY relates_to_id, event_type, aggregation_key
+            ORDER BY relates_to_id, count DESC, MIN(stream_ordering)
+            "#,
+        )
+        .bind(event_ids)
+        .fetch_all(&*self.pool)
+        .await
+    }
+
+    /// Whether `sender` already annotated the event with `key`.
+    pub async fn has_annotation(
+        &self,
+        relates_to_id: &str,
+        sender: &str,
+        event_type: &str,
+        key: &str,
+    ) -> Result<bool, sqlx::Error> {
+        sqlx::query_scalar(
+            r#"
+            SELECT EXISTS (
+                SELECT 1 FROM event_relations
+                WHERE relates_to_id = $1 AND rel_type = 'm.annotation'
+                  AND sender = $2 AND event_type = $3 AND aggregation_key = $4
+            )
+            "#,
+        )
+        .bind(relates_to_id)
+        .bind(sender)
+        .bind(event_type)
+        .bind(key)
+        .fetch_one(&*self.pool)
+        .await
+    }
+
+    pub async fn subscribe_to_thread(
+        &self,
+        user_id: &str,
+        room_id: &str,
+        root_event_id: &str,
+        notify: bool,
+    ) -> Result<ThreadSubscriptionRow, sqlx::Error> {
+        sqlx::query_as(
+            r#"
+            INSERT INTO thread_subscriptions (user_id, room_id, root_event_id, notify, subscribed_ts)
+            VALUES ($1, $2, $3, $4, $5)
+            ON CONFLICT (user_id, root_event_id) DO UPDATE SET notify = EXCLUDED.notify
+            RETURNING user_id, room_id, root_event_id, notify, subscribed_ts
+            "#,
+        )
+        .bind(user_id)
+        .bind(room_id)
+        .bind(root_event_id)
+        .bind(notify)
+        .bind(chrono::Utc::now().timestamp_millis())
+        .fetch_one(&*self.pool)
+        .await
+    }
+
+    pub async fn unsubscribe_from_thread(&self, user_id: &str, root_event_id: &str) -> Result<bool, sqlx::Error> {
+        let deleted = sqlx::query("DELETE FROM thread_subscriptions WHERE user_id = $1 AND root_event_id = $2")
+            .bind(user_id)
+            .bind(root_event_id)
+            .execute(&*self.pool)
+            .await?
+            .rows_affected();
+        Ok(deleted > 0)
+    }
+
+    pub async fn get_user_subscriptions(&self, user_id: &str) -> Result<Vec<ThreadSubscriptionRow>, sqlx::Error> {
+        sqlx::query_as(
+            r#"
+            SELECT user_id, room_id, root_event_id, notify, subscribed_ts
+            FROM thread_subscriptions WHERE user_id = $1 ORDER BY subscribed_ts
+            "#,
+        )
+        .bind(user_id)
+        .fetch_all(&*self.pool)
+        .await
+    }
+
+    pub async fn get_thread_subscribers(&self, root_event_id: &str) -> Result<Vec<String>, sqlx::Error> {
+        sqlx::query_scalar(
+            "SELECT user_id FROM thread_subscriptions WHERE root_event_id = $1 AND notify ORDER BY user_id",
+        )
+        .bind(root_event_id)
+        .fetch_all(&*self.pool)
+        .await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn test_relation_from_content() {
+        let reaction = json!({
+            "m.relates_to": { "rel_type": "m.annotation", "event_id": "$target", "key": "👍" }
+        });
+        assert_eq!(
+            EventRelation::from_content(&reaction),
+            Some(EventRelation {
+                relates_to_id: "$target".to_string(),
+                rel_type: "m.annotation".to_string(),
+                aggregation_key: Some("👍".to_string()),
+            })
+        );
+
+        // Rich replies have no rel_type and are not relations
+        let reply = json!({ "m.relates_to": { "m.in_reply_to": { "event_id": "$target" } } });
+        assert_eq!(EventRelation::from_content(&reply), None);
+        assert_eq!(EventRelation::from_content(&json!({ "body": "hi" })), None);
+    }
+}
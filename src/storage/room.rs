@@ -23,6 +23,14 @@ pub struct Room {
     pub creation_ts: i64,
 }
 
+/// A room joined without its full member list, see [`RoomStorage::mark_partial_state`].
+#[derive(Debug, Clone, sqlx::FromRow)]
+pub struct PartialStateRoom {
+    pub room_id: String,
+    pub join_event_id: String,
+    pub servers_in_room: Vec<String>,
+}
+
 #[derive(Debug, Clone, sqlx::FromRow)]
 struct RoomRecord {
     room_id: String,
@@ -130,6 +138,132 @@ impl RoomStorage {
         })
     }
 
+    /// Creates the local record of a room that lives on other servers, as found in the state
+    /// returned when joining it. An existing record only has its version corrected.
+    pub async fn create_remote_room(
+        &self,
+        room_id: &str,
+        creator: &str,
+        join_rule: &str,
+        version: &str,
+    ) -> Result<(), sqlx::Error> {
+        let now = chrono::Utc::now().timestamp();
+        sqlx::query(
+            r#"
+            INSERT INTO rooms (room_id, creator, join_rule, version, is_public, member_count, history_visibility, creation_ts, last_activity_ts)
+            VALUES ($1, $2, $3, $4, FALSE, 0, 'shared', $5, $5)
+            ON CONFLICT (room_id) DO UPDATE SET version = EXCLUDED.version
+            "#,
+        )
+        .bind(room_id)
+        .bind(creator)
+        .bind(join_rule)
+        .bind(version)
+        .bind(now)
+        .execute(&*self.pool)
+        .await?;
+        Ok(())
+    }
+
+    /// Copies the state-backed room columns from the room's current state.
+    pub async fn update_room_from_state(
+        &self,
+        room_id: &str,
+        join_rule: Option<&str>,
+        history_visibility: Option<&str>,
+        encryption: Option<&str>,
+    ) -> Result<(), sqlx::Error> {
+        sqlx::query(
+            r#"
+            UPDATE rooms SET
+                join_rule = COALESCE($2, join_rule),
+                history_visibility = COALESCE($3, history_visibility),
+                encryption = COALESCE($4, encryption),
+                member_count = (SELECT COUNT(*) FROM room_memberships WHERE room_id = $1 AND membership = 'join')
+            WHERE room_id = $1
+            "#,
+        )
+        .bind(room_id)
+        .bind(join_rule)
+        .bind(history_visibility)
+        .bind(encryption)
+        .execute(&*self.pool)
+        .await?;
+        Ok(())
+    }
+
+    /// Marks a room joined without its full member list. `servers_in_room` are sent our events
+    /// until the full state has been fetched.
+    pub async fn mark_partial_state(
+        &self,
+        room_id: &str,
+        join_event_id: &str,
+        servers_in_room: &[String],
+    ) -> Result<(), sqlx::Error> {
+        sqlx::query(
+            r#"
+            INSERT INTO partial_state_rooms (room_id, join_event_id, servers_in_room, created_ts)
+            VALUES ($1, $2, $3, $4)
+            ON CONFLICT (room_id) DO UPDATE SET
+                join_event_id = EXCLUDED.join_event_id,
+                servers_in_room = EXCLUDED.servers_in_room,
+                created_ts = EXCLUDED.created_ts
+            "#,
+        )
+        .bind(room_id)
+        .bind(join_event_id)
+        .bind(servers_in_room)
+        .bind(chrono::Utc::now().timestamp_millis())
+        .execute(&*self.pool)
+        .await?;
+        Ok(())
+    }
+
+    pub async fn get_partial_state(&self, room_id: &str) -> Result<Option<PartialStateRoom>, sqlx::Error> {
+        sqlx::query_as::<_, PartialStateRoom>(
+            r#"
+            SELECT room_id, join_event_id, servers_in_room FROM partial_state_rooms WHERE room_id = $1
+            "#,
+        )
+        .bind(room_id)
+        .fetch_optional(&*self.pool)
+        .await
+    }
+
+    pub async fn get_partial_state_rooms(&self) -> Result<Vec<PartialStateRoom>, sqlx::Error> {
+        sqlx::query_as::<_, PartialStateRoom>(
+            r#"
+            SELECT room_id, join_event_id, servers_in_room FROM partial_state_rooms ORDER BY created_ts
+            "#,
+        )
+        .fetch_all(&*self.pool)
+        .await
+    }
+
+    pub async fn is_partial_state(&self, room_id: &str) -> Result<bool, sqlx::Error> {
+        let result = sqlx::query_scalar::<_, i32>(
+            r#"
+            SELECT 1 FROM partial_state_rooms WHERE room_id = $1
+            "#,
+        )
+        .bind(room_id)
+        .fetch_optional(&*self.pool)
+        .await?;
+        Ok(result.is_some())
+    }
+
+    pub async fn clear_partial_state(&self, room_id: &str) -> Result<(), sqlx::Error> {
+        sqlx::query(
+            r#"
+            DELETE FROM partial_state_rooms WHERE room_id = $1
+            "#,
+        )
+        .bind(room_id)
+        .execute(&*self.pool)
+        .await?;
+        Ok(())
+    }
+
     pub async fn get_room(&self, room_id: &str) -> Result<Option<Room>, sqlx::Error> {
         let row = sqlx::query_as::<_, RoomRecord>(
             r#"
@@ -658,6 +792,26 @@ impl RoomStorage {
         Ok(())
     }
 
+    /// Receipts in a room updated after `since_ts`, as `(receipt_type, event_id, user_id, ts)`.
+    pub async fn get_receipts_since(
+        &self,
+        room_id: &str,
+        since_ts: i64,
+    ) -> Result<Vec<(String, String, String, i64)>, sqlx::Error> {
+        sqlx::query_as::<_, (String, String, String, i64)>(
+            r#"
+            SELECT receipt_type, event_id, user_id, created_at
+            FROM event_receipts
+            WHERE room_id = $1 AND created_at > $2
+            ORDER BY created_at ASC
+            "#,
+        )
+        .bind(room_id)
+        .bind(since_ts)
+        .fetch_all(&*self.pool)
+        .await
+    }
+
     pub async fn set_room_tombstone(
         &self,
         room_id: &str,
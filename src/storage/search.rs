@@ -0,0 +1,151 @@
+use crate::storage::event::RoomEvent;
+use sqlx::{Pool, Postgres};
+use std::sync::Arc;
+
+/// The text search configuration of `event_search.vector`.
+const TEXT_SEARCH_CONFIG: &str = "english";
+
+/// The searchable key of an event type and the content field it names.
+pub fn search_key(event_type: &str) -> Option<(&'static str, &'static str)> {
+    match event_type {
+        "m.room.message" => Some(("content.body", "body")),
+        "m.room.name" => Some(("content.name", "name")),
+        "m.room.topic" => Some(("content.topic", "topic")),
+        _ => None,
+    }
+}
+
+#[derive(Debug, Clone, sqlx::FromRow)]
+pub struct SearchResultRow {
+    pub event_id: String,
+    pub room_id: String,
+    pub rank: f64,
+    pub stream_ordering: i64,
+    /// The matched value with the matched words between `<<<` and `>>>`.
+    pub headline: String,
+}
+
+/// What to search, with `tsquery` in `to_tsquery` syntax.
+#[derive(Debug, Clone)]
+pub struct EventSearchQuery<'a> {
+    pub tsquery: &'a str,
+    pub room_ids: &'a [String],
+    pub keys: &'a [String],
+    pub senders: Option<&'a [String]>,
+    pub not_senders: &'a [String],
+}
+
+#[derive(Clone)]
+pub struct EventSearchStorage {
+    pub pool: Arc<Pool<Postgres>>,
+}
+
+impl EventSearchStorage {
+    pub fn new(pool: &Arc<Pool<Postgres>>) -> Self {
+        Self { pool: pool.clone() }
+    }
+
+    /// Indexes the event's body, name or topic, if it has one.
+    pub async fn add_event(&self, event: &RoomEvent) -> Result<(), sqlx::Error> {
+        let Some((key, field)) = search_key(&event.event_type) else {
+            return Ok(());
+        };
+        let Some(value) = event.content.get(field).and_then(|v| v.as_str()).filter(|v| !v.is_empty()) else {
+            return Ok(());
+        };
+        sqlx::query(
+            r#"
+            INSERT INTO event_search (event_id, room_id, sender, key, value, vector, origin_server_ts, stream_ordering)
+            VALUES ($1, $2, $3, $4, $5, to_tsvector($6::regconfig, $5), $7, $8)
+            ON CONFLICT (event_id) DO NOTHING
+            "#,
+        )
+        .bind(&event.event_id)
+        .bind(&event.room_id)
+        .bind(&event.user_id)
+        .bind(key)
+        .bind(value)
+        .bind(TEXT_SEARCH_CONFIG)
+        .bind(event.origin_server_ts)
+        .bind(event.stream_ordering)
+        .execute(&*self.pool)
+        .await?;
+        Ok(())
+    }
+
+    /// A page of matches, best first or, with `recent`, newest first. Rank order pages
+    /// by `offset`; recent order pages by the exclusive `(stream_ordering, event_id)` in `before`.
+    pub async fn search(
+        &self,
+        query: &EventSearchQuery<'_>,
+        recent: bool,
+        offset: i64,
+        before: Option<(i64, &str)>,
+        limit: i64,
+    ) -> Result<Vec<SearchResultRow>, sqlx::Error> {
+        let (before_ordering, before_event_id) = before.unzip();
+        let order = if recent {
+            "s.stream_ordering DESC, s.event_id DESC"
+        } else {
+            "rank DESC, s.stream_ordering DESC, s.event_id DESC"
+        };
+        let sql = format!(
+            r#"
+            SELECT s.event_id, s.room_id, ts_rank_cd(s.vector, q.query)::FLOAT8 AS rank, s.stream_ordering,
+                   ts_headline($1::regconfig, s.value, q.query, 'StartSel=<<<, StopSel=>>>, HighlightAll=TRUE') AS headline
+            FROM event_search s, to_tsquery($1::regconfig, $2) AS q(query)
+            WHERE s.vector @@ q.query
+              AND s.room_id = ANY($3) AND s.key = ANY($4)
+              AND ($5::TEXT[] IS NULL OR s.sender = ANY($5)) AND NOT (s.sender = ANY($6))
+              AND ($7::BIGINT IS NULL OR (s.stream_ordering, s.event_id) < ($7, $8))
+            ORDER BY {order}
+            LIMIT $9 OFFSET $10
+            "#
+        );
+        sqlx::query_as::<_, SearchResultRow>(&sql)
+            .bind(TEXT_SEARCH_CONFIG)
+            .bind(query.tsquery)
+            .bind(query.room_ids)
+            .bind(query.keys)
+            .bind(query.senders)
+            .bind(query.not_senders)
+            .bind(before_ordering)
+            .bind(before_event_id)
+            .bind(limit)
+            .bind(offset)
+            .fetch_all(&*self.pool)
+            .await
+    }
+
+    /// How many events match, regardless of pagination.
+    pub async fn count(&self, query: &EventSearchQuery<'_>) -> Result<i64, sqlx::Error> {
+        sqlx::query_scalar(
+            r#"
+            SELECT COUNT(*) FROM event_search s
+            WHERE s.vector @@ to_tsquery($1::regconfig, $2)
+              AND s.room_id = ANY($3) AND s.key = ANY($4)
+              AND ($5::TEXT[] IS NULL OR s.sender = ANY($5)) AND NOT (s.sender = ANY($6))
+            "#,
+        )
+        .bind(TEXT_SEARCH_CONFIG)
+        .bind(query.tsquery)
+        .bind(query.room_ids)
+        .bind(query.keys)
+        .bind(query.senders)
+        .bind(query.not_senders)
+        .fetch_one(&*self.pool)
+        .await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_search_key_covers_messages_names_and_topics() {
+        assert_eq!(search_key("m.room.message"), Some(("content.body", "body")));
+        assert_eq!(search_key("m.room.topic"), Some(("content.topic", "topic")));
+        assert_eq!(search_key("m.reaction"), None);
+    }
+}
@@ -0,0 +1,119 @@
+use serde::{Deserialize, Serialize};
+use sqlx::{Pool, Postgres};
+use std::collections::HashMap;
+use std::sync::Arc;
+
+/// What a sliding sync connection has already been sent for one room.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct SlidingRoomState {
+    /// Timestamp of the newest event delivered for the room.
+    pub last_ts: i64,
+    /// Fingerprint of the room config (required_state, timeline_limit) it was sent with.
+    pub config: String,
+}
+
+pub type SlidingRoomStates = HashMap<String, SlidingRoomState>;
+
+#[derive(Clone)]
+pub struct SlidingSyncStorage {
+    pub pool: Arc<Pool<Postgres>>,
+}
+
+impl SlidingSyncStorage {
+    pub fn new(pool: &Arc<Pool<Postgres>>) -> Self {
+        Self { pool: pool.clone() }
+    }
+
+    pub async fn get_connection_state(
+        &self,
+        user_id: &str,
+        device_id: &str,
+        conn_id: &str,
+        pos: i64,
+    ) -> Result<Option<SlidingRoomStates>, sqlx::Error> {
+        let row: Option<(serde_json::Value,)> = sqlx::query_as(
+            r#"
+            SELECT room_states FROM sliding_sync_connections
+            WHERE user_id = $1 AND device_id = $2 AND conn_id = $3 AND pos = $4
+            "#,
+        )
+        .bind(user_id)
+        .bind(device_id)
+        .bind(conn_id)
+        .bind(pos)
+        .fetch_optional(&*self.pool)
+        .await?;
+
+        Ok(row.map(|(states,)| serde_json::from_value(states).unwrap_or_default()))
+    }
+
+    /// Stores the state for a new `pos`, dropping every older position except `previous_pos`.
+    pub async fn save_connection_state(
+        &self,
+        user_id: &str,
+        device_id: &str,
+        conn_id: &str,
+        previous_pos: Option<i64>,
+        pos: i64,
+        room_states: &SlidingRoomStates,
+    ) -> Result<(), sqlx::Error> {
+        let now = chrono::Utc::now().timestamp_millis();
+        let states = serde_json::to_value(room_states).unwrap_or_else(|_| serde_json::json!({}));
+
+        let mut tx = self.pool.begin().await?;
+        sqlx::query(
+            r#"
+            INSERT INTO sliding_sync_connections (user_id, device_id, conn_id, pos, room_states, created_ts)
+            VALUES ($1, $2, $3, $4, $5, $6)
+            ON CONFLICT (user_id, device_id, conn_id, pos) DO UPDATE SET
+                room_states = EXCLUDED.room_states,
+                created_ts = EXCLUDED.created_ts
+            "#,
+        )
+        .bind(user_id)
+        .bind(device_id)
+        .bind(conn_id)
+        .bind(pos)
+        .bind(states)
+        .bind(now)
+        .execute(&mut *tx)
+        .await?;
+
+        sqlx::query(
+            r#"
+            DELETE FROM sliding_sync_connections
+            WHERE user_id = $1 AND device_id = $2 AND conn_id = $3
+              AND pos <> $4 AND pos <> COALESCE($5, $4)
+            "#,
+        )
+        .bind(user_id)
+        .bind(device_id)
+        .bind(conn_id)
+        .bind(pos)
+        .bind(previous_pos)
+        .execute(&mut *tx)
+        .await?;
+
+        tx.commit().await
+    }
+
+    pub async fn delete_connection(
+        &self,
+        user_id: &str,
+        device_id: &str,
+        conn_id: &str,
+    ) -> Result<(), sqlx::Error> {
+        sqlx::query(
+            r#"
+            DELETE FROM sliding_sync_connections
+            WHERE user_id = $1 AND device_id = $2 AND conn_id = $3
+            "#,
+        )
+        .bind(user_id)
+        .bind(device_id)
+        .bind(conn_id)
+        .execute(&*self.pool)
+        .await?;
+        Ok(())
+    }
+}
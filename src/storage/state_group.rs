@@ -0,0 +1,118 @@
+use crate::federation::StateMap;
+use sqlx::{Pool, Postgres};
+use std::sync::Arc;
+
+#[derive(Clone)]
+pub struct StateGroupStorage {
+    pub pool: Arc<Pool<Postgres>>,
+}
+
+impl StateGroupStorage {
+    pub fn new(pool: &Arc<Pool<Postgres>>) -> Self {
+        Self { pool: pool.clone() }
+    }
+
+    /// Stores `state` as a new group, recording the event it was computed for.
+    pub async fn create_state_group(
+        &self,
+        room_id: &str,
+        event_id: Option<&str>,
+        state: &StateMap,
+    ) -> Result<i64, sqlx::Error> {
+        let mut tx = self.pool.begin().await?;
+
+        let group: i64 = sqlx::query_scalar(
+            "INSERT INTO state_groups (room_id, event_id, created_ts) VALUES ($1, $2, $3) RETURNING id",
+        )
+        .bind(room_id)
+        .bind(event_id)
+        .bind(chrono::Utc::now().timestamp_millis())
+        .fetch_one(&mut *tx)
+        .await?;
+
+        let (event_types, rest): (Vec<&str>, Vec<(&str, &str)>) = state
+            .iter()
+            .map(|((event_type, state_key), event_id)| {
+                (event_type.as_str(), (state_key.as_str(), event_id.as_str()))
+            })
+            .unzip();
+        let (state_keys, event_ids): (Vec<&str>, Vec<&str>) = rest.into_iter().unzip();
+
+        sqlx::query(
+            r#"
+            INSERT INTO state_groups_state (state_group, event_type, state_key, event_id)
+            SELECT $1, * FROM UNNEST($2::text[], $3::text[], $4::text[])
+            "#,
+        )
+        .bind(group)
+        .bind(&event_types)
+        .bind(&state_keys)
+        .bind(&event_ids)
+        .execute(&mut *tx)
+        .await?;
+
+        tx.commit().await?;
+        Ok(group)
+    }
+
+    pub async fn get_state_group(&self, group: i64) -> Result<StateMap, sqlx::Error> {
+        let rows: Vec<(String, String, String)> = sqlx::query_as(
+            "SELECT event_type, state_key, event_id FROM state_groups_state WHERE state_group = $1",
+        )
+        .bind(group)
+        .fetch_all(&*self.pool)
+        .await?;
+
+        Ok(rows
+            .into_iter()
+            .map(|(event_type, state_key, event_id)| ((event_type, state_key), event_id))
+            .collect())
+    }
+
+    pub async fn get_state_group_for_event(&self, event_id: &str) -> Result<Option<i64>, sqlx::Error> {
+        sqlx::query_scalar("SELECT state_group FROM event_to_state_groups WHERE event_id = $1")
+            .bind(event_id)
+            .fetch_optional(&*self.pool)
+            .await
+    }
+
+    pub async fn set_event_state_group(&self, event_id: &str, group: i64) -> Result<(), sqlx::Error> {
+        sqlx::query(
+            r#"
+            INSERT INTO event_to_state_groups (event_id, state_group) VALUES ($1, $2)
+            ON CONFLICT (event_id) DO UPDATE SET state_group = EXCLUDED.state_group
+            "#,
+        )
+        .bind(event_id)
+        .bind(group)
+        .execute(&*self.pool)
+        .await?;
+        Ok(())
+    }
+
+    /// The group previously resolved from exactly `groups`, which must be sorted.
+    pub async fn get_resolved_group(&self, room_id: &str, groups: &[i64]) -> Result<Option<i64>, sqlx::Error> {
+        sqlx::query_scalar(
+            "SELECT state_group FROM state_group_resolutions WHERE room_id = $1 AND input_groups = $2",
+        )
+        .bind(room_id)
+        .bind(groups)
+        .fetch_optional(&*self.pool)
+        .await
+    }
+
+    pub async fn save_resolved_group(&self, room_id: &str, groups: &[i64], group: i64) -> Result<(), sqlx::Error> {
+        sqlx::query(
+            r#"
+            INSERT INTO state_group_resolutions (room_id, input_groups, state_group) VALUES ($1, $2, $3)
+            ON CONFLICT (room_id, input_groups) DO NOTHING
+            "#,
+        )
+        .bind(room_id)
+        .bind(groups)
+        .bind(group)
+        .execute(&*self.pool)
+        .await?;
+        Ok(())
+    }
+}
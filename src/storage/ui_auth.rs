@@ -0,0 +1,208 @@
+use serde_json::Value;
+use sqlx::{Pool, Postgres};
+use std::sync::Arc;
+
+/// A user-interactive authentication session. `completed` maps each finished stage type to
+/// what it established, e.g. the registration token held or the policies agreed to.
+#[derive(Debug, Clone, sqlx::FromRow)]
+pub struct UiAuthSession {
+    pub session_id: String,
+    pub user_id: Option<String>,
+    pub action: String,
+    pub completed: Value,
+    pub created_ts: i64,
+}
+
+impl UiAuthSession {
+    /// Stage types completed so far, in no particular order.
+    pub fn completed_stages(&self) -> Vec<String> {
+        self.completed
+            .as_object()
+            .map(|stages| stages.keys().cloned().collect())
+            .unwrap_or_default()
+    }
+
+    pub fn is_completed(&self, stage: &str) -> bool {
+        self.completed.get(stage).is_some()
+    }
+
+    /// What completing `stage` established, if it was completed.
+    pub fn stage_result(&self, stage: &str) -> Option<&Value> {
+        self.completed.get(stage)
+    }
+}
+
+/// An SSO fallback started for a UIA session, found again by the state the identity provider
+/// redirects back with.
+#[derive(Debug, Clone, sqlx::FromRow)]
+pub struct PendingSso {
+    pub session_id: String,
+    pub redirect_uri: String,
+    pub code_verifier: String,
+}
+
+#[derive(Clone)]
+pub struct UiAuthStorage {
+    pub pool: Arc<Pool<Postgres>>,
+}
+
+impl UiAuthStorage {
+    pub fn new(pool: &Arc<Pool<Postgres>>) -> Self {
+        Self { pool: pool.clone() }
+    }
+
+    pub async fn create_session(&self, session: &UiAuthSession) -> Result<(), sqlx::Error> {
+        sqlx::query(
+            r#"
+            INSERT INTO ui_auth_sessions (session_id, user_id, action, completed, created_ts)
+            VALUES ($1, $2, $3, $4, $5)
+            "#,
+        )
+        .bind(&session.session_id)
+        .bind(&session.user_id)
+        .bind(&session.action)
+        .bind(&session.completed)
+        .bind(session.created_ts)
+        .execute(&*self.pool)
+        .await?;
+        Ok(())
+    }
+
+    pub async fn get_session(&self, session_id: &str) -> Result<Option<UiAuthSession>, sqlx::Error> {
+        sqlx::query_as("SELECT * FROM ui_auth_sessions WHERE session_id = $1")
+            .bind(session_id)
+            .fetch_optional(&*self.pool)
+            .await
+    }
+
+    /// Records `stage` as completed with `result`, returning the updated session.
+    pub async fn add_completed(
+        &self,
+        session_id: &str,
+        stage: &str,
+        result: &Value,
+    ) -> Result<Option<UiAuthSession>, sqlx::Error> {
+        sqlx::query_as(
+            r#"
+            UPDATE ui_auth_sessions
+            SET completed = completed || jsonb_build_object($2::TEXT, $3::JSONB)
+            WHERE session_id = $1
+            RETURNING *
+            "#,
+        )
+        .bind(session_id)
+        .bind(stage)
+        .bind(result)
+        .fetch_optional(&*self.pool)
+        .await
+    }
+
+    /// Records the SSO fallback started for `session_id`, replacing any earlier one.
+    pub async fn set_pending_sso(
+        &self,
+        session_id: &str,
+        state: &str,
+        redirect_uri: &str,
+        code_verifier: &str,
+    ) -> Result<bool, sqlx::Error> {
+        let result = sqlx::query(
+            r#"
+            UPDATE ui_auth_sessions
+            SET sso_state = $2, sso_redirect_uri = $3, sso_code_verifier = $4
+            WHERE session_id = $1
+            "#,
+        )
+        .bind(session_id)
+        .bind(state)
+        .bind(redirect_uri)
+        .bind(code_verifier)
+        .execute(&*self.pool)
+        .await?;
+        Ok(result.rows_affected() > 0)
+    }
+
+    /// Clears and returns the SSO fallback started with `state`, so a state is used only once.
+    pub async fn take_pending_sso(&self, state: &str) -> Result<Option<PendingSso>, sqlx::Error> {
+        sqlx::query_as(
+            r#"
+            UPDATE ui_auth_sessions s
+            SET sso_state = NULL, sso_redirect_uri = NULL, sso_code_verifier = NULL
+            FROM (
+                SELECT session_id, sso_redirect_uri, sso_code_verifier FROM ui_auth_sessions
+                WHERE sso_state = $1
+                FOR UPDATE
+            ) pending
+            WHERE s.session_id = pending.session_id
+            RETURNING pending.session_id,
+                      pending.sso_redirect_uri AS redirect_uri,
+                      pending.sso_code_verifier AS code_verifier
+            "#,
+        )
+        .bind(state)
+        .fetch_optional(&*self.pool)
+        .await
+    }
+
+    pub async fn delete_session(&self, session_id: &str) -> Result<(), sqlx::Error> {
+        sqlx::query("DELETE FROM ui_auth_sessions WHERE session_id = $1")
+            .bind(session_id)
+            .execute(&*self.pool)
+            .await?;
+        Ok(())
+    }
+
+    /// Deletes sessions created before `before`, returning them so held resources can be released.
+    pub async fn delete_expired(&self, before: i64) -> Result<Vec<UiAuthSession>, sqlx::Error> {
+        sqlx::query_as("DELETE FROM ui_auth_sessions WHERE created_ts < $1 RETURNING *")
+            .bind(before)
+            .fetch_all(&*self.pool)
+            .await
+    }
+
+    /// Deletes all but the `keep` newest sessions of `user_id`, returning the deleted ones.
+    pub async fn delete_oldest_for_user(
+        &self,
+        user_id: &str,
+        keep: i64,
+    ) -> Result<Vec<UiAuthSession>, sqlx::Error> {
+        sqlx::query_as(
+            r#"
+            DELETE FROM ui_auth_sessions
+            WHERE session_id IN (
+                SELECT session_id FROM ui_auth_sessions
+                WHERE user_id = $1
+                ORDER BY created_ts DESC
+                OFFSET $2
+            )
+            RETURNING *
+            "#,
+        )
+        .bind(user_id)
+        .bind(keep)
+        .fetch_all(&*self.pool)
+        .await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn test_completed_stages() {
+        let session = UiAuthSession {
+            session_id: "abc".to_string(),
+            user_id: None,
+            action: "register".to_string(),
+            completed: json!({"m.login.dummy": true, "m.login.registration_token": "tok"}),
+            created_ts: 0,
+        };
+        let mut stages = session.completed_stages();
+        stages.sort();
+        assert_eq!(stages, vec!["m.login.dummy", "m.login.registration_token"]);
+        assert!(session.is_completed("m.login.dummy"));
+        assert!(!session.is_completed("m.login.password"));
+        assert_eq!(session.stage_result("m.login.registration_token"), Some(&json!("tok")));
+    }
+}
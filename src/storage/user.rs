@@ -108,6 +108,32 @@ impl UserStorage {
         .await
     }
 
+    /// Creates a passwordless user owned by an application service.
+    pub async fn create_appservice_user(
+        &self,
+        user_id: &str,
+        username: &str,
+        appservice_id: &str,
+    ) -> Result<User, sqlx::Error> {
+        let now = chrono::Utc::now().timestamp();
+        sqlx::query_as::<_, User>(
+            r#"
+            INSERT INTO users (user_id, username, is_admin, appservice_id, creation_ts, generation)
+            VALUES ($1, $2, FALSE, $3, $4, $5)
+            RETURNING user_id, username, password_hash, displayname, avatar_url, is_admin, deactivated,
+                      is_guest, consent_version, appservice_id, user_type, shadow_banned, generation,
+                      invalid_update_ts, migration_state, creation_ts, updated_ts
+            "#,
+        )
+        .bind(user_id)
+        .bind(username)
+        .bind(appservice_id)
+        .bind(now)
+        .bind(now * 1000)
+        .fetch_one(&*self.pool)
+        .await
+    }
+
     pub async fn get_user_by_id(&self, user_id: &str) -> Result<Option<User>, sqlx::Error> {
         sqlx::query_as::<_, User>(
             r#"
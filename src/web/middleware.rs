@@ -177,36 +177,16 @@ async fn prewarm_keys_for_origin(
         return Ok(());
     }
 
-    let urls = [
-        format!("https://{}/_matrix/key/v2/server", origin),
-        format!("http://{}:8448/_matrix/key/v2/server", origin),
-    ];
-
-    let client = reqwest::Client::builder()
-        .timeout(std::time::Duration::from_secs(2))
-        .build()
-        .map_err(|e| ApiError::internal(e.to_string()))?;
-
-    for url in urls {
-        match client.get(&url).send().await {
-            Ok(resp) if resp.status().is_success() => match resp.json::<Value>().await {
-                Ok(json) => {
-                    let keys_json = serde_json::to_string(&json).unwrap_or_default();
-                    let ttl = FEDERATION_KEY_CACHE_TTL;
-                    let _ = state.cache.set(&cache_key, keys_json, ttl).await;
-                    tracing::info!("Successfully prewarmed keys for {}", origin);
-                    return Ok(());
-                }
-                Err(e) => {
-                    tracing::debug!("Failed to parse response from {}: {}", url, e);
-                }
-            },
-            Err(e) => {
-                tracing::debug!("Failed to fetch keys from {}: {}", url, e);
-            }
-            _ => {
-                tracing::debug!("Non-success status from {}", url);
-            }
+    match state.services.federation_client.get_server_keys(origin).await {
+        Ok(json) => {
+            let keys_json = serde_json::to_string(&json).unwrap_or_default();
+            let ttl = FEDERATION_KEY_CACHE_TTL;
+            let _ = state.cache.set(&cache_key, keys_json, ttl).await;
+            tracing::info!("Successfully prewarmed keys for {}", origin);
+            return Ok(());
+        }
+        Err(e) => {
+            tracing::debug!("Failed to fetch keys from {}: {}", origin, e);
         }
     }
 
@@ -520,8 +500,11 @@ pub async fn auth_middleware(
         None => return ApiError::unauthorized("Missing access token".to_string()).into_response(),
     };
 
-    if let Err(err) = state.services.auth_service.validate_token(&token).await {
-        return err.into_response();
+    // Application services are resolved to the user they act as by the extractor
+    if state.services.app_services.get_by_token(&token).await.is_none() {
+        if let Err(err) = state.services.auth_service.validate_token(&token).await {
+            return err.into_response();
+        }
     }
 
     next.run(request).await
@@ -594,7 +577,7 @@ pub async fn federation_auth_middleware(
         .map(|p| p.as_str().to_string())
         .unwrap_or_else(|| parts.uri.path().to_string());
 
-    let signed_bytes = canonical_federation_request_bytes(
+    let signed_bytes = crate::federation::client::request_signing_bytes(
         parts.method.as_str(),
         &request_target,
         &params.origin,
@@ -624,6 +607,10 @@ pub async fn federation_auth_middleware(
         return ApiError::unauthorized("Invalid federation signature".to_string()).into_response();
     }
 
+    let sender = state.services.federation_sender.clone();
+    let origin = params.origin.clone();
+    tokio::spawn(async move { sender.on_remote_online(&origin).await });
+
     let request = Request::from_parts(parts, Body::from(body_bytes));
     next.run(request).await
 }
@@ -664,6 +651,15 @@ struct XMatrixAuthParams {
     sig: String,
 }
 
+/// The origin server named in a request's `Authorization: X-Matrix` header.
+pub(crate) fn federation_request_origin(headers: &HeaderMap) -> Option<String> {
+    headers
+        .get("authorization")
+        .and_then(|h| h.to_str().ok())
+        .and_then(parse_x_matrix_authorization)
+        .map(|params| params.origin)
+}
+
 fn parse_x_matrix_authorization(header_value: &str) -> Option<XMatrixAuthParams> {
     let header_value = header_value.trim();
     let header_value = header_value.strip_prefix("X-Matrix ")?;
@@ -696,82 +692,7 @@ fn parse_x_matrix_authorization(header_value: &str) -> Option<XMatrixAuthParams>
     })
 }
 
-fn canonical_federation_request_bytes(
-    method: &str,
-    uri: &str,
-    origin: &str,
-    destination: &str,
-    content: Option<&Value>,
-) -> Vec<u8> {
-    let mut obj = serde_json::Map::new();
-    obj.insert("method".to_string(), Value::String(method.to_string()));
-    obj.insert("uri".to_string(), Value::String(uri.to_string()));
-    obj.insert("origin".to_string(), Value::String(origin.to_string()));
-    obj.insert(
-        "destination".to_string(),
-        Value::String(destination.to_string()),
-    );
-    if let Some(content) = content {
-        obj.insert("content".to_string(), content.clone());
-    }
-    canonical_json_bytes(&Value::Object(obj))
-}
-
-fn canonical_json_bytes(value: &Value) -> Vec<u8> {
-    canonical_json_string(value).into_bytes()
-}
-
-fn canonical_json_string(value: &Value) -> String {
-    match value {
-        Value::Null => "null".to_string(),
-        Value::Bool(b) => {
-            if *b {
-                "true".to_string()
-            } else {
-                "false".to_string()
-            }
-        }
-        Value::Number(n) => n.to_string(),
-        Value::String(s) => serde_json::to_string(s).unwrap_or_else(|_| "\"\"".to_string()),
-        Value::Array(arr) => {
-            let mut out = String::from("[");
-            let mut first = true;
-            for v in arr {
-                if !first {
-                    out.push(',');
-                }
-                first = false;
-                out.push_str(&canonical_json_string(v));
-            }
-            out.push(']');
-            out
-        }
-        Value::Object(map) => {
-            let mut keys: Vec<&String> = map.keys().collect();
-            keys.sort();
-
-            let mut out = String::from("{");
-            let mut first = true;
-            for k in keys {
-                if !first {
-                    out.push(',');
-                }
-                first = false;
-                out.push_str(&serde_json::to_string(k).unwrap_or_else(|_| "\"\"".to_string()));
-                out.push(':');
-                if let Some(v) = map.get(k) {
-                    out.push_str(&canonical_json_string(v));
-                } else {
-                    out.push_str("null");
-                }
-            }
-            out.push('}');
-            out
-        }
-    }
-}
-
-async fn verify_federation_signature_with_cache(
+pub(crate) async fn verify_federation_signature_with_cache(
     state: &crate::web::routes::AppState,
     origin: &str,
     key_id: &str,
@@ -909,47 +830,36 @@ async fn get_federation_verify_key(
         }
     }
 
-    let fetched = fetch_federation_verify_key(origin, key_id).await?;
+    let fetched = fetch_federation_verify_key(state, origin, key_id).await?;
     let ttl = 3600u64;
     let _ = state.cache.set(&cache_key, &fetched, ttl).await;
     decode_ed25519_public_key(&fetched)
         .map_err(|_| ApiError::unauthorized("Invalid public key".to_string()))
 }
 
-async fn fetch_federation_verify_key(origin: &str, key_id: &str) -> Result<String, ApiError> {
-    let client = reqwest::Client::builder()
-        .timeout(std::time::Duration::from_secs(3))
-        .build()
-        .map_err(|e| ApiError::internal(e.to_string()))?;
-
-    let urls = [
-        format!("https://{}/_matrix/key/v2/server", origin),
-        format!("http://{}/_matrix/key/v2/server", origin),
-        format!(
-            "https://{}/_matrix/key/v2/query/{}/{}",
-            origin, origin, key_id
-        ),
-        format!(
-            "http://{}/_matrix/key/v2/query/{}/{}",
-            origin, origin, key_id
-        ),
-    ];
-
-    for url in urls {
-        let resp = match client.get(&url).send().await {
-            Ok(r) => r,
-            Err(_) => continue,
-        };
-        if !resp.status().is_success() {
-            continue;
+async fn fetch_federation_verify_key(
+    state: &crate::web::routes::AppState,
+    origin: &str,
+    key_id: &str,
+) -> Result<String, ApiError> {
+    let client = &state.services.federation_client;
+    match client.get_server_keys(origin).await {
+        Ok(json) => {
+            if let Some(key) = extract_verify_key_from_server_keys(&json, origin, key_id) {
+                return Ok(key);
+            }
         }
-        let json = match resp.json::<Value>().await {
-            Ok(v) => v,
-            Err(_) => continue,
-        };
-        if let Some(key) = extract_verify_key_from_server_keys(&json, origin, key_id) {
-            return Ok(key);
+        Err(e) => tracing::debug!("Failed to fetch server keys from {}: {}", origin, e),
+    }
+
+    let path = format!("/_matrix/key/v2/query/{}/{}", origin, key_id);
+    match client.get_json(origin, &path).await {
+        Ok(json) => {
+            if let Some(key) = extract_verify_key_from_server_keys(&json, origin, key_id) {
+                return Ok(key);
+            }
         }
+        Err(e) => tracing::debug!("Failed to query key {} from {}: {}", key_id, origin, e),
     }
 
     Err(ApiError::unauthorized("Public key not found".to_string()))
@@ -139,6 +139,20 @@ pub fn create_admin_router(_state: AppState) -> Router<AppState> {
             "/_synapse/admin/v1/purge_media_cache",
             post(purge_media_cache),
         )
+        .route(
+            "/_synapse/admin/v1/registration_tokens",
+            get(list_registration_tokens),
+        )
+        .route(
+            "/_synapse/admin/v1/registration_tokens/new",
+            post(create_registration_token),
+        )
+        .route(
+            "/_synapse/admin/v1/registration_tokens/{token}",
+            get(get_registration_token)
+                .put(update_registration_token)
+                .delete(delete_registration_token),
+        )
 }
 
 #[derive(Debug, Deserialize)]
@@ -2091,3 +2105,138 @@ pub async fn purge_media_cache(
         "deleted": result.rows_affected(),
     })))
 }
+
+/// Reads a nullable integer field of a registration token request: `None` if absent,
+/// `Some(None)` if null.
+fn registration_token_limit(body: &Value, field: &str) -> Result<Option<Option<i64>>, ApiError> {
+    match body.get(field) {
+        None => Ok(None),
+        Some(Value::Null) => Ok(Some(None)),
+        Some(value) => value
+            .as_i64()
+            .map(|value| Some(Some(value)))
+            .ok_or_else(|| ApiError::validation(format!("{} must be an integer or null", field))),
+    }
+}
+
+fn registration_token_uses_allowed(body: &Value) -> Result<Option<Option<i32>>, ApiError> {
+    registration_token_limit(body, "uses_allowed")?
+        .map(|uses| {
+            uses.map(i32::try_from)
+                .transpose()
+                .map_err(|_| ApiError::validation("uses_allowed is too large".to_string()))
+        })
+        .transpose()
+}
+
+#[axum::debug_handler]
+pub async fn list_registration_tokens(
+    _admin: AdminUser,
+    State(state): State<AppState>,
+    axum::extract::Query(params): axum::extract::Query<std::collections::HashMap<String, String>>,
+) -> Result<Json<Value>, ApiError> {
+    let valid = match params.get("valid").map(String::as_str) {
+        None => None,
+        Some("true") => Some(true),
+        Some("false") => Some(false),
+        Some(_) => return Err(ApiError::validation("valid must be true or false".to_string())),
+    };
+    let tokens = state
+        .services
+        .registration_token_service
+        .list_tokens(valid)
+        .await?;
+
+    Ok(Json(json!({
+        "registration_tokens": tokens.iter().map(|token| token.to_json()).collect::<Vec<_>>(),
+    })))
+}
+
+#[axum::debug_handler]
+pub async fn get_registration_token(
+    _admin: AdminUser,
+    State(state): State<AppState>,
+    Path(token): Path<String>,
+) -> Result<Json<Value>, ApiError> {
+    let token = state
+        .services
+        .registration_token_service
+        .get_token(&token)
+        .await?;
+    Ok(Json(token.to_json()))
+}
+
+/// Creates a token, generating a random one of `length` characters unless `token` is given.
+#[axum::debug_handler]
+pub async fn create_registration_token(
+    admin: AdminUser,
+    State(state): State<AppState>,
+    Json(body): Json<Value>,
+) -> Result<Json<Value>, ApiError> {
+    let token = match body.get("token") {
+        None => None,
+        Some(token) => Some(
+            token
+                .as_str()
+                .ok_or_else(|| ApiError::validation("token must be a string".to_string()))?
+                .to_string(),
+        ),
+    };
+    let length = match body.get("length") {
+        None => None,
+        Some(length) => Some(
+            length
+                .as_u64()
+                .ok_or_else(|| ApiError::validation("length must be an integer".to_string()))?
+                as usize,
+        ),
+    };
+    let new_token = crate::services::NewRegistrationToken {
+        token,
+        length,
+        uses_allowed: registration_token_uses_allowed(&body)?.flatten(),
+        expiry_time: registration_token_limit(&body, "expiry_time")?.flatten(),
+    };
+
+    let token = state
+        .services
+        .registration_token_service
+        .create_token(new_token, &admin.user_id)
+        .await?;
+    Ok(Json(token.to_json()))
+}
+
+/// Changes `uses_allowed` and `expiry_time`; fields set to null lift the limit.
+#[axum::debug_handler]
+pub async fn update_registration_token(
+    _admin: AdminUser,
+    State(state): State<AppState>,
+    Path(token): Path<String>,
+    Json(body): Json<Value>,
+) -> Result<Json<Value>, ApiError> {
+    let update = crate::storage::RegistrationTokenUpdate {
+        uses_allowed: registration_token_uses_allowed(&body)?,
+        expiry_time: registration_token_limit(&body, "expiry_time")?,
+    };
+
+    let token = state
+        .services
+        .registration_token_service
+        .update_token(&token, update)
+        .await?;
+    Ok(Json(token.to_json()))
+}
+
+#[axum::debug_handler]
+pub async fn delete_registration_token(
+    _admin: AdminUser,
+    State(state): State<AppState>,
+    Path(token): Path<String>,
+) -> Result<Json<Value>, ApiError> {
+    state
+        .services
+        .registration_token_service
+        .delete_token(&token)
+        .await?;
+    Ok(Json(json!({})))
+}
@@ -1,4 +1,5 @@
-use super::{AppState, AuthenticatedUser};
+use super::{finish_ui_auth, require_ui_auth, AppState, AuthenticatedUser};
+use crate::services::UiAuthAction;
 use crate::ApiError;
 use crate::web::routes::MatrixJson;
 use axum::{
@@ -43,11 +44,12 @@ async fn upload_keys(
         .clone()
         .ok_or_else(|| ApiError::bad_request("Device ID required".to_string()))?;
 
+    let device_keys_changed = body.get("device_keys").is_some();
     let request = crate::e2ee::device_keys::KeyUploadRequest {
-        device_keys: if body.get("device_keys").is_some() {
+        device_keys: if device_keys_changed {
             Some(crate::e2ee::device_keys::DeviceKeys {
                 user_id: auth_user.user_id.clone(),
-                device_id,
+                device_id: device_id.clone(),
                 algorithms: vec!["m.olm.v1.curve25519-aes-sha2".to_string()],
                 keys: body["device_keys"]["keys"].clone(),
                 signatures: body["device_keys"]["signatures"].clone(),
@@ -64,14 +66,63 @@ async fn upload_keys(
     let response = state
         .services
         .device_keys_service
-        .upload_keys(request)
+        .upload_keys_for_device(&auth_user.user_id, &device_id, request)
         .await?;
 
+    if let Some(fallback_keys) = body.get("fallback_keys") {
+        state
+            .services
+            .device_keys_service
+            .upload_fallback_keys(&auth_user.user_id, &device_id, fallback_keys)
+            .await?;
+    }
+
+    if device_keys_changed {
+        notify_device_list_change(&state, &auth_user.user_id, &device_id, &body["device_keys"]).await;
+    }
+
     Ok(Json(serde_json::json!({
         "one_time_key_counts": response.one_time_key_counts
     })))
 }
 
+/// Wakes the /sync of everyone sharing a room with `user_id` so they pick up the new keys,
+/// and sends the update to the other servers they share rooms with.
+async fn notify_device_list_change(state: &AppState, user_id: &str, device_id: &str, keys: &Value) {
+    let content = serde_json::json!({
+        "user_id": user_id,
+        "device_id": device_id,
+        "keys": keys,
+        "stream_id": chrono::Utc::now().timestamp_millis(),
+        "prev_id": []
+    });
+    if let Err(e) = state
+        .services
+        .federation_sender
+        .queue_user_edu(user_id, "m.device_list_update", content)
+        .await
+    {
+        ::tracing::warn!("Failed to queue device list update for federation: {}", e);
+    }
+
+    let notifier = &state.services.notifier;
+    let user_ids = [user_id.to_string()];
+    notifier
+        .notify_users(crate::services::NotifierStream::DeviceLists, &user_ids)
+        .await;
+
+    match state.services.member_storage.get_joined_rooms(user_id).await {
+        Ok(room_ids) => {
+            for room_id in room_ids {
+                notifier
+                    .notify_room(crate::services::NotifierStream::DeviceLists, &room_id, &[])
+                    .await;
+            }
+        }
+        Err(e) => ::tracing::warn!("Failed to notify device list change for {}: {}", user_id, e),
+    }
+}
+
 #[axum::debug_handler]
 async fn query_keys(
     State(state): State<AppState>,
@@ -167,7 +218,7 @@ async fn room_key_distribution(
 async fn send_to_device(
     State(state): State<AppState>,
     auth_user: AuthenticatedUser,
-    Path((_event_type, _transaction_id)): Path<(String, String)>,
+    Path((event_type, _transaction_id)): Path<(String, String)>,
     MatrixJson(body): MatrixJson<Value>,
 ) -> Result<Json<Value>, crate::error::ApiError> {
     let messages = body.get("messages").ok_or_else(|| {
@@ -177,7 +228,7 @@ async fn send_to_device(
     state
         .services
         .to_device_service
-        .send_messages(&auth_user.user_id, messages)
+        .send_messages(&auth_user.user_id, &event_type, messages)
         .await?;
 
     Ok(Json(serde_json::json!({})))
@@ -230,6 +281,25 @@ async fn upload_device_signing_keys(
     auth_user: AuthenticatedUser,
     MatrixJson(body): MatrixJson<Value>,
 ) -> Result<Json<Value>, crate::error::ApiError> {
+    // Setting up cross-signing needs no UIA; replacing existing keys does
+    let has_master_key: bool = sqlx::query_scalar(
+        "SELECT EXISTS (SELECT 1 FROM cross_signing_keys WHERE user_id = $1 AND key_type = 'master')",
+    )
+    .bind(&auth_user.user_id)
+    .fetch_one(&*state.services.user_storage.pool)
+    .await?;
+    let ui_auth_session = if has_master_key {
+        require_ui_auth(
+            &state,
+            &auth_user,
+            UiAuthAction::UploadCrossSigningKeys,
+            Some(&body),
+        )
+        .await?
+    } else {
+        None
+    };
+
     let master_key = body.get("master_key").cloned();
     let self_signing_key = body.get("self_signing_key").cloned();
     let user_signing_key = body.get("user_signing_key").cloned();
@@ -287,5 +357,6 @@ async fn upload_device_signing_keys(
         .ok();
     }
 
+    finish_ui_auth(&state, &auth_user, ui_auth_session).await?;
     Ok(Json(serde_json::json!({})))
 }
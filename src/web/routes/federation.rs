@@ -1,7 +1,10 @@
 use crate::common::*;
+use crate::federation::pdu;
+use crate::federation::event_builder::{self, decode_base64_32, DEFAULT_ROOM_VERSION};
+use crate::federation::{AuthState, EventAuthChain, EventBuilder, MembershipHandshake};
 use crate::web::routes::AppState;
 use axum::{
-    extract::{Json, Path, State},
+    extract::{Json, Path, RawQuery, State},
     middleware,
     routing::{get, post, put},
     Router,
@@ -31,6 +34,12 @@ pub fn create_federation_router(state: AppState) -> Router<AppState> {
         .route(
             "/_matrix/federation/v1/room/{room_id}/{event_id}",
             get(get_room_event),
+        )
+        // Identity servers have no server keys to sign requests with; each invite they pass on
+        // must carry the identity server's signature instead
+        .route(
+            "/_matrix/federation/v1/3pid/onbind",
+            post(three_pid_onbind),
         );
 
     let protected = Router::new()
@@ -50,10 +59,6 @@ pub fn create_federation_router(state: AppState) -> Router<AppState> {
             "/_matrix/federation/v1/room_auth/{room_id}",
             get(get_room_auth),
         )
-        .route(
-            "/_matrix/federation/v1/knock/{room_id}/{user_id}",
-            get(knock_room),
-        )
         .route(
             "/_matrix/federation/v1/thirdparty/invite",
             post(thirdparty_invite),
@@ -62,6 +67,10 @@ pub fn create_federation_router(state: AppState) -> Router<AppState> {
             "/_matrix/federation/v1/get_joining_rules/{room_id}",
             get(get_joining_rules),
         )
+        .route(
+            "/_matrix/federation/v1/hierarchy/{room_id}",
+            get(get_space_hierarchy),
+        )
         .route(
             "/_matrix/federation/v2/invite/{room_id}/{event_id}",
             put(invite_v2),
@@ -86,6 +95,22 @@ pub fn create_federation_router(state: AppState) -> Router<AppState> {
             "/_matrix/federation/v1/send_leave/{room_id}/{event_id}",
             put(send_leave),
         )
+        .route(
+            "/_matrix/federation/v2/send_join/{room_id}/{event_id}",
+            put(send_join_v2),
+        )
+        .route(
+            "/_matrix/federation/v2/send_leave/{room_id}/{event_id}",
+            put(send_leave_v2),
+        )
+        .route(
+            "/_matrix/federation/v1/make_knock/{room_id}/{user_id}",
+            get(make_knock),
+        )
+        .route(
+            "/_matrix/federation/v1/send_knock/{room_id}/{event_id}",
+            put(send_knock),
+        )
         .route(
             "/_matrix/federation/v1/invite/{room_id}/{event_id}",
             put(invite),
@@ -104,6 +129,10 @@ pub fn create_federation_router(state: AppState) -> Router<AppState> {
             "/_matrix/federation/v1/state_ids/{room_id}",
             get(get_state_ids),
         )
+        .route(
+            "/_matrix/federation/v1/query/directory",
+            get(room_alias_query),
+        )
         .route(
             "/_matrix/federation/v1/query/directory/room/{room_id}",
             get(room_directory_query),
@@ -128,10 +157,6 @@ pub fn create_federation_router(state: AppState) -> Router<AppState> {
             "/_matrix/federation/v1/on_bind_third_party_invite/{room_id}",
             put(on_bind_third_party_invite),
         )
-        .route(
-            "/_matrix/federation/v1/3pid/onbind",
-            post(three_pid_onbind),
-        )
         .route(
             "/_matrix/federation/v1/sendToDevice/{txn_id}",
             put(send_to_device),
@@ -162,9 +187,11 @@ async fn federation_discovery(State(state): State<AppState>) -> Json<Value> {
         "capabilities": {
             "m.change_password": true,
             "m.room_versions": {
-                "1": {
-                    "status": "stable"
-                }
+                "default": DEFAULT_ROOM_VERSION,
+                "available": pdu::SUPPORTED_ROOM_VERSIONS
+                    .iter()
+                    .map(|version| (version.to_string(), json!("stable")))
+                    .collect::<serde_json::Map<String, Value>>()
             }
         }
     }))
@@ -202,39 +229,6 @@ async fn get_room_members(
     })))
 }
 
-async fn knock_room(
-    State(state): State<AppState>,
-    Path((room_id, user_id)): Path<(String, String)>,
-) -> Result<Json<Value>, ApiError> {
-    let event_id = format!(
-        "${}",
-        crate::common::crypto::generate_event_id(&state.services.server_name)
-    );
-
-    let params = crate::storage::event::CreateEventParams {
-        event_id: event_id.clone(),
-        room_id: room_id.clone(),
-        user_id: user_id.clone(),
-        event_type: "m.room.member".to_string(),
-        content: json!({"membership": "knock"}),
-        state_key: Some(user_id.clone()),
-        origin_server_ts: chrono::Utc::now().timestamp_millis(),
-    };
-
-    state
-        .services
-        .event_storage
-        .create_event(params, None)
-        .await
-        .map_err(|e| ApiError::internal(format!("Failed to create knock event: {}", e)))?;
-
-    Ok(Json(json!({
-        "event_id": event_id,
-        "room_id": room_id,
-        "state": "knocking"
-    })))
-}
-
 async fn thirdparty_invite(
     State(state): State<AppState>,
     Json(body): Json<Value>,
@@ -252,10 +246,7 @@ async fn thirdparty_invite(
         .and_then(|v| v.as_str())
         .ok_or_else(|| ApiError::bad_request("sender required".to_string()))?;
 
-    let event_id = format!(
-        "${}",
-        crate::common::crypto::generate_event_id(&state.services.server_name)
-    );
+    let event_id = crate::common::crypto::generate_event_id(&state.services.server_name);
 
     let params = crate::storage::event::CreateEventParams {
         event_id: event_id.clone(),
@@ -275,7 +266,7 @@ async fn thirdparty_invite(
         origin_server_ts: chrono::Utc::now().timestamp_millis(),
     };
 
-    state
+    let event = state
         .services
         .event_storage
         .create_event(params, None)
@@ -283,7 +274,7 @@ async fn thirdparty_invite(
         .map_err(|e| ApiError::internal(format!("Failed to create invite event: {}", e)))?;
 
     Ok(Json(json!({
-        "event_id": event_id,
+        "event_id": event.event_id,
         "room_id": room_id,
         "state": "invited"
     })))
@@ -313,6 +304,29 @@ async fn get_joining_rules(
     }
 }
 
+#[derive(Debug, serde::Deserialize)]
+struct HierarchyParams {
+    #[serde(default)]
+    suggested_only: bool,
+}
+
+async fn get_space_hierarchy(
+    State(state): State<AppState>,
+    headers: axum::http::HeaderMap,
+    Path(room_id): Path<String>,
+    axum::extract::Query(params): axum::extract::Query<HierarchyParams>,
+) -> Result<Json<Value>, ApiError> {
+    let origin = crate::web::middleware::federation_request_origin(&headers)
+        .ok_or_else(|| ApiError::unauthorized("Missing X-Matrix origin".to_string()))?;
+    Ok(Json(
+        state
+            .services
+            .space_manager
+            .get_federation_hierarchy(&origin, &room_id, params.suggested_only)
+            .await?,
+    ))
+}
+
 async fn get_joined_room_members(
     State(state): State<AppState>,
     Path(room_id): Path<String>,
@@ -413,119 +427,241 @@ async fn get_room_auth(
     })))
 }
 
+/// Receives an invite for one of our users: checks the inviting server signed it, counter-signs
+/// it and records the invite, creating the room from the stripped state if it is new to us.
 async fn invite_v2(
     State(state): State<AppState>,
     Path((room_id, event_id)): Path<(String, String)>,
+    headers: axum::http::HeaderMap,
     Json(body): Json<Value>,
 ) -> Result<Json<Value>, ApiError> {
-    let origin = body
-        .get("origin")
-        .and_then(|v| v.as_str())
-        .ok_or_else(|| ApiError::bad_request("Origin required".to_string()))?;
-
     let event = body
         .get("event")
+        .filter(|event| event.is_object())
+        .cloned()
         .ok_or_else(|| ApiError::bad_request("Event required".to_string()))?;
+    let room_version = body.get("room_version").and_then(|v| v.as_str()).unwrap_or("1");
+    let invite_room_state: Vec<Value> = body
+        .get("invite_room_state")
+        .and_then(|v| v.as_array())
+        .cloned()
+        .unwrap_or_default();
 
-    let sender = event.get("sender").and_then(|v| v.as_str()).unwrap_or("");
-    let state_key = event
-        .get("state_key")
-        .and_then(|v| v.as_str())
-        .unwrap_or("");
-    let content = event.get("content").cloned().unwrap_or(json!({}));
+    let event = accept_invite(&state, &room_id, &event_id, &headers, event, room_version, invite_room_state).await?;
+    Ok(Json(json!({ "event": event })))
+}
 
+/// v1 `invite`, used by servers for room versions 1 and 2: the body is the invite event with
+/// the stripped room state in its `unsigned`, and the response is the `[200, {event}]` pair.
+async fn invite(
+    State(state): State<AppState>,
+    Path((room_id, event_id)): Path<(String, String)>,
+    headers: axum::http::HeaderMap,
+    Json(event): Json<Value>,
+) -> Result<Json<Value>, ApiError> {
+    if !event.is_object() {
+        return Err(ApiError::bad_request("Event required".to_string()));
+    }
+    // v1 carries no room version: rooms we know keep theirs, unknown ones are at most version 2,
+    // whose event format matches version 1.
+    let room_version = state
+        .services
+        .event_storage
+        .get_room_version(&room_id)
+        .await
+        .map_err(|e| ApiError::internal(format!("Failed to load room version: {}", e)))?;
+    let invite_room_state: Vec<Value> = event["unsigned"]
+        .get("invite_room_state")
+        .and_then(|v| v.as_array())
+        .cloned()
+        .unwrap_or_default();
+
+    let event = accept_invite(&state, &room_id, &event_id, &headers, event, &room_version, invite_room_state).await?;
+    Ok(Json(json!([200, { "event": event }])))
+}
+
+/// Checks an invite of one of our users sent by the inviter's server, counter-signs it and
+/// records it, creating the room from the stripped state when we do not know it. Returns the
+/// counter-signed event.
+#[allow(clippy::too_many_arguments)]
+async fn accept_invite(
+    state: &AppState,
+    room_id: &str,
+    event_id: &str,
+    headers: &axum::http::HeaderMap,
+    mut event: Value,
+    room_version: &str,
+    invite_room_state: Vec<Value>,
+) -> Result<Value, ApiError> {
+    if !pdu::is_supported_room_version(room_version) {
+        return Err(ApiError::incompatible_room_version(format!(
+            "Room version {} is not supported",
+            room_version
+        )));
+    }
+
+    let server_name = state.services.server_name.clone();
+    let sender = event["sender"].as_str().unwrap_or_default().to_string();
+    let invitee = event["state_key"].as_str().unwrap_or_default().to_string();
+    if event["type"] != "m.room.member"
+        || event["content"]["membership"] != "invite"
+        || event["room_id"].as_str() != Some(room_id)
+    {
+        return Err(ApiError::bad_request("Not an invite to this room".to_string()));
+    }
+    if event_id_of(&event, room_version).as_deref() != Some(event_id) {
+        return Err(ApiError::bad_request("Event ID does not match the event".to_string()));
+    }
+    let origin = pdu::server_name_of(&sender).unwrap_or_default();
+    if let Some(authenticated) = crate::web::middleware::federation_request_origin(headers) {
+        if authenticated != origin {
+            return Err(ApiError::forbidden("Invite was not sent by the inviter's server".to_string()));
+        }
+    }
+    if pdu::server_name_of(&invitee) != Some(server_name.as_str()) {
+        return Err(ApiError::bad_request(format!("{} is not a user of this server", invitee)));
+    }
+    let invitee_exists = state
+        .services
+        .user_storage
+        .user_exists(&invitee)
+        .await
+        .map_err(|e| ApiError::internal(format!("Failed to check user existence: {}", e)))?;
+    if !invitee_exists {
+        return Err(ApiError::not_found(format!("User {} not found", invitee)));
+    }
+    verify_server_signature(state, &event, origin, &pdu::signing_bytes(&event, room_version))
+        .await
+        .map_err(ApiError::forbidden)?;
+
+    let signing_key = state
+        .services
+        .event_storage
+        .signing_key()
+        .ok_or_else(|| ApiError::internal("No federation signing key is configured".to_string()))?;
+    signing_key.sign_event(&mut event, room_version);
+
+    let known_room = state
+        .services
+        .room_storage
+        .room_exists(room_id)
+        .await
+        .map_err(|e| ApiError::internal(format!("Failed to check room: {}", e)))?;
+    if !known_room {
+        create_remote_room(state, room_id, room_version, &invite_room_state).await?;
+    }
+
+    let mut stored = event.clone();
+    stored["event_id"] = json!(event_id);
+    // Shown to the invitee by /sync, as we may hold no state of the room ourselves
+    stored["unsigned"]["invite_room_state"] = json!(invite_room_state);
     let params = crate::storage::event::CreateEventParams {
-        event_id: event_id.clone(),
-        room_id: room_id.clone(),
-        user_id: sender.to_string(),
+        event_id: event_id.to_string(),
+        room_id: room_id.to_string(),
+        user_id: sender.clone(),
         event_type: "m.room.member".to_string(),
-        content,
-        state_key: Some(state_key.to_string()),
-        origin_server_ts: event
-            .get("origin_server_ts")
-            .and_then(|v| v.as_i64())
-            .unwrap_or(chrono::Utc::now().timestamp_millis()),
+        content: stored["content"].clone(),
+        state_key: Some(invitee.clone()),
+        origin_server_ts: stored["origin_server_ts"].as_i64().unwrap_or(0),
     };
-
-    state
+    // Rooms we are in get the invite through the DAG as well; only rooms we are not in keep it
+    // as a historical event
+    let event_storage = &state.services.event_storage;
+    let resident = state
         .services
-        .event_storage
-        .create_event(params, None)
+        .member_storage
+        .has_local_joined_members(room_id)
         .await
-        .map_err(|e| ApiError::internal(format!("Failed to create invite event: {}", e)))?;
+        .map_err(|e| ApiError::internal(format!("Failed to check membership: {}", e)))?;
+    let already_stored = event_storage
+        .get_event(event_id)
+        .await
+        .map_err(|e| ApiError::internal(format!("Failed to check for event: {}", e)))?
+        .is_some();
+    if !resident && !already_stored {
+        event_storage
+            .create_historical_event(params, origin, &stored)
+            .await
+            .map_err(|e| ApiError::internal(format!("Failed to persist invite event: {}", e)))?;
+    }
+    apply_remote_state(state, room_id, &[(event_id.to_string(), stored)]).await?;
 
-    ::tracing::info!(
-        "Processed v2 invite for room {} event {} from {}",
-        room_id,
-        event_id,
-        origin
-    );
+    ::tracing::info!("{} was invited to {} by {}", invitee, room_id, sender);
+    Ok(event)
+}
 
-    Ok(Json(json!({
-        "event_id": event_id
-    })))
+const MAX_TRANSACTION_PDUS: usize = 50;
+
+/// Events one `get_missing_events` request may return when closing a gap.
+const MAX_MISSING_EVENTS: usize = 20;
+
+/// State and auth events fetched one by one for a gap; larger gaps are left open.
+const MAX_GAP_STATE_EVENTS: usize = 100;
+
+/// Backward extremities sent in one `/backfill` request.
+const MAX_BACKFILL_EXTREMITIES: i64 = 5;
+
+/// Where an inbound PDU came from, which decides how much of its history is fetched.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum PduSource {
+    /// Pushed to us in a transaction: missing prev events are fetched, then the state at
+    /// whatever gap remains.
+    Transaction,
+    /// Fetched to fill a gap before another PDU: only the state at a remaining gap is fetched.
+    GapFill,
+    /// History from `/backfill`. Its missing prev events are expected, and it is checked
+    /// against its own auth events only.
+    Backfill,
 }
 
+/// The event ID of a processed PDU, when known, and whether it was accepted.
+type PduOutcome = (Option<String>, Result<(), String>);
+
 async fn send_transaction(
     State(state): State<AppState>,
     Path(txn_id): Path<String>,
+    headers: axum::http::HeaderMap,
     Json(body): Json<Value>,
 ) -> Result<Json<Value>, ApiError> {
-    let _origin = body
+    let origin = body
         .get("origin")
         .and_then(|v| v.as_str())
         .ok_or_else(|| ApiError::bad_request("Origin required".to_string()))?;
+    if let Some(authenticated) = crate::web::middleware::federation_request_origin(&headers) {
+        if authenticated != origin {
+            return Err(ApiError::forbidden(
+                "Transaction origin does not match the signing server".to_string(),
+            ));
+        }
+    }
     let pdus = body
         .get("pdus") // Matrix spec uses 'pdus'
         .or_else(|| body.get("pdu")) // Fallback to 'pdu'
         .and_then(|v| v.as_array())
         .ok_or_else(|| ApiError::bad_request("PDUs required".to_string()))?;
+    if pdus.len() > MAX_TRANSACTION_PDUS {
+        return Err(ApiError::bad_request(format!(
+            "Transactions may contain at most {} PDUs",
+            MAX_TRANSACTION_PDUS
+        )));
+    }
 
-    let mut results = Vec::with_capacity(pdus.len());
+    let mut results = serde_json::Map::new();
 
     for pdu in pdus {
-        let event_id = pdu
-            .get("event_id")
-            .and_then(|v| v.as_str())
-            .map(|s| s.to_string())
-            .unwrap_or_else(|| format!("${}", crate::common::crypto::generate_event_id(_origin)));
-
-        let room_id = pdu.get("room_id").and_then(|v| v.as_str()).unwrap_or("");
-        let user_id = pdu.get("sender").and_then(|v| v.as_str()).unwrap_or("");
-        let event_type = pdu.get("type").and_then(|v| v.as_str()).unwrap_or("");
-        let content = pdu.get("content").cloned().unwrap_or(json!({}));
-        let state_key = pdu
-            .get("state_key")
-            .and_then(|v| v.as_str())
-            .map(|s| s.to_string());
-        let origin_server_ts = pdu
-            .get("origin_server_ts")
-            .and_then(|v| v.as_i64())
-            .unwrap_or(0);
-
-        let params = crate::storage::event::CreateEventParams {
-            event_id: event_id.clone(),
-            room_id: room_id.to_string(),
-            user_id: user_id.to_string(),
-            event_type: event_type.to_string(),
-            content,
-            state_key,
-            origin_server_ts,
+        let (event_id, outcome) = process_inbound_pdu(&state, origin, pdu, PduSource::Transaction).await;
+        let Some(event_id) = event_id else {
+            ::tracing::warn!("Dropping PDU without an event ID from {}: {:?}", origin, outcome);
+            continue;
         };
 
-        match state.services.event_storage.create_event(params, None).await {
-            Ok(_) => {
-                results.push(json!({
-                    "event_id": event_id,
-                    "success": true
-                }));
+        match outcome {
+            Ok(()) => {
+                results.insert(event_id, json!({}));
             }
-            Err(e) => {
-                ::tracing::error!("Failed to persist PDU {}: {}", event_id, e);
-                results.push(json!({
-                    "event_id": event_id,
-                    "error": e.to_string()
-                }));
+            Err(error) => {
+                ::tracing::warn!("Rejected PDU {} from {}: {}", event_id, origin, error);
+                results.insert(event_id, json!({ "error": error }));
             }
         }
     }
@@ -533,131 +669,1491 @@ async fn send_transaction(
     ::tracing::info!(
         "Processed transaction {} from {} with {} PDUs",
         txn_id,
-        _origin,
+        origin,
         pdus.len()
     );
 
     Ok(Json(json!({
-        "results": results
+        "pdus": results
     })))
 }
 
-async fn make_join(
-    State(state): State<AppState>,
-    Path((room_id, user_id)): Path<(String, String)>,
-) -> Result<Json<Value>, ApiError> {
-    let auth_events = state
+/// Runs an inbound PDU through format, signature, hash and auth checks and persists it.
+///
+/// Returns the event ID (when one can be determined) and either success, which includes
+/// soft-failed events, or the reason the PDU was rejected.
+async fn process_inbound_pdu(
+    state: &AppState,
+    origin: &str,
+    pdu: &Value,
+    source: PduSource,
+) -> PduOutcome {
+    let claimed_event_id = pdu
+        .get("event_id")
+        .and_then(|v| v.as_str())
+        .map(|s| s.to_string());
+
+    let Some(room_id) = pdu.get("room_id").and_then(|v| v.as_str()) else {
+        return (claimed_event_id, Err("PDU has no room_id".to_string()));
+    };
+
+    let room = match state.services.room_storage.get_room(room_id).await {
+        Ok(Some(room)) => room,
+        Ok(None) => return (claimed_event_id, Err("Unknown room".to_string())),
+        Err(e) => return (claimed_event_id, Err(format!("Failed to load room: {}", e))),
+    };
+    let room_version = match state.services.event_storage.get_room_version(room_id).await {
+        Ok(room_version) => room_version,
+        Err(e) => return (claimed_event_id, Err(format!("Failed to load room version: {}", e))),
+    };
+    if !pdu::is_supported_room_version(&room_version) {
+        return (
+            claimed_event_id,
+            Err(format!("Unsupported room version {}", room_version)),
+        );
+    }
+
+    let event_id = match pdu::compute_event_id(pdu, &room_version) {
+        Some(computed) => {
+            if claimed_event_id.as_ref().is_some_and(|claimed| *claimed != computed) {
+                return (
+                    claimed_event_id,
+                    Err("Event ID does not match the reference hash".to_string()),
+                );
+            }
+            computed
+        }
+        None => match claimed_event_id {
+            Some(event_id) => event_id,
+            None => return (None, Err("PDU has no event_id".to_string())),
+        },
+    };
+
+    let result = verify_and_persist_pdu(state, origin, &room, &room_version, &event_id, pdu, source).await;
+    (Some(event_id), result)
+}
+
+/// `process_inbound_pdu` for events fetched while handling another PDU, boxed because the
+/// two call each other.
+fn process_fetched_pdu<'a>(
+    state: &'a AppState,
+    origin: &'a str,
+    pdu: &'a Value,
+    source: PduSource,
+) -> std::pin::Pin<Box<dyn std::future::Future<Output = PduOutcome> + Send + 'a>> {
+    Box::pin(process_inbound_pdu(state, origin, pdu, source))
+}
+
+async fn verify_and_persist_pdu(
+    state: &AppState,
+    origin: &str,
+    room: &crate::storage::room::Room,
+    room_version: &str,
+    event_id: &str,
+    pdu: &Value,
+    source: PduSource,
+) -> Result<(), String> {
+    let sender = pdu
+        .get("sender")
+        .and_then(|v| v.as_str())
+        .filter(|s| s.starts_with('@') && pdu::server_name_of(s).is_some())
+        .ok_or("PDU has no valid sender")?;
+    let event_type = pdu
+        .get("type")
+        .and_then(|v| v.as_str())
+        .ok_or("PDU has no type")?;
+    let origin_server_ts = pdu
+        .get("origin_server_ts")
+        .and_then(|v| v.as_i64())
+        .ok_or("PDU has no origin_server_ts")?;
+    if !pdu.get("content").is_some_and(|c| c.is_object()) {
+        return Err("PDU content must be an object".to_string());
+    }
+    let state_key = match pdu.get("state_key") {
+        None => None,
+        Some(Value::String(key)) => Some(key.clone()),
+        Some(_) => return Err("PDU state_key must be a string".to_string()),
+    };
+
+    match state.services.event_storage.get_event(event_id).await {
+        Ok(Some(_)) => return Ok(()),
+        Ok(None) => {}
+        Err(e) => return Err(format!("Failed to check for event: {}", e)),
+    }
+
+    verify_pdu_signatures(state, pdu, room_version).await?;
+
+    // A PDU whose content does not match its hash is processed in redacted form
+    let pdu = if pdu::verify_content_hash(pdu, room_version) {
+        pdu.clone()
+    } else {
+        ::tracing::warn!("Content hash mismatch for {}, redacting", event_id);
+        pdu::redact(pdu, room_version)
+    };
+    let mut event = pdu.clone();
+    event["event_id"] = json!(event_id);
+
+    if source != PduSource::Backfill {
+        fill_gap(state, origin, &room.room_id, room_version, event_id, &event, source).await;
+    }
+
+    let auth_types = EventAuthChain::auth_types_for_event(&event);
+    let (current_state, fallback_state) = load_auth_state(state, room, &auth_types)
+        .await
+        .map_err(|e| e.to_string())?;
+
+    // Rule checks against the auth events the PDU cites
+    let mut cited_state = load_cited_auth_state(state, &room.room_id, &event).await?;
+    // Rooms created before state events were persisted only have their table-backed state
+    for key in &auth_types {
+        if !cited_state.contains_key(key) {
+            if let Some(fallback) = fallback_state.get(key) {
+                cited_state.insert(key.clone(), fallback.clone());
+            }
+        }
+    }
+    EventAuthChain::check_auth_rules(&event, &cited_state)
+        .map_err(|reason| format!("Event failed authorization: {}", reason))?;
+
+    // Passing against its own auth events but not the current state means soft-fail. History
+    // is not held to the current state, and neither is anything while the member list is
+    // incomplete.
+    let partial_state = source != PduSource::Backfill
+        && state
+            .services
+            .room_storage
+            .is_partial_state(&room.room_id)
+            .await
+            .map_err(|e| format!("Failed to check room state: {}", e))?;
+    let soft_failed = match EventAuthChain::check_auth_rules(&event, &current_state) {
+        _ if source == PduSource::Backfill || partial_state => false,
+        Ok(()) => false,
+        Err(reason) => {
+            ::tracing::info!("Soft-failing {} in {}: {}", event_id, room.room_id, reason);
+            true
+        }
+    };
+
+    let params = crate::storage::event::CreateEventParams {
+        event_id: event_id.to_string(),
+        room_id: room.room_id.clone(),
+        user_id: sender.to_string(),
+        event_type: event_type.to_string(),
+        content: event["content"].clone(),
+        state_key,
+        origin_server_ts,
+    };
+    let event_storage = &state.services.event_storage;
+    if source == PduSource::Backfill {
+        // The state before backfilled history is not known, so none is recorded for it
+        event_storage
+            .create_historical_event(params, origin, &event)
+            .await
+            .map_err(|e| format!("Failed to persist event: {}", e))?;
+        return Ok(());
+    }
+    let persisted = event_storage
+        .create_federated_event(params, origin, &event, soft_failed)
+        .await
+        .map_err(|e| format!("Failed to persist event: {}", e))?;
+
+    if !soft_failed {
+        if let ("m.room.member", Some(state_key)) = (event_type, persisted.state_key.as_deref()) {
+            if let Err(e) = state
+                .services
+                .member_storage
+                .apply_member_event(&room.room_id, event_id, sender, state_key, &event["content"])
+                .await
+            {
+                ::tracing::warn!("Failed to record membership from {}: {}", event_id, e);
+            }
+        }
+    }
+    record_pdu_state(state, &room.room_id, room_version, event_id, &event).await;
+    Ok(())
+}
+
+/// Fetches what we are missing before an inbound PDU: its prev events through
+/// `get_missing_events`, then for any still missing the room state at that point through
+/// `/state_ids` and `/event`. Best effort; a gap that cannot be filled is only logged.
+async fn fill_gap(
+    state: &AppState,
+    origin: &str,
+    room_id: &str,
+    room_version: &str,
+    event_id: &str,
+    event: &Value,
+    source: PduSource,
+) {
+    let event_storage = &state.services.event_storage;
+    let prev_events = pdu::referenced_event_ids(event.get("prev_events"));
+    let mut missing = match event_storage.get_unknown_events(&prev_events).await {
+        Ok(missing) => missing,
+        Err(e) => {
+            ::tracing::warn!("Failed to look up prev events of {}: {}", event_id, e);
+            return;
+        }
+    };
+    if missing.is_empty() {
+        return;
+    }
+
+    if source == PduSource::Transaction {
+        ::tracing::info!("Fetching {} missing prev events of {} from {}", missing.len(), event_id, origin);
+        if let Err(e) = fetch_missing_events(state, origin, room_id, room_version, event_id).await {
+            ::tracing::warn!("Failed to fetch missing events before {}: {}", event_id, e);
+        }
+        missing = event_storage.get_unknown_events(&prev_events).await.unwrap_or_default();
+    }
+
+    for prev_event_id in missing {
+        if let Err(e) = fetch_state_at_gap(state, origin, room_id, room_version, &prev_event_id).await {
+            ::tracing::warn!("Failed to fetch state at {} in {}: {}", prev_event_id, room_id, e);
+        }
+    }
+}
+
+/// Asks `origin` for the events between our forward extremities and `event_id`, and processes
+/// them oldest first.
+async fn fetch_missing_events(
+    state: &AppState,
+    origin: &str,
+    room_id: &str,
+    room_version: &str,
+    event_id: &str,
+) -> Result<(), ApiError> {
+    let extremities = state
         .services
         .event_storage
-        .get_state_events(&room_id)
+        .get_forward_extremities(room_id)
         .await
-        .map_err(|e| ApiError::internal(format!("Failed to get auth events: {}", e)))?;
+        .map_err(|e| ApiError::internal(format!("Failed to get forward extremities: {}", e)))?;
+    let min_depth = extremities.iter().map(|(_, depth)| *depth).min().unwrap_or(0);
+    let earliest_events: Vec<String> = extremities.into_iter().map(|(event_id, _)| event_id).collect();
+
+    let pdus = state
+        .services
+        .federation_client
+        .get_missing_events(
+            origin,
+            room_id,
+            &earliest_events,
+            &[event_id.to_string()],
+            MAX_MISSING_EVENTS,
+            min_depth,
+        )
+        .await?;
+
+    for (missing_event_id, pdu) in sort_by_dag_depth(state, pdus, room_version) {
+        let (_, outcome) = process_fetched_pdu(state, origin, &pdu, PduSource::GapFill).await;
+        if let Err(e) = outcome {
+            ::tracing::warn!("Rejected missing event {} from {}: {}", missing_event_id, origin, e);
+        }
+    }
+    Ok(())
+}
+
+/// Fills a gap at `prev_event_id` with the state `origin` reports there: the state and auth
+/// chain events we lack are fetched and stored as history, and the state after the event is
+/// recorded so later events resolve against it.
+async fn fetch_state_at_gap(
+    state: &AppState,
+    origin: &str,
+    room_id: &str,
+    room_version: &str,
+    prev_event_id: &str,
+) -> Result<(), ApiError> {
+    let client = &state.services.federation_client;
+    let event_storage = &state.services.event_storage;
+
+    let (state_ids, auth_chain_ids) = client.get_state_ids(origin, room_id, prev_event_id).await?;
+    let mut wanted: Vec<String> = state_ids.iter().chain(&auth_chain_ids).cloned().collect();
+    wanted.push(prev_event_id.to_string());
+    wanted.sort();
+    wanted.dedup();
+
+    let unknown = event_storage
+        .get_unknown_events(&wanted)
+        .await
+        .map_err(|e| ApiError::internal(format!("Failed to look up events: {}", e)))?;
+    if unknown.len() > MAX_GAP_STATE_EVENTS {
+        return Err(ApiError::internal(format!(
+            "Gap needs {} events, more than the {} fetched individually",
+            unknown.len(),
+            MAX_GAP_STATE_EVENTS
+        )));
+    }
+
+    let mut fetched = Vec::with_capacity(unknown.len());
+    for event_id in &unknown {
+        match client.get_event(origin, event_id).await {
+            Ok(pdu) => fetched.push(pdu),
+            Err(e) => ::tracing::warn!("Failed to fetch {} from {}: {}", event_id, origin, e),
+        }
+    }
+
+    let mut prev_event = None;
+    for (event_id, pdu) in sort_by_dag_depth(state, fetched, room_version) {
+        if !unknown.contains(&event_id) || pdu.get("room_id").and_then(|v| v.as_str()) != Some(room_id) {
+            ::tracing::warn!("Ignoring unexpected event {} from {}", event_id, origin);
+            continue;
+        }
+        match persist_gap_event(state, origin, room_version, &event_id, &pdu).await {
+            Ok(event) if event_id == prev_event_id => prev_event = Some(event),
+            Ok(_) => {}
+            Err(e) => ::tracing::warn!("Rejected gap event {} from {}: {}", event_id, origin, e),
+        }
+    }
+
+    let Some(prev_event) = prev_event else {
+        return Err(ApiError::not_found(format!("Could not fetch {}", prev_event_id)));
+    };
+    let state_before: crate::federation::StateMap = event_storage
+        .get_state_keys(&state_ids)
+        .await
+        .map_err(|e| ApiError::internal(format!("Failed to load state events: {}", e)))?
+        .into_iter()
+        .map(|(event_id, event_type, state_key)| ((event_type, state_key), event_id))
+        .collect();
+    state
+        .services
+        .state_resolution_service
+        .record_state_at_gap(room_id, prev_event_id, &prev_event, state_before)
+        .await?;
+    Ok(())
+}
+
+/// Stores a state or auth event fetched for a gap once its signatures check out. It is
+/// history, so it does not become current state or a forward extremity.
+async fn persist_gap_event(
+    state: &AppState,
+    origin: &str,
+    room_version: &str,
+    event_id: &str,
+    pdu: &Value,
+) -> Result<Value, String> {
+    verify_pdu_signatures(state, pdu, room_version).await?;
+    let mut event = if pdu::verify_content_hash(pdu, room_version) {
+        pdu.clone()
+    } else {
+        pdu::redact(pdu, room_version)
+    };
+    event["event_id"] = json!(event_id);
+
+    let params = crate::storage::event::CreateEventParams {
+        event_id: event_id.to_string(),
+        room_id: event["room_id"].as_str().unwrap_or_default().to_string(),
+        user_id: event["sender"].as_str().ok_or("PDU has no sender")?.to_string(),
+        event_type: event["type"].as_str().ok_or("PDU has no type")?.to_string(),
+        content: event["content"].clone(),
+        state_key: event["state_key"].as_str().map(String::from),
+        origin_server_ts: event["origin_server_ts"].as_i64().unwrap_or(0),
+    };
+    state
+        .services
+        .event_storage
+        .create_historical_event(params, origin, &event)
+        .await
+        .map_err(|e| format!("Failed to persist event: {}", e))?;
+    Ok(event)
+}
+
+/// Pairs fetched PDUs with their event IDs and orders them so each comes after the prev
+/// events it references among them.
+fn sort_by_dag_depth(state: &AppState, pdus: Vec<Value>, room_version: &str) -> Vec<(String, Value)> {
+    sort_by_references(state, pdus, room_version, "prev_events")
+}
 
-    let auth_events_json: Vec<Value> = auth_events
+/// Like `sort_by_dag_depth`, following the references in `field` (`prev_events` or
+/// `auth_events`).
+fn sort_by_references(state: &AppState, pdus: Vec<Value>, room_version: &str, field: &str) -> Vec<(String, Value)> {
+    let mut pdus: Vec<(String, Value)> = pdus
+        .into_iter()
+        .filter_map(|pdu| Some((event_id_of(&pdu, room_version)?, pdu)))
+        .collect();
+
+    let infos: Vec<crate::federation::EventInfo> = pdus
         .iter()
-        .map(|e| {
-            json!({
-                "event_id": e.event_id,
-                "type": e.event_type,
-                "state_key": e.state_key
-            })
+        .map(|(event_id, pdu)| crate::federation::EventInfo {
+            event_id: event_id.clone(),
+            prev_events: pdu.get(field).cloned(),
         })
         .collect();
+    let depths = state.services.event_auth_chain.calculate_event_depth(&infos);
+
+    // Events on a cycle get no depth and go last; the claimed depth breaks ties
+    pdus.sort_by_key(|(event_id, pdu)| {
+        (
+            depths.get(event_id).copied().unwrap_or(i64::MAX),
+            pdu.get("depth").and_then(|d| d.as_i64()).unwrap_or(0),
+        )
+    });
+    pdus
+}
+
+/// The reference-hash event ID of a PDU, or the one it states for room v1/v2.
+fn event_id_of(pdu: &Value, room_version: &str) -> Option<String> {
+    pdu::compute_event_id(pdu, room_version)
+        .or_else(|| pdu.get("event_id").and_then(|v| v.as_str()).map(String::from))
+}
+
+/// Backfills `room_id` from the other servers in it once local history has run out. Returns
+/// how many events were added.
+pub(crate) async fn backfill_room(state: &AppState, room_id: &str, limit: usize) -> Result<usize, ApiError> {
+    let event_storage = &state.services.event_storage;
+    let extremities = event_storage
+        .get_backward_extremities(room_id, MAX_BACKFILL_EXTREMITIES)
+        .await
+        .map_err(|e| ApiError::internal(format!("Failed to get backward extremities: {}", e)))?;
+    if extremities.is_empty() {
+        return Ok(0);
+    }
+    let servers = state
+        .services
+        .member_storage
+        .get_joined_servers(room_id)
+        .await
+        .map_err(|e| ApiError::internal(format!("Failed to get servers in room: {}", e)))?;
+    let room_version = event_storage
+        .get_room_version(room_id)
+        .await
+        .map_err(|e| ApiError::internal(format!("Failed to get room version: {}", e)))?;
+
+    for server in servers {
+        let pdus = match state
+            .services
+            .federation_client
+            .backfill(&server, room_id, &extremities, limit)
+            .await
+        {
+            Ok(pdus) => pdus,
+            Err(e) => {
+                ::tracing::info!("Failed to backfill {} from {}: {}", room_id, server, e);
+                continue;
+            }
+        };
+
+        let mut added = 0;
+        for (event_id, pdu) in sort_by_dag_depth(state, pdus, &room_version) {
+            match process_fetched_pdu(state, &server, &pdu, PduSource::Backfill).await.1 {
+                Ok(()) => added += 1,
+                Err(e) => ::tracing::warn!("Rejected backfilled event {} from {}: {}", event_id, server, e),
+            }
+        }
+        ::tracing::info!("Backfilled {} events in {} from {}", added, room_id, server);
+        return Ok(added);
+    }
+    Ok(0)
+}
+
+/// A membership event signed by us and accepted by a resident server.
+struct SignedMembership {
+    server: String,
+    room_version: String,
+    event_id: String,
+    event: Value,
+    response: Value,
+}
+
+/// Changes `user_id`'s membership in a room this server is not in: asks one of `servers` for
+/// a template, fills in `content`, signs it and hands it back. Servers are tried in order
+/// until one completes the handshake.
+async fn membership_handshake(
+    state: &AppState,
+    handshake: MembershipHandshake,
+    room_id: &str,
+    user_id: &str,
+    servers: &[String],
+    content: &Value,
+) -> Result<SignedMembership, ApiError> {
+    let server_name = &state.services.server_name;
+    let Some(signing_key) = state.services.event_storage.signing_key() else {
+        return Err(ApiError::internal(
+            "No federation signing key is configured to sign membership events".to_string(),
+        ));
+    };
+    let client = &state.services.federation_client;
+
+    let mut last_error = ApiError::not_found(format!(
+        "No server to {} {} through",
+        handshake.membership(),
+        room_id
+    ));
+    for server in servers.iter().filter(|server| *server != server_name) {
+        let (room_version, template) = match client
+            .make_membership_event(server, handshake, room_id, user_id, &pdu::SUPPORTED_ROOM_VERSIONS)
+            .await
+        {
+            Ok(made) => made,
+            Err(e) => {
+                ::tracing::info!("make_{} for {} via {} failed: {}", handshake.membership(), room_id, server, e);
+                last_error = e.into();
+                continue;
+            }
+        };
+        if !pdu::is_supported_room_version(&room_version) {
+            last_error = ApiError::incompatible_room_version(format!(
+                "Room version {} is not supported",
+                room_version
+            ));
+            continue;
+        }
+        let template = match fill_membership_template(template, handshake, room_id, user_id, server_name, content) {
+            Ok(template) => template,
+            Err(e) => {
+                ::tracing::warn!("Bad make_{} template from {}: {}", handshake.membership(), server, e);
+                last_error = ApiError::internal(format!("Invalid template from {}: {}", server, e));
+                continue;
+            }
+        };
+
+        let local_event_id = crate::common::crypto::generate_event_id(server_name);
+        let (event_id, event) = event_builder::finish_event(template, &room_version, &local_event_id, Some(signing_key));
+        match client
+            .send_membership_event(server, handshake, room_id, &event_id, &event)
+            .await
+        {
+            Ok(response) => {
+                return Ok(SignedMembership {
+                    server: server.clone(),
+                    room_version,
+                    event_id,
+                    event,
+                    response,
+                })
+            }
+            Err(e) => {
+                ::tracing::info!("send_{} for {} via {} failed: {}", handshake.membership(), room_id, server, e);
+                last_error = e.into();
+            }
+        }
+    }
+    Err(last_error)
+}
+
+/// Checks a remote template is the membership change asked for, and makes it ours: our
+/// origin and timestamp, and `content` (display name, reason and so on) merged in.
+fn fill_membership_template(
+    mut template: Value,
+    handshake: MembershipHandshake,
+    room_id: &str,
+    user_id: &str,
+    origin: &str,
+    content: &Value,
+) -> Result<Value, String> {
+    let field = |name: &str| template.get(name).and_then(|v| v.as_str());
+    if field("type") != Some("m.room.member") {
+        return Err("not a membership event".to_string());
+    }
+    if field("room_id") != Some(room_id) {
+        return Err("wrong room".to_string());
+    }
+    if field("sender") != Some(user_id) || field("state_key") != Some(user_id) {
+        return Err("wrong user".to_string());
+    }
+    if template["content"]["membership"].as_str() != Some(handshake.membership()) {
+        return Err("wrong membership".to_string());
+    }
+
+    if let (Some(template_content), Some(content)) = (template["content"].as_object_mut(), content.as_object()) {
+        for (key, value) in content.iter().filter(|(key, _)| *key != "membership") {
+            template_content.insert(key.clone(), value.clone());
+        }
+    }
+    template["origin"] = json!(origin);
+    template["origin_server_ts"] = json!(chrono::Utc::now().timestamp_millis());
+    Ok(template)
+}
+
+/// Joins a room this server is not in through one of `servers`: runs the `make_join` /
+/// `send_join` handshake, checks and stores the returned state and auth chain, and records
+/// the join on top of it. Returns the join event ID.
+///
+/// Joins ask for the member list to be left out. When the resident server does so, the room
+/// is marked as having partial state and the full state is fetched in the background.
+pub(crate) async fn remote_join(
+    state: &AppState,
+    room_id: &str,
+    user_id: &str,
+    servers: &[String],
+    content: &Value,
+) -> Result<String, ApiError> {
+    let joined = membership_handshake(state, MembershipHandshake::Join, room_id, user_id, servers, content).await?;
+    let SignedMembership {
+        server,
+        room_version,
+        event_id,
+        event,
+        response,
+    } = joined;
+
+    // Restricted joins come back counter-signed by the resident server
+    let mut event = response
+        .get("event")
+        .filter(|signed| event_id_of(signed, &room_version).as_deref() == Some(event_id.as_str()))
+        .cloned()
+        .unwrap_or(event);
+    event["event_id"] = json!(event_id);
+
+    let state_pdus = response
+        .get("state")
+        .and_then(|v| v.as_array())
+        .cloned()
+        .ok_or_else(|| ApiError::internal(format!("send_join response from {} has no state", server)))?;
+    let auth_chain = response
+        .get("auth_chain")
+        .and_then(|v| v.as_array())
+        .cloned()
+        .unwrap_or_default();
+
+    create_remote_room(state, room_id, &room_version, &state_pdus).await?;
+    let room_state = persist_remote_state(state, &server, room_id, &room_version, state_pdus, auth_chain).await?;
+
+    // The join itself must be allowed by the state it was sent into
+    let auth_types = EventAuthChain::auth_types_for_event(&event);
+    let auth_state: AuthState = room_state
+        .iter()
+        .filter_map(|(_, state_event)| {
+            let key = (
+                state_event["type"].as_str()?.to_string(),
+                state_event["state_key"].as_str()?.to_string(),
+            );
+            auth_types.contains(&key).then(|| (key, state_event.clone()))
+        })
+        .collect();
+    EventAuthChain::check_auth_rules(&event, &auth_state)
+        .map_err(|reason| ApiError::forbidden(format!("Join event failed authorization: {}", reason)))?;
+
+    let params = crate::storage::event::CreateEventParams {
+        event_id: event_id.clone(),
+        room_id: room_id.to_string(),
+        user_id: user_id.to_string(),
+        event_type: "m.room.member".to_string(),
+        content: event["content"].clone(),
+        state_key: Some(user_id.to_string()),
+        origin_server_ts: event["origin_server_ts"].as_i64().unwrap_or(0),
+    };
+    state
+        .services
+        .event_storage
+        .create_federated_event(params, &state.services.server_name, &event, false)
+        .await
+        .map_err(|e| ApiError::internal(format!("Failed to persist join event: {}", e)))?;
+
+    let state_before = state_map_of(&room_state);
+    let mut joined_state = room_state;
+    joined_state.push((event_id.clone(), event.clone()));
+    apply_remote_state(state, room_id, &joined_state).await?;
+    state
+        .services
+        .state_resolution_service
+        .record_state_at_gap(room_id, &event_id, &event, state_before)
+        .await?;
+
+    if response.get("members_omitted").and_then(|v| v.as_bool()) == Some(true) {
+        let mut servers_in_room: Vec<String> = response
+            .get("servers_in_room")
+            .and_then(|v| v.as_array())
+            .map(|servers| servers.iter().filter_map(|s| s.as_str().map(String::from)).collect())
+            .unwrap_or_default();
+        servers_in_room.retain(|s| *s != state.services.server_name && *s != server);
+        servers_in_room.insert(0, server.clone());
+        state
+            .services
+            .room_storage
+            .mark_partial_state(room_id, &event_id, &servers_in_room)
+            .await
+            .map_err(|e| ApiError::internal(format!("Failed to mark partial state: {}", e)))?;
+        spawn_partial_state_sync(state.clone(), room_id.to_string());
+    }
+
+    ::tracing::info!("{} joined {} via {}", user_id, room_id, server);
+    Ok(event_id)
+}
+
+/// Leaves a room this server is not in, which is how invites to remote rooms are rejected,
+/// through `make_leave` / `send_leave`. Returns the leave event ID.
+pub(crate) async fn remote_leave(
+    state: &AppState,
+    room_id: &str,
+    user_id: &str,
+    servers: &[String],
+    content: &Value,
+) -> Result<String, ApiError> {
+    let left = membership_handshake(state, MembershipHandshake::Leave, room_id, user_id, servers, content).await?;
+    persist_own_membership(state, room_id, user_id, &left).await?;
+    ::tracing::info!("{} left {} via {}", user_id, room_id, left.server);
+    Ok(left.event_id)
+}
+
+/// Knocks on a room this server is not in through `make_knock` / `send_knock`. Returns the
+/// knock event ID.
+pub(crate) async fn remote_knock(
+    state: &AppState,
+    room_id: &str,
+    user_id: &str,
+    servers: &[String],
+    content: &Value,
+) -> Result<String, ApiError> {
+    let mut knocked = membership_handshake(state, MembershipHandshake::Knock, room_id, user_id, servers, content).await?;
+
+    // The stripped state describes the room well enough to keep a record of it, and is kept
+    // with the knock to show the knocker until they are let in
+    let knock_room_state = knocked
+        .response
+        .get("knock_room_state")
+        .and_then(|v| v.as_array())
+        .cloned()
+        .unwrap_or_default();
+    create_remote_room(state, room_id, &knocked.room_version, &knock_room_state).await?;
+    knocked.event["unsigned"] = json!({ "knock_room_state": knock_room_state });
+    persist_own_membership(state, room_id, user_id, &knocked).await?;
+    ::tracing::info!("{} knocked on {} via {}", user_id, room_id, knocked.server);
+    Ok(knocked.event_id)
+}
+
+/// Stores a membership event of ours for a room whose DAG we do not follow.
+async fn persist_own_membership(
+    state: &AppState,
+    room_id: &str,
+    user_id: &str,
+    signed: &SignedMembership,
+) -> Result<(), ApiError> {
+    let mut event = signed.event.clone();
+    event["event_id"] = json!(signed.event_id);
+    let params = crate::storage::event::CreateEventParams {
+        event_id: signed.event_id.clone(),
+        room_id: room_id.to_string(),
+        user_id: user_id.to_string(),
+        event_type: "m.room.member".to_string(),
+        content: event["content"].clone(),
+        state_key: Some(user_id.to_string()),
+        origin_server_ts: event["origin_server_ts"].as_i64().unwrap_or(0),
+    };
+    state
+        .services
+        .event_storage
+        .create_historical_event(params, &state.services.server_name, &event)
+        .await
+        .map_err(|e| ApiError::internal(format!("Failed to persist membership event: {}", e)))?;
+    apply_remote_state(state, room_id, &[(signed.event_id.clone(), event)]).await
+}
+
+/// Invites a user on another server to a room of ours through the v2 `invite` API. The invite
+/// is only stored once the invitee's server has counter-signed it. Returns the event ID.
+pub(crate) async fn remote_invite(
+    state: &AppState,
+    room_id: &str,
+    inviter: &str,
+    invitee: &str,
+    content: &Value,
+) -> Result<String, ApiError> {
+    let destination = pdu::server_name_of(invitee)
+        .ok_or_else(|| ApiError::bad_request(format!("Invalid user ID: {}", invitee)))?;
+    let room = state
+        .services
+        .room_storage
+        .get_room(room_id)
+        .await
+        .map_err(|e| ApiError::internal(format!("Failed to get room: {}", e)))?
+        .ok_or_else(|| ApiError::not_found("Room not found".to_string()))?;
+    let event_storage = &state.services.event_storage;
+
+    let params = crate::storage::event::CreateEventParams {
+        event_id: crate::common::crypto::generate_event_id(&state.services.server_name),
+        room_id: room_id.to_string(),
+        user_id: inviter.to_string(),
+        event_type: "m.room.member".to_string(),
+        content: content.clone(),
+        state_key: Some(invitee.to_string()),
+        origin_server_ts: chrono::Utc::now().timestamp_millis(),
+    };
+    let (event_id, event) = event_storage
+        .build_event(params)
+        .await
+        .map_err(|e| ApiError::internal(format!("Failed to build invite event: {}", e)))?;
+
+    let auth_types = EventAuthChain::auth_types_for_event(&event);
+    let (current_state, _) = load_auth_state(state, &room, &auth_types).await?;
+    EventAuthChain::check_auth_rules(&event, &current_state)
+        .map_err(|reason| ApiError::forbidden(format!("Invite is not allowed: {}", reason)))?;
+
+    let invite_room_state = invite_room_state(state, &room, inviter).await?;
+    let signed = state
+        .services
+        .federation_client
+        .send_invite(destination, room_id, &event_id, &room.version, &event, &invite_room_state)
+        .await?;
+
+    if event_id_of(&signed, &room.version).as_deref() != Some(event_id.as_str()) {
+        return Err(ApiError::internal(format!("{} altered the invite event", destination)));
+    }
+    verify_server_signature(state, &signed, destination, &pdu::signing_bytes(&signed, &room.version))
+        .await
+        .map_err(|e| ApiError::internal(format!("Invite was not counter-signed: {}", e)))?;
+
+    event_storage
+        .create_built_event(&event_id, &signed)
+        .await
+        .map_err(|e| ApiError::internal(format!("Failed to persist invite event: {}", e)))?;
+    state
+        .services
+        .member_storage
+        .apply_member_event(room_id, &event_id, inviter, invitee, &signed["content"])
+        .await
+        .map_err(|e| ApiError::internal(format!("Failed to record membership: {}", e)))?;
+
+    ::tracing::info!("{} invited {} to {}", inviter, invitee, room_id);
+    Ok(event_id)
+}
+
+/// The stripped state sent with an invite.
+async fn invite_room_state(
+    state: &AppState,
+    room: &crate::storage::room::Room,
+    inviter: &str,
+) -> Result<Vec<Value>, ApiError> {
+    let mut keys: Vec<(String, String)> = crate::services::STRIPPED_STATE_TYPES
+        .iter()
+        .map(|event_type| (event_type.to_string(), String::new()))
+        .collect();
+    keys.push(("m.room.member".to_string(), inviter.to_string()));
+    let (current_state, _) = load_auth_state(state, room, &keys).await?;
+
+    Ok(keys
+        .iter()
+        .filter_map(|key| current_state.get(key))
+        .map(|event| {
+            json!({
+                "type": event["type"],
+                "state_key": event["state_key"],
+                "sender": event["sender"],
+                "content": event["content"],
+            })
+        })
+        .collect())
+}
+
+/// Turns a third-party invite whose address has been bound into a membership invite for the
+/// bound user. The signed binding is checked against the room's `m.room.third_party_invite`
+/// event, whose public key must still be valid at the identity server. Returns the event ID.
+pub(crate) async fn complete_third_party_invite(
+    state: &AppState,
+    room_id: &str,
+    event: &Value,
+) -> Result<String, ApiError> {
+    let field = |name: &str| {
+        event
+            .get(name)
+            .and_then(|v| v.as_str())
+            .ok_or_else(|| ApiError::bad_request(format!("Missing {}", name)))
+    };
+    let sender = field("sender")?;
+    let invitee = field("state_key")?;
+    if field("type")? != "m.room.member" || event.get("room_id").and_then(|v| v.as_str()) != Some(room_id) {
+        return Err(ApiError::bad_request("Not a member event for this room".to_string()));
+    }
+    if pdu::server_name_of(sender) != Some(state.services.server_name.as_str()) {
+        return Err(ApiError::forbidden(format!("{} is not a user of this server", sender)));
+    }
+    let room = state
+        .services
+        .room_storage
+        .get_room(room_id)
+        .await
+        .map_err(|e| ApiError::internal(format!("Failed to get room: {}", e)))?
+        .ok_or_else(|| ApiError::not_found("Room not found".to_string()))?;
+
+    let mut content = event.get("content").filter(|c| c.is_object()).cloned().unwrap_or(json!({}));
+    content["membership"] = json!("invite");
+    let candidate = json!({
+        "type": "m.room.member",
+        "room_id": room_id,
+        "sender": sender,
+        "state_key": invitee,
+        "content": content,
+    });
+    let auth_types = EventAuthChain::auth_types_for_event(&candidate);
+    let (current_state, _) = load_auth_state(state, &room, &auth_types).await?;
+    EventAuthChain::check_auth_rules(&candidate, &current_state)
+        .map_err(|reason| ApiError::forbidden(format!("Third-party invite is not allowed: {}", reason)))?;
+
+    check_third_party_invite_key(state, &current_state, &content).await?;
+
+    let params = crate::storage::event::CreateEventParams {
+        event_id: crate::common::crypto::generate_event_id(&state.services.server_name),
+        room_id: room_id.to_string(),
+        user_id: sender.to_string(),
+        event_type: "m.room.member".to_string(),
+        content: content.clone(),
+        state_key: Some(invitee.to_string()),
+        origin_server_ts: chrono::Utc::now().timestamp_millis(),
+    };
+    let created = state
+        .services
+        .event_storage
+        .create_event(params, None)
+        .await
+        .map_err(|e| ApiError::internal(format!("Failed to create invite event: {}", e)))?;
+    state
+        .services
+        .member_storage
+        .apply_member_event(room_id, &created.event_id, sender, invitee, &content)
+        .await
+        .map_err(|e| ApiError::internal(format!("Failed to record membership: {}", e)))?;
+
+    ::tracing::info!("Exchanged third-party invite in {} for {}", room_id, invitee);
+    Ok(created.event_id)
+}
+
+/// Checks that the public key of the `m.room.third_party_invite` event named by the invite's
+/// signed token is still valid at the identity server that issued it.
+async fn check_third_party_invite_key(
+    state: &AppState,
+    current_state: &AuthState,
+    content: &Value,
+) -> Result<(), ApiError> {
+    let token = content["third_party_invite"]["signed"]["token"].as_str().unwrap_or_default();
+    let invite_key = ("m.room.third_party_invite".to_string(), token.to_string());
+    let invite_content = current_state
+        .get(&invite_key)
+        .map(|invite| invite["content"].clone())
+        .ok_or_else(|| ApiError::forbidden("Unknown third-party invite token".to_string()))?;
+    if let (Some(url), Some(public_key)) = (
+        invite_content["key_validity_url"].as_str(),
+        invite_content["public_key"].as_str(),
+    ) {
+        if !state.services.identity_service.is_public_key_valid(url, public_key).await {
+            return Err(ApiError::forbidden("Third-party invite key is no longer valid".to_string()));
+        }
+    }
+    Ok(())
+}
+
+/// Creates the local record of a remote room from the `m.room.create` event in `state_events`,
+/// after checking it states the room version the resident server gave.
+async fn create_remote_room(
+    state: &AppState,
+    room_id: &str,
+    room_version: &str,
+    state_events: &[Value],
+) -> Result<(), ApiError> {
+    let state_event = |event_type: &str| {
+        state_events
+            .iter()
+            .find(|event| event["type"] == event_type && event["state_key"] == "")
+    };
+
+    let create = state_event("m.room.create");
+    if let Some(create) = create {
+        let stated_version = event_builder::room_version_from_create(&create["content"]);
+        if stated_version != room_version {
+            return Err(ApiError::internal(format!(
+                "Room {} is version {}, not {} as the resident server said",
+                room_id, stated_version, room_version
+            )));
+        }
+    }
+    // Room v11 dropped `creator` in favour of the create event's sender
+    let creator = create
+        .and_then(|create| create["content"]["creator"].as_str().or(create["sender"].as_str()))
+        .unwrap_or_default();
+    let join_rule = state_event("m.room.join_rules")
+        .and_then(|event| event["content"]["join_rule"].as_str())
+        .unwrap_or("invite");
+
+    state
+        .services
+        .room_storage
+        .create_remote_room(room_id, creator, join_rule, room_version)
+        .await
+        .map_err(|e| ApiError::internal(format!("Failed to create room: {}", e)))
+}
+
+/// Checks the state and auth chain a resident server returned and stores the events that pass
+/// as history. Each event must be signed by its servers, and allowed by the auth events it
+/// cites, which are checked first. Returns the accepted state events with their IDs.
+async fn persist_remote_state(
+    state: &AppState,
+    origin: &str,
+    room_id: &str,
+    room_version: &str,
+    state_pdus: Vec<Value>,
+    auth_chain: Vec<Value>,
+) -> Result<Vec<(String, Value)>, ApiError> {
+    let state_ids: std::collections::HashSet<String> = state_pdus
+        .iter()
+        .filter_map(|pdu| event_id_of(pdu, room_version))
+        .collect();
+    let pdus: Vec<Value> = state_pdus.into_iter().chain(auth_chain).collect();
+
+    let mut seen = std::collections::HashSet::new();
+    let mut accepted = Vec::new();
+    for (event_id, pdu) in sort_by_references(state, pdus, room_version, "auth_events") {
+        if !seen.insert(event_id.clone()) {
+            continue;
+        }
+        if pdu.get("room_id").and_then(|v| v.as_str()) != Some(room_id) {
+            ::tracing::warn!("Ignoring event {} from another room in state from {}", event_id, origin);
+            continue;
+        }
+        let event = match persist_remote_state_event(state, origin, room_id, room_version, &event_id, &pdu).await {
+            Ok(event) => event,
+            Err(e) => {
+                ::tracing::warn!("Rejected state event {} from {}: {}", event_id, origin, e);
+                continue;
+            }
+        };
+        if state_ids.contains(&event_id) && event["state_key"].is_string() {
+            accepted.push((event_id, event));
+        }
+    }
+
+    if !accepted.iter().any(|(_, event)| event["type"] == "m.room.create") {
+        return Err(ApiError::internal(format!(
+            "State for {} from {} has no valid create event",
+            room_id, origin
+        )));
+    }
+    Ok(accepted)
+}
+
+async fn persist_remote_state_event(
+    state: &AppState,
+    origin: &str,
+    room_id: &str,
+    room_version: &str,
+    event_id: &str,
+    pdu: &Value,
+) -> Result<Value, String> {
+    let mut event = if pdu::verify_content_hash(pdu, room_version) {
+        pdu.clone()
+    } else {
+        pdu::redact(pdu, room_version)
+    };
+    event["event_id"] = json!(event_id);
+
+    let event_storage = &state.services.event_storage;
+    match event_storage.get_event(event_id).await {
+        Ok(Some(_)) => return Ok(event),
+        Ok(None) => {}
+        Err(e) => return Err(format!("Failed to check for event: {}", e)),
+    }
+
+    verify_pdu_signatures(state, pdu, room_version).await?;
+    let cited_state = load_cited_auth_state(state, room_id, &event).await?;
+    EventAuthChain::check_auth_rules(&event, &cited_state)
+        .map_err(|reason| format!("Event failed authorization: {}", reason))?;
+
+    let params = crate::storage::event::CreateEventParams {
+        event_id: event_id.to_string(),
+        room_id: room_id.to_string(),
+        user_id: event["sender"].as_str().ok_or("PDU has no sender")?.to_string(),
+        event_type: event["type"].as_str().ok_or("PDU has no type")?.to_string(),
+        content: event["content"].clone(),
+        state_key: event["state_key"].as_str().map(String::from),
+        origin_server_ts: event["origin_server_ts"].as_i64().unwrap_or(0),
+    };
+    event_storage
+        .create_historical_event(params, origin, &event)
+        .await
+        .map_err(|e| format!("Failed to persist event: {}", e))?;
+    Ok(event)
+}
+
+fn state_map_of(state_events: &[(String, Value)]) -> crate::federation::StateMap {
+    state_events
+        .iter()
+        .filter_map(|(event_id, event)| {
+            let key = (event["type"].as_str()?.to_string(), event["state_key"].as_str()?.to_string());
+            Some((key, event_id.clone()))
+        })
+        .collect()
+}
+
+/// Brings the membership and room tables in line with state events from another server.
+async fn apply_remote_state(state: &AppState, room_id: &str, state_events: &[(String, Value)]) -> Result<(), ApiError> {
+    let mut join_rule = None;
+    let mut history_visibility = None;
+    let mut encryption = None;
+    for (event_id, event) in state_events {
+        let content = &event["content"];
+        match (event["type"].as_str(), event["state_key"].as_str()) {
+            (Some("m.room.member"), Some(state_key)) => state
+                .services
+                .member_storage
+                .apply_member_event(room_id, event_id, event["sender"].as_str().unwrap_or(state_key), state_key, content)
+                .await
+                .map_err(|e| ApiError::internal(format!("Failed to record membership: {}", e)))?,
+            (Some("m.room.join_rules"), Some("")) => join_rule = content["join_rule"].as_str(),
+            (Some("m.room.history_visibility"), Some("")) => {
+                history_visibility = content["history_visibility"].as_str()
+            }
+            (Some("m.room.encryption"), Some("")) => encryption = content["algorithm"].as_str(),
+            _ => {}
+        }
+    }
+
+    state
+        .services
+        .room_storage
+        .update_room_from_state(room_id, join_rule, history_visibility, encryption)
+        .await
+        .map_err(|e| ApiError::internal(format!("Failed to update room: {}", e)))
+}
+
+/// Fetches the full state at the join of a room joined with partial state, in the background.
+pub(crate) fn spawn_partial_state_sync(state: AppState, room_id: String) {
+    tokio::spawn(async move {
+        if let Err(e) = sync_partial_state(&state, &room_id).await {
+            ::tracing::warn!("Failed to fetch the full state of {}: {}", room_id, e);
+        }
+    });
+}
+
+/// Resumes the state fetches of rooms still marked as partial, as after a restart.
+pub fn resume_partial_state_syncs(state: AppState) {
+    tokio::spawn(async move {
+        let rooms = match state.services.room_storage.get_partial_state_rooms().await {
+            Ok(rooms) => rooms,
+            Err(e) => {
+                ::tracing::warn!("Failed to load partial state rooms: {}", e);
+                return;
+            }
+        };
+        for room in rooms {
+            if let Err(e) = sync_partial_state(&state, &room.room_id).await {
+                ::tracing::warn!("Failed to fetch the full state of {}: {}", room.room_id, e);
+            }
+        }
+    });
+}
+
+/// Replaces the partial state of a room with the full state at our join, fetched through
+/// `/state` from the servers named when joining, then clears the partial state mark.
+async fn sync_partial_state(state: &AppState, room_id: &str) -> Result<(), ApiError> {
+    let Some(partial) = state
+        .services
+        .room_storage
+        .get_partial_state(room_id)
+        .await
+        .map_err(|e| ApiError::internal(format!("Failed to load partial state: {}", e)))?
+    else {
+        return Ok(());
+    };
+    let join_event = state
+        .services
+        .event_storage
+        .get_event(&partial.join_event_id)
+        .await
+        .map_err(|e| ApiError::internal(format!("Failed to load join event: {}", e)))?
+        .ok_or_else(|| ApiError::not_found(format!("Join event {} not found", partial.join_event_id)))?;
+    let join_event = json!({
+        "type": join_event.event_type,
+        "state_key": join_event.state_key,
+    });
+    let room_version = state
+        .services
+        .event_storage
+        .get_room_version(room_id)
+        .await
+        .map_err(|e| ApiError::internal(format!("Failed to get room version: {}", e)))?;
+
+    for server in &partial.servers_in_room {
+        let (pdus, auth_chain) = match state
+            .services
+            .federation_client
+            .get_room_state(server, room_id, &partial.join_event_id)
+            .await
+        {
+            Ok(fetched) => fetched,
+            Err(e) => {
+                ::tracing::info!("Failed to fetch state of {} from {}: {}", room_id, server, e);
+                continue;
+            }
+        };
+        let room_state = match persist_remote_state(state, server, room_id, &room_version, pdus, auth_chain).await {
+            Ok(room_state) => room_state,
+            Err(e) => {
+                ::tracing::warn!("Rejected state of {} from {}: {}", room_id, server, e);
+                continue;
+            }
+        };
+        apply_remote_state(state, room_id, &room_state).await?;
+
+        state
+            .services
+            .state_resolution_service
+            .record_state_at_gap(room_id, &partial.join_event_id, &join_event, state_map_of(&room_state))
+            .await?;
+        state
+            .services
+            .room_storage
+            .clear_partial_state(room_id)
+            .await
+            .map_err(|e| ApiError::internal(format!("Failed to clear partial state: {}", e)))?;
+        ::tracing::info!("Fetched the full state of {} from {}", room_id, server);
+        return Ok(());
+    }
+    Err(ApiError::internal(format!(
+        "None of the {} servers in {} returned its state",
+        partial.servers_in_room.len(),
+        room_id
+    )))
+}
+
+/// Stores the resolved state after an accepted PDU. Failures only cost the cached state, so
+/// they are logged rather than rejecting an event that has already been persisted.
+async fn record_pdu_state(state: &AppState, room_id: &str, room_version: &str, event_id: &str, event: &Value) {
+    let resolver = &state.services.state_resolution_service;
+    let prev_events = pdu::referenced_event_ids(event.get("prev_events"));
+
+    let result = match resolver.state_before_event(room_id, room_version, &prev_events).await {
+        Ok(before) => resolver.record_event_state(room_id, event_id, event, before).await,
+        Err(e) => Err(e),
+    };
+    if let Err(e) = result {
+        ::tracing::warn!("Failed to record state for {} in {}: {}", event_id, room_id, e);
+    }
+}
+
+/// Checks the signatures every required server must have made over the redacted PDU.
+async fn verify_pdu_signatures(state: &AppState, pdu: &Value, room_version: &str) -> Result<(), String> {
+    let signed_bytes = pdu::signing_bytes(pdu, room_version);
+
+    for server in pdu::required_signers(pdu, room_version) {
+        verify_server_signature(state, pdu, &server, &signed_bytes).await?;
+    }
+
+    Ok(())
+}
+
+/// Checks that `server` made one of the ed25519 signatures on `pdu` over `signed_bytes`.
+async fn verify_server_signature(state: &AppState, pdu: &Value, server: &str, signed_bytes: &[u8]) -> Result<(), String> {
+    let signatures = pdu["signatures"][server]
+        .as_object()
+        .ok_or_else(|| format!("Missing signature from {}", server))?;
+
+    for (key_id, signature) in signatures {
+        let Some(signature) = signature.as_str().filter(|_| key_id.starts_with("ed25519:")) else {
+            continue;
+        };
+        let verified = if server == state.services.server_name {
+            state
+                .services
+                .key_rotation_manager
+                .verify_with_key_rotation(server, key_id, signature, signed_bytes)
+                .await
+                .unwrap_or(false)
+        } else {
+            crate::web::middleware::verify_federation_signature_with_cache(
+                state,
+                server,
+                key_id,
+                signature,
+                signed_bytes,
+            )
+            .await
+            .is_ok()
+        };
+        if verified {
+            return Ok(());
+        }
+    }
+
+    Err(format!("Invalid signature from {}", server))
+}
+
+/// Loads the auth events `event` cites, keyed by state type and key. They must all be held
+/// and be state events of the same room.
+async fn load_cited_auth_state(state: &AppState, room_id: &str, event: &Value) -> Result<AuthState, String> {
+    let mut cited_state = AuthState::new();
+    for auth_event_id in pdu::referenced_event_ids(event.get("auth_events")) {
+        let auth_event = state
+            .services
+            .event_storage
+            .get_event(&auth_event_id)
+            .await
+            .map_err(|e| format!("Failed to load auth event: {}", e))?
+            .ok_or_else(|| format!("Missing auth event {}", auth_event_id))?;
+        if auth_event.room_id != room_id {
+            return Err(format!("Auth event {} is from another room", auth_event_id));
+        }
+        let Some(auth_state_key) = auth_event.state_key.clone() else {
+            return Err(format!("Auth event {} is not a state event", auth_event_id));
+        };
+        let key = (auth_event.event_type.clone(), auth_state_key.clone());
+        if cited_state.contains_key(&key) {
+            return Err(format!("Duplicate auth event for {:?}", key));
+        }
+        cited_state.insert(
+            key,
+            json!({
+                "event_id": auth_event.event_id,
+                "type": auth_event.event_type,
+                "state_key": auth_state_key,
+                "sender": auth_event.user_id,
+                "content": auth_event.content
+            }),
+        );
+    }
+    Ok(cited_state)
+}
+
+/// Loads the room's current state for `keys`, along with the fallback entries synthesised
+/// from the room tables for keys that have no state event.
+async fn load_auth_state(
+    state: &AppState,
+    room: &crate::storage::room::Room,
+    keys: &[(String, String)],
+) -> Result<(AuthState, AuthState), ApiError> {
+    let mut current = AuthState::new();
+    let mut fallback = AuthState::new();
+
+    for (event_type, state_key) in keys {
+        let key = (event_type.clone(), state_key.clone());
+        let existing = state
+            .services
+            .event_storage
+            .get_state_event(&room.room_id, event_type, state_key)
+            .await
+            .map_err(|e| ApiError::internal(format!("Failed to load state: {}", e)))?;
+
+        if let Some(event) = existing {
+            current.insert(
+                key,
+                json!({
+                    "event_id": event.event_id,
+                    "type": event.event_type,
+                    "state_key": event.state_key,
+                    "sender": event.sender,
+                    "content": event.content
+                }),
+            );
+            continue;
+        }
+
+        let synthesised = match event_type.as_str() {
+            "m.room.create" => Some(json!({
+                "type": "m.room.create",
+                "state_key": "",
+                "sender": room.creator,
+                "content": {"creator": room.creator, "room_version": room.version}
+            })),
+            "m.room.join_rules" => Some(json!({
+                "type": "m.room.join_rules",
+                "state_key": "",
+                "sender": room.creator,
+                "content": {"join_rule": room.join_rule}
+            })),
+            "m.room.member" => state
+                .services
+                .member_storage
+                .get_member(&room.room_id, state_key)
+                .await
+                .map_err(|e| ApiError::internal(format!("Failed to load member: {}", e)))?
+                .map(|member| {
+                    json!({
+                        "type": "m.room.member",
+                        "state_key": member.user_id,
+                        "sender": member.sender.unwrap_or_else(|| member.user_id.clone()),
+                        "content": {"membership": member.membership}
+                    })
+                }),
+            _ => None,
+        };
 
-    Ok(Json(json!({
-        "room_version": "1",
-        "auth_events": auth_events_json,
-        "event": {
-            "type": "m.room.member",
-            "content": {
-                "membership": "join"
-            },
-            "sender": user_id,
-            "state_key": user_id
+        if let Some(event) = synthesised {
+            current.insert(key.clone(), event.clone());
+            fallback.insert(key, event);
         }
-    })))
+    }
+
+    Ok((current, fallback))
+}
+
+async fn make_join(
+    State(state): State<AppState>,
+    Path((room_id, user_id)): Path<(String, String)>,
+    RawQuery(query): RawQuery,
+) -> Result<Json<Value>, ApiError> {
+    let supported = requested_room_versions(query.as_deref());
+    make_membership_template(&state, &room_id, &user_id, "join", Some(&supported)).await
 }
 
 async fn make_leave(
     State(state): State<AppState>,
     Path((room_id, user_id)): Path<(String, String)>,
 ) -> Result<Json<Value>, ApiError> {
-    let auth_events = state
+    make_membership_template(&state, &room_id, &user_id, "leave", None).await
+}
+
+/// Room versions listed in the repeated `ver` query parameter; a server that lists none only
+/// supports v1.
+fn requested_room_versions(query: Option<&str>) -> Vec<String> {
+    let versions: Vec<String> = query
+        .unwrap_or_default()
+        .split('&')
+        .filter_map(|pair| pair.split_once('='))
+        .filter(|(key, _)| *key == "ver")
+        .map(|(_, version)| version.to_string())
+        .collect();
+    if versions.is_empty() {
+        vec!["1".to_string()]
+    } else {
+        versions
+    }
+}
+
+/// Builds the unsigned membership event a remote server fills in and signs, placed at the
+/// room's current forward extremities.
+async fn make_membership_template(
+    state: &AppState,
+    room_id: &str,
+    user_id: &str,
+    membership: &str,
+    supported_versions: Option<&[String]>,
+) -> Result<Json<Value>, ApiError> {
+    if !state
+        .services
+        .room_storage
+        .room_exists(room_id)
+        .await
+        .map_err(|e| ApiError::internal(format!("Failed to check room: {}", e)))?
+    {
+        return Err(ApiError::not_found("Room not found".to_string()));
+    }
+
+    let builder = EventBuilder::new(room_id, user_id, "m.room.member", json!({ "membership": membership }))
+        .with_state_key(user_id);
+    let ctx = state
         .services
         .event_storage
-        .get_state_events(&room_id)
+        .get_event_dag_context(&builder)
         .await
         .map_err(|e| ApiError::internal(format!("Failed to get auth events: {}", e)))?;
 
-    let auth_events_json: Vec<Value> = auth_events
-        .iter()
-        .map(|e| {
-            json!({
-                "event_id": e.event_id,
-                "type": e.event_type,
-                "state_key": e.state_key
-            })
-        })
-        .collect();
+    if supported_versions.is_some_and(|versions| !versions.contains(&ctx.room_version)) {
+        return Err(ApiError::incompatible_room_version(format!(
+            "Your homeserver does not support room version {}",
+            ctx.room_version
+        )));
+    }
 
     Ok(Json(json!({
-        "room_version": "1",
-        "auth_events": auth_events_json,
-        "event": {
-            "type": "m.room.member",
-            "content": {
-                "membership": "leave"
-            },
-            "sender": user_id,
-            "state_key": user_id
-        }
+        "room_version": ctx.room_version,
+        "event": builder.template(&state.services.server_name, &ctx)
     })))
 }
 
 async fn send_join(
     State(state): State<AppState>,
     Path((room_id, event_id)): Path<(String, String)>,
+    headers: axum::http::HeaderMap,
     Json(body): Json<Value>,
 ) -> Result<Json<Value>, ApiError> {
-    let origin = body
-        .get("origin")
-        .and_then(|v| v.as_str())
-        .ok_or_else(|| ApiError::bad_request("Origin required".to_string()))?;
-
-    let event = body
-        .get("event")
-        .ok_or_else(|| ApiError::bad_request("Event required".to_string()))?;
-    let user_id = event.get("sender").and_then(|v| v.as_str()).unwrap_or("");
-    let content = event.get("content").cloned().unwrap_or(json!({}));
-    let display_name = content.get("displayname").and_then(|v| v.as_str());
-
-    // 1. Persist the event
-    let params = crate::storage::event::CreateEventParams {
-        event_id: event_id.clone(),
-        room_id: room_id.clone(),
-        user_id: user_id.to_string(),
-        event_type: "m.room.member".to_string(),
-        content: content.clone(),
-        state_key: Some(user_id.to_string()),
-        origin_server_ts: event
-            .get("origin_server_ts")
-            .and_then(|v| v.as_i64())
-            .unwrap_or(0),
-    };
-    state
-        .services
-        .event_storage
-        .create_event(params, None)
-        .await
-        .map_err(|e| ApiError::internal(format!("Failed to persist join event: {}", e)))?;
-
-    // 2. Update membership
-    state
-        .services
-        .member_storage
-        .add_member(&room_id, user_id, "join", display_name, None, None)
-        .await
-        .map_err(|e| ApiError::internal(format!("Failed to update membership: {}", e)))?;
+    let event = membership_request_event(&body)?;
+    let origin = accept_membership_event(&state, &room_id, &event_id, &headers, event, "join").await?;
 
     ::tracing::info!(
         "Processed join for room {} event {} from {}",
@@ -674,75 +2170,220 @@ async fn send_join(
 async fn send_leave(
     State(state): State<AppState>,
     Path((room_id, event_id)): Path<(String, String)>,
+    headers: axum::http::HeaderMap,
     Json(body): Json<Value>,
 ) -> Result<Json<Value>, ApiError> {
+    let event = membership_request_event(&body)?;
+    let origin = accept_membership_event(&state, &room_id, &event_id, &headers, event, "leave").await?;
+
+    ::tracing::info!(
+        "Processed leave for room {} event {} from {}",
+        room_id,
+        event_id,
+        origin
+    );
+
+    Ok(Json(json!({
+        "event_id": event_id
+    })))
+}
+
+/// v2 `send_join`: the body is the signed join event itself, and the response carries the
+/// room state before the join with its auth chain, which the joining server builds on.
+async fn send_join_v2(
+    State(state): State<AppState>,
+    Path((room_id, event_id)): Path<(String, String)>,
+    headers: axum::http::HeaderMap,
+    Json(event): Json<Value>,
+) -> Result<Json<Value>, ApiError> {
+    let origin = accept_membership_event(&state, &room_id, &event_id, &headers, &event, "join").await?;
+    ::tracing::info!("Processed join for room {} event {} from {}", room_id, event_id, origin);
+
+    let event_storage = &state.services.event_storage;
+    let room_version = event_storage
+        .get_room_version(&room_id)
+        .await
+        .map_err(|e| ApiError::internal(format!("Failed to get room version: {}", e)))?;
+    // Newest first, so the first event seen for a key is the one in force before the join
+    let mut seen = std::collections::HashSet::new();
+    let state_ids: Vec<String> = event_storage
+        .get_state_events(&room_id)
+        .await
+        .map_err(|e| ApiError::internal(format!("Failed to get room state: {}", e)))?
+        .into_iter()
+        .filter(|e| e.event_id != event_id && seen.insert((e.event_type.clone(), e.state_key.clone())))
+        .map(|e| e.event_id)
+        .collect();
+    let mut chain_from = state_ids.clone();
+    chain_from.push(event_id.clone());
+    let auth_chain_ids = event_storage
+        .get_auth_chain_ids(&chain_from)
+        .await
+        .map_err(|e| ApiError::internal(format!("Failed to get auth chain: {}", e)))?;
+
+    chain_from.extend(auth_chain_ids.iter().cloned());
+    let pdus = event_storage
+        .get_pdus(&chain_from)
+        .await
+        .map_err(|e| ApiError::internal(format!("Failed to load events: {}", e)))?;
+    let served = |ids: &[String]| -> Vec<Value> {
+        ids.iter()
+            .filter_map(|id| pdus.get(id))
+            .map(|pdu| outgoing_pdu(pdu, &room_version))
+            .collect()
+    };
+
+    Ok(Json(json!({
+        "origin": state.services.server_name,
+        "state": served(&state_ids),
+        "auth_chain": served(&auth_chain_ids),
+        "event": served(std::slice::from_ref(&event_id)).pop()
+    })))
+}
+
+/// v2 `send_leave`: the body is the signed leave event itself, and the response is empty.
+async fn send_leave_v2(
+    State(state): State<AppState>,
+    Path((room_id, event_id)): Path<(String, String)>,
+    headers: axum::http::HeaderMap,
+    Json(event): Json<Value>,
+) -> Result<Json<Value>, ApiError> {
+    let origin = accept_membership_event(&state, &room_id, &event_id, &headers, &event, "leave").await?;
+    ::tracing::info!("Processed leave for room {} event {} from {}", room_id, event_id, origin);
+    Ok(Json(json!({})))
+}
+
+/// A stored event in the form sent to other servers, which from room v3 on carry no
+/// `event_id` since it is derived from the event.
+fn outgoing_pdu(pdu: &Value, room_version: &str) -> Value {
+    let mut pdu = pdu.clone();
+    if !pdu::has_explicit_event_id(room_version) {
+        if let Some(fields) = pdu.as_object_mut() {
+            fields.remove("event_id");
+        }
+    }
+    pdu
+}
+
+/// The event of a v1 `send_join` / `send_leave` body, `{origin, event}`, whose origin must be
+/// the event sender's server.
+fn membership_request_event(body: &Value) -> Result<&Value, ApiError> {
     let origin = body
         .get("origin")
         .and_then(|v| v.as_str())
         .ok_or_else(|| ApiError::bad_request("Origin required".to_string()))?;
-
     let event = body
         .get("event")
+        .filter(|event| event.is_object())
         .ok_or_else(|| ApiError::bad_request("Event required".to_string()))?;
-    let user_id = event.get("sender").and_then(|v| v.as_str()).unwrap_or("");
+    let sender = event.get("sender").and_then(|v| v.as_str()).unwrap_or_default();
+    if pdu::server_name_of(sender) != Some(origin) {
+        return Err(ApiError::forbidden("Origin is not the sender's server".to_string()));
+    }
+    Ok(event)
+}
 
-    // 1. Persist the event
-    let params = crate::storage::event::CreateEventParams {
-        event_id: event_id.clone(),
-        room_id: room_id.clone(),
-        user_id: user_id.to_string(),
-        event_type: "m.room.member".to_string(),
-        content: event.get("content").cloned().unwrap_or(json!({})),
-        state_key: Some(user_id.to_string()),
-        origin_server_ts: event
-            .get("origin_server_ts")
-            .and_then(|v| v.as_i64())
-            .unwrap_or(0),
-    };
-    state
+/// Accepts a membership event another server built from one of our `make_*` templates and
+/// signed: it must set `membership` for its own sender, come from the sender's server and carry
+/// the event ID in the path, and it is checked like any other PDU — signatures, content hash
+/// and the auth rules at its auth events and at the current state. An event that is rejected,
+/// or only soft-fails, leaves the membership unchanged and is refused. Returns the origin.
+async fn accept_membership_event(
+    state: &AppState,
+    room_id: &str,
+    event_id: &str,
+    headers: &axum::http::HeaderMap,
+    event: &Value,
+    membership: &str,
+) -> Result<String, ApiError> {
+    let field = |name: &str| event.get(name).and_then(|v| v.as_str());
+    let sender = field("sender").ok_or_else(|| ApiError::bad_request("Membership event has no sender".to_string()))?;
+    if field("type") != Some("m.room.member") || event["content"]["membership"].as_str() != Some(membership) {
+        return Err(ApiError::bad_request(format!("Not a {} event", membership)));
+    }
+    if field("room_id") != Some(room_id) || field("state_key") != Some(sender) {
+        return Err(ApiError::bad_request("Membership event does not match the request".to_string()));
+    }
+    let origin = pdu::server_name_of(sender)
+        .ok_or_else(|| ApiError::bad_request(format!("Invalid user ID: {}", sender)))?;
+    if crate::web::middleware::federation_request_origin(headers).is_some_and(|authenticated| authenticated != origin) {
+        return Err(ApiError::forbidden("Membership event was not sent by the sender's server".to_string()));
+    }
+
+    let room_version = state
         .services
         .event_storage
-        .create_event(params, None)
+        .get_room_version(room_id)
         .await
-        .map_err(|e| ApiError::internal(format!("Failed to persist leave event: {}", e)))?;
+        .map_err(|e| ApiError::internal(format!("Failed to get room version: {}", e)))?;
+    if event_id_of(event, &room_version).as_deref() != Some(event_id) {
+        return Err(ApiError::bad_request("Event ID does not match the membership event".to_string()));
+    }
 
-    // 2. Update membership
-    state
+    let (_, outcome) = process_inbound_pdu(state, origin, event, PduSource::Transaction).await;
+    outcome.map_err(ApiError::forbidden)?;
+    let current = state
         .services
         .member_storage
-        .add_member(&room_id, user_id, "leave", None, None, None)
+        .get_membership(room_id, sender)
         .await
-        .map_err(|e| ApiError::internal(format!("Failed to update membership: {}", e)))?;
+        .map_err(|e| ApiError::internal(format!("Failed to check membership: {}", e)))?;
+    if current.as_deref() != Some(membership) {
+        return Err(ApiError::forbidden(format!(
+            "The {} was not accepted by the current room state",
+            membership
+        )));
+    }
+    Ok(origin.to_string())
+}
 
-    ::tracing::info!(
-        "Processed leave for room {} event {} from {}",
-        room_id,
-        event_id,
-        origin
-    );
+/// The template for knocking, refused up front when the room's version or join rules, or the
+/// user's current membership, would not allow the knock.
+async fn make_knock(
+    State(state): State<AppState>,
+    Path((room_id, user_id)): Path<(String, String)>,
+    RawQuery(query): RawQuery,
+    headers: axum::http::HeaderMap,
+) -> Result<Json<Value>, ApiError> {
+    if let Some(origin) = crate::web::middleware::federation_request_origin(&headers) {
+        if pdu::server_name_of(&user_id) != Some(origin.as_str()) {
+            return Err(ApiError::forbidden("Cannot knock on behalf of another server's user".to_string()));
+        }
+    }
+    let supported = requested_room_versions(query.as_deref());
+    let Json(template) = make_membership_template(&state, &room_id, &user_id, "knock", Some(&supported)).await?;
 
-    Ok(Json(json!({
-        "event_id": event_id
-    })))
+    let room = state
+        .services
+        .room_storage
+        .get_room(&room_id)
+        .await
+        .map_err(|e| ApiError::internal(format!("Failed to get room: {}", e)))?
+        .ok_or_else(|| ApiError::not_found("Room not found".to_string()))?;
+    let event = &template["event"];
+    let (current_state, _) = load_auth_state(&state, &room, &EventAuthChain::auth_types_for_event(event)).await?;
+    EventAuthChain::check_auth_rules(event, &current_state)
+        .map_err(|reason| ApiError::forbidden(format!("You cannot knock on this room: {}", reason)))?;
+    Ok(Json(template))
 }
 
-async fn invite(
-    State(_state): State<AppState>,
+/// Accepts a knock signed by the knocker's server, checked like any other PDU, and returns
+/// the stripped state the knocker may see of the room.
+async fn send_knock(
+    State(state): State<AppState>,
     Path((room_id, event_id)): Path<(String, String)>,
-    Json(body): Json<Value>,
+    headers: axum::http::HeaderMap,
+    Json(event): Json<Value>,
 ) -> Result<Json<Value>, ApiError> {
-    let _origin = body
-        .get("origin")
-        .and_then(|v| v.as_str())
-        .ok_or_else(|| ApiError::bad_request("Origin required".to_string()))?;
+    let origin = accept_membership_event(&state, &room_id, &event_id, &headers, &event, "knock").await?;
+    let sender = event["sender"].as_str().unwrap_or_default();
 
-    ::tracing::info!("Processing invite for room {} event {}", room_id, event_id);
-
-    Ok(Json(json!({
-        "event_id": event_id
-    })))
+    ::tracing::info!("Processed knock on {} by {} from {}", room_id, sender, origin);
+    let knock_room_state = state.services.knock_service.knock_room_state(&room_id, sender).await?;
+    Ok(Json(json!({ "knock_room_state": knock_room_state })))
 }
 
+
 async fn get_missing_events(
     State(state): State<AppState>,
     Path(room_id): Path<String>,
@@ -928,6 +2569,41 @@ async fn get_state_ids(
     })))
 }
 
+/// Resolves one of our room aliases for another server, with the servers to join through.
+async fn room_alias_query(
+    State(state): State<AppState>,
+    axum::extract::Query(params): axum::extract::Query<std::collections::HashMap<String, String>>,
+) -> Result<Json<Value>, ApiError> {
+    let alias = params
+        .get("room_alias")
+        .ok_or_else(|| ApiError::bad_request("room_alias required".to_string()))?;
+    if pdu::server_name_of(alias) != Some(state.services.server_name.as_str()) {
+        return Err(ApiError::not_found("Room alias not found".to_string()));
+    }
+    let room_id = state
+        .services
+        .room_storage
+        .get_room_by_alias(alias)
+        .await
+        .map_err(|e| ApiError::internal(format!("Failed to look up room alias: {}", e)))?
+        .ok_or_else(|| ApiError::not_found("Room alias not found".to_string()))?;
+
+    let mut servers = vec![state.services.server_name.clone()];
+    servers.extend(
+        state
+            .services
+            .member_storage
+            .get_joined_servers(&room_id)
+            .await
+            .map_err(|e| ApiError::internal(format!("Failed to get servers in room: {}", e)))?,
+    );
+
+    Ok(Json(json!({
+        "room_id": room_id,
+        "servers": servers
+    })))
+}
+
 #[axum::debug_handler]
 async fn room_directory_query(
     State(state): State<AppState>,
@@ -1213,27 +2889,6 @@ fn derive_ed25519_verify_key_base64(signing_key: &str) -> Option<String> {
     Some(base64::engine::general_purpose::STANDARD_NO_PAD.encode(verifying_key.as_bytes()))
 }
 
-fn decode_base64_32(value: &str) -> Option<[u8; 32]> {
-    let value = value.trim();
-    let engines = [
-        base64::engine::general_purpose::STANDARD,
-        base64::engine::general_purpose::STANDARD_NO_PAD,
-        base64::engine::general_purpose::URL_SAFE,
-        base64::engine::general_purpose::URL_SAFE_NO_PAD,
-    ];
-
-    for engine in engines {
-        if let Ok(bytes) = engine.decode(value) {
-            if bytes.len() == 32 {
-                let mut out = [0u8; 32];
-                out.copy_from_slice(&bytes);
-                return Some(out);
-            }
-        }
-    }
-    None
-}
-
 async fn key_clone(Json(_body): Json<Value>) -> Json<Value> {
     Json(json!({
         "success": true
@@ -1251,123 +2906,137 @@ async fn user_keys_query(
     })))
 }
 
+/// Completes a third-party invite to one of our rooms once the invitee's server has seen the
+/// address bound.
 async fn exchange_third_party_invite(
     State(state): State<AppState>,
     Path(room_id): Path<String>,
     Json(body): Json<Value>,
 ) -> Result<Json<Value>, ApiError> {
-    ::tracing::info!("Processing exchange third party invite for room: {}", room_id);
-
-    let sender = body.get("sender")
-        .and_then(|v| v.as_str())
-        .ok_or_else(|| ApiError::bad_request("Missing sender".to_string()))?;
-
-    let state_key = body.get("state_key")
-        .and_then(|v| v.as_str())
-        .ok_or_else(|| ApiError::bad_request("Missing state_key".to_string()))?;
-
-    let event_id = format!("${}", uuid::Uuid::new_v4());
-    let now = chrono::Utc::now().timestamp();
-
-    let content = body.get("content").cloned().unwrap_or(json!({}));
-
-    sqlx::query(
-        r#"
-        INSERT INTO events (event_id, room_id, event_type, state_key, sender, content, origin_server_ts)
-        VALUES ($1, $2, 'm.room.third_party_invite', $3, $4, $5, $6)
-        "#,
-    )
-    .bind(&event_id)
-    .bind(&room_id)
-    .bind(state_key)
-    .bind(sender)
-    .bind(content.to_string())
-    .bind(now)
-    .execute(&*state.services.user_storage.pool)
-    .await
-    .map_err(|e| ApiError::internal(format!("Failed to store invite: {}", e)))?;
-
-    Ok(Json(json!({
-        "event_id": event_id
-    })))
+    complete_third_party_invite(&state, &room_id, &body).await?;
+    Ok(Json(json!({})))
 }
 
+/// Accepts a third-party invite to one of our rooms made by another server's user, as the
+/// inviter's server signed it. The signed binding must match a `m.room.third_party_invite`
+/// event in the room's state, and the invite is checked like any other PDU before it is stored.
 async fn on_bind_third_party_invite(
     State(state): State<AppState>,
     Path(room_id): Path<String>,
-    Json(body): Json<Value>,
+    headers: axum::http::HeaderMap,
+    Json(event): Json<Value>,
 ) -> Result<Json<Value>, ApiError> {
-    ::tracing::info!("Processing on bind third party invite for room: {}", room_id);
-
-    let event_id = body.get("event_id")
-        .and_then(|v| v.as_str())
-        .ok_or_else(|| ApiError::bad_request("Missing event_id".to_string()))?;
-
-    let sender = body.get("sender")
-        .and_then(|v| v.as_str())
-        .ok_or_else(|| ApiError::bad_request("Missing sender".to_string()))?;
+    let field = |name: &str| event.get(name).and_then(|v| v.as_str());
+    let sender = field("sender").ok_or_else(|| ApiError::bad_request("Missing sender".to_string()))?;
+    let invitee = field("state_key").ok_or_else(|| ApiError::bad_request("Missing state_key".to_string()))?;
+    if field("type") != Some("m.room.member")
+        || field("room_id") != Some(room_id.as_str())
+        || event["content"]["membership"] != "invite"
+        || !event["content"]["third_party_invite"].is_object()
+    {
+        return Err(ApiError::bad_request("Not a third-party invite to this room".to_string()));
+    }
+    // Our own users' invites are built and signed here
+    if pdu::server_name_of(sender) == Some(state.services.server_name.as_str()) {
+        complete_third_party_invite(&state, &room_id, &event).await?;
+        return Ok(Json(json!({})));
+    }
 
-    let state_key = body.get("state_key")
-        .and_then(|v| v.as_str())
-        .ok_or_else(|| ApiError::bad_request("Missing state_key".to_string()))?;
-
-    let now = chrono::Utc::now().timestamp();
-    let content = body.get("content").cloned().unwrap_or(json!({}));
-
-    sqlx::query(
-        r#"
-        INSERT INTO events (event_id, room_id, event_type, state_key, sender, content, origin_server_ts)
-        VALUES ($1, $2, 'm.room.member', $3, $4, $5, $6)
-        ON CONFLICT (event_id) DO UPDATE SET content = $5
-        "#,
-    )
-    .bind(event_id)
-    .bind(&room_id)
-    .bind(state_key)
-    .bind(sender)
-    .bind(content.to_string())
-    .bind(now)
-    .execute(&*state.services.user_storage.pool)
-    .await
-    .map_err(|e| ApiError::internal(format!("Failed to bind third party invite: {}", e)))?;
+    let origin = pdu::server_name_of(sender)
+        .ok_or_else(|| ApiError::bad_request(format!("Invalid user ID: {}", sender)))?;
+    if crate::web::middleware::federation_request_origin(&headers).is_some_and(|authenticated| authenticated != origin) {
+        return Err(ApiError::forbidden("Invite was not sent by the inviter's server".to_string()));
+    }
+    let room = state
+        .services
+        .room_storage
+        .get_room(&room_id)
+        .await
+        .map_err(|e| ApiError::internal(format!("Failed to get room: {}", e)))?
+        .ok_or_else(|| ApiError::not_found("Room not found".to_string()))?;
+
+    let auth_types = EventAuthChain::auth_types_for_event(&event);
+    let (current_state, _) = load_auth_state(&state, &room, &auth_types).await?;
+    EventAuthChain::check_auth_rules(&event, &current_state)
+        .map_err(|reason| ApiError::forbidden(format!("Third-party invite is not allowed: {}", reason)))?;
+    check_third_party_invite_key(&state, &current_state, &event["content"]).await?;
+
+    let (_, outcome) = process_inbound_pdu(&state, origin, &event, PduSource::Transaction).await;
+    outcome.map_err(ApiError::forbidden)?;
+    let membership = state
+        .services
+        .member_storage
+        .get_membership(&room_id, invitee)
+        .await
+        .map_err(|e| ApiError::internal(format!("Failed to check membership: {}", e)))?;
+    if membership.as_deref() != Some("invite") {
+        return Err(ApiError::forbidden("The invite was not accepted by the current room state".to_string()));
+    }
 
+    ::tracing::info!("{} invited {} to {} through a third-party invite", sender, invitee, room_id);
     Ok(Json(json!({})))
 }
 
+/// Called by an identity server when a third-party ID is bound to one of our users. Pending
+/// invites for the address are exchanged with the servers of the rooms they are for, once the
+/// identity server's signature on each has been checked.
 async fn three_pid_onbind(
     State(state): State<AppState>,
     Json(body): Json<Value>,
 ) -> Result<Json<Value>, ApiError> {
-    ::tracing::info!("Processing 3pid onbind");
-
     let mxid = body.get("mxid")
         .and_then(|v| v.as_str())
         .ok_or_else(|| ApiError::bad_request("Missing mxid".to_string()))?;
-
-    let medium = body.get("medium")
-        .and_then(|v| v.as_str())
-        .unwrap_or("email");
-
+    if pdu::server_name_of(mxid) != Some(state.services.server_name.as_str()) {
+        return Err(ApiError::bad_request(format!("{} is not a user of this server", mxid)));
+    }
     let address = body.get("address")
         .and_then(|v| v.as_str())
         .unwrap_or("");
 
-    let now = chrono::Utc::now().timestamp();
-
-    sqlx::query(
-        r#"
-        INSERT INTO user_threepids (user_id, medium, address, validated_at, added_at)
-        VALUES ($1, $2, $3, $4, $4)
-        ON CONFLICT (user_id, medium, address) DO UPDATE SET validated_at = $4
-        "#,
-    )
-    .bind(mxid)
-    .bind(medium)
-    .bind(address)
-    .bind(now)
-    .execute(&*state.services.user_storage.pool)
-    .await
-    .map_err(|e| ApiError::internal(format!("Failed to bind 3pid: {}", e)))?;
+    let invites = body.get("invites").and_then(|v| v.as_array()).cloned().unwrap_or_default();
+    for invite in invites {
+        let (Some(room_id), Some(sender)) = (invite["room_id"].as_str(), invite["sender"].as_str()) else {
+            continue;
+        };
+        if invite["mxid"].as_str() != Some(mxid) || invite["signed"]["mxid"].as_str() != Some(mxid) {
+            ::tracing::warn!("Ignoring third-party invite in {} bound to another user", room_id);
+            continue;
+        }
+        if !state.services.identity_service.verify_signed(&invite["signed"]).await {
+            ::tracing::warn!("Ignoring third-party invite in {} without a valid identity server signature", room_id);
+            continue;
+        }
+        let event = json!({
+            "type": "m.room.member",
+            "room_id": room_id,
+            "sender": sender,
+            "state_key": mxid,
+            "content": {
+                "membership": "invite",
+                "third_party_invite": {
+                    "display_name": address,
+                    "signed": invite["signed"],
+                },
+            },
+        });
+
+        let result = match pdu::server_name_of(sender) {
+            Some(server) if server == state.services.server_name => {
+                complete_third_party_invite(&state, room_id, &event).await.map(|_| ())
+            }
+            Some(server) => state
+                .services
+                .federation_client
+                .exchange_third_party_invite(server, room_id, &event)
+                .await
+                .map_err(ApiError::from),
+            None => continue,
+        };
+        if let Err(e) = result {
+            ::tracing::warn!("Failed to exchange third-party invite in {} for {}: {}", room_id, mxid, e);
+        }
+    }
 
     Ok(Json(json!({})))
 }
@@ -1380,40 +3049,76 @@ async fn send_to_device(
     ::tracing::info!("Processing send to device, txn_id: {}", txn_id);
 
     let messages = body.get("messages")
-        .and_then(|v| v.as_object())
+        .filter(|v| v.is_object())
         .ok_or_else(|| ApiError::bad_request("Missing messages".to_string()))?;
+    let sender = body.get("sender")
+        .and_then(|v| v.as_str())
+        .ok_or_else(|| ApiError::bad_request("Missing sender".to_string()))?;
+    let event_type = body.get("type")
+        .and_then(|v| v.as_str())
+        .ok_or_else(|| ApiError::bad_request("Missing type".to_string()))?;
 
-    let now = chrono::Utc::now().timestamp();
-
-    for (user_id, devices) in messages {
-        if let Some(devices_map) = devices.as_object() {
-            for (device_id, message) in devices_map {
-                let message_content = message.to_string();
-                let event_type = body.get("type")
-                    .and_then(|v| v.as_str())
-                    .unwrap_or("m.room.message");
-
-                sqlx::query(
-                    r#"
-                    INSERT INTO to_device_messages (
-                        message_id, user_id, device_id, event_type, content, received_ts, txn_id
-                    )
-                    VALUES ($1, $2, $3, $4, $5, $6, $7)
-                    "#,
-                )
-                .bind(format!("{}_{}", txn_id, uuid::Uuid::new_v4()))
-                .bind(user_id)
-                .bind(device_id)
-                .bind(event_type)
-                .bind(&message_content)
-                .bind(now)
-                .bind(&txn_id)
-                .execute(&*state.services.user_storage.pool)
-                .await
-                .map_err(|e| ApiError::internal(format!("Failed to store to-device message: {}", e)))?;
-            }
-        }
-    }
+    state
+        .services
+        .to_device_service
+        .send_messages(sender, event_type, messages)
+        .await?;
 
     Ok(Json(json!({})))
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn join_template() -> Value {
+        json!({
+            "type": "m.room.member",
+            "room_id": "!room:remote.test",
+            "sender": "@alice:origin.test",
+            "state_key": "@alice:origin.test",
+            "origin": "remote.test",
+            "content": {"membership": "join", "join_authorised_via_users_server": "@bob:remote.test"},
+            "prev_events": ["$prev"],
+            "auth_events": ["$create"],
+            "depth": 7
+        })
+    }
+
+    #[test]
+    fn test_fill_membership_template_merges_profile() {
+        let filled = fill_membership_template(
+            join_template(),
+            MembershipHandshake::Join,
+            "!room:remote.test",
+            "@alice:origin.test",
+            "origin.test",
+            &json!({"membership": "leave", "displayname": "Alice", "reason": "hi"}),
+        )
+        .unwrap();
+
+        assert_eq!(filled["origin"], "origin.test");
+        assert_eq!(filled["content"]["membership"], "join");
+        assert_eq!(filled["content"]["displayname"], "Alice");
+        assert_eq!(filled["content"]["join_authorised_via_users_server"], "@bob:remote.test");
+        assert_eq!(filled["depth"], 7);
+    }
+
+    #[test]
+    fn test_fill_membership_template_rejects_other_changes() {
+        let content = json!({});
+        let fill = |template: Value, handshake: MembershipHandshake| {
+            fill_membership_template(template, handshake, "!room:remote.test", "@alice:origin.test", "origin.test", &content)
+        };
+
+        assert!(fill(join_template(), MembershipHandshake::Leave).is_err());
+
+        let mut other_user = join_template();
+        other_user["state_key"] = json!("@mallory:origin.test");
+        assert!(fill(other_user, MembershipHandshake::Join).is_err());
+
+        let mut other_room = join_template();
+        other_room["room_id"] = json!("!other:remote.test");
+        assert!(fill(other_room, MembershipHandshake::Join).is_err());
+    }
+}
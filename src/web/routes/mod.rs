@@ -5,6 +5,15 @@ pub mod federation;
 pub mod friend_room;
 pub mod key_backup;
 pub mod media;
+pub mod notifications;
+pub mod push_rules;
+pub mod pushers;
+pub mod relations;
+pub mod search;
+pub mod sliding_sync;
+pub mod spaces;
+pub mod thirdparty;
+pub mod ui_auth;
 pub mod voice;
 pub mod voip;
 
@@ -15,6 +24,15 @@ pub use federation::create_federation_router;
 pub use friend_room::create_friend_router;
 pub use key_backup::create_key_backup_router;
 pub use media::create_media_router;
+pub use notifications::create_notifications_router;
+pub use push_rules::create_push_rules_router;
+pub use pushers::create_pushers_router;
+pub use relations::create_relations_router;
+pub use search::create_search_router;
+pub use sliding_sync::create_sliding_sync_router;
+pub use spaces::create_spaces_router;
+pub use thirdparty::create_thirdparty_router;
+pub use ui_auth::create_ui_auth_router;
 pub use voice::create_voice_router;
 pub use voip::get_turn_server;
 pub use voip::get_voip_config;
@@ -23,7 +41,7 @@ pub use voip::get_turn_credentials_guest;
 use crate::cache::*;
 use crate::common::*;
 use crate::services::*;
-use crate::storage::CreateEventParams;
+use crate::storage::{CreateEventParams, UiAuthSession};
 use crate::web::middleware::rate_limit_middleware;
 use axum::extract::rejection::JsonRejection;
 use axum::{
@@ -116,10 +134,20 @@ impl FromRequestParts<AppState> for AuthenticatedUser {
         state: &AppState,
     ) -> impl std::future::Future<Output = Result<Self, Self::Rejection>> + Send {
         let token_result = extract_token_from_headers(&parts.headers);
+        let query = parts.uri.query().map(str::to_string);
         let state = state.clone();
 
         async move {
             let token = token_result?;
+            if let Some(service) = state.services.app_services.get_by_token(&token).await {
+                let user_id = appservice_user(&state, &service, query.as_deref()).await?;
+                return Ok(AuthenticatedUser {
+                    user_id,
+                    device_id: None,
+                    is_admin: false,
+                    access_token: token,
+                });
+            }
             let (user_id, device_id, is_admin) =
                 state.services.auth_service.validate_token(&token).await?;
 
@@ -133,6 +161,43 @@ impl FromRequestParts<AppState> for AuthenticatedUser {
     }
 }
 
+/// The user an application service acts as: the `user_id` query parameter, which must be a
+/// registered local user in its namespace, or otherwise its sender.
+async fn appservice_user(
+    state: &AppState,
+    service: &crate::appservice::AppService,
+    query: Option<&str>,
+) -> Result<String, ApiError> {
+    let server_name = &state.services.server_name;
+    let requested = query.and_then(|query| {
+        url::form_urlencoded::parse(query.as_bytes())
+            .find(|(key, _)| key == "user_id")
+            .map(|(_, user_id)| user_id.into_owned())
+    });
+    let Some(user_id) = requested else {
+        return Ok(service.sender_user_id(server_name));
+    };
+
+    let is_local = user_id.strip_prefix('@').and_then(|id| id.split_once(':')).map(|(_, server)| server)
+        == Some(server_name.as_str());
+    if !is_local || !service.is_interested_in_user(&user_id, server_name) {
+        return Err(ApiError::forbidden(format!(
+            "Application service cannot masquerade as {}",
+            user_id
+        )));
+    }
+    let exists = state
+        .services
+        .user_storage
+        .user_exists(&user_id)
+        .await
+        .map_err(|e| ApiError::internal(format!("Database error: {}", e)))?;
+    if !exists {
+        return Err(ApiError::forbidden(format!("User {} has not been registered", user_id)));
+    }
+    Ok(user_id)
+}
+
 impl FromRequestParts<AppState> for AdminUser {
     type Rejection = ApiError;
 
@@ -208,9 +273,18 @@ pub fn create_router(state: AppState) -> Router {
         .merge(create_media_router(state.clone()))
         .merge(create_e2ee_router(state.clone()))
         .merge(create_key_backup_router(state.clone()))
+        .merge(create_push_rules_router(state.clone()))
+        .merge(create_pushers_router(state.clone()))
+        .merge(create_notifications_router(state.clone()))
+        .merge(create_relations_router(state.clone()))
+        .merge(create_search_router(state.clone()))
+        .merge(create_thirdparty_router(state.clone()))
+        .merge(create_ui_auth_router(state.clone()))
+        .merge(create_spaces_router(state.clone()))
         .merge(create_admin_router(state.clone()))
         .merge(create_federation_router(state.clone()))
         .merge(create_friend_router(state.clone()))
+        .merge(create_sliding_sync_router(state.clone()))
         .merge(create_external_services_router())
         .route("/_matrix/client/v3/voip/turnServer", get(get_turn_server))
         .route("/_matrix/client/v3/voip/config", get(get_voip_config))
@@ -230,6 +304,10 @@ fn create_auth_router() -> Router<AppState> {
             "/_matrix/client/r0/register/available",
             get(check_username_availability),
         )
+        .route(
+            "/_matrix/client/v1/register/m.login.registration_token/validity",
+            get(check_registration_token_validity),
+        )
         .route(
             "/_matrix/client/r0/register/email/requestToken",
             post(request_email_verification),
@@ -238,6 +316,14 @@ fn create_auth_router() -> Router<AppState> {
             "/_matrix/client/r0/register/email/submitToken",
             post(submit_email_token),
         )
+        .route(
+            "/_matrix/client/r0/register/msisdn/requestToken",
+            post(request_msisdn_verification),
+        )
+        .route(
+            "/_matrix/client/r0/register/msisdn/submitToken",
+            post(submit_msisdn_token),
+        )
         .route("/_matrix/client/r0/login", post(login))
         .route("/_matrix/client/r0/logout", post(logout))
         .route("/_matrix/client/r0/logout/all", post(logout_all))
@@ -364,6 +450,14 @@ fn create_room_router() -> Router<AppState> {
             "/_matrix/client/r0/join/{room_id_or_alias}",
             post(join_room_by_id_or_alias),
         )
+        .route(
+            "/_matrix/client/r0/knock/{room_id_or_alias}",
+            post(knock_room),
+        )
+        .route(
+            "/_matrix/client/v3/knock/{room_id_or_alias}",
+            post(knock_room),
+        )
         .route(
             "/_matrix/client/r0/user/{user_id}/rooms",
             get(get_user_rooms),
@@ -455,7 +549,8 @@ async fn get_client_versions() -> Json<Value> {
         "unstable_features": {
             "m.lazy_load_members": true,
             "m.require_identity_server": false,
-            "m.supports_login_via_phone_number": true
+            "m.supports_login_via_phone_number": true,
+            "org.matrix.simplified_msc3575": true
         }
     }))
 }
@@ -494,12 +589,31 @@ async fn get_well_known_client(State(state): State<AppState>) -> Json<Value> {
 
 async fn register(
     State(state): State<AppState>,
+    headers: HeaderMap,
     MatrixJson(body): MatrixJson<Value>,
 ) -> Result<Json<Value>, ApiError> {
     let username = body
         .get("username")
         .and_then(|v| v.as_str())
         .ok_or_else(|| ApiError::bad_request("Username required".to_string()))?;
+
+    // Application services register users in their namespace with their `as_token`
+    let registration_type = body
+        .get("type")
+        .or_else(|| body.get("auth").and_then(|a| a.get("type")))
+        .and_then(|t| t.as_str());
+    if registration_type == Some("m.login.application_service") {
+        let as_token = extract_token_from_headers(&headers)?;
+        let inhibit_login = body.get("inhibit_login").and_then(|v| v.as_bool()).unwrap_or(false);
+        return Ok(Json(
+            state
+                .services
+                .registration_service
+                .register_appservice_user(&as_token, username, inhibit_login)
+                .await?,
+        ));
+    }
+
     let password = body
         .get("password")
         .and_then(|v| v.as_str())
@@ -517,32 +631,49 @@ async fn register(
         .validator
         .validate_password(password)?;
 
-    // Matrix spec: check for registration type if provided
-    if let Some(auth_type) = body
-        .get("auth")
-        .and_then(|a| a.get("type"))
-        .and_then(|t| t.as_str())
-    {
-        if auth_type != "m.login.password" && auth_type != "m.login.dummy" {
-            return Err(ApiError::bad_request(format!(
-                "Unsupported authentication type: {}",
-                auth_type
-            )));
-        }
-    }
+    // Registration tokens, captcha, terms and required 3PIDs are UIA stages of /register;
+    // without any of them, registration takes the `m.login.dummy` stage
+    let ui_auth_session = state
+        .services
+        .ui_auth_service
+        .authenticate(UiAuthAction::Register, None, body.get("auth"))
+        .await?;
 
     // P0 Security: Standard registration cannot request admin status.
     // Admin registration must go through the dedicated admin registration flow (HMAC-based).
     let admin = false;
     let displayname = body.get("displayname").and_then(|v| v.as_str());
 
-    Ok(Json(
+    let registered = state
+        .services
+        .registration_service
+        .register_user(username, password, admin, displayname)
+        .await?;
+    if let (Some(session), Some(user_id)) = (&ui_auth_session, registered["user_id"].as_str()) {
         state
             .services
-            .registration_service
-            .register_user(username, password, admin, displayname)
-            .await?,
-    ))
+            .ui_auth_service
+            .finish(session, user_id)
+            .await?;
+    }
+    Ok(Json(registered))
+}
+
+/// Lets clients check a registration token before starting registration (MSC3231).
+async fn check_registration_token_validity(
+    State(state): State<AppState>,
+    Query(params): Query<Value>,
+) -> Result<Json<Value>, ApiError> {
+    let token = params
+        .get("token")
+        .and_then(|v| v.as_str())
+        .ok_or_else(|| ApiError::bad_request("Missing token".to_string()))?;
+    let valid = state
+        .services
+        .registration_token_service
+        .is_valid(token)
+        .await?;
+    Ok(Json(json!({ "valid": valid })))
 }
 
 async fn check_username_availability(
@@ -564,6 +695,11 @@ async fn check_username_availability(
     }
 
     let user_id = format!("@{}:{}", username, state.services.server_name);
+    state
+        .services
+        .registration_service
+        .check_not_exclusive(&user_id)
+        .await?;
     let exists = state
         .services
         .user_storage
@@ -702,6 +838,65 @@ async fn submit_email_token(
     })))
 }
 
+/// Texts a code to the phone number for the `m.login.msisdn` registration stage.
+async fn request_msisdn_verification(
+    State(state): State<AppState>,
+    MatrixJson(body): MatrixJson<Value>,
+) -> Result<Json<Value>, ApiError> {
+    let phone_number = body
+        .get("phone_number")
+        .and_then(|v| v.as_str())
+        .ok_or_else(|| ApiError::bad_request("Phone number is required".to_string()))?;
+    let client_secret = body
+        .get("client_secret")
+        .and_then(|v| v.as_str())
+        .ok_or_else(|| ApiError::bad_request("Client secret is required".to_string()))?;
+
+    let sid = state
+        .services
+        .phone_verification_service
+        .request_session(phone_number, client_secret)
+        .await?;
+
+    let submit_url = format!(
+        "https://{}:{}/_matrix/client/r0/register/msisdn/submitToken",
+        state.services.config.server.host, state.services.config.server.port
+    );
+
+    Ok(Json(json!({
+        "sid": sid,
+        "submit_url": submit_url
+    })))
+}
+
+async fn submit_msisdn_token(
+    State(state): State<AppState>,
+    MatrixJson(body): MatrixJson<Value>,
+) -> Result<Json<Value>, ApiError> {
+    let sid = body
+        .get("sid")
+        .and_then(|v| v.as_str())
+        .ok_or_else(|| ApiError::bad_request("Session ID (sid) is required".to_string()))?;
+    let client_secret = body
+        .get("client_secret")
+        .and_then(|v| v.as_str())
+        .ok_or_else(|| ApiError::bad_request("Client secret is required".to_string()))?;
+    let token = body
+        .get("token")
+        .and_then(|v| v.as_str())
+        .ok_or_else(|| ApiError::bad_request("Verification token is required".to_string()))?;
+
+    let success = state
+        .services
+        .phone_verification_service
+        .submit_session_code(sid, client_secret, token)
+        .await?;
+
+    Ok(Json(json!({
+        "success": success
+    })))
+}
+
 async fn login(
     State(state): State<AppState>,
     MatrixJson(body): MatrixJson<Value>,
@@ -915,13 +1110,15 @@ async fn get_profile(
 ) -> Result<Json<Value>, ApiError> {
     validate_user_id(&user_id)?;
 
-    Ok(Json(
-        state
-            .services
-            .registration_service
-            .get_profile(&user_id)
-            .await?,
-    ))
+    let profile = state.services.registration_service.get_profile(&user_id).await;
+    let profile = match profile {
+        // An application service may create users in its namespace on demand
+        Err(ApiError::NotFound(_)) if state.services.app_services.query_user(&user_id).await => {
+            state.services.registration_service.get_profile(&user_id).await
+        }
+        profile => profile,
+    };
+    Ok(Json(profile?))
 }
 
 async fn update_displayname(
@@ -1013,6 +1210,14 @@ async fn change_password(
     auth_user: AuthenticatedUser,
     Json(body): Json<Value>,
 ) -> Result<Json<Value>, ApiError> {
+    let ui_auth_session = require_ui_auth(
+        &state,
+        &auth_user,
+        UiAuthAction::ChangePassword,
+        Some(&body),
+    )
+    .await?;
+
     let new_password = body
         .get("new_password")
         .and_then(|v| v.as_str())
@@ -1030,13 +1235,53 @@ async fn change_password(
         .change_password(&auth_user.user_id, new_password)
         .await?;
 
+    finish_ui_auth(&state, &auth_user, ui_auth_session).await?;
     Ok(Json(json!({})))
 }
 
+/// Runs user-interactive authentication for `action` on the `auth` dict of the request body,
+/// answering 401 with the remaining flows until the user has authenticated.
+async fn require_ui_auth(
+    state: &AppState,
+    auth_user: &AuthenticatedUser,
+    action: UiAuthAction,
+    body: Option<&Value>,
+) -> Result<Option<UiAuthSession>, ApiError> {
+    state
+        .services
+        .ui_auth_service
+        .authenticate(action, Some(&auth_user.user_id), body.and_then(|b| b.get("auth")))
+        .await
+}
+
+/// Closes the UIA session of a request that went through.
+async fn finish_ui_auth(
+    state: &AppState,
+    auth_user: &AuthenticatedUser,
+    session: Option<UiAuthSession>,
+) -> Result<(), ApiError> {
+    if let Some(session) = session {
+        state
+            .services
+            .ui_auth_service
+            .finish(&session, &auth_user.user_id)
+            .await?;
+    }
+    Ok(())
+}
+
 async fn deactivate_account(
     State(state): State<AppState>,
     auth_user: AuthenticatedUser,
+    body: Option<Json<Value>>,
 ) -> Result<Json<Value>, ApiError> {
+    let ui_auth_session = require_ui_auth(
+        &state,
+        &auth_user,
+        UiAuthAction::DeactivateAccount,
+        body.as_ref().map(|Json(b)| b),
+    )
+    .await?;
     let user_id = auth_user.user_id.clone();
 
     state
@@ -1057,6 +1302,7 @@ async fn deactivate_account(
         .delete(&format!("token:{}", auth_user.access_token))
         .await;
 
+    finish_ui_auth(&state, &auth_user, ui_auth_session).await?;
     Ok(Json(json!({})))
 }
 
@@ -1072,10 +1318,9 @@ async fn create_filter(
 
     let filter_id = state
         .services
-        .user_storage
-        .create_filter(&user_id, &filter)
-        .await
-        .map_err(|e| ApiError::internal(format!("Failed to create filter: {}", e)))?;
+        .sync_service
+        .set_filter(&user_id, &filter)
+        .await?;
 
     Ok(Json(json!({ "filter_id": filter_id })))
 }
@@ -1091,10 +1336,9 @@ async fn get_filter(
 
     let filter = state
         .services
-        .user_storage
+        .sync_service
         .get_filter(&user_id, &filter_id)
-        .await
-        .map_err(|e| ApiError::internal(format!("Failed to get filter: {}", e)))?
+        .await?
         .ok_or_else(|| ApiError::not_found("Filter not found".to_string()))?;
 
     Ok(Json(filter))
@@ -1117,6 +1361,12 @@ async fn set_account_data(
         .await
         .map_err(|e| ApiError::internal(format!("Failed to set account data: {}", e)))?;
 
+    state
+        .services
+        .notifier
+        .notify_users(NotifierStream::AccountData, std::slice::from_ref(&user_id))
+        .await;
+
     Ok(Json(json!({})))
 }
 
@@ -1142,12 +1392,9 @@ async fn get_account_data(
 
 async fn search_user_directory(
     State(state): State<AppState>,
-    headers: HeaderMap,
+    _auth_user: AuthenticatedUser,
     Json(body): Json<Value>,
 ) -> Result<Json<Value>, ApiError> {
-    let token = extract_token_from_headers(&headers)?;
-    let (_, _, _) = state.services.auth_service.validate_token(&token).await?;
-
     let search_query = body
         .get("search_term")
         .and_then(|v| v.as_str())
@@ -1181,12 +1428,9 @@ async fn search_user_directory(
 
 async fn list_user_directory(
     State(state): State<AppState>,
-    headers: HeaderMap,
+    _auth_user: AuthenticatedUser,
     Json(body): Json<Value>,
 ) -> Result<Json<Value>, ApiError> {
-    let token = extract_token_from_headers(&headers)?;
-    let (_, _, _) = state.services.auth_service.validate_token(&token).await?;
-
     let limit = body.get("limit").and_then(|v| v.as_u64()).unwrap_or(50) as i64;
 
     let offset = body.get("offset").and_then(|v| v.as_u64()).unwrap_or(0) as i64;
@@ -1282,21 +1526,25 @@ async fn update_report_score(
     Ok(Json(json!({})))
 }
 
+const MAX_SYNC_TIMEOUT_MS: u64 = 300_000;
+const MAX_MESSAGES_LIMIT: u64 = 1000;
+
 async fn sync(
     State(state): State<AppState>,
-    headers: HeaderMap,
+    auth_user: AuthenticatedUser,
     Query(params): Query<Value>,
 ) -> Result<Json<Value>, ApiError> {
-    let token = extract_token_from_headers(&headers)?;
-    let (user_id, _, _) = state.services.auth_service.validate_token(&token).await?;
+    let AuthenticatedUser { user_id, device_id, .. } = auth_user;
 
+    // Query string values arrive as strings
     let timeout = params
         .get("timeout")
-        .and_then(|v| v.as_u64())
-        .unwrap_or(30000);
+        .and_then(|v| v.as_u64().or_else(|| v.as_str().and_then(|s| s.parse().ok())))
+        .unwrap_or(0)
+        .min(MAX_SYNC_TIMEOUT_MS);
     let full_state = params
         .get("full_state")
-        .and_then(|v| v.as_bool())
+        .and_then(|v| v.as_bool().or_else(|| v.as_str().map(|s| s == "true")))
         .unwrap_or(false);
     let set_presence = params
         .get("set_presence")
@@ -1305,78 +1553,120 @@ async fn sync(
     let since = params
         .get("since")
         .and_then(|v| v.as_str());
+    let filter = state
+        .services
+        .sync_service
+        .resolve_filter(&user_id, params.get("filter").and_then(|v| v.as_str()))
+        .await?;
 
     Ok(Json(
         state
             .services
             .sync_service
-            .sync(&user_id, timeout, full_state, set_presence, since)
+            .sync_with_filter(
+                &user_id,
+                device_id.as_deref(),
+                timeout,
+                full_state,
+                set_presence,
+                since,
+                &filter,
+            )
             .await?,
     ))
 }
 
 async fn get_messages(
     State(state): State<AppState>,
-    _auth_user: AuthenticatedUser,
+    auth_user: AuthenticatedUser,
     Path(room_id): Path<String>,
     Query(params): Query<Value>,
 ) -> Result<Json<Value>, ApiError> {
     validate_room_id(&room_id)?;
 
-    let from = params
-        .get("from")
-        .and_then(|v| v.as_str())
-        .and_then(|s| s.parse().ok())
-        .unwrap_or(0);
-    let limit = params.get("limit").and_then(|v| v.as_u64()).unwrap_or(10);
+    let from = params.get("from").and_then(|v| v.as_str()).unwrap_or("");
+    let limit = params
+        .get("limit")
+        .and_then(|v| v.as_u64().or_else(|| v.as_str().and_then(|s| s.parse().ok())))
+        .unwrap_or(10)
+        .min(MAX_MESSAGES_LIMIT);
     let direction = params.get("dir").and_then(|v| v.as_str()).unwrap_or("b");
+    let filter = match params.get("filter").and_then(|v| v.as_str()) {
+        Some(raw) => serde_json::from_str::<SyncFilter>(raw)
+            .map_err(|e| ApiError::bad_request(format!("Invalid filter: {}", e)))?,
+        None => SyncFilter {
+            limit: None,
+            ..Default::default()
+        },
+    };
 
-    Ok(Json(
-        state
-            .services
-            .room_service
-            .get_room_messages(&room_id, from as i64, limit as i64, direction)
-            .await?,
-    ))
+    let sync_service = &state.services.sync_service;
+    let mut response = sync_service
+        .get_room_messages(&room_id, &auth_user.user_id, from, limit as i64, direction, &filter)
+        .await?;
+
+    // Paginating back past our earliest event in a room we joined late: fetch more history
+    // from the other servers in it and try again.
+    let short = response["chunk"].as_array().map_or(0, |chunk| chunk.len()) < limit as usize;
+    if direction == "b" && short {
+        match federation::backfill_room(&state, &room_id, limit as usize).await {
+            Ok(0) => {}
+            Ok(_) => {
+                response = sync_service
+                    .get_room_messages(&room_id, &auth_user.user_id, from, limit as i64, direction, &filter)
+                    .await?;
+            }
+            Err(e) => ::tracing::warn!("Failed to backfill {}: {}", room_id, e),
+        }
+    }
+
+    if wants_edits_applied(&params) {
+        if let Some(chunk) = response["chunk"].as_array_mut() {
+            apply_edits(chunk);
+        }
+    }
+
+    Ok(Json(response))
+}
+
+/// Whether the client asked for events as last edited, with `apply_edits=true`.
+fn wants_edits_applied(params: &Value) -> bool {
+    match params.get("apply_edits") {
+        Some(Value::Bool(apply)) => *apply,
+        Some(Value::String(apply)) => apply == "true",
+        _ => false,
+    }
 }
 
 async fn send_message(
     State(state): State<AppState>,
     auth_user: AuthenticatedUser,
-    Path((room_id, _event_type, _txn_id)): Path<(String, String, String)>,
+    Path((room_id, event_type, _txn_id)): Path<(String, String, String)>,
     Json(body): Json<Value>,
 ) -> Result<Json<Value>, ApiError> {
     validate_room_id(&room_id)?;
 
-    let msgtype = body
-        .get("msgtype")
-        .and_then(|v| v.as_str())
-        .unwrap_or("m.room.message");
-    let content = body
-        .get("body")
-        .ok_or_else(|| ApiError::bad_request("Message body required".to_string()))?;
-
+    if !body.is_object() {
+        return Err(ApiError::bad_request("Event content must be an object".to_string()));
+    }
     // Validate content length to prevent DoS
-    if let Some(s) = content.as_str() {
-        if s.len() > 65536 {
-            return Err(ApiError::bad_request(
-                "Message body too long (max 64KB)".to_string(),
-            ));
-        }
-    } else {
-        let s = content.to_string();
-        if s.len() > 65536 {
-            return Err(ApiError::bad_request(
-                "Message body too long (max 64KB)".to_string(),
-            ));
-        }
+    if body.to_string().len() > 65536 {
+        return Err(ApiError::bad_request(
+            "Message body too long (max 64KB)".to_string(),
+        ));
     }
 
+    state
+        .services
+        .thread_service
+        .check_relation(&room_id, &auth_user.user_id, &event_type, &body)
+        .await?;
+
     Ok(Json(
         state
             .services
             .room_service
-            .send_message(&room_id, &auth_user.user_id, msgtype, content)
+            .send_event(&room_id, &auth_user.user_id, &event_type, body)
             .await?,
     ))
 }
@@ -1385,6 +1675,7 @@ async fn get_event(
     State(state): State<AppState>,
     auth_user: AuthenticatedUser,
     Path((room_id, event_id)): Path<(String, String)>,
+    Query(params): Query<Value>,
 ) -> Result<Json<Value>, ApiError> {
     validate_room_id(&room_id)?;
 
@@ -1413,7 +1704,7 @@ async fn get_event(
         return Err(ApiError::not_found("Event not found in this room".to_string()));
     }
 
-    Ok(Json(json!({
+    let mut event = [json!({
         "event_id": event.event_id,
         "room_id": event.room_id,
         "sender": event.user_id,
@@ -1421,7 +1712,18 @@ async fn get_event(
         "content": event.content,
         "origin_server_ts": event.origin_server_ts,
         "state_key": event.state_key
-    })))
+    })];
+    state
+        .services
+        .thread_service
+        .bundle_aggregations(&auth_user.user_id, &mut event)
+        .await?;
+    if wants_edits_applied(&params) {
+        apply_edits(&mut event);
+    }
+    let [event] = event;
+
+    Ok(Json(event))
 }
 
 async fn get_event_context(
@@ -1480,29 +1782,30 @@ async fn get_event_context(
         .await
         .map_err(|e| ApiError::internal(format!("Failed to get state: {}", e)))?;
 
+    let context_event = |e: &crate::storage::RoomEvent| json!({
+        "event_id": e.event_id,
+        "sender": e.user_id,
+        "type": e.event_type,
+        "content": e.content,
+        "origin_server_ts": e.origin_server_ts
+    });
+    let mut event_json = [context_event(&event)];
+    event_json[0]["room_id"] = json!(event.room_id);
+    let mut before_json: Vec<Value> = events_before.iter().map(context_event).collect();
+    let mut after_json: Vec<Value> = events_after.iter().map(context_event).collect();
+    let thread_service = &state.services.thread_service;
+    for events in [&mut event_json[..], &mut before_json[..], &mut after_json[..]] {
+        thread_service.bundle_aggregations(&auth_user.user_id, events).await?;
+        if wants_edits_applied(&params) {
+            apply_edits(events);
+        }
+    }
+    let [event_json] = event_json;
+
     Ok(Json(json!({
-        "event": {
-            "event_id": event.event_id,
-            "room_id": event.room_id,
-            "sender": event.user_id,
-            "type": event.event_type,
-            "content": event.content,
-            "origin_server_ts": event.origin_server_ts
-        },
-        "events_before": events_before.iter().map(|e| json!({
-            "event_id": e.event_id,
-            "sender": e.user_id,
-            "type": e.event_type,
-            "content": e.content,
-            "origin_server_ts": e.origin_server_ts
-        })).collect::<Vec<_>>(),
-        "events_after": events_after.iter().map(|e| json!({
-            "event_id": e.event_id,
-            "sender": e.user_id,
-            "type": e.event_type,
-            "content": e.content,
-            "origin_server_ts": e.origin_server_ts
-        })).collect::<Vec<_>>(),
+        "event": event_json,
+        "events_before": before_json,
+        "events_after": after_json,
         "state": state_events.iter().map(|e| json!({
             "event_id": e.event_id,
             "sender": e.user_id,
@@ -1685,34 +1988,79 @@ async fn timestamp_to_event(
 
 async fn join_room(
     State(state): State<AppState>,
-    headers: HeaderMap,
+    auth_user: AuthenticatedUser,
     Path(room_id): Path<String>,
 ) -> Result<Json<Value>, ApiError> {
     validate_room_id(&room_id)?;
 
-    let token = extract_token_from_headers(&headers)?;
-    let (user_id, _, _) = state.services.auth_service.validate_token(&token).await?;
+    let user_id = auth_user.user_id;
 
-    state
-        .services
-        .room_service
-        .join_room(&room_id, &user_id)
-        .await?;
+    join_room_via(&state, &room_id, &user_id, Vec::new(), None).await?;
     Ok(Json(json!({})))
 }
 
+/// Joins locally when we hold the room's state, and otherwise through one of `servers` or
+/// the server named in the room ID.
+async fn join_room_via(
+    state: &AppState,
+    room_id: &str,
+    user_id: &str,
+    servers: Vec<String>,
+    reason: Option<&str>,
+) -> Result<(), ApiError> {
+    let room_service = &state.services.room_service;
+    if !state.services.config.federation.enabled || !room_service.needs_remote_membership(room_id).await? {
+        return room_service.join_room(room_id, user_id).await;
+    }
+
+    let servers = candidate_servers(room_id, servers);
+    let content = room_service.membership_content(user_id, "join", reason).await?;
+    federation::remote_join(state, room_id, user_id, &servers, &content).await?;
+    Ok(())
+}
+
+/// Servers to try for a remote membership change: those given, then the room's own.
+fn candidate_servers(room_id: &str, mut servers: Vec<String>) -> Vec<String> {
+    if let Some(server) = crate::federation::pdu::server_name_of(room_id) {
+        servers.push(server.to_string());
+    }
+    let mut seen = std::collections::HashSet::new();
+    servers.retain(|server| !server.is_empty() && seen.insert(server.clone()));
+    servers
+}
+
 async fn leave_room(
     State(state): State<AppState>,
     auth_user: AuthenticatedUser,
     Path(room_id): Path<String>,
+    body: Option<Json<Value>>,
 ) -> Result<Json<Value>, ApiError> {
     validate_room_id(&room_id)?;
 
-    state
+    let room_service = &state.services.room_service;
+    if !state.services.config.federation.enabled || !room_service.needs_remote_membership(&room_id).await? {
+        room_service.leave_room(&room_id, &auth_user.user_id).await?;
+        return Ok(Json(json!({})));
+    }
+
+    // We are not in the room, so this rejects an invite or retracts a knock; the server that
+    // sent it is the one most likely to still be in the room
+    let mut servers = Vec::new();
+    if let Some(member) = state
         .services
-        .room_service
-        .leave_room(&room_id, &auth_user.user_id)
-        .await?;
+        .member_storage
+        .get_room_member(&room_id, &auth_user.user_id)
+        .await
+        .map_err(|e| ApiError::internal(format!("Failed to get membership: {}", e)))?
+    {
+        if let Some(server) = member.sender.as_deref().and_then(crate::federation::pdu::server_name_of) {
+            servers.push(server.to_string());
+        }
+    }
+    let servers = candidate_servers(&room_id, servers);
+    let reason = body.as_ref().and_then(|Json(body)| body.get("reason")).and_then(|v| v.as_str());
+    let content = room_service.membership_content(&auth_user.user_id, "leave", reason).await?;
+    federation::remote_leave(&state, &room_id, &auth_user.user_id, &servers, &content).await?;
     Ok(Json(json!({})))
 }
 
@@ -1733,64 +2081,127 @@ async fn forget_room(
 
 async fn join_room_by_id_or_alias(
     State(state): State<AppState>,
-    headers: HeaderMap,
+    auth_user: AuthenticatedUser,
     Path(room_id_or_alias): Path<String>,
+    axum::extract::RawQuery(query): axum::extract::RawQuery,
     Json(body): Json<Value>,
 ) -> Result<Json<Value>, ApiError> {
-    let token = extract_token_from_headers(&headers)?;
-    let (user_id, _, _) = state.services.auth_service.validate_token(&token).await?;
+    let user_id = auth_user.user_id;
+
+    // `via` replaced `server_name`; both may be repeated. Older clients sent them in the body.
+    let mut servers: Vec<String> = url::form_urlencoded::parse(query.unwrap_or_default().as_bytes())
+        .filter(|(key, _)| key == "via" || key == "server_name")
+        .map(|(_, server)| server.into_owned())
+        .collect();
+    if let Some(body_servers) = body.get("server_name").and_then(|v| v.as_array()) {
+        servers.extend(body_servers.iter().filter_map(|x| x.as_str().map(String::from)));
+    }
 
     let room_id = if room_id_or_alias.starts_with('!') {
         room_id_or_alias.clone()
-    } else if room_id_or_alias.starts_with('#') {
-        let resolved = state
-            .services
-            .room_service
-            .get_room_by_alias(&room_id_or_alias)
-            .await?
-            .ok_or_else(|| ApiError::not_found("Room alias not found".to_string()))?;
-        resolved
     } else {
-        let alias = format!("#{}", room_id_or_alias);
-        let resolved = state
-            .services
-            .room_service
-            .get_room_by_alias(&alias)
-            .await?
-            .ok_or_else(|| ApiError::not_found("Room alias not found".to_string()))?;
-        resolved
+        let alias = if room_id_or_alias.starts_with('#') {
+            room_id_or_alias.clone()
+        } else {
+            format!("#{}", room_id_or_alias)
+        };
+        let (room_id, alias_servers) = resolve_room_alias(&state, &alias).await?;
+        servers.extend(alias_servers);
+        room_id
     };
 
-    let _server_names = body
-        .get("server_name")
-        .and_then(|v| v.as_array())
-        .map(|arr| {
-            arr.iter()
-                .filter_map(|x| x.as_str().map(String::from))
-                .collect::<Vec<String>>()
-        })
-        .unwrap_or_default();
-
-    state
-        .services
-        .room_service
-        .join_room(&room_id, &user_id)
-        .await?;
+    let reason = body.get("reason").and_then(|v| v.as_str());
+    join_room_via(&state, &room_id, &user_id, servers, reason).await?;
 
     Ok(Json(json!({
         "room_id": room_id
     })))
 }
 
-async fn get_room_members(
+/// Knocks on a room: through the knock service when we hold the room's state, and otherwise
+/// through one of the `via` servers or the server named in the room ID.
+async fn knock_room(
     State(state): State<AppState>,
-    headers: HeaderMap,
-    Path(room_id): Path<String>,
+    auth_user: AuthenticatedUser,
+    Path(room_id_or_alias): Path<String>,
+    axum::extract::RawQuery(query): axum::extract::RawQuery,
+    body: Option<Json<Value>>,
+) -> Result<Json<Value>, ApiError> {
+    let user_id = auth_user.user_id;
+    let body = body.map(|Json(body)| body).unwrap_or_else(|| json!({}));
+
+    let mut servers: Vec<String> = url::form_urlencoded::parse(query.unwrap_or_default().as_bytes())
+        .filter(|(key, _)| key == "via" || key == "server_name")
+        .map(|(_, server)| server.into_owned())
+        .collect();
+    let room_id = if room_id_or_alias.starts_with('!') {
+        validate_room_id(&room_id_or_alias)?;
+        room_id_or_alias
+    } else {
+        let (room_id, alias_servers) = resolve_room_alias(&state, &room_id_or_alias).await?;
+        servers.extend(alias_servers);
+        room_id
+    };
+
+    let reason = body.get("reason").and_then(|v| v.as_str());
+    let room_service = &state.services.room_service;
+    let content = room_service.membership_content(&user_id, "knock", reason).await?;
+    if !state.services.config.federation.enabled || !room_service.needs_remote_membership(&room_id).await? {
+        state.services.knock_service.knock(&room_id, &user_id, &content).await?;
+    } else {
+        let servers = candidate_servers(&room_id, servers);
+        federation::remote_knock(&state, &room_id, &user_id, &servers, &content).await?;
+    }
+
+    Ok(Json(json!({
+        "room_id": room_id
+    })))
+}
+
+/// Looks up one of our aliases. An unknown alias in an application service's namespace is
+/// queried from the service, which may create the room, and looked up again.
+async fn get_local_room_by_alias(state: &AppState, alias: &str) -> Result<Option<String>, ApiError> {
+    let room_id = state.services.room_service.get_room_by_alias(alias).await?;
+    if room_id.is_some() || !state.services.app_services.query_alias(alias).await {
+        return Ok(room_id);
+    }
+    state.services.room_service.get_room_by_alias(alias).await
+}
+
+/// Resolves a room alias, asking the server it belongs to when that is not us. Returns the
+/// room ID and servers to join it through.
+async fn resolve_room_alias(state: &AppState, alias: &str) -> Result<(String, Vec<String>), ApiError> {
+    let alias_server = crate::federation::pdu::server_name_of(alias)
+        .ok_or_else(|| ApiError::bad_request("Invalid room alias".to_string()))?;
+
+    if alias_server == state.services.server_name || !state.services.config.federation.enabled {
+        let room_id = get_local_room_by_alias(state, alias)
+            .await?
+            .ok_or_else(|| ApiError::not_found("Room alias not found".to_string()))?;
+        return Ok((room_id, Vec::new()));
+    }
+
+    let (room_id, mut servers) = state
+        .services
+        .federation_client
+        .query_room_alias(alias_server, alias)
+        .await
+        .map_err(|e| match e.status() {
+            Some(404) => ApiError::not_found("Room alias not found".to_string()),
+            _ => e.into(),
+        })?;
+    servers.push(alias_server.to_string());
+    Ok((room_id, servers))
+}
+
+async fn get_room_members(
+    State(state): State<AppState>,
+    auth_user: AuthenticatedUser,
+    Path(room_id): Path<String>,
 ) -> Result<Json<Value>, ApiError> {
     validate_room_id(&room_id)?;
 
-    let token = extract_token_from_headers(&headers)?;
-    let (user_id, _, _) = state.services.auth_service.validate_token(&token).await?;
+    let user_id = auth_user.user_id;
 
     let members = state
         .services
@@ -1808,6 +2219,11 @@ async fn invite_user(
 ) -> Result<Json<Value>, ApiError> {
     validate_room_id(&room_id)?;
 
+    if body.get("id_server").is_some() {
+        invite_third_party_id(&state, &room_id, &auth_user.user_id, &body).await?;
+        return Ok(Json(json!({})));
+    }
+
     let invitee = body
         .get("user_id")
         .and_then(|v| v.as_str())
@@ -1815,21 +2231,144 @@ async fn invite_user(
 
     validate_user_id(invitee)?;
 
+    let reason = body.get("reason").and_then(|v| v.as_str());
+    invite_by_user_id(&state, &room_id, &auth_user.user_id, invitee, reason).await?;
+    Ok(Json(json!({})))
+}
+
+/// Invites a user, through their server when they are not one of ours.
+async fn invite_by_user_id(
+    state: &AppState,
+    room_id: &str,
+    inviter: &str,
+    invitee: &str,
+    reason: Option<&str>,
+) -> Result<(), ApiError> {
+    if crate::federation::pdu::server_name_of(invitee) == Some(state.services.server_name.as_str()) {
+        // Inviting a knocker is how their knock is approved
+        if membership_of(state, room_id, invitee).await?.as_deref() == Some("knock") {
+            state.services.knock_service.approve(room_id, invitee, inviter, reason).await?;
+            return Ok(());
+        }
+        return state.services.room_service.invite_user(room_id, inviter, invitee).await;
+    }
+    if !state.services.config.federation.enabled {
+        return Err(ApiError::forbidden("Federation is disabled".to_string()));
+    }
+
+    let mut content = json!({ "membership": "invite" });
+    if let Some(reason) = reason {
+        content["reason"] = json!(reason);
+    }
+    federation::remote_invite(state, room_id, inviter, invitee, &content).await?;
+    Ok(())
+}
+
+async fn membership_of(state: &AppState, room_id: &str, user_id: &str) -> Result<Option<String>, ApiError> {
     state
         .services
-        .room_service
-        .invite_user(&room_id, &auth_user.user_id, invitee)
-        .await?;
-    Ok(Json(json!({})))
+        .member_storage
+        .get_membership(room_id, user_id)
+        .await
+        .map_err(|e| ApiError::internal(format!("Failed to check membership: {}", e)))
+}
+
+/// Invites users on other servers named in a `createRoom` request. The room exists by then, so
+/// failures are logged rather than failing the request.
+async fn invite_remote_users(state: &AppState, room_id: &str, inviter: &str, invitees: &[String]) {
+    let server_name = state.services.server_name.as_str();
+    let remote = invitees
+        .iter()
+        .filter(|invitee| crate::federation::pdu::server_name_of(invitee) != Some(server_name));
+    for invitee in remote {
+        if let Err(e) = invite_by_user_id(state, room_id, inviter, invitee, None).await {
+            ::tracing::warn!("Failed to invite {} to {}: {}", invitee, room_id, e);
+        }
+    }
+}
+
+/// Invites an email address or phone number. When the identity server already has it bound to
+/// a user, that user is invited; otherwise the identity server stores the invite and the room
+/// gets an `m.room.third_party_invite` event the bound user's server can exchange later.
+async fn invite_third_party_id(state: &AppState, room_id: &str, inviter: &str, body: &Value) -> Result<(), ApiError> {
+    let field = |name: &str| {
+        body.get(name)
+            .and_then(|v| v.as_str())
+            .ok_or_else(|| ApiError::bad_request(format!("{} required", name)))
+    };
+    let id_server = field("id_server")?;
+    let id_access_token = field("id_access_token")?;
+    let medium = field("medium")?;
+    let address = field("address")?;
+
+    let is_member = state
+        .services
+        .member_storage
+        .is_member(room_id, inviter)
+        .await
+        .map_err(|e| ApiError::internal(format!("Failed to check membership: {}", e)))?;
+    if !is_member {
+        return Err(ApiError::forbidden("You must be a member of this room to invite".to_string()));
+    }
+
+    let identity_service = &state.services.identity_service;
+    if let Some(user_id) = identity_service.lookup(id_server, id_access_token, medium, address).await? {
+        return invite_by_user_id(state, room_id, inviter, &user_id, None).await;
+    }
+
+    let room = state
+        .services
+        .room_storage
+        .get_room(room_id)
+        .await
+        .map_err(|e| ApiError::internal(format!("Failed to get room: {}", e)))?
+        .ok_or_else(|| ApiError::not_found("Room not found".to_string()))?;
+    let sender_display_name = state
+        .services
+        .user_storage
+        .get_user_by_id(inviter)
+        .await
+        .map_err(|e| ApiError::internal(format!("Failed to get user: {}", e)))?
+        .and_then(|user| user.displayname);
+    let invite = StoreInviteRequest {
+        medium: medium.to_string(),
+        address: address.to_string(),
+        room_id: room_id.to_string(),
+        sender: inviter.to_string(),
+        sender_display_name,
+        room_alias: room.canonical_alias,
+        room_name: room.name,
+        room_avatar_url: room.avatar_url,
+        room_join_rules: Some(room.join_rule),
+    };
+    let stored = identity_service.store_invite(id_server, id_access_token, &invite).await?;
+
+    state
+        .services
+        .event_storage
+        .create_event(
+            CreateEventParams {
+                event_id: crate::common::crypto::generate_event_id(&state.services.server_name),
+                room_id: room_id.to_string(),
+                user_id: inviter.to_string(),
+                event_type: "m.room.third_party_invite".to_string(),
+                content: stored.event_content(),
+                state_key: Some(stored.token.clone()),
+                origin_server_ts: chrono::Utc::now().timestamp_millis(),
+            },
+            None,
+        )
+        .await
+        .map_err(|e| ApiError::internal(format!("Failed to create third-party invite: {}", e)))?;
+    Ok(())
 }
 
 async fn create_room(
     State(state): State<AppState>,
-    headers: HeaderMap,
+    auth_user: AuthenticatedUser,
     Json(body): Json<Value>,
 ) -> Result<Json<Value>, ApiError> {
-    let token = extract_token_from_headers(&headers)?;
-    let (user_id, _, _) = state.services.auth_service.validate_token(&token).await?;
+    let user_id = auth_user.user_id;
 
     let visibility = body.get("visibility").and_then(|v| v.as_str());
     if let Some(v) = visibility {
@@ -1890,6 +2429,22 @@ async fn create_room(
 
     let preset = body.get("preset").and_then(|v| v.as_str());
 
+    let creation_content = body.get("creation_content").cloned();
+    if creation_content.as_ref().is_some_and(|c| !c.is_object()) {
+        return Err(ApiError::bad_request("creation_content must be an object".to_string()));
+    }
+    let initial_state = match body.get("initial_state") {
+        None => None,
+        Some(Value::Array(events)) if events.iter().all(|event| event.get("type").is_some_and(|t| t.is_string())) => {
+            Some(events.clone())
+        }
+        Some(_) => {
+            return Err(ApiError::bad_request(
+                "initial_state must be a list of state events".to_string(),
+            ))
+        }
+    };
+
     let config = CreateRoomConfig {
         visibility: visibility.map(|s| s.to_string()),
         room_alias_name: room_alias.map(|s| s.to_string()),
@@ -1897,16 +2452,25 @@ async fn create_room(
         topic: topic.map(|s| s.to_string()),
         invite_list: invite,
         preset: preset.map(|s| s.to_string()),
+        room_version: body
+            .get("room_version")
+            .and_then(|v| v.as_str())
+            .map(|s| s.to_string()),
+        creation_content,
+        initial_state,
         ..Default::default()
     };
 
-    Ok(Json(
-        state
-            .services
-            .room_service
-            .create_room(&user_id, config)
-            .await?,
-    ))
+    let invite_list = config.invite_list.clone().unwrap_or_default();
+    let room = state
+        .services
+        .room_service
+        .create_room(&user_id, config)
+        .await?;
+    if let Some(room_id) = room.get("room_id").and_then(|v| v.as_str()) {
+        invite_remote_users(&state, room_id, &user_id, &invite_list).await;
+    }
+    Ok(Json(room))
 }
 
 async fn get_room(
@@ -2086,11 +2650,7 @@ async fn get_room_by_alias(
         format!("#{}", room_alias)
     };
 
-    let room_id = state
-        .services
-        .room_service
-        .get_room_by_alias(&alias)
-        .await?;
+    let room_id = get_local_room_by_alias(&state, &alias).await?;
     match room_id {
         Some(rid) => Ok(Json(json!({ "room_id": rid }))),
         None => Err(ApiError::not_found("Room alias not found".to_string())),
@@ -2155,16 +2715,23 @@ async fn create_public_room(
         topic: topic.map(|s| s.to_string()),
         invite_list: invite,
         preset: preset.map(|s| s.to_string()),
+        room_version: body
+            .get("room_version")
+            .and_then(|v| v.as_str())
+            .map(|s| s.to_string()),
         ..Default::default()
     };
 
-    Ok(Json(
-        state
-            .services
-            .room_service
-            .create_room(&auth_user.user_id, config)
-            .await?,
-    ))
+    let invite_list = config.invite_list.clone().unwrap_or_default();
+    let room = state
+        .services
+        .room_service
+        .create_room(&auth_user.user_id, config)
+        .await?;
+    if let Some(room_id) = room.get("room_id").and_then(|v| v.as_str()) {
+        invite_remote_users(&state, room_id, &auth_user.user_id, &invite_list).await;
+    }
+    Ok(Json(room))
 }
 
 async fn get_user_rooms(
@@ -2189,10 +2756,9 @@ async fn get_user_rooms(
 
 async fn get_devices(
     State(state): State<AppState>,
-    headers: HeaderMap,
+    auth_user: AuthenticatedUser,
 ) -> Result<Json<Value>, ApiError> {
-    let token = extract_token_from_headers(&headers)?;
-    let (user_id, _, _) = state.services.auth_service.validate_token(&token).await?;
+    let user_id = auth_user.user_id;
 
     let devices = state
         .services
@@ -2263,25 +2829,79 @@ async fn update_device(
 }
 
 async fn delete_device(
-    State(_state): State<AppState>,
-    _auth_user: AuthenticatedUser,
+    State(state): State<AppState>,
+    auth_user: AuthenticatedUser,
     Path(device_id): Path<String>,
+    body: Option<Json<Value>>,
 ) -> Result<Json<Value>, ApiError> {
-    _state
+    let ui_auth_session = require_ui_auth(
+        &state,
+        &auth_user,
+        UiAuthAction::DeleteDevices,
+        body.as_ref().map(|Json(b)| b),
+    )
+    .await?;
+
+    state
         .services
         .device_storage
         .delete_device(&device_id)
         .await
         .map_err(|e| ApiError::internal(format!("Failed to delete device: {}", e)))?;
 
+    queue_device_deletions(&state, &auth_user.user_id, std::slice::from_ref(&device_id)).await;
+    delete_device_pushers(&state, &auth_user.user_id, std::slice::from_ref(&device_id)).await;
+
+    finish_ui_auth(&state, &auth_user, ui_auth_session).await?;
     Ok(Json(json!({})))
 }
 
+/// Tells the servers sharing a room with the user that the devices are gone.
+async fn queue_device_deletions(state: &AppState, user_id: &str, device_ids: &[String]) {
+    for device_id in device_ids {
+        let content = json!({
+            "user_id": user_id,
+            "device_id": device_id,
+            "deleted": true,
+            "stream_id": chrono::Utc::now().timestamp_millis(),
+            "prev_id": []
+        });
+        if let Err(e) = state
+            .services
+            .federation_sender
+            .queue_user_edu(user_id, "m.device_list_update", content)
+            .await
+        {
+            ::tracing::warn!("Failed to queue device list update for federation: {}", e);
+        }
+    }
+}
+
+/// Pushers belong to the device that registered them and stop with it.
+async fn delete_device_pushers(state: &AppState, user_id: &str, device_ids: &[String]) {
+    if let Err(e) = state
+        .services
+        .push_service
+        .delete_device_pushers(user_id, device_ids)
+        .await
+    {
+        ::tracing::warn!("Failed to delete pushers of deleted devices: {}", e);
+    }
+}
+
 async fn delete_devices(
     State(state): State<AppState>,
-    _auth_user: AuthenticatedUser,
+    auth_user: AuthenticatedUser,
     Json(body): Json<Value>,
 ) -> Result<Json<Value>, ApiError> {
+    let ui_auth_session = require_ui_auth(
+        &state,
+        &auth_user,
+        UiAuthAction::DeleteDevices,
+        Some(&body),
+    )
+    .await?;
+
     let devices = body
         .get("devices")
         .and_then(|v| v.as_array())
@@ -2294,6 +2914,7 @@ async fn delete_devices(
         .collect();
 
     if device_ids.is_empty() {
+        finish_ui_auth(&state, &auth_user, ui_auth_session).await?;
         return Ok(Json(json!({})));
     }
 
@@ -2304,6 +2925,10 @@ async fn delete_devices(
         .await
         .map_err(|e| ApiError::internal(format!("Failed to delete devices: {}", e)))?;
 
+    queue_device_deletions(&state, &auth_user.user_id, &device_ids).await;
+    delete_device_pushers(&state, &auth_user.user_id, &device_ids).await;
+
+    finish_ui_auth(&state, &auth_user, ui_auth_session).await?;
     Ok(Json(json!({})))
 }
 
@@ -2354,6 +2979,24 @@ async fn set_presence(
         .await
         .map_err(|e| ApiError::internal(format!("Failed to set presence: {}", e)))?;
 
+    let content = json!({
+        "push": [{
+            "user_id": &user_id,
+            "presence": presence,
+            "status_msg": status_msg,
+            "last_active_ago": 0,
+            "currently_active": presence == "online"
+        }]
+    });
+    if let Err(e) = state
+        .services
+        .federation_sender
+        .queue_user_edu(&user_id, "m.presence", content)
+        .await
+    {
+        ::tracing::warn!("Failed to queue presence for federation: {}", e);
+    }
+
     Ok(Json(json!({})))
 }
 
@@ -2382,6 +3025,32 @@ async fn set_typing(
         .await
         .map_err(|e| ApiError::internal(format!("Failed to set typing: {}", e)))?;
 
+    let content = json!({"room_id": &room_id, "user_id": &user_id, "typing": typing});
+    if let Err(e) = state
+        .services
+        .federation_sender
+        .queue_room_edu(&room_id, "m.typing", content)
+        .await
+    {
+        ::tracing::warn!("Failed to queue typing notification for federation: {}", e);
+    }
+    match state.services.presence_storage.get_typing_users(&room_id).await {
+        Ok(user_ids) => {
+            state
+                .services
+                .appservice_scheduler
+                .handle_room_ephemeral(&room_id, "m.typing", json!({ "user_ids": user_ids }))
+                .await
+        }
+        Err(e) => ::tracing::warn!("Failed to load typing users for application services: {}", e),
+    }
+
+    state
+        .services
+        .notifier
+        .notify_room(NotifierStream::Typing, &room_id, &[])
+        .await;
+
     Ok(Json(json!({})))
 }
 
@@ -2537,7 +3206,6 @@ async fn send_state_event(
         ));
     }
 
-    let new_event_id = crate::common::crypto::generate_event_id(&state.services.server_name);
     let now = chrono::Utc::now().timestamp_millis();
 
     let state_event = state
@@ -2545,7 +3213,7 @@ async fn send_state_event(
         .event_storage
         .create_event(
             CreateEventParams {
-                event_id: new_event_id.clone(),
+                event_id: crate::common::crypto::generate_event_id(&state.services.server_name),
                 room_id: room_id.clone(),
                 user_id: auth_user.user_id.clone(),
                 event_type: event_type.clone(),
@@ -2559,7 +3227,7 @@ async fn send_state_event(
         .map_err(|e| ApiError::internal(format!("Failed to send state event: {}", e)))?;
 
     Ok(Json(json!({
-        "event_id": new_event_id,
+        "event_id": state_event.event_id,
         "type": state_event.event_type,
         "state_key": state_event.state_key
     })))
@@ -2586,7 +3254,6 @@ async fn put_state_event(
         ));
     }
 
-    let new_event_id = crate::common::crypto::generate_event_id(&state.services.server_name);
     let now = chrono::Utc::now().timestamp_millis();
 
     let event = state
@@ -2594,7 +3261,7 @@ async fn put_state_event(
         .event_storage
         .create_event(
             CreateEventParams {
-                event_id: new_event_id.clone(),
+                event_id: crate::common::crypto::generate_event_id(&state.services.server_name),
                 room_id: room_id.clone(),
                 user_id: auth_user.user_id.clone(),
                 event_type: event_type.clone(),
@@ -2608,7 +3275,7 @@ async fn put_state_event(
         .map_err(|e| ApiError::internal(format!("Failed to put state event: {}", e)))?;
 
     Ok(Json(json!({
-        "event_id": new_event_id,
+        "event_id": event.event_id,
         "type": event.event_type,
         "state_key": event.state_key
     })))
@@ -2618,10 +3285,11 @@ async fn send_receipt(
     State(state): State<AppState>,
     auth_user: AuthenticatedUser,
     Path((room_id, receipt_type, event_id)): Path<(String, String, String)>,
-    Json(_body): Json<Value>,
+    Json(body): Json<Value>,
 ) -> Result<Json<Value>, ApiError> {
     validate_room_id(&room_id)?;
     validate_event_id(&event_id)?;
+    let thread_id = body.get("thread_id").and_then(|v| v.as_str());
 
     let event = state
         .services
@@ -2635,8 +3303,8 @@ async fn send_receipt(
         .services
         .room_storage
         .add_receipt(
-            &auth_user.user_id,
             &event.user_id,
+            &auth_user.user_id,
             &room_id,
             &event_id,
             &receipt_type,
@@ -2644,6 +3312,54 @@ async fn send_receipt(
         .await
         .map_err(|e| ApiError::internal(format!("Failed to store receipt: {}", e)))?;
 
+    if matches!(receipt_type.as_str(), "m.read" | "m.read.private") {
+        state
+            .services
+            .push_actions_service
+            .mark_read(&auth_user.user_id, &room_id, thread_id, &event_id)
+            .await?;
+    }
+
+    // Private receipts stay on this server.
+    if receipt_type == "m.read" {
+        let content = json!({
+            &room_id: {
+                "m.read": {
+                    &auth_user.user_id: {
+                        "event_ids": [&event_id],
+                        "data": {"ts": chrono::Utc::now().timestamp_millis()}
+                    }
+                }
+            }
+        });
+        if let Err(e) = state
+            .services
+            .federation_sender
+            .queue_room_edu(&room_id, "m.receipt", content)
+            .await
+        {
+            ::tracing::warn!("Failed to queue receipt for federation: {}", e);
+        }
+        let content = json!({
+            &event_id: {
+                "m.read": {
+                    &auth_user.user_id: {"ts": chrono::Utc::now().timestamp_millis()}
+                }
+            }
+        });
+        state
+            .services
+            .appservice_scheduler
+            .handle_room_ephemeral(&room_id, "m.receipt", content)
+            .await;
+    }
+
+    state
+        .services
+        .notifier
+        .notify_room(NotifierStream::Receipts, &room_id, &[])
+        .await;
+
     Ok(Json(json!({})))
 }
 
@@ -2669,6 +3385,16 @@ async fn set_read_markers(
         .await
         .map_err(|e| ApiError::internal(format!("Failed to set read marker: {}", e)))?;
 
+    for receipt_type in ["m.read", "m.read.private"] {
+        if let Some(read_event_id) = body.get(receipt_type).and_then(|v| v.as_str()) {
+            state
+                .services
+                .push_actions_service
+                .mark_read(&auth_user.user_id, &room_id, None, read_event_id)
+                .await?;
+        }
+    }
+
     Ok(Json(json!({})))
 }
 async fn get_membership_events(
@@ -2720,19 +3446,33 @@ async fn redact_event(
 
     let reason = body.get("reason").and_then(|v| v.as_str());
 
-    let new_event_id = crate::common::crypto::generate_event_id(&state.services.server_name);
+    let original = state
+        .services
+        .event_storage
+        .get_event(&event_id)
+        .await
+        .map_err(|e| ApiError::internal(format!("Failed to get event: {}", e)))?
+        .filter(|event| event.room_id == room_id)
+        .ok_or_else(|| ApiError::not_found("Event not found".to_string()))?;
+    if original.user_id != auth_user.user_id && !can_redact_others(&state, &room_id, &auth_user.user_id).await? {
+        return Err(ApiError::forbidden(
+            "You are not allowed to redact other users' events".to_string(),
+        ));
+    }
+
     let now = chrono::Utc::now().timestamp_millis();
 
     let content = json!({
+        "redacts": event_id,
         "reason": reason
     });
 
-    state
+    let redaction = state
         .services
         .event_storage
         .create_event(
             CreateEventParams {
-                event_id: new_event_id.clone(),
+                event_id: crate::common::crypto::generate_event_id(&state.services.server_name),
                 room_id: room_id.clone(),
                 user_id: auth_user.user_id,
                 event_type: "m.room.redaction".to_string(),
@@ -2745,11 +3485,43 @@ async fn redact_event(
         .await
         .map_err(|e| ApiError::internal(format!("Failed to redact event: {}", e)))?;
 
+    state
+        .services
+        .event_storage
+        .redact_event_content(&event_id)
+        .await
+        .map_err(|e| ApiError::internal(format!("Failed to redact event: {}", e)))?;
+
     Ok(Json(json!({
-        "event_id": new_event_id
+        "event_id": redaction.event_id
     })))
 }
 
+/// Whether the user's power level reaches the room's `redact` level.
+async fn can_redact_others(state: &AppState, room_id: &str, user_id: &str) -> Result<bool, ApiError> {
+    let event_storage = &state.services.event_storage;
+    let state_event = |event_type: &'static str| async move {
+        event_storage
+            .get_state_event(room_id, event_type, "")
+            .await
+            .map_err(|e| ApiError::internal(format!("Failed to get room state: {}", e)))
+    };
+    let power_levels = state_event("m.room.power_levels")
+        .await?
+        .map(|event| json!({ "content": event.content }));
+    let create = state_event("m.room.create")
+        .await?
+        .map(|event| json!({ "sender": event.sender, "content": event.content }))
+        .unwrap_or_default();
+
+    let redact_level = power_levels
+        .as_ref()
+        .and_then(|levels| levels["content"]["redact"].as_i64())
+        .unwrap_or(50);
+    let user_level = crate::federation::event_auth::user_power_level(user_id, power_levels.as_ref(), &create);
+    Ok(user_level >= redact_level)
+}
+
 async fn kick_user(
     State(state): State<AppState>,
     auth_user: AuthenticatedUser,
@@ -2772,6 +3544,16 @@ async fn kick_user(
         }
     }
 
+    // Kicking a knocker is how their knock is rejected
+    if membership_of(&state, &room_id, target).await?.as_deref() == Some("knock") {
+        state
+            .services
+            .knock_service
+            .reject(&room_id, target, &auth_user.user_id, reason)
+            .await?;
+        return Ok(Json(json!({})));
+    }
+
     let event_id = crate::common::crypto::generate_event_id(&state.services.server_name);
     let content = json!({
         "membership": "leave",
@@ -2835,43 +3617,15 @@ async fn ban_user(
         }
     }
 
-    let event_id = crate::common::crypto::generate_event_id(&state.services.server_name);
-    let content = json!({
-        "membership": "ban",
-        "reason": reason.unwrap_or("")
-    });
-
-    state
-        .services
-        .member_storage
-        .add_member(&room_id, target, "ban", None, None, None)
-        .await
-        .map_err(|e| ApiError::internal(format!("Failed to ban user: {}", e)))?;
-
+    let mut content = json!({ "membership": "ban" });
+    if let Some(reason) = reason {
+        content["reason"] = json!(reason);
+    }
     state
         .services
-        .event_storage
-        .create_event(
-            CreateEventParams {
-                event_id,
-                room_id: room_id.clone(),
-                user_id: auth_user.user_id,
-                event_type: "m.room.member".to_string(),
-                content,
-                state_key: Some(target.to_string()),
-                origin_server_ts: chrono::Utc::now().timestamp_millis(),
-            },
-            None,
-        )
-        .await
-        .map_err(|e| {
-            ::tracing::warn!(
-                "Failed to create membership event for room {}: {}",
-                room_id,
-                e
-            );
-        })
-        .ok();
+        .room_service
+        .send_membership(&room_id, &auth_user.user_id, target, content, None)
+        .await?;
 
     Ok(Json(json!({})))
 }
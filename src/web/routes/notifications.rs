@@ -0,0 +1,47 @@
+use super::{AppState, AuthenticatedUser};
+use crate::common::ApiError;
+use axum::{
+    extract::{Query, State},
+    routing::get,
+    Json, Router,
+};
+use serde::Deserialize;
+use serde_json::{json, Value};
+
+const DEFAULT_NOTIFICATIONS_LIMIT: i64 = 50;
+const MAX_NOTIFICATIONS_LIMIT: i64 = 100;
+
+pub fn create_notifications_router(_state: AppState) -> Router<AppState> {
+    Router::new().route("/_matrix/client/v3/notifications", get(get_notifications))
+}
+
+#[derive(Debug, Deserialize)]
+pub struct NotificationsQuery {
+    pub from: Option<String>,
+    pub limit: Option<i64>,
+    /// `highlight` to list only the notifications that highlighted.
+    pub only: Option<String>,
+}
+
+async fn get_notifications(
+    State(state): State<AppState>,
+    auth_user: AuthenticatedUser,
+    Query(query): Query<NotificationsQuery>,
+) -> Result<Json<Value>, ApiError> {
+    let limit = query
+        .limit
+        .unwrap_or(DEFAULT_NOTIFICATIONS_LIMIT)
+        .clamp(1, MAX_NOTIFICATIONS_LIMIT);
+    let only_highlight = query.only.as_deref() == Some("highlight");
+    let page = state
+        .services
+        .push_actions_service
+        .get_notifications(&auth_user.user_id, query.from.as_deref(), limit, only_highlight)
+        .await?;
+
+    let mut response = json!({ "notifications": page.notifications });
+    if let Some(next_token) = page.next_token {
+        response["next_token"] = json!(next_token);
+    }
+    Ok(Json(response))
+}
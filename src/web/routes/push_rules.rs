@@ -0,0 +1,172 @@
+use super::{AppState, AuthenticatedUser};
+use crate::common::ApiError;
+use axum::{
+    extract::{Path, Query, State},
+    routing::get,
+    Json, Router,
+};
+use serde::Deserialize;
+use serde_json::{json, Value};
+
+pub fn create_push_rules_router(_state: AppState) -> Router<AppState> {
+    Router::new()
+        .route("/_matrix/client/v3/pushrules", get(get_push_rules))
+        .route("/_matrix/client/v3/pushrules/", get(get_push_rules))
+        .route("/_matrix/client/v3/pushrules/{scope}/", get(get_push_rules_scope))
+        .route(
+            "/_matrix/client/v3/pushrules/{scope}/{kind}/{rule_id}",
+            get(get_push_rule).put(put_push_rule).delete(delete_push_rule),
+        )
+        .route(
+            "/_matrix/client/v3/pushrules/{scope}/{kind}/{rule_id}/enabled",
+            get(get_push_rule_enabled).put(put_push_rule_enabled),
+        )
+        .route(
+            "/_matrix/client/v3/pushrules/{scope}/{kind}/{rule_id}/actions",
+            get(get_push_rule_actions).put(put_push_rule_actions),
+        )
+}
+
+#[derive(Debug, Deserialize)]
+pub struct PushRulePlacement {
+    pub before: Option<String>,
+    pub after: Option<String>,
+}
+
+async fn get_push_rules(
+    State(state): State<AppState>,
+    auth_user: AuthenticatedUser,
+) -> Result<Json<Value>, ApiError> {
+    Ok(Json(
+        state
+            .services
+            .push_rule_service
+            .get_ruleset(&auth_user.user_id)
+            .await?,
+    ))
+}
+
+async fn get_push_rules_scope(
+    State(state): State<AppState>,
+    auth_user: AuthenticatedUser,
+    Path(scope): Path<String>,
+) -> Result<Json<Value>, ApiError> {
+    let ruleset = state
+        .services
+        .push_rule_service
+        .get_ruleset(&auth_user.user_id)
+        .await?;
+    ruleset
+        .get(&scope)
+        .cloned()
+        .map(Json)
+        .ok_or_else(|| ApiError::bad_request(format!("Unknown push rule scope {}", scope)))
+}
+
+async fn get_push_rule(
+    State(state): State<AppState>,
+    auth_user: AuthenticatedUser,
+    Path((scope, kind, rule_id)): Path<(String, String, String)>,
+) -> Result<Json<Value>, ApiError> {
+    Ok(Json(
+        state
+            .services
+            .push_rule_service
+            .get_rule(&auth_user.user_id, &scope, &kind, &rule_id)
+            .await?,
+    ))
+}
+
+async fn put_push_rule(
+    State(state): State<AppState>,
+    auth_user: AuthenticatedUser,
+    Path((scope, kind, rule_id)): Path<(String, String, String)>,
+    Query(placement): Query<PushRulePlacement>,
+    Json(body): Json<Value>,
+) -> Result<Json<Value>, ApiError> {
+    state
+        .services
+        .push_rule_service
+        .put_rule(
+            &auth_user.user_id,
+            &scope,
+            &kind,
+            &rule_id,
+            &body,
+            placement.before.as_deref(),
+            placement.after.as_deref(),
+        )
+        .await?;
+    Ok(Json(json!({})))
+}
+
+async fn delete_push_rule(
+    State(state): State<AppState>,
+    auth_user: AuthenticatedUser,
+    Path((scope, kind, rule_id)): Path<(String, String, String)>,
+) -> Result<Json<Value>, ApiError> {
+    state
+        .services
+        .push_rule_service
+        .delete_rule(&auth_user.user_id, &scope, &kind, &rule_id)
+        .await?;
+    Ok(Json(json!({})))
+}
+
+async fn get_push_rule_enabled(
+    State(state): State<AppState>,
+    auth_user: AuthenticatedUser,
+    Path((scope, kind, rule_id)): Path<(String, String, String)>,
+) -> Result<Json<Value>, ApiError> {
+    let enabled = state
+        .services
+        .push_rule_service
+        .is_enabled(&auth_user.user_id, &scope, &kind, &rule_id)
+        .await?;
+    Ok(Json(json!({ "enabled": enabled })))
+}
+
+async fn put_push_rule_enabled(
+    State(state): State<AppState>,
+    auth_user: AuthenticatedUser,
+    Path((scope, kind, rule_id)): Path<(String, String, String)>,
+    Json(body): Json<Value>,
+) -> Result<Json<Value>, ApiError> {
+    let enabled = body
+        .get("enabled")
+        .and_then(|v| v.as_bool())
+        .ok_or_else(|| ApiError::bad_request("enabled must be a boolean".to_string()))?;
+    state
+        .services
+        .push_rule_service
+        .set_enabled(&auth_user.user_id, &scope, &kind, &rule_id, enabled)
+        .await?;
+    Ok(Json(json!({})))
+}
+
+async fn get_push_rule_actions(
+    State(state): State<AppState>,
+    auth_user: AuthenticatedUser,
+    Path((scope, kind, rule_id)): Path<(String, String, String)>,
+) -> Result<Json<Value>, ApiError> {
+    let actions = state
+        .services
+        .push_rule_service
+        .get_actions(&auth_user.user_id, &scope, &kind, &rule_id)
+        .await?;
+    Ok(Json(json!({ "actions": actions })))
+}
+
+async fn put_push_rule_actions(
+    State(state): State<AppState>,
+    auth_user: AuthenticatedUser,
+    Path((scope, kind, rule_id)): Path<(String, String, String)>,
+    Json(body): Json<Value>,
+) -> Result<Json<Value>, ApiError> {
+    state
+        .services
+        .push_rule_service
+        .set_actions(&auth_user.user_id, &scope, &kind, &rule_id, body.get("actions"))
+        .await?;
+    Ok(Json(json!({})))
+}
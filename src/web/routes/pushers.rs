@@ -0,0 +1,118 @@
+use super::{AppState, AuthenticatedUser};
+use crate::common::ApiError;
+use crate::storage::pusher::Pusher;
+use axum::{
+    extract::State,
+    routing::{get, post},
+    Json, Router,
+};
+use serde::Deserialize;
+use serde_json::{json, Value};
+
+/// The path every HTTP pusher URL has to point at.
+const PUSH_GATEWAY_PATH: &str = "/_matrix/push/v1/notify";
+
+const MAX_APP_ID_LENGTH: usize = 64;
+const MAX_PUSHKEY_LENGTH: usize = 512;
+
+pub fn create_pushers_router(_state: AppState) -> Router<AppState> {
+    Router::new()
+        .route("/_matrix/client/v3/pushers", get(get_pushers))
+        .route("/_matrix/client/v3/pushers/set", post(set_pusher))
+}
+
+/// The body of `/pushers/set`. A `null` kind deletes the pusher, so only the
+/// `(app_id, pushkey)` pair is required for that.
+#[derive(Debug, Deserialize)]
+pub struct SetPusherRequest {
+    pub pushkey: String,
+    pub kind: Option<String>,
+    pub app_id: String,
+    pub app_display_name: Option<String>,
+    pub device_display_name: Option<String>,
+    pub profile_tag: Option<String>,
+    pub lang: Option<String>,
+    pub data: Option<Value>,
+    #[serde(default)]
+    pub append: bool,
+}
+
+async fn get_pushers(
+    State(state): State<AppState>,
+    auth_user: AuthenticatedUser,
+) -> Result<Json<Value>, ApiError> {
+    let pushers = state
+        .services
+        .push_service
+        .get_pushers(&auth_user.user_id)
+        .await?;
+    Ok(Json(json!({
+        "pushers": pushers.iter().map(Pusher::to_json).collect::<Vec<_>>()
+    })))
+}
+
+async fn set_pusher(
+    State(state): State<AppState>,
+    auth_user: AuthenticatedUser,
+    Json(body): Json<SetPusherRequest>,
+) -> Result<Json<Value>, ApiError> {
+    if body.app_id.len() > MAX_APP_ID_LENGTH {
+        return Err(ApiError::validation(format!(
+            "app_id must be at most {} characters",
+            MAX_APP_ID_LENGTH
+        )));
+    }
+    if body.pushkey.len() > MAX_PUSHKEY_LENGTH {
+        return Err(ApiError::validation(format!(
+            "pushkey must be at most {} bytes",
+            MAX_PUSHKEY_LENGTH
+        )));
+    }
+
+    let Some(kind) = body.kind else {
+        state
+            .services
+            .push_service
+            .delete_pusher(&auth_user.user_id, &body.app_id, &body.pushkey)
+            .await?;
+        return Ok(Json(json!({})));
+    };
+    if kind != "http" {
+        return Err(ApiError::validation(format!("Unsupported pusher kind {}", kind)));
+    }
+
+    let data = body.data.unwrap_or_else(|| json!({}));
+    let url = data
+        .get("url")
+        .and_then(|v| v.as_str())
+        .ok_or_else(|| ApiError::validation("data.url is required for http pushers"))?;
+    if !url.starts_with("http") || !url.ends_with(PUSH_GATEWAY_PATH) {
+        return Err(ApiError::validation(format!(
+            "data.url must be an http(s) URL ending in {}",
+            PUSH_GATEWAY_PATH
+        )));
+    }
+
+    let required = |field: Option<String>, name: &str| {
+        field.ok_or_else(|| ApiError::bad_request(format!("{} is required", name)))
+    };
+    let pusher = Pusher {
+        user_id: auth_user.user_id,
+        device_id: auth_user.device_id,
+        kind,
+        app_display_name: required(body.app_display_name, "app_display_name")?,
+        device_display_name: required(body.device_display_name, "device_display_name")?,
+        lang: required(body.lang, "lang")?,
+        app_id: body.app_id,
+        pushkey: body.pushkey,
+        profile_tag: body.profile_tag,
+        data,
+        created_ts: chrono::Utc::now().timestamp_millis(),
+    };
+    state
+        .services
+        .push_service
+        .set_pusher(&pusher, body.append)
+        .await?;
+    Ok(Json(json!({})))
+}
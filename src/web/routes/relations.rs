@@ -0,0 +1,151 @@
+use super::{validate_room_id, AppState, AuthenticatedUser};
+use crate::common::ApiError;
+use crate::services::thread_service::RelationsQuery;
+use axum::{
+    extract::{Path, Query, State},
+    routing::get,
+    Json, Router,
+};
+use serde::Deserialize;
+use serde_json::Value;
+
+const DEFAULT_RELATIONS_LIMIT: i64 = 50;
+const MAX_RELATIONS_LIMIT: i64 = 100;
+
+pub fn create_relations_router(_state: AppState) -> Router<AppState> {
+    Router::new()
+        .route(
+            "/_matrix/client/v1/rooms/{room_id}/relations/{event_id}",
+            get(get_relations),
+        )
+        .route(
+            "/_matrix/client/v1/rooms/{room_id}/relations/{event_id}/{rel_type}",
+            get(get_relations_by_type),
+        )
+        .route(
+            "/_matrix/client/v1/rooms/{room_id}/relations/{event_id}/{rel_type}/{event_type}",
+            get(get_relations_by_type_and_event_type),
+        )
+        .route("/_matrix/client/v1/rooms/{room_id}/threads", get(get_threads))
+}
+
+#[derive(Debug, Deserialize)]
+pub struct RelationsParams {
+    pub from: Option<String>,
+    pub limit: Option<i64>,
+    pub dir: Option<String>,
+    #[serde(default)]
+    pub recurse: bool,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ThreadsParams {
+    pub from: Option<String>,
+    pub limit: Option<i64>,
+    /// `all` (the default) or `participated`.
+    pub include: Option<String>,
+}
+
+fn clamp_limit(limit: Option<i64>) -> i64 {
+    limit.unwrap_or(DEFAULT_RELATIONS_LIMIT).clamp(1, MAX_RELATIONS_LIMIT)
+}
+
+async fn ensure_member(state: &AppState, auth_user: &AuthenticatedUser, room_id: &str) -> Result<(), ApiError> {
+    validate_room_id(room_id)?;
+    let is_member = state
+        .services
+        .member_storage
+        .is_member(room_id, &auth_user.user_id)
+        .await
+        .map_err(|e| ApiError::internal(format!("Failed to check membership: {}", e)))?;
+    if !is_member && !auth_user.is_admin {
+        return Err(ApiError::forbidden(
+            "You must be a member of this room to view its events".to_string(),
+        ));
+    }
+    Ok(())
+}
+
+async fn relations(
+    state: AppState,
+    auth_user: AuthenticatedUser,
+    room_id: String,
+    event_id: String,
+    rel_type: Option<String>,
+    event_type: Option<String>,
+    params: RelationsParams,
+) -> Result<Json<Value>, ApiError> {
+    ensure_member(&state, &auth_user, &room_id).await?;
+    let forwards = match params.dir.as_deref() {
+        None | Some("b") => false,
+        Some("f") => true,
+        Some(_) => return Err(ApiError::bad_request("dir must be f or b")),
+    };
+    let query = RelationsQuery {
+        rel_type,
+        event_type,
+        from: params.from,
+        limit: clamp_limit(params.limit),
+        forwards,
+        recurse: params.recurse,
+    };
+    let response = state
+        .services
+        .thread_service
+        .get_relations(&auth_user.user_id, &room_id, &event_id, &query)
+        .await?;
+    Ok(Json(response))
+}
+
+async fn get_relations(
+    State(state): State<AppState>,
+    auth_user: AuthenticatedUser,
+    Path((room_id, event_id)): Path<(String, String)>,
+    Query(params): Query<RelationsParams>,
+) -> Result<Json<Value>, ApiError> {
+    relations(state, auth_user, room_id, event_id, None, None, params).await
+}
+
+async fn get_relations_by_type(
+    State(state): State<AppState>,
+    auth_user: AuthenticatedUser,
+    Path((room_id, event_id, rel_type)): Path<(String, String, String)>,
+    Query(params): Query<RelationsParams>,
+) -> Result<Json<Value>, ApiError> {
+    relations(state, auth_user, room_id, event_id, Some(rel_type), None, params).await
+}
+
+async fn get_relations_by_type_and_event_type(
+    State(state): State<AppState>,
+    auth_user: AuthenticatedUser,
+    Path((room_id, event_id, rel_type, event_type)): Path<(String, String, String, String)>,
+    Query(params): Query<RelationsParams>,
+) -> Result<Json<Value>, ApiError> {
+    relations(state, auth_user, room_id, event_id, Some(rel_type), Some(event_type), params).await
+}
+
+async fn get_threads(
+    State(state): State<AppState>,
+    auth_user: AuthenticatedUser,
+    Path(room_id): Path<String>,
+    Query(params): Query<ThreadsParams>,
+) -> Result<Json<Value>, ApiError> {
+    ensure_member(&state, &auth_user, &room_id).await?;
+    let participated_only = match params.include.as_deref() {
+        None | Some("all") => false,
+        Some("participated") => true,
+        Some(_) => return Err(ApiError::bad_request("include must be all or participated")),
+    };
+    let response = state
+        .services
+        .thread_service
+        .get_threads(
+            &auth_user.user_id,
+            &room_id,
+            participated_only,
+            params.from.as_deref(),
+            clamp_limit(params.limit),
+        )
+        .await?;
+    Ok(Json(response))
+}
@@ -0,0 +1,37 @@
+use super::{AppState, AuthenticatedUser};
+use crate::common::ApiError;
+use crate::services::room_search_service::SearchRequest;
+use axum::{
+    extract::{Query, State},
+    routing::post,
+    Json, Router,
+};
+use serde::Deserialize;
+use serde_json::Value;
+
+pub fn create_search_router(_state: AppState) -> Router<AppState> {
+    Router::new()
+        .route("/_matrix/client/v3/search", post(search))
+        .route("/_matrix/client/r0/search", post(search))
+}
+
+#[derive(Debug, Deserialize)]
+pub struct SearchParams {
+    pub next_batch: Option<String>,
+}
+
+async fn search(
+    State(state): State<AppState>,
+    auth_user: AuthenticatedUser,
+    Query(params): Query<SearchParams>,
+    Json(body): Json<Value>,
+) -> Result<Json<Value>, ApiError> {
+    let request: SearchRequest = serde_json::from_value(body)
+        .map_err(|e| ApiError::bad_request(format!("Invalid request: {}", e)))?;
+    let results = state
+        .services
+        .room_search_service
+        .search(&auth_user.user_id, &request, params.next_batch.as_deref())
+        .await?;
+    Ok(Json(results))
+}
@@ -0,0 +1,51 @@
+use super::{AppState, AuthenticatedUser, MAX_SYNC_TIMEOUT_MS};
+use crate::services::SlidingSyncRequest;
+use crate::web::routes::MatrixJson;
+use crate::ApiError;
+use axum::{
+    extract::{Query, State},
+    routing::post,
+    Json, Router,
+};
+use serde::Deserialize;
+use serde_json::Value;
+
+pub fn create_sliding_sync_router(_state: AppState) -> Router<AppState> {
+    Router::new().route(
+        "/_matrix/client/unstable/org.matrix.simplified_msc3575/sync",
+        post(sliding_sync),
+    )
+}
+
+#[derive(Debug, Deserialize)]
+pub struct SlidingSyncQuery {
+    pub pos: Option<String>,
+    pub timeout: Option<u64>,
+}
+
+async fn sliding_sync(
+    State(state): State<AppState>,
+    auth_user: AuthenticatedUser,
+    Query(params): Query<SlidingSyncQuery>,
+    MatrixJson(body): MatrixJson<SlidingSyncRequest>,
+) -> Result<Json<Value>, ApiError> {
+    let device_id = auth_user
+        .device_id
+        .clone()
+        .ok_or_else(|| ApiError::bad_request("Device ID required".to_string()))?;
+    let timeout = params.timeout.unwrap_or(0).min(MAX_SYNC_TIMEOUT_MS);
+
+    Ok(Json(
+        state
+            .services
+            .sliding_sync_service
+            .sync(
+                &auth_user.user_id,
+                &device_id,
+                params.pos.as_deref(),
+                timeout,
+                &body,
+            )
+            .await?,
+    ))
+}
@@ -0,0 +1,34 @@
+use super::{validate_room_id, AppState, AuthenticatedUser};
+use crate::common::ApiError;
+use crate::spaces::HierarchyRequest;
+use axum::{
+    extract::{Path, Query, State},
+    routing::get,
+    Json, Router,
+};
+use serde_json::Value;
+
+pub fn create_spaces_router(_state: AppState) -> Router<AppState> {
+    Router::new().route(
+        "/_matrix/client/v1/rooms/{room_id}/hierarchy",
+        get(get_hierarchy),
+    )
+}
+
+async fn get_hierarchy(
+    State(state): State<AppState>,
+    auth_user: AuthenticatedUser,
+    Path(room_id): Path<String>,
+    Query(request): Query<HierarchyRequest>,
+) -> Result<Json<Value>, ApiError> {
+    validate_room_id(&room_id)?;
+    if request.limit == Some(0) {
+        return Err(ApiError::validation("limit must be positive"));
+    }
+    let hierarchy = state
+        .services
+        .space_manager
+        .get_hierarchy(&auth_user.user_id, &room_id, &request)
+        .await?;
+    Ok(Json(serde_json::to_value(hierarchy)?))
+}
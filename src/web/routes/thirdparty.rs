@@ -0,0 +1,136 @@
+use super::{AppState, AuthenticatedUser};
+use crate::appservice::ThirdPartyEntityKind;
+use crate::common::ApiError;
+use axum::{
+    extract::{Path, RawQuery, State},
+    routing::get,
+    Json, Router,
+};
+use serde_json::Value;
+
+pub fn create_thirdparty_router(_state: AppState) -> Router<AppState> {
+    let mut router = Router::new();
+    for version in ["v3", "r0"] {
+        let prefix = format!("/_matrix/client/{}/thirdparty", version);
+        router = router
+            .route(&format!("{}/protocols", prefix), get(get_protocols))
+            .route(&format!("{}/protocol/{{protocol}}", prefix), get(get_protocol))
+            .route(&format!("{}/location", prefix), get(lookup_location_by_alias))
+            .route(&format!("{}/location/{{protocol}}", prefix), get(lookup_locations))
+            .route(&format!("{}/user", prefix), get(lookup_user_by_id))
+            .route(&format!("{}/user/{{protocol}}", prefix), get(lookup_users));
+    }
+    router
+}
+
+/// The query string as search fields, without the client's access token.
+fn search_fields(query: Option<String>) -> Vec<(String, String)> {
+    url::form_urlencoded::parse(query.unwrap_or_default().as_bytes())
+        .filter(|(key, _)| key != "access_token")
+        .map(|(key, value)| (key.into_owned(), value.into_owned()))
+        .collect()
+}
+
+async fn get_protocols(
+    State(state): State<AppState>,
+    _auth_user: AuthenticatedUser,
+) -> Result<Json<Value>, ApiError> {
+    Ok(Json(Value::Object(state.services.app_services.get_protocols().await)))
+}
+
+async fn get_protocol(
+    State(state): State<AppState>,
+    _auth_user: AuthenticatedUser,
+    Path(protocol): Path<String>,
+) -> Result<Json<Value>, ApiError> {
+    state
+        .services
+        .app_services
+        .get_protocol(&protocol)
+        .await
+        .map(Json)
+        .ok_or_else(|| ApiError::not_found(format!("Protocol {} is not supported", protocol)))
+}
+
+async fn lookup_locations(
+    State(state): State<AppState>,
+    _auth_user: AuthenticatedUser,
+    Path(protocol): Path<String>,
+    RawQuery(query): RawQuery,
+) -> Result<Json<Vec<Value>>, ApiError> {
+    lookup(&state, ThirdPartyEntityKind::Location, Some(&protocol), query).await
+}
+
+async fn lookup_users(
+    State(state): State<AppState>,
+    _auth_user: AuthenticatedUser,
+    Path(protocol): Path<String>,
+    RawQuery(query): RawQuery,
+) -> Result<Json<Vec<Value>>, ApiError> {
+    lookup(&state, ThirdPartyEntityKind::User, Some(&protocol), query).await
+}
+
+async fn lookup_location_by_alias(
+    State(state): State<AppState>,
+    _auth_user: AuthenticatedUser,
+    RawQuery(query): RawQuery,
+) -> Result<Json<Vec<Value>>, ApiError> {
+    lookup(&state, ThirdPartyEntityKind::Location, None, query).await
+}
+
+async fn lookup_user_by_id(
+    State(state): State<AppState>,
+    _auth_user: AuthenticatedUser,
+    RawQuery(query): RawQuery,
+) -> Result<Json<Vec<Value>>, ApiError> {
+    lookup(&state, ThirdPartyEntityKind::User, None, query).await
+}
+
+/// Without a protocol this is a reverse lookup, which needs the Matrix ID to look up.
+async fn lookup(
+    state: &AppState,
+    kind: ThirdPartyEntityKind,
+    protocol: Option<&str>,
+    query: Option<String>,
+) -> Result<Json<Vec<Value>>, ApiError> {
+    let mut fields = search_fields(query);
+    match protocol {
+        Some(protocol) => {
+            if state.services.app_services.get_services_for_protocol(protocol).await.is_empty() {
+                return Err(ApiError::not_found(format!("Protocol {} is not supported", protocol)));
+            }
+        }
+        None => {
+            fields.retain(|(key, _)| key == kind.id_field());
+            if fields.is_empty() {
+                return Err(ApiError::bad_request(format!("Missing {} parameter", kind.id_field())));
+            }
+        }
+    }
+
+    Ok(Json(
+        state
+            .services
+            .app_services
+            .query_third_party(kind, protocol, &fields)
+            .await,
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_search_fields_drop_the_access_token() {
+        let fields = search_fields(Some("network=libera&channel=%23matrix&access_token=secret".to_string()));
+        assert_eq!(
+            fields,
+            vec![
+                ("network".to_string(), "libera".to_string()),
+                ("channel".to_string(), "#matrix".to_string()),
+            ]
+        );
+        assert!(search_fields(None).is_empty());
+    }
+}
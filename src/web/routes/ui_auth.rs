@@ -0,0 +1,103 @@
+use super::AppState;
+use crate::common::ApiError;
+use crate::services::AuthStage;
+use axum::{
+    extract::{Path, Query, State},
+    response::{Html, Redirect},
+    routing::get,
+    Router,
+};
+use serde::Deserialize;
+
+const SSO_CALLBACK_PATH: &str = "/_synapse/client/ui_auth/sso/callback";
+
+pub fn create_ui_auth_router(_state: AppState) -> Router<AppState> {
+    let mut router = Router::new().route(SSO_CALLBACK_PATH, get(sso_callback));
+    for version in ["v3", "r0"] {
+        router = router.route(
+            &format!("/_matrix/client/{}/auth/{{auth_type}}/fallback/web", version),
+            get(fallback_web),
+        );
+    }
+    router
+}
+
+#[derive(Debug, Deserialize)]
+struct FallbackQuery {
+    session: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct SsoCallbackQuery {
+    state: String,
+    code: Option<String>,
+    error: Option<String>,
+}
+
+/// The fallback page for a UIA stage. Only `m.login.sso` has one: it sends the user to the
+/// identity provider, which returns to the callback below.
+async fn fallback_web(
+    State(state): State<AppState>,
+    Path(auth_type): Path<String>,
+    Query(query): Query<FallbackQuery>,
+) -> Result<Redirect, ApiError> {
+    if AuthStage::parse(&auth_type) != Some(AuthStage::Sso) {
+        return Err(ApiError::not_found(format!(
+            "No fallback for authentication type {}",
+            auth_type
+        )));
+    }
+
+    let config = &state.services.config;
+    let base_url = config
+        .server
+        .public_baseurl
+        .clone()
+        .unwrap_or_else(|| format!("https://{}", config.server.name));
+    let redirect_uri = format!("{}{}", base_url.trim_end_matches('/'), SSO_CALLBACK_PATH);
+
+    let url = state
+        .services
+        .ui_auth_service
+        .start_sso(&query.session, &redirect_uri)
+        .await?;
+    Ok(Redirect::to(&url))
+}
+
+/// Where the identity provider returns to. Completes the `m.login.sso` stage and tells the
+/// client's fallback window that authentication is done.
+async fn sso_callback(
+    State(state): State<AppState>,
+    Query(query): Query<SsoCallbackQuery>,
+) -> Result<Html<&'static str>, ApiError> {
+    if let Some(error) = query.error {
+        return Err(ApiError::forbidden(format!("Identity provider refused: {}", error)));
+    }
+    let code = query
+        .code
+        .ok_or_else(|| ApiError::bad_request("Missing authorization code".to_string()))?;
+
+    state
+        .services
+        .ui_auth_service
+        .complete_sso(&query.state, &code)
+        .await?;
+    Ok(Html(AUTH_DONE_PAGE))
+}
+
+/// Signals completion the way the client-server spec asks of fallback pages.
+const AUTH_DONE_PAGE: &str = r#"<!DOCTYPE html>
+<html>
+<head><title>Authentication complete</title></head>
+<body>
+<p>Thank you. You may now close this window and return to the application.</p>
+<script>
+if (window.onAuthDone) {
+    window.onAuthDone();
+} else if (window.opener && window.opener.postMessage) {
+    window.opener.postMessage("authDone", "*");
+}
+</script>
+</body>
+</html>
+"#;
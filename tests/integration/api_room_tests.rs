@@ -8,7 +8,7 @@ use std::sync::Arc;
 use synapse_rust::cache::{CacheConfig, CacheManager};
 use synapse_rust::common::config::{
     AdminRegistrationConfig, Config, CorsConfig, DatabaseConfig, FederationConfig,
-    RateLimitConfig, RedisConfig, SearchConfig, SecurityConfig, ServerConfig, SmtpConfig,
+    RateLimitConfig, RedisConfig, SearchBackend, SearchConfig, SecurityConfig, ServerConfig, SmtpConfig,
     VoipConfig, WorkerConfig,
 };
 use synapse_rust::services::{DatabaseInitService, ServiceContainer};
@@ -114,6 +114,8 @@ async fn setup_test_app() -> axum::Router {
             max_image_resolution: 1000000,
             enable_registration: true,
             enable_registration_captcha: false,
+            registration_requires_token: false,
+            registrations_require_3pid: Vec::new(),
             background_tasks_interval: 60,
             expire_access_token: true,
             expire_access_token_lifetime: 3600,
@@ -169,6 +171,7 @@ async fn setup_test_app() -> axum::Router {
         search: SearchConfig {
             elasticsearch_url: "http://localhost:9200".to_string(),
             enabled: false,
+            backend: SearchBackend::Postgres,
         },
         rate_limit: RateLimitConfig::default(),
         admin_registration: AdminRegistrationConfig {
@@ -183,6 +186,8 @@ async fn setup_test_app() -> axum::Router {
         push: synapse_rust::common::config::PushConfig::default(),
         url_preview: synapse_rust::common::config::UrlPreviewConfig::default(),
         oidc: synapse_rust::common::config::OidcConfig::default(),
+        app_service_config_files: Vec::new(),
+        ui_auth: synapse_rust::common::config::UiAuthConfig::default(),
     };
 
     let container = ServiceContainer::new(&pool, cache.clone(), config, None);
@@ -251,6 +256,8 @@ fn setup_test_app_with_voip() -> axum::Router {
             max_image_resolution: 1000000,
             enable_registration: true,
             enable_registration_captcha: false,
+            registration_requires_token: false,
+            registrations_require_3pid: Vec::new(),
             background_tasks_interval: 60,
             expire_access_token: true,
             expire_access_token_lifetime: 3600,
@@ -307,6 +314,7 @@ fn setup_test_app_with_voip() -> axum::Router {
         search: SearchConfig {
             elasticsearch_url: "http://localhost:9200".to_string(),
             enabled: false,
+            backend: SearchBackend::Postgres,
         },
         admin_registration: AdminRegistrationConfig {
             enabled: true,
@@ -332,6 +340,8 @@ fn setup_test_app_with_voip() -> axum::Router {
         push: synapse_rust::common::config::PushConfig::default(),
         url_preview: synapse_rust::common::config::UrlPreviewConfig::default(),
         oidc: synapse_rust::common::config::OidcConfig::default(),
+        app_service_config_files: Vec::new(),
+        ui_auth: synapse_rust::common::config::UiAuthConfig::default(),
     };
 
     let cache = Arc::new(CacheManager::new(CacheConfig::default()));
@@ -954,7 +964,7 @@ async fn test_get_event_context() {
         .await
         .unwrap();
     assert_eq!(response.status(), StatusCode::OK);
-    let body = axum::body::to_bytes(response.into_body(), 1024)
+    let body = axum::body::to_bytes(response.into_body(), usize::MAX)
         .await
         .unwrap();
     let json: Value = serde_json::from_slice(&body).unwrap();
@@ -994,7 +1004,7 @@ async fn test_room_initial_sync() {
         .await
         .unwrap();
     assert_eq!(response.status(), StatusCode::OK);
-    let body = axum::body::to_bytes(response.into_body(), 2048)
+    let body = axum::body::to_bytes(response.into_body(), usize::MAX)
         .await
         .unwrap();
     let json: Value = serde_json::from_slice(&body).unwrap();
@@ -7,7 +7,7 @@ use std::sync::Arc;
 use synapse_rust::cache::{CacheConfig, CacheManager};
 use synapse_rust::common::config::{
     AdminRegistrationConfig, Config, CorsConfig, DatabaseConfig, FederationConfig,
-    RateLimitConfig, RedisConfig, SearchConfig, SecurityConfig, ServerConfig, SmtpConfig,
+    RateLimitConfig, RedisConfig, SearchBackend, SearchConfig, SecurityConfig, ServerConfig, SmtpConfig,
     VoipConfig, WorkerConfig,
 };
 use synapse_rust::services::{DatabaseInitService, ServiceContainer};
@@ -141,6 +141,8 @@ async fn setup_test_app() -> axum::Router {
             max_image_resolution: 1000000,
             enable_registration: true,
             enable_registration_captcha: false,
+            registration_requires_token: false,
+            registrations_require_3pid: Vec::new(),
             background_tasks_interval: 60,
             expire_access_token: true,
             expire_access_token_lifetime: 3600,
@@ -196,6 +198,7 @@ async fn setup_test_app() -> axum::Router {
         search: SearchConfig {
             elasticsearch_url: "http://localhost:9200".to_string(),
             enabled: false,
+            backend: SearchBackend::Postgres,
         },
         rate_limit: RateLimitConfig::default(),
         admin_registration: AdminRegistrationConfig {
@@ -210,6 +213,8 @@ async fn setup_test_app() -> axum::Router {
         push: synapse_rust::common::config::PushConfig::default(),
         url_preview: synapse_rust::common::config::UrlPreviewConfig::default(),
         oidc: synapse_rust::common::config::OidcConfig::default(),
+        app_service_config_files: Vec::new(),
+        ui_auth: synapse_rust::common::config::UiAuthConfig::default(),
     };
 
     let container = ServiceContainer::new(&pool, cache.clone(), config, None);
@@ -134,6 +134,7 @@ fn test_get_room_events_since_batch() {
         let base_ts = chrono::Utc::now().timestamp_millis();
         let room_id = "!room_batch:localhost".to_string();
 
+        let mut positions = Vec::new();
         for i in 1..=5 {
             let params = CreateEventParams {
                 event_id: format!("$event_batch{}:localhost", i),
@@ -144,18 +145,20 @@ fn test_get_room_events_since_batch() {
                 state_key: None,
                 origin_server_ts: base_ts + i * 1000,
             };
-            storage.create_event(params, None).await.unwrap();
+            positions.push(storage.create_event(params, None).await.unwrap().stream_ordering);
         }
 
         let room_ids = vec![room_id];
         
-        let result = storage.get_room_events_since_batch(&room_ids, base_ts + 2500, 10).await;
+        let result = storage.get_room_events_since_batch(&room_ids, positions[1], positions[3], 10).await;
         assert!(result.is_ok());
         
         let events_map = result.unwrap();
         let events = events_map.values().next().unwrap();
         
-        assert!(events.len() >= 2, "Should have events after the since timestamp");
+        assert_eq!(events.len(), 2, "Should have the events between the two positions");
+        assert_eq!(events[0].stream_ordering, positions[3]);
+        assert_eq!(events[1].stream_ordering, positions[2]);
     });
 }
 
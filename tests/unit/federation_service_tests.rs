@@ -168,3 +168,50 @@ fn test_device_revocation() {
         assert!(result.is_ok());
     });
 }
+
+#[test]
+fn test_federation_queue_backoff_and_catch_up() {
+    use synapse_rust::storage::FederationQueueStorage;
+
+    let rt = Runtime::new().unwrap();
+    rt.block_on(async {
+        let pool = match setup_test_database().await {
+            Some(pool) => Arc::new(pool),
+            None => return,
+        };
+        let queue = FederationQueueStorage::new(&pool, "localhost");
+        let destination = format!("queue{}.example.com", unique_id());
+        let destinations = vec![destination.clone(), "localhost".to_string()];
+
+        for i in 0..3 {
+            let edu = serde_json::json!({"edu_type": "m.presence", "content": {"n": i}});
+            queue.enqueue_edu(&destinations, &edu).await.unwrap();
+        }
+        assert_eq!(queue.get_pending_count(&destination).await.unwrap(), 3);
+        assert_eq!(queue.get_pending_count("localhost").await.unwrap(), 0);
+
+        let transaction = queue.get_next_transaction(&destination, 50, 2).await.unwrap();
+        assert_eq!(transaction.edus.len(), 2);
+        assert_eq!(transaction.edus[0]["content"]["n"], 0);
+
+        let now = chrono::Utc::now().timestamp_millis();
+        queue.record_failure(&destination, 60_000).await.unwrap();
+        let ready = queue.get_ready_destinations(now, 1000).await.unwrap();
+        assert!(!ready.contains(&destination));
+        let state = queue.get_destination(&destination).await.unwrap().unwrap();
+        assert_eq!(state.failure_count, 1);
+
+        assert!(queue.reset_backoff(&destination).await.unwrap());
+        let ready = queue.get_ready_destinations(now, 1000).await.unwrap();
+        assert!(ready.contains(&destination));
+
+        queue.complete_transaction(&destination, &transaction).await.unwrap();
+        let state = queue.get_destination(&destination).await.unwrap().unwrap();
+        assert_eq!(state.last_successful_stream_id, transaction.last_stream_id());
+        let rest = queue.get_next_transaction(&destination, 50, 100).await.unwrap();
+        assert_eq!(rest.edus.len(), 1);
+        assert_eq!(rest.edus[0]["content"]["n"], 2);
+
+        queue.clear_destination(&destination).await.unwrap();
+    });
+}
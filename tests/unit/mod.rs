@@ -1,13 +1,25 @@
+mod appservice_queue_tests;
 mod auth_service_tests;
 mod device_storage_tests;
 mod event_storage_tests;
 mod exception_tests;
+mod federation_membership_tests;
 mod federation_service_tests;
+mod knock_tests;
 mod media_service_tests;
+mod push_actions_service_tests;
+mod push_rule_service_tests;
+mod push_service_tests;
 
 mod registration_service_tests;
+mod registration_token_tests;
+mod room_search_tests;
 mod room_service_tests;
 mod search_service_tests;
+mod state_res_tests;
 mod storage_tests;
+mod space_tests;
 mod sync_service_tests;
+mod thread_service_tests;
+mod ui_auth_tests;
 mod voice_service_tests;
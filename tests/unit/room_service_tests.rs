@@ -1,12 +1,14 @@
 #![cfg(test)]
 
-use serde_json::json;
+use serde_json::{json, Value};
 use sqlx::{Pool, Postgres};
 use std::sync::atomic::{AtomicU64, Ordering};
 use std::sync::Arc;
 use tokio::runtime::Runtime;
 
 use synapse_rust::common::validation::Validator;
+use synapse_rust::common::ApiError;
+use synapse_rust::federation::EventSigningKey;
 use synapse_rust::services::room_service::{CreateRoomConfig, RoomService};
 use synapse_rust::storage::event::EventStorage;
 use synapse_rust::storage::membership::RoomMemberStorage;
@@ -177,6 +179,63 @@ fn test_join_room_success() {
     });
 }
 
+#[test]
+fn test_needs_remote_membership_for_rooms_we_are_not_in() {
+    let rt = Runtime::new().unwrap();
+    rt.block_on(async {
+        let pool = match setup_test_database().await {
+            Some(pool) => Arc::new(pool),
+            None => return,
+        };
+        let id = unique_id();
+        let alice_id = format!("@alice_{}:localhost", id);
+        create_test_user(&pool, &alice_id, &format!("alice_{}", id)).await;
+
+        let cache = Arc::new(CacheManager::new(CacheConfig::default()));
+        let room_storage = RoomStorage::new(&pool);
+        let member_storage = RoomMemberStorage::new(&pool, "localhost");
+        let room_service = RoomService::new(
+            room_storage.clone(),
+            member_storage.clone(),
+            EventStorage::new(&pool),
+            UserStorage::new(&pool, cache.clone()),
+            Arc::new(Validator::default()),
+            "localhost".to_string(),
+            None,
+        );
+
+        let room_val = room_service
+            .create_room(&alice_id, CreateRoomConfig::default())
+            .await
+            .unwrap();
+        let local_room = room_val["room_id"].as_str().unwrap();
+        assert!(!room_service.needs_remote_membership(local_room).await.unwrap());
+
+        let remote_room = format!("!remote_{}:remote.test", id);
+        assert!(room_service.needs_remote_membership(&remote_room).await.unwrap());
+
+        room_storage
+            .create_remote_room(&remote_room, "@carol:remote.test", "public", "10")
+            .await
+            .unwrap();
+        assert!(room_service.needs_remote_membership(&remote_room).await.unwrap());
+
+        member_storage
+            .apply_member_event(
+                &remote_room,
+                &format!("$join_{}", id),
+                &alice_id,
+                &alice_id,
+                &json!({"membership": "join", "displayname": "Alice"}),
+            )
+            .await
+            .unwrap();
+        assert!(!room_service.needs_remote_membership(&remote_room).await.unwrap());
+        let member = member_storage.get_room_member(&remote_room, &alice_id).await.unwrap().unwrap();
+        assert_eq!(member.display_name.as_deref(), Some("Alice"));
+    });
+}
+
 #[test]
 fn test_send_message_success() {
     let rt = Runtime::new().unwrap();
@@ -220,13 +279,170 @@ fn test_send_message_success() {
             .get_room_messages(room_id, 0, 10, "b")
             .await
             .unwrap();
-        let chunk = messages["chunk"].as_array().unwrap();
+        // The room's initial state comes before the message
+        let chunk: Vec<&Value> = messages["chunk"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .filter(|event| event["type"] == "m.room.message")
+            .collect();
         assert_eq!(chunk.len(), 1);
         assert_eq!(chunk[0]["content"]["body"]["body"], "Hello world");
         assert_eq!(chunk[0]["sender"], alice_id);
     });
 }
 
+#[test]
+fn test_send_message_extends_signed_event_dag() {
+    let rt = Runtime::new().unwrap();
+    rt.block_on(async {
+        let pool = match setup_test_database().await {
+            Some(pool) => Arc::new(pool),
+            None => return,
+        };
+        let id = unique_id();
+        let alice_id = format!("@alice_{}:localhost", id);
+        let alice_name = format!("alice_{}", id);
+        create_test_user(&pool, &alice_id, &alice_name).await;
+
+        let signing_key =
+            EventSigningKey::from_base64("localhost", "ed25519:test", &"A".repeat(43)).unwrap();
+        let event_storage = EventStorage::new(&pool).with_signing_key(signing_key);
+        let cache = Arc::new(CacheManager::new(CacheConfig::default()));
+        let room_service = RoomService::new(
+            RoomStorage::new(&pool),
+            RoomMemberStorage::new(&pool, "localhost"),
+            event_storage.clone(),
+            UserStorage::new(&pool, cache.clone()),
+            Arc::new(Validator::default()),
+            "localhost".to_string(),
+            None,
+        );
+
+        let unsupported = room_service
+            .create_room(
+                &alice_id,
+                CreateRoomConfig {
+                    room_version: Some("42".to_string()),
+                    ..Default::default()
+                },
+            )
+            .await;
+        assert!(matches!(unsupported, Err(ApiError::UnsupportedRoomVersion(_))));
+
+        let room_val = room_service
+            .create_room(&alice_id, CreateRoomConfig::default())
+            .await
+            .unwrap();
+        let room_id = room_val["room_id"].as_str().unwrap();
+        assert_eq!(event_storage.get_room_version(room_id).await.unwrap(), "10");
+
+        let first = room_service
+            .send_message(room_id, &alice_id, "m.text", &json!({"body": "one"}))
+            .await
+            .unwrap();
+        let second = room_service
+            .send_message(room_id, &alice_id, "m.text", &json!({"body": "two"}))
+            .await
+            .unwrap();
+        let first_id = first["event_id"].as_str().unwrap();
+        let second_id = second["event_id"].as_str().unwrap();
+        assert!(!second_id.contains(':'));
+
+        let (prev_events, depth, hashes, signatures): (Value, i64, Value, Value) = sqlx::query_as(
+            "SELECT prev_events, depth, hashes, signatures FROM events WHERE event_id = $1",
+        )
+        .bind(second_id)
+        .fetch_one(&*pool)
+        .await
+        .unwrap();
+        let first_depth: i64 = sqlx::query_scalar("SELECT depth FROM events WHERE event_id = $1")
+            .bind(first_id)
+            .fetch_one(&*pool)
+            .await
+            .unwrap();
+
+        assert_eq!(prev_events, json!([first_id]));
+        assert_eq!(depth, first_depth + 1);
+        assert!(hashes["sha256"].is_string());
+        assert!(signatures["localhost"]["ed25519:test"].is_string());
+    });
+}
+
+#[test]
+fn test_create_room_sends_initial_state_in_order() {
+    let rt = Runtime::new().unwrap();
+    rt.block_on(async {
+        let pool = match setup_test_database().await {
+            Some(pool) => Arc::new(pool),
+            None => return,
+        };
+        let id = unique_id();
+        let alice_id = format!("@alice_{}:localhost", id);
+        let bob_id = format!("@bob_{}:localhost", id);
+        create_test_user(&pool, &alice_id, &format!("alice_{}", id)).await;
+        create_test_user(&pool, &bob_id, &format!("bob_{}", id)).await;
+
+        let cache = Arc::new(CacheManager::new(CacheConfig::default()));
+        let room_service = RoomService::new(
+            RoomStorage::new(&pool),
+            RoomMemberStorage::new(&pool, "localhost"),
+            EventStorage::new(&pool),
+            UserStorage::new(&pool, cache.clone()),
+            Arc::new(Validator::default()),
+            "localhost".to_string(),
+            None,
+        );
+
+        let config = CreateRoomConfig {
+            name: Some("Garden".to_string()),
+            topic: Some("Plants".to_string()),
+            preset: Some("public_chat".to_string()),
+            invite_list: Some(vec![bob_id.clone()]),
+            initial_state: Some(vec![
+                json!({"type": "m.room.guest_access", "content": {"guest_access": "can_join"}}),
+            ]),
+            ..Default::default()
+        };
+        let room_val = room_service.create_room(&alice_id, config).await.unwrap();
+        let room_id = room_val["room_id"].as_str().unwrap();
+
+        let events: Vec<(String, String, String, Value)> = sqlx::query_as(
+            "SELECT event_id, event_type, state_key, content FROM events WHERE room_id = $1 ORDER BY depth",
+        )
+        .bind(room_id)
+        .fetch_all(&*pool)
+        .await
+        .unwrap();
+        let types: Vec<&str> = events.iter().map(|(_, event_type, _, _)| event_type.as_str()).collect();
+        assert_eq!(
+            types,
+            vec![
+                "m.room.create",
+                "m.room.member",
+                "m.room.power_levels",
+                "m.room.join_rules",
+                "m.room.history_visibility",
+                "m.room.guest_access",
+                "m.room.name",
+                "m.room.topic",
+                "m.room.member",
+            ]
+        );
+        assert_eq!(events[2].3["users"][&alice_id], 100);
+        assert_eq!(events[3].3["join_rule"], "public");
+        assert_eq!(events[8].2, bob_id);
+
+        // Memberships point at the events that set them
+        let members = RoomMemberStorage::new(&pool, "localhost");
+        let creator = members.get_member(room_id, &alice_id).await.unwrap().unwrap();
+        let invitee = members.get_member(room_id, &bob_id).await.unwrap().unwrap();
+        assert_eq!(creator.event_id.as_deref(), Some(events[1].0.as_str()));
+        assert_eq!(invitee.membership, "invite");
+        assert_eq!(invitee.event_id.as_deref(), Some(events[8].0.as_str()));
+    });
+}
+
 #[test]
 fn test_invite_user_success() {
     let rt = Runtime::new().unwrap();
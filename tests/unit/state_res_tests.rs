@@ -0,0 +1,286 @@
+#![cfg(test)]
+
+//! State resolution v2 test vectors, following the scenarios used by the Matrix specification
+//! and the reference homeserver.
+//!
+//! Each case describes a room DAG through chains of node names (each node's `prev_events` are
+//! the nodes that follow it in a chain). The state after every node is computed by resolving
+//! the states after its prev events, and the state at `END` is compared with the expected
+//! winners.
+
+use serde_json::{json, Value};
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use synapse_rust::federation::state_res::{resolve_state, StateMap};
+use synapse_rust::federation::EventAuthChain;
+
+const ROOM_ID: &str = "!test:example.com";
+const ROOM_VERSION: &str = "2";
+const ALICE: &str = "@alice:example.com";
+const BOB: &str = "@bob:example.com";
+const CHARLIE: &str = "@charlie:example.com";
+const EVELYN: &str = "@evelyn:example.com";
+const ZARA: &str = "@zara:example.com";
+
+struct FakeEvent {
+    id: &'static str,
+    sender: &'static str,
+    event_type: &'static str,
+    state_key: Option<&'static str>,
+    content: Value,
+}
+
+fn state(id: &'static str, sender: &'static str, event_type: &'static str, state_key: &'static str, content: Value) -> FakeEvent {
+    FakeEvent {
+        id,
+        sender,
+        event_type,
+        state_key: Some(state_key),
+        content,
+    }
+}
+
+fn message(id: &'static str, sender: &'static str) -> FakeEvent {
+    FakeEvent {
+        id,
+        sender,
+        event_type: "m.room.message",
+        state_key: None,
+        content: json!({}),
+    }
+}
+
+fn member(id: &'static str, sender: &'static str, target: &'static str, membership: &str) -> FakeEvent {
+    state(id, sender, "m.room.member", target, json!({ "membership": membership }))
+}
+
+fn event_id(node: &str) -> String {
+    format!("${}:example.com", node)
+}
+
+fn initial_events() -> Vec<FakeEvent> {
+    vec![
+        state("CREATE", ALICE, "m.room.create", "", json!({ "creator": ALICE })),
+        member("IMA", ALICE, ALICE, "join"),
+        state("IPOWER", ALICE, "m.room.power_levels", "", json!({ "users": { ALICE: 100 } })),
+        state("IJR", ALICE, "m.room.join_rules", "", json!({ "join_rule": "public" })),
+        member("IMB", BOB, BOB, "join"),
+        member("IMC", CHARLIE, CHARLIE, "join"),
+        member("IMZ", ZARA, ZARA, "join"),
+        message("START", ZARA),
+        message("END", ZARA),
+    ]
+}
+
+const INITIAL_EDGES: [&str; 8] = ["START", "IMZ", "IMC", "IMB", "IJR", "IPOWER", "IMA", "CREATE"];
+
+fn do_check(events: Vec<FakeEvent>, edges: &[&[&str]], expected_state_ids: &[&str]) {
+    // Timestamps follow declaration order, as tie-breaks depend on them.
+    let mut fake_events: HashMap<&str, (FakeEvent, i64)> = HashMap::new();
+    for (ts, event) in initial_events().into_iter().chain(events).enumerate() {
+        fake_events.insert(event.id, (event, ts as i64));
+    }
+
+    let mut graph: BTreeMap<&str, BTreeSet<&str>> = BTreeMap::new();
+    for chain in std::iter::once(&INITIAL_EDGES[..]).chain(edges.iter().copied()) {
+        for pair in chain.windows(2) {
+            graph.entry(pair[0]).or_default().insert(pair[1]);
+            graph.entry(pair[1]).or_default();
+        }
+    }
+
+    let mut event_map: HashMap<String, Value> = HashMap::new();
+    let mut state_at: HashMap<&str, StateMap> = HashMap::new();
+
+    for node in topological_order(&graph) {
+        let (fake, ts) = &fake_events[node];
+        let prev_nodes: Vec<&str> = graph[node].iter().copied().collect();
+
+        let state_before = match prev_nodes.as_slice() {
+            [] => StateMap::new(),
+            [prev] => state_at[prev].clone(),
+            prevs => {
+                let sets: Vec<StateMap> = prevs.iter().map(|prev| state_at[prev].clone()).collect();
+                resolve_state(ROOM_VERSION, &sets, &event_map).unwrap()
+            }
+        };
+
+        let mut event = json!({
+            "event_id": event_id(fake.id),
+            "room_id": ROOM_ID,
+            "sender": fake.sender,
+            "type": fake.event_type,
+            "content": fake.content,
+            "origin_server_ts": ts,
+            "prev_events": prev_nodes.iter().map(|prev| event_id(prev)).collect::<Vec<_>>(),
+        });
+        if let Some(state_key) = fake.state_key {
+            event["state_key"] = json!(state_key);
+        }
+        let auth_events: Vec<String> = EventAuthChain::auth_types_for_event(&event)
+            .into_iter()
+            .filter_map(|key| state_before.get(&key).cloned())
+            .collect();
+        event["auth_events"] = json!(auth_events);
+
+        let mut state_after = state_before;
+        if let Some(state_key) = fake.state_key {
+            state_after.insert((fake.event_type.to_string(), state_key.to_string()), event_id(fake.id));
+        }
+        state_at.insert(node, state_after);
+        event_map.insert(event_id(fake.id), event);
+    }
+
+    let mut expected = StateMap::new();
+    for node in expected_state_ids {
+        let (fake, _) = &fake_events[node];
+        let key = (fake.event_type.to_string(), fake.state_key.unwrap().to_string());
+        expected.insert(key, event_id(node));
+    }
+
+    let start = &state_at["START"];
+    let end: StateMap = state_at["END"]
+        .iter()
+        .filter(|(key, value)| expected.contains_key(*key) || start.get(*key) != Some(*value))
+        .map(|(key, value)| (key.clone(), value.clone()))
+        .collect();
+    assert_eq!(expected, end);
+}
+
+/// Orders nodes so that every node comes after its prev events, smallest name first.
+fn topological_order<'a>(graph: &BTreeMap<&'a str, BTreeSet<&'a str>>) -> Vec<&'a str> {
+    let mut done: BTreeSet<&str> = BTreeSet::new();
+    let mut order = Vec::new();
+    while order.len() < graph.len() {
+        let next = graph
+            .iter()
+            .find(|(node, prevs)| !done.contains(*node) && prevs.iter().all(|p| done.contains(p)))
+            .map(|(node, _)| *node)
+            .expect("event graph has a cycle");
+        done.insert(next);
+        order.push(next);
+    }
+    order
+}
+
+#[test]
+fn test_ban_vs_power_levels() {
+    let events = vec![
+        state("PA", ALICE, "m.room.power_levels", "", json!({ "users": { ALICE: 100, BOB: 50 } })),
+        member("MA", ALICE, ALICE, "join"),
+        member("MB", ALICE, BOB, "ban"),
+        state("PB", BOB, "m.room.power_levels", "", json!({ "users": { ALICE: 100, BOB: 50 } })),
+    ];
+    let edges: &[&[&str]] = &[&["END", "MB", "MA", "PA", "START"], &["END", "PB", "PA"]];
+
+    do_check(events, edges, &["PA", "MA", "MB"]);
+}
+
+#[test]
+fn test_join_rule_evasion() {
+    let events = vec![
+        state("JR", ALICE, "m.room.join_rules", "", json!({ "join_rule": "private" })),
+        member("ME", EVELYN, EVELYN, "join"),
+    ];
+    let edges: &[&[&str]] = &[&["END", "JR", "START"], &["END", "ME", "START"]];
+
+    do_check(events, edges, &["JR"]);
+}
+
+#[test]
+fn test_offtopic_power_levels() {
+    let events = vec![
+        state("PA", ALICE, "m.room.power_levels", "", json!({ "users": { ALICE: 100, BOB: 50 } })),
+        state("PB", BOB, "m.room.power_levels", "", json!({ "users": { ALICE: 100, BOB: 50, CHARLIE: 50 } })),
+        state("PC", CHARLIE, "m.room.power_levels", "", json!({ "users": { ALICE: 100, BOB: 50, CHARLIE: 0 } })),
+    ];
+    let edges: &[&[&str]] = &[&["END", "PC", "PB", "PA", "START"], &["END", "PA"]];
+
+    do_check(events, edges, &["PC"]);
+}
+
+#[test]
+fn test_topic_basic() {
+    let events = vec![
+        state("T1", ALICE, "m.room.topic", "", json!({})),
+        state("PA1", ALICE, "m.room.power_levels", "", json!({ "users": { ALICE: 100, BOB: 50 } })),
+        state("T2", ALICE, "m.room.topic", "", json!({})),
+        state("PA2", ALICE, "m.room.power_levels", "", json!({ "users": { ALICE: 100, BOB: 0 } })),
+        state("PB", BOB, "m.room.power_levels", "", json!({ "users": { ALICE: 100, BOB: 50 } })),
+        state("T3", BOB, "m.room.topic", "", json!({})),
+    ];
+    let edges: &[&[&str]] = &[
+        &["END", "PA2", "T2", "PA1", "T1", "START"],
+        &["END", "T3", "PB", "PA1"],
+    ];
+
+    do_check(events, edges, &["PA2", "T2"]);
+}
+
+#[test]
+fn test_topic_reset() {
+    let events = vec![
+        state("T1", ALICE, "m.room.topic", "", json!({})),
+        state("PA", ALICE, "m.room.power_levels", "", json!({ "users": { ALICE: 100, BOB: 50 } })),
+        state("T2", BOB, "m.room.topic", "", json!({})),
+        member("MB", ALICE, BOB, "ban"),
+    ];
+    let edges: &[&[&str]] = &[&["END", "MB", "T2", "PA", "T1", "START"], &["END", "T1"]];
+
+    do_check(events, edges, &["T1", "MB", "PA"]);
+}
+
+#[test]
+fn test_topic() {
+    let events = vec![
+        state("T1", ALICE, "m.room.topic", "", json!({})),
+        state("PA1", ALICE, "m.room.power_levels", "", json!({ "users": { ALICE: 100, BOB: 50 } })),
+        state("T2", ALICE, "m.room.topic", "", json!({})),
+        state("PA2", ALICE, "m.room.power_levels", "", json!({ "users": { ALICE: 100, BOB: 0 } })),
+        state("PB", BOB, "m.room.power_levels", "", json!({ "users": { ALICE: 100, BOB: 50 } })),
+        state("T3", BOB, "m.room.topic", "", json!({})),
+        message("MZ1", ZARA),
+        state("T4", ALICE, "m.room.topic", "", json!({})),
+    ];
+    let edges: &[&[&str]] = &[
+        &["END", "T4", "MZ1", "PA2", "T2", "PA1", "T1", "START"],
+        &["END", "MZ1", "T3", "PB", "PA1"],
+    ];
+
+    do_check(events, edges, &["T4", "PA2"]);
+}
+
+#[test]
+fn test_mainline_sort() {
+    let events = vec![
+        state("T1", ALICE, "m.room.topic", "", json!({})),
+        state("PA1", ALICE, "m.room.power_levels", "", json!({ "users": { ALICE: 100, BOB: 50 } })),
+        state("T2", ALICE, "m.room.topic", "", json!({})),
+        state(
+            "PA2",
+            ALICE,
+            "m.room.power_levels",
+            "",
+            json!({ "users": { ALICE: 100, BOB: 50 }, "events": { "m.room.power_levels": 100 } }),
+        ),
+        state("PB", BOB, "m.room.power_levels", "", json!({ "users": { ALICE: 100, BOB: 50 } })),
+        state("T3", BOB, "m.room.topic", "", json!({})),
+        state("T4", ALICE, "m.room.topic", "", json!({})),
+    ];
+    let edges: &[&[&str]] = &[
+        &["END", "T3", "PA2", "T2", "PA1", "T1", "START"],
+        &["END", "T4", "PB", "PA1"],
+    ];
+
+    do_check(events, edges, &["T3", "PA2"]);
+}
+
+#[test]
+fn test_unconflicted_state_is_kept() {
+    let events = vec![
+        state("T1", ALICE, "m.room.topic", "", json!({})),
+        state("N1", ALICE, "m.room.name", "", json!({ "name": "one" })),
+    ];
+    let edges: &[&[&str]] = &[&["END", "T1", "START"], &["END", "N1", "START"]];
+
+    do_check(events, edges, &["T1", "N1"]);
+}
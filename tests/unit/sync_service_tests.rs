@@ -9,11 +9,15 @@ use serde_json::json;
     use synapse_rust::common::validation::Validator;
     use synapse_rust::services::room_service::{CreateRoomConfig, RoomService};
     use synapse_rust::services::sync_service::SyncService;
+    use synapse_rust::common::ApiError;
+    use synapse_rust::services::Notifier;
+    use synapse_rust::services::{SlidingSyncRequest, SlidingSyncService};
     use synapse_rust::services::PresenceStorage;
     use synapse_rust::storage::device::DeviceStorage;
-    use synapse_rust::storage::event::EventStorage;
+    use synapse_rust::storage::event::{CreateEventParams, EventStorage};
     use synapse_rust::storage::membership::RoomMemberStorage;
     use synapse_rust::storage::room::RoomStorage;
+    use synapse_rust::storage::sliding_sync::SlidingSyncStorage;
     use synapse_rust::storage::user::UserStorage;
 
     async fn setup_test_database() -> Option<Pool<Postgres>> {
@@ -118,6 +122,712 @@ use serde_json::json;
 
             assert!(val["rooms"]["join"].as_object().unwrap().contains_key(room_id));
             let room_data = &val["rooms"]["join"][room_id];
-            assert_eq!(room_data["timeline"]["events"].as_array().unwrap().len(), 1);
+            let timeline = room_data["timeline"]["events"].as_array().unwrap();
+            assert_eq!(timeline.iter().filter(|e| e["type"] == "m.room.message").count(), 1);
+            assert!(timeline.iter().any(|e| e["type"] == "m.room.create"));
+        });
+    }
+
+    #[test]
+    fn test_sync_long_poll_wakes_on_new_event() {
+        let rt = Runtime::new().unwrap();
+        rt.block_on(async {
+            let pool = match setup_test_database().await {
+                Some(pool) => Arc::new(pool),
+                None => return,
+            };
+            create_test_user(&pool, "@carol:localhost", "carol").await;
+
+            let cache = Arc::new(CacheManager::new(CacheConfig::default()));
+            let notifier = Notifier::new();
+            let presence_storage = PresenceStorage::new(pool.clone(), cache.clone());
+            let member_storage = RoomMemberStorage::new(&pool, "localhost");
+            let event_storage = EventStorage::new(&pool).with_notifier(notifier.clone());
+            let room_storage = RoomStorage::new(&pool);
+            let user_storage = UserStorage::new(&pool, cache.clone());
+
+            let room_service = Arc::new(RoomService::new(
+                room_storage.clone(),
+                member_storage.clone(),
+                event_storage.clone(),
+                user_storage.clone(),
+                Arc::new(Validator::default()),
+                "localhost".to_string(),
+                None,
+            ));
+
+            let sync_service = SyncService::new(
+                presence_storage,
+                member_storage,
+                event_storage,
+                room_storage,
+                DeviceStorage::new(&pool),
+            )
+            .with_notifier(notifier);
+
+            let config = CreateRoomConfig {
+                name: Some("Long Poll Room".to_string()),
+                ..Default::default()
+            };
+            let room_val = room_service
+                .create_room("@carol:localhost", config)
+                .await
+                .unwrap();
+            let room_id = room_val["room_id"].as_str().unwrap().to_string();
+
+            let initial = sync_service
+                .sync("@carol:localhost", 0, false, "online", None)
+                .await
+                .unwrap();
+            let since = initial["next_batch"].as_str().unwrap().to_string();
+
+            let sender = room_service.clone();
+            let sender_room = room_id.clone();
+            tokio::spawn(async move {
+                tokio::time::sleep(std::time::Duration::from_millis(200)).await;
+                sender
+                    .send_message(&sender_room, "@carol:localhost", "m.text", &json!("wake"))
+                    .await
+                    .unwrap();
+            });
+
+            let started = std::time::Instant::now();
+            let val = sync_service
+                .sync("@carol:localhost", 10_000, false, "online", Some(&since))
+                .await
+                .unwrap();
+
+            assert!(started.elapsed() < std::time::Duration::from_secs(5));
+            let events = val["rooms"]["join"][&room_id]["timeline"]["events"]
+                .as_array()
+                .unwrap();
+            assert_eq!(events.last().unwrap()["content"]["body"], "wake");
+        });
+    }
+
+    #[test]
+    fn test_sync_long_poll_times_out_without_events() {
+        let rt = Runtime::new().unwrap();
+        rt.block_on(async {
+            let pool = match setup_test_database().await {
+                Some(pool) => Arc::new(pool),
+                None => return,
+            };
+            create_test_user(&pool, "@dave:localhost", "dave").await;
+
+            let cache = Arc::new(CacheManager::new(CacheConfig::default()));
+            let sync_service = SyncService::new(
+                PresenceStorage::new(pool.clone(), cache.clone()),
+                RoomMemberStorage::new(&pool, "localhost"),
+                EventStorage::new(&pool),
+                RoomStorage::new(&pool),
+                DeviceStorage::new(&pool),
+            );
+
+            let initial = sync_service
+                .sync("@dave:localhost", 0, false, "online", None)
+                .await
+                .unwrap();
+            let since = initial["next_batch"].as_str().unwrap().to_string();
+
+            let started = std::time::Instant::now();
+            let val = sync_service
+                .sync("@dave:localhost", 300, false, "online", Some(&since))
+                .await
+                .unwrap();
+
+            assert!(started.elapsed() >= std::time::Duration::from_millis(300));
+            assert!(val["rooms"]["join"].as_object().unwrap().is_empty());
+        });
+    }
+
+    #[test]
+    fn test_incremental_sync_includes_events_with_older_timestamps() {
+        let rt = Runtime::new().unwrap();
+        rt.block_on(async {
+            let pool = match setup_test_database().await {
+                Some(pool) => Arc::new(pool),
+                None => return,
+            };
+            create_test_user(&pool, "@oscar:localhost", "oscar").await;
+
+            let cache = Arc::new(CacheManager::new(CacheConfig::default()));
+            let member_storage = RoomMemberStorage::new(&pool, "localhost");
+            let event_storage = EventStorage::new(&pool);
+            let room_storage = RoomStorage::new(&pool);
+            let room_service = RoomService::new(
+                room_storage.clone(),
+                member_storage.clone(),
+                event_storage.clone(),
+                UserStorage::new(&pool, cache.clone()),
+                Arc::new(Validator::default()),
+                "localhost".to_string(),
+                None,
+            );
+            let sync_service = SyncService::new(
+                PresenceStorage::new(pool.clone(), cache.clone()),
+                member_storage,
+                event_storage.clone(),
+                room_storage,
+                DeviceStorage::new(&pool),
+            );
+
+            let room_val = room_service
+                .create_room("@oscar:localhost", CreateRoomConfig::default())
+                .await
+                .unwrap();
+            let room_id = room_val["room_id"].as_str().unwrap();
+            let initial = sync_service
+                .sync("@oscar:localhost", 0, false, "offline", None)
+                .await
+                .unwrap();
+            let since = initial["next_batch"].as_str().unwrap().to_string();
+
+            // Stamped by a server whose clock is behind ours
+            let skewed = event_storage
+                .create_event(
+                    CreateEventParams {
+                        event_id: "$skewed:localhost".to_string(),
+                        room_id: room_id.to_string(),
+                        user_id: "@oscar:localhost".to_string(),
+                        event_type: "m.room.message".to_string(),
+                        content: json!({"msgtype": "m.text", "body": "late"}),
+                        state_key: None,
+                        origin_server_ts: chrono::Utc::now().timestamp_millis() - 60_000,
+                    },
+                    None,
+                )
+                .await
+                .unwrap();
+
+            let val = sync_service
+                .sync("@oscar:localhost", 0, false, "offline", Some(&since))
+                .await
+                .unwrap();
+            let events = val["rooms"]["join"][room_id]["timeline"]["events"]
+                .as_array()
+                .unwrap();
+            assert_eq!(events.len(), 1);
+            assert_eq!(events[0]["event_id"], skewed.event_id);
+
+            let next = val["next_batch"].as_str().unwrap().to_string();
+            let val = sync_service
+                .sync("@oscar:localhost", 0, false, "offline", Some(&next))
+                .await
+                .unwrap();
+            assert!(val["rooms"]["join"].as_object().unwrap().is_empty());
+        });
+    }
+
+    #[test]
+    fn test_sync_reports_invites_with_stripped_state() {
+        let rt = Runtime::new().unwrap();
+        rt.block_on(async {
+            let pool = match setup_test_database().await {
+                Some(pool) => Arc::new(pool),
+                None => return,
+            };
+            create_test_user(&pool, "@ivan:localhost", "ivan").await;
+            create_test_user(&pool, "@judy:localhost", "judy").await;
+
+            let cache = Arc::new(CacheManager::new(CacheConfig::default()));
+            let member_storage = RoomMemberStorage::new(&pool, "localhost");
+            let event_storage = EventStorage::new(&pool);
+            let room_storage = RoomStorage::new(&pool);
+            let room_service = RoomService::new(
+                room_storage.clone(),
+                member_storage.clone(),
+                event_storage.clone(),
+                UserStorage::new(&pool, cache.clone()),
+                Arc::new(Validator::default()),
+                "localhost".to_string(),
+                None,
+            );
+            let sync_service = SyncService::new(
+                PresenceStorage::new(pool.clone(), cache.clone()),
+                member_storage,
+                event_storage,
+                room_storage,
+                DeviceStorage::new(&pool),
+            );
+
+            let room_val = room_service
+                .create_room(
+                    "@ivan:localhost",
+                    CreateRoomConfig {
+                        creation_content: Some(json!({})),
+                        ..Default::default()
+                    },
+                )
+                .await
+                .unwrap();
+            let room_id = room_val["room_id"].as_str().unwrap();
+
+            let before = sync_service
+                .sync("@judy:localhost", 0, false, "offline", None)
+                .await
+                .unwrap();
+            assert!(before["rooms"]["invite"].as_object().unwrap().is_empty());
+            let since = before["next_batch"].as_str().unwrap().to_string();
+
+            tokio::time::sleep(std::time::Duration::from_millis(5)).await;
+            room_service
+                .invite_user(room_id, "@ivan:localhost", "@judy:localhost")
+                .await
+                .unwrap();
+
+            let val = sync_service
+                .sync("@judy:localhost", 0, false, "offline", Some(&since))
+                .await
+                .unwrap();
+            let invite_state = val["rooms"]["invite"][room_id]["invite_state"]["events"]
+                .as_array()
+                .unwrap();
+            assert!(invite_state
+                .iter()
+                .any(|e| e["type"] == "m.room.create" && e.get("event_id").is_none()));
+            assert!(val["rooms"]["join"].as_object().unwrap().is_empty());
+
+            let next = val["next_batch"].as_str().unwrap().to_string();
+            let val = sync_service
+                .sync("@judy:localhost", 0, false, "offline", Some(&next))
+                .await
+                .unwrap();
+            assert!(val["rooms"]["invite"].as_object().unwrap().is_empty());
+        });
+    }
+
+    #[test]
+    fn test_sync_applies_persisted_filter() {
+        let rt = Runtime::new().unwrap();
+        rt.block_on(async {
+            let pool = match setup_test_database().await {
+                Some(pool) => Arc::new(pool),
+                None => return,
+            };
+            create_test_user(&pool, "@erin:localhost", "erin").await;
+
+            let cache = Arc::new(CacheManager::new(CacheConfig::default()));
+            let member_storage = RoomMemberStorage::new(&pool, "localhost");
+            let event_storage = EventStorage::new(&pool);
+            let room_storage = RoomStorage::new(&pool);
+            let user_storage = UserStorage::new(&pool, cache.clone());
+
+            let room_service = RoomService::new(
+                room_storage.clone(),
+                member_storage.clone(),
+                event_storage.clone(),
+                user_storage.clone(),
+                Arc::new(Validator::default()),
+                "localhost".to_string(),
+                None,
+            );
+            let sync_service = SyncService::new(
+                PresenceStorage::new(pool.clone(), cache.clone()),
+                member_storage,
+                event_storage,
+                room_storage,
+                DeviceStorage::new(&pool),
+            )
+            .with_user_storage(user_storage);
+
+            let room_val = room_service
+                .create_room("@erin:localhost", CreateRoomConfig::default())
+                .await
+                .unwrap();
+            let room_id = room_val["room_id"].as_str().unwrap();
+            for body in ["one", "two"] {
+                room_service
+                    .send_message(room_id, "@erin:localhost", "m.text", &json!(body))
+                    .await
+                    .unwrap();
+                tokio::time::sleep(std::time::Duration::from_millis(5)).await;
+            }
+
+            let filter_id = sync_service
+                .set_filter(
+                    "@erin:localhost",
+                    &json!({
+                        "event_fields": ["type", "content.body"],
+                        "room": {"timeline": {"types": ["m.room.message"], "limit": 1}}
+                    }),
+                )
+                .await
+                .unwrap();
+            assert!(sync_service
+                .get_filter("@erin:localhost", &filter_id)
+                .await
+                .unwrap()
+                .is_some());
+
+            let filter = sync_service
+                .resolve_filter("@erin:localhost", Some(&filter_id))
+                .await
+                .unwrap();
+            let val = sync_service
+                .sync_with_filter("@erin:localhost", None, 0, false, "online", None, &filter)
+                .await
+                .unwrap();
+
+            let timeline = &val["rooms"]["join"][room_id]["timeline"];
+            let events = timeline["events"].as_array().unwrap();
+            assert_eq!(events.len(), 1);
+            assert_eq!(events[0], json!({"type": "m.room.message", "content": {"body": "two"}}));
+            assert_eq!(timeline["limited"], true);
+
+            let messages = sync_service
+                .get_room_messages(
+                    room_id,
+                    "@erin:localhost",
+                    "",
+                    10,
+                    "b",
+                    &synapse_rust::services::SyncFilter {
+                        limit: None,
+                        not_types: Some(vec!["m.room.message".to_string()]),
+                        ..Default::default()
+                    },
+                )
+                .await
+                .unwrap();
+            assert!(messages["chunk"]
+                .as_array()
+                .unwrap()
+                .iter()
+                .all(|e| e["type"] != "m.room.message"));
+        });
+    }
+
+    #[test]
+    fn test_set_filter_rejects_invalid_definition() {
+        let rt = Runtime::new().unwrap();
+        rt.block_on(async {
+            let pool = match setup_test_database().await {
+                Some(pool) => Arc::new(pool),
+                None => return,
+            };
+
+            let cache = Arc::new(CacheManager::new(CacheConfig::default()));
+            let sync_service = SyncService::new(
+                PresenceStorage::new(pool.clone(), cache.clone()),
+                RoomMemberStorage::new(&pool, "localhost"),
+                EventStorage::new(&pool),
+                RoomStorage::new(&pool),
+                DeviceStorage::new(&pool),
+            )
+            .with_user_storage(UserStorage::new(&pool, cache));
+
+            let result = sync_service
+                .set_filter("@frank:localhost", &json!({"room": {"timeline": {"limit": "ten"}}}))
+                .await;
+            assert!(result.is_err());
+        });
+    }
+
+    #[test]
+    fn test_sync_delivers_to_device_until_acknowledged() {
+        let rt = Runtime::new().unwrap();
+        rt.block_on(async {
+            let pool = match setup_test_database().await {
+                Some(pool) => Arc::new(pool),
+                None => return,
+            };
+            create_test_user(&pool, "@gina:localhost", "gina").await;
+            let device_storage = DeviceStorage::new(&pool);
+            device_storage
+                .create_device("GINADEVICE", "@gina:localhost", None)
+                .await
+                .unwrap();
+
+            let cache = Arc::new(CacheManager::new(CacheConfig::default()));
+            let to_device_service = synapse_rust::e2ee::to_device::ToDeviceService::new(
+                synapse_rust::e2ee::to_device::ToDeviceStorage::new(&pool),
+            );
+            let sync_service = SyncService::new(
+                PresenceStorage::new(pool.clone(), cache.clone()),
+                RoomMemberStorage::new(&pool, "localhost"),
+                EventStorage::new(&pool),
+                RoomStorage::new(&pool),
+                device_storage,
+            )
+            .with_to_device_service(to_device_service.clone());
+
+            to_device_service
+                .send_messages(
+                    "@remote:example.com",
+                    "m.room_key_request",
+                    &json!({"@gina:localhost": {"GINADEVICE": {"action": "request"}}}),
+                )
+                .await
+                .unwrap();
+
+            let filter = Default::default();
+            let first = sync_service
+                .sync_with_filter("@gina:localhost", Some("GINADEVICE"), 0, false, "offline", None, &filter)
+                .await
+                .unwrap();
+            let events = first["to_device"]["events"].as_array().unwrap();
+            assert_eq!(events.len(), 1);
+            assert_eq!(events[0]["sender"], "@remote:example.com");
+            assert_eq!(events[0]["type"], "m.room_key_request");
+
+            // Not acknowledged yet: a retry from the same position sees it again
+            let retry = sync_service
+                .sync_with_filter("@gina:localhost", Some("GINADEVICE"), 0, false, "offline", None, &filter)
+                .await
+                .unwrap();
+            assert_eq!(retry["to_device"]["events"].as_array().unwrap().len(), 1);
+
+            let since = retry["next_batch"].as_str().unwrap().to_string();
+            let acked = sync_service
+                .sync_with_filter("@gina:localhost", Some("GINADEVICE"), 0, false, "offline", Some(&since), &filter)
+                .await
+                .unwrap();
+            assert!(acked["to_device"]["events"].as_array().unwrap().is_empty());
+        });
+    }
+
+    #[test]
+    fn test_sliding_sync_is_incremental_per_connection() {
+        let rt = Runtime::new().unwrap();
+        rt.block_on(async {
+            let pool = match setup_test_database().await {
+                Some(pool) => Arc::new(pool),
+                None => return,
+            };
+            create_test_user(&pool, "@hana:localhost", "hana").await;
+
+            let cache = Arc::new(CacheManager::new(CacheConfig::default()));
+            let member_storage = RoomMemberStorage::new(&pool, "localhost");
+            let event_storage = EventStorage::new(&pool);
+            let room_storage = RoomStorage::new(&pool);
+            let user_storage = UserStorage::new(&pool, cache.clone());
+
+            let room_service = RoomService::new(
+                room_storage.clone(),
+                member_storage.clone(),
+                event_storage.clone(),
+                user_storage.clone(),
+                Arc::new(Validator::default()),
+                "localhost".to_string(),
+                None,
+            );
+            let sync_service = Arc::new(SyncService::new(
+                PresenceStorage::new(pool.clone(), cache.clone()),
+                member_storage.clone(),
+                event_storage.clone(),
+                room_storage,
+                DeviceStorage::new(&pool),
+            ));
+            let sliding_sync = SlidingSyncService::new(
+                sync_service,
+                member_storage,
+                event_storage,
+                SlidingSyncStorage::new(&pool),
+            );
+
+            let room_val = room_service
+                .create_room(
+                    "@hana:localhost",
+                    CreateRoomConfig {
+                        name: Some("Sliding".to_string()),
+                        ..Default::default()
+                    },
+                )
+                .await
+                .unwrap();
+            let room_id = room_val["room_id"].as_str().unwrap();
+
+            let request: SlidingSyncRequest = serde_json::from_value(json!({
+                "conn_id": "main",
+                "lists": {
+                    "all": {
+                        "ranges": [[0, 10]],
+                        "required_state": [["m.room.name", ""]],
+                        "timeline_limit": 5
+                    }
+                }
+            }))
+            .unwrap();
+
+            let first = sliding_sync
+                .sync("@hana:localhost", "DEVICE", None, 0, &request)
+                .await
+                .unwrap();
+            assert_eq!(first["lists"]["all"]["count"], 1);
+            let room = &first["rooms"][room_id];
+            assert_eq!(room["initial"], true);
+            assert_eq!(room["name"], "Sliding");
+            assert_eq!(room["required_state"].as_array().unwrap().len(), 1);
+            let pos = first["pos"].as_str().unwrap().to_string();
+
+            // Nothing changed, so the room is left out of the next response
+            tokio::time::sleep(std::time::Duration::from_millis(5)).await;
+            let idle = sliding_sync
+                .sync("@hana:localhost", "DEVICE", Some(&pos), 0, &request)
+                .await
+                .unwrap();
+            assert!(idle["rooms"].as_object().unwrap().is_empty());
+            let pos = idle["pos"].as_str().unwrap().to_string();
+
+            room_service
+                .send_message(room_id, "@hana:localhost", "m.text", &json!({"body": "hi"}))
+                .await
+                .unwrap();
+            let update = sliding_sync
+                .sync("@hana:localhost", "DEVICE", Some(&pos), 0, &request)
+                .await
+                .unwrap();
+            let room = &update["rooms"][room_id];
+            assert!(room.get("initial").is_none());
+            assert_eq!(room["num_live"], 1);
+            assert_eq!(room["timeline"].as_array().unwrap().len(), 1);
+
+            let unknown = sliding_sync
+                .sync("@hana:localhost", "DEVICE", Some("12345"), 0, &request)
+                .await;
+            assert!(matches!(unknown, Err(ApiError::UnknownPos(_))));
+        });
+    }
+
+    #[test]
+    fn test_incremental_sync_sends_full_state_for_newly_joined_room() {
+        let rt = Runtime::new().unwrap();
+        rt.block_on(async {
+            let pool = match setup_test_database().await {
+                Some(pool) => Arc::new(pool),
+                None => return,
+            };
+            create_test_user(&pool, "@rupert:localhost", "rupert").await;
+            create_test_user(&pool, "@sybil:localhost", "sybil").await;
+
+            let cache = Arc::new(CacheManager::new(CacheConfig::default()));
+            let member_storage = RoomMemberStorage::new(&pool, "localhost");
+            let event_storage = EventStorage::new(&pool);
+            let room_storage = RoomStorage::new(&pool);
+            let room_service = RoomService::new(
+                room_storage.clone(),
+                member_storage.clone(),
+                event_storage.clone(),
+                UserStorage::new(&pool, cache.clone()),
+                Arc::new(Validator::default()),
+                "localhost".to_string(),
+                None,
+            );
+            let sync_service = SyncService::new(
+                PresenceStorage::new(pool.clone(), cache.clone()),
+                member_storage,
+                event_storage,
+                room_storage,
+                DeviceStorage::new(&pool),
+            );
+
+            let config = CreateRoomConfig {
+                name: Some("Newcomers".to_string()),
+                preset: Some("public_chat".to_string()),
+                ..Default::default()
+            };
+            let room_val = room_service.create_room("@rupert:localhost", config).await.unwrap();
+            let room_id = room_val["room_id"].as_str().unwrap();
+
+            let initial = sync_service
+                .sync("@sybil:localhost", 0, false, "offline", None)
+                .await
+                .unwrap();
+            assert!(initial["rooms"]["join"].get(room_id).is_none());
+            let since = initial["next_batch"].as_str().unwrap().to_string();
+
+            room_service.join_room(room_id, "@sybil:localhost").await.unwrap();
+            let val = sync_service
+                .sync("@sybil:localhost", 0, false, "offline", Some(&since))
+                .await
+                .unwrap();
+            let state = val["rooms"]["join"][room_id]["state"]["events"].as_array().unwrap();
+            for event_type in ["m.room.create", "m.room.power_levels", "m.room.join_rules", "m.room.name"] {
+                assert!(state.iter().any(|e| e["type"] == event_type), "{} missing", event_type);
+            }
+            assert!(state
+                .iter()
+                .any(|e| e["type"] == "m.room.member" && e["state_key"] == "@rupert:localhost"));
+        });
+    }
+
+    #[test]
+    fn test_limited_incremental_sync_sends_state_changed_in_the_gap() {
+        let rt = Runtime::new().unwrap();
+        rt.block_on(async {
+            let pool = match setup_test_database().await {
+                Some(pool) => Arc::new(pool),
+                None => return,
+            };
+            create_test_user(&pool, "@quentin:localhost", "quentin").await;
+
+            let cache = Arc::new(CacheManager::new(CacheConfig::default()));
+            let member_storage = RoomMemberStorage::new(&pool, "localhost");
+            let event_storage = EventStorage::new(&pool);
+            let room_storage = RoomStorage::new(&pool);
+            let room_service = RoomService::new(
+                room_storage.clone(),
+                member_storage.clone(),
+                event_storage.clone(),
+                UserStorage::new(&pool, cache.clone()),
+                Arc::new(Validator::default()),
+                "localhost".to_string(),
+                None,
+            );
+            let sync_service = SyncService::new(
+                PresenceStorage::new(pool.clone(), cache.clone()),
+                member_storage,
+                event_storage.clone(),
+                room_storage,
+                DeviceStorage::new(&pool),
+            );
+
+            let room_val = room_service
+                .create_room("@quentin:localhost", CreateRoomConfig::default())
+                .await
+                .unwrap();
+            let room_id = room_val["room_id"].as_str().unwrap();
+            let initial = sync_service
+                .sync("@quentin:localhost", 0, false, "offline", None)
+                .await
+                .unwrap();
+            let since = initial["next_batch"].as_str().unwrap().to_string();
+
+            let topic = event_storage
+                .create_event(
+                    CreateEventParams {
+                        event_id: "$gap_topic:localhost".to_string(),
+                        room_id: room_id.to_string(),
+                        user_id: "@quentin:localhost".to_string(),
+                        event_type: "m.room.topic".to_string(),
+                        content: json!({"topic": "Changed in the gap"}),
+                        state_key: Some(String::new()),
+                        origin_server_ts: chrono::Utc::now().timestamp_millis(),
+                    },
+                    None,
+                )
+                .await
+                .unwrap();
+            // More messages than the default timeline limit push the topic out of the timeline
+            for i in 0..55 {
+                room_service
+                    .send_message(room_id, "@quentin:localhost", "m.text", &json!({"body": i.to_string()}))
+                    .await
+                    .unwrap();
+            }
+
+            let val = sync_service
+                .sync("@quentin:localhost", 0, false, "offline", Some(&since))
+                .await
+                .unwrap();
+            let room = &val["rooms"]["join"][room_id];
+            assert_eq!(room["timeline"]["limited"], true);
+            let timeline = room["timeline"]["events"].as_array().unwrap();
+            assert!(!timeline.iter().any(|e| e["event_id"] == topic.event_id));
+            let state = room["state"]["events"].as_array().unwrap();
+            assert_eq!(state.len(), 1);
+            assert_eq!(state[0]["event_id"], topic.event_id);
+            assert_eq!(state[0]["content"]["topic"], "Changed in the gap");
         });
     }